@@ -0,0 +1,81 @@
+//! Relay server with rooms
+//!
+//! Runs a signaling server routing the rovers' status to the operator
+//! consoles of their room, and the consoles' commands to the rovers. Clients
+//! choose their room with the `room` query parameter of the signaling URL,
+//! and messages never cross rooms:
+//!
+//! ```text
+//! cargo run --example relay_server -- 0.0.0.0:3000
+//! cargo run --example telemetry_rover -- http://127.0.0.1:3000/?room=lab rover-1
+//! cargo run --example teleop_console -- http://127.0.0.1:3000/?room=lab console-1
+//! ```
+
+use std::collections::BTreeMap;
+
+use rover_rtc::model::routing::{Destination, Route, RoutingConfig, ANY};
+use rover_rtc::server::ServerEvent;
+use rover_rtc::RoverRtc;
+
+/// Channel the rovers publish their status on.
+const STATUS_CHANNEL: &str = "status";
+
+/// Channel the consoles send their commands on.
+const COMMANDS_CHANNEL: &str = "commands";
+
+fn main() -> anyhow::Result<()> {
+    let http_addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "0.0.0.0:3000".to_string());
+
+    // Every client's status and commands reach the other clients of its
+    // room that opened the same channel
+    let routes = [STATUS_CHANNEL, COMMANDS_CHANNEL]
+        .into_iter()
+        .map(|channel| Route {
+            channel: channel.to_string(),
+            to: Destination::Broadcast,
+        })
+        .collect();
+    let routing = RoutingConfig {
+        routes: BTreeMap::from([(ANY.to_string(), routes)]),
+    };
+
+    let mut server = RoverRtc::builder()
+        .with_logging()
+        .http_addr(http_addr)
+        .routing(routing)
+        .on_server_event(|event| match event {
+            ServerEvent::ClientConnected { id, alias, .. } => {
+                println!(
+                    "client {} connected as {}",
+                    id,
+                    alias.as_deref().unwrap_or("-")
+                );
+            }
+            ServerEvent::ChannelData { id, channel, data } if channel == COMMANDS_CHANNEL => {
+                println!("client {} commands: {}", id, String::from_utf8_lossy(data));
+            }
+            _ => {}
+        })
+        .on_client_removed(|removal| {
+            println!(
+                "client {} ({}) left room {}: {}, {} message(s) received",
+                removal.id,
+                removal.alias.as_deref().unwrap_or("-"),
+                removal.room.as_deref().unwrap_or("-"),
+                removal.reason,
+                removal.counters.messages_received
+            );
+        })
+        .build_server();
+    server.start()?;
+    if let Some(addr) = server.http_addr() {
+        println!("relay listening on http://{}", addr);
+    }
+    if let Some(shutdown) = server.shutdown() {
+        shutdown.trigger_on_ctrl_c()?;
+    }
+    server.wait();
+    Ok(())
+}
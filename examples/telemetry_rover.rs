@@ -0,0 +1,71 @@
+//! Telemetry-only rover
+//!
+//! Connects to a signaling server and publishes, once a second, a GPS fix on
+//! the telemetry channel, which the server's geofences and rules act on, and
+//! a JSON status on the status channel, which the relay server routes to the
+//! operator consoles of the rover's room. It accepts no commands.
+//!
+//! ```text
+//! cargo run --example telemetry_rover -- http://127.0.0.1:3000/?room=lab rover-1
+//! ```
+
+use std::{thread, time::Duration};
+
+use rover_rtc::model::telemetry::{GpsFix, Telemetry, TELEMETRY_CHANNEL};
+use rover_rtc::peer::PeerEvent;
+use rover_rtc::RoverRtc;
+
+/// Channel the rover publishes its status on.
+const STATUS_CHANNEL: &str = "status";
+
+/// Interval between two samples.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let signaling_url = args
+        .next()
+        .unwrap_or_else(|| "http://127.0.0.1:3000".to_string());
+    let alias = args.next().unwrap_or_else(|| "rover-1".to_string());
+
+    let mut peer = RoverRtc::builder()
+        .with_logging()
+        .signaling_url(signaling_url)
+        .alias(alias.clone())
+        .channel(TELEMETRY_CHANNEL)
+        .channel(STATUS_CHANNEL)
+        .on_peer_event(|event| match event {
+            PeerEvent::Connected => println!("connected"),
+            PeerEvent::Reconnecting { attempt, delay } => {
+                println!("connection lost, reconnecting in {:?} ({})", delay, attempt)
+            }
+            _ => {}
+        })
+        .build_peer();
+    peer.start()?;
+    peer.shutdown().trigger_on_ctrl_c()?;
+
+    // A rover driving in a circle around its start
+    let mut step = 0u32;
+    while peer.is_running() {
+        let angle = f64::from(step) / 60.0 * std::f64::consts::TAU;
+        let fix = GpsFix::new(
+            47.3769 + 0.0005 * angle.sin(),
+            8.5417 + 0.0005 * angle.cos(),
+            408.0,
+        );
+        let status = serde_json::json!({
+            "alias": alias,
+            "latitude": fix.latitude,
+            "longitude": fix.longitude,
+            "battery": 100 - (step / 60).min(100),
+        });
+        let handle = peer.handle();
+        handle.send(TELEMETRY_CHANNEL, Telemetry::Gps(fix).encode());
+        handle.send(STATUS_CHANNEL, status.to_string().into_bytes());
+
+        step += 1;
+        thread::sleep(SAMPLE_INTERVAL);
+    }
+    peer.wait()
+}
@@ -0,0 +1,68 @@
+//! Teleoperation console
+//!
+//! Connects to a signaling server as an operator console, prints the status
+//! the rovers of its room publish and sends every line typed on the standard
+//! input as a command. Through the relay server, the commands reach the
+//! rovers of the room that opened the commands channel, e.g. one started with
+//! `rover-rtc peer --channel commands`.
+//!
+//! ```text
+//! cargo run --example teleop_console -- http://127.0.0.1:3000/?room=lab console-1
+//! ```
+
+use std::{io::BufRead, thread};
+
+use rover_rtc::peer::PeerEvent;
+use rover_rtc::RoverRtc;
+
+/// Channel the rovers publish their status on.
+const STATUS_CHANNEL: &str = "status";
+
+/// Channel the console sends its commands on.
+const COMMANDS_CHANNEL: &str = "commands";
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let signaling_url = args
+        .next()
+        .unwrap_or_else(|| "http://127.0.0.1:3000".to_string());
+    let alias = args.next().unwrap_or_else(|| "console-1".to_string());
+
+    let mut peer = RoverRtc::builder()
+        .with_logging()
+        .signaling_url(signaling_url)
+        .alias(alias)
+        .channel(STATUS_CHANNEL)
+        .channel(COMMANDS_CHANNEL)
+        .on_peer_event(|event| match event {
+            PeerEvent::ChannelOpen { label } if label == COMMANDS_CHANNEL => {
+                println!("ready, type a command and press enter")
+            }
+            PeerEvent::Disconnected => println!("disconnected"),
+            _ => {}
+        })
+        .build_peer();
+    let mut status = peer.handle().subscribe(STATUS_CHANNEL);
+    peer.start()?;
+    peer.shutdown().trigger_on_ctrl_c()?;
+
+    thread::spawn(move || {
+        while let Some(data) = status.blocking_recv() {
+            println!("status: {}", String::from_utf8_lossy(&data));
+        }
+    });
+
+    // Ends with the standard input, e.g. on ctrl-d
+    let handle = peer.handle().clone();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        let command = line.trim();
+        if !command.is_empty() {
+            handle.send(COMMANDS_CHANNEL, command.as_bytes().to_vec());
+        }
+        if !peer.is_running() {
+            break;
+        }
+    }
+    peer.stop()
+}
@@ -0,0 +1,140 @@
+/*
+ * C bindings for the rover-rtc peer API.
+ *
+ * Link against librover_rtc.so or librover_rtc.a (built by `cargo build`).
+ * Functions returning int return 0 on success and -1 on failure; the reason
+ * is then available from rover_rtc_last_error() on the calling thread.
+ */
+#ifndef ROVER_RTC_H
+#define ROVER_RTC_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum RoverRtcEventKind {
+    /* No event was pending */
+    ROVER_RTC_EVENT_NONE = 0,
+    /* ICE connected */
+    ROVER_RTC_EVENT_CONNECTED = 1,
+    /* A data channel opened; label names it */
+    ROVER_RTC_EVENT_CHANNEL_OPEN = 2,
+    /* The first data channel opened; setup_ms is the total setup time */
+    ROVER_RTC_EVENT_SETUP_COMPLETE = 3,
+    /* ICE is restarting after a network change */
+    ROVER_RTC_EVENT_RESTARTING = 4,
+    /* The connection was lost or stopped */
+    ROVER_RTC_EVENT_DISCONNECTED = 5,
+    /* Data arrived on a subscribed channel; label, data and len describe it */
+    ROVER_RTC_EVENT_DATA = 6,
+    /* The connection is down; attempt runs after duration_ms */
+    ROVER_RTC_EVENT_RECONNECTING = 7,
+    /* The channels are back; duration_ms is the outage, after attempt attempts */
+    ROVER_RTC_EVENT_RECONNECTED = 8,
+    /* The video encoder should produce a keyframe */
+    ROVER_RTC_EVENT_KEYFRAME_REQUESTED = 9,
+    /* The operator's voice arrived; label is "opus" for an Opus packet in
+     * data, or "pcm" for 8 kHz 16-bit native-endian samples */
+    ROVER_RTC_EVENT_AUDIO = 10,
+    /* A frame of a track another client publishes arrived, forwarded by the
+     * server, or of a track of the remote; label is the kind and codec, e.g.
+     * "video/H264", and origin the publishing client, 0 for the remote */
+    ROVER_RTC_EVENT_MEDIA = 11,
+    /* A message was given up under its channel's write retry policy; label,
+     * data and len describe it */
+    ROVER_RTC_EVENT_WRITE_FAILED = 12,
+    /* A file arrived on the transfer channel; label is the name it was sent
+     * under and data the UTF-8 path it was stored at */
+    ROVER_RTC_EVENT_FILE_RECEIVED = 13,
+    /* The remote confirmed a file sent to it; label is its name */
+    ROVER_RTC_EVENT_TRANSFER_COMPLETE = 14,
+    /* A transfer was refused or its file did not match its digest; label is
+     * the file name and data the UTF-8 reason */
+    ROVER_RTC_EVENT_TRANSFER_FAILED = 15,
+    /* The bonding moved the application traffic to another link; label is
+     * its ID and data the UTF-8 reason */
+    ROVER_RTC_EVENT_LINK_FAILOVER = 16,
+    /* An announcement to every client of the room arrived, in data */
+    ROVER_RTC_EVENT_ANNOUNCEMENT = 17,
+    /* A health check moved the connection to another state; label is
+     * "healthy", "degraded" or "dead" */
+    ROVER_RTC_EVENT_HEALTH_CHANGED = 18,
+} RoverRtcEventKind;
+
+/*
+ * An event, borrowed from the peer. The pointers stay valid until the
+ * callback returns, or until the next rover_rtc_peer_poll_event() or
+ * rover_rtc_peer_free() on the peer.
+ */
+typedef struct RoverRtcEvent {
+    RoverRtcEventKind kind;
+    const char *label;
+    const uint8_t *data;
+    size_t len;
+    double setup_ms;
+    uint32_t attempt;
+    double duration_ms;
+    uint64_t origin;
+} RoverRtcEvent;
+
+/* Opaque peer handle */
+typedef struct RoverRtcPeer RoverRtcPeer;
+
+/* Receives every event, from the peer's threads */
+typedef void (*RoverRtcEventCallback)(const RoverRtcEvent *event, void *user_data);
+
+/* Returns the last error on the calling thread, or NULL */
+const char *rover_rtc_last_error(void);
+
+/* Installs the library's logger, configured with RUST_LOG */
+void rover_rtc_init_logging(void);
+
+/*
+ * Creates a peer from a TOML or YAML configuration file (NULL for the
+ * defaults), with the ROVER_* environment variables applied over it.
+ * Returns NULL on error.
+ */
+RoverRtcPeer *rover_rtc_peer_new(const char *config_path);
+
+/* Subscribes to the data received on a channel, reported as DATA events */
+int rover_rtc_peer_subscribe(RoverRtcPeer *peer, const char *label);
+
+/*
+ * Registers the callback receiving every event, or removes it if NULL.
+ * Events are queued for rover_rtc_peer_poll_event() while no callback is
+ * registered. The callback must not call rover_rtc_peer_set_callback() or
+ * rover_rtc_peer_free().
+ */
+int rover_rtc_peer_set_callback(RoverRtcPeer *peer, RoverRtcEventCallback callback,
+                                void *user_data);
+
+/* Starts the peer on its own thread */
+int rover_rtc_peer_start(RoverRtcPeer *peer);
+
+/* Queues data to be sent on a channel; dropped if the channel is not open */
+int rover_rtc_peer_send(RoverRtcPeer *peer, const char *label, const uint8_t *data,
+                        size_t len);
+
+/* Sends a file on the transfer channel, resuming it after reconnections */
+int rover_rtc_peer_send_file(RoverRtcPeer *peer, const char *path);
+
+/* Takes the next queued event without blocking: 1 if one was written, 0 if none */
+int rover_rtc_peer_poll_event(RoverRtcPeer *peer, RoverRtcEvent *event);
+
+/* Returns 1 while the peer's thread is running */
+int rover_rtc_peer_is_running(const RoverRtcPeer *peer);
+
+/* Stops the peer and waits for it to disconnect */
+int rover_rtc_peer_stop(RoverRtcPeer *peer);
+
+/* Stops the peer if it is running and releases it */
+void rover_rtc_peer_free(RoverRtcPeer *peer);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ROVER_RTC_H */
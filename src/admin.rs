@@ -0,0 +1,326 @@
+//! Client for the server's admin API
+//!
+//! Wraps the HTTP routes served under `/admin/` and `/clients/` (see the
+//! server's `ROVER_ADMIN_TOKEN`) so tools and test scenarios can inspect and
+//! steer a running server without hand-writing requests. Responses are
+//! returned as JSON values, in the shape the server sends them.
+
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::{bail, Context};
+use reqwest::{blocking::RequestBuilder, Method};
+use serde_json::{json, Value};
+
+use crate::model::logs::LogQuery;
+
+/// How long a single admin request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Blocking client for the admin API of a running server.
+#[derive(Debug, Clone)]
+pub struct AdminClient {
+    base_url: String,
+    token: String,
+    http: reqwest::blocking::Client,
+}
+
+impl AdminClient {
+    /// Creates a client for the server at `base_url`.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_url` - The server's HTTP address, e.g. `http://10.0.0.1:3000`
+    /// * `token` - The admin token the server was started with
+    pub fn new(base_url: impl Into<String>, token: impl Into<String>) -> anyhow::Result<Self> {
+        let http = reqwest::blocking::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            token: token.into(),
+            http,
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, format!("{}{}", self.base_url, path))
+            .bearer_auth(&self.token)
+    }
+
+    /// Sends a request, returning its JSON body or `Null` if it has none.
+    fn send(&self, request: RequestBuilder) -> anyhow::Result<Value> {
+        let response = request.send().context("admin request failed")?;
+        let status = response.status();
+        let body = response.text()?;
+        if !status.is_success() {
+            bail!("admin request failed with {}: {}", status, body.trim());
+        }
+        if body.trim().is_empty() {
+            return Ok(Value::Null);
+        }
+        serde_json::from_str(&body).context("invalid admin response")
+    }
+
+    /// Lists the connected clients with their aliases, rooms, health states
+    /// and current connection statistics.
+    pub fn clients(&self) -> anyhow::Result<Value> {
+        self.send(self.request(Method::GET, "/admin/clients"))
+    }
+
+    /// Dumps the state of the server and every client, with their recent
+    /// significant events.
+    pub fn dump_state(&self) -> anyhow::Result<Value> {
+        self.send(self.request(Method::GET, "/admin/state"))
+    }
+
+    /// Returns a client's metric time series.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client's numeric ID or alias
+    /// * `window` - How far back to report, e.g. `15m`
+    pub fn stats(&self, client: &str, window: &str) -> anyhow::Result<Value> {
+        let request = self
+            .request(Method::GET, &format!("/clients/{}/stats", client))
+            .query(&[("window", window)]);
+        self.send(request)
+    }
+
+    /// Returns a client's current connection statistics.
+    pub fn connection(&self, client: &str) -> anyhow::Result<Value> {
+        self.send(self.request(Method::GET, &format!("/clients/{}/connection", client)))
+    }
+
+    /// Returns the time spent in each phase of a client's setup.
+    pub fn setup(&self, client: &str) -> anyhow::Result<Value> {
+        self.send(self.request(Method::GET, &format!("/clients/{}/setup", client)))
+    }
+
+    /// Sends a text message to a client.
+    pub fn send_message(&self, client: &str, message: &str) -> anyhow::Result<()> {
+        let request = self
+            .request(Method::POST, &format!("/clients/{}/messages", client))
+            .body(message.to_string());
+        self.send(request).map(drop)
+    }
+
+    /// Closes a client's session.
+    pub fn disconnect(&self, client: &str) -> anyhow::Result<()> {
+        self.send(self.request(Method::DELETE, &format!("/clients/{}", client)))
+            .map(drop)
+    }
+
+    /// Asks a client's peer to restart ICE; fails unless the peer
+    /// advertised `remote_restart`.
+    pub fn restart_ice(&self, client: &str) -> anyhow::Result<()> {
+        self.send(self.request(Method::POST, &format!("/clients/{}/restart", client)))
+            .map(drop)
+    }
+
+    /// Sends an announcement to every client of a room and waits for their
+    /// acknowledgments.
+    ///
+    /// # Arguments
+    ///
+    /// * `room` - The room
+    /// * `message` - The announcement
+    /// * `timeout_ms` - How long acknowledgments are awaited, at most 8000;
+    ///   5000 if `None`
+    ///
+    /// # Returns
+    ///
+    /// The aggregate result: how many clients acknowledged it, and the
+    /// delivery to each client
+    pub fn broadcast(
+        &self,
+        room: &str,
+        message: &str,
+        timeout_ms: Option<u64>,
+    ) -> anyhow::Result<Value> {
+        let request = self
+            .request(Method::POST, &format!("/admin/rooms/{}/broadcast", room))
+            .json(&json!({ "message": message, "timeout_ms": timeout_ms }));
+        self.send(request)
+    }
+
+    /// Fetches a client's recent log lines over its connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client's numeric ID or alias
+    /// * `query` - The filters selecting the lines
+    ///
+    /// # Returns
+    ///
+    /// The matching lines, oldest first, or an error if the peer refused or
+    /// did not answer in time
+    pub fn logs(&self, client: &str, query: &LogQuery) -> anyhow::Result<String> {
+        let response = self
+            .request(Method::POST, &format!("/clients/{}/logs", client))
+            .json(query)
+            .send()
+            .context("admin request failed")?;
+        let status = response.status();
+        let body = response.text()?;
+        if !status.is_success() {
+            bail!("admin request failed with {}: {}", status, body.trim());
+        }
+        Ok(body)
+    }
+
+    /// Starts a capture of a client's UDP traffic on its peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client's numeric ID or alias
+    /// * `duration_secs` - How long to record; the peer may shorten it
+    /// * `max_bytes` - The largest pcap file; the peer may reduce it
+    pub fn start_capture(
+        &self,
+        client: &str,
+        duration_secs: u32,
+        max_bytes: u64,
+    ) -> anyhow::Result<()> {
+        let request = self
+            .request(Method::POST, &format!("/clients/{}/capture", client))
+            .json(&json!({ "duration_secs": duration_secs, "max_bytes": max_bytes }));
+        self.send(request).map(drop)
+    }
+
+    /// Returns the state of a client's last capture.
+    pub fn capture_status(&self, client: &str) -> anyhow::Result<Value> {
+        self.send(self.request(Method::GET, &format!("/clients/{}/capture", client)))
+    }
+
+    /// Ends a client's running capture early; the peer then sends it.
+    pub fn stop_capture(&self, client: &str) -> anyhow::Result<()> {
+        self.send(self.request(Method::DELETE, &format!("/clients/{}/capture", client)))
+            .map(drop)
+    }
+
+    /// Downloads a client's finished capture as a pcap file.
+    pub fn download_capture(&self, client: &str) -> anyhow::Result<Vec<u8>> {
+        let response = self
+            .request(Method::GET, &format!("/clients/{}/capture.pcap", client))
+            .send()
+            .context("admin request failed")?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("admin request failed with {}: no finished capture", status);
+        }
+        Ok(response.bytes()?.to_vec())
+    }
+
+    /// Issues a guest link for a room.
+    pub fn issue_guest_link(&self, room: &str, ttl_secs: i64) -> anyhow::Result<Value> {
+        let request = self
+            .request(Method::POST, "/admin/guest-links")
+            .json(&json!({ "room": room, "ttl_secs": ttl_secs }));
+        self.send(request)
+    }
+
+    /// Revokes a guest link.
+    pub fn revoke_guest_link(&self, id: &str) -> anyhow::Result<()> {
+        self.send(self.request(Method::DELETE, &format!("/admin/guest-links/{}", id)))
+            .map(drop)
+    }
+
+    /// Replays a recording into a room.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The recording, on the server's file system
+    /// * `room` - The room to replay into
+    /// * `speed` - The playback speed, `1.0` for real time
+    pub fn replay(&self, path: &str, room: &str, speed: f64) -> anyhow::Result<()> {
+        let request = self
+            .request(Method::POST, "/admin/replays")
+            .json(&json!({ "path": path, "room": room, "speed": speed }));
+        self.send(request).map(drop)
+    }
+
+    /// Returns the event log verbosity per event kind.
+    pub fn event_log(&self) -> anyhow::Result<Value> {
+        self.send(self.request(Method::GET, "/admin/log/events"))
+    }
+
+    /// Changes the event log verbosity, e.g. with `channel_data=sample:100`.
+    ///
+    /// # Returns
+    ///
+    /// The resulting verbosity per event kind
+    pub fn set_event_log(&self, spec: &str) -> anyhow::Result<Value> {
+        let request = self
+            .request(Method::PUT, "/admin/log/events")
+            .body(spec.to_string());
+        self.send(request)
+    }
+
+    /// Returns the state of the server's pcapng file tap, `null` if stopped.
+    pub fn pcap_status(&self) -> anyhow::Result<Value> {
+        self.send(self.request(Method::GET, "/admin/pcap"))
+    }
+
+    /// Starts writing the server's UDP traffic to a pcapng file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file, on the server's file system; the configured one if `None`
+    /// * `max_bytes` - The size after which the file stops growing; the
+    ///   configured one if `None`
+    pub fn start_pcap(&self, path: Option<&str>, max_bytes: Option<u64>) -> anyhow::Result<Value> {
+        let request = self
+            .request(Method::POST, "/admin/pcap")
+            .json(&json!({ "path": path, "max_bytes": max_bytes }));
+        self.send(request)
+    }
+
+    /// Stops the server's pcapng file tap and returns its final state.
+    pub fn stop_pcap(&self) -> anyhow::Result<Value> {
+        self.send(self.request(Method::DELETE, "/admin/pcap"))
+    }
+
+    /// Returns the server's simulated network impairment and what it did,
+    /// `null` if it is off.
+    pub fn netsim(&self) -> anyhow::Result<Value> {
+        self.send(self.request(Method::GET, "/admin/netsim"))
+    }
+
+    /// Replaces the server's simulated network impairment.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The settings of a `[network.netsim]` section, e.g.
+    ///   `{"enabled": true, "loss": 1.0}` to cut the link
+    pub fn set_netsim(&self, config: &Value) -> anyhow::Result<Value> {
+        let request = self.request(Method::PUT, "/admin/netsim").json(config);
+        self.send(request)
+    }
+
+    /// Lists blocked source addresses.
+    pub fn blocklist(&self) -> anyhow::Result<Value> {
+        self.send(self.request(Method::GET, "/admin/blocklist"))
+    }
+
+    /// Blocks a source address, for `duration_secs` or indefinitely.
+    pub fn block(
+        &self,
+        ip: IpAddr,
+        duration_secs: Option<u64>,
+        reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let request = self.request(Method::POST, "/admin/blocklist").json(&json!({
+            "ip": ip,
+            "duration_secs": duration_secs,
+            "reason": reason,
+        }));
+        self.send(request).map(drop)
+    }
+
+    /// Unblocks a source address.
+    pub fn unblock(&self, ip: IpAddr) -> anyhow::Result<()> {
+        self.send(self.request(Method::DELETE, &format!("/admin/blocklist/{}", ip)))
+            .map(drop)
+    }
+}
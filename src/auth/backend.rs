@@ -0,0 +1,510 @@
+//! Pluggable authentication backends for signaling
+//!
+//! The signaling server hands every request that does not carry a guest token
+//! to a single [`AuthBackend`], selected by the `[server.auth]` configuration
+//! section, so the server can sit behind whatever identity system a team
+//! already runs:
+//!
+//! ```toml
+//! [server.auth]
+//! backend = "jwt"
+//! algorithm = "RS256"
+//! public_key_file = "/etc/rover/idp.pem"
+//! issuer = "https://idp.example.com"
+//! audience = "rover-rtc"
+//! ```
+//!
+//! - `open` (the default) admits every request as a participant
+//! - `static_tokens` admits bearer tokens from a fixed list
+//! - `jwt` admits bearer JWTs signed by an identity provider
+//! - `mtls` admits clients whose certificate was verified by a TLS-terminating
+//!   reverse proxy, which forwards the outcome in request headers
+
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rouille::Request;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{bearer_token, Access, Role};
+
+/// Environment variable holding the JWT HMAC secret, used when the
+/// configuration does not set one.
+pub const JWT_SECRET_ENV: &str = "ROVER_JWT_SECRET";
+
+/// Errors returned when a signaling request cannot be authenticated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// The request carries no credentials the backend understands
+    MissingCredentials,
+    /// The credentials were presented but are not valid
+    InvalidCredentials(String),
+    /// Certificate headers came from an address that is not a trusted proxy
+    UntrustedProxy,
+    /// The credentials do not grant access to the requested room
+    WrongRoom,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingCredentials => f.write_str("missing credentials"),
+            AuthError::InvalidCredentials(reason) => write!(f, "invalid credentials: {}", reason),
+            AuthError::UntrustedProxy => f.write_str("client certificate from an untrusted proxy"),
+            AuthError::WrongRoom => f.write_str("credentials not valid for this room"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Authenticates signaling requests.
+pub trait AuthBackend: Send + Sync {
+    /// The backend name used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Authenticates a signaling request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The incoming offer or WebSocket upgrade request
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Access)` - The access granted to the session
+    /// * `Err(AuthError)` - If the request must be rejected
+    fn authenticate(&self, request: &Request) -> Result<Access, AuthError>;
+
+    /// Re-authenticates an established session when it is refreshed.
+    ///
+    /// Backends whose credentials cannot be sent in-band accept the refresh
+    /// alone, which is the default.
+    ///
+    /// # Arguments
+    ///
+    /// * `access` - The access the session was granted
+    /// * `credential` - A fresh bearer credential, if the peer sent one
+    ///
+    /// # Returns
+    ///
+    /// An error if the session must not be extended
+    fn reauthenticate(&self, access: &Access, credential: Option<&str>) -> Result<(), AuthError> {
+        let _ = (access, credential);
+        Ok(())
+    }
+}
+
+/// Selects and configures the authentication backend.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum AuthConfig {
+    /// Admit every request, see [`OpenBackend`]
+    #[default]
+    Open,
+    /// Admit bearer tokens from a fixed list, see [`StaticTokenBackend`]
+    StaticTokens(StaticTokensConfig),
+    /// Admit bearer JWTs, see [`JwtBackend`]
+    Jwt(JwtConfig),
+    /// Admit proxy-verified client certificates, see [`MtlsBackend`]
+    Mtls(MtlsConfig),
+}
+
+impl AuthConfig {
+    /// Builds the configured backend, reading any key files.
+    pub fn build(&self) -> anyhow::Result<Box<dyn AuthBackend>> {
+        Ok(match self {
+            AuthConfig::Open => Box::new(OpenBackend),
+            AuthConfig::StaticTokens(config) => Box::new(StaticTokenBackend::new(config)?),
+            AuthConfig::Jwt(config) => Box::new(JwtBackend::new(config)?),
+            AuthConfig::Mtls(config) => Box::new(MtlsBackend::new(config.clone())),
+        })
+    }
+}
+
+/// Settings of the `static_tokens` backend.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaticTokensConfig {
+    /// The accepted tokens
+    pub tokens: Vec<StaticToken>,
+}
+
+/// A bearer token accepted by the `static_tokens` backend.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticToken {
+    /// The token itself
+    pub token: String,
+    /// The identity the token stands for, e.g. a rover or operator name
+    pub subject: String,
+    /// The role granted to sessions using the token
+    #[serde(default)]
+    pub role: Role,
+    /// The only room the token grants access to, if restricted
+    #[serde(default)]
+    pub room: Option<String>,
+}
+
+/// Settings of the `jwt` backend.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    /// The signing algorithm the identity provider uses
+    pub algorithm: Algorithm,
+    /// The HMAC secret for `HS*` algorithms; falls back to [`JWT_SECRET_ENV`]
+    pub secret: Option<String>,
+    /// PEM file with the public key for RSA, ECDSA and EdDSA algorithms
+    pub public_key_file: Option<PathBuf>,
+    /// The required `iss` claim, if checked
+    pub issuer: Option<String>,
+    /// The required `aud` claim, if checked
+    pub audience: Option<String>,
+    /// Clock skew tolerated when checking `exp` and `nbf`, in seconds
+    pub leeway_secs: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            secret: None,
+            public_key_file: None,
+            issuer: None,
+            audience: None,
+            leeway_secs: 60,
+        }
+    }
+}
+
+/// Settings of the `mtls` backend.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MtlsConfig {
+    /// Header carrying the proxy's verification result, `SUCCESS` if the
+    /// certificate was verified (nginx's `$ssl_client_verify`)
+    pub verify_header: String,
+    /// Header carrying the certificate's subject DN (nginx's `$ssl_client_s_dn`)
+    pub subject_header: String,
+    /// Addresses of the proxies allowed to set the headers
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Common names admitted; any verified certificate is admitted if empty
+    pub allowed_subjects: Vec<String>,
+}
+
+impl Default for MtlsConfig {
+    fn default() -> Self {
+        Self {
+            verify_header: "X-SSL-Client-Verify".into(),
+            subject_header: "X-SSL-Client-S-DN".into(),
+            trusted_proxies: vec![
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ],
+            allowed_subjects: vec![],
+        }
+    }
+}
+
+/// Admits every request as a participant of the requested room.
+///
+/// A request presenting a bearer token is rejected, since the token was meant
+/// for a backend that is not configured.
+#[derive(Debug, Default)]
+pub struct OpenBackend;
+
+impl AuthBackend for OpenBackend {
+    fn name(&self) -> &'static str {
+        "open"
+    }
+
+    fn authenticate(&self, request: &Request) -> Result<Access, AuthError> {
+        if bearer_token(request).is_some() {
+            return Err(AuthError::InvalidCredentials("unrecognized token".into()));
+        }
+        Ok(Access {
+            room: request.get_param("room"),
+            ..Access::default()
+        })
+    }
+}
+
+/// Admits bearer tokens from a fixed list.
+///
+/// Only SHA-256 digests of the tokens are kept, so lookups do not leak the
+/// tokens through timing and a memory dump does not reveal them.
+#[derive(Debug)]
+pub struct StaticTokenBackend {
+    tokens: HashMap<[u8; 32], StaticToken>,
+}
+
+impl StaticTokenBackend {
+    /// Creates a backend accepting the configured tokens.
+    ///
+    /// # Returns
+    ///
+    /// The backend, or an error if a token is empty or listed twice
+    pub fn new(config: &StaticTokensConfig) -> anyhow::Result<Self> {
+        let mut tokens = HashMap::new();
+        for token in &config.tokens {
+            if token.token.is_empty() {
+                bail!("static token for '{}' is empty", token.subject);
+            }
+            let entry = StaticToken {
+                token: String::new(),
+                ..token.clone()
+            };
+            if tokens.insert(digest(&token.token), entry).is_some() {
+                bail!("static token for '{}' is listed twice", token.subject);
+            }
+        }
+        Ok(Self { tokens })
+    }
+}
+
+impl AuthBackend for StaticTokenBackend {
+    fn name(&self) -> &'static str {
+        "static_tokens"
+    }
+
+    fn authenticate(&self, request: &Request) -> Result<Access, AuthError> {
+        let token = bearer_token(request).ok_or(AuthError::MissingCredentials)?;
+        self.token_access(&token, request.get_param("room"))
+    }
+
+    fn reauthenticate(&self, access: &Access, credential: Option<&str>) -> Result<(), AuthError> {
+        let token = credential.ok_or(AuthError::MissingCredentials)?;
+        same_grant(access, &self.token_access(token, access.room.clone())?)
+    }
+}
+
+impl StaticTokenBackend {
+    /// Looks up a token and returns the access it grants to a room.
+    fn token_access(&self, token: &str, room: Option<String>) -> Result<Access, AuthError> {
+        let entry = self
+            .tokens
+            .get(&digest(token))
+            .ok_or_else(|| AuthError::InvalidCredentials("unknown token".into()))?;
+        Ok(Access {
+            role: entry.role,
+            room: resolve_room(entry.room.as_deref(), room)?,
+            subject: Some(entry.subject.clone()),
+            ..Access::default()
+        })
+    }
+}
+
+/// The JWT claims used by [`JwtBackend`], besides the validated ones.
+#[derive(Debug, Deserialize)]
+struct JwtClaims {
+    /// The authenticated identity
+    sub: Option<String>,
+    /// The only room the token grants access to, if restricted
+    room: Option<String>,
+    /// The granted role; participant if absent
+    role: Option<Role>,
+}
+
+/// Admits bearer JWTs signed by an identity provider.
+///
+/// The signature and `exp` are always checked, `iss` and `aud` when
+/// configured. The optional `room` and `role` claims restrict the access.
+pub struct JwtBackend {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtBackend {
+    /// Creates a backend validating tokens as configured.
+    ///
+    /// # Returns
+    ///
+    /// The backend, or an error if the key is missing or cannot be read
+    pub fn new(config: &JwtConfig) -> anyhow::Result<Self> {
+        let key = match config.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = match &config.secret {
+                    Some(secret) => secret.clone(),
+                    None => env::var(JWT_SECRET_ENV).with_context(|| {
+                        format!(
+                            "{:?} needs a secret or {}",
+                            config.algorithm, JWT_SECRET_ENV
+                        )
+                    })?,
+                };
+                if secret.is_empty() {
+                    bail!("the JWT secret is empty");
+                }
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            algorithm => {
+                let Some(path) = &config.public_key_file else {
+                    bail!("{:?} needs a public_key_file", algorithm);
+                };
+                let pem = fs::read(path)
+                    .with_context(|| format!("reading JWT public key {}", path.display()))?;
+                match algorithm {
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                    _ => DecodingKey::from_rsa_pem(&pem),
+                }
+                .with_context(|| format!("parsing JWT public key {}", path.display()))?
+            }
+        };
+
+        let mut validation = Validation::new(config.algorithm);
+        validation.leeway = config.leeway_secs;
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(Self { key, validation })
+    }
+}
+
+impl AuthBackend for JwtBackend {
+    fn name(&self) -> &'static str {
+        "jwt"
+    }
+
+    fn authenticate(&self, request: &Request) -> Result<Access, AuthError> {
+        let token = bearer_token(request).ok_or(AuthError::MissingCredentials)?;
+        self.token_access(&token, request.get_param("room"))
+    }
+
+    fn reauthenticate(&self, access: &Access, credential: Option<&str>) -> Result<(), AuthError> {
+        let token = credential.ok_or(AuthError::MissingCredentials)?;
+        same_grant(access, &self.token_access(token, access.room.clone())?)
+    }
+}
+
+impl JwtBackend {
+    /// Validates a token and returns the access it grants to a room.
+    fn token_access(&self, token: &str, room: Option<String>) -> Result<Access, AuthError> {
+        let claims = jsonwebtoken::decode::<JwtClaims>(token, &self.key, &self.validation)
+            .map_err(|e| AuthError::InvalidCredentials(e.to_string()))?
+            .claims;
+        Ok(Access {
+            role: claims.role.unwrap_or_default(),
+            room: resolve_room(claims.room.as_deref(), room)?,
+            subject: claims.sub,
+            ..Access::default()
+        })
+    }
+}
+
+/// Admits clients whose certificate was verified by a reverse proxy.
+///
+/// The proxy terminates TLS, requests a client certificate and forwards the
+/// verification result and subject in headers. They are only trusted from
+/// the configured proxy addresses, since any client could set them otherwise.
+/// The certificate's common name becomes the session's subject.
+#[derive(Debug)]
+pub struct MtlsBackend {
+    config: MtlsConfig,
+}
+
+impl MtlsBackend {
+    /// Creates a backend trusting the configured proxies.
+    pub fn new(config: MtlsConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl AuthBackend for MtlsBackend {
+    fn name(&self) -> &'static str {
+        "mtls"
+    }
+
+    fn authenticate(&self, request: &Request) -> Result<Access, AuthError> {
+        if !self
+            .config
+            .trusted_proxies
+            .contains(&request.remote_addr().ip())
+        {
+            return Err(AuthError::UntrustedProxy);
+        }
+        let verify = request
+            .header(&self.config.verify_header)
+            .ok_or(AuthError::MissingCredentials)?;
+        if !verify.eq_ignore_ascii_case("SUCCESS") {
+            return Err(AuthError::InvalidCredentials(format!(
+                "certificate verification {}",
+                verify
+            )));
+        }
+        let subject = request
+            .header(&self.config.subject_header)
+            .and_then(common_name)
+            .ok_or_else(|| AuthError::InvalidCredentials("no common name".into()))?;
+        if !self.config.allowed_subjects.is_empty()
+            && !self.config.allowed_subjects.contains(&subject)
+        {
+            return Err(AuthError::InvalidCredentials(format!(
+                "'{}' is not allowed",
+                subject
+            )));
+        }
+        Ok(Access {
+            room: request.get_param("room"),
+            subject: Some(subject),
+            ..Access::default()
+        })
+    }
+}
+
+/// Checks the requested room against the room granted by the credentials.
+///
+/// # Returns
+///
+/// The session's room: the granted one if restricted, otherwise the requested one
+fn resolve_room(
+    granted: Option<&str>,
+    requested: Option<String>,
+) -> Result<Option<String>, AuthError> {
+    match (granted, requested) {
+        (Some(granted), Some(requested)) if granted != requested => Err(AuthError::WrongRoom),
+        (Some(granted), _) => Ok(Some(granted.to_string())),
+        (None, requested) => Ok(requested),
+    }
+}
+
+/// Checks that a fresh credential grants the same identity and role as the
+/// one that established the session.
+fn same_grant(access: &Access, fresh: &Access) -> Result<(), AuthError> {
+    if fresh.subject != access.subject {
+        return Err(AuthError::InvalidCredentials(
+            "credential is for another subject".into(),
+        ));
+    }
+    if fresh.role != access.role {
+        return Err(AuthError::InvalidCredentials(
+            "credential grants another role".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Extracts the common name from a subject DN, in either the RFC 2253 form
+/// (`CN=rover-7,O=Fleet`) or the legacy OpenSSL form (`/O=Fleet/CN=rover-7`).
+fn common_name(dn: &str) -> Option<String> {
+    dn.split([',', '/'])
+        .filter_map(|part| part.trim().split_once('='))
+        .find(|(key, _)| key.eq_ignore_ascii_case("CN"))
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|cn| !cn.is_empty())
+}
+
+/// Returns the SHA-256 digest of a token.
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
@@ -0,0 +1,241 @@
+//! Time-limited guest access links
+//!
+//! Guest links grant an external party observer access to a single room until
+//! an expiry time. Tokens are HMAC-SHA256 signed by the server, so they can be
+//! verified without storing them, and can be revoked by ID before they expire.
+
+use std::{collections::HashSet, env, fmt};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::auth::{Access, Role};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Environment variable holding the hex-encoded signing secret.
+pub const GUEST_SECRET_ENV: &str = "ROVER_GUEST_SECRET";
+
+/// The longest a guest link stays valid, in seconds: a year.
+pub const MAX_TTL_SECS: i64 = 365 * 24 * 3600;
+
+/// Errors returned when a guest token cannot be accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuestError {
+    /// The token is not in the expected format
+    Malformed,
+    /// The signature does not match the claims
+    BadSignature,
+    /// The token's expiry time has passed
+    Expired,
+    /// The token was revoked by an administrator
+    Revoked,
+    /// The token grants access to a different room
+    WrongRoom,
+}
+
+impl fmt::Display for GuestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            GuestError::Malformed => "malformed guest token",
+            GuestError::BadSignature => "invalid guest token signature",
+            GuestError::Expired => "guest token expired",
+            GuestError::Revoked => "guest token revoked",
+            GuestError::WrongRoom => "guest token not valid for this room",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for GuestError {}
+
+/// The claims carried by a guest token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestClaims {
+    /// Unique ID of the link, used for revocation
+    pub id: String,
+    /// The room the guest may observe
+    pub room: String,
+    /// Expiry time, in seconds since the Unix epoch
+    pub exp: i64,
+}
+
+/// A newly issued guest link.
+#[derive(Debug, Clone, Serialize)]
+pub struct GuestLink {
+    /// Unique ID of the link, used for revocation
+    pub id: String,
+    /// The signed token to present during signaling
+    pub token: String,
+    /// The room the guest may observe
+    pub room: String,
+    /// Expiry time, in seconds since the Unix epoch
+    pub expires_at: i64,
+}
+
+/// Issues, verifies and revokes guest tokens.
+#[derive(Debug)]
+pub struct GuestAuthority {
+    secret: Vec<u8>,
+    revoked: HashSet<String>,
+}
+
+impl GuestAuthority {
+    /// Creates an authority signing with the given secret.
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            revoked: HashSet::new(),
+        }
+    }
+
+    /// Creates an authority using the secret from [`GUEST_SECRET_ENV`].
+    ///
+    /// If the variable is unset or not valid hex, a random secret is generated,
+    /// which invalidates all outstanding links when the server restarts.
+    pub fn from_env() -> Self {
+        let secret = env::var(GUEST_SECRET_ENV)
+            .ok()
+            .and_then(|s| hex_decode(&s))
+            .unwrap_or_else(|| {
+                let mut secret = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            });
+        Self::new(secret)
+    }
+
+    /// Issues a guest link for a room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room` - The room the guest may observe
+    /// * `ttl_secs` - How long the link stays valid, in seconds, at most
+    ///   [`MAX_TTL_SECS`]
+    pub fn issue(&self, room: &str, ttl_secs: i64) -> GuestLink {
+        let mut id = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut id);
+
+        let claims = GuestClaims {
+            id: hex_encode(&id),
+            room: room.to_string(),
+            exp: Utc::now().timestamp() + ttl_secs.clamp(0, MAX_TTL_SECS),
+        };
+        let body = hex_encode(&serde_json::to_vec(&claims).expect("claims to serialise"));
+        let token = format!("{}.{}", body, hex_encode(&self.sign(body.as_bytes())));
+
+        GuestLink {
+            id: claims.id,
+            token,
+            room: claims.room,
+            expires_at: claims.exp,
+        }
+    }
+
+    /// Verifies a guest token.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(GuestClaims)` - If the token is authentic, unexpired and not revoked
+    /// * `Err(GuestError)` - Otherwise
+    pub fn verify(&self, token: &str) -> Result<GuestClaims, GuestError> {
+        let (body, signature) = token.split_once('.').ok_or(GuestError::Malformed)?;
+        let signature = hex_decode(signature).ok_or(GuestError::Malformed)?;
+
+        let mut mac = self.mac();
+        mac.update(body.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| GuestError::BadSignature)?;
+
+        let claims: GuestClaims = hex_decode(body)
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(GuestError::Malformed)?;
+
+        self.check(&claims)?;
+        Ok(claims)
+    }
+
+    /// Checks that verified claims are still valid.
+    pub fn check(&self, claims: &GuestClaims) -> Result<(), GuestError> {
+        if self.revoked.contains(&claims.id) {
+            return Err(GuestError::Revoked);
+        }
+        if claims.exp <= Utc::now().timestamp() {
+            return Err(GuestError::Expired);
+        }
+        Ok(())
+    }
+
+    /// Verifies a guest token for a room and returns the resulting access.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token presented during signaling
+    /// * `room` - The requested room; defaults to the token's room
+    pub fn authorize(&self, token: &str, room: Option<&str>) -> Result<Access, GuestError> {
+        let claims = self.verify(token)?;
+        if room.is_some_and(|r| r != claims.room) {
+            return Err(GuestError::WrongRoom);
+        }
+
+        Ok(Access {
+            role: Role::Observer,
+            room: Some(claims.room),
+            guest_id: Some(claims.id),
+            expires_at: Some(claims.exp),
+            subject: None,
+        })
+    }
+
+    /// Revokes a guest link by ID.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the link was not already revoked
+    pub fn revoke(&mut self, id: &str) -> bool {
+        self.revoked.insert(id.to_string())
+    }
+
+    /// Returns `true` if a session admitted with the given access may stay.
+    ///
+    /// Sessions not admitted through a guest link are always allowed.
+    pub fn still_valid(&self, access: &Access) -> bool {
+        let revoked = access
+            .guest_id
+            .as_ref()
+            .is_some_and(|id| self.revoked.contains(id));
+        let expired = access
+            .expires_at
+            .is_some_and(|exp| exp <= Utc::now().timestamp());
+        !revoked && !expired
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key length")
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Encodes bytes as lowercase hex.
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a hex string, returning `None` if it is not valid hex.
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
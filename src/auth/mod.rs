@@ -0,0 +1,83 @@
+//! Access control for the signaling server
+//!
+//! This module defines the access granted to a session when it is created via
+//! the signaling endpoint, and the mechanisms used to grant it.
+
+use serde::{Deserialize, Serialize};
+
+pub mod backend;
+pub mod guest;
+pub mod turn;
+
+/// The role of a session within a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Full participant: may send data, commands and telemetry
+    #[default]
+    Participant,
+    /// Read-only observer: data it sends is dropped by the server
+    Observer,
+}
+
+/// Access granted to a session during signaling.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Access {
+    /// The role of the session
+    pub role: Role,
+    /// The room the session belongs to, if any
+    pub room: Option<String>,
+    /// The ID of the guest link used to join, if any
+    pub guest_id: Option<String>,
+    /// When the access expires, in seconds since the Unix epoch
+    pub expires_at: Option<i64>,
+    /// The identity established by the authentication backend, if any
+    pub subject: Option<String>,
+}
+
+impl Access {
+    /// Returns `true` if the session may only observe.
+    pub fn is_observer(&self) -> bool {
+        self.role == Role::Observer
+    }
+}
+
+/// Extracts a bearer token from a signaling request.
+///
+/// The token is read from the `Authorization: Bearer` header, falling back to
+/// the `token` query parameter so guest links can be shared as plain URLs.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request
+///
+/// # Returns
+///
+/// * `Some(String)` - The token, if one was supplied
+/// * `None` - If the request carries no token
+pub fn bearer_token(request: &rouille::Request) -> Option<String> {
+    request
+        .header("Authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_string())
+        .or_else(|| request.get_param("token"))
+}
+
+/// Checks a supplied token against the expected one, in constant time.
+///
+/// # Arguments
+///
+/// * `given` - The token supplied with the request, if any
+/// * `expected` - The token that grants access
+pub fn token_matches(given: Option<&str>, expected: &str) -> bool {
+    let Some(given) = given else {
+        return false;
+    };
+    let (given, expected) = (given.as_bytes(), expected.as_bytes());
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
@@ -0,0 +1,117 @@
+//! Short-lived TURN credentials
+//!
+//! Rather than storing a long-lived TURN password on every rover, the server
+//! shares a secret with the TURN server and mints time-limited credentials for
+//! each session, following the TURN REST API convention understood by coturn
+//! (`use-auth-secret`): the username is `<expiry>:<user>` and the password is
+//! the base64-encoded HMAC-SHA1 of the username, keyed with the shared secret.
+//! The TURN server verifies them without any per-rover state.
+
+use std::env;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::model::signaling::IceServer;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Environment variable holding the secret shared with the TURN server
+/// (coturn's `static-auth-secret`).
+pub const TURN_SECRET_ENV: &str = "ROVER_TURN_SECRET";
+
+/// Environment variable with the comma-separated TURN URLs to mint
+/// credentials for, e.g. `turn:turn.example.com:3478?transport=udp`.
+pub const TURN_URLS_ENV: &str = "ROVER_TURN_URLS";
+
+/// Environment variable with the credential lifetime in seconds.
+pub const TURN_TTL_ENV: &str = "ROVER_TURN_TTL";
+
+/// Default credential lifetime, in seconds.
+const DEFAULT_TTL_SECS: i64 = 3600;
+
+/// Mints TURN credentials from a shared secret.
+#[derive(Debug, Clone)]
+pub struct TurnMinter {
+    secret: Vec<u8>,
+    urls: Vec<String>,
+    ttl_secs: i64,
+}
+
+impl TurnMinter {
+    /// Creates a minter for the given TURN server.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The secret shared with the TURN server
+    /// * `urls` - The TURN URLs the credentials are valid for
+    /// * `ttl_secs` - How long minted credentials stay valid, in seconds
+    pub fn new(secret: Vec<u8>, urls: Vec<String>, ttl_secs: i64) -> Self {
+        Self {
+            secret,
+            urls,
+            ttl_secs,
+        }
+    }
+
+    /// Creates a minter from [`TURN_SECRET_ENV`], [`TURN_URLS_ENV`] and
+    /// [`TURN_TTL_ENV`].
+    ///
+    /// # Returns
+    ///
+    /// * `Some(TurnMinter)` - If both a secret and at least one URL are set
+    /// * `None` - If TURN is not configured
+    pub fn from_env() -> Option<Self> {
+        let secret = env::var(TURN_SECRET_ENV).ok().filter(|s| !s.is_empty())?;
+        let urls: Vec<String> = env::var(TURN_URLS_ENV)
+            .ok()?
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect();
+        if urls.is_empty() {
+            return None;
+        }
+        let ttl_secs = env::var(TURN_TTL_ENV)
+            .ok()
+            .and_then(|ttl| ttl.parse().ok())
+            .filter(|ttl| *ttl > 0)
+            .unwrap_or(DEFAULT_TTL_SECS);
+        Some(Self::new(secret.into_bytes(), urls, ttl_secs))
+    }
+
+    /// Returns the TURN URLs the minted credentials are valid for.
+    pub fn urls(&self) -> &[String] {
+        &self.urls
+    }
+
+    /// Mints credentials for a user, valid from now for the configured lifetime.
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - Identifies the session in the TURN server's logs, e.g. an alias
+    ///
+    /// # Returns
+    ///
+    /// An ICE server entry with the TURN URLs and the minted credentials
+    pub fn mint(&self, user: &str) -> IceServer {
+        let expires_at = Utc::now().timestamp() + self.ttl_secs;
+        let username = format!("{}:{}", expires_at, user);
+        IceServer {
+            urls: self.urls.clone(),
+            credential: Some(self.credential(&username)),
+            username: Some(username),
+            expires_at: Some(expires_at),
+        }
+    }
+
+    /// Computes the password for a REST API username.
+    fn credential(&self, username: &str) -> String {
+        let mut mac = HmacSha1::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(username.as_bytes());
+        STANDARD.encode(mac.finalize().into_bytes())
+    }
+}
@@ -0,0 +1,297 @@
+//! MAVLink bridge
+//!
+//! Carries MAVLink between an autopilot and ground-control software over the
+//! peer's connection. On the rover the bridge reads the autopilot's serial
+//! port; on the base station it exchanges UDP datagrams with the
+//! ground-control software, e.g. QGroundControl listening on port 14550.
+//! Either side splits what it reads into frames (see
+//! [`crate::model::mavlink`]), sends each frame as one message on the MAVLink
+//! channel, and writes the frames the remote sends to its own end, so the
+//! ground-control software sees the autopilot as if it were on the LAN.
+//!
+//! Frames read while the channel is not open are dropped; MAVLink expects a
+//! lossy link, and the autopilot sends its heartbeat and telemetry again.
+//! The channel is reliable and ordered unless configured otherwise under
+//! `[peer.channel_options]`; unordered delivery without retransmissions
+//! keeps stale telemetry from delaying fresh frames.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    error::RoverRtcError,
+    model::{
+        bus::EventTopic,
+        mavlink::{MavlinkFramer, MavlinkHeader, MAVLINK_CHANNEL},
+    },
+    peer::{PeerEvent, PeerHandle},
+    util::serial,
+};
+
+/// Read timeout of the UDP socket, after which the reader checks whether it
+/// should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// MAVLink bridge settings, the `[peer.mavlink]` configuration section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MavlinkConfig {
+    /// Bridge MAVLink over the peer's connection
+    pub enabled: bool,
+    /// Label of the data channel carrying the frames
+    pub channel: String,
+    /// Serial device of the autopilot; the UDP socket is used if unset
+    pub serial: Option<PathBuf>,
+    /// Baud rate of the serial device
+    pub baud: u32,
+    /// Local address of the UDP socket for the ground-control software
+    pub udp_bind: SocketAddr,
+    /// Where frames go until the ground-control software sends one; later
+    /// frames go to where it last sent from
+    pub udp_target: Option<SocketAddr>,
+}
+
+impl Default for MavlinkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            channel: MAVLINK_CHANNEL.to_string(),
+            serial: None,
+            baud: 57_600,
+            udp_bind: SocketAddr::from(([0, 0, 0, 0], 0)),
+            udp_target: Some(SocketAddr::from(([127, 0, 0, 1], 14550))),
+        }
+    }
+}
+
+impl MavlinkConfig {
+    /// Checks that the channel and the end of the bridge are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.channel.is_empty() {
+            return Err("channel must not be empty".into());
+        }
+        if self.baud == 0 {
+            return Err("baud must be positive".into());
+        }
+        if self.serial.is_none() && self.udp_target.is_none() && self.udp_bind.port() == 0 {
+            return Err("udp_bind needs a port when udp_target is unset".into());
+        }
+        Ok(())
+    }
+}
+
+/// The local end of the bridge.
+#[derive(Debug)]
+enum Endpoint {
+    /// The autopilot's serial port
+    Serial(File),
+    /// The ground-control software's UDP socket, and where frames go
+    Udp {
+        socket: UdpSocket,
+        remote: Mutex<Option<SocketAddr>>,
+    },
+}
+
+impl Endpoint {
+    fn open(config: &MavlinkConfig) -> io::Result<Self> {
+        match &config.serial {
+            Some(path) => {
+                let port = serial::open(path, config.baud)?;
+                info!(
+                    "MAVLink: Bridging {} at {} baud",
+                    path.display(),
+                    config.baud
+                );
+                Ok(Endpoint::Serial(port))
+            }
+            None => {
+                let socket = UdpSocket::bind(config.udp_bind)?;
+                socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                info!(
+                    "MAVLink: Bridging UDP on {}{}",
+                    socket.local_addr()?,
+                    config
+                        .udp_target
+                        .map(|target| format!(" to {}", target))
+                        .unwrap_or_default()
+                );
+                Ok(Endpoint::Udp {
+                    socket,
+                    remote: Mutex::new(config.udp_target),
+                })
+            }
+        }
+    }
+
+    /// Reads what arrived, waiting at most [`POLL_INTERVAL`] or the serial
+    /// port's timeout.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes read, 0 if nothing arrived in time
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Endpoint::Serial(port) => (&mut &*port).read(buf),
+            Endpoint::Udp { socket, remote } => match socket.recv_from(buf) {
+                Ok((len, source)) => {
+                    let mut remote = remote.lock().expect("MAVLink remote lock");
+                    if *remote != Some(source) {
+                        info!("MAVLink: Ground-control software at {}", source);
+                        *remote = Some(source);
+                    }
+                    Ok(len)
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    Ok(0)
+                }
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Writes a frame the remote sent; dropped if no ground-control software
+    /// is known yet.
+    fn write(&self, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Endpoint::Serial(port) => (&mut &*port).write_all(frame),
+            Endpoint::Udp { socket, remote } => {
+                let remote = *remote.lock().expect("MAVLink remote lock");
+                match remote {
+                    Some(addr) => socket.send_to(frame, addr).map(|_| ()),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Bridges MAVLink between the local end and the peer's MAVLink channel.
+///
+/// The bridge runs until dropped.
+#[derive(Debug)]
+pub struct MavlinkBridge {
+    stop: Arc<AtomicBool>,
+    writer: tokio::task::JoinHandle<()>,
+}
+
+impl MavlinkBridge {
+    /// Opens the local end and starts bridging it with a peer.
+    ///
+    /// Must be called within a Tokio runtime, which writes the frames the
+    /// remote sends. The peer must open the configured channel, see
+    /// [`PeerConfig::mavlink`](crate::peer::PeerConfig::mavlink).
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The bridge settings
+    /// * `handle` - The handle of the peer carrying the frames
+    ///
+    /// # Returns
+    ///
+    /// The bridge, or an error if the serial port or UDP socket could not
+    /// be opened
+    pub fn start(config: &MavlinkConfig, handle: &PeerHandle) -> Result<Self, RoverRtcError> {
+        let endpoint = Arc::new(Endpoint::open(config)?);
+        let stop = Arc::new(AtomicBool::new(false));
+        let open = Arc::new(AtomicBool::new(false));
+        {
+            let open = open.clone();
+            let label = config.channel.clone();
+            let topics = [EventTopic::Lifecycle];
+            handle
+                .events()
+                .subscribe(&topics, move |event| match event {
+                    PeerEvent::ChannelOpen { label: opened } if *opened == label => {
+                        open.store(true, Ordering::Relaxed)
+                    }
+                    PeerEvent::Disconnected => open.store(false, Ordering::Relaxed),
+                    _ => {}
+                });
+        }
+        {
+            let endpoint = endpoint.clone();
+            let stop = stop.clone();
+            let handle = handle.clone();
+            let label = config.channel.clone();
+            thread::Builder::new()
+                .name("mavlink".to_string())
+                .spawn(move || read_frames(&endpoint, &handle, &label, &open, &stop))?;
+        }
+        let mut frames = handle.subscribe(&config.channel);
+        let writer = tokio::spawn(async move {
+            while let Some(frame) = frames.recv().await {
+                if let Err(e) = endpoint.write(&frame) {
+                    warn!("MAVLink: Failed to write a frame: {}", e);
+                }
+            }
+        });
+        Ok(Self { stop, writer })
+    }
+}
+
+impl Drop for MavlinkBridge {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.writer.abort();
+    }
+}
+
+/// Sends the frames read from the local end on the channel until stopped.
+fn read_frames(
+    endpoint: &Endpoint,
+    handle: &PeerHandle,
+    label: &str,
+    open: &AtomicBool,
+    stop: &AtomicBool,
+) {
+    let mut framer = MavlinkFramer::new();
+    let mut buf = vec![0; 65_536];
+    let mut sent = 0u64;
+    while !stop.load(Ordering::Relaxed) {
+        let len = match endpoint.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("MAVLink: Bridge stopped: {}", e);
+                return;
+            }
+        };
+        for frame in framer.push(&buf[..len]) {
+            if !open.load(Ordering::Relaxed) {
+                continue;
+            }
+            if sent == 0 {
+                if let Some(header) = MavlinkHeader::parse(&frame) {
+                    debug!(
+                        "MAVLink: First frame from system {} (v{})",
+                        header.system_id, header.version
+                    );
+                }
+            }
+            sent += 1;
+            handle.send(label, frame);
+        }
+    }
+    debug!(
+        "MAVLink: Sent {} frames, skipped {} bytes",
+        sent,
+        framer.skipped()
+    );
+}
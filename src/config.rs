@@ -0,0 +1,884 @@
+//! Configuration of the server, the peer and network discovery
+//!
+//! Settings are read from an optional TOML or YAML file, then overridden by
+//! environment variables, then validated. Every setting has a default, so an
+//! empty file (or no file at all) yields a working local setup:
+//!
+//! ```toml
+//! [server]
+//! http_addr = "0.0.0.0:3000"
+//! udp_port = 50000
+//!
+//! [peer]
+//! signaling_url = "http://172.17.0.1:3000"
+//! alias = "rover-7"
+//! channels = ["video", "telemetry"]
+//!
+//! [peer.channel_options.telemetry]
+//! ordered = false
+//! max_retransmits = 0
+//!
+//! [server.tls]
+//! cert_file = "/etc/rover/signaling.crt"
+//! key_file = "/etc/rover/signaling.key"
+//!
+//! [server.auth]
+//! backend = "static_tokens"
+//! tokens = [{ token = "s3cret", subject = "rover-7" }]
+//!
+//! [server.session]
+//! lifetime_secs = 3600
+//!
+//! [network]
+//! skip_interfaces = ["docker", "br-", "veth", "virbr"]
+//! ip_family = "both"
+//! wait_secs = 60
+//! mdns_timeout_ms = 1000
+//!
+//! [network.socket]
+//! recv_buffer = 4194304
+//! send_buffer = 4194304
+//!
+//! [network.pcap]
+//! enabled = false
+//! path = "/var/log/rover/webrtc.pcapng"
+//!
+//! [protocol]
+//! allow_fallback = false
+//!
+//! [log]
+//! filter = "info,str0m=warn"
+//!
+//! [crash]
+//! dir = "/var/lib/rover/crash-reports"
+//!
+//! [operator]
+//! rovers = ["rover-7", "rover-8"]
+//! ```
+//!
+//! Named profiles bundle settings for an environment, e.g. a rover on an LTE
+//! modem or on the lab network, and are applied over the rest of the file
+//! when selected with `--profile` or [`PROFILE_ENV`]. Besides the profiles a
+//! file defines under `[profiles.<name>]`, the built-in `field-lte`,
+//! `lab-lan` and `demo` profiles are always available; a file's profile of
+//! the same name replaces the built-in one:
+//!
+//! ```toml
+//! [profiles.field-lte.protocol.heartbeat]
+//! interval_ms = 5000
+//!
+//! [profiles.field-lte.log]
+//! filter = "warn"
+//! ```
+//!
+//! Feature-specific settings such as geofences, guest links and TURN secrets
+//! keep their own environment variables, documented in their modules.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    env, fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::crash::CrashConfig;
+use crate::model::channel::ChannelOptions;
+use crate::model::control::ProtocolConfig;
+use crate::model::registry::is_valid_alias;
+use crate::model::rules::Rule;
+use crate::model::signaling::{ice_servers_from_env, IceServer, ICE_SERVERS_ENV};
+use crate::operator::OperatorConfig;
+use crate::peer::PeerConfig;
+use crate::server::{ServerConfig, TlsConfig};
+use crate::util::netsim::NetsimConfig;
+use crate::util::pcap::PcapConfig;
+use crate::util::sockopt::SocketConfig;
+
+/// Environment variable with the path of the configuration file.
+pub const CONFIG_ENV: &str = "ROVER_CONFIG";
+
+/// Environment variable naming the profile to apply, see [`Config::with_profile`].
+pub const PROFILE_ENV: &str = "ROVER_PROFILE";
+
+/// Environment variable overriding [`ServerConfig::http_addr`].
+pub const HTTP_ADDR_ENV: &str = "ROVER_HTTP_ADDR";
+
+/// Environment variable overriding [`ServerConfig::udp_host`].
+pub const UDP_HOST_ENV: &str = "ROVER_UDP_HOST";
+
+/// Environment variable overriding [`ServerConfig::udp_port`].
+pub const UDP_PORT_ENV: &str = "ROVER_UDP_PORT";
+
+/// Environment variable overriding [`ServerConfig::poll_workers`].
+pub const POLL_WORKERS_ENV: &str = "ROVER_POLL_WORKERS";
+
+/// Environment variable overriding [`PeerConfig::signaling_url`]; a `ws://`
+/// URL selects the WebSocket transport with trickle ICE.
+pub const SIGNALING_URL_ENV: &str = "ROVER_SIGNALING_URL";
+
+/// Environment variable overriding [`PeerConfig::alias`].
+pub const ALIAS_ENV: &str = "ROVER_ALIAS";
+
+/// Environment variable overriding [`PeerConfig::channels`], comma-separated.
+pub const CHANNELS_ENV: &str = "ROVER_CHANNELS";
+
+/// Environment variable overriding [`PeerConfig::auth_token`].
+pub const AUTH_TOKEN_ENV: &str = "ROVER_AUTH_TOKEN";
+
+/// Environment variable with the server's TLS certificate chain; requires
+/// [`TLS_KEY_ENV`] and overrides [`ServerConfig::tls`].
+pub const TLS_CERT_ENV: &str = "ROVER_TLS_CERT";
+
+/// Environment variable with the private key of [`TLS_CERT_ENV`].
+pub const TLS_KEY_ENV: &str = "ROVER_TLS_KEY";
+
+/// Environment variable overriding [`PeerConfig::ca_file`].
+pub const CA_FILE_ENV: &str = "ROVER_CA_FILE";
+
+/// Environment variable overriding [`NetworkConfig::wait_secs`].
+pub const NETWORK_WAIT_ENV: &str = "ROVER_NETWORK_WAIT_SECS";
+
+/// IP versions used for host addresses and candidates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    /// IPv4 only
+    #[default]
+    V4,
+    /// IPv6 only
+    V6,
+    /// Both, on a dual-stack socket; the server prefers an IPv4 host address
+    Both,
+}
+
+impl IpFamily {
+    /// Returns `true` if addresses of the IP version of `ip` are used.
+    pub fn allows(self, ip: &IpAddr) -> bool {
+        match self {
+            Self::V4 => ip.is_ipv4(),
+            Self::V6 => ip.is_ipv6(),
+            Self::Both => true,
+        }
+    }
+}
+
+/// Network interface discovery settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Prefixes of interface names never selected as the server's host
+    /// address, e.g. container bridges
+    pub skip_interfaces: Vec<String>,
+    /// Address used to check that an interface has internet access; nothing
+    /// is sent to it
+    pub connectivity_probe: String,
+    /// Address used to check that an IPv6 interface has internet access
+    pub connectivity_probe_v6: String,
+    /// IP versions of the host addresses and candidates
+    pub ip_family: IpFamily,
+    /// Also use link-local IPv6 addresses (`fe80::/10`), which only reach
+    /// hosts on the same link
+    pub link_local_v6: bool,
+    /// Seconds the peer and the server wait at startup for an interface to
+    /// get a usable address, e.g. while the rover boots; 0 fails at once
+    pub wait_secs: u64,
+    /// Milliseconds to wait for the address of a remote `.local` candidate
+    /// over mDNS; 0 leaves such candidates unresolved
+    pub mdns_timeout_ms: u64,
+    /// Options of the peer's and the server's WebRTC socket, the
+    /// `[network.socket]` section
+    pub socket: SocketConfig,
+    /// Writing of the WebRTC socket's traffic to a pcapng file, the
+    /// `[network.pcap]` section
+    pub pcap: PcapConfig,
+    /// Simulated impairment of the WebRTC socket's traffic, for testing, the
+    /// `[network.netsim]` section
+    pub netsim: NetsimConfig,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            skip_interfaces: ["docker", "br-", "veth", "virbr"]
+                .map(String::from)
+                .to_vec(),
+            connectivity_probe: "8.8.8.8:53".into(),
+            connectivity_probe_v6: "[2001:4860:4860::8888]:53".into(),
+            ip_family: IpFamily::V4,
+            link_local_v6: false,
+            wait_secs: 60,
+            mdns_timeout_ms: 1000,
+            socket: SocketConfig::default(),
+            pcap: PcapConfig::default(),
+            netsim: NetsimConfig::default(),
+        }
+    }
+}
+
+/// Logging settings, the `[log]` configuration section.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `tracing` filter directives of the command-line binary, e.g.
+    /// `info,str0m=warn`; `RUST_LOG` takes precedence
+    pub filter: Option<String>,
+}
+
+/// Profiles every configuration has, as TOML.
+const BUILTIN_PROFILES: &[(&str, &str)] = &[
+    (
+        // A rover on a cellular modem: tolerant heartbeats, batched and
+        // rate-controlled traffic, quick reaction to interface and address changes
+        "field-lte",
+        r#"
+[network]
+ip_family = "both"
+
+[peer]
+interface_scan_secs = 2
+mapping_check_secs = 15
+
+[peer.reconnect]
+initial_delay_ms = 1000
+max_delay_ms = 60000
+
+[server]
+health_check_secs = 10
+
+[protocol]
+features = ["batching", "rate_control", "heartbeat"]
+
+[protocol.heartbeat]
+interval_ms = 2000
+timeout_ms = 6000
+max_missed = 4
+
+[log]
+filter = "info,str0m=warn"
+"#,
+    ),
+    (
+        // A rover on the lab network: fast failure detection, verbose logs
+        "lab-lan",
+        r#"
+[peer.reconnect]
+initial_delay_ms = 200
+max_delay_ms = 5000
+
+[server]
+health_check_secs = 2
+
+[protocol]
+features = ["heartbeat"]
+
+[protocol.heartbeat]
+interval_ms = 250
+timeout_ms = 1000
+max_missed = 3
+
+[log]
+filter = "debug"
+"#,
+    ),
+    (
+        // A short demonstration: frequent messages, gives up quickly
+        "demo",
+        r#"
+[peer]
+message_interval_secs = 1
+
+[peer.reconnect]
+max_attempts = 3
+
+[log]
+filter = "info,str0m=warn"
+"#,
+    ),
+];
+
+/// The complete configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Signaling server settings
+    pub server: ServerConfig,
+    /// Peer settings
+    pub peer: PeerConfig,
+    /// Settings of the operator connecting to several rovers
+    pub operator: OperatorConfig,
+    /// Network discovery settings, shared by both sides
+    pub network: NetworkConfig,
+    /// Protocol negotiation settings, shared by both sides
+    pub protocol: ProtocolConfig,
+    /// Logging settings of the command-line binary
+    pub log: LogConfig,
+    /// Crash reporting settings, shared by both sides
+    pub crash: CrashConfig,
+    /// Named presets of any of the settings above, applied over them when
+    /// selected, see [`Config::with_profile`]
+    #[serde(skip_serializing)]
+    pub profiles: BTreeMap<String, serde_json::Value>,
+}
+
+impl Config {
+    /// Loads the configuration.
+    ///
+    /// The file is taken from `path`, falling back to [`CONFIG_ENV`]; without
+    /// either, the defaults are used. The profile named by [`PROFILE_ENV`], if
+    /// any, is applied, then the environment overrides, and the result is
+    /// validated.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of a `.toml`, `.yaml` or `.yml` file, if any
+    ///
+    /// # Returns
+    ///
+    /// The configuration, or an error naming the file or setting at fault
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        Self::load_profile(path, None)
+    }
+
+    /// Loads the configuration like [`Config::load`], applying a profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of a `.toml`, `.yaml` or `.yml` file, if any
+    /// * `profile` - The profile to apply; defaults to [`PROFILE_ENV`]
+    ///
+    /// # Returns
+    ///
+    /// The configuration, or an error naming the file, profile or setting at fault
+    pub fn load_profile(path: Option<&Path>, profile: Option<&str>) -> anyhow::Result<Self> {
+        let from_env = env::var(CONFIG_ENV).ok();
+        let mut config = match path.or(from_env.as_deref().map(Path::new)) {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        let profile_env = env::var(PROFILE_ENV).ok();
+        if let Some(name) = profile.or(profile_env.as_deref()) {
+            config = config.with_profile(name)?;
+        }
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a configuration file, choosing the format by its extension.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(anyhow::Error::from),
+            Some("yaml" | "yml") => serde_yaml::from_str(&text).map_err(anyhow::Error::from),
+            _ => bail!(
+                "config file {} must have a .toml, .yaml or .yml extension",
+                path.display()
+            ),
+        };
+        config.with_context(|| format!("parsing config file {}", path.display()))
+    }
+
+    /// Applies a profile over the settings.
+    ///
+    /// The profile's settings replace those of the configuration; sections
+    /// and tables are merged, so a profile only lists what it changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - A profile of the configuration, or a built-in one
+    ///
+    /// # Returns
+    ///
+    /// The configuration with the profile applied, or an error if there is
+    /// no such profile or its settings are invalid
+    pub fn with_profile(self, name: &str) -> anyhow::Result<Self> {
+        let preset = match self.profiles.get(name) {
+            Some(preset) => preset.clone(),
+            None => match BUILTIN_PROFILES.iter().find(|(n, _)| *n == name) {
+                Some((_, text)) => {
+                    toml::from_str(text).with_context(|| format!("built-in profile '{}'", name))?
+                }
+                None => bail!(
+                    "unknown profile '{}'; available: {}",
+                    name,
+                    self.profile_names().join(", ")
+                ),
+            },
+        };
+        if preset.get("profiles").is_some() {
+            bail!("profiles.{} must not define profiles", name);
+        }
+
+        let mut merged = serde_json::to_value(&self).context("serializing the configuration")?;
+        merge_settings(&mut merged, preset);
+        let mut config: Self =
+            serde_json::from_value(merged).with_context(|| format!("profiles.{}", name))?;
+        config.profiles = self.profiles;
+        Ok(config)
+    }
+
+    /// Returns the names of the built-in profiles and those of the
+    /// configuration, sorted.
+    pub fn profile_names(&self) -> Vec<String> {
+        let builtin = BUILTIN_PROFILES.iter().map(|(name, _)| name.to_string());
+        let names: BTreeSet<String> = builtin.chain(self.profiles.keys().cloned()).collect();
+        names.into_iter().collect()
+    }
+
+    /// Applies the environment variable overrides.
+    pub fn apply_env(&mut self) -> anyhow::Result<()> {
+        if let Ok(addr) = env::var(HTTP_ADDR_ENV) {
+            self.server.http_addr = addr;
+        }
+        if let Ok(host) = env::var(UDP_HOST_ENV) {
+            self.server.udp_host = Some(parse_env(UDP_HOST_ENV, &host)?);
+        }
+        if let Ok(port) = env::var(UDP_PORT_ENV) {
+            self.server.udp_port = parse_env(UDP_PORT_ENV, &port)?;
+        }
+        if let Ok(workers) = env::var(POLL_WORKERS_ENV) {
+            self.server.poll_workers = Some(parse_env(POLL_WORKERS_ENV, &workers)?);
+        }
+        if env::var(ICE_SERVERS_ENV).is_ok() {
+            let servers = ice_servers_from_env().context(ICE_SERVERS_ENV)?;
+            self.server.ice_servers = servers.clone();
+            self.peer.ice_servers = servers;
+        }
+        if let Ok(url) = env::var(SIGNALING_URL_ENV) {
+            self.peer.signaling_url = url;
+        }
+        if let Ok(alias) = env::var(ALIAS_ENV) {
+            self.peer.alias = Some(alias);
+        }
+        if let Ok(channels) = env::var(CHANNELS_ENV) {
+            self.peer.channels = channels
+                .split(',')
+                .map(str::trim)
+                .filter(|c| !c.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Ok(token) = env::var(AUTH_TOKEN_ENV) {
+            self.peer.auth_token = Some(token);
+        }
+        match (env::var(TLS_CERT_ENV), env::var(TLS_KEY_ENV)) {
+            (Ok(cert), Ok(key)) => {
+                self.server.tls = Some(TlsConfig {
+                    cert_file: cert.into(),
+                    key_file: key.into(),
+                })
+            }
+            (Err(_), Err(_)) => {}
+            _ => bail!("{} and {} must be set together", TLS_CERT_ENV, TLS_KEY_ENV),
+        }
+        if let Ok(path) = env::var(CA_FILE_ENV) {
+            self.peer.ca_file = Some(path.into());
+        }
+        if let Ok(secs) = env::var(NETWORK_WAIT_ENV) {
+            self.network.wait_secs = parse_env(NETWORK_WAIT_ENV, &secs)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that every setting is usable.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.server
+            .http_addr
+            .parse::<SocketAddr>()
+            .with_context(|| format!("server.http_addr '{}'", self.server.http_addr))?;
+        if self.server.poll_workers == Some(0) {
+            bail!("server.poll_workers must be at least 1");
+        }
+        if self.server.health_check_secs == 0 {
+            bail!("server.health_check_secs must be at least 1");
+        }
+        self.server
+            .loop_budget
+            .validate()
+            .map_err(|e| anyhow!("server.loop_budget.{}", e))?;
+        validate_ice_servers("server.ice_servers", &self.server.ice_servers)?;
+        self.server.auth.build().context("server.auth")?;
+        if let Some(lifetime) = self.server.session.lifetime_secs {
+            if lifetime == 0 {
+                bail!("server.session.lifetime_secs must be at least 1");
+            }
+            if self.server.session.refresh_before_secs >= lifetime {
+                bail!("server.session.refresh_before_secs must be less than lifetime_secs");
+            }
+        }
+        validate_channel_options("server.channels", &self.server.channels)?;
+        self.server
+            .send_queue
+            .validate()
+            .map_err(|e| anyhow!("server.send_queue.{}", e))?;
+        self.server
+            .routing
+            .validate()
+            .map_err(|e| anyhow!("server.routing.{}", e))?;
+        self.server
+            .transfer
+            .validate()
+            .map_err(|e| anyhow!("server.transfer.{}", e))?;
+        self.server
+            .failover
+            .validate()
+            .map_err(|e| anyhow!("server.failover.{}", e))?;
+        self.server
+            .recording
+            .validate()
+            .map_err(|e| anyhow!("server.recording.{}", e))?;
+        self.server
+            .health
+            .validate()
+            .map_err(|e| anyhow!("server.health.{}", e))?;
+        self.server
+            .provisioning
+            .validate()
+            .map_err(|e| anyhow!("server.provisioning.{}", e))?;
+        for (i, webhook) in self.server.webhooks.iter().enumerate() {
+            webhook
+                .validate()
+                .map_err(|e| anyhow!("server.webhooks[{}].{}", i, e))?;
+        }
+        validate_rules("server.rules", &self.server.rules)?;
+        if let Some(tls) = &self.server.tls {
+            tls.load().context("server.tls")?;
+        }
+
+        let url = reqwest::Url::parse(&self.peer.signaling_url)
+            .with_context(|| format!("peer.signaling_url '{}'", self.peer.signaling_url))?;
+        if !matches!(url.scheme(), "http" | "https" | "ws" | "wss") {
+            bail!(
+                "peer.signaling_url must be an http, https, ws or wss URL, got '{}'",
+                self.peer.signaling_url
+            );
+        }
+        if let Some(alias) = &self.peer.alias {
+            if !is_valid_alias(alias) {
+                bail!("peer.alias '{}' is not a valid alias", alias);
+            }
+        }
+        let mut labels = HashSet::new();
+        for label in &self.peer.channels {
+            if label.is_empty() || label.len() > u16::MAX as usize {
+                bail!("peer.channels contains an invalid label '{}'", label);
+            }
+            if !labels.insert(label) {
+                bail!("peer.channels contains '{}' twice", label);
+            }
+        }
+        validate_channel_options("peer.channel_options", &self.peer.channel_options)?;
+        self.peer
+            .duplicate
+            .validate()
+            .map_err(|e| anyhow!("peer.duplicate.{}", e))?;
+        if let Some(label) = self
+            .peer
+            .duplicate
+            .channels
+            .iter()
+            .find(|label| !labels.contains(label))
+        {
+            bail!(
+                "peer.duplicate.channels names '{}', which is not in peer.channels",
+                label
+            );
+        }
+        if let Some(target) = &self.peer.mesh_target {
+            if !is_valid_alias(target) {
+                bail!("peer.mesh_target '{}' is not a valid alias", target);
+            }
+            if self.peer.mesh_listen {
+                bail!("peer.mesh_target and peer.mesh_listen are exclusive");
+            }
+        }
+        if self.peer.mesh_listen && self.peer.alias.is_none() {
+            bail!("peer.mesh_listen requires peer.alias to listen under");
+        }
+        if self.peer.discovery.enabled && !self.peer.mesh_listen && self.peer.mesh_target.is_none()
+        {
+            bail!("peer.discovery requires peer.mesh_listen or peer.mesh_target");
+        }
+        if (self.peer.mesh_listen || self.peer.mesh_target.is_some())
+            && !self.peer.discovery.enabled
+            && matches!(url.scheme(), "ws" | "wss")
+        {
+            bail!("peer mesh mode requires an http or https signaling_url");
+        }
+        let mut ids = HashSet::new();
+        for connection in &self.peer.connections {
+            if !is_valid_alias(&connection.id) {
+                bail!(
+                    "peer.connections contains an invalid id '{}'",
+                    connection.id
+                );
+            }
+            if !ids.insert(&connection.id) {
+                bail!("peer.connections contains '{}' twice", connection.id);
+            }
+            let url = reqwest::Url::parse(&connection.signaling_url).with_context(|| {
+                format!(
+                    "peer.connections.{}.signaling_url '{}'",
+                    connection.id, connection.signaling_url
+                )
+            })?;
+            if !matches!(url.scheme(), "http" | "https" | "ws" | "wss") {
+                bail!(
+                    "peer.connections.{}.signaling_url must be an http, https, ws or wss URL",
+                    connection.id
+                );
+            }
+            for alias in [&connection.alias, &connection.mesh_target]
+                .into_iter()
+                .flatten()
+            {
+                if !is_valid_alias(alias) {
+                    bail!(
+                        "peer.connections.{} names an invalid alias '{}'",
+                        connection.id,
+                        alias
+                    );
+                }
+            }
+            if connection.mesh_target.is_some() && matches!(url.scheme(), "ws" | "wss") {
+                bail!(
+                    "peer.connections.{} mesh mode requires an http or https signaling_url",
+                    connection.id
+                );
+            }
+            if connection.interface.as_deref() == Some("") {
+                bail!(
+                    "peer.connections.{}.interface must not be empty",
+                    connection.id
+                );
+            }
+        }
+        self.peer
+            .bonding
+            .validate()
+            .map_err(|e| anyhow!("peer.bonding.{}", e))?;
+        if self.peer.bonding.enabled && !ids.contains(&self.peer.bonding.backup) {
+            bail!(
+                "peer.bonding.backup '{}' names none of peer.connections",
+                self.peer.bonding.backup
+            );
+        }
+        self.peer
+            .health
+            .validate()
+            .map_err(|e| anyhow!("peer.health.{}", e))?;
+        if self.peer.message_interval_secs == 0 || self.peer.interface_scan_secs == 0 {
+            bail!("peer intervals must be at least 1 second");
+        }
+        validate_ice_servers("peer.ice_servers", &self.peer.ice_servers)?;
+        self.peer
+            .reconnect
+            .validate()
+            .map_err(|e| anyhow!("peer.reconnect.{}", e))?;
+        self.peer
+            .rate_control
+            .validate()
+            .map_err(|e| anyhow!("peer.rate_control.{}", e))?;
+        self.peer
+            .discovery
+            .validate()
+            .map_err(|e| anyhow!("peer.discovery.{}", e))?;
+        self.peer
+            .failover
+            .validate()
+            .map_err(|e| anyhow!("peer.failover.{}", e))?;
+        self.peer
+            .recording
+            .validate()
+            .map_err(|e| anyhow!("peer.recording.{}", e))?;
+        self.peer
+            .pmtu
+            .validate()
+            .map_err(|e| anyhow!("peer.pmtu.{}", e))?;
+        self.peer
+            .capture
+            .validate()
+            .map_err(|e| anyhow!("peer.capture.{}", e))?;
+        self.peer
+            .video
+            .validate()
+            .map_err(|e| anyhow!("peer.video.{}", e))?;
+        self.peer
+            .transfer
+            .validate()
+            .map_err(|e| anyhow!("peer.transfer.{}", e))?;
+        self.peer
+            .mavlink
+            .validate()
+            .map_err(|e| anyhow!("peer.mavlink.{}", e))?;
+        #[cfg(feature = "gstreamer")]
+        {
+            self.peer
+                .gstreamer
+                .validate()
+                .map_err(|e| anyhow!("peer.gstreamer.{}", e))?;
+            if self.peer.gstreamer.enabled && !self.peer.video.enabled {
+                bail!("peer.gstreamer needs peer.video.enabled");
+            }
+        }
+        #[cfg(feature = "ros2")]
+        self.peer
+            .ros
+            .validate()
+            .map_err(|e| anyhow!("peer.ros.{}", e))?;
+        self.protocol
+            .heartbeat
+            .validate()
+            .map_err(|e| anyhow!("protocol.heartbeat.{}", e))?;
+        self.crash.validate().map_err(|e| anyhow!("crash.{}", e))?;
+        self.operator
+            .validate()
+            .map_err(|e| anyhow!("operator.{}", e))?;
+        validate_rules("operator.rules", &self.operator.rules)?;
+        self.peer
+            .http_client()
+            .map_err(|e| anyhow!("peer.ca_file: {}", e))?;
+        if let Some(filter) = &self.log.filter {
+            tracing_subscriber::EnvFilter::try_new(filter)
+                .map_err(|e| anyhow!("log.filter '{}': {}", filter, e))?;
+        }
+
+        self.network
+            .socket
+            .validate()
+            .map_err(|e| anyhow!("network.socket.{}", e))?;
+        self.network
+            .pcap
+            .validate()
+            .map_err(|e| anyhow!("network.pcap.{}", e))?;
+        self.network
+            .netsim
+            .validate()
+            .map_err(|e| anyhow!("network.netsim.{}", e))?;
+        self.network
+            .connectivity_probe
+            .parse::<SocketAddr>()
+            .with_context(|| {
+                format!(
+                    "network.connectivity_probe '{}'",
+                    self.network.connectivity_probe
+                )
+            })?;
+        let probe_v6 = self
+            .network
+            .connectivity_probe_v6
+            .parse::<SocketAddr>()
+            .with_context(|| {
+                format!(
+                    "network.connectivity_probe_v6 '{}'",
+                    self.network.connectivity_probe_v6
+                )
+            })?;
+        if !probe_v6.is_ipv6() {
+            bail!("network.connectivity_probe_v6 must be an IPv6 address");
+        }
+        Ok(())
+    }
+
+    /// Returns the server configuration with the shared network, protocol and
+    /// crash reporting settings.
+    pub fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            network: self.network.clone(),
+            protocol: self.protocol.clone(),
+            crash: self.crash.clone(),
+            ..self.server.clone()
+        }
+    }
+
+    /// Returns the peer configuration with the shared network, protocol and
+    /// crash reporting settings.
+    pub fn peer_config(&self) -> PeerConfig {
+        PeerConfig {
+            network: self.network.clone(),
+            protocol: self.protocol.clone(),
+            crash: self.crash.clone(),
+            ..self.peer.clone()
+        }
+    }
+}
+
+/// Merges settings into others, replacing everything but tables, which are
+/// merged key by key.
+fn merge_settings(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_settings(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Parses the value of an environment variable, naming it in the error.
+fn parse_env<T: std::str::FromStr>(name: &str, value: &str) -> anyhow::Result<T> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("invalid value '{}' for {}", value, name))
+}
+
+/// Checks the labels and delivery options of configured data channels.
+fn validate_channel_options(
+    setting: &str,
+    channels: &BTreeMap<String, ChannelOptions>,
+) -> anyhow::Result<()> {
+    for (label, options) in channels {
+        if label.is_empty() || label.len() > u16::MAX as usize {
+            bail!("{} contains an invalid label '{}'", setting, label);
+        }
+        options
+            .validate()
+            .map_err(|e| anyhow!("{}.{}: {}", setting, label, e))?;
+    }
+    Ok(())
+}
+
+/// Checks every rule, naming the rule at fault.
+fn validate_rules(setting: &str, rules: &[Rule]) -> anyhow::Result<()> {
+    let mut names = HashSet::new();
+    for rule in rules {
+        rule.validate()
+            .map_err(|e| anyhow!("{}.{}: {}", setting, rule.name, e))?;
+        if !names.insert(&rule.name) {
+            bail!("{} contains '{}' twice", setting, rule.name);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every ICE server has at least one STUN or TURN URL.
+fn validate_ice_servers(setting: &str, servers: &[IceServer]) -> anyhow::Result<()> {
+    for server in servers {
+        if server.urls.is_empty() {
+            bail!("{} contains a server without URLs", setting);
+        }
+        if let Some(url) = server.urls.iter().find(|url| {
+            !["stun:", "turn:", "turns:"]
+                .iter()
+                .any(|scheme| url.starts_with(scheme))
+        }) {
+            bail!(
+                "{} contains '{}', which is not a STUN or TURN URL",
+                setting,
+                url
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,377 @@
+//! Crash reports with a state snapshot
+//!
+//! An intermittent crash on a rover in the field rarely leaves more than a
+//! line on a console nobody watches. Once [`install`]ed, a panic anywhere in
+//! the process, or a fatal error handed to [`report_error`], writes a JSON
+//! [`CrashReport`] to the configured directory: the message and backtrace,
+//! the most recent log lines, the connection state the peer and server
+//! record through [`record_state`] as they run, and a digest of the
+//! configuration, so a report can be matched to the settings it crashed
+//! with without shipping secrets.
+//!
+//! Reports stay on disk until the peer's next session uploads them on the
+//! "crash" data channel (see [`crate::model::crash`]); the server keeps them
+//! under `received` in its own crash directory. Only the newest
+//! [`CrashConfig::max_reports`] unsent reports are kept.
+
+use std::{
+    backtrace::Backtrace,
+    collections::BTreeMap,
+    fmt, fs, io,
+    panic::{self, PanicHookInfo},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{auth::guest::hex_encode, util::logbuf};
+
+/// Prefix of report file names.
+const REPORT_PREFIX: &str = "crash-";
+
+/// Extension of report file names.
+const REPORT_EXTENSION: &str = ".json";
+
+/// Subdirectory in which the server stores uploaded reports.
+const RECEIVED_DIR: &str = "received";
+
+/// Number of recent log lines in a report.
+const LOG_LINES: usize = 200;
+
+/// The settings given to [`install`], once it was called.
+static INSTALLED: OnceLock<Installed> = OnceLock::new();
+
+/// The state recorded through [`record_state`], by key.
+static STATE: Mutex<BTreeMap<String, serde_json::Value>> = Mutex::new(BTreeMap::new());
+
+/// Crash reporting settings, the `[crash]` section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrashConfig {
+    /// Write a report on panics and fatal errors
+    pub enabled: bool,
+    /// Directory of the reports
+    pub dir: PathBuf,
+    /// Upload the peer's reports over its next connection
+    pub upload: bool,
+    /// Number of reports kept on disk; the oldest are deleted first
+    pub max_reports: usize,
+}
+
+impl Default for CrashConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: PathBuf::from("crash-reports"),
+            upload: true,
+            max_reports: 20,
+        }
+    }
+}
+
+impl CrashConfig {
+    /// Checks that at least one report can be kept.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_reports == 0 {
+            return Err("max_reports must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+/// What ended the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrashKind {
+    /// A panic, caught by the hook
+    Panic,
+    /// An error the application gave up on
+    Error,
+}
+
+/// A crash report, as written to disk and uploaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrashReport {
+    /// What ended the process
+    pub kind: CrashKind,
+    /// The panic message or the error
+    pub message: String,
+    /// Source location of the panic
+    pub location: Option<String>,
+    /// Name of the thread that crashed
+    pub thread: Option<String>,
+    /// The backtrace of the crashing thread
+    pub backtrace: String,
+    /// When the crash happened
+    pub time: DateTime<Utc>,
+    /// Version of the crate
+    pub version: String,
+    /// ID of the crashed process
+    pub pid: u32,
+    /// Digest of the configuration, see [`config_digest`]
+    pub config_digest: String,
+    /// The last state recorded through [`record_state`], by key
+    pub state: BTreeMap<String, serde_json::Value>,
+    /// The most recent log lines, oldest first
+    pub recent_logs: Vec<String>,
+}
+
+/// A report waiting for upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingReport {
+    /// File name of the report
+    pub name: String,
+    /// The JSON report
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Installed {
+    config: CrashConfig,
+    config_digest: String,
+}
+
+/// Installs the panic hook writing crash reports.
+///
+/// The previous hook still runs after the report is written, so panics are
+/// printed as before. Only the first call has an effect.
+///
+/// # Arguments
+///
+/// * `config` - The crash reporting settings; nothing is installed if
+///   reporting is disabled
+/// * `config_digest` - Digest of the application's configuration, see
+///   [`config_digest`]
+pub fn install(config: &CrashConfig, config_digest: String) {
+    if !config.enabled {
+        return;
+    }
+    let installed = Installed {
+        config: config.clone(),
+        config_digest,
+    };
+    if INSTALLED.set(installed).is_err() {
+        return;
+    }
+
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let (message, location) = panic_message(info);
+        if let Some(path) = write_report(CrashKind::Panic, message, location) {
+            eprintln!("Crash report written to {}", path.display());
+        }
+        previous(info);
+    }));
+}
+
+/// Writes a crash report for a fatal error.
+///
+/// # Arguments
+///
+/// * `error` - The error the application gave up on
+///
+/// # Returns
+///
+/// The path of the report, or `None` if reporting is not installed or the
+/// report could not be written
+pub fn report_error(error: &dyn fmt::Display) -> Option<PathBuf> {
+    write_report(CrashKind::Error, error.to_string(), None)
+}
+
+/// Records a piece of state to include in crash reports, replacing the
+/// previous value of the key.
+///
+/// # Arguments
+///
+/// * `key` - What the state describes, e.g. `peer.ice_state`
+/// * `value` - The state
+pub fn record_state(key: &str, value: impl Serialize) {
+    if INSTALLED.get().is_none() {
+        return;
+    }
+    let Ok(value) = serde_json::to_value(value) else {
+        return;
+    };
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    state.insert(key.to_string(), value);
+}
+
+/// Returns a digest identifying a configuration without revealing it.
+///
+/// # Arguments
+///
+/// * `config` - The configuration, serialized to JSON for hashing
+///
+/// # Returns
+///
+/// The hex-encoded SHA-256 of the JSON
+pub fn config_digest(config: &impl Serialize) -> String {
+    let json = serde_json::to_vec(config).unwrap_or_default();
+    hex_encode(&Sha256::digest(json))
+}
+
+/// Returns the reports waiting in a directory, oldest first.
+///
+/// # Arguments
+///
+/// * `dir` - The crash report directory
+///
+/// # Returns
+///
+/// The reports that could be read; none if the directory does not exist
+pub fn pending(dir: &Path) -> Vec<PendingReport> {
+    let mut reports: Vec<PendingReport> = report_names(dir)
+        .into_iter()
+        .filter_map(|name| {
+            let data = fs::read(dir.join(&name)).ok()?;
+            Some(PendingReport { name, data })
+        })
+        .collect();
+    reports.sort_by(|a, b| a.name.cmp(&b.name));
+    reports
+}
+
+/// Deletes an uploaded report.
+///
+/// # Arguments
+///
+/// * `dir` - The crash report directory
+/// * `name` - File name of the report
+///
+/// # Returns
+///
+/// `false` if the name is not a report's, or the file could not be deleted
+pub fn remove(dir: &Path, name: &str) -> bool {
+    is_report_name(name) && fs::remove_file(dir.join(name)).is_ok()
+}
+
+/// Stores a report uploaded by a peer.
+///
+/// # Arguments
+///
+/// * `dir` - The server's crash report directory
+/// * `client` - Name of the client that uploaded it, prefixed to the file name
+/// * `name` - File name of the report on the peer
+/// * `data` - The JSON report
+///
+/// # Returns
+///
+/// The path of the stored report, or an error if the name is not a report's,
+/// the data is not a report, or writing failed
+pub fn store_received(dir: &Path, client: &str, name: &str, data: &[u8]) -> io::Result<PathBuf> {
+    if !is_report_name(name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not a crash report name", name),
+        ));
+    }
+    serde_json::from_slice::<CrashReport>(data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let client: String = client
+        .chars()
+        .map(|c| if is_name_char(c) { c } else { '_' })
+        .collect();
+    let dir = dir.join(RECEIVED_DIR);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}-{}", client, name));
+    fs::write(&path, data)?;
+    Ok(path)
+}
+
+/// Writes a report, if reporting is installed, and deletes the oldest ones
+/// beyond the limit.
+fn write_report(kind: CrashKind, message: String, location: Option<String>) -> Option<PathBuf> {
+    let installed = INSTALLED.get()?;
+    let time = Utc::now();
+    let report = CrashReport {
+        kind,
+        message,
+        location,
+        thread: std::thread::current().name().map(str::to_string),
+        backtrace: Backtrace::force_capture().to_string(),
+        time,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        pid: std::process::id(),
+        config_digest: installed.config_digest.clone(),
+        // The crashing thread may hold the lock
+        state: STATE
+            .try_lock()
+            .map(|state| state.clone())
+            .unwrap_or_default(),
+        recent_logs: logbuf::try_recent(LOG_LINES),
+    };
+
+    let dir = &installed.config.dir;
+    let name = format!(
+        "{}{}-{}{}",
+        REPORT_PREFIX,
+        time.format("%Y%m%dT%H%M%S%3fZ"),
+        report.pid,
+        REPORT_EXTENSION
+    );
+    let path = dir.join(&name);
+    // Written aside and renamed, so an upload never sees half a report
+    let partial = dir.join(format!(".{}", name));
+    let json = serde_json::to_vec_pretty(&report).ok()?;
+    let written = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&partial, json))
+        .and_then(|_| fs::rename(&partial, &path));
+    if let Err(e) = written {
+        eprintln!("Failed to write a crash report to {}: {}", dir.display(), e);
+        return None;
+    }
+
+    let names = report_names(dir);
+    let excess = names.len().saturating_sub(installed.config.max_reports);
+    for old in &names[..excess] {
+        let _ = fs::remove_file(dir.join(old));
+    }
+    Some(path)
+}
+
+/// Returns the message and location of a panic.
+fn panic_message(info: &PanicHookInfo<'_>) -> (String, Option<String>) {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".into());
+    let location = info
+        .location()
+        .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()));
+    (message, location)
+}
+
+/// Returns the names of the reports in a directory, oldest first.
+fn report_names(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_report_name(name))
+        .collect();
+    // The timestamp in the name orders them
+    names.sort();
+    names
+}
+
+/// Returns `true` if a name is that of a report file, with no path in it.
+fn is_report_name(name: &str) -> bool {
+    name.starts_with(REPORT_PREFIX)
+        && name.ends_with(REPORT_EXTENSION)
+        && name.chars().all(is_name_char)
+        && !name.contains("..")
+}
+
+/// Returns `true` for the characters allowed in stored file names.
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')
+}
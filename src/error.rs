@@ -0,0 +1,83 @@
+//! Errors of the library
+//!
+//! Library callers get a [`RoverRtcError`] wherever the network, the remote or
+//! the received bytes can make an operation fail, instead of a panic aborting
+//! the embedding process.
+
+use std::io;
+
+use thiserror::Error;
+
+/// Errors of the signaling server, the peer and the wire protocol.
+#[derive(Debug, Error)]
+pub enum RoverRtcError {
+    /// No network interface has a routable IPv4 address with internet access
+    #[error("found no usable network interface with internet access")]
+    NoUsableInterface,
+    /// No ICE candidate could be gathered from the network interfaces
+    #[error("no ICE candidates were found")]
+    NoCandidates,
+    /// A socket or file operation failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// An SDP offer or answer could not be created or was not accepted
+    #[error("SDP negotiation failed: {0}")]
+    Sdp(String),
+    /// The WebRTC stack failed to handle input or produce output
+    #[error("WebRTC error: {0}")]
+    Webrtc(String),
+    /// The signaling server could not be reached or rejected a request
+    #[error("signaling failed: {0}")]
+    Signaling(String),
+    /// A setting cannot be used, e.g. an unreadable CA certificate
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    /// Received bytes are not the expected message
+    #[error("failed to decode {what}: {reason}")]
+    Decode {
+        /// What the bytes should have been, e.g. `payload`
+        what: &'static str,
+        /// Why decoding failed
+        reason: String,
+    },
+    /// The connection was lost and not restored
+    #[error("connection lost: {0}")]
+    ConnectionLost(String),
+    /// The remote refused the session; connecting again would not help
+    #[error("session refused: {0}")]
+    Refused(String),
+    /// A thread the operation depends on has stopped
+    #[error("the {0} stopped")]
+    Disconnected(&'static str),
+}
+
+/// Result of the library's fallible operations.
+pub type Result<T> = std::result::Result<T, RoverRtcError>;
+
+#[cfg(feature = "native")]
+impl From<str0m::RtcError> for RoverRtcError {
+    fn from(e: str0m::RtcError) -> Self {
+        Self::Webrtc(e.to_string())
+    }
+}
+
+#[cfg(feature = "native")]
+impl From<str0m::error::IceError> for RoverRtcError {
+    fn from(e: str0m::error::IceError) -> Self {
+        Self::Webrtc(e.to_string())
+    }
+}
+
+#[cfg(feature = "native")]
+impl From<reqwest::Error> for RoverRtcError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Signaling(e.to_string())
+    }
+}
+
+#[cfg(feature = "native")]
+impl From<tungstenite::Error> for RoverRtcError {
+    fn from(e: tungstenite::Error) -> Self {
+        Self::Signaling(e.to_string())
+    }
+}
@@ -0,0 +1,581 @@
+//! C bindings for the peer API
+//!
+//! Flight software written in C or C++ embeds the peer through these functions
+//! instead of running the `rover-rtc` binary. The declarations are in
+//! `include/rover_rtc.h`; the library is built as `librover_rtc.so` and
+//! `librover_rtc.a` next to the Rust library.
+//!
+//! A peer is created from a configuration file, subscribed to the channels
+//! whose data the application wants, and started on its own thread. Events
+//! (connection changes and received data) are either delivered to a callback
+//! or queued for [`rover_rtc_peer_poll_event`]. Functions returning `int`
+//! return `0` on success and `-1` on failure, with the reason available from
+//! [`rover_rtc_last_error`] on the calling thread.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    ptr, slice,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::config::Config;
+use crate::model::audio::AudioFrame;
+use crate::peer::PeerEvent;
+use crate::rover::{RoverPeer, RoverRtc};
+use crate::util::init_log;
+
+/// Kinds of events reported to C applications.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoverRtcEventKind {
+    /// No event was pending
+    None = 0,
+    /// ICE connected
+    Connected = 1,
+    /// A data channel opened; `label` names it
+    ChannelOpen = 2,
+    /// The first data channel opened; `setup_ms` is the total setup time
+    SetupComplete = 3,
+    /// ICE is restarting after a network change
+    Restarting = 4,
+    /// The connection was lost or stopped
+    Disconnected = 5,
+    /// Data arrived on a subscribed channel; `label`, `data` and `len` describe it
+    Data = 6,
+    /// The connection is down; `attempt` runs after `duration_ms`
+    Reconnecting = 7,
+    /// The channels are back; `duration_ms` is the outage, after `attempt` attempts
+    Reconnected = 8,
+    /// The video encoder should produce a keyframe
+    KeyframeRequested = 9,
+    /// The operator's voice arrived; `label` is `opus` for an Opus packet in
+    /// `data`, or `pcm` for 8 kHz 16-bit native-endian samples
+    Audio = 10,
+    /// A frame of a track another client publishes arrived, forwarded by the
+    /// server, or of a track of the remote; `label` is the kind and codec,
+    /// e.g. `video/H264`, and `origin` the publishing client, `0` for the
+    /// remote
+    Media = 11,
+    /// A message was given up under its channel's write retry policy;
+    /// `label`, `data` and `len` describe it
+    WriteFailed = 12,
+    /// A file arrived on the transfer channel; `label` is the name it was
+    /// sent under and `data` the UTF-8 path it was stored at
+    FileReceived = 13,
+    /// The remote confirmed a file sent to it; `label` is its name
+    TransferComplete = 14,
+    /// A transfer was refused or its file did not match its digest; `label`
+    /// is the file name and `data` the UTF-8 reason
+    TransferFailed = 15,
+    /// The bonding moved the application traffic to another link; `label`
+    /// is its ID and `data` the UTF-8 reason
+    LinkFailover = 16,
+    /// An announcement to every client of the room arrived, in `data`
+    Announcement = 17,
+    /// A health check moved the connection to another state; `label` is
+    /// `healthy`, `degraded` or `dead`
+    HealthChanged = 18,
+}
+
+/// An event, borrowed from the peer.
+///
+/// The pointers stay valid until the callback returns, or until the next
+/// [`rover_rtc_peer_poll_event`] or [`rover_rtc_peer_free`] on the peer.
+#[repr(C)]
+#[derive(Debug)]
+pub struct RoverRtcEvent {
+    pub kind: RoverRtcEventKind,
+    /// The channel label, or null
+    pub label: *const c_char,
+    /// The received data, or null
+    pub data: *const u8,
+    /// The length of `data`
+    pub len: usize,
+    /// The total setup time in milliseconds, for `SetupComplete`
+    pub setup_ms: f64,
+    /// The reconnection attempt, for `Reconnecting` and `Reconnected`
+    pub attempt: u32,
+    /// The reconnection delay or outage in milliseconds, for `Reconnecting`
+    /// and `Reconnected`
+    pub duration_ms: f64,
+    /// The ID of the publishing client, for `Media`
+    pub origin: u64,
+}
+
+/// Callback receiving every event, from the peer's threads.
+pub type RoverRtcEventCallback = extern "C" fn(event: *const RoverRtcEvent, user_data: *mut c_void);
+
+/// An event owning its label and data.
+struct OwnedEvent {
+    kind: RoverRtcEventKind,
+    label: Option<CString>,
+    data: Vec<u8>,
+    setup_ms: f64,
+    attempt: u32,
+    duration_ms: f64,
+    origin: u64,
+}
+
+impl OwnedEvent {
+    fn from_peer_event(event: &PeerEvent) -> Self {
+        let media_label: String;
+        let state_label: String;
+        let (kind, label, setup_ms) = match event {
+            PeerEvent::Connected => (RoverRtcEventKind::Connected, None, 0.0),
+            PeerEvent::ChannelOpen { label } => {
+                (RoverRtcEventKind::ChannelOpen, Some(label.as_str()), 0.0)
+            }
+            PeerEvent::SetupComplete { breakdown } => (
+                RoverRtcEventKind::SetupComplete,
+                None,
+                breakdown.total_ms.unwrap_or(0.0),
+            ),
+            PeerEvent::Restarting => (RoverRtcEventKind::Restarting, None, 0.0),
+            PeerEvent::Disconnected => (RoverRtcEventKind::Disconnected, None, 0.0),
+            PeerEvent::Reconnecting { .. } => (RoverRtcEventKind::Reconnecting, None, 0.0),
+            PeerEvent::Reconnected { .. } => (RoverRtcEventKind::Reconnected, None, 0.0),
+            PeerEvent::KeyframeRequested => (RoverRtcEventKind::KeyframeRequested, None, 0.0),
+            PeerEvent::Audio { frame } => {
+                let codec = match frame {
+                    AudioFrame::Opus(_) => "opus",
+                    AudioFrame::Pcm(_) => "pcm",
+                };
+                (RoverRtcEventKind::Audio, Some(codec), 0.0)
+            }
+            PeerEvent::Media { kind, codec, .. } => {
+                media_label = format!("{}/{}", kind, codec);
+                (RoverRtcEventKind::Media, Some(media_label.as_str()), 0.0)
+            }
+            PeerEvent::FileReceived { name, .. } => {
+                (RoverRtcEventKind::FileReceived, Some(name.as_str()), 0.0)
+            }
+            PeerEvent::TransferComplete { name, .. } => (
+                RoverRtcEventKind::TransferComplete,
+                Some(name.as_str()),
+                0.0,
+            ),
+            PeerEvent::TransferFailed { name, .. } => {
+                (RoverRtcEventKind::TransferFailed, Some(name.as_str()), 0.0)
+            }
+            PeerEvent::WriteFailed { label, .. } => {
+                (RoverRtcEventKind::WriteFailed, Some(label.as_str()), 0.0)
+            }
+            PeerEvent::LinkFailover { link, .. } => {
+                (RoverRtcEventKind::LinkFailover, Some(link.as_str()), 0.0)
+            }
+            PeerEvent::Announcement { .. } => (RoverRtcEventKind::Announcement, None, 0.0),
+            PeerEvent::HealthChanged { state } => {
+                state_label = state.to_string();
+                (
+                    RoverRtcEventKind::HealthChanged,
+                    Some(state_label.as_str()),
+                    0.0,
+                )
+            }
+        };
+        let data = match event {
+            PeerEvent::Audio {
+                frame: AudioFrame::Opus(packet),
+            } => packet.clone(),
+            PeerEvent::Audio {
+                frame: AudioFrame::Pcm(samples),
+            } => samples.iter().flat_map(|s| s.to_ne_bytes()).collect(),
+            PeerEvent::Media { data, .. }
+            | PeerEvent::WriteFailed { data, .. }
+            | PeerEvent::Announcement { data, .. } => data.clone(),
+            PeerEvent::FileReceived { path, .. } => path.to_string_lossy().as_bytes().to_vec(),
+            PeerEvent::TransferFailed { reason, .. } | PeerEvent::LinkFailover { reason, .. } => {
+                reason.as_bytes().to_vec()
+            }
+            _ => vec![],
+        };
+        let (attempt, duration) = match event {
+            PeerEvent::Reconnecting { attempt, delay } => (*attempt, *delay),
+            PeerEvent::Reconnected { outage, attempts } => (*attempts, *outage),
+            _ => (0, Duration::ZERO),
+        };
+        Self {
+            kind,
+            label: label.and_then(|l| CString::new(l).ok()),
+            data,
+            setup_ms,
+            attempt,
+            duration_ms: duration.as_secs_f64() * 1000.0,
+            origin: match event {
+                PeerEvent::Media { origin, .. } => *origin,
+                _ => 0,
+            },
+        }
+    }
+
+    fn borrow(&self) -> RoverRtcEvent {
+        RoverRtcEvent {
+            kind: self.kind,
+            label: self.label.as_ref().map_or(ptr::null(), |l| l.as_ptr()),
+            data: if self.data.is_empty() {
+                ptr::null()
+            } else {
+                self.data.as_ptr()
+            },
+            len: self.data.len(),
+            setup_ms: self.setup_ms,
+            attempt: self.attempt,
+            duration_ms: self.duration_ms,
+            origin: self.origin,
+        }
+    }
+}
+
+/// The registered callback and its user data.
+struct Callback {
+    function: RoverRtcEventCallback,
+    user_data: *mut c_void,
+}
+
+// SAFETY: the application promises the user data may be used from the peer's
+// threads by registering it
+unsafe impl Send for Callback {}
+
+/// Delivers events to the callback, or queues them for polling.
+#[derive(Default)]
+struct EventSink {
+    queue: Mutex<VecDeque<OwnedEvent>>,
+    callback: Mutex<Option<Callback>>,
+}
+
+impl EventSink {
+    fn deliver(&self, event: OwnedEvent) {
+        // Held during the call, so the callback cannot be replaced while it runs
+        let callback = self.callback.lock().expect("callback lock");
+        match callback.as_ref() {
+            Some(callback) => (callback.function)(&event.borrow(), callback.user_data),
+            None => self.queue.lock().expect("queue lock").push_back(event),
+        }
+    }
+}
+
+/// A peer owned by a C application.
+pub struct RoverRtcPeer {
+    peer: RoverPeer,
+    sink: Arc<EventSink>,
+    /// The event last returned by polling, kept alive for its pointers
+    polled: Option<OwnedEvent>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: impl Into<String>) {
+    let message = CString::new(message.into().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning errors and panics into `-1` and the last error.
+fn guarded(f: impl FnOnce() -> Result<(), String>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(e);
+            -1
+        }
+        Err(_) => {
+            set_last_error("panic in rover-rtc");
+            -1
+        }
+    }
+}
+
+/// Reads a C string argument.
+///
+/// # Safety
+///
+/// `s` must be null or point to a NUL-terminated string
+unsafe fn read_str<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{} is null", name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+/// Returns the last error on the calling thread, or null.
+///
+/// The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn rover_rtc_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Installs the crate's logger, configured with `RUST_LOG`.
+#[no_mangle]
+pub extern "C" fn rover_rtc_init_logging() {
+    init_log();
+}
+
+/// Creates a peer from a configuration file.
+///
+/// The file is read like the binary's `--config`, with the `ROVER_*`
+/// environment variables applied over it.
+///
+/// # Arguments
+///
+/// * `config_path` - Path of a TOML or YAML file, or null for the defaults
+///
+/// # Returns
+///
+/// The peer, to be released with [`rover_rtc_peer_free`], or null on error
+///
+/// # Safety
+///
+/// `config_path` must be null or point to a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_new(config_path: *const c_char) -> *mut RoverRtcPeer {
+    let mut peer = None;
+    let result = guarded(|| {
+        let path = match config_path.is_null() {
+            true => None,
+            false => Some(read_str(config_path, "config_path")?),
+        };
+        let config = Config::load(path.map(Path::new)).map_err(|e| format!("{:#}", e))?;
+        config.validate().map_err(|e| format!("{:#}", e))?;
+
+        let sink = Arc::new(EventSink::default());
+        let events = sink.clone();
+        let rover = RoverRtc::builder()
+            .peer_config(config.peer_config())
+            .on_peer_event(move |event| events.deliver(OwnedEvent::from_peer_event(event)))
+            .build_peer();
+        peer = Some(RoverRtcPeer {
+            peer: rover,
+            sink,
+            polled: None,
+        });
+        Ok(())
+    });
+    match (result, peer) {
+        (0, Some(peer)) => Box::into_raw(Box::new(peer)),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Subscribes to the data received on a channel, reported as `Data` events.
+///
+/// # Safety
+///
+/// `peer` must come from [`rover_rtc_peer_new`]; `label` must point to a
+/// NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_subscribe(
+    peer: *mut RoverRtcPeer,
+    label: *const c_char,
+) -> c_int {
+    guarded(|| {
+        let peer = peer.as_ref().ok_or("peer is null")?;
+        let label = read_str(label, "label")?.to_string();
+        let c_label = CString::new(label.as_str()).map_err(|e| e.to_string())?;
+        let mut rx = peer.peer.handle().subscribe(&label);
+        let sink = peer.sink.clone();
+        // Ends when the peer is freed and the subscription goes away
+        thread::spawn(move || {
+            while let Some(data) = rx.blocking_recv() {
+                sink.deliver(OwnedEvent {
+                    kind: RoverRtcEventKind::Data,
+                    label: Some(c_label.clone()),
+                    data,
+                    setup_ms: 0.0,
+                    attempt: 0,
+                    duration_ms: 0.0,
+                    origin: 0,
+                });
+            }
+        });
+        Ok(())
+    })
+}
+
+/// Registers the callback receiving every event, or removes it if null.
+///
+/// Events are queued for [`rover_rtc_peer_poll_event`] while no callback is
+/// registered. The callback runs on the peer's threads and must not call
+/// `rover_rtc_peer_set_callback` or `rover_rtc_peer_free`.
+///
+/// # Safety
+///
+/// `peer` must come from [`rover_rtc_peer_new`]; `user_data` must be usable
+/// from other threads
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_set_callback(
+    peer: *mut RoverRtcPeer,
+    callback: Option<RoverRtcEventCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    guarded(|| {
+        let peer = peer.as_ref().ok_or("peer is null")?;
+        *peer.sink.callback.lock().expect("callback lock") = callback.map(|function| Callback {
+            function,
+            user_data,
+        });
+        Ok(())
+    })
+}
+
+/// Starts the peer on its own thread.
+///
+/// # Safety
+///
+/// `peer` must come from [`rover_rtc_peer_new`]
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_start(peer: *mut RoverRtcPeer) -> c_int {
+    guarded(|| {
+        let peer = peer.as_mut().ok_or("peer is null")?;
+        peer.peer.start().map_err(|e| e.to_string())
+    })
+}
+
+/// Queues data to be sent on a channel.
+///
+/// Data for a channel that is not open is dropped.
+///
+/// # Safety
+///
+/// `peer` must come from [`rover_rtc_peer_new`]; `label` must point to a
+/// NUL-terminated string; `data` must point to `len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_send(
+    peer: *mut RoverRtcPeer,
+    label: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    guarded(|| {
+        let peer = peer.as_ref().ok_or("peer is null")?;
+        let label = read_str(label, "label")?;
+        let data = match len {
+            0 => &[][..],
+            _ if data.is_null() => return Err("data is null".into()),
+            _ => slice::from_raw_parts(data, len),
+        };
+        peer.peer.handle().send(label, data.to_vec());
+        Ok(())
+    })
+}
+
+/// Sends a file on the transfer channel, resuming it after reconnections.
+///
+/// Its end is reported as a `TransferComplete` or `TransferFailed` event.
+///
+/// # Safety
+///
+/// `peer` must come from [`rover_rtc_peer_new`]; `path` must point to a
+/// NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_send_file(
+    peer: *mut RoverRtcPeer,
+    path: *const c_char,
+) -> c_int {
+    guarded(|| {
+        let peer = peer.as_ref().ok_or("peer is null")?;
+        let path = read_str(path, "path")?;
+        peer.peer
+            .handle()
+            .send_file(path)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// Takes the next queued event, without blocking.
+///
+/// # Returns
+///
+/// `1` if an event was written to `event`, `0` if none was pending (the kind
+/// is then `None`), or `-1` on error
+///
+/// # Safety
+///
+/// `peer` must come from [`rover_rtc_peer_new`]; `event` must point to a
+/// writable [`RoverRtcEvent`]
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_poll_event(
+    peer: *mut RoverRtcPeer,
+    event: *mut RoverRtcEvent,
+) -> c_int {
+    let mut found = false;
+    let result = guarded(|| {
+        let peer = peer.as_mut().ok_or("peer is null")?;
+        let out = event.as_mut().ok_or("event is null")?;
+        peer.polled = peer.sink.queue.lock().expect("queue lock").pop_front();
+        *out = match &peer.polled {
+            Some(polled) => {
+                found = true;
+                polled.borrow()
+            }
+            None => RoverRtcEvent {
+                kind: RoverRtcEventKind::None,
+                label: ptr::null(),
+                data: ptr::null(),
+                len: 0,
+                setup_ms: 0.0,
+                attempt: 0,
+                duration_ms: 0.0,
+                origin: 0,
+            },
+        };
+        Ok(())
+    });
+    match result {
+        0 => found as c_int,
+        error => error,
+    }
+}
+
+/// Returns `1` while the peer's thread is running, `0` otherwise.
+///
+/// # Safety
+///
+/// `peer` must be null or come from [`rover_rtc_peer_new`]
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_is_running(peer: *const RoverRtcPeer) -> c_int {
+    peer.as_ref().is_some_and(|p| p.peer.is_running()) as c_int
+}
+
+/// Stops the peer and waits for it to disconnect.
+///
+/// # Returns
+///
+/// `-1` if the peer had stopped with an error, e.g. a failed signaling
+///
+/// # Safety
+///
+/// `peer` must come from [`rover_rtc_peer_new`]
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_stop(peer: *mut RoverRtcPeer) -> c_int {
+    guarded(|| {
+        let peer = peer.as_mut().ok_or("peer is null")?;
+        peer.peer.stop().map_err(|e| e.to_string())
+    })
+}
+
+/// Stops the peer if it is running and releases it.
+///
+/// # Safety
+///
+/// `peer` must be null or come from [`rover_rtc_peer_new`], and must not be
+/// used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn rover_rtc_peer_free(peer: *mut RoverRtcPeer) {
+    if peer.is_null() {
+        return;
+    }
+    let mut peer = Box::from_raw(peer);
+    *peer.sink.callback.lock().expect("callback lock") = None;
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| peer.peer.stop()));
+}
@@ -0,0 +1,55 @@
+//! Rover RTC - A WebRTC-based P2P communication system for rovers
+//!
+//! This crate provides a WebRTC implementation for direct peer-to-peer communication
+//! between rovers using data channels. It includes both a signaling server and peer
+//! functionality, designed to support seamless network handovers for resilient
+//! connections in changing network environments.
+//!
+//! Applications embed either side through [`RoverRtc::builder`]; the `rover-rtc`
+//! binary is a thin command-line wrapper around the same API.
+
+#[cfg(feature = "native")]
+pub mod admin;
+#[cfg(feature = "native")]
+pub mod auth;
+#[cfg(feature = "native")]
+pub mod bridge;
+#[cfg(feature = "native")]
+pub mod config;
+#[cfg(feature = "native")]
+pub mod crash;
+pub mod error;
+#[cfg(feature = "native")]
+pub mod ffi;
+#[cfg(feature = "native")]
+pub mod media;
+pub mod model;
+#[cfg(feature = "native")]
+pub mod operator;
+#[cfg(feature = "native")]
+pub mod peer;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "native")]
+pub mod replay;
+#[cfg(feature = "ros2")]
+pub mod ros;
+#[cfg(feature = "native")]
+pub mod rover;
+#[cfg(feature = "native")]
+pub mod selftest;
+#[cfg(feature = "native")]
+pub mod server;
+#[cfg(feature = "native")]
+pub mod transfer;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "native")]
+pub mod wizard;
+
+#[cfg(feature = "native")]
+mod util;
+
+pub use error::RoverRtcError;
+#[cfg(feature = "native")]
+pub use rover::{RoverPeer, RoverRtc, RoverRtcBuilder, RoverServer};
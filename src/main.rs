@@ -1,57 +1,469 @@
-//! Rover RTC - A WebRTC-based P2P communication system for rovers
+//! Rover RTC command-line interface
 //!
-//! This crate provides a WebRTC implementation for direct peer-to-peer communication
-//! between rovers using data channels. It includes both a signaling server and peer
-//! functionality, designed to support seamless network handovers for resilient
-//! connections in changing network environments.
+//! Runs the signaling server, a peer, an operator connected to several rovers
+//! or the loopback self-test, replays a recording of data channel traffic
+//! through the local stack, lists the peers on the LAN, writes a first
+//! configuration interactively, or prints the wire protocol description for
+//! other implementations. Settings come
+//! from the configuration file and environment (see [`Config`]), and the flags
+//! of each subcommand override them. See the library documentation for
+//! embedding either side in another application.
 
-pub mod model;
-pub mod peer;
-pub mod server;
+use std::{
+    env, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    process,
+    time::Duration,
+};
 
-use std::env;
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rover_rtc::{
+    admin::AdminClient,
+    config::Config,
+    crash,
+    model::{recording::Direction, schema::ProtocolDoc},
+    operator, peer, replay, selftest, server, wizard,
+};
 
-mod util;
+/// Rover RTC: WebRTC data channels between rovers and a signaling server.
+#[derive(Debug, Parser)]
+#[command(name = "rover-rtc", version)]
+struct Cli {
+    /// TOML or YAML configuration file; `ROVER_CONFIG` also names one
+    #[arg(short, long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Profile of settings to apply, e.g. `field-lte`, `lab-lan` or `demo`;
+    /// `ROVER_PROFILE` also names one
+    #[arg(short, long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Start the WebRTC signaling server
+    Server(ServerArgs),
+    /// Start a WebRTC peer
+    Peer(PeerArgs),
+    /// List the mesh peers announcing themselves on the LAN
+    Discover(DiscoverArgs),
+    /// Connect to several rovers and serve their telemetry and commands locally
+    Operator(OperatorArgs),
+    /// Connect a local peer and server and report each stage
+    Selftest(SelftestArgs),
+    /// Send a recording of data channel traffic through a local peer and
+    /// server
+    Replay(ReplayArgs),
+    /// Print a JSON description of the data channel messages
+    ProtocolDoc(ProtocolDocArgs),
+    /// Probe the network and write a configuration file interactively
+    Init(InitArgs),
+    /// Print the state of a running server and its clients, for support tickets
+    DumpState(DumpStateArgs),
+}
+
+#[derive(Debug, Args)]
+struct ServerArgs {
+    /// Port of the HTTP signaling endpoint
+    #[arg(long)]
+    http_port: Option<u16>,
+    /// Host address of the UDP socket; selected automatically by default
+    #[arg(long)]
+    udp_host: Option<IpAddr>,
+    /// Port of the UDP socket; 0 picks a random port
+    #[arg(long)]
+    udp_port: Option<u16>,
+    /// Number of client polling workers
+    #[arg(long)]
+    poll_workers: Option<usize>,
+}
+
+#[derive(Debug, Args)]
+struct PeerArgs {
+    /// URL of the signaling server; a ws:// URL enables trickle ICE
+    #[arg(long, value_name = "URL")]
+    signal_url: Option<String>,
+    /// Alias announced to the server
+    #[arg(long)]
+    alias: Option<String>,
+    /// Additional data channel to open; may be repeated
+    #[arg(long = "channel", value_name = "LABEL")]
+    channels: Vec<String>,
+    /// Connect directly to the peer listening under this alias
+    #[arg(long, value_name = "ALIAS", conflicts_with = "mesh_listen")]
+    mesh_target: Option<String>,
+    /// Wait under the alias for another peer to connect directly
+    #[arg(long)]
+    mesh_listen: bool,
+    /// Find the mesh peer by multicast on the LAN, without the signaling
+    /// server
+    #[arg(long)]
+    lan: bool,
+    /// Bridge MAVLink with the autopilot on this serial device
+    #[arg(long, value_name = "DEVICE")]
+    mavlink_serial: Option<PathBuf>,
+    /// Baud rate of the MAVLink serial device
+    #[arg(long, value_name = "BAUD", requires = "mavlink_serial")]
+    mavlink_baud: Option<u32>,
+    /// Bridge MAVLink with the ground-control software at this UDP address
+    #[arg(long, value_name = "ADDR", conflicts_with = "mavlink_serial")]
+    mavlink_udp: Option<SocketAddr>,
+}
+
+#[derive(Debug, Args)]
+struct DiscoverArgs {
+    /// How long to listen for the peers, in seconds
+    #[arg(long, default_value_t = 3)]
+    duration_secs: u64,
+}
+
+#[derive(Debug, Args)]
+struct OperatorArgs {
+    /// Alias of a rover listening in mesh mode; may be repeated
+    #[arg(long = "rover", value_name = "ALIAS")]
+    rovers: Vec<String>,
+    /// Address of the local API and WebSocket stream
+    #[arg(long, value_name = "ADDR")]
+    listen: Option<SocketAddr>,
+    /// URL of the signaling server relaying the mesh offers
+    #[arg(long, value_name = "URL")]
+    signal_url: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct SelftestArgs {
+    /// How long each stage may take, in seconds
+    #[arg(long, default_value_t = 10)]
+    timeout_secs: u64,
+}
+
+#[derive(Debug, Args)]
+struct ReplayArgs {
+    /// The recording, a JSON Lines file
+    #[arg(value_name = "FILE")]
+    recording: PathBuf,
+    /// Playback speed factor; 1 respects the original timing
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Replay only the messages recorded in this direction
+    #[arg(long, value_enum)]
+    direction: Option<ReplayDirection>,
+    /// File the server records the replayed messages to
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// How long connecting may take, in seconds
+    #[arg(long, default_value_t = 10)]
+    timeout_secs: u64,
+}
+
+/// Direction of the recorded messages to replay.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ReplayDirection {
+    /// Messages the recording side received
+    Inbound,
+    /// Messages the recording side sent
+    Outbound,
+}
+
+#[derive(Debug, Args)]
+struct ProtocolDocArgs {
+    /// File to write the description to instead of standard output
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct InitArgs {
+    /// File to write the configuration to
+    #[arg(short, long, value_name = "FILE", default_value = wizard::DEFAULT_PATH)]
+    output: PathBuf,
+    /// Overwrite an existing file without asking
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Debug, Args)]
+struct DumpStateArgs {
+    /// Address of the server; the configured `server.http_addr` by default.
+    /// `ROVER_ADMIN_TOKEN` holds the admin token
+    #[arg(long, value_name = "URL")]
+    url: Option<String>,
+    /// File to write the state to instead of standard output
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    /// Applies the flags over the loaded configuration.
+    fn apply(&self, config: &mut Config) -> anyhow::Result<()> {
+        if let Some(port) = self.http_port {
+            let mut addr: SocketAddr = config
+                .server
+                .http_addr
+                .parse()
+                .with_context(|| format!("server.http_addr '{}'", config.server.http_addr))?;
+            addr.set_port(port);
+            config.server.http_addr = addr.to_string();
+        }
+        if let Some(host) = self.udp_host {
+            config.server.udp_host = Some(host);
+        }
+        if let Some(port) = self.udp_port {
+            config.server.udp_port = port;
+        }
+        if let Some(workers) = self.poll_workers {
+            config.server.poll_workers = Some(workers);
+        }
+        Ok(())
+    }
+}
+
+impl PeerArgs {
+    /// Applies the flags over the loaded configuration.
+    fn apply(&self, config: &mut Config) {
+        if let Some(url) = &self.signal_url {
+            config.peer.signaling_url = url.clone();
+        }
+        if let Some(alias) = &self.alias {
+            config.peer.alias = Some(alias.clone());
+        }
+        if !self.channels.is_empty() {
+            config.peer.channels = self.channels.clone();
+        }
+        if let Some(target) = &self.mesh_target {
+            config.peer.mesh_target = Some(target.clone());
+        }
+        if self.mesh_listen {
+            config.peer.mesh_listen = true;
+        }
+        if self.lan {
+            config.peer.discovery.enabled = true;
+        }
+        if let Some(device) = &self.mavlink_serial {
+            config.peer.mavlink.enabled = true;
+            config.peer.mavlink.serial = Some(device.clone());
+        }
+        if let Some(baud) = self.mavlink_baud {
+            config.peer.mavlink.baud = baud;
+        }
+        if let Some(addr) = self.mavlink_udp {
+            config.peer.mavlink.enabled = true;
+            config.peer.mavlink.serial = None;
+            config.peer.mavlink.udp_target = Some(addr);
+        }
+    }
+}
+
+impl OperatorArgs {
+    /// Applies the flags over the loaded configuration.
+    fn apply(&self, config: &mut Config) {
+        if !self.rovers.is_empty() {
+            config.operator.rovers = self.rovers.clone();
+        }
+        if let Some(addr) = self.listen {
+            config.operator.listen_addr = addr.to_string();
+        }
+        if let Some(url) = &self.signal_url {
+            config.peer.signaling_url = url.clone();
+        }
+    }
+}
 
 /// Entry point for the Rover RTC application.
 ///
-/// Parses command-line arguments to determine whether to run as a server or peer.
-///
 /// # Usage
 ///
 /// ```bash
-/// cargo run server  # Start the WebRTC signaling server
-/// cargo run peer    # Start a WebRTC peer client
+/// rover-rtc server --http-port 3000 --udp-port 50000
+/// rover-rtc peer --signal-url http://172.17.0.1:3000 --channel video
+/// rover-rtc peer --alias rover-7 --mesh-listen
+/// rover-rtc peer --alias operator --mesh-target rover-7
+/// rover-rtc peer --alias rover-7 --mesh-listen --lan
+/// rover-rtc discover
+/// rover-rtc peer --alias rover-7 --mesh-listen --mavlink-serial /dev/ttyACM0
+/// rover-rtc peer --mesh-target rover-7 --mavlink-udp 127.0.0.1:14550
+/// rover-rtc operator --rover rover-7 --rover rover-8 --listen 127.0.0.1:4000
+/// rover-rtc --config rover.toml peer
+/// rover-rtc --profile field-lte peer
+/// rover-rtc selftest
+/// rover-rtc replay recording.jsonl --speed 4 --direction outbound
+/// rover-rtc protocol-doc --output protocol.json
+/// rover-rtc init --output rover.toml
+/// ROVER_ADMIN_TOKEN=s3cret rover-rtc dump-state --output state.json
 /// ```
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let cli = Cli::parse();
 
-    if args.len() > 1 {
-        match args[1].as_str() {
-            "server" => {
-                println!("Starting server...");
-                let _ = server::main();
+    // The wizard writes the configuration, so it must not need a valid one
+    if let Command::Init(args) = &cli.command {
+        if let Err(e) = wizard::run(&args.output, args.force) {
+            eprintln!("Setup failed: {:#}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let config = load_config(&cli).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {:#}", e);
+        process::exit(2);
+    });
+
+    // The logging is set up from RUST_LOG, which overrides the configured filter
+    if let Some(filter) = &config.log.filter {
+        if env::var_os("RUST_LOG").is_none() {
+            env::set_var("RUST_LOG", filter);
+        }
+    }
+
+    // Panics and fatal errors from here on leave a crash report
+    crash::install(&config.crash, crash::config_digest(&config));
+
+    match cli.command {
+        Command::Server(_) => {
+            println!("Starting server...");
+            server::main(config.server_config());
+        }
+        Command::Peer(_) => {
+            println!("Starting WebRTC peer...");
+            match peer::main(config.peer_config()) {
+                Ok(_) => println!("Peer completed successfully"),
+                Err(e) => {
+                    println!("Peer error:\n{}", e);
+                    if let Some(path) = crash::report_error(&e) {
+                        println!("Crash report written to {}", path.display());
+                    }
+                }
             }
-            "peer" => {
-                println!("Starting WebRTC peer...");
-                match peer::main() {
-                    Ok(_) => println!("Peer completed successfully"),
-                    Err(e) => println!("Peer error:\n{}", e),
+        }
+        Command::Discover(args) => {
+            let duration = Duration::from_secs(args.duration_secs);
+            match peer::discover(&config.peer_config(), duration) {
+                Ok(peers) if peers.is_empty() => println!("No peers found on the LAN"),
+                Ok(peers) => {
+                    for peer in peers {
+                        println!("{}\t{}\t{}", peer.alias, peer.addr, peer.version);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Discovery failed: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Command::Operator(_) => {
+            println!("Starting operator...");
+            if let Err(e) = operator::main(config.operator.clone(), config.peer_config()) {
+                eprintln!("Operator error: {:#}", e);
+                process::exit(1);
+            }
+        }
+        Command::Selftest(args) => {
+            println!("Running loopback self-test...");
+            let report = selftest::run(Duration::from_secs(args.timeout_secs));
+            println!("{}", report);
+            process::exit(if report.passed() { 0 } else { 1 });
+        }
+        Command::Replay(args) => {
+            let options = replay::ReplayOptions {
+                speed: args.speed,
+                direction: args.direction.map(|d| match d {
+                    ReplayDirection::Inbound => Direction::Inbound,
+                    ReplayDirection::Outbound => Direction::Outbound,
+                }),
+                output: args.output,
+                timeout: Duration::from_secs(args.timeout_secs),
+            };
+            println!("Replaying {}...", args.recording.display());
+            match replay::run(&args.recording, &options) {
+                Ok(report) => {
+                    println!("{}", report);
+                    process::exit(if report.complete() { 0 } else { 1 });
                 }
+                Err(e) => {
+                    eprintln!("Replay failed: {:#}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Command::Init(_) => unreachable!("handled before loading the configuration"),
+        Command::DumpState(args) => {
+            if let Err(e) = dump_state(&config, &args) {
+                eprintln!("Failed to dump the state: {:#}", e);
+                process::exit(1);
             }
-            _ => {
-                print_usage();
+        }
+        Command::ProtocolDoc(args) => {
+            let doc = ProtocolDoc::new().to_json();
+            match &args.output {
+                Some(path) => {
+                    if let Err(e) = fs::write(path, doc + "\n") {
+                        eprintln!("Failed to write {}: {}", path.display(), e);
+                        process::exit(1);
+                    }
+                }
+                None => println!("{}", doc),
             }
         }
-    } else {
-        print_usage();
     }
 }
 
-/// Prints usage information for the application.
-fn print_usage() {
-    println!("Rover RTC");
-    println!("Usage:");
-    println!("  cargo run server  - Start the WebRTC server");
-    println!("  cargo run client  - Start the WebRTC client");
+/// Loads the configuration and applies the subcommand's flags over it.
+fn load_config(cli: &Cli) -> anyhow::Result<Config> {
+    let mut config = Config::load_profile(cli.config.as_deref(), cli.profile.as_deref())?;
+    match &cli.command {
+        Command::Server(args) => args.apply(&mut config)?,
+        Command::Peer(args) => args.apply(&mut config),
+        Command::Operator(args) => args.apply(&mut config),
+        Command::Selftest(_)
+        | Command::Replay(_)
+        | Command::Discover(_)
+        | Command::ProtocolDoc(_)
+        | Command::Init(_)
+        | Command::DumpState(_) => return Ok(config),
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+/// Fetches the state of a running server through its admin API and prints it.
+fn dump_state(config: &Config, args: &DumpStateArgs) -> anyhow::Result<()> {
+    let url = match &args.url {
+        Some(url) => url.clone(),
+        None => {
+            let mut addr: SocketAddr = config
+                .server
+                .http_addr
+                .parse()
+                .with_context(|| format!("server.http_addr '{}'", config.server.http_addr))?;
+            // A server listening on every interface is reachable locally
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let scheme = if config.server.tls.is_some() {
+                "https"
+            } else {
+                "http"
+            };
+            format!("{}://{}", scheme, addr)
+        }
+    };
+    let token = env::var(server::ADMIN_TOKEN_ENV)
+        .with_context(|| format!("{} is not set", server::ADMIN_TOKEN_ENV))?;
+    let state = AdminClient::new(url, token)?.dump_state()?;
+    let json = serde_json::to_string_pretty(&state)?;
+    match &args.output {
+        Some(path) => {
+            fs::write(path, json + "\n").with_context(|| format!("writing {}", path.display()))?
+        }
+        None => println!("{}", json),
+    }
+    Ok(())
 }
@@ -0,0 +1,569 @@
+//! Camera capture through GStreamer
+//!
+//! Runs a capture and encode pipeline with `gst-launch-1.0` and feeds the
+//! encoded frames into the peer's video track (see [`crate::model::video`]).
+//! The pipeline is configurable up to the encoder; the bridge appends the
+//! elements that write the encoder's output to the launcher's standard
+//! output, and splits that stream into frames:
+//!
+//! * H.264 as an Annex B byte stream, split into access units at access unit
+//!   delimiters, parameter sets, or the first slice of a picture
+//! * VP8 in an IVF container, muxed by `avmux_ivf` of `gst-libav`
+//!
+//! ```toml
+//! [peer.video]
+//! enabled = true
+//! codec = "h264"
+//!
+//! [peer.gstreamer]
+//! enabled = true
+//! pipeline = "v4l2src device=/dev/video0 ! video/x-raw,width=1280,height=720,framerate=30/1 \
+//!             ! videoconvert ! x264enc tune=zerolatency bitrate=2000 key-int-max=30"
+//! ```
+//!
+//! `gst-launch-1.0` cannot be asked for a keyframe, so the default pipelines
+//! produce one every second, and a keyframe request waits for the next one.
+//! A pipeline that exits, e.g. because the camera was unplugged, is started
+//! again after [`RESTART_DELAY`].
+//!
+//! The other way round, a [`GstreamerSink`] writes a video track the peer
+//! receives to the standard input of a pipeline, e.g. to show an operator
+//! the rover's camera:
+//!
+//! ```ignore
+//! let sink = GstreamerSink::start(Path::new("gst-launch-1.0"), DEFAULT_PLAYBACK, VideoCodec::H264)?;
+//! peer.handle().add_frame_sink(sink);
+//! ```
+
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    error::RoverRtcError,
+    media::sink::{FrameSink, ReceivedFrame, VideoWriter},
+    model::video::{VideoCodec, VideoFrame},
+    peer::{PeerEvent, PeerHandle},
+};
+
+/// Time after which a pipeline that exited is started again.
+pub const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+/// Pipeline of a [`GstreamerSink`] showing the video in a window.
+pub const DEFAULT_PLAYBACK: &str = "decodebin ! videoconvert ! autovideosink sync=false";
+
+/// Time a [`GstreamerSink`]'s pipeline gets to handle the end of the stream,
+/// e.g. to complete a file, before it is killed.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Size of the IVF file header.
+const IVF_HEADER: usize = 32;
+
+/// Size of the header of each IVF frame: the frame size and timestamp.
+const IVF_FRAME_HEADER: usize = 12;
+
+/// Largest frame accepted from the pipeline, in bytes.
+const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// GStreamer capture settings, the `[peer.gstreamer]` configuration section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GstreamerConfig {
+    /// Capture and encode with GStreamer and send the frames on the video
+    /// track, which must be enabled
+    pub enabled: bool,
+    /// The pipeline up to and including the encoder, in `gst-launch-1.0`
+    /// syntax; a V4L2 camera pipeline for the codec of the video track if
+    /// unset
+    pub pipeline: Option<String>,
+    /// Camera device of the default pipeline
+    pub device: PathBuf,
+    /// Width of the default pipeline's frames, in pixels
+    pub width: u32,
+    /// Height of the default pipeline's frames, in pixels
+    pub height: u32,
+    /// Frame rate of the default pipeline, in frames per second
+    pub framerate: u32,
+    /// Bit rate of the default pipeline's encoder, in kbit/s
+    pub bitrate_kbps: u32,
+    /// The `gst-launch-1.0` executable
+    pub launcher: PathBuf,
+}
+
+impl Default for GstreamerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pipeline: None,
+            device: PathBuf::from("/dev/video0"),
+            width: 1280,
+            height: 720,
+            framerate: 30,
+            bitrate_kbps: 2000,
+            launcher: PathBuf::from("gst-launch-1.0"),
+        }
+    }
+}
+
+impl GstreamerConfig {
+    /// Checks that the default pipeline's settings are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 || self.framerate == 0 || self.bitrate_kbps == 0 {
+            return Err("width, height, framerate and bitrate_kbps must be positive".into());
+        }
+        if self
+            .pipeline
+            .as_deref()
+            .is_some_and(|pipeline| pipeline.trim().is_empty())
+        {
+            return Err("pipeline must not be empty".into());
+        }
+        Ok(())
+    }
+
+    /// Returns the complete pipeline, writing frames of a codec to the
+    /// standard output.
+    pub fn launch_line(&self, codec: VideoCodec) -> String {
+        let source = format!(
+            "v4l2src device={} ! video/x-raw,width={},height={},framerate={}/1 ! videoconvert",
+            self.device.display(),
+            self.width,
+            self.height,
+            self.framerate
+        );
+        let capture = match (&self.pipeline, codec) {
+            (Some(pipeline), _) => pipeline.clone(),
+            (None, VideoCodec::H264) => format!(
+                "{} ! x264enc tune=zerolatency speed-preset=ultrafast bitrate={} key-int-max={}",
+                source, self.bitrate_kbps, self.framerate
+            ),
+            (None, VideoCodec::Vp8) => format!(
+                "{} ! vp8enc deadline=1 target-bitrate={} keyframe-max-dist={}",
+                source,
+                self.bitrate_kbps * 1000,
+                self.framerate
+            ),
+        };
+        let sink = match codec {
+            VideoCodec::H264 => {
+                "h264parse config-interval=-1 \
+                 ! video/x-h264,stream-format=byte-stream,alignment=au ! fdsink fd=1"
+            }
+            VideoCodec::Vp8 => "avmux_ivf ! fdsink fd=1",
+        };
+        format!("{} ! {}", capture, sink)
+    }
+}
+
+/// Captures frames with a GStreamer pipeline and sends them on a peer's
+/// video track.
+///
+/// The capture runs until dropped.
+#[derive(Debug)]
+pub struct GstreamerCapture {
+    stop: Arc<AtomicBool>,
+    child: Arc<Mutex<Option<Child>>>,
+}
+
+impl GstreamerCapture {
+    /// Starts the pipeline and sends its frames to a peer.
+    ///
+    /// The peer's video track must be enabled with the same codec, see
+    /// [`PeerConfig::video`](crate::peer::PeerConfig::video).
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The capture settings
+    /// * `codec` - The codec of the video track
+    /// * `handle` - The handle of the peer sending the frames
+    ///
+    /// # Returns
+    ///
+    /// The capture, or an error if the launcher could not be started
+    pub fn start(
+        config: &GstreamerConfig,
+        codec: VideoCodec,
+        handle: &PeerHandle,
+    ) -> Result<Self, RoverRtcError> {
+        let launch_line = config.launch_line(codec);
+        info!("GStreamer: Capturing with {}", launch_line);
+        let child = Arc::new(Mutex::new(None));
+        let stdout = spawn(&config.launcher, &launch_line, &child)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        handle.on_event(|event| {
+            if *event == PeerEvent::KeyframeRequested {
+                debug!("GStreamer: Keyframe requested, sending from the next one");
+            }
+        });
+        {
+            let launcher = config.launcher.clone();
+            let child = child.clone();
+            let stop = stop.clone();
+            let handle = handle.clone();
+            thread::Builder::new()
+                .name("gstreamer".to_string())
+                .spawn(move || {
+                    run(
+                        &launcher,
+                        &launch_line,
+                        codec,
+                        stdout,
+                        &child,
+                        &handle,
+                        &stop,
+                    )
+                })?;
+        }
+        Ok(Self { stop, child })
+    }
+}
+
+impl Drop for GstreamerCapture {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(child) = self.child.lock().expect("GStreamer child lock").as_mut() {
+            let _ = child.kill();
+        }
+    }
+}
+
+/// Plays, stores or re-encodes a video track the peer receives with a
+/// GStreamer pipeline.
+///
+/// The first track of the sink's codec is written to the standard input of
+/// `gst-launch-1.0`, parsed for the pipeline, from its first keyframe on.
+/// Unlike a capture, a pipeline that exits is not started again; the sink
+/// fails and the peer removes it.
+pub struct GstreamerSink {
+    codec: VideoCodec,
+    child: Child,
+    writer: Option<VideoWriter<ChildStdin>>,
+}
+
+impl GstreamerSink {
+    /// Starts the pipeline.
+    ///
+    /// # Arguments
+    ///
+    /// * `launcher` - The `gst-launch-1.0` executable
+    /// * `pipeline` - The pipeline after the parser, in `gst-launch-1.0`
+    ///   syntax, e.g. [`DEFAULT_PLAYBACK`]
+    /// * `codec` - The codec of the track to write
+    ///
+    /// # Returns
+    ///
+    /// The sink, or an error if the launcher could not be started
+    pub fn start(
+        launcher: &Path,
+        pipeline: &str,
+        codec: VideoCodec,
+    ) -> Result<Self, RoverRtcError> {
+        let parser = match codec {
+            VideoCodec::H264 => "h264parse",
+            VideoCodec::Vp8 => "ivfparse",
+        };
+        let launch_line = format!("fdsrc fd=0 ! {} ! {}", parser, pipeline);
+        info!("GStreamer: Playing with {}", launch_line);
+        let mut child = Command::new(launcher)
+            .arg("-q")
+            .arg(&launch_line)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", launcher.display(), e)))?;
+        let stdin = child.stdin.take().expect("piped stdin");
+        Ok(Self {
+            codec,
+            child,
+            writer: Some(VideoWriter::new(stdin)),
+        })
+    }
+}
+
+impl FrameSink for GstreamerSink {
+    fn write_frame(&mut self, frame: &ReceivedFrame) -> Result<(), RoverRtcError> {
+        if frame.codec != self.codec.codec() {
+            return Ok(());
+        }
+        if let Some(writer) = self.writer.as_mut() {
+            if writer.write(frame)? {
+                writer.output().flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), RoverRtcError> {
+        // Closing the standard input ends the stream
+        self.writer = None;
+        let deadline = Instant::now() + FINISH_TIMEOUT;
+        while Instant::now() < deadline {
+            if self.child.try_wait()?.is_some() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        warn!("GStreamer: Playback pipeline did not end, stopping it");
+        self.child.kill()?;
+        Ok(())
+    }
+}
+
+impl Drop for GstreamerSink {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Starts the launcher, keeping the process for [`GstreamerCapture`] to stop.
+fn spawn(
+    launcher: &Path,
+    launch_line: &str,
+    child: &Mutex<Option<Child>>,
+) -> io::Result<ChildStdout> {
+    let mut process = Command::new(launcher)
+        // Quiet, so only frames are written to the standard output
+        .arg("-q")
+        .arg(launch_line)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", launcher.display(), e)))?;
+    let stdout = process.stdout.take().expect("piped stdout");
+    *child.lock().expect("GStreamer child lock") = Some(process);
+    Ok(stdout)
+}
+
+/// Sends the frames of the pipeline, restarting it whenever it exits, until
+/// stopped.
+fn run(
+    launcher: &Path,
+    launch_line: &str,
+    codec: VideoCodec,
+    mut stdout: ChildStdout,
+    child: &Mutex<Option<Child>>,
+    handle: &PeerHandle,
+    stop: &AtomicBool,
+) {
+    loop {
+        let sent = read_frames(&mut stdout, codec, handle, stop);
+        let status = child
+            .lock()
+            .expect("GStreamer child lock")
+            .take()
+            .map(|mut process| process.wait());
+        if stop.load(Ordering::Relaxed) {
+            debug!("GStreamer: Capture stopped after {} frames", sent);
+            return;
+        }
+        match status {
+            Some(Ok(status)) => warn!(
+                "GStreamer: Pipeline exited ({}) after {} frames",
+                status, sent
+            ),
+            Some(Err(e)) => warn!("GStreamer: Pipeline lost: {}", e),
+            None => {}
+        }
+        thread::sleep(RESTART_DELAY);
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        match spawn(launcher, launch_line, child) {
+            Ok(restarted) => stdout = restarted,
+            Err(e) => {
+                warn!("GStreamer: Cannot restart the pipeline: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Reads the pipeline's output until it ends, sending each frame.
+///
+/// # Returns
+///
+/// The number of frames sent
+fn read_frames(
+    stdout: &mut ChildStdout,
+    codec: VideoCodec,
+    handle: &PeerHandle,
+    stop: &AtomicBool,
+) -> u64 {
+    let mut splitter = FrameSplitter::new(codec);
+    let mut buf = vec![0; 65_536];
+    let mut sent = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let len = match stdout.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("GStreamer: Failed to read the pipeline: {}", e);
+                break;
+            }
+        };
+        let frames = match splitter.push(&buf[..len]) {
+            Ok(frames) => frames,
+            Err(reason) => {
+                warn!("GStreamer: {}", reason);
+                break;
+            }
+        };
+        for (data, keyframe) in frames {
+            if sent == 0 {
+                info!("GStreamer: First frame, {} bytes", data.len());
+            }
+            sent += 1;
+            handle.send_video_frame(VideoFrame::new(data, keyframe));
+        }
+    }
+    sent
+}
+
+/// Splits the pipeline's output into frames.
+#[derive(Debug)]
+enum FrameSplitter {
+    H264(AccessUnits),
+    Vp8(IvfFrames),
+}
+
+impl FrameSplitter {
+    fn new(codec: VideoCodec) -> Self {
+        match codec {
+            VideoCodec::H264 => FrameSplitter::H264(AccessUnits::default()),
+            VideoCodec::Vp8 => FrameSplitter::Vp8(IvfFrames::default()),
+        }
+    }
+
+    /// Adds output of the pipeline.
+    ///
+    /// # Returns
+    ///
+    /// The frames completed by the output and whether each is a keyframe,
+    /// or the reason the output cannot be split
+    fn push(&mut self, data: &[u8]) -> Result<Vec<(Vec<u8>, bool)>, String> {
+        match self {
+            FrameSplitter::H264(units) => units.push(data),
+            FrameSplitter::Vp8(frames) => frames.push(data),
+        }
+    }
+}
+
+/// Splits an H.264 Annex B byte stream into access units.
+///
+/// A unit is complete once the first NAL unit of the next one starts, so
+/// each frame is sent when the encoder starts writing the next.
+#[derive(Debug, Default)]
+struct AccessUnits {
+    buffer: Vec<u8>,
+    /// Offset up to which the buffer was searched for start codes
+    scanned: usize,
+    /// Whether the current unit has a slice
+    has_slice: bool,
+    /// Whether the current unit has an IDR slice
+    keyframe: bool,
+}
+
+impl AccessUnits {
+    fn push(&mut self, data: &[u8]) -> Result<Vec<(Vec<u8>, bool)>, String> {
+        self.buffer.extend_from_slice(data);
+        let mut units = vec![];
+        let mut at = self.scanned;
+        // A start code 00 00 01, the NAL header, and the first byte of a slice
+        while at + 5 <= self.buffer.len() {
+            if self.buffer[at..at + 3] != [0, 0, 1] {
+                at += 1;
+                continue;
+            }
+            let nal_type = self.buffer[at + 3] & 0x1f;
+            let is_slice = matches!(nal_type, 1 | 5);
+            // first_mb_in_slice is 0, coded as a single 1 bit, in the first
+            // slice of a picture
+            let first_slice = is_slice && self.buffer[at + 4] & 0x80 != 0;
+            // A delimiter, SEI or parameter set after a slice, or the first
+            // slice of another picture, starts the next unit
+            if self.has_slice && (matches!(nal_type, 6..=9) || first_slice) {
+                // The four-byte form 00 00 00 01 belongs to the next unit
+                let start = if at > 0 && self.buffer[at - 1] == 0 {
+                    at - 1
+                } else {
+                    at
+                };
+                units.push((self.buffer.drain(..start).collect(), self.keyframe));
+                at -= start;
+                self.has_slice = false;
+                self.keyframe = false;
+            }
+            self.has_slice |= is_slice;
+            self.keyframe |= nal_type == 5;
+            at += 4;
+        }
+        self.scanned = at;
+        if self.buffer.len() > MAX_FRAME_SIZE {
+            return Err(format!(
+                "An H.264 access unit exceeds {} bytes",
+                MAX_FRAME_SIZE
+            ));
+        }
+        Ok(units)
+    }
+}
+
+/// Splits an IVF stream into VP8 frames.
+#[derive(Debug, Default)]
+struct IvfFrames {
+    buffer: Vec<u8>,
+    header_read: bool,
+}
+
+impl IvfFrames {
+    fn push(&mut self, data: &[u8]) -> Result<Vec<(Vec<u8>, bool)>, String> {
+        self.buffer.extend_from_slice(data);
+        if !self.header_read {
+            if self.buffer.len() < IVF_HEADER {
+                return Ok(vec![]);
+            }
+            if &self.buffer[..4] != b"DKIF" {
+                return Err("The pipeline does not write IVF".into());
+            }
+            self.buffer.drain(..IVF_HEADER);
+            self.header_read = true;
+        }
+        let mut frames = vec![];
+        while self.buffer.len() >= IVF_FRAME_HEADER {
+            let size = u32::from_le_bytes([
+                self.buffer[0],
+                self.buffer[1],
+                self.buffer[2],
+                self.buffer[3],
+            ]) as usize;
+            if size > MAX_FRAME_SIZE {
+                return Err(format!("A VP8 frame exceeds {} bytes", MAX_FRAME_SIZE));
+            }
+            if self.buffer.len() < IVF_FRAME_HEADER + size {
+                break;
+            }
+            let frame: Vec<u8> = self
+                .buffer
+                .drain(..IVF_FRAME_HEADER + size)
+                .skip(IVF_FRAME_HEADER)
+                .collect();
+            // The low bit of the frame tag is clear on keyframes
+            let keyframe = frame.first().is_some_and(|tag| tag & 1 == 0);
+            frames.push((frame, keyframe));
+        }
+        Ok(frames)
+    }
+}
@@ -0,0 +1,10 @@
+//! Media capture and playback for the peer's tracks
+//!
+//! Sources that produce encoded frames for
+//! [`PeerHandle::send_video_frame`](crate::peer::PeerHandle::send_video_frame),
+//! so a rover streams its camera without glue code of its own, and sinks
+//! consuming the frames the peer receives, see [`sink`].
+
+#[cfg(feature = "gstreamer")]
+pub mod gstreamer;
+pub mod sink;
@@ -0,0 +1,275 @@
+//! Consumers of the media the peer receives
+//!
+//! The remote may send the peer media of its own, e.g. a rover's camera
+//! reaching an operator console in mesh mode, and the server forwards the
+//! tracks other clients publish (see [`crate::model::forward`]). str0m
+//! depacketizes the RTP of each track into whole frames: H.264 access units
+//! as an Annex B byte stream, VP8 frames and Opus packets. Each frame is
+//! reported as [`PeerEvent::Media`](crate::peer::PeerEvent::Media), or as
+//! [`PeerEvent::Audio`](crate::peer::PeerEvent::Audio) for the operator's
+//! voice, and written to the [`FrameSink`]s added with
+//! [`PeerHandle::add_frame_sink`](crate::peer::PeerHandle::add_frame_sink):
+//!
+//! * [`FileSink`] stores one video track as a raw H.264 stream or an IVF file
+//! * [`CallbackSink`] hands every frame to a closure
+//! * `GstreamerSink` (`gstreamer` feature) plays or re-encodes one video track
+//!   with a `gst-launch-1.0` pipeline
+//!
+//! A sink whose write fails is removed. The sinks are finished when the peer
+//! stops, and kept across reconnections.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use str0m::{
+    format::Codec,
+    media::{Frequency, MediaKind, MediaTime},
+};
+use tracing::{debug, info};
+
+use crate::error::RoverRtcError;
+
+/// Size of the IVF file header.
+const IVF_HEADER: usize = 32;
+
+/// Offset of the frame count in the IVF file header.
+const IVF_FRAME_COUNT: u64 = 24;
+
+/// A frame received on a media track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedFrame {
+    /// The ID of the publishing client for a track the server forwards, `0`
+    /// for a track of the remote itself
+    pub origin: u64,
+    /// The media ID of the track
+    pub mid: String,
+    pub kind: MediaKind,
+    pub codec: Codec,
+    /// The RTP time of the frame
+    pub time: MediaTime,
+    /// Whether the frame decodes without any earlier frame
+    pub keyframe: bool,
+    /// Whether no frame was lost since the previous one of the track
+    pub contiguous: bool,
+    pub data: Vec<u8>,
+}
+
+impl ReceivedFrame {
+    /// Returns `true` if an H.264 access unit or a VP8 frame decodes without
+    /// any earlier frame; audio frames always do.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - The codec of the frame
+    /// * `data` - The frame, H.264 as an Annex B byte stream
+    pub fn is_keyframe(codec: Codec, data: &[u8]) -> bool {
+        match codec {
+            // An IDR slice follows one of the start codes
+            Codec::H264 => data
+                .windows(4)
+                .any(|w| w[..3] == [0, 0, 1] && w[3] & 0x1f == 5),
+            // The low bit of the frame tag is clear on keyframes
+            Codec::Vp8 => data.first().is_some_and(|tag| tag & 1 == 0),
+            codec => codec.is_audio(),
+        }
+    }
+
+    /// Returns the dimensions in a VP8 keyframe's header.
+    fn vp8_size(&self) -> Option<(u16, u16)> {
+        if self.codec != Codec::Vp8 || !self.keyframe || self.data.len() < 10 {
+            return None;
+        }
+        let width = u16::from_le_bytes([self.data[6], self.data[7]]) & 0x3fff;
+        let height = u16::from_le_bytes([self.data[8], self.data[9]]) & 0x3fff;
+        Some((width, height))
+    }
+}
+
+/// A consumer of received frames.
+pub trait FrameSink: Send {
+    /// Consumes a frame of any track.
+    ///
+    /// # Returns
+    ///
+    /// An error if the sink cannot take further frames; it is then removed
+    fn write_frame(&mut self, frame: &ReceivedFrame) -> Result<(), RoverRtcError>;
+
+    /// Completes the output once the peer stops, e.g. a file's header.
+    fn finish(&mut self) -> Result<(), RoverRtcError> {
+        Ok(())
+    }
+}
+
+/// Hands every frame to a closure.
+pub struct CallbackSink<F>(F);
+
+impl<F: FnMut(&ReceivedFrame) + Send> CallbackSink<F> {
+    /// Creates a sink calling `callback` with each frame, on the peer's
+    /// thread; it should return quickly.
+    pub fn new(callback: F) -> Self {
+        Self(callback)
+    }
+}
+
+impl<F: FnMut(&ReceivedFrame) + Send> FrameSink for CallbackSink<F> {
+    fn write_frame(&mut self, frame: &ReceivedFrame) -> Result<(), RoverRtcError> {
+        (self.0)(frame);
+        Ok(())
+    }
+}
+
+/// Writes one video track as decoders read it from a stream: H.264 as it
+/// arrives, VP8 in an IVF container.
+///
+/// Frames before the first keyframe, and frames following a loss until the
+/// next keyframe, are dropped, since they would not decode.
+pub(crate) struct VideoWriter<W: Write> {
+    output: W,
+    /// The track written, once its first keyframe arrived
+    track: Option<(String, Codec)>,
+    /// Whether frames are dropped until the next keyframe
+    awaiting_keyframe: bool,
+    /// RTP time of the first frame written
+    start: Option<MediaTime>,
+    frames: u32,
+}
+
+impl<W: Write> VideoWriter<W> {
+    pub(crate) fn new(output: W) -> Self {
+        Self {
+            output,
+            track: None,
+            awaiting_keyframe: true,
+            start: None,
+            frames: 0,
+        }
+    }
+
+    /// Writes a frame of the track, starting with the first video keyframe
+    /// of H.264 or VP8.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the frame was written
+    pub(crate) fn write(&mut self, frame: &ReceivedFrame) -> Result<bool, RoverRtcError> {
+        match &self.track {
+            Some((mid, _)) if *mid != frame.mid => return Ok(false),
+            Some(_) => {}
+            None if frame.keyframe && matches!(frame.codec, Codec::H264 | Codec::Vp8) => {
+                info!(
+                    "Media: Writing {} track {} of Client({})",
+                    frame.codec, frame.mid, frame.origin
+                );
+                self.track = Some((frame.mid.clone(), frame.codec));
+                if let Some((width, height)) = frame.vp8_size() {
+                    self.write_ivf_header(width, height)?;
+                }
+            }
+            None => return Ok(false),
+        }
+        if !frame.contiguous {
+            self.awaiting_keyframe = true;
+        }
+        if self.awaiting_keyframe && !frame.keyframe {
+            debug!("Media: Dropping a frame until the next keyframe");
+            return Ok(false);
+        }
+        self.awaiting_keyframe = false;
+        if frame.codec == Codec::Vp8 {
+            let start = *self.start.get_or_insert(frame.time);
+            let timestamp = frame
+                .time
+                .saturating_sub(start)
+                .rebase(Frequency::NINETY_KHZ)
+                .numer();
+            self.output
+                .write_all(&(frame.data.len() as u32).to_le_bytes())?;
+            self.output.write_all(&timestamp.to_le_bytes())?;
+        }
+        self.output.write_all(&frame.data)?;
+        self.frames += 1;
+        Ok(true)
+    }
+
+    /// Returns the codec of the track written, once it started.
+    pub(crate) fn codec(&self) -> Option<Codec> {
+        self.track.as_ref().map(|(_, codec)| *codec)
+    }
+
+    /// Returns the number of frames written.
+    pub(crate) fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    pub(crate) fn output(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    fn write_ivf_header(&mut self, width: u16, height: u16) -> Result<(), RoverRtcError> {
+        let mut header = Vec::with_capacity(IVF_HEADER);
+        header.extend_from_slice(b"DKIF");
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&(IVF_HEADER as u16).to_le_bytes());
+        header.extend_from_slice(b"VP80");
+        header.extend_from_slice(&width.to_le_bytes());
+        header.extend_from_slice(&height.to_le_bytes());
+        header.extend_from_slice(&Frequency::NINETY_KHZ.get().to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+        // The frame count, completed once the track ends
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        self.output.write_all(&header)?;
+        Ok(())
+    }
+}
+
+/// Stores the first video track received in a file: H.264 as a raw Annex B
+/// stream, e.g. `camera.h264`, VP8 as IVF, e.g. `camera.ivf`.
+///
+/// Other tracks, and the frames of the track before its first keyframe,
+/// are ignored.
+pub struct FileSink {
+    path: PathBuf,
+    writer: VideoWriter<BufWriter<File>>,
+}
+
+impl FileSink {
+    /// Creates the file, replacing an existing one.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, RoverRtcError> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path)?;
+        Ok(Self {
+            path,
+            writer: VideoWriter::new(BufWriter::new(file)),
+        })
+    }
+}
+
+impl FrameSink for FileSink {
+    fn write_frame(&mut self, frame: &ReceivedFrame) -> Result<(), RoverRtcError> {
+        self.writer.write(frame)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), RoverRtcError> {
+        let frames = self.writer.frames();
+        let codec = self.writer.codec();
+        let output = self.writer.output();
+        output.flush()?;
+        if codec == Some(Codec::Vp8) {
+            let file = output.get_mut();
+            file.seek(SeekFrom::Start(IVF_FRAME_COUNT))?;
+            file.write_all(&frames.to_le_bytes())?;
+            file.seek(SeekFrom::End(0))?;
+        }
+        info!(
+            "Media: Wrote {} frame(s) to {}",
+            frames,
+            self.path.display()
+        );
+        Ok(())
+    }
+}
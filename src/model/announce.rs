@@ -0,0 +1,258 @@
+//! Room announcements with delivery acknowledgment
+//!
+//! Routing a message to `*` (see [`crate::model::routing`]) reaches every
+//! client of a room, but nobody learns who actually got it. A mission-critical
+//! announcement, e.g. "return to base", instead goes out as an
+//! [`AnnounceMessage::Announce`] on the reliable "announce" data channel of
+//! each client in the room, and each peer answers with an
+//! [`AnnounceMessage::Ack`] once it handed the announcement to the
+//! application. A [`PendingBroadcast`] collects the acknowledgments until all
+//! arrived or the timeout passed, and reports the aggregate as a
+//! [`BroadcastReport`], e.g. "delivered to 4/5 clients, rover-3 timed out".
+
+use std::{
+    collections::HashSet,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use bincode::config::{self, Configuration};
+use serde::{Deserialize, Serialize};
+
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying announcements.
+pub const ANNOUNCE_CHANNEL: &str = "announce";
+
+/// How long acknowledgments are awaited by default.
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages exchanged on the announce channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum AnnounceMessage {
+    /// An announcement to hand to the application.
+    Announce { id: u32, data: Vec<u8> },
+    /// The peer handed the announcement to the application.
+    Ack { id: u32 },
+}
+
+impl AnnounceMessage {
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(AnnounceMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+}
+
+/// What became of an announcement for one client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    /// The peer acknowledged it
+    Delivered,
+    /// No acknowledgment arrived before the timeout, or the client left
+    TimedOut,
+    /// The client has no announce channel, so it was not sent
+    Unreachable,
+}
+
+/// The delivery of an announcement to one client of the room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecipientDelivery {
+    /// The client's numeric ID
+    pub id: u64,
+    /// The alias the client announced, if any
+    pub alias: Option<String>,
+    pub delivery: Delivery,
+}
+
+impl RecipientDelivery {
+    /// Returns the alias of the client, or its numeric ID.
+    pub fn name(&self) -> String {
+        self.alias
+            .clone()
+            .unwrap_or_else(|| format!("client {}", self.id))
+    }
+}
+
+/// The aggregate result of an announcement to a room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BroadcastReport {
+    /// The ID of the announcement
+    pub id: u32,
+    /// The room it went to
+    pub room: String,
+    /// Number of clients that acknowledged it
+    pub delivered: usize,
+    /// Number of clients in the room when it was sent
+    pub total: usize,
+    /// Every client of the room, in ID order
+    pub recipients: Vec<RecipientDelivery>,
+    /// How long the acknowledgments took, in milliseconds
+    pub elapsed_ms: u64,
+}
+
+impl BroadcastReport {
+    /// Returns `true` if every client of the room acknowledged it.
+    pub fn is_complete(&self) -> bool {
+        self.delivered == self.total
+    }
+}
+
+impl fmt::Display for BroadcastReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "delivered to {}/{} clients", self.delivered, self.total)?;
+        for recipient in &self.recipients {
+            match recipient.delivery {
+                Delivery::Delivered => {}
+                Delivery::TimedOut => write!(f, ", {} timed out", recipient.name())?,
+                Delivery::Unreachable => write!(f, ", {} unreachable", recipient.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An announcement waiting for the acknowledgments of a room's clients.
+#[derive(Debug)]
+pub struct PendingBroadcast {
+    id: u32,
+    room: String,
+    started: Instant,
+    deadline: Instant,
+    recipients: Vec<RecipientDelivery>,
+    /// Clients the announcement was sent to that did not acknowledge it yet
+    awaited: HashSet<u64>,
+}
+
+impl PendingBroadcast {
+    /// Starts waiting for the acknowledgments of an announcement.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the announcement
+    /// * `room` - The room it went to
+    /// * `recipients` - The ID and alias of each client of the room, and
+    ///   whether the announcement was sent to it
+    /// * `timeout` - How long acknowledgments are awaited
+    pub fn new(
+        id: u32,
+        room: &str,
+        recipients: Vec<(u64, Option<String>, bool)>,
+        timeout: Duration,
+    ) -> Self {
+        let started = Instant::now();
+        let mut awaited = HashSet::new();
+        let mut recipients: Vec<_> = recipients
+            .into_iter()
+            .map(|(id, alias, sent)| {
+                if sent {
+                    awaited.insert(id);
+                }
+                RecipientDelivery {
+                    id,
+                    alias,
+                    delivery: if sent {
+                        Delivery::TimedOut
+                    } else {
+                        Delivery::Unreachable
+                    },
+                }
+            })
+            .collect();
+        recipients.sort_by_key(|r| r.id);
+        Self {
+            id,
+            room: room.to_string(),
+            started,
+            deadline: started + timeout,
+            recipients,
+            awaited,
+        }
+    }
+
+    /// Returns the ID of the announcement.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the clients whose acknowledgment is still awaited.
+    pub fn awaited(&self) -> impl Iterator<Item = u64> + '_ {
+        self.awaited.iter().copied()
+    }
+
+    /// Records a client's acknowledgment.
+    pub fn acknowledge(&mut self, client: u64) {
+        if !self.awaited.remove(&client) {
+            return;
+        }
+        if let Some(recipient) = self.recipients.iter_mut().find(|r| r.id == client) {
+            recipient.delivery = Delivery::Delivered;
+        }
+    }
+
+    /// Gives up on a client that left the room.
+    pub fn abandon(&mut self, client: u64) {
+        self.awaited.remove(&client);
+    }
+
+    /// Returns `true` once every acknowledgment arrived or the timeout passed.
+    pub fn is_settled(&self, now: Instant) -> bool {
+        self.awaited.is_empty() || now >= self.deadline
+    }
+
+    /// Ends the wait; clients that did not acknowledge the announcement
+    /// timed out.
+    pub fn finish(self) -> BroadcastReport {
+        let delivered = self
+            .recipients
+            .iter()
+            .filter(|r| r.delivery == Delivery::Delivered)
+            .count();
+        BroadcastReport {
+            id: self.id,
+            room: self.room,
+            delivered,
+            total: self.recipients.len(),
+            recipients: self.recipients,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+impl WireSchema for AnnounceMessage {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "AnnounceMessage",
+            "Messages exchanged on the announce channel",
+            vec![
+                (
+                    "Announce",
+                    "An announcement to hand to the application",
+                    vec![
+                        field("id", WireType::U32, "ID of the announcement"),
+                        field("data", WireType::Bytes, "The announcement"),
+                    ],
+                ),
+                (
+                    "Ack",
+                    "The peer handed the announcement to the application",
+                    vec![field("id", WireType::U32, "ID of the announcement")],
+                ),
+            ],
+        )
+    }
+}
@@ -0,0 +1,202 @@
+//! Operator voice channel to the peer
+//!
+//! An operator pushes voice commands to a rover over an audio track next to
+//! the data channels. The peer offers a receive-only audio m-line with Opus
+//! and G.711 µ-law (PCMU); the server writes Opus packets from the operator's
+//! encoder as they are, and encodes raw PCM to PCMU, so a console without an
+//! Opus encoder can still talk. On the rover, every received frame is
+//! reported as a [`PeerEvent::Audio`](crate::peer::PeerEvent::Audio), with
+//! PCMU decoded back to PCM.
+
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use str0m::{
+    format::Codec,
+    media::{Frequency, MediaTime, Mid},
+    Rtc, RtcConfig, RtcError,
+};
+
+/// Sample rate of Opus RTP timestamps.
+const OPUS_RATE: u64 = 48_000;
+
+/// Sample rate of PCM frames, and of PCMU RTP timestamps.
+pub const PCM_RATE: u32 = 8_000;
+
+/// Audio settings of the peer, the `[peer.audio]` section.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// Negotiate an audio track receiving the operator's voice
+    pub enabled: bool,
+}
+
+impl AudioConfig {
+    /// Enables the audio codecs in an RTC configuration, if audio is enabled.
+    pub fn enable_codecs(&self, rtc_config: RtcConfig) -> RtcConfig {
+        if !self.enabled {
+            return rtc_config;
+        }
+        rtc_config.enable_opus(true).enable_pcmu(true)
+    }
+}
+
+/// An audio frame.
+///
+/// A stream should stick to one variant: both share the RTP stream of the
+/// track, and receivers lose frames around a switch of clock rate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioFrame {
+    /// One Opus packet, as produced by the encoder at 48 kHz
+    Opus(Vec<u8>),
+    /// Mono 16-bit samples at [`PCM_RATE`], sent as G.711 µ-law
+    Pcm(Vec<i16>),
+}
+
+impl AudioFrame {
+    /// Returns the codec the frame is sent with.
+    fn codec(&self) -> Codec {
+        match self {
+            AudioFrame::Opus(_) => Codec::Opus,
+            AudioFrame::Pcm(_) => Codec::PCMU,
+        }
+    }
+
+    /// Returns the number of samples in the frame, at the clock rate of its
+    /// codec.
+    ///
+    /// # Returns
+    ///
+    /// The number of samples, or `None` for an empty or malformed Opus packet
+    fn samples(&self) -> Option<u64> {
+        match self {
+            AudioFrame::Opus(packet) => opus_samples(packet),
+            AudioFrame::Pcm(samples) => Some(samples.len() as u64),
+        }
+    }
+
+    /// Decodes a frame received on the audio track.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - The codec of the payload type it arrived with
+    /// * `data` - The payload
+    ///
+    /// # Returns
+    ///
+    /// The frame, or `None` for a codec the voice channel does not carry
+    pub fn from_media(codec: Codec, data: Vec<u8>) -> Option<Self> {
+        match codec {
+            Codec::Opus => Some(AudioFrame::Opus(data)),
+            Codec::PCMU => Some(AudioFrame::Pcm(data.into_iter().map(ulaw_decode).collect())),
+            _ => None,
+        }
+    }
+}
+
+/// The outgoing audio track of a client, on the server.
+#[derive(Debug)]
+pub struct AudioSender {
+    /// The media ID of the track
+    mid: Mid,
+    /// RTP timestamp of the next Opus frame
+    opus_time: u64,
+    /// RTP timestamp of the next PCMU frame
+    pcmu_time: u64,
+}
+
+impl AudioSender {
+    /// Creates a sender for a negotiated track.
+    pub fn new(mid: Mid) -> Self {
+        Self {
+            mid,
+            opus_time: 0,
+            pcmu_time: 0,
+        }
+    }
+
+    /// Writes a frame to the track.
+    ///
+    /// # Arguments
+    ///
+    /// * `rtc` - The RTC instance of the client
+    /// * `frame` - The frame to send
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the frame was written
+    /// * `Ok(false)` - If the peer did not accept the frame's codec, or the
+    ///   frame is not a valid Opus packet
+    /// * `Err(RtcError)` - If str0m refused the frame
+    pub fn write(&mut self, rtc: &mut Rtc, frame: AudioFrame) -> Result<bool, RtcError> {
+        let Some(writer) = rtc.writer(self.mid) else {
+            return Ok(false);
+        };
+        let codec = frame.codec();
+        let Some(pt) = writer
+            .payload_params()
+            .find(|params| params.spec().codec == codec)
+            .map(|params| params.pt())
+        else {
+            return Ok(false);
+        };
+        let Some(samples) = frame.samples() else {
+            return Ok(false);
+        };
+
+        let (time, frequency, data) = match frame {
+            AudioFrame::Opus(packet) => (&mut self.opus_time, Frequency::FORTY_EIGHT_KHZ, packet),
+            AudioFrame::Pcm(samples) => (
+                &mut self.pcmu_time,
+                Frequency::EIGHT_KHZ,
+                samples.into_iter().map(ulaw_encode).collect(),
+            ),
+        };
+        writer.write(pt, Instant::now(), MediaTime::new(*time, frequency), data)?;
+        *time += samples;
+        Ok(true)
+    }
+}
+
+/// Returns the number of 48 kHz samples in an Opus packet, from its TOC byte
+/// (RFC 6716, section 3.1).
+fn opus_samples(packet: &[u8]) -> Option<u64> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+    // Frame durations in units of 2.5 ms
+    let frame_units: u64 = match config {
+        0..=11 => [4, 8, 16, 24][(config % 4) as usize],
+        12..=15 => [4, 8][(config % 2) as usize],
+        _ => [1, 2, 4, 8][(config % 4) as usize],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3f) as u64,
+    };
+    Some(frame_units * frames * OPUS_RATE / 400)
+}
+
+/// Encodes a 16-bit sample as G.711 µ-law.
+fn ulaw_encode(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32_635;
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = (sample as i32).abs().min(CLIP) + BIAS;
+    let exponent = (31 - magnitude.leading_zeros() as i32 - 7).clamp(0, 7);
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0f;
+    !(sign | (exponent << 4) as u8 | mantissa as u8)
+}
+
+/// Decodes a G.711 µ-law byte into a 16-bit sample.
+fn ulaw_decode(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0f) as i32;
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if byte & 0x80 != 0 {
+        -magnitude as i16
+    } else {
+        magnitude as i16
+    }
+}
@@ -0,0 +1,194 @@
+//! Coalescing of small messages into batches
+//!
+//! A rover streaming sensor samples at 100 Hz pays the SCTP, DTLS and UDP
+//! overhead once per sample. Channels configured with a batch window collect
+//! the messages sent within that window and send them as one SCTP message,
+//! framed as a [`BATCH_MARKER`] byte followed by the bincode-encoded list of
+//! messages. The receiver unpacks the batch and handles each message as if it
+//! had arrived on its own.
+//!
+//! Batches are only sent once both sides advertised
+//! [`Feature::Batching`](crate::model::control::Feature::Batching). From then
+//! on, a message starting with the marker byte is a batch; a single message
+//! that happens to start with it is sent as a batch of one.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use bincode::config::{self, Configuration};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// First byte of a batch.
+pub const BATCH_MARKER: u8 = 0xFE;
+
+/// The largest batch, in bytes, so that a batch fits in one SCTP chunk.
+pub const MAX_BATCH_SIZE: usize = 1100;
+
+/// Bytes added per message by the batch framing, at most: a varint length.
+const MESSAGE_OVERHEAD: usize = 3;
+
+/// Bytes added per batch by the framing, at most: the marker and a varint count.
+const BATCH_OVERHEAD: usize = 4;
+
+/// Returns `true` if the message is a batch.
+///
+/// Only meaningful once batching was negotiated with the sender.
+pub fn is_batch(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&BATCH_MARKER)
+}
+
+/// Frames messages as one batch.
+pub fn encode_batch(messages: &[Vec<u8>]) -> Vec<u8> {
+    let body = bincode::encode_to_vec(messages, BINCODE_CONFIG).expect("Serialization failed");
+    [vec![BATCH_MARKER], body].concat()
+}
+
+/// Unpacks a batch.
+///
+/// # Returns
+///
+/// * `Some(Vec<Vec<u8>>)` - The messages, in the order they were sent
+/// * `None` - If the bytes are not a well-formed batch
+pub fn decode_batch(bytes: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let [BATCH_MARKER, body @ ..] = bytes else {
+        return None;
+    };
+    bincode::decode_from_slice(body, BINCODE_CONFIG)
+        .ok()
+        .filter(|(_, read)| *read == body.len())
+        .map(|(messages, _)| messages)
+}
+
+/// Frames a message sent on its own once batching was negotiated.
+///
+/// # Returns
+///
+/// The message itself, or a batch of one if it starts with the marker byte
+pub fn frame_single(message: Vec<u8>) -> Vec<u8> {
+    if is_batch(&message) {
+        encode_batch(&[message])
+    } else {
+        message
+    }
+}
+
+/// Messages collected for one channel.
+#[derive(Debug)]
+struct PendingBatch {
+    messages: Vec<Vec<u8>>,
+    size: usize,
+    deadline: Instant,
+}
+
+impl PendingBatch {
+    fn encode(self) -> Vec<u8> {
+        match <[Vec<u8>; 1]>::try_from(self.messages) {
+            Ok([message]) => frame_single(message),
+            Err(messages) => encode_batch(&messages),
+        }
+    }
+}
+
+/// Collects the messages of each channel until its batch window elapses or
+/// the batch is full.
+#[derive(Debug, Default)]
+pub struct Batcher {
+    pending: HashMap<String, PendingBatch>,
+    /// Size limit below [`MAX_BATCH_SIZE`], e.g. for a path with a small MTU
+    max_size: Option<usize>,
+}
+
+impl Batcher {
+    /// Creates a batcher without pending messages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits batches to the given size, so each fits in one datagram of the
+    /// path; sizes above [`MAX_BATCH_SIZE`] are capped to it.
+    pub fn set_max_size(&mut self, size: usize) {
+        self.max_size = Some(size.min(MAX_BATCH_SIZE));
+    }
+
+    /// Adds a message to the batch of its channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel
+    /// * `message` - The message to send
+    /// * `window` - How long the first message of a batch may wait
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// What must be sent on the channel right away, in order: the pending
+    /// batch if the message does not fit in it, and the message itself if it
+    /// is too large to be batched at all
+    pub fn push(
+        &mut self,
+        label: &str,
+        message: Vec<u8>,
+        window: Duration,
+        now: Instant,
+    ) -> Vec<Vec<u8>> {
+        let mut ready = vec![];
+        let size = message.len() + MESSAGE_OVERHEAD;
+        let max_size = self.max_size.unwrap_or(MAX_BATCH_SIZE);
+        if size + BATCH_OVERHEAD > max_size {
+            ready.extend(self.take(label));
+            ready.push(frame_single(message));
+            return ready;
+        }
+        if self
+            .pending
+            .get(label)
+            .is_some_and(|p| p.size + size > max_size)
+        {
+            ready.extend(self.take(label));
+        }
+        let pending = self
+            .pending
+            .entry(label.to_string())
+            .or_insert_with(|| PendingBatch {
+                messages: vec![],
+                size: BATCH_OVERHEAD,
+                deadline: now + window,
+            });
+        pending.messages.push(message);
+        pending.size += size;
+        ready
+    }
+
+    /// Returns the batches whose window has elapsed, with their channel label.
+    pub fn poll(&mut self, now: Instant) -> Vec<(String, Vec<u8>)> {
+        let due: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(label, _)| label.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|label| self.take(&label).map(|batch| (label, batch)))
+            .collect()
+    }
+
+    /// Returns every pending batch, e.g. before closing the connection.
+    pub fn flush(&mut self) -> Vec<(String, Vec<u8>)> {
+        self.pending
+            .drain()
+            .map(|(label, pending)| (label, pending.encode()))
+            .collect()
+    }
+
+    /// Returns when the next batch is due, if any is pending.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.deadline).min()
+    }
+
+    fn take(&mut self, label: &str) -> Option<Vec<u8>> {
+        self.pending.remove(label).map(PendingBatch::encode)
+    }
+}
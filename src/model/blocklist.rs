@@ -0,0 +1,211 @@
+//! Blocklist of abusive source addresses
+//!
+//! Sources that flood the UDP port with packets no client accepts are blocked
+//! automatically for a while; administrators can also block and unblock
+//! addresses manually. Blocked packets are dropped before demultiplexing.
+
+use std::{
+    collections::HashMap,
+    env,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::warn;
+
+/// Environment variable overriding the automatic blocking threshold.
+pub const BLOCK_THRESHOLD_ENV: &str = "ROVER_BLOCK_THRESHOLD";
+
+/// Environment variable overriding the automatic blocking duration, in seconds.
+pub const BLOCK_DURATION_ENV: &str = "ROVER_BLOCK_SECS";
+
+/// Window over which unmatched packets are counted.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Default number of unmatched packets per window that triggers a block.
+const DEFAULT_THRESHOLD: u64 = 500;
+
+/// Default duration of an automatic block.
+const DEFAULT_BLOCK_DURATION: Duration = Duration::from_secs(600);
+
+/// A blocked address.
+#[derive(Debug, Clone)]
+pub struct BlockEntry {
+    /// Why the address was blocked
+    pub reason: String,
+    /// When the block expires; `None` blocks until removed
+    pub until: Option<Instant>,
+    /// Packets dropped since the block started
+    pub dropped: u64,
+}
+
+/// Serializable view of a blocked address for the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockInfo {
+    pub ip: IpAddr,
+    pub reason: String,
+    /// Seconds until the block expires; `None` if permanent
+    pub expires_in_secs: Option<u64>,
+    pub dropped: u64,
+}
+
+/// Automatic and manual blocklist keyed by source IP.
+#[derive(Debug)]
+pub struct Blocklist {
+    entries: HashMap<IpAddr, BlockEntry>,
+    unmatched: HashMap<IpAddr, (Instant, u64)>,
+    threshold: u64,
+    block_duration: Duration,
+}
+
+impl Default for Blocklist {
+    fn default() -> Self {
+        Self::new(DEFAULT_THRESHOLD, DEFAULT_BLOCK_DURATION)
+    }
+}
+
+impl Blocklist {
+    /// Creates an empty blocklist.
+    ///
+    /// # Arguments
+    ///
+    /// * `threshold` - Unmatched packets per 10 s window that trigger a block
+    /// * `block_duration` - How long automatic blocks last
+    pub fn new(threshold: u64, block_duration: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            unmatched: HashMap::new(),
+            threshold,
+            block_duration,
+        }
+    }
+
+    /// Creates a blocklist using the thresholds from the environment.
+    pub fn from_env() -> Self {
+        let threshold = env::var(BLOCK_THRESHOLD_ENV)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_THRESHOLD);
+        let block_duration = env::var(BLOCK_DURATION_ENV)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_BLOCK_DURATION);
+        Self::new(threshold, block_duration)
+    }
+
+    /// Checks whether packets from an address should be dropped.
+    ///
+    /// Counts the dropped packet and lifts the block if it has expired.
+    pub fn check_and_count(&mut self, ip: IpAddr) -> bool {
+        let Some(entry) = self.entries.get_mut(&ip) else {
+            return false;
+        };
+        if entry.until.is_some_and(|until| until <= Instant::now()) {
+            self.entries.remove(&ip);
+            return false;
+        }
+        entry.dropped += 1;
+        true
+    }
+
+    /// Records a packet from an address that no client accepted.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the address crossed the threshold and was blocked
+    pub fn record_unmatched(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let (started, count) = self.unmatched.entry(ip).or_insert((now, 0));
+        if now.duration_since(*started) > RATE_WINDOW {
+            *started = now;
+            *count = 0;
+        }
+        *count += 1;
+
+        if *count < self.threshold {
+            return false;
+        }
+
+        self.unmatched.remove(&ip);
+        warn!(
+            "Blocking {} for {:?}: {} unmatched packets within {:?}",
+            ip, self.block_duration, self.threshold, RATE_WINDOW
+        );
+        let reason = format!("more than {} unmatched packets", self.threshold);
+        if let Err(e) = self.block(ip, reason, Some(self.block_duration)) {
+            warn!("Cannot block {}: {}", ip, e);
+            return false;
+        }
+        true
+    }
+
+    /// Blocks an address.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The address to block
+    /// * `reason` - Why the address is blocked
+    /// * `duration` - How long to block; `None` blocks until removed
+    ///
+    /// # Returns
+    ///
+    /// An error if the block would end beyond what the clock can represent
+    pub fn block(
+        &mut self,
+        ip: IpAddr,
+        reason: String,
+        duration: Option<Duration>,
+    ) -> Result<(), String> {
+        let until = match duration {
+            Some(d) => Some(
+                Instant::now()
+                    .checked_add(d)
+                    .ok_or_else(|| format!("a block of {}s is too long", d.as_secs()))?,
+            ),
+            None => None,
+        };
+        self.entries.insert(
+            ip,
+            BlockEntry {
+                reason,
+                until,
+                dropped: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes an address from the blocklist.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the address was blocked
+    pub fn unblock(&mut self, ip: IpAddr) -> bool {
+        self.entries.remove(&ip).is_some()
+    }
+
+    /// Drops expired entries and stale rate counters.
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        self.entries
+            .retain(|_, e| e.until.is_none_or(|until| until > now));
+        self.unmatched
+            .retain(|_, (started, _)| now.duration_since(*started) <= RATE_WINDOW);
+    }
+
+    /// Lists the currently blocked addresses.
+    pub fn list(&self) -> Vec<BlockInfo> {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .map(|(ip, e)| BlockInfo {
+                ip: *ip,
+                reason: e.reason.clone(),
+                expires_in_secs: e.until.map(|u| u.saturating_duration_since(now).as_secs()),
+                dropped: e.dropped,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,200 @@
+//! Primary/backup link bonding of the peer
+//!
+//! A rover with two uplinks, e.g. Wi-Fi and LTE, keeps a connection over
+//! each: the peer's main connection is the primary link, and one of its
+//! further connections, bound to the other interface, the backup. Both run
+//! their own heartbeats, so the quality of the idle backup is known before
+//! it is needed.
+//!
+//! Application traffic sent through the peer's handle goes over the primary
+//! link while it is healthy. Once it is disconnected, restarting ICE, or its
+//! heartbeats measure more loss or a longer round trip than configured, a
+//! [`Bond`] moves the traffic to a healthy backup. It moves it back once the
+//! primary stayed healthy for [`BondingConfig::failback_secs`], so a link
+//! flapping at the edge of coverage does not bounce the traffic. Messages
+//! received over either link reach the same subscribers.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::heartbeat::LinkStats;
+use crate::model::registry::is_valid_alias;
+
+/// ID of the peer's main connection, the primary link, in failover events.
+pub const PRIMARY_LINK: &str = "primary";
+
+/// Bonding settings of the peer, the `[peer.bonding]` section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BondingConfig {
+    /// Fail application traffic over to the backup link
+    pub enabled: bool,
+    /// ID of the further connection carrying the backup link
+    pub backup: String,
+    /// Fraction of lost heartbeats, from 0 to 1, above which a link is
+    /// degraded
+    pub max_loss: f64,
+    /// Heartbeat round-trip time above which a link is degraded, in
+    /// milliseconds; not checked if unset
+    pub max_rtt_ms: Option<f64>,
+    /// How long the primary must stay healthy before the traffic returns to
+    /// it, in seconds
+    pub failback_secs: u64,
+}
+
+impl Default for BondingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            backup: "backup".into(),
+            max_loss: 0.3,
+            max_rtt_ms: None,
+            failback_secs: 10,
+        }
+    }
+}
+
+impl BondingConfig {
+    /// Checks that the thresholds are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_valid_alias(&self.backup) {
+            return Err(format!(
+                "backup '{}' is not a valid connection id",
+                self.backup
+            ));
+        }
+        if !(self.max_loss > 0.0 && self.max_loss <= 1.0) {
+            return Err("max_loss must be above 0 and at most 1".into());
+        }
+        if self
+            .max_rtt_ms
+            .is_some_and(|rtt| rtt.is_nan() || rtt <= 0.0)
+        {
+            return Err("max_rtt_ms must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+/// One of the bonded links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BondLink {
+    Primary,
+    Backup,
+}
+
+/// What is known of a link at a bonding check.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkObservation {
+    /// Whether ICE is connected and not restarting
+    pub connected: bool,
+    /// Link quality from the heartbeats, once the remote answers them
+    pub link: Option<LinkStats>,
+}
+
+/// A change of the link carrying the application traffic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BondSwitch {
+    /// The link now carrying the traffic
+    pub to: BondLink,
+    /// Why the traffic moved
+    pub reason: String,
+}
+
+/// Chooses the link carrying the application traffic.
+#[derive(Debug)]
+pub struct Bond {
+    config: BondingConfig,
+    active: BondLink,
+    /// Since when the primary is healthy while the backup is active
+    primary_healthy_since: Option<Instant>,
+}
+
+impl Bond {
+    /// Creates a bond sending over the primary link.
+    pub fn new(config: &BondingConfig) -> Self {
+        Self {
+            config: config.clone(),
+            active: BondLink::Primary,
+            primary_healthy_since: None,
+        }
+    }
+
+    /// Returns the link carrying the application traffic.
+    pub fn active(&self) -> BondLink {
+        self.active
+    }
+
+    /// Returns why a link is degraded, or `None` if it is healthy.
+    pub fn degradation(&self, observation: &LinkObservation) -> Option<String> {
+        if !observation.connected {
+            return Some("disconnected".into());
+        }
+        let link = observation.link?;
+        if link.loss > self.config.max_loss {
+            return Some(format!("{:.0}% heartbeat loss", link.loss * 100.0));
+        }
+        match (link.rtt_ms, self.config.max_rtt_ms) {
+            (Some(rtt), Some(max)) if rtt > max => Some(format!("{:.0} ms round trip", rtt)),
+            _ => None,
+        }
+    }
+
+    /// Checks both links and moves the traffic if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `primary` - What is known of the primary link
+    /// * `backup` - What is known of the backup link
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// The switch, if the traffic moved to the other link
+    pub fn update(
+        &mut self,
+        primary: &LinkObservation,
+        backup: &LinkObservation,
+        now: Instant,
+    ) -> Option<BondSwitch> {
+        let primary_degraded = self.degradation(primary);
+        let backup_degraded = self.degradation(backup);
+        match self.active {
+            BondLink::Primary => {
+                let reason = primary_degraded?;
+                if backup_degraded.is_some() {
+                    // Nowhere better to go
+                    return None;
+                }
+                self.primary_healthy_since = None;
+                self.switch(BondLink::Backup, format!("primary link {}", reason))
+            }
+            BondLink::Backup => {
+                if primary_degraded.is_some() {
+                    self.primary_healthy_since = None;
+                    return match backup_degraded {
+                        // The primary may still carry some of the traffic
+                        Some(reason) if primary.connected => {
+                            self.switch(BondLink::Primary, format!("backup link {}", reason))
+                        }
+                        _ => None,
+                    };
+                }
+                let since = *self.primary_healthy_since.get_or_insert(now);
+                if backup_degraded.is_none()
+                    && now.duration_since(since) < Duration::from_secs(self.config.failback_secs)
+                {
+                    return None;
+                }
+                self.primary_healthy_since = None;
+                self.switch(BondLink::Primary, "primary link recovered".into())
+            }
+        }
+    }
+
+    fn switch(&mut self, to: BondLink, reason: String) -> Option<BondSwitch> {
+        self.active = to;
+        Some(BondSwitch { to, reason })
+    }
+}
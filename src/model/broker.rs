@@ -0,0 +1,230 @@
+//! Brokering of direct peer-to-peer connections
+//!
+//! Normally the server terminates every connection. In mesh mode it only
+//! carries the signaling: a listening peer (e.g. a rover) long-polls for offers
+//! under its alias, and another peer (e.g. an operator console) posts an offer
+//! naming that alias as its target. The broker hands the offer to the
+//! listener, waits for its answer and returns it to the offering peer. ICE,
+//! DTLS and the data channels then run directly between the two peers, with
+//! the server out of the data path.
+//!
+//! An alias is held by the first listener polling under it, identified by
+//! its authenticated subject or, without one, its address, until it stops
+//! polling for [`LISTENER_TIMEOUT`]. Another listener polling under a held
+//! alias is refused, so it cannot take the offers meant for the holder and
+//! answer them with its own fingerprint.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use rand::RngCore;
+use str0m::change::{SdpAnswer, SdpOffer};
+
+use crate::auth::guest::hex_encode;
+use crate::model::signaling::MeshOffer;
+
+/// Time after its last poll until a listening peer is considered gone.
+pub const LISTENER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a listening peer's poll waits for an offer.
+pub const OFFER_POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// How long an offering peer waits for the listener's answer.
+pub const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reasons an offer could not be brokered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrokerError {
+    /// No peer is listening under the target alias
+    UnknownTarget(String),
+    /// Another peer is listening under the alias
+    AliasHeld(String),
+    /// The listener did not answer in time
+    Timeout,
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrokerError::UnknownTarget(alias) => write!(f, "no peer is listening as '{}'", alias),
+            BrokerError::AliasHeld(alias) => {
+                write!(f, "another peer is listening as '{}'", alias)
+            }
+            BrokerError::Timeout => write!(f, "the listening peer did not answer in time"),
+        }
+    }
+}
+
+impl std::error::Error for BrokerError {}
+
+/// A peer waiting for offers.
+struct Listener {
+    /// Who holds the alias
+    holder: String,
+    last_poll: Instant,
+    offers: VecDeque<MeshOffer>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    /// Listening peers by alias
+    listeners: HashMap<String, Listener>,
+    /// Offers awaiting their answer, by offer ID
+    answers: HashMap<String, Option<SdpAnswer>>,
+}
+
+/// Mailboxes exchanging offers and answers between peers.
+///
+/// Shared by the signaling handlers, which block on it from their own threads.
+#[derive(Default)]
+pub struct Broker {
+    state: Mutex<BrokerState>,
+    changed: Condvar,
+}
+
+impl Broker {
+    /// Creates a broker without listeners.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BrokerState> {
+        self.state.lock().expect("broker lock")
+    }
+
+    /// Registers a listening peer and waits for an offer addressed to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The alias the peer listens under
+    /// * `holder` - Who the peer is, its subject or address
+    /// * `wait` - How long to wait for an offer
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(MeshOffer))` - The next offer
+    /// * `Ok(None)` - If none arrived in time
+    /// * `Err(BrokerError::AliasHeld)` - If another peer holds the alias
+    pub fn poll_offer(
+        &self,
+        alias: &str,
+        holder: &str,
+        wait: Duration,
+    ) -> Result<Option<MeshOffer>, BrokerError> {
+        let deadline = Instant::now() + wait;
+        let mut state = self.lock();
+        state
+            .listeners
+            .retain(|_, l| l.last_poll.elapsed() < LISTENER_TIMEOUT);
+        loop {
+            let listener = state
+                .listeners
+                .entry(alias.to_string())
+                .or_insert_with(|| Listener {
+                    holder: holder.to_string(),
+                    last_poll: Instant::now(),
+                    offers: VecDeque::new(),
+                });
+            if listener.holder != holder {
+                return Err(BrokerError::AliasHeld(alias.to_string()));
+            }
+            listener.last_poll = Instant::now();
+            if let Some(offer) = listener.offers.pop_front() {
+                return Ok(Some(offer));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            state = self
+                .changed
+                .wait_timeout(state, deadline - now)
+                .expect("broker lock")
+                .0;
+        }
+    }
+
+    /// Hands an offer to a listening peer and waits for its answer.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The alias of the listening peer
+    /// * `from` - The authenticated subject of the offering peer, if any
+    /// * `offer` - The offering peer's SDP offer
+    /// * `wait` - How long to wait for the answer
+    ///
+    /// # Returns
+    ///
+    /// The listener's answer, or why there is none
+    pub fn connect(
+        &self,
+        target: &str,
+        from: Option<String>,
+        offer: SdpOffer,
+        wait: Duration,
+    ) -> Result<SdpAnswer, BrokerError> {
+        let mut token = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut token);
+        let id = hex_encode(&token);
+
+        let deadline = Instant::now() + wait;
+        let mut state = self.lock();
+        state
+            .listeners
+            .retain(|_, l| l.last_poll.elapsed() < LISTENER_TIMEOUT);
+        let Some(listener) = state.listeners.get_mut(target) else {
+            return Err(BrokerError::UnknownTarget(target.to_string()));
+        };
+        listener.offers.push_back(MeshOffer {
+            id: id.clone(),
+            from,
+            offer,
+        });
+        state.answers.insert(id.clone(), None);
+        self.changed.notify_all();
+
+        loop {
+            if let Some(Some(_)) = state.answers.get(&id) {
+                return state
+                    .answers
+                    .remove(&id)
+                    .flatten()
+                    .ok_or(BrokerError::Timeout);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.answers.remove(&id);
+                if let Some(listener) = state.listeners.get_mut(target) {
+                    listener.offers.retain(|o| o.id != id);
+                }
+                return Err(BrokerError::Timeout);
+            }
+            state = self
+                .changed
+                .wait_timeout(state, deadline - now)
+                .expect("broker lock")
+                .0;
+        }
+    }
+
+    /// Delivers a listener's answer to the waiting offering peer.
+    ///
+    /// # Returns
+    ///
+    /// `false` if no peer is waiting for an answer to the offer
+    pub fn answer(&self, id: &str, answer: SdpAnswer) -> bool {
+        let mut state = self.lock();
+        match state.answers.get_mut(id) {
+            Some(slot @ None) => {
+                *slot = Some(answer);
+                self.changed.notify_all();
+                true
+            }
+            _ => false,
+        }
+    }
+}
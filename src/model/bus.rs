@@ -0,0 +1,289 @@
+//! Typed event bus of the server and the peer
+//!
+//! The server's event loop and the peer's sessions publish every event once
+//! on an [`EventBus`], and whatever consumes them subscribes to the topics it
+//! cares about: the embedding application's callbacks, the stats kept for
+//! the admin API, the event webhooks, the MAVLink and ROS bridges and the
+//! admin event stream. A new consumer subscribes from where it is set up
+//! instead of being called from inside the event loop.
+//!
+//! Each event belongs to one [`EventTopic`]. A subscription is either a
+//! handler, called on the publishing thread, or a bounded channel drained
+//! from another thread; a channel that is full drops the event rather than
+//! stall the event loop, and a channel whose receiver is gone ends its
+//! subscription.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// Events a channel subscription holds before further ones are dropped.
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+/// What an event is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTopic {
+    /// Connections, disconnections, channels opening and session keys
+    Lifecycle,
+    /// Application data received, or given up sending
+    ChannelData,
+    /// Health state changes, see [`crate::model::health`]
+    Health,
+    /// ICE restarts, reconnections and link failovers
+    Handover,
+    /// Media frames and keyframe requests
+    Media,
+    /// File transfers
+    Transfer,
+}
+
+impl EventTopic {
+    /// Every topic.
+    pub const ALL: [EventTopic; 6] = [
+        EventTopic::Lifecycle,
+        EventTopic::ChannelData,
+        EventTopic::Health,
+        EventTopic::Handover,
+        EventTopic::Media,
+        EventTopic::Transfer,
+    ];
+
+    /// Returns the name of the topic in configuration files and URLs.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventTopic::Lifecycle => "lifecycle",
+            EventTopic::ChannelData => "channel_data",
+            EventTopic::Health => "health",
+            EventTopic::Handover => "handover",
+            EventTopic::Media => "media",
+            EventTopic::Transfer => "transfer",
+        }
+    }
+
+    /// Parses a comma-separated list of topic names, e.g. from a query
+    /// parameter; an empty list means every topic.
+    pub fn parse_list(list: &str) -> Result<Vec<EventTopic>, String> {
+        let topics = list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(if topics.is_empty() {
+            EventTopic::ALL.to_vec()
+        } else {
+            topics
+        })
+    }
+}
+
+impl fmt::Display for EventTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for EventTopic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EventTopic::ALL
+            .into_iter()
+            .find(|topic| topic.name() == s)
+            .ok_or_else(|| format!("unknown event topic '{}'", s))
+    }
+}
+
+/// An event published on a bus.
+pub trait BusEvent: Clone + Send + 'static {
+    /// Returns the topic the event belongs to.
+    fn topic(&self) -> EventTopic;
+}
+
+/// Identifies a subscription, to end it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+/// A handler called with each event of its topics.
+type Handler<E> = Arc<dyn Fn(&E) + Send + Sync>;
+
+/// Where a subscription's events go.
+enum Delivery<E> {
+    Handler(Handler<E>),
+    Channel(SyncSender<E>),
+}
+
+struct Subscriber<E> {
+    id: SubscriptionId,
+    topics: Vec<EventTopic>,
+    delivery: Delivery<E>,
+}
+
+/// Fans the published events out to the subscribers of their topics.
+///
+/// Clones share the subscriptions, so a bus can be handed to whatever
+/// subscribes later.
+pub struct EventBus<E> {
+    subscribers: Arc<Mutex<Vec<Subscriber<E>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl<E> Clone for EventBus<E> {
+    fn clone(&self) -> Self {
+        Self {
+            subscribers: self.subscribers.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl<E> Default for EventBus<E> {
+    fn default() -> Self {
+        Self {
+            subscribers: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+}
+
+impl<E> fmt::Debug for EventBus<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventBus")
+            .field(
+                "subscribers",
+                &self.subscribers.lock().expect("bus lock").len(),
+            )
+            .finish()
+    }
+}
+
+impl<E: BusEvent> EventBus<E> {
+    /// Creates a bus without subscribers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `handler` with each event of the given topics.
+    ///
+    /// The handler runs on the publishing thread, the event loop for the
+    /// server's and the peer's events, and should return quickly.
+    pub fn subscribe(
+        &self,
+        topics: &[EventTopic],
+        handler: impl Fn(&E) + Send + Sync + 'static,
+    ) -> SubscriptionId {
+        self.add(topics, Delivery::Handler(Arc::new(handler)))
+    }
+
+    /// Passes each event of the given topics through a channel, for a
+    /// consumer on another thread.
+    ///
+    /// Events are dropped while [`CHANNEL_CAPACITY`] of them wait, and the
+    /// subscription ends once the receiver is dropped.
+    pub fn subscribe_channel(&self, topics: &[EventTopic]) -> (SubscriptionId, Receiver<E>) {
+        let (tx, rx) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        (self.add(topics, Delivery::Channel(tx)), rx)
+    }
+
+    /// Ends a subscription.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the subscription had already ended
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.subscribers.lock().expect("bus lock");
+        let before = subscribers.len();
+        subscribers.retain(|s| s.id != id);
+        subscribers.len() < before
+    }
+
+    /// Hands an event to the subscribers of its topic; handlers are called
+    /// in the order they subscribed.
+    pub fn publish(&self, event: &E) {
+        let topic = event.topic();
+        let mut handlers = vec![];
+        let mut closed = vec![];
+        {
+            let subscribers = self.subscribers.lock().expect("bus lock");
+            for subscriber in subscribers.iter().filter(|s| s.topics.contains(&topic)) {
+                match &subscriber.delivery {
+                    Delivery::Handler(handler) => handlers.push(handler.clone()),
+                    Delivery::Channel(tx) => match tx.try_send(event.clone()) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            debug!("Bus: Subscriber lags behind, dropping a {} event", topic)
+                        }
+                        Err(TrySendError::Disconnected(_)) => closed.push(subscriber.id),
+                    },
+                }
+            }
+        }
+        // Handlers may subscribe or publish themselves
+        for handler in handlers {
+            handler(event);
+        }
+        for id in closed {
+            self.unsubscribe(id);
+        }
+    }
+
+    /// Returns the number of subscriptions.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().expect("bus lock").len()
+    }
+
+    fn add(&self, topics: &[EventTopic], delivery: Delivery<E>) -> SubscriptionId {
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.subscribers.lock().expect("bus lock").push(Subscriber {
+            id,
+            topics: topics.to_vec(),
+            delivery,
+        });
+        id
+    }
+}
+
+/// An endpoint the server posts its events to as JSON, an entry of
+/// `[[server.webhooks]]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// URL the events are posted to
+    pub url: String,
+    /// Topics of the events posted; every topic but media and channel data
+    /// by default
+    #[serde(default = "default_webhook_topics")]
+    pub topics: Vec<EventTopic>,
+}
+
+fn default_webhook_topics() -> Vec<EventTopic> {
+    vec![
+        EventTopic::Lifecycle,
+        EventTopic::Health,
+        EventTopic::Handover,
+        EventTopic::Transfer,
+    ]
+}
+
+impl WebhookConfig {
+    /// Checks that the URL is http and some topic is posted.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(format!("url '{}' must be http", self.url));
+        }
+        if self.topics.is_empty() {
+            return Err("topics must not be empty".into());
+        }
+        Ok(())
+    }
+}
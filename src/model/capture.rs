@@ -0,0 +1,260 @@
+//! Remote packet capture protocol
+//!
+//! The server asks a peer to capture its UDP traffic on the reliable
+//! "capture" data channel, for NAT and firewall problems at sites nobody can
+//! reach. A [`CaptureMessage::Start`] bounds the capture in time and size; the
+//! peer records until either limit is reached or a [`CaptureMessage::Stop`]
+//! arrives, then sends the pcap file in [`CaptureMessage::Chunk`]s of at most
+//! [`CHUNK_SIZE`] bytes, or answers with a [`CaptureMessage::Refused`].
+
+use std::{collections::HashSet, time::Instant};
+
+use bincode::config::{self, Configuration};
+use serde::{Deserialize, Serialize};
+
+use crate::model::chunked::ChunkAssembler;
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying capture messages.
+pub const CAPTURE_CHANNEL: &str = "capture";
+
+/// Maximum number of capture bytes per chunk, keeping each SCTP message small.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The largest capture requested and reassembled, in bytes.
+pub const MAX_CAPTURE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Messages exchanged on the capture channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum CaptureMessage {
+    /// Asks the peer to start capturing.
+    Start {
+        id: u32,
+        duration_secs: u32,
+        max_bytes: u64,
+    },
+    /// Asks the peer to end the capture early and send what it recorded.
+    Stop { id: u32 },
+    /// A slice of the pcap file, identified by its chunk index.
+    Chunk {
+        id: u32,
+        index: u32,
+        total: u32,
+        packets: u64,
+        truncated: bool,
+        data: Vec<u8>,
+    },
+    /// The peer did not capture.
+    Refused { id: u32, reason: String },
+}
+
+impl CaptureMessage {
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(CaptureMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+
+    /// Splits a finished capture into chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the capture
+    /// * `packets` - Number of recorded datagrams
+    /// * `truncated` - Whether recording stopped at the size limit
+    /// * `data` - The pcap file
+    ///
+    /// # Returns
+    ///
+    /// At least one chunk, even for an empty file
+    pub fn chunks(id: u32, packets: u64, truncated: bool, data: &[u8]) -> Vec<CaptureMessage> {
+        let slices: Vec<&[u8]> = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(CHUNK_SIZE).collect()
+        };
+        let total = slices.len() as u32;
+        slices
+            .into_iter()
+            .enumerate()
+            .map(|(index, slice)| CaptureMessage::Chunk {
+                id,
+                index: index as u32,
+                total,
+                packets,
+                truncated,
+                data: slice.to_vec(),
+            })
+            .collect()
+    }
+}
+
+/// A capture collected from a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureResult {
+    /// The pcap file
+    pub data: Vec<u8>,
+    /// Number of recorded datagrams
+    pub packets: u64,
+    /// Whether recording stopped because the size limit was reached
+    pub truncated: bool,
+}
+
+/// Reassembles the chunked captures sent by a peer.
+///
+/// Only the captures announced with [`CaptureAssembler::expect`] are
+/// reassembled, up to [`MAX_CAPTURE_SIZE`]; see [`crate::model::chunked`] for
+/// the other limits.
+#[derive(Debug)]
+pub struct CaptureAssembler {
+    requested: HashSet<u32>,
+    partial: ChunkAssembler<u32, (u64, bool)>,
+}
+
+impl Default for CaptureAssembler {
+    fn default() -> Self {
+        Self {
+            requested: HashSet::new(),
+            partial: ChunkAssembler::new(MAX_CAPTURE_SIZE as usize),
+        }
+    }
+}
+
+impl CaptureAssembler {
+    /// Creates an assembler with no capture in progress.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts the chunks of a capture requested from the peer.
+    pub fn expect(&mut self, id: u32) {
+        self.requested.insert(id);
+    }
+
+    /// Handles a message received on the capture channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message received from the peer
+    ///
+    /// # Returns
+    ///
+    /// The capture ID and the capture, or why there is none, once the last
+    /// chunk or a refusal arrived
+    pub fn handle(
+        &mut self,
+        message: CaptureMessage,
+    ) -> Option<(u32, Result<CaptureResult, String>)> {
+        match message {
+            CaptureMessage::Start { .. } | CaptureMessage::Stop { .. } => None,
+            CaptureMessage::Refused { id, reason } => {
+                self.partial.remove(&id);
+                self.requested.remove(&id).then_some((id, Err(reason)))
+            }
+            CaptureMessage::Chunk {
+                id,
+                index,
+                total,
+                packets,
+                truncated,
+                data,
+            } => {
+                if !self.requested.contains(&id) {
+                    return None;
+                }
+                let result = match self.partial.push(
+                    id,
+                    index,
+                    total,
+                    (packets, truncated),
+                    data,
+                    Instant::now(),
+                ) {
+                    Ok(None) => return None,
+                    Ok(Some(((packets, truncated), data))) => Ok(CaptureResult {
+                        data,
+                        packets,
+                        truncated,
+                    }),
+                    Err(e) => Err(e.to_string()),
+                };
+                self.requested.remove(&id);
+                Some((id, result))
+            }
+        }
+    }
+}
+
+impl WireSchema for CaptureMessage {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "CaptureMessage",
+            "Messages exchanged on the capture channel",
+            vec![
+                (
+                    "Start",
+                    "Asks the peer to start capturing",
+                    vec![
+                        field("id", WireType::U32, "Capture ID echoed in the reply"),
+                        field(
+                            "duration_secs",
+                            WireType::U32,
+                            "How long to record; the peer may shorten it",
+                        ),
+                        field(
+                            "max_bytes",
+                            WireType::U64,
+                            "The largest pcap file; the peer may reduce it",
+                        ),
+                    ],
+                ),
+                (
+                    "Stop",
+                    "Asks the peer to end the capture early and send what it recorded",
+                    vec![field("id", WireType::U32, "The capture ID")],
+                ),
+                (
+                    "Chunk",
+                    "A slice of the pcap file, identified by its chunk index",
+                    vec![
+                        field("id", WireType::U32, "The capture ID"),
+                        field("index", WireType::U32, "Zero-based chunk index"),
+                        field("total", WireType::U32, "Number of chunks of the file"),
+                        field("packets", WireType::U64, "Number of recorded datagrams"),
+                        field(
+                            "truncated",
+                            WireType::U8,
+                            "1 if recording stopped at the size limit, 0 otherwise",
+                        ),
+                        field(
+                            "data",
+                            WireType::Bytes,
+                            "pcap file with raw IP link type, in little-endian byte order",
+                        ),
+                    ],
+                ),
+                (
+                    "Refused",
+                    "The peer did not capture",
+                    vec![
+                        field("id", WireType::U32, "The capture ID"),
+                        field("reason", WireType::String, "Why the request was refused"),
+                    ],
+                ),
+            ],
+        )
+    }
+}
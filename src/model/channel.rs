@@ -0,0 +1,218 @@
+//! Delivery options of data channels
+//!
+//! Data channels are reliable and ordered by default, so a lost packet holds
+//! back everything after it until it is retransmitted. Rover telemetry prefers
+//! the freshest reading over a complete history, so channels can be opened
+//! with SCTP partial reliability: messages are abandoned after a number of
+//! retransmits or once they are too old, and may be delivered out of order.
+//!
+//! High-rate streams of small messages can also be coalesced into batches,
+//! see [`crate::model::batch`], and each channel gets a weighted share of the
+//! connection's bandwidth, see [`crate::model::scheduler`].
+//!
+//! Each channel also belongs to a [`QosClass`]: while the link is
+//! constrained, control messages go out before telemetry, and telemetry
+//! before bulk transfers, whatever the weights.
+//!
+//! A write fails while the channel cannot take data at all, e.g. while its
+//! SCTP stream is being re-established. What happens to the message then is
+//! the channel's [`WriteRetry`] policy: by default it stays at the head of
+//! the channel's queue until it is written, so a command is never lost
+//! silently.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use str0m::channel::{ChannelConfig, Reliability};
+
+use crate::model::{
+    announce::ANNOUNCE_CHANNEL, capture::CAPTURE_CHANNEL, control::CONTROL_CHANNEL,
+    coordination::COORDINATION_CHANNEL, crash::CRASH_CHANNEL, forward::FORWARD_CHANNEL,
+    logs::LOGS_CHANNEL, session::SESSION_CHANNEL, transfer::TRANSFER_CHANNEL,
+};
+
+/// How a data channel delivers its messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelOptions {
+    /// Deliver messages in the order they were sent
+    pub ordered: bool,
+    /// Abandon a message after this many retransmits; `0` never retransmits
+    pub max_retransmits: Option<u16>,
+    /// Abandon a message this many milliseconds after it was sent
+    pub max_packet_lifetime_ms: Option<u16>,
+    /// Coalesce the messages the peer sends within this many milliseconds
+    /// into one, if the remote decodes batches
+    pub batch_window_ms: Option<u16>,
+    /// Share of the bandwidth the channel gets while other channels have
+    /// data queued too, relative to their weights
+    pub weight: u32,
+    /// What happens to a message whose write failed
+    pub on_write_error: WriteRetry,
+    /// Failed writes of a message before it is reported as failed, with
+    /// [`WriteRetry::RetryAfterPoll`]
+    pub max_write_retries: u8,
+    /// Precedence of the channel's messages over other channels' on a
+    /// constrained link; by label if unset, see [`QosClass::for_label`]
+    pub qos: Option<QosClass>,
+}
+
+/// Precedence of a channel's messages over those of other channels while the
+/// link is constrained.
+///
+/// Classes are served in order: a message of a lower class is only handed to
+/// SCTP while no message of a higher class waits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QosClass {
+    /// Protocol and teleoperation messages, e.g. commands
+    Control,
+    /// Streams of readings
+    Telemetry,
+    /// Transfers that may take whatever bandwidth is left, e.g. files
+    Bulk,
+}
+
+impl QosClass {
+    /// Returns the class of a channel without a configured one: the
+    /// protocol's own signaling and announce channels are control, its file, capture,
+    /// crash and log uploads bulk, and every other channel telemetry.
+    pub fn for_label(label: &str) -> QosClass {
+        match label {
+            CONTROL_CHANNEL | SESSION_CHANNEL | COORDINATION_CHANNEL | FORWARD_CHANNEL
+            | ANNOUNCE_CHANNEL => QosClass::Control,
+            TRANSFER_CHANNEL | CAPTURE_CHANNEL | CRASH_CHANNEL | LOGS_CHANNEL => QosClass::Bulk,
+            _ => QosClass::Telemetry,
+        }
+    }
+}
+
+/// What happens to a message whose write to its channel failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteRetry {
+    /// Keep the message at the head of the channel's queue and write it
+    /// again after every poll until it goes through
+    #[default]
+    Requeue,
+    /// Write the message again after the next polls, then report it as
+    /// failed once `max_write_retries` writes failed
+    RetryAfterPoll,
+    /// Report the message as failed to the application at once
+    Fail,
+    /// Log the failure and drop the message
+    Drop,
+}
+
+/// A message reported as failed under the channel's [`WriteRetry`] policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteFailure {
+    /// The label of the channel
+    pub channel: String,
+    /// The message
+    pub data: Vec<u8>,
+    /// The error of the last write
+    pub error: String,
+}
+
+/// The fate of a message whose write failed, see
+/// [`ChannelOptions::after_write_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOutcome {
+    /// Keep it queued and write it again after the next poll
+    Retry,
+    /// Report it to the application as failed
+    Fail,
+    /// Drop it
+    Drop,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        Self {
+            ordered: true,
+            max_retransmits: None,
+            max_packet_lifetime_ms: None,
+            batch_window_ms: None,
+            weight: 1,
+            on_write_error: WriteRetry::default(),
+            max_write_retries: 3,
+            qos: None,
+        }
+    }
+}
+
+impl ChannelOptions {
+    /// Returns `true` if every message is delivered.
+    pub fn is_reliable(&self) -> bool {
+        self.max_retransmits.is_none() && self.max_packet_lifetime_ms.is_none()
+    }
+
+    /// Checks that the options can be combined.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_retransmits.is_some() && self.max_packet_lifetime_ms.is_some() {
+            return Err("max_retransmits and max_packet_lifetime_ms are exclusive".into());
+        }
+        if self.batch_window_ms == Some(0) {
+            return Err("batch_window_ms must be positive".into());
+        }
+        if self.weight == 0 {
+            return Err("weight must be at least 1".into());
+        }
+        if self.on_write_error == WriteRetry::RetryAfterPoll && self.max_write_retries == 0 {
+            return Err("max_write_retries must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    /// Decides what happens to a message whose write failed.
+    ///
+    /// # Arguments
+    ///
+    /// * `attempts` - The number of failed writes of the message, this one
+    ///   included
+    pub fn after_write_error(&self, attempts: u32) -> WriteOutcome {
+        match self.on_write_error {
+            WriteRetry::Requeue => WriteOutcome::Retry,
+            WriteRetry::RetryAfterPoll if attempts < u32::from(self.max_write_retries) => {
+                WriteOutcome::Retry
+            }
+            WriteRetry::RetryAfterPoll | WriteRetry::Fail => WriteOutcome::Fail,
+            WriteRetry::Drop => WriteOutcome::Drop,
+        }
+    }
+
+    /// Returns the QoS class of the channel with these options.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel
+    pub fn qos_class(&self, label: &str) -> QosClass {
+        self.qos.unwrap_or_else(|| QosClass::for_label(label))
+    }
+
+    /// Returns how long messages wait to be batched, if batching is enabled.
+    pub fn batch_window(&self) -> Option<Duration> {
+        self.batch_window_ms
+            .map(|ms| Duration::from_millis(u64::from(ms)))
+    }
+
+    /// Returns the str0m configuration of a channel with these options.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel
+    pub fn config(&self, label: &str) -> ChannelConfig {
+        let reliability = match (self.max_retransmits, self.max_packet_lifetime_ms) {
+            (Some(retransmits), _) => Reliability::MaxRetransmits { retransmits },
+            (None, Some(lifetime)) => Reliability::MaxPacketLifetime { lifetime },
+            (None, None) => Reliability::Reliable,
+        };
+        ChannelConfig {
+            label: label.to_string(),
+            ordered: self.ordered,
+            reliability,
+            ..ChannelConfig::default()
+        }
+    }
+}
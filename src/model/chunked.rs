@@ -0,0 +1,182 @@
+//! Bounded reassembly of chunked transfers
+//!
+//! Log replies, packet captures and crash reports travel as numbered chunks,
+//! each carrying its index and the number of chunks of the transfer. Both,
+//! and the key identifying the transfer, are chosen by the sender, so like
+//! the fragment [`Reassembler`](crate::model::fragment::Reassembler) the
+//! [`ChunkAssembler`] checks the count against [`MAX_CHUNKS`] before
+//! allocating, drops a transfer once its chunks add up to more than its size
+//! limit, holds at most [`MAX_PENDING`] transfers at once and drops one that
+//! received no chunk for [`REASSEMBLY_TIMEOUT`].
+
+use std::{collections::HashMap, fmt, hash::Hash, time::Instant};
+
+use crate::model::fragment::REASSEMBLY_TIMEOUT;
+
+/// The most chunks of a transfer.
+pub const MAX_CHUNKS: u32 = u16::MAX as u32;
+
+/// The most transfers reassembled at once.
+pub const MAX_PENDING: usize = 16;
+
+/// Reasons a chunk could not be reassembled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkError {
+    /// The count is zero, the index not below it, or the count differs from
+    /// the one of the earlier chunks
+    Malformed,
+    /// The count exceeds [`MAX_CHUNKS`], or the data the size limit
+    TooLarge,
+    /// [`MAX_PENDING`] other transfers are in progress
+    TooManyPending,
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ChunkError::Malformed => "malformed chunk",
+            ChunkError::TooLarge => "chunked transfer too large",
+            ChunkError::TooManyPending => "too many chunked transfers in progress",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+/// A transfer whose chunks are still arriving.
+#[derive(Debug)]
+struct PartialTransfer<M> {
+    meta: M,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    updated: Instant,
+}
+
+/// Reassembles the chunked transfers of one channel.
+///
+/// `K` identifies a transfer, and `M` is what the first chunk of a transfer
+/// tells about it besides its data, returned with the reassembled bytes.
+#[derive(Debug)]
+pub struct ChunkAssembler<K, M = ()> {
+    max_size: usize,
+    partial: HashMap<K, PartialTransfer<M>>,
+}
+
+impl<K: Eq + Hash + Clone, M> ChunkAssembler<K, M> {
+    /// Creates an assembler without partial transfers.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_size` - The largest transfer reassembled, in bytes
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Adds a received chunk.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The transfer the chunk belongs to
+    /// * `index` - The zero-based index of the chunk
+    /// * `total` - The number of chunks of the transfer
+    /// * `meta` - What the chunk tells about the transfer; only kept from the
+    ///   first chunk
+    /// * `data` - The data of the chunk
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some((M, Vec<u8>)))` - The transfer, if this chunk completed it
+    /// * `Ok(None)` - If chunks of the transfer are missing
+    /// * `Err(ChunkError)` - If the chunk is malformed or the transfer too
+    ///   large, in which case its chunks are dropped, or if too many other
+    ///   transfers are in progress
+    pub fn push(
+        &mut self,
+        key: K,
+        index: u32,
+        total: u32,
+        meta: M,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Result<Option<(M, Vec<u8>)>, ChunkError> {
+        self.expire(now);
+        if total == 0 || index >= total {
+            self.partial.remove(&key);
+            return Err(ChunkError::Malformed);
+        }
+        if total > MAX_CHUNKS {
+            self.partial.remove(&key);
+            return Err(ChunkError::TooLarge);
+        }
+        if total == 1 && !self.partial.contains_key(&key) {
+            if data.len() > self.max_size {
+                return Err(ChunkError::TooLarge);
+            }
+            return Ok(Some((meta, data)));
+        }
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_PENDING {
+            return Err(ChunkError::TooManyPending);
+        }
+
+        let partial = self
+            .partial
+            .entry(key.clone())
+            .or_insert_with(|| PartialTransfer {
+                meta,
+                chunks: vec![None; total as usize],
+                received: 0,
+                size: 0,
+                updated: now,
+            });
+        if partial.chunks.len() != total as usize {
+            self.partial.remove(&key);
+            return Err(ChunkError::Malformed);
+        }
+        let slot = &mut partial.chunks[index as usize];
+        if slot.is_none() {
+            partial.size += data.len();
+            partial.received += 1;
+            *slot = Some(data);
+        }
+        partial.updated = now;
+        if partial.size > self.max_size {
+            self.partial.remove(&key);
+            return Err(ChunkError::TooLarge);
+        }
+        if partial.received < partial.chunks.len() {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&key).expect("partial transfer");
+        Ok(Some((
+            partial.meta,
+            partial.chunks.into_iter().flatten().flatten().collect(),
+        )))
+    }
+
+    /// Forgets a transfer still in progress.
+    ///
+    /// # Returns
+    ///
+    /// `false` if no such transfer was in progress
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.partial.remove(key).is_some()
+    }
+
+    /// Drops the transfers that received no chunk for [`REASSEMBLY_TIMEOUT`].
+    ///
+    /// # Returns
+    ///
+    /// The number of transfers dropped
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.partial.len();
+        self.partial
+            .retain(|_, p| now.duration_since(p.updated) < REASSEMBLY_TIMEOUT);
+        before - self.partial.len()
+    }
+}
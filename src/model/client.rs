@@ -4,16 +4,114 @@
 //! peer connections on the server side. Each client represents a connected peer with
 //! its own RTC instance, data channel, and connection state.
 
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
-use str0m::channel::ChannelId;
-use str0m::{change::SdpOffer, Candidate, Event, IceConnectionState, Input, Output, Rtc};
+use serde::Serialize;
+use str0m::channel::{ChannelData, ChannelId};
+use str0m::media::{Direction, KeyframeRequest, KeyframeRequestKind, MediaData, MediaKind, Mid};
+use str0m::{
+    change::{SdpAnswer, SdpOffer, SdpPendingOffer},
+    Candidate, Event, IceConnectionState, Input, Output, Rtc, RtcError,
+};
 use tracing::{debug, info, warn};
 
-use crate::model::payload::Payload;
+use crate::auth::{Access, Role};
+use crate::error::RoverRtcError;
+use crate::model::announce::{AnnounceMessage, ANNOUNCE_CHANNEL};
+use crate::model::audio::{AudioFrame, AudioSender};
+use crate::model::batch;
+use crate::model::capture::{
+    CaptureAssembler, CaptureMessage, CaptureResult, CAPTURE_CHANNEL, MAX_CAPTURE_SIZE,
+};
+use crate::model::channel::{ChannelOptions, QosClass, WriteFailure, WriteOutcome};
+use crate::model::control::{ControlMessage, Feature, FeatureSet, Negotiation, CONTROL_CHANNEL};
+use crate::model::coordination::COORDINATION_CHANNEL;
+use crate::model::crash::{CrashAssembler, CrashMessage, CRASH_CHANNEL};
+use crate::model::demux::PacketClass;
+use crate::model::events::{EventCategory, EventRing, RecordedEvent};
+use crate::model::forward::{ForwardMessage, ForwardedTrack, FORWARD_CHANNEL};
+use crate::model::heartbeat::{HeartbeatConfig, LinkMonitor, LinkStats};
+use crate::model::keys::{KeyAgreement, SessionKeys};
+use crate::model::logs::{LogAssembler, LogMessage, LogQuery, LogReply, LOGS_CHANNEL};
+use crate::model::mission::{
+    MissionMessage, MissionPlan, MissionReceiver, DEFAULT_CHUNK_SIZE, MISSION_CHANNEL,
+};
+use crate::model::outbound::{OutboundMessage, OutboundQueue, SendQueueConfig};
+use crate::model::payload::{Envelope, MessageKind, Payload, WireFormat};
+use crate::model::propagated::Propagated;
+use crate::model::rate::RateDemand;
+use crate::model::recording::{self, Recorder};
+use crate::model::session::{SessionConfig, SessionLifetime, SessionMessage, SESSION_CHANNEL};
+use crate::model::setup::{SetupBreakdown, SetupTimer};
+use crate::model::stats::{
+    codec_name, ChannelStats, CodecTracker, ConnectionStats, ConnectionTracker, TrafficCounters,
+    CODEC_NONE,
+};
+use crate::model::telemetry::{GpsFix, Telemetry, TELEMETRY_CHANNEL};
+use crate::model::tracks::{TrackIn, TrackInEntry, TrackOut, TrackOutState};
+use crate::model::transfer::{OutgoingTransfer, TRANSFER_CHANNEL};
+use crate::model::transport::Transport;
+use crate::transfer::{self, TransferConfig, TransferEvent, Transfers};
+use crate::util::event_log::{EventKind, EventLogger};
+use crate::util::logbuf;
+
+/// Minimum interval between keyframe requests for the same track.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time the peer has to answer an offer of forwarded tracks, and the minimum
+/// interval between offers after one was rejected or went unanswered.
+const FORWARD_OFFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The state of a client, as included in state dumps.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientState {
+    /// The client's ID
+    pub id: u64,
+    /// The client's alias, if it announced one
+    pub alias: Option<String>,
+    /// The role granted during signaling
+    pub role: Role,
+    /// The room the client belongs to, if any
+    pub room: Option<String>,
+    /// Whether the RTC instance is still alive
+    pub alive: bool,
+    /// The current ICE connection state
+    pub ice_state: Option<String>,
+    /// Remote addresses the client has sent traffic from
+    pub remote_addrs: Vec<SocketAddr>,
+    /// The negotiated protocol version, if the handshake completed
+    pub protocol_version: Option<u16>,
+    /// Whether the client speaks an incompatible protocol
+    pub protocol_fallback: bool,
+    /// The optional features both sides can decode
+    pub features: Vec<&'static str>,
+    /// Labels of the open data channels
+    pub channels: Vec<String>,
+    /// Kinds and media IDs of the tracks the peer sends
+    pub tracks: Vec<String>,
+    /// Time spent in each setup phase
+    pub setup: SetupBreakdown,
+    /// Link quality measured with heartbeats, if the client answers them
+    pub link: Option<LinkStats>,
+    /// Total bytes received on data channels and tracks
+    pub bytes_received: u64,
+    /// Total bytes transmitted on the UDP socket
+    pub bytes_sent: u64,
+    /// Messages waiting in the outbound queues of congested channels
+    pub messages_queued: usize,
+    /// Messages the outbound queues dropped or superseded by newer ones
+    pub messages_dropped: u64,
+    /// The most recent significant events, oldest first
+    pub events: Vec<RecordedEvent>,
+    /// Number of older events dropped from the history
+    pub events_dropped: u64,
+}
 
 /// Represents a connected WebRTC client with its own RTC instance.
 ///
@@ -23,19 +121,168 @@ use crate::model::payload::Payload;
 pub struct Client {
     /// Unique identifier for this client
     pub id: ClientId,
+    /// Human-readable alias announced by the peer, if any
+    pub alias: Option<String>,
+    /// Opaque token identifying the session, handed to the peer during signaling
+    pub session_token: String,
     /// The str0m RTC instance managing the WebRTC connection
     pub rtc: Rtc,
+    /// The access granted to this client during signaling
+    pub access: Access,
+    /// Cumulative traffic counters, sampled into the stats history
+    pub counters: TrafficCounters,
+    /// Statistics of the connection, from str0m and the socket
+    connection: ConnectionTracker,
+    /// Encoding work of the data channels
+    codecs: CodecTracker,
+    /// Timing of the connection setup phases
+    pub setup: SetupTimer,
+    /// Expiry and refresh token of the session
+    pub session: SessionLifetime,
+    /// The protocol version negotiated with the peer
+    pub protocol: Negotiation,
+    /// The optional features both sides can decode
+    pub features: FeatureSet,
+    /// Whether legacy payloads are accepted from and sent to this client
+    pub legacy_interop: bool,
+    /// The publishing rates the server's receivers want from this client
+    pub rates: RateDemand,
+    /// Heartbeats sent to this client and the link quality measured from them
+    pub link: LinkMonitor,
+    /// The agreement of the session's application keys
+    pub key_agreement: KeyAgreement,
+    /// The application keys agreed with this client, see
+    /// [`crate::model::keys`]
+    pub session_keys: Option<Arc<SessionKeys>>,
+    /// The most recent significant events of the connection
+    pub events: EventRing,
+    /// Sequence number of the next payload sent to this client
+    payload_sequence: u64,
+    /// The local ICE username fragment, used to attribute stray STUN traffic
+    local_ufrag: String,
+    /// Remote addresses this client has accepted traffic from
+    remote_addrs: HashSet<SocketAddr>,
+    /// Sampling state for event logging
+    event_log: EventLogger,
+    /// Log prefix identifying this client
+    log_prefix: String,
     /// The ID of the data channel, if one has been opened
     cid: Option<ChannelId>,
+    /// All open data channels by label
+    channels: HashMap<String, ChannelId>,
+    /// Data channels opened by the server rather than the peer, with their
+    /// delivery options
+    own_channels: HashMap<ChannelId, ChannelOptions>,
+    /// The ID of the mission channel, if one has been opened
+    mission_cid: Option<ChannelId>,
+    /// The last mission plan downloaded from the peer
+    mission: MissionReceiver,
+    /// The ID of the telemetry channel, if one has been opened
+    telemetry_cid: Option<ChannelId>,
+    /// GPS fixes received since the last call to [`Client::take_gps_fixes`]
+    gps_fixes: Vec<GpsFix>,
+    /// The ID of the coordination channel, if one has been opened
+    coordination_cid: Option<ChannelId>,
+    /// Coordination messages waiting to be relayed to the other clients
+    coordination_inbox: Vec<Vec<u8>>,
+    /// The ID of the control channel, if one has been opened
+    control_cid: Option<ChannelId>,
+    /// Control messages received since the last call to
+    /// [`Client::take_control_messages`]
+    control_inbox: Vec<ControlMessage>,
+    /// The ID of the session channel, if one has been opened
+    session_cid: Option<ChannelId>,
+    /// Session messages received since the last call to
+    /// [`Client::take_session_messages`]
+    session_inbox: Vec<SessionMessage>,
+    /// The ID of the logs channel, if one has been opened
+    logs_cid: Option<ChannelId>,
+    /// Log replies whose chunks are still arriving
+    logs: LogAssembler,
+    /// ID of the next log request
+    next_log_request: u32,
+    /// Log requests answered since the last call to [`Client::take_log_replies`]
+    log_replies: Vec<(u32, Result<String, String>)>,
+    /// The ID of the announce channel, if one has been opened
+    announce_cid: Option<ChannelId>,
+    /// Announcements acknowledged since the last call to
+    /// [`Client::take_announce_acks`]
+    announce_acks: Vec<u32>,
+    /// The ID of the capture channel, if one has been opened
+    capture_cid: Option<ChannelId>,
+    /// Captures whose chunks are still arriving
+    captures: CaptureAssembler,
+    /// ID of the next capture
+    next_capture: u32,
+    /// Captures received since the last call to [`Client::take_captures`]
+    capture_results: Vec<(u32, Result<CaptureResult, String>)>,
+    /// The ID of the crash channel, if one has been opened
+    crash_cid: Option<ChannelId>,
+    /// Crash reports whose chunks are still arriving
+    crash_uploads: CrashAssembler,
+    /// Crash reports received since the last call to
+    /// [`Client::take_crash_reports`]
+    crash_reports: Vec<(String, Vec<u8>)>,
+    /// The ID of the transfer channel, if one has been opened
+    transfer_cid: Option<ChannelId>,
+    /// Files sent to and received from the peer
+    transfers: Transfers,
+    /// Transfers that ended since the last call to
+    /// [`Client::take_transfer_events`]
+    transfer_events: Vec<TransferEvent>,
+    /// Application data received since the last call to [`Client::take_received`]
+    received: Vec<(String, Vec<u8>)>,
+    /// Media tracks the peer sends
+    tracks: Vec<TrackInEntry>,
+    /// Media received since the last call to [`Client::take_media`]
+    media: Vec<MediaData>,
+    /// The audio track carrying the operator's voice, if the peer offered one
+    audio: Option<AudioSender>,
+    /// The ID of the forward channel, if the peer receives forwarded tracks
+    forward_cid: Option<ChannelId>,
+    /// Whether the forward channel opened since the last call to
+    /// [`Client::take_forward_opened`]
+    forward_opened: bool,
+    /// Tracks of other clients forwarded to the peer
+    tracks_out: Vec<TrackOut>,
+    /// The offer of forwarded tracks awaiting the peer's answer
+    forward_pending: Option<SdpPendingOffer>,
+    /// When forwarded tracks were last offered, until the offer is answered
+    forward_offered_at: Option<Instant>,
+    /// Events for other clients since the last call to
+    /// [`Client::take_propagated`]
+    propagated: Vec<Propagated>,
+    /// Settings of the outbound queues
+    send_queue: SendQueueConfig,
+    /// Messages whose writes failed since the last call to
+    /// [`Client::take_write_failures`]
+    write_failures: Vec<WriteFailure>,
+    /// Messages waiting for their congested channel, by channel
+    outbound: HashMap<ChannelId, OutboundQueue>,
+    /// Records the messages sent and received, if recording is enabled
+    recorder: Option<Recorder>,
+    /// Why the server disconnected the client, if it did, see [`Client::evict`]
+    eviction: Option<String>,
 }
 
 /// Unique identifier for a client connection.
 ///
 /// Client IDs are assigned sequentially using an atomic counter to ensure
 /// uniqueness across all clients.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ClientId(u64);
 
+impl ClientId {
+    /// Allocates the next unique client ID.
+    ///
+    /// IDs are allocated during signaling, so the peer can be told its ID
+    /// before the client is handed to the event loop.
+    pub fn next() -> ClientId {
+        static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+        ClientId(ID_COUNTER.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
 impl Deref for ClientId {
     type Target = u64;
 
@@ -44,23 +291,235 @@ impl Deref for ClientId {
     }
 }
 
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Why a client was removed from the server's pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// The WebRTC connection ended on its own: the peer left, or ICE or DTLS
+    /// failed
+    RtcDead,
+    /// The server disconnected the client, e.g. because its session expired
+    Evicted(String),
+    /// The server shut down and closed the connection
+    Drained,
+}
+
+impl fmt::Display for RemovalReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RtcDead => write!(f, "connection ended"),
+            Self::Evicted(reason) => write!(f, "evicted: {}", reason),
+            Self::Drained => write!(f, "server shutting down"),
+        }
+    }
+}
+
+/// A client removed from the server's pool, with its final statistics, so
+/// external systems can release what they hold for it.
+#[derive(Debug, Clone)]
+pub struct ClientRemoval {
+    /// The ID of the removed client
+    pub id: ClientId,
+    /// The alias the client was registered under, if any
+    pub alias: Option<String>,
+    /// The identity established by authentication, if any
+    pub subject: Option<String>,
+    /// The room the client belonged to, if any
+    pub room: Option<String>,
+    /// Why it was removed
+    pub reason: RemovalReason,
+    /// The statistics of the connection when it was removed
+    pub stats: ConnectionStats,
+    /// The client's cumulative traffic counters
+    pub counters: TrafficCounters,
+}
+
 impl Client {
-    /// Creates a new client with a unique ID and the given RTC instance.
+    /// Creates a new client with the given ID and RTC instance.
     ///
     /// # Arguments
     ///
+    /// * `id` - The unique ID allocated with [`ClientId::next`]
     /// * `rtc` - The str0m RTC instance for this client
+    /// * `access` - The access granted to this client during signaling
+    /// * `session_token` - The session token handed to the peer
     ///
     /// # Returns
     ///
-    /// A new `Client` instance with a unique ID
-    pub fn new(rtc: Rtc) -> Client {
-        static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
-        let next_id = ID_COUNTER.fetch_add(1, Ordering::SeqCst);
+    /// A new `Client` instance
+    pub fn new(id: ClientId, mut rtc: Rtc, access: Access, session_token: String) -> Client {
+        let local_ufrag = rtc.direct_api().local_ice_credentials().ufrag;
         Client {
-            id: ClientId(next_id),
+            id,
+            alias: None,
+            session_token,
             rtc,
+            access,
+            counters: TrafficCounters::default(),
+            connection: ConnectionTracker::new(),
+            codecs: CodecTracker::new(),
+            setup: SetupTimer::new(),
+            session: SessionLifetime::new(&SessionConfig::default()),
+            protocol: Negotiation::default(),
+            features: FeatureSet::default(),
+            legacy_interop: true,
+            rates: RateDemand::new(),
+            link: LinkMonitor::new(HeartbeatConfig::default()),
+            key_agreement: KeyAgreement::new(),
+            session_keys: None,
+            events: EventRing::new(),
+            payload_sequence: 0,
+            local_ufrag,
+            remote_addrs: HashSet::new(),
+            event_log: EventLogger::new(),
+            log_prefix: format!("Client({})", id),
             cid: None,
+            channels: HashMap::new(),
+            own_channels: HashMap::new(),
+            mission_cid: None,
+            mission: MissionReceiver::downloads(),
+            telemetry_cid: None,
+            gps_fixes: vec![],
+            coordination_cid: None,
+            coordination_inbox: vec![],
+            control_cid: None,
+            control_inbox: vec![],
+            session_cid: None,
+            session_inbox: vec![],
+            logs_cid: None,
+            logs: LogAssembler::new(),
+            next_log_request: 0,
+            log_replies: vec![],
+            announce_cid: None,
+            announce_acks: vec![],
+            capture_cid: None,
+            crash_cid: None,
+            crash_uploads: CrashAssembler::new(),
+            crash_reports: vec![],
+            transfer_cid: None,
+            transfers: Transfers::default(),
+            transfer_events: vec![],
+            captures: CaptureAssembler::new(),
+            next_capture: 0,
+            capture_results: vec![],
+            received: vec![],
+            tracks: vec![],
+            media: vec![],
+            audio: None,
+            forward_cid: None,
+            forward_opened: false,
+            tracks_out: vec![],
+            forward_pending: None,
+            forward_offered_at: None,
+            propagated: vec![],
+            send_queue: SendQueueConfig::default(),
+            write_failures: vec![],
+            outbound: HashMap::new(),
+            recorder: None,
+            eviction: None,
+        }
+    }
+
+    /// Sets the alias assigned by the client registry.
+    ///
+    /// The alias is included in every log line about this client, followed by
+    /// the authenticated identity if that differs.
+    pub fn set_alias(&mut self, alias: Option<String>) {
+        self.log_prefix = match (&alias, self.identity()) {
+            (Some(alias), Some(subject)) if alias != subject => {
+                format!("Client({} {} as {})", self.id, alias, subject)
+            }
+            (Some(alias), _) => format!("Client({} {})", self.id, alias),
+            (None, Some(subject)) => format!("Client({} as {})", self.id, subject),
+            (None, None) => format!("Client({})", self.id),
+        };
+        self.alias = alias;
+    }
+
+    /// Sets the outbound queue settings; channels already queueing messages
+    /// keep their capacity and coalescing.
+    pub fn set_send_queue(&mut self, config: SendQueueConfig) {
+        self.send_queue = config;
+    }
+
+    /// Sets the file transfer settings; received files go to a directory
+    /// named after the client's alias, or its ID, in the transfer directory.
+    pub fn set_transfer(&mut self, config: &TransferConfig) {
+        let owner = match &self.alias {
+            Some(alias) => transfer::file_name(alias),
+            None => format!("client-{}", *self.id),
+        };
+        self.transfers.configure(config, config.dir.join(owner));
+    }
+
+    /// Sets the recorder of the messages sent and received.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Records a message exchanged on a channel, if recording is enabled.
+    fn record(&self, direction: recording::Direction, cid: ChannelId, data: &[u8]) {
+        let (Some(recorder), Some(label)) = (&self.recorder, self.label_of(cid)) else {
+            return;
+        };
+        let client = match &self.alias {
+            Some(alias) => alias.clone(),
+            None => self.id.to_string(),
+        };
+        recorder.record(direction, label, Some(&client), data);
+    }
+
+    /// Returns the identity established by the authentication backend, e.g.
+    /// the subject of the peer's token.
+    pub fn identity(&self) -> Option<&str> {
+        self.access.subject.as_deref()
+    }
+
+    /// Returns the name identifying this client in logs, e.g. `Client(3 rover-7)`.
+    pub fn name(&self) -> &str {
+        &self.log_prefix
+    }
+
+    /// Disconnects the client on the server's initiative.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why, reported once the client is removed; the first
+    ///   reason given is kept
+    pub fn evict(&mut self, reason: &str) {
+        self.events
+            .record(EventCategory::State, format!("evicted: {}", reason));
+        self.eviction.get_or_insert_with(|| reason.to_string());
+        self.rtc.disconnect();
+    }
+
+    /// Returns why the connection ended: the reason it was evicted for, or
+    /// [`RemovalReason::RtcDead`].
+    pub fn removal_reason(&self) -> RemovalReason {
+        self.eviction
+            .clone()
+            .map_or(RemovalReason::RtcDead, RemovalReason::Evicted)
+    }
+
+    /// Takes the final statistics of a client leaving the server's pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the client is removed
+    pub fn removal(&mut self, reason: RemovalReason) -> ClientRemoval {
+        ClientRemoval {
+            id: self.id,
+            alias: self.alias.clone(),
+            subject: self.access.subject.clone(),
+            room: self.access.room.clone(),
+            reason,
+            stats: self.stats(),
+            counters: self.counters.clone(),
         }
     }
 
@@ -80,6 +539,31 @@ impl Client {
         self.rtc.accepts(input)
     }
 
+    /// Returns the ufrag of the server's end of the session.
+    pub fn local_ufrag(&self) -> &str {
+        &self.local_ufrag
+    }
+
+    /// Checks if an unmatched packet plausibly belongs to this client.
+    ///
+    /// STUN is attributed by the recipient ufrag in its USERNAME attribute; DTLS
+    /// and RTP by a source IP this client has previously received traffic from,
+    /// which is typical of a NAT rebinding to a new port.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The source address of the unmatched packet
+    /// * `class` - The classification of the unmatched packet
+    pub fn is_implicated_by(&self, source: SocketAddr, class: &PacketClass) -> bool {
+        match class {
+            PacketClass::Stun { ufrag: Some(ufrag) } => *ufrag == self.local_ufrag,
+            PacketClass::Dtls | PacketClass::Rtp => {
+                self.remote_addrs.iter().any(|a| a.ip() == source.ip())
+            }
+            _ => false,
+        }
+    }
+
     /// Handles an input event for this client.
     ///
     /// Passes the input to the RTC instance for processing. If the client is
@@ -93,8 +577,14 @@ impl Client {
             return;
         }
 
+        if let Input::Receive(_, receive) = &input {
+            self.remote_addrs.insert(receive.source);
+        }
+
         if let Err(e) = self.rtc.handle_input(input) {
-            warn!("Client ({}) disconnected: {:?}", *self.id, e);
+            warn!("{} disconnected: {:?}", self.log_prefix, e);
+            self.events
+                .record(EventCategory::Error, format!("disconnected: {:?}", e));
             self.rtc.disconnect();
         }
     }
@@ -106,21 +596,26 @@ impl Client {
     ///
     /// # Arguments
     ///
-    /// * `socket` - The UDP socket for sending outgoing packets
+    /// * `transport` - Where outgoing packets are sent
     ///
     /// # Returns
     ///
     /// * `Some(Instant)` - The next timeout instant if a timeout event was received
     /// * `None` - If a transmit or application event was handled
-    pub fn poll_output(&mut self, socket: &UdpSocket) -> Option<Instant> {
+    pub fn poll_output(&mut self, transport: &dyn Transport) -> Option<Instant> {
         if !self.rtc.is_alive() {
             return Some(Instant::now());
         }
+        if self.counters.messages_queued > 0 {
+            self.flush_outbound(false);
+        }
 
         match self.rtc.poll_output() {
-            Ok(output) => self.handle_output(output, socket),
+            Ok(output) => self.handle_output(output, transport),
             Err(e) => {
-                warn!("Client ({}) poll_output failed: {:?}", *self.id, e);
+                warn!("{} poll_output failed: {:?}", self.log_prefix, e);
+                self.events
+                    .record(EventCategory::Error, format!("poll_output failed: {:?}", e));
                 self.rtc.disconnect();
                 Some(Instant::now())
             }
@@ -137,106 +632,574 @@ impl Client {
     /// # Arguments
     ///
     /// * `output` - The output event from the RTC instance
-    /// * `socket` - The UDP socket for sending packets
+    /// * `transport` - Where packets are sent
     ///
     /// # Returns
     ///
     /// * `Some(Instant)` - The next timeout instant for timeout events
     /// * `None` - For transmit and application events
-    fn handle_output(&mut self, output: Output, socket: &UdpSocket) -> Option<Instant> {
+    fn handle_output(&mut self, output: Output, transport: &dyn Transport) -> Option<Instant> {
         match output {
             Output::Transmit(transmit) => {
-                if let Err(e) = socket.send_to(&transmit.contents, transmit.destination) {
+                if let Err(e) = transport.send_to(&transmit.contents, transmit.destination) {
                     warn!(
-                        "Client({}) failed to send UDP data: {:?}. Connection may be degraded.",
-                        *self.id, e
+                        "{} failed to send UDP data: {:?}. Connection may be degraded.",
+                        self.log_prefix, e
                     );
                     // Don't disconnect immediately - allow recovery attempts
                 } else {
-                    debug!(
-                        "Client({}) transmitted {} bytes",
-                        *self.id,
-                        transmit.contents.len()
-                    );
+                    self.counters.bytes_sent += transmit.contents.len() as u64;
+                    self.connection.sent(transmit.contents.len());
+                    self.event_log
+                        .log(&self.log_prefix, EventKind::Transmit, || {
+                            format!("transmitted {} bytes", transmit.contents.len())
+                        });
                 }
                 None
             }
             Output::Timeout(t) => Some(t),
-            Output::Event(e) => {
-                // Enhanced event logging for connection monitoring
-                match &e {
-                    Event::IceConnectionStateChange(state) => {
-                        info!("Client({}): ICE State changed to {:?}", *self.id, state);
-
-                        match state {
-                            IceConnectionState::Checking => {
-                                info!("Client({}): ICE checking started", *self.id);
-                            }
-                            IceConnectionState::Connected => {
-                                info!("Client({}): ICE connection established", *self.id);
-                            }
-                            IceConnectionState::Disconnected => {
-                                warn!(
-                                    "Client({}): ICE disconnected - monitoring for recovery",
-                                    *self.id
-                                );
-                                // Don't auto-disconnect - connection might recover
-                            }
-                            _ => {}
+            Output::Event(Event::ChannelData(data))
+                if self.features.contains(Feature::Batching)
+                    && Some(data.id) != self.control_cid
+                    && batch::is_batch(&data.data) =>
+            {
+                // Handle each message of a batch as if it had arrived alone
+                let started = Instant::now();
+                match batch::decode_batch(&data.data) {
+                    Some(messages) => {
+                        if let Some(label) = self.label_of(data.id).map(str::to_string) {
+                            self.codecs.decoded(
+                                &label,
+                                codec_name(false, true),
+                                data.data.len(),
+                                messages.iter().map(Vec::len).sum(),
+                                started.elapsed(),
+                            );
+                        }
+                        for message in messages {
+                            let unpacked = ChannelData {
+                                id: data.id,
+                                binary: data.binary,
+                                data: message,
+                            };
+                            self.handle_event(Event::ChannelData(unpacked));
                         }
                     }
-                    Event::ChannelOpen(cid, name) => {
-                        info!(
-                            "Client({}) data channel opened - Name: '{}', ID: {:?}",
-                            *self.id, name, cid
-                        );
-                        self.cid = Some(*cid);
+                    None => warn!("{} sent an undecodable batch", self.log_prefix),
+                }
+                None
+            }
+            Output::Event(e) => {
+                if let Event::ChannelData(data) = &e {
+                    if let Some(label) = self.label_of(data.id).map(str::to_string) {
+                        let size = data.data.len();
+                        self.codecs
+                            .decoded(&label, CODEC_NONE, size, size, Duration::ZERO);
                     }
-                    Event::ChannelData(data) => {
-                        let payload: Payload = Payload::deserialize(data.data.clone());
-                        info!(
-                            "Client({}) received data: {}, timestamp: {}, latency: {} ms",
-                            *self.id,
-                            payload.data(),
-                            payload.timestamp(),
-                            payload.latency()
-                        );
+                }
+                self.handle_event(e);
+                None
+            }
+        }
+    }
+
+    /// Handles an application event of the RTC instance: ICE state changes,
+    /// opened channels and received data.
+    fn handle_event(&mut self, e: Event) {
+        self.connection.handle_event(&e);
+        if let Event::ChannelData(data) = &e {
+            self.counters.bytes_received += data.data.len() as u64;
+            self.counters.messages_received += 1;
+            self.record(recording::Direction::Inbound, data.id, &data.data);
+        }
+        if let Event::ChannelClose(cid) = &e {
+            let label = self.label_of(*cid).unwrap_or("unknown").to_string();
+            self.events.record(
+                EventCategory::Channel,
+                format!("channel '{}' closed", label),
+            );
+            if let Some(mut queue) = self.outbound.remove(cid) {
+                self.counters.messages_dropped += queue.clear() as u64;
+                self.update_queued();
+            }
+        }
+        if self.setup.observe(&e) {
+            let breakdown = self.setup.breakdown();
+            info!("{} setup complete: {}", self.log_prefix, breakdown);
+            self.events.record(
+                EventCategory::State,
+                format!("setup complete: {}", breakdown),
+            );
+        }
+
+        if let Event::MediaData(data) = e {
+            self.counters.bytes_received += data.data.len() as u64;
+            // Frames following lost packets may not decode until the next
+            // keyframe
+            if !data.contiguous {
+                self.request_keyframe(data.mid);
+            }
+            self.media.push(data);
+            return;
+        }
+
+        // Enhanced event logging for connection monitoring
+        match &e {
+            Event::IceConnectionStateChange(state) => {
+                self.event_log
+                    .log(&self.log_prefix, EventKind::IceState, || {
+                        format!("ICE State changed to {:?}", state)
+                    });
+                self.counters.ice_state = Some(*state);
+                self.events
+                    .record(EventCategory::State, format!("ICE {:?}", state));
+
+                if *state == IceConnectionState::Disconnected {
+                    warn!(
+                        "{}: ICE disconnected - monitoring for recovery",
+                        self.log_prefix
+                    );
+                    // Don't auto-disconnect - connection might recover
+                }
+            }
+            Event::MediaAdded(added) if self.is_forwarded_track(added.mid) => {
+                debug!(
+                    "{} negotiated forwarded track {}",
+                    self.log_prefix, added.mid
+                );
+            }
+            Event::MediaAdded(added)
+                if added.kind == MediaKind::Audio && added.direction.is_sending() =>
+            {
+                info!(
+                    "{} accepts operator audio, media ID {}",
+                    self.log_prefix, added.mid
+                );
+                self.events.record(
+                    EventCategory::Channel,
+                    format!("audio track {} added", added.mid),
+                );
+                self.audio = Some(AudioSender::new(added.mid));
+            }
+            Event::MediaAdded(added) => {
+                info!(
+                    "{} added {:?} track, media ID {}",
+                    self.log_prefix, added.kind, added.mid
+                );
+                self.events.record(
+                    EventCategory::Channel,
+                    format!("{:?} track {} added", added.kind, added.mid),
+                );
+                let track = TrackIn {
+                    origin: self.id,
+                    origin_name: self.log_prefix.clone(),
+                    mid: added.mid,
+                    kind: added.kind,
+                };
+                let track = Arc::new(track);
+                // Observers' media is not forwarded, like their data
+                if !self.access.is_observer() {
+                    self.propagated
+                        .push(Propagated::TrackOpen(self.id, Arc::downgrade(&track)));
+                }
+                self.tracks.push(TrackInEntry {
+                    id: track,
+                    last_keyframe_request: None,
+                });
+            }
+            Event::KeyframeRequest(request) => {
+                let source = self
+                    .tracks_out
+                    .iter()
+                    .find(|track| track.mid() == Some(request.mid))
+                    .and_then(TrackOut::track_in);
+                if let Some(source) = source {
+                    self.propagated.push(Propagated::KeyframeRequest(
+                        self.id,
+                        *request,
+                        source.origin,
+                        source.mid,
+                    ));
+                }
+            }
+            Event::ChannelOpen(cid, name) => {
+                self.event_log
+                    .log(&self.log_prefix, EventKind::ChannelOpen, || {
+                        format!("data channel opened - Name: '{}', ID: {:?}", name, cid)
+                    });
+                self.events
+                    .record(EventCategory::Channel, format!("channel '{}' opened", name));
+                self.channels.insert(name.clone(), *cid);
+                if name == MISSION_CHANNEL {
+                    self.mission_cid = Some(*cid);
+                } else if name == TELEMETRY_CHANNEL {
+                    self.telemetry_cid = Some(*cid);
+                } else if name == COORDINATION_CHANNEL {
+                    self.coordination_cid = Some(*cid);
+                } else if name == SESSION_CHANNEL {
+                    self.session_cid = Some(*cid);
+                } else if name == CONTROL_CHANNEL {
+                    self.control_cid = Some(*cid);
+                } else if name == LOGS_CHANNEL {
+                    self.logs_cid = Some(*cid);
+                } else if name == ANNOUNCE_CHANNEL {
+                    self.announce_cid = Some(*cid);
+                } else if name == CAPTURE_CHANNEL {
+                    self.capture_cid = Some(*cid);
+                } else if name == CRASH_CHANNEL {
+                    self.crash_cid = Some(*cid);
+                } else if name == TRANSFER_CHANNEL {
+                    self.transfer_cid = Some(*cid);
+                } else if name == FORWARD_CHANNEL {
+                    self.forward_cid = Some(*cid);
+                    self.forward_opened = true;
+                } else if !self.own_channels.contains_key(cid) {
+                    self.cid = Some(*cid);
+                }
+            }
+            Event::ChannelData(data) if Some(data.id) == self.control_cid => {
+                match ControlMessage::decode(&data.data) {
+                    Some(message) => self.control_inbox.push(message),
+                    None => {
+                        warn!("{} sent an undecodable control message", self.log_prefix);
+                        self.events
+                            .record(EventCategory::Error, "undecodable control message");
                     }
-                    _ => {
-                        debug!("Client({}): Event: {:?}", *self.id, e);
+                }
+            }
+            Event::ChannelData(data)
+                if self.protocol.is_fallback() && self.is_versioned(data.id) =>
+            {
+                debug!(
+                    "{} speaks an incompatible protocol, dropping versioned data",
+                    self.log_prefix
+                );
+            }
+            // Observers must be able to refresh their session too
+            Event::ChannelData(data) if Some(data.id) == self.session_cid => {
+                match SessionMessage::decode(&data.data) {
+                    Some(message) => self.session_inbox.push(message),
+                    None => {
+                        warn!("{} sent an undecodable session message", self.log_prefix);
                     }
                 }
-
-                // Only disconnect on explicit close, not on transient disconnections
-                // This allows the connection to recover from temporary network issues
-                None
             }
+            // Logs are only sent on request, so observers may answer too
+            Event::ChannelData(data) if Some(data.id) == self.logs_cid => {
+                self.handle_logs_data(&data.data);
+            }
+            // Announcements go to observers too, which acknowledge them
+            Event::ChannelData(data) if Some(data.id) == self.announce_cid => {
+                match AnnounceMessage::decode(&data.data) {
+                    Some(AnnounceMessage::Ack { id }) => self.announce_acks.push(id),
+                    _ => warn!("{} sent an unexpected announce message", self.log_prefix),
+                }
+            }
+            Event::ChannelData(data) if Some(data.id) == self.capture_cid => {
+                match CaptureMessage::decode(&data.data) {
+                    Some(message) => {
+                        self.capture_results.extend(self.captures.handle(message));
+                    }
+                    None => {
+                        warn!("{} sent an undecodable capture message", self.log_prefix);
+                    }
+                }
+            }
+            // Observers may receive forwarded tracks
+            Event::ChannelData(data) if Some(data.id) == self.forward_cid => {
+                match ForwardMessage::decode(&data.data) {
+                    Some(message) => self.handle_forward_message(message),
+                    None => {
+                        warn!("{} sent an undecodable forward message", self.log_prefix);
+                    }
+                }
+            }
+            Event::ChannelData(_) if self.access.is_observer() => {
+                debug!("{} is an observer, dropping its data", self.log_prefix);
+            }
+            Event::ChannelData(data) if Some(data.id) == self.transfer_cid => {
+                let events = self.transfers.handle(&data.data);
+                self.transfer_events.extend(events);
+            }
+            Event::ChannelData(data) if Some(data.id) == self.crash_cid => {
+                match CrashMessage::decode(&data.data) {
+                    Some(message) => match self.crash_uploads.handle(message) {
+                        Ok(report) => self.crash_reports.extend(report),
+                        Err(e) => warn!("{} sent an unusable crash report: {}", self.log_prefix, e),
+                    },
+                    None => {
+                        warn!("{} sent an undecodable crash message", self.log_prefix);
+                    }
+                }
+            }
+            Event::ChannelData(data) if Some(data.id) == self.mission_cid => {
+                self.handle_mission_data(&data.data);
+            }
+            Event::ChannelData(data) if Some(data.id) == self.telemetry_cid => {
+                match Telemetry::decode(&data.data) {
+                    Some(Telemetry::Gps(fix)) => self.gps_fixes.push(fix),
+                    None => {
+                        warn!("{} sent undecodable telemetry", self.log_prefix);
+                    }
+                }
+            }
+            Event::ChannelData(data) if Some(data.id) == self.coordination_cid => {
+                self.coordination_inbox.push(data.data.clone());
+            }
+            Event::ChannelData(data) => {
+                let envelope = Envelope::decode(&data.data);
+                if let Some((_, WireFormat::Legacy)) = &envelope {
+                    if !self.legacy_interop {
+                        warn!(
+                            "{} sent a legacy payload, but legacy interop is disabled",
+                            self.log_prefix
+                        );
+                        return;
+                    }
+                }
+                if let Some(label) = self.label_of(data.id) {
+                    self.received.push((label.to_string(), data.data.clone()));
+                }
+                // Other application data is opaque to the server
+                if let Some((envelope, format)) = envelope {
+                    let payload = &envelope.payload;
+                    self.counters.last_latency_ms = Some(payload.latency_ms());
+                    self.event_log
+                        .log(&self.log_prefix, EventKind::ChannelData, || {
+                            format!(
+                                "received {:?} {} #{} on '{}': {}, timestamp: {}, latency: {} ms",
+                                format,
+                                envelope.kind.name(),
+                                envelope.sequence,
+                                envelope.topic,
+                                payload.data(),
+                                payload.timestamp(),
+                                payload.latency()
+                            )
+                        });
+                }
+            }
+            _ => {
+                self.event_log.log(&self.log_prefix, EventKind::Other, || {
+                    format!("Event: {:?}", e)
+                });
+            }
+        }
+    }
+
+    /// Counts a datagram received from the peer in the connection statistics.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The size of the datagram
+    pub fn count_received(&mut self, bytes: usize) {
+        self.connection.received(bytes);
+    }
+
+    /// Returns the current statistics of the connection: the selected
+    /// candidate pair, the traffic, the round-trip times and the state of
+    /// every open data channel.
+    pub fn stats(&mut self) -> ConnectionStats {
+        let link = self
+            .features
+            .contains(Feature::Heartbeat)
+            .then(|| self.link.stats());
+        let mut channels = vec![];
+        for (label, cid) in &self.channels {
+            let Some(mut channel) = self.rtc.channel(*cid) else {
+                continue;
+            };
+            channels.push(ChannelStats {
+                label: label.clone(),
+                buffered_amount: channel.buffered_amount(),
+                queued_messages: self.outbound.get(cid).map_or(0, OutboundQueue::len),
+                codec: self.codecs.stats(label),
+            });
+        }
+        channels.sort_by(|a, b| a.label.cmp(&b.label));
+        self.connection.snapshot(link, channels)
+    }
+
+    /// Returns the current state of the client, for state dumps.
+    pub fn state(&self) -> ClientState {
+        let mut channels: Vec<String> = self.channels.keys().cloned().collect();
+        channels.sort();
+        let mut remote_addrs: Vec<SocketAddr> = self.remote_addrs.iter().copied().collect();
+        remote_addrs.sort();
+        ClientState {
+            id: *self.id,
+            alias: self.alias.clone(),
+            role: self.access.role,
+            room: self.access.room.clone(),
+            alive: self.rtc.is_alive(),
+            ice_state: self.counters.ice_state.map(|s| format!("{:?}", s)),
+            remote_addrs,
+            protocol_version: match self.protocol {
+                Negotiation::Agreed { version } => Some(version),
+                _ => None,
+            },
+            protocol_fallback: self.protocol.is_fallback(),
+            features: self.features.names(),
+            channels,
+            tracks: self
+                .tracks
+                .iter()
+                .map(|t| format!("{:?} {}", t.id.kind, t.id.mid))
+                .collect(),
+            setup: self.setup.breakdown(),
+            link: self
+                .features
+                .contains(Feature::Heartbeat)
+                .then(|| self.link.stats()),
+            bytes_received: self.counters.bytes_received,
+            bytes_sent: self.counters.bytes_sent,
+            messages_queued: self.counters.messages_queued,
+            messages_dropped: self.counters.messages_dropped,
+            events: self.events.snapshot(),
+            events_dropped: self.events.dropped(),
         }
     }
 
+    /// Closes the connection for a graceful shutdown.
+    ///
+    /// Sends a goodbye on the control channel, so the peer disconnects at once
+    /// instead of waiting for the ICE timeout, closes every data channel and
+    /// transmits what is still queued before disconnecting the RTC instance.
+    /// str0m closes the channels locally only and sends no DTLS close alert,
+    /// hence the goodbye.
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - Where the closing packets are sent
+    /// * `reason` - Why the connection is closed, reported to the peer
+    pub fn close(&mut self, transport: &dyn Transport, reason: &str) {
+        if !self.rtc.is_alive() {
+            return;
+        }
+        info!(
+            "{} closing {} channel(s)",
+            self.log_prefix,
+            self.channels.len()
+        );
+        self.events
+            .record(EventCategory::State, format!("closed: {}", reason));
+        // The goodbye must not be left behind in a queue
+        self.flush_outbound(true);
+        self.send_control(&ControlMessage::Goodbye {
+            reason: reason.to_string(),
+        });
+        self.flush_outbound(true);
+        for (_, cid) in self.channels.drain() {
+            self.rtc.direct_api().close_data_channel(cid);
+        }
+        // Transmit everything queued until str0m only has a timeout left
+        self.rtc.handle_input(Input::Timeout(Instant::now())).ok();
+        while self.poll_output(transport).is_none() {}
+        self.rtc.disconnect();
+    }
+
     /// Sends a message to the client over the data channel.
     ///
-    /// If a data channel is open, this method writes the message as bytes.
-    /// Logs success or failure of the send operation.
+    /// If a data channel is open, this method writes the message as text, or
+    /// queues it while the channel is congested.
     ///
     /// # Arguments
     ///
     /// * `message` - The string message to send
     pub fn send_message(&mut self, message: &str) {
         if let Some(cid) = self.cid {
-            if let Some(mut channel) = self.rtc.channel(cid) {
-                match channel.write(false, message.as_bytes()) {
-                    Ok(_) => {
-                        info!("Sent to Client({}): {}", *self.id, message);
-                    }
-                    Err(e) => {
-                        warn!("Failed to send to Client({}): {:?}", *self.id, e);
-                    }
-                }
+            if self.write(cid, false, message.as_bytes().to_vec()) {
+                info!("Sent to {}: {}", self.log_prefix, message);
             }
         }
     }
 
+    /// Sends a timestamped payload on the data channel, in the format the
+    /// client understands.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The payload data
+    ///
+    /// # Returns
+    ///
+    /// `true` if a data channel is open and the payload was written or queued
+    pub fn send_payload(&mut self, data: &[u8]) -> bool {
+        let format = WireFormat::for_session(self.protocol, self.legacy_interop);
+        let Some(cid) = self.cid else {
+            return false;
+        };
+        let topic = self.label_of(cid).unwrap_or_default().to_string();
+        let envelope = Envelope::new(
+            MessageKind::Data,
+            &topic,
+            self.payload_sequence,
+            Payload::new(data),
+        );
+        let written = self.write(cid, true, envelope.encode(format));
+        if written {
+            self.payload_sequence += 1;
+        }
+        written
+    }
+
+    /// Opens a data channel towards the peer.
+    ///
+    /// The channel is announced once the SCTP association is up, and is
+    /// reported in the logs and usable with [`Client::send_on_channel`] when
+    /// it opens.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel
+    /// * `options` - The delivery options of the channel
+    ///
+    /// # Returns
+    ///
+    /// The ID of the new channel
+    pub fn open_channel(&mut self, label: &str, options: &ChannelOptions) -> ChannelId {
+        let cid = self
+            .rtc
+            .direct_api()
+            .create_data_channel(options.config(label));
+        self.own_channels.insert(cid, *options);
+        cid
+    }
+
+    /// Sends raw bytes on the data channel with the given label.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel to write to
+    /// * `data` - The bytes to send
+    ///
+    /// # Returns
+    ///
+    /// `true` if the channel is open and the data was written or queued,
+    /// `false` otherwise
+    pub fn send_on_channel(&mut self, label: &str, data: &[u8]) -> bool {
+        let Some(&cid) = self.channels.get(label) else {
+            return false;
+        };
+        // Once batching is agreed, data starting with the batch marker must be
+        // framed as a batch of one
+        let started = Instant::now();
+        let batching = self.features.contains(Feature::Batching);
+        let framed = if batching {
+            batch::frame_single(data.to_vec())
+        } else {
+            data.to_vec()
+        };
+        self.codecs.encoded(
+            label,
+            codec_name(false, batching),
+            data.len(),
+            framed.len(),
+            started.elapsed(),
+        );
+        self.write(cid, true, framed)
+    }
+
     /// Updates local candidates when network interfaces change.
     ///
     /// Call this when you detect a network change to add new candidates.
@@ -244,22 +1207,948 @@ impl Client {
     /// # Arguments
     ///
     /// * `new_addr` - The new socket address to add as a candidate
-    pub fn add_new_candidate(&mut self, new_addr: SocketAddr) {
-        let candidate = Candidate::host(new_addr, "udp").expect("valid host candidate");
+    ///
+    /// # Returns
+    ///
+    /// An error if the address cannot be a host candidate, e.g. because it
+    /// is unspecified
+    pub fn add_new_candidate(&mut self, new_addr: SocketAddr) -> Result<(), RoverRtcError> {
+        let candidate = Candidate::host(new_addr, "udp")?;
         self.rtc.add_local_candidate(candidate);
+        Ok(())
     }
 
     /// Initiates an ICE restart to recover from network changes.
     ///
-    /// This creates a new offer with ice_restart flag set to true.
-    /// Note: In a production system, this offer needs to be sent to the peer
-    /// via the signaling channel and an answer must be received and applied.
+    /// The offer must reach the peer through a signaling channel, and its
+    /// answer be accepted with the returned pending offer. Peers usually
+    /// restart ICE themselves, see [`Client::accept_restart_offer`].
+    ///
+    /// # Returns
+    ///
+    /// An SDP offer with new ICE credentials and the pending offer to accept
+    /// the answer with
+    pub fn create_ice_restart_offer(&mut self) -> Option<(SdpOffer, SdpPendingOffer)> {
+        let mut change = self.rtc.sdp_api();
+        change.ice_restart(true);
+        change.apply()
+    }
+
+    /// Accepts an ICE restart offer from the peer, e.g. after it moved to
+    /// another network.
+    ///
+    /// The DTLS and SCTP associations and all data channels survive the
+    /// restart; data keeps flowing over the old path while the new one is
+    /// checked, if it still works.
+    ///
+    /// # Arguments
+    ///
+    /// * `offer` - The peer's offer with new ICE credentials
+    ///
+    /// # Returns
+    ///
+    /// The answer to send back to the peer, or an error if the offer was rejected
+    pub fn accept_restart_offer(&mut self, offer: SdpOffer) -> Result<SdpAnswer, RtcError> {
+        let answer = self.rtc.sdp_api().accept_offer(offer)?;
+        self.local_ufrag = self.rtc.direct_api().local_ice_credentials().ufrag;
+        info!("{} restarted ICE", self.log_prefix);
+        self.events
+            .record(EventCategory::Handover, "ICE restarted by the peer");
+        Ok(answer)
+    }
+
+    /// Uploads a mission plan to the peer over the mission channel.
+    ///
+    /// The plan is split into chunks; the peer only activates it once every
+    /// chunk has been received and validated, replying with an `Ack` or `Reject`.
+    ///
+    /// # Arguments
+    ///
+    /// * `plan` - The mission plan to upload
+    ///
+    /// # Returns
+    ///
+    /// `true` if all messages were written to the channel, `false` otherwise
+    pub fn upload_mission(&mut self, plan: &MissionPlan) -> bool {
+        self.write_mission(plan.to_messages(DEFAULT_CHUNK_SIZE))
+    }
+
+    /// Requests the peer's currently active mission plan.
+    ///
+    /// The downloaded plan becomes available through [`Client::mission_plan`]
+    /// once the transfer completes.
+    pub fn download_mission(&mut self) -> bool {
+        self.write_mission(vec![MissionMessage::RequestDownload])
+    }
+
+    /// Returns the last mission plan downloaded from the peer, if any.
+    pub fn mission_plan(&self) -> Option<&MissionPlan> {
+        self.mission.active()
+    }
+
+    /// Asks the peer for its recent log lines.
+    ///
+    /// The answer becomes available through [`Client::take_log_replies`]
+    /// once all its chunks arrived.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The filters selecting the lines
+    ///
+    /// # Returns
+    ///
+    /// The ID of the request, or `None` if the peer has not opened a logs
+    /// channel or it closed
+    pub fn request_logs(&mut self, query: LogQuery) -> Option<u32> {
+        let cid = self.logs_cid?;
+        let id = self.next_log_request;
+        self.next_log_request = self.next_log_request.wrapping_add(1);
+        let request = LogMessage::Request { id, query };
+        self.write(cid, true, request.encode()).then_some(id)
+    }
+
+    /// Forgets a log request whose answer is no longer awaited.
+    pub fn abandon_logs(&mut self, id: u32) {
+        self.logs.abandon(id);
+    }
+
+    /// Drains the answers to log requests received since the last call,
+    /// with the ID of each request: the log text, or why there is none.
+    pub fn take_log_replies(&mut self) -> Vec<(u32, Result<String, String>)> {
+        std::mem::take(&mut self.log_replies)
+    }
+
+    /// Sends an announcement on the announce channel.
+    ///
+    /// The peer's acknowledgment becomes available through
+    /// [`Client::take_announce_acks`].
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the announcement, the same for every client of the room
+    /// * `data` - The announcement
     ///
     /// # Returns
     ///
-    /// An SDP offer that can be sent to the peer to restart ICE
-    pub fn create_ice_restart_offer(&mut self) -> Option<SdpOffer> {
-        let change = self.rtc.sdp_api();
-        change.apply().map(|(offer, _)| offer)
+    /// `false` if the peer has not opened an announce channel or it closed
+    pub fn announce(&mut self, id: u32, data: &[u8]) -> bool {
+        let Some(cid) = self.announce_cid else {
+            return false;
+        };
+        let message = AnnounceMessage::Announce {
+            id,
+            data: data.to_vec(),
+        };
+        self.write(cid, true, message.encode())
+    }
+
+    /// Drains the IDs of the announcements the peer acknowledged since the
+    /// last call.
+    pub fn take_announce_acks(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.announce_acks)
+    }
+
+    /// Asks the peer to capture its UDP traffic.
+    ///
+    /// The peer sends the capture once it reaches either limit, which may be
+    /// lower on the peer; it becomes available through
+    /// [`Client::take_captures`].
+    ///
+    /// # Arguments
+    ///
+    /// * `duration_secs` - How long to record
+    /// * `max_bytes` - The largest pcap file, at most [`MAX_CAPTURE_SIZE`]
+    ///
+    /// # Returns
+    ///
+    /// The ID of the capture, or `None` if the peer has not opened a capture
+    /// channel or it closed
+    pub fn start_capture(&mut self, duration_secs: u32, max_bytes: u64) -> Option<u32> {
+        let cid = self.capture_cid?;
+        let id = self.next_capture;
+        self.next_capture = self.next_capture.wrapping_add(1);
+        let start = CaptureMessage::Start {
+            id,
+            duration_secs,
+            max_bytes: max_bytes.min(MAX_CAPTURE_SIZE),
+        };
+        if !self.write(cid, true, start.encode()) {
+            return None;
+        }
+        self.captures.expect(id);
+        Some(id)
+    }
+
+    /// Asks the peer to end a capture early and send what it recorded.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the peer has not opened a capture channel or it closed
+    pub fn stop_capture(&mut self, id: u32) -> bool {
+        let Some(cid) = self.capture_cid else {
+            return false;
+        };
+        self.write(cid, true, CaptureMessage::Stop { id }.encode())
+    }
+
+    /// Drains the captures received since the last call, with the ID of each
+    /// capture: the pcap file, or why there is none.
+    pub fn take_captures(&mut self) -> Vec<(u32, Result<CaptureResult, String>)> {
+        std::mem::take(&mut self.capture_results)
+    }
+
+    /// Drains the crash reports uploaded since the last call: the file name
+    /// of each report on the peer and its compressed JSON.
+    pub fn take_crash_reports(&mut self) -> Vec<(String, Vec<u8>)> {
+        std::mem::take(&mut self.crash_reports)
+    }
+
+    /// Tells the peer a crash report was stored, so it deletes its copy.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the peer has not opened a crash channel or it closed
+    pub fn acknowledge_crash_report(&mut self, name: &str) -> bool {
+        let Some(cid) = self.crash_cid else {
+            return false;
+        };
+        let stored = CrashMessage::Stored {
+            name: name.to_string(),
+        };
+        self.write(cid, true, stored.encode())
+    }
+
+    /// Queues a file for sending to the peer; it is offered once the peer
+    /// opened the transfer channel.
+    ///
+    /// Files still unconfirmed when the session ends are not sent again by
+    /// the next session, but sending them again resumes where the peer's
+    /// copy stopped.
+    ///
+    /// # Returns
+    ///
+    /// The ID of the transfer
+    pub fn send_file(&mut self, transfer: OutgoingTransfer) -> String {
+        self.transfers.send(transfer)
+    }
+
+    /// Writes the answers to the peer's transfers and the chunks of the
+    /// files sent to it that the window allows.
+    pub fn poll_transfers(&mut self) {
+        let Some(cid) = self.transfer_cid else {
+            return;
+        };
+        for message in self.transfers.poll() {
+            self.write(cid, true, message);
+        }
+    }
+
+    /// Drains the transfers in either direction that ended since the last
+    /// call.
+    pub fn take_transfer_events(&mut self) -> Vec<TransferEvent> {
+        std::mem::take(&mut self.transfer_events)
+    }
+
+    /// Drains the GPS fixes received since the last call.
+    pub fn take_gps_fixes(&mut self) -> Vec<GpsFix> {
+        std::mem::take(&mut self.gps_fixes)
+    }
+
+    /// Drains the coordination messages waiting to be relayed.
+    pub fn take_coordination_messages(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.coordination_inbox)
+    }
+
+    /// Drains the control messages received since the last call.
+    pub fn take_control_messages(&mut self) -> Vec<ControlMessage> {
+        std::mem::take(&mut self.control_inbox)
+    }
+
+    /// Drains the session messages received since the last call.
+    pub fn take_session_messages(&mut self) -> Vec<SessionMessage> {
+        std::mem::take(&mut self.session_inbox)
+    }
+
+    /// Drains the application data received since the last call, with the
+    /// label of the channel each message arrived on.
+    ///
+    /// Control, mission, telemetry, coordination, session, logs and capture
+    /// traffic is handled internally and not included.
+    pub fn take_received(&mut self) -> Vec<(String, Vec<u8>)> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// Sends a frame of the operator's voice on the audio track.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the peer offered an audio track and the frame was written
+    pub fn send_audio(&mut self, frame: AudioFrame) -> bool {
+        let Some(audio) = &mut self.audio else {
+            debug!("{} has no audio track, dropping audio", self.log_prefix);
+            return false;
+        };
+        match audio.write(&mut self.rtc, frame) {
+            Ok(written) => written,
+            Err(e) => {
+                warn!("{} failed to send audio: {:?}", self.log_prefix, e);
+                false
+            }
+        }
+    }
+
+    /// Drains the media received since the last call: whole video frames
+    /// in the codec's format, with the media ID of their track.
+    pub fn take_media(&mut self) -> Vec<MediaData> {
+        std::mem::take(&mut self.media)
+    }
+
+    /// Returns the kind of a track the peer sends.
+    pub fn media_kind(&self, mid: Mid) -> Option<MediaKind> {
+        self.tracks
+            .iter()
+            .find(|entry| entry.id.mid == mid)
+            .map(|entry| entry.id.kind)
+    }
+
+    /// Asks the peer for a keyframe on a video track, at most once per
+    /// [`KEYFRAME_REQUEST_INTERVAL`].
+    ///
+    /// # Arguments
+    ///
+    /// * `mid` - The media ID of the track
+    pub fn request_keyframe(&mut self, mid: Mid) {
+        let Some(entry) = self
+            .tracks
+            .iter_mut()
+            .find(|entry| entry.id.mid == mid && entry.id.kind == MediaKind::Video)
+        else {
+            return;
+        };
+        let now = Instant::now();
+        if entry
+            .last_keyframe_request
+            .is_some_and(|last| now - last < KEYFRAME_REQUEST_INTERVAL)
+        {
+            return;
+        }
+        entry.last_keyframe_request = Some(now);
+
+        let Some(mut writer) = self.rtc.writer(mid) else {
+            return;
+        };
+        if !writer.is_request_keyframe_possible(KeyframeRequestKind::Pli) {
+            debug!("{} did not negotiate keyframe requests", self.log_prefix);
+            return;
+        }
+        match writer.request_keyframe(None, KeyframeRequestKind::Pli) {
+            Ok(()) => debug!(
+                "{} asked for a keyframe on track {} of {}",
+                self.log_prefix, mid, entry.id.origin_name
+            ),
+            Err(e) => debug!("{} keyframe request failed: {:?}", self.log_prefix, e),
+        }
+    }
+
+    /// Returns the tracks the peer publishes, e.g. to forward them to a
+    /// client that joined later.
+    pub fn published_tracks(&self) -> Vec<Weak<TrackIn>> {
+        if self.access.is_observer() {
+            return vec![];
+        }
+        self.tracks
+            .iter()
+            .map(|entry| Arc::downgrade(&entry.id))
+            .collect()
+    }
+
+    /// Returns `true` once after the peer opened the forward channel, so the
+    /// tracks already published by other clients can be offered to it.
+    pub fn take_forward_opened(&mut self) -> bool {
+        std::mem::take(&mut self.forward_opened)
+    }
+
+    /// Returns `true` if the peer opened the forward channel and receives
+    /// the tracks of other clients.
+    pub fn receives_forwarded(&self) -> bool {
+        self.forward_cid.is_some()
+    }
+
+    /// Drains the messages whose writes failed since the last call, under
+    /// the [`WriteRetry::Fail`](crate::model::channel::WriteRetry::Fail) or
+    /// [`WriteRetry::RetryAfterPoll`](crate::model::channel::WriteRetry::RetryAfterPoll)
+    /// policy of their channel.
+    pub fn take_write_failures(&mut self) -> Vec<WriteFailure> {
+        std::mem::take(&mut self.write_failures)
+    }
+
+    /// Drains the events for other clients since the last call: tracks the
+    /// peer started publishing, and keyframe requests for their publishers.
+    pub fn take_propagated(&mut self) -> Vec<Propagated> {
+        std::mem::take(&mut self.propagated)
+    }
+
+    /// Queues a track of another client for forwarding to the peer; it is
+    /// offered on the next call to [`Client::negotiate_forwarded`].
+    ///
+    /// # Arguments
+    ///
+    /// * `track` - The published track
+    pub fn handle_track_open(&mut self, track: Weak<TrackIn>) {
+        if self
+            .tracks_out
+            .iter()
+            .any(|out| out.track_in.ptr_eq(&track))
+        {
+            return;
+        }
+        self.tracks_out.push(TrackOut {
+            track_in: track,
+            state: TrackOutState::ToOpen,
+        });
+    }
+
+    /// Offers the queued forwarded tracks to the peer on the forward channel.
+    ///
+    /// One offer is outstanding at a time; one that was rejected or not
+    /// answered within [`FORWARD_OFFER_TIMEOUT`] is made again.
+    pub fn negotiate_forwarded(&mut self) {
+        let Some(cid) = self.forward_cid else {
+            return;
+        };
+        // Tracks whose publisher left before they were offered
+        self.tracks_out
+            .retain(|out| out.state != TrackOutState::ToOpen || out.track_in().is_some());
+
+        if let Some(offered_at) = self.forward_offered_at {
+            if offered_at.elapsed() < FORWARD_OFFER_TIMEOUT {
+                return;
+            }
+            if self.forward_pending.take().is_some() {
+                warn!(
+                    "{} did not answer the offer of forwarded tracks",
+                    self.log_prefix
+                );
+                self.reset_forward_offer();
+            }
+            self.forward_offered_at = None;
+        }
+        if !self
+            .tracks_out
+            .iter()
+            .any(|out| out.state == TrackOutState::ToOpen)
+        {
+            return;
+        }
+
+        let mut change = self.rtc.sdp_api();
+        let mut offered = vec![];
+        for out in &mut self.tracks_out {
+            if out.state != TrackOutState::ToOpen {
+                continue;
+            }
+            let Some(source) = out.track_in() else {
+                continue;
+            };
+            let mid = change.add_media(source.kind, Direction::SendOnly, None, None, None);
+            out.state = TrackOutState::Negotiating(mid);
+            offered.push(ForwardedTrack {
+                mid: mid.to_string(),
+                origin: *source.origin,
+                kind: source.kind.to_string(),
+            });
+        }
+        let Some((offer, pending)) = change.apply() else {
+            return;
+        };
+
+        self.forward_offered_at = Some(Instant::now());
+        let count = offered.len();
+        let message = ForwardMessage::Offer {
+            sdp: offer.to_sdp_string(),
+            tracks: offered,
+        };
+        if self.write(cid, true, message.encode()) {
+            info!("{} offered {} forwarded tracks", self.log_prefix, count);
+            self.forward_pending = Some(pending);
+        } else {
+            self.reset_forward_offer();
+        }
+    }
+
+    /// Writes a frame of another client's track to the peer, if the track is
+    /// forwarded to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `origin` - The client publishing the track
+    /// * `data` - The frame, as received from the publisher
+    pub fn handle_media_data_out(&mut self, origin: ClientId, data: &MediaData) {
+        let Some(mid) = self
+            .tracks_out
+            .iter()
+            .filter(|out| matches!(out.state, TrackOutState::Open(_)))
+            .find(|out| {
+                out.track_in()
+                    .is_some_and(|source| source.origin == origin && source.mid == data.mid)
+            })
+            .and_then(TrackOut::mid)
+        else {
+            return;
+        };
+        let Some(writer) = self.rtc.writer(mid) else {
+            return;
+        };
+        let Some(pt) = writer.match_params(data.params) else {
+            debug!(
+                "{} accepted no {} payload type, dropping forwarded media",
+                self.log_prefix,
+                data.params.spec().codec
+            );
+            return;
+        };
+        if let Err(e) = writer.write(pt, data.network_time, data.time, data.data.clone()) {
+            warn!("{} failed to forward media: {:?}", self.log_prefix, e);
+        }
+    }
+
+    /// Handles a message received on the forward channel.
+    fn handle_forward_message(&mut self, message: ForwardMessage) {
+        match message {
+            ForwardMessage::Answer { sdp } => {
+                let Some(pending) = self.forward_pending.take() else {
+                    warn!("{} sent an unexpected forward answer", self.log_prefix);
+                    return;
+                };
+                self.forward_offered_at = None;
+                let accepted = SdpAnswer::from_sdp_string(&sdp)
+                    .map_err(|e| e.to_string())
+                    .and_then(|answer| {
+                        self.rtc
+                            .sdp_api()
+                            .accept_answer(pending, answer)
+                            .map_err(|e| e.to_string())
+                    });
+                if let Err(e) = accepted {
+                    // E.g. an ICE restart of the peer superseded the offer
+                    warn!(
+                        "{} forward answer not accepted, offering again: {}",
+                        self.log_prefix, e
+                    );
+                    self.reset_forward_offer();
+                    return;
+                }
+                for out in &mut self.tracks_out {
+                    let TrackOutState::Negotiating(mid) = out.state else {
+                        continue;
+                    };
+                    out.state = TrackOutState::Open(mid);
+                    let Some(source) = out.track_in() else {
+                        continue;
+                    };
+                    self.events.record(
+                        EventCategory::Channel,
+                        format!(
+                            "forwarding {} track {} of {} as {}",
+                            source.kind, source.mid, source.origin_name, mid
+                        ),
+                    );
+                    // The receiver cannot decode video before a keyframe
+                    if source.kind == MediaKind::Video {
+                        let request = KeyframeRequest {
+                            mid,
+                            rid: None,
+                            kind: KeyframeRequestKind::Pli,
+                        };
+                        self.propagated.push(Propagated::KeyframeRequest(
+                            self.id,
+                            request,
+                            source.origin,
+                            source.mid,
+                        ));
+                    }
+                }
+            }
+            ForwardMessage::Rejected { reason } => {
+                info!(
+                    "{} rejected the offer of forwarded tracks: {}",
+                    self.log_prefix, reason
+                );
+                // Offered again once the timeout expired
+                self.forward_pending = None;
+                self.reset_forward_offer();
+            }
+            ForwardMessage::Offer { .. } => {
+                warn!("{} sent an offer on the forward channel", self.log_prefix);
+            }
+        }
+    }
+
+    /// Returns `true` if `mid` is that of a track forwarded to the peer.
+    fn is_forwarded_track(&self, mid: Mid) -> bool {
+        self.tracks_out.iter().any(|out| out.mid() == Some(mid))
+    }
+
+    /// Returns the tracks of an offer that did not go through to the state
+    /// in which they are offered again.
+    fn reset_forward_offer(&mut self) {
+        for out in &mut self.tracks_out {
+            if let TrackOutState::Negotiating(_) = out.state {
+                out.state = TrackOutState::ToOpen;
+            }
+        }
+    }
+
+    /// Returns `true` if the channel carries messages whose encoding depends
+    /// on the protocol version.
+    fn is_versioned(&self, id: ChannelId) -> bool {
+        [
+            self.mission_cid,
+            self.telemetry_cid,
+            self.coordination_cid,
+            self.session_cid,
+        ]
+        .contains(&Some(id))
+    }
+
+    /// Returns the label of an open channel.
+    fn label_of(&self, id: ChannelId) -> Option<&str> {
+        self.channels
+            .iter()
+            .find(|(_, cid)| **cid == id)
+            .map(|(label, _)| label.as_str())
+    }
+
+    /// Relays a coordination message to this client.
+    ///
+    /// Does nothing if the client has not opened a coordination channel or
+    /// speaks an incompatible protocol.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The encoded coordination message
+    pub fn send_coordination(&mut self, message: &[u8]) {
+        if self.protocol.is_fallback() {
+            return;
+        }
+        if let Some(cid) = self.coordination_cid {
+            self.write(cid, true, message.to_vec());
+        }
+    }
+
+    /// Sends a control message to this client.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to send
+    pub fn send_control(&mut self, message: &ControlMessage) {
+        if let Some(cid) = self.control_cid {
+            self.write(cid, true, message.encode());
+        }
+    }
+
+    /// Asks this client's peer to restart ICE, e.g. to move a rover stuck
+    /// on a poor candidate pair.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the peer did not advertise `remote_restart`
+    pub fn request_ice_restart(&mut self) -> bool {
+        if !self.features.contains(Feature::RemoteRestart) {
+            return false;
+        }
+        let Some(cid) = self.control_cid else {
+            return false;
+        };
+        self.write(cid, true, ControlMessage::RestartIce.encode())
+    }
+
+    /// Sends a session message to this client.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to send
+    ///
+    /// # Returns
+    ///
+    /// `false` if the client has not opened a session channel or it closed
+    pub fn send_session(&mut self, message: &SessionMessage) -> bool {
+        if self.protocol.is_fallback() {
+            return false;
+        }
+        let Some(cid) = self.session_cid else {
+            return false;
+        };
+        self.write(cid, true, message.encode())
+    }
+
+    /// Handles a message received on the mission channel.
+    fn handle_mission_data(&mut self, data: &[u8]) {
+        let Some(message) = MissionMessage::decode(data) else {
+            warn!("{} sent an undecodable mission message", self.log_prefix);
+            return;
+        };
+
+        match &message {
+            MissionMessage::Ack { version } => {
+                info!("{} activated mission plan v{}", self.log_prefix, version);
+            }
+            MissionMessage::Reject { version, reason } => {
+                warn!(
+                    "{} rejected mission plan v{}: {}",
+                    self.log_prefix, version, reason
+                );
+            }
+            _ => {}
+        }
+
+        let replies = self.mission.handle(message);
+        self.write_mission(replies);
+    }
+
+    /// Handles a message received on the logs channel.
+    fn handle_logs_data(&mut self, data: &[u8]) {
+        let Some(message) = LogMessage::decode(data) else {
+            warn!("{} sent an undecodable logs message", self.log_prefix);
+            return;
+        };
+        let Some((id, reply)) = self.logs.handle(message) else {
+            return;
+        };
+
+        let result = match reply {
+            LogReply::Refused(reason) => Err(reason),
+            LogReply::Logs { compressed, data } => {
+                let data = if compressed {
+                    logbuf::decompress(&data)
+                } else {
+                    Some(data)
+                };
+                data.map(|data| String::from_utf8_lossy(&data).into_owned())
+                    .ok_or_else(|| "undecodable compressed logs".to_string())
+            }
+        };
+        if let Err(reason) = &result {
+            warn!(
+                "{} did not serve log request {}: {}",
+                self.log_prefix, id, reason
+            );
+        }
+        self.log_replies.push((id, result));
+    }
+
+    /// Writes mission messages to the mission channel.
+    fn write_mission(&mut self, messages: Vec<MissionMessage>) -> bool {
+        if self.protocol.is_fallback() {
+            return false;
+        }
+        let Some(cid) = self.mission_cid else {
+            return false;
+        };
+        messages
+            .into_iter()
+            .all(|message| self.write(cid, true, message.encode()))
+    }
+
+    /// Writes a message to a channel, or queues it behind the channel's
+    /// earlier messages while the channel is congested or cannot take data.
+    ///
+    /// # Arguments
+    ///
+    /// * `cid` - The channel to write to
+    /// * `binary` - Whether the message is binary rather than text
+    /// * `data` - The message
+    ///
+    /// # Returns
+    ///
+    /// `false` if the channel is closed, so the message was discarded
+    fn write(&mut self, cid: ChannelId, binary: bool, data: Vec<u8>) -> bool {
+        if self.rtc.channel(cid).is_none() {
+            return false;
+        }
+        self.record(recording::Direction::Outbound, cid, &data);
+        let options = self.options_of(cid);
+        if !self.outbound.contains_key(&cid) {
+            let label = self.label_of(cid).unwrap_or_default();
+            let queue = self.send_queue.queue(label, options.qos_class(label));
+            self.outbound.insert(cid, queue);
+        }
+        let class = self.outbound[&cid].class();
+        // Messages already waiting go first, those of higher classes too
+        let preempted = self
+            .outbound
+            .iter()
+            .any(|(id, queue)| !queue.is_empty() && (*id == cid || queue.class() < class));
+        let ready = !preempted && self.has_room(cid, class);
+        let queue = self
+            .outbound
+            .get_mut(&cid)
+            .expect("queue was just inserted");
+        let mut channel = self.rtc.channel(cid).expect("channel was just found");
+
+        let mut attempts = 0;
+        if ready {
+            match channel.write(binary, &data) {
+                Ok(_) => return true,
+                Err(e) => {
+                    attempts = 1;
+                    let outcome = options.after_write_error(attempts);
+                    if outcome != WriteOutcome::Retry {
+                        self.write_failed(cid, data, &e, outcome);
+                        return true;
+                    }
+                    self.connection.write_retried();
+                    debug!(
+                        "{} queues a message after a failed write: {:?}",
+                        self.log_prefix, e
+                    );
+                }
+            }
+        }
+
+        let overflowed = queue.overflowed();
+        let discarded = queue.push(OutboundMessage {
+            binary,
+            data,
+            attempts,
+        });
+        if queue.overflowed() && !overflowed {
+            warn!(
+                "{} send queue of channel {:?} is full, dropping the oldest messages",
+                self.log_prefix, cid
+            );
+        }
+        self.counters.messages_dropped += discarded as u64;
+        self.update_queued();
+        true
+    }
+
+    /// Writes queued messages in order while their channels have room, by
+    /// QoS class: a class waits while a higher one still holds messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `force` - Write regardless of the buffered amount, e.g. before closing
+    fn flush_outbound(&mut self, force: bool) {
+        let mut queues: Vec<_> = self
+            .outbound
+            .iter()
+            .filter(|(_, queue)| !queue.is_empty())
+            .map(|(cid, queue)| (queue.class(), *cid))
+            .collect();
+        queues.sort_by_key(|(class, _)| *class);
+        let mut blocked: Option<QosClass> = None;
+        let mut failed = vec![];
+        for (class, cid) in queues {
+            if !force && blocked.is_some_and(|blocked| blocked < class) {
+                break;
+            }
+            let options = self.own_channels.get(&cid).copied().unwrap_or_default();
+            if self.rtc.channel(cid).is_none() {
+                continue;
+            }
+            while force || self.has_room(cid, class) {
+                let Some(queue) = self.outbound.get_mut(&cid) else {
+                    break;
+                };
+                let Some(message) = queue.front_mut() else {
+                    break;
+                };
+                let mut channel = self.rtc.channel(cid).expect("channel was just found");
+                if let Err(e) = channel.write(message.binary, &message.data) {
+                    message.attempts += 1;
+                    let outcome = options.after_write_error(message.attempts);
+                    if outcome == WriteOutcome::Retry {
+                        debug!("{} keeps messages queued: {:?}", self.log_prefix, e);
+                        self.connection.write_retried();
+                        break;
+                    }
+                    let message = queue.pop_front().expect("front message");
+                    failed.push((cid, message.data, e, outcome));
+                    continue;
+                }
+                queue.pop_front();
+            }
+            if self
+                .outbound
+                .get(&cid)
+                .is_some_and(|queue| !queue.is_empty())
+            {
+                blocked.get_or_insert(class);
+            }
+        }
+        for (cid, data, e, outcome) in failed {
+            self.write_failed(cid, data, &e, outcome);
+        }
+        self.update_queued();
+    }
+
+    /// Returns `true` if a message of a channel may be handed to SCTP now:
+    /// the channel buffers less than the limit and, below the control class,
+    /// so do all the channels written to together.
+    fn has_room(&mut self, cid: ChannelId, class: QosClass) -> bool {
+        let limit = self.send_queue.buffered_limit;
+        let Some(buffered) = self.rtc.channel(cid).map(|mut c| c.buffered_amount()) else {
+            return false;
+        };
+        if buffered >= limit {
+            return false;
+        }
+        if class == QosClass::Control {
+            return true;
+        }
+        let cids: Vec<_> = self.outbound.keys().copied().collect();
+        let total: usize = cids
+            .into_iter()
+            .filter_map(|cid| self.rtc.channel(cid).map(|mut c| c.buffered_amount()))
+            .sum();
+        total < limit
+    }
+
+    /// Returns the delivery options of a channel; those of the channels the
+    /// peer opened are not known to the server, which uses the defaults.
+    fn options_of(&self, cid: ChannelId) -> ChannelOptions {
+        self.own_channels.get(&cid).copied().unwrap_or_default()
+    }
+
+    /// Gives up on a message whose write failed, reporting or dropping it.
+    fn write_failed(
+        &mut self,
+        cid: ChannelId,
+        data: Vec<u8>,
+        error: &RtcError,
+        outcome: WriteOutcome,
+    ) {
+        let channel = self.label_of(cid).unwrap_or("unknown").to_string();
+        match outcome {
+            WriteOutcome::Retry => {}
+            WriteOutcome::Drop => {
+                warn!(
+                    "{} dropped a message on '{}' after a failed write: {:?}",
+                    self.log_prefix, channel, error
+                );
+                self.counters.messages_dropped += 1;
+            }
+            WriteOutcome::Fail => {
+                warn!(
+                    "{} failed to write a message on '{}': {:?}",
+                    self.log_prefix, channel, error
+                );
+                self.events.record(
+                    EventCategory::Error,
+                    format!("write on '{}' failed: {:?}", channel, error),
+                );
+                self.write_failures.push(WriteFailure {
+                    channel,
+                    data,
+                    error: format!("{:?}", error),
+                });
+            }
+        }
+    }
+
+    /// Updates the count of queued messages in the traffic counters.
+    fn update_queued(&mut self) {
+        self.counters.messages_queued = self.outbound.values().map(OutboundQueue::len).sum();
     }
 }
@@ -0,0 +1,478 @@
+//! Protocol version negotiation
+//!
+//! Operators and rovers are not always updated together, and a peer speaking
+//! an older encoding of the mission, coordination or session messages would
+//! misread them silently. Both sides therefore open the "control" data channel
+//! and send a [`ControlMessage::Hello`] with the range of protocol versions
+//! they speak as its first message.
+//!
+//! If the ranges overlap, the highest common version is used. If they do not,
+//! the session is closed with an error naming both ranges, unless fallback is
+//! allowed: then only plain application data is exchanged and the versioned
+//! features (missions, telemetry, coordination, session refresh) are disabled.
+//!
+//! After the hello, each side advertises the optional [`Feature`]s it can
+//! decode in a [`ControlMessage::Capabilities`]. A sender only enables the
+//! features both sides advertised, so it never batches, fragments or pings a
+//! peer that cannot handle it.
+
+use std::fmt;
+
+use bincode::config::{self, Configuration};
+use serde::{Deserialize, Serialize};
+
+use crate::model::heartbeat::HeartbeatConfig;
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying control messages.
+pub const CONTROL_CHANNEL: &str = "control";
+
+/// The protocol version this build speaks.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// The oldest protocol version this build still speaks.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Protocol negotiation settings, the `[protocol]` configuration section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolConfig {
+    /// Keep sessions with incompatible peers, exchanging plain application
+    /// data only, instead of closing them
+    pub allow_fallback: bool,
+    /// Optional features this side can decode; none by default
+    pub features: Vec<Feature>,
+    /// Accept bare legacy payloads, and send them to peers that have not
+    /// completed the handshake, for fielded rovers predating envelopes
+    pub legacy_payloads: bool,
+    /// Ping interval and loss thresholds, used once both sides advertised
+    /// [`Feature::Heartbeat`]
+    pub heartbeat: HeartbeatConfig,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            allow_fallback: false,
+            features: vec![],
+            legacy_payloads: true,
+            heartbeat: HeartbeatConfig::default(),
+        }
+    }
+}
+
+/// Optional features, advertised during the handshake.
+///
+/// The discriminant of a feature is its bit in a [`FeatureSet`]; bits 0 to 3
+/// are reserved for features not implemented yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Small messages coalesced into batches, see [`crate::model::batch`]
+    Batching = 4,
+    /// Publishing rates requested by the receivers, see [`crate::model::rate`]
+    RateControl,
+    /// Pings measuring round-trip time, jitter and loss, see
+    /// [`crate::model::heartbeat`]
+    Heartbeat,
+    /// Per-session application keys, see `crate::model::keys`
+    KeyAgreement,
+    /// Messages split to fit the path MTU, see [`crate::model::fragment`]
+    Fragmentation,
+    /// Copies of critical messages sent over several links, see
+    /// [`crate::model::duplicate`]
+    Deduplication,
+    /// ICE restarts the server asks the peer for
+    RemoteRestart,
+}
+
+impl Feature {
+    /// All features, in bit order.
+    pub const ALL: [Feature; 7] = [
+        Feature::Batching,
+        Feature::RateControl,
+        Feature::Heartbeat,
+        Feature::KeyAgreement,
+        Feature::Fragmentation,
+        Feature::Deduplication,
+        Feature::RemoteRestart,
+    ];
+
+    /// The name used in logs and the stats API.
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Batching => "batching",
+            Feature::RateControl => "rate_control",
+            Feature::Heartbeat => "heartbeat",
+            Feature::KeyAgreement => "key_agreement",
+            Feature::Fragmentation => "fragmentation",
+            Feature::Deduplication => "deduplication",
+            Feature::RemoteRestart => "remote_restart",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// A set of features, sent as a bit mask.
+///
+/// Bits of features unknown to this build are kept when decoding, and drop
+/// out when intersecting with the local set.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+pub struct FeatureSet(u32);
+
+impl FeatureSet {
+    /// Returns the set of the given features.
+    pub fn of(features: &[Feature]) -> Self {
+        FeatureSet(features.iter().fold(0, |bits, f| bits | f.bit()))
+    }
+
+    /// Returns `true` if the set contains the feature.
+    pub fn contains(self, feature: Feature) -> bool {
+        self.0 & feature.bit() != 0
+    }
+
+    /// Returns the features in both sets.
+    pub fn intersection(self, other: FeatureSet) -> Self {
+        FeatureSet(self.0 & other.0)
+    }
+
+    /// Returns the names of the features known to this build.
+    pub fn names(self) -> Vec<&'static str> {
+        Feature::ALL
+            .into_iter()
+            .filter(|f| self.contains(*f))
+            .map(Feature::name)
+            .collect()
+    }
+}
+
+impl fmt::Display for FeatureSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.names();
+        if names.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&names.join(", "))
+        }
+    }
+}
+
+/// Messages exchanged on the control channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum ControlMessage {
+    /// The first message on the channel, sent by both sides
+    Hello {
+        /// The newest protocol version the sender speaks
+        version: u16,
+        /// The oldest protocol version the sender speaks
+        min_version: u16,
+        /// The sender's software version, for error messages
+        software: String,
+    },
+    /// The sender found the versions incompatible and closes the session
+    Incompatible { reason: String },
+    /// The optional features the sender can decode, sent after the hello
+    Capabilities { features: FeatureSet },
+    /// The sender is shutting down and closes the session
+    Goodbye { reason: String },
+    /// The highest rate the sender's receivers want for a topic
+    RequestRate {
+        /// The topic, i.e. the channel label
+        topic: String,
+        /// The rate in millihertz; `0` withdraws the request
+        millihertz: u32,
+    },
+    /// A heartbeat, answered with a pong carrying the same sequence number
+    Ping { sequence: u64 },
+    /// The answer to a ping
+    Pong { sequence: u64 },
+    /// The sender's ephemeral X25519 public key, once both sides advertised
+    /// [`Feature::KeyAgreement`]
+    KeyShare { public_key: Vec<u8> },
+    /// The server asks the peer to restart ICE, once both sides advertised
+    /// [`Feature::RemoteRestart`]
+    RestartIce,
+}
+
+impl ControlMessage {
+    /// Returns the hello message of this build.
+    pub fn hello() -> Self {
+        ControlMessage::Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            software: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Returns the capabilities message for the configured features.
+    pub fn capabilities(config: &ProtocolConfig) -> Self {
+        ControlMessage::Capabilities {
+            features: FeatureSet::of(&config.features),
+        }
+    }
+
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(ControlMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+}
+
+/// The outcome of the version negotiation of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Negotiation {
+    /// No hello was received yet
+    #[default]
+    Pending,
+    /// Both sides speak the given version
+    Agreed { version: u16 },
+    /// The versions are incompatible; only plain application data is exchanged
+    Fallback,
+}
+
+impl Negotiation {
+    /// Returns `true` if the versioned features must not be used.
+    pub fn is_fallback(self) -> bool {
+        self == Negotiation::Fallback
+    }
+}
+
+/// The version ranges of two incompatible peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMismatch {
+    /// The remote's newest version
+    pub remote_version: u16,
+    /// The remote's oldest version
+    pub remote_min_version: u16,
+    /// The remote's software version
+    pub remote_software: String,
+}
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "incompatible protocol versions: this side (rover-rtc {}) speaks v{}..=v{}, \
+             the remote (rover-rtc {}) speaks v{}..=v{}; update the {} side",
+            env!("CARGO_PKG_VERSION"),
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION,
+            self.remote_software,
+            self.remote_min_version,
+            self.remote_version,
+            if self.remote_version < MIN_PROTOCOL_VERSION {
+                "remote"
+            } else {
+                "local"
+            }
+        )
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+/// Negotiates the protocol version from the remote's hello.
+///
+/// # Arguments
+///
+/// * `hello` - The remote's hello message
+/// * `config` - The negotiation settings
+///
+/// # Returns
+///
+/// The negotiated version, the fallback if the versions are incompatible and
+/// fallback is allowed, or the mismatch otherwise
+pub fn negotiate(
+    hello: &ControlMessage,
+    config: &ProtocolConfig,
+) -> Result<Negotiation, VersionMismatch> {
+    let ControlMessage::Hello {
+        version,
+        min_version,
+        software,
+    } = hello
+    else {
+        return Ok(Negotiation::Pending);
+    };
+
+    let common = PROTOCOL_VERSION.min(*version);
+    if common >= MIN_PROTOCOL_VERSION.max(*min_version) {
+        return Ok(Negotiation::Agreed { version: common });
+    }
+    if config.allow_fallback {
+        return Ok(Negotiation::Fallback);
+    }
+    Err(VersionMismatch {
+        remote_version: *version,
+        remote_min_version: *min_version,
+        remote_software: software.clone(),
+    })
+}
+
+/// Returns the features both sides can decode.
+///
+/// # Arguments
+///
+/// * `remote` - The features the remote advertised
+/// * `negotiation` - The outcome of the version negotiation
+/// * `config` - The local negotiation settings
+///
+/// # Returns
+///
+/// The common features; none in fallback mode
+pub fn common_features(
+    remote: FeatureSet,
+    negotiation: Negotiation,
+    config: &ProtocolConfig,
+) -> FeatureSet {
+    if negotiation.is_fallback() {
+        return FeatureSet::default();
+    }
+    FeatureSet::of(&config.features).intersection(remote)
+}
+
+impl WireSchema for ControlMessage {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "ControlMessage",
+            "Messages exchanged on the control channel",
+            vec![
+                (
+                    "Hello",
+                    "The first message on the channel, sent by both sides",
+                    vec![
+                        field(
+                            "version",
+                            WireType::U16,
+                            "The newest protocol version the sender speaks",
+                        ),
+                        field(
+                            "min_version",
+                            WireType::U16,
+                            "The oldest protocol version the sender speaks",
+                        ),
+                        field(
+                            "software",
+                            WireType::String,
+                            "The sender's software version",
+                        ),
+                    ],
+                ),
+                (
+                    "Incompatible",
+                    "The sender found the versions incompatible and closes the session",
+                    vec![field(
+                        "reason",
+                        WireType::String,
+                        "Human-readable explanation",
+                    )],
+                ),
+                (
+                    "Capabilities",
+                    "The optional features the sender can decode, sent after the hello",
+                    vec![field(
+                        "features",
+                        WireType::Ref { name: "FeatureSet" },
+                        "Feature bit mask",
+                    )],
+                ),
+                (
+                    "Goodbye",
+                    "The sender is shutting down and closes the session",
+                    vec![field(
+                        "reason",
+                        WireType::String,
+                        "Human-readable explanation",
+                    )],
+                ),
+                (
+                    "RequestRate",
+                    "The highest rate the sender's receivers want for a topic",
+                    vec![
+                        field(
+                            "topic",
+                            WireType::String,
+                            "The topic, i.e. the channel label",
+                        ),
+                        field(
+                            "millihertz",
+                            WireType::U32,
+                            "The rate in millihertz; 0 withdraws the request",
+                        ),
+                    ],
+                ),
+                (
+                    "Ping",
+                    "A heartbeat, answered with a pong carrying the same sequence number",
+                    vec![field(
+                        "sequence",
+                        WireType::U64,
+                        "Number of the ping, counting from 0 each session",
+                    )],
+                ),
+                (
+                    "Pong",
+                    "The answer to a ping",
+                    vec![field(
+                        "sequence",
+                        WireType::U64,
+                        "The sequence number of the ping",
+                    )],
+                ),
+                (
+                    "KeyShare",
+                    "The sender's ephemeral X25519 public key, once both sides advertised key_agreement",
+                    vec![field(
+                        "public_key",
+                        WireType::Bytes,
+                        "The 32-byte public key",
+                    )],
+                ),
+                (
+                    "RestartIce",
+                    "The server asks the peer to restart ICE, once both sides advertised remote_restart",
+                    vec![],
+                ),
+            ],
+        )
+    }
+}
+
+impl WireSchema for FeatureSet {
+    fn wire_schema() -> TypeDef {
+        TypeDef::structure(
+            "FeatureSet",
+            "Bit mask of optional features: batching = 16, rate_control = 32, heartbeat = 64, \
+             key_agreement = 128, fragmentation = 256, deduplication = 512, \
+             remote_restart = 1024; 1, 2, 4 and 8 are reserved, unknown bits are ignored",
+            vec![field("bits", WireType::U32, "The feature bits")],
+        )
+    }
+}
@@ -0,0 +1,301 @@
+//! Multi-rover coordination protocol
+//!
+//! This module implements a small coordination sub-protocol for convoys. Every
+//! rover broadcasts periodic heartbeats on the "coordination" data channel, which
+//! the server relays to all other rovers of its room. From the heartbeats each rover derives
+//! the same membership view and elects the same leader: the live member with the
+//! highest priority, ties broken by the highest node ID.
+//!
+//! Autonomy stacks use [`Coordinator`] to query the current leader and members
+//! and to share their own formation state through the heartbeat payload.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use bincode::config::{self, Configuration};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying coordination messages.
+pub const COORDINATION_CHANNEL: &str = "coordination";
+
+/// Default interval between heartbeats.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default time without heartbeats after which a member is considered lost.
+pub const MEMBER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most remote members tracked; heartbeats of further rovers are ignored
+/// until a member leaves or times out.
+pub const MAX_MEMBERS: usize = 64;
+
+/// Messages exchanged on the coordination channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum CoordinationMessage {
+    /// Periodic liveness announcement from a rover.
+    Heartbeat {
+        /// ID of the sending rover
+        node_id: u64,
+        /// Election priority of the sending rover
+        priority: u32,
+        /// The leader as seen by the sending rover
+        leader: Option<u64>,
+        /// Application-defined state shared with the convoy
+        state: Vec<u8>,
+        /// Send time, in nanoseconds since the Unix epoch
+        sent_at: i64,
+    },
+    /// Graceful departure of a rover from the convoy.
+    Leave { node_id: u64 },
+}
+
+impl CoordinationMessage {
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(CoordinationMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+}
+
+/// A convoy member known from its heartbeats.
+#[derive(Debug, Clone)]
+pub struct Member {
+    /// Election priority of the member
+    pub priority: u32,
+    /// The most recent state shared by the member
+    pub state: Vec<u8>,
+    /// When the last heartbeat from the member was received
+    pub last_seen: Instant,
+}
+
+/// Membership and leadership changes reported by the [`Coordinator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoordinationEvent {
+    /// A new member sent its first heartbeat
+    MemberJoined(u64),
+    /// A member left or stopped sending heartbeats
+    MemberLost(u64),
+    /// The elected leader changed
+    LeaderChanged(Option<u64>),
+}
+
+/// Local view of the convoy, driving heartbeats and leader election.
+#[derive(Debug)]
+pub struct Coordinator {
+    node_id: u64,
+    priority: u32,
+    state: Vec<u8>,
+    members: HashMap<u64, Member>,
+    leader: Option<u64>,
+    timeout: Duration,
+}
+
+impl Coordinator {
+    /// Creates a coordinator for the local rover.
+    ///
+    /// # Arguments
+    ///
+    /// * `node_id` - Unique ID of the local rover
+    /// * `priority` - Election priority; higher values are preferred as leader
+    pub fn new(node_id: u64, priority: u32) -> Self {
+        Self {
+            node_id,
+            priority,
+            state: vec![],
+            members: HashMap::new(),
+            leader: Some(node_id),
+            timeout: MEMBER_TIMEOUT,
+        }
+    }
+
+    /// Returns the ID of the local rover.
+    pub fn node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    /// Returns the currently elected leader.
+    pub fn leader(&self) -> Option<u64> {
+        self.leader
+    }
+
+    /// Returns `true` if the local rover is the elected leader.
+    pub fn is_leader(&self) -> bool {
+        self.leader == Some(self.node_id)
+    }
+
+    /// Returns the remote members currently considered alive, at most
+    /// [`MAX_MEMBERS`].
+    pub fn members(&self) -> &HashMap<u64, Member> {
+        &self.members
+    }
+
+    /// Returns the state shared with the convoy in the heartbeats.
+    pub fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    /// Sets the state shared with the convoy in subsequent heartbeats.
+    pub fn set_state(&mut self, state: Vec<u8>) {
+        self.state = state;
+    }
+
+    /// Builds the heartbeat to broadcast to the convoy.
+    pub fn heartbeat(&self) -> CoordinationMessage {
+        CoordinationMessage::Heartbeat {
+            node_id: self.node_id,
+            priority: self.priority,
+            leader: self.leader,
+            state: self.state.clone(),
+            sent_at: Utc::now().timestamp_nanos_opt().unwrap_or(0),
+        }
+    }
+
+    /// Builds the message announcing the local rover's departure.
+    pub fn leave(&self) -> CoordinationMessage {
+        CoordinationMessage::Leave {
+            node_id: self.node_id,
+        }
+    }
+
+    /// Handles a message received from the convoy.
+    ///
+    /// # Returns
+    ///
+    /// The membership and leadership changes caused by the message
+    pub fn handle(&mut self, message: CoordinationMessage, now: Instant) -> Vec<CoordinationEvent> {
+        let mut events = vec![];
+
+        match message {
+            CoordinationMessage::Heartbeat {
+                node_id,
+                priority,
+                state,
+                ..
+            } => {
+                if node_id == self.node_id
+                    || (self.members.len() >= MAX_MEMBERS && !self.members.contains_key(&node_id))
+                {
+                    return events;
+                }
+                let member = Member {
+                    priority,
+                    state,
+                    last_seen: now,
+                };
+                if self.members.insert(node_id, member).is_none() {
+                    events.push(CoordinationEvent::MemberJoined(node_id));
+                }
+            }
+            CoordinationMessage::Leave { node_id } => {
+                if self.members.remove(&node_id).is_some() {
+                    events.push(CoordinationEvent::MemberLost(node_id));
+                }
+            }
+        }
+
+        events.extend(self.elect());
+        events
+    }
+
+    /// Expires members whose heartbeats timed out and re-runs the election.
+    ///
+    /// # Returns
+    ///
+    /// The membership and leadership changes caused by the expiry
+    pub fn poll(&mut self, now: Instant) -> Vec<CoordinationEvent> {
+        let timeout = self.timeout;
+        let mut events: Vec<CoordinationEvent> = self
+            .members
+            .iter()
+            .filter(|(_, m)| now.duration_since(m.last_seen) > timeout)
+            .map(|(id, _)| CoordinationEvent::MemberLost(*id))
+            .collect();
+
+        for event in &events {
+            if let CoordinationEvent::MemberLost(id) = event {
+                self.members.remove(id);
+            }
+        }
+
+        events.extend(self.elect());
+        events
+    }
+
+    /// Elects the live member with the highest `(priority, node_id)`.
+    fn elect(&mut self) -> Option<CoordinationEvent> {
+        let leader = self
+            .members
+            .iter()
+            .map(|(id, m)| (m.priority, *id))
+            .chain(std::iter::once((self.priority, self.node_id)))
+            .max()
+            .map(|(_, id)| id);
+
+        if leader != self.leader {
+            self.leader = leader;
+            Some(CoordinationEvent::LeaderChanged(leader))
+        } else {
+            None
+        }
+    }
+}
+
+impl WireSchema for CoordinationMessage {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "CoordinationMessage",
+            "Messages exchanged on the coordination channel",
+            vec![
+                (
+                    "Heartbeat",
+                    "Periodic liveness announcement from a rover",
+                    vec![
+                        field("node_id", WireType::U64, "ID of the sending rover"),
+                        field(
+                            "priority",
+                            WireType::U32,
+                            "Election priority of the sending rover",
+                        ),
+                        field(
+                            "leader",
+                            WireType::option(WireType::U64),
+                            "The leader as seen by the sending rover",
+                        ),
+                        field(
+                            "state",
+                            WireType::Bytes,
+                            "Application-defined state shared with the convoy",
+                        ),
+                        field(
+                            "sent_at",
+                            WireType::I64,
+                            "Send time, in nanoseconds since the Unix epoch",
+                        ),
+                    ],
+                ),
+                (
+                    "Leave",
+                    "Graceful departure of a rover from the convoy",
+                    vec![field("node_id", WireType::U64, "ID of the departing rover")],
+                ),
+            ],
+        )
+    }
+}
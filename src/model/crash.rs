@@ -0,0 +1,174 @@
+//! Crash report upload protocol
+//!
+//! A peer that crashed keeps its reports on disk (see [`crate::crash`]) and
+//! uploads them on the reliable "crash" data channel of the next session that
+//! connects, so field crashes reach the operators without anyone fetching
+//! files from the rover. Each report is sent as deflate-compressed JSON in
+//! [`CrashMessage::Chunk`]s of at most [`CHUNK_SIZE`] bytes; the server
+//! answers with a [`CrashMessage::Stored`] once it wrote the report, after
+//! which the peer deletes its copy. A report whose acknowledgement never
+//! arrives is sent again by the next session.
+
+use std::time::Instant;
+
+use bincode::config::{self, Configuration};
+use serde::{Deserialize, Serialize};
+
+use crate::model::chunked::{ChunkAssembler, ChunkError};
+use crate::model::fragment::MAX_MESSAGE_SIZE;
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying crash reports.
+pub const CRASH_CHANNEL: &str = "crash";
+
+/// Maximum number of report bytes per chunk, keeping each SCTP message small.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Messages exchanged on the crash channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum CrashMessage {
+    /// A slice of a compressed report, identified by its chunk index.
+    Chunk {
+        name: String,
+        index: u32,
+        total: u32,
+        data: Vec<u8>,
+    },
+    /// The server stored the report, which the peer may delete.
+    Stored { name: String },
+}
+
+impl CrashMessage {
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(CrashMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+
+    /// Splits a compressed report into chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The file name of the report
+    /// * `data` - The deflate-compressed report
+    ///
+    /// # Returns
+    ///
+    /// At least one chunk, even for an empty report
+    pub fn chunks(name: &str, data: &[u8]) -> Vec<CrashMessage> {
+        let slices: Vec<&[u8]> = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(CHUNK_SIZE).collect()
+        };
+        let total = slices.len() as u32;
+        slices
+            .into_iter()
+            .enumerate()
+            .map(|(index, slice)| CrashMessage::Chunk {
+                name: name.to_string(),
+                index: index as u32,
+                total,
+                data: slice.to_vec(),
+            })
+            .collect()
+    }
+}
+
+/// Reassembles the chunked reports sent by a peer.
+///
+/// Reports above [`MAX_MESSAGE_SIZE`] are dropped, see
+/// [`crate::model::chunked`] for the other limits.
+#[derive(Debug)]
+pub struct CrashAssembler {
+    partial: ChunkAssembler<String>,
+}
+
+impl Default for CrashAssembler {
+    fn default() -> Self {
+        Self {
+            partial: ChunkAssembler::new(MAX_MESSAGE_SIZE),
+        }
+    }
+}
+
+impl CrashAssembler {
+    /// Creates an assembler with no report in progress.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles a message received on the crash channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message received from the peer
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some((String, Vec<u8>)))` - The name of the report and its
+    ///   compressed bytes, once the last chunk arrived
+    /// * `Ok(None)` - If chunks of the report are missing
+    /// * `Err(ChunkError)` - If the report cannot be reassembled
+    pub fn handle(
+        &mut self,
+        message: CrashMessage,
+    ) -> Result<Option<(String, Vec<u8>)>, ChunkError> {
+        let CrashMessage::Chunk {
+            name,
+            index,
+            total,
+            data,
+        } = message
+        else {
+            return Ok(None);
+        };
+        let report = self
+            .partial
+            .push(name.clone(), index, total, (), data, Instant::now())?;
+        Ok(report.map(|((), data)| (name, data)))
+    }
+}
+
+impl WireSchema for CrashMessage {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "CrashMessage",
+            "Messages exchanged on the crash channel",
+            vec![
+                (
+                    "Chunk",
+                    "A slice of a compressed report, identified by its chunk index",
+                    vec![
+                        field("name", WireType::String, "File name of the report"),
+                        field("index", WireType::U32, "Zero-based chunk index"),
+                        field("total", WireType::U32, "Number of chunks of the report"),
+                        field(
+                            "data",
+                            WireType::Bytes,
+                            "Raw deflate of the JSON report, once concatenated",
+                        ),
+                    ],
+                ),
+                (
+                    "Stored",
+                    "The server stored the report, which the peer may delete",
+                    vec![field("name", WireType::String, "File name of the report")],
+                ),
+            ],
+        )
+    }
+}
@@ -0,0 +1,307 @@
+//! Diagnostics for UDP packets no client accepts
+//!
+//! This module classifies datagrams that fail demultiplexing (RFC 7983 first-byte
+//! ranges, plus the STUN USERNAME attribute), keeps counters per source address,
+//! and determines which clients, if any, the packet actually implicates.
+
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info};
+
+use crate::model::client::{Client, ClientId};
+
+/// Environment variable selecting the diagnostics level.
+pub const DEMUX_DIAGNOSTICS_ENV: &str = "ROVER_DEMUX_DIAGNOSTICS";
+
+/// STUN magic cookie (RFC 5389).
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// STUN USERNAME attribute type.
+const STUN_ATTR_USERNAME: u16 = 0x0006;
+
+/// DTLS record content types: change_cipher_spec, alert, handshake,
+/// application_data, heartbeat and tls12_cid.
+const DTLS_CONTENT_TYPES: [u8; 6] = [20, 21, 22, 23, 24, 25];
+
+/// Length of a DTLS record header.
+const DTLS_HEADER_LEN: usize = 13;
+
+/// Length of a fixed RTP header.
+const RTP_HEADER_LEN: usize = 12;
+
+/// Maximum number of distinct source addresses tracked.
+const MAX_SOURCES: usize = 1024;
+
+/// How much detail is recorded about unmatched packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiagnosticsLevel {
+    /// No classification or counters
+    Off,
+    /// Per-source counters with a periodic summary
+    #[default]
+    Summary,
+    /// Counters plus a log line for every unmatched packet
+    Verbose,
+}
+
+impl DiagnosticsLevel {
+    /// Reads the level from [`DEMUX_DIAGNOSTICS_ENV`] (`off`, `summary` or `verbose`).
+    pub fn from_env() -> Self {
+        match env::var(DEMUX_DIAGNOSTICS_ENV).as_deref() {
+            Ok("off") => DiagnosticsLevel::Off,
+            Ok("verbose") => DiagnosticsLevel::Verbose,
+            _ => DiagnosticsLevel::Summary,
+        }
+    }
+}
+
+/// The protocol of a datagram, as far as it can be told from its bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketClass {
+    /// A STUN message; `ufrag` is the local (recipient) part of USERNAME, if present
+    Stun { ufrag: Option<String> },
+    /// A DTLS record
+    Dtls,
+    /// An RTP or RTCP packet
+    Rtp,
+    /// Anything else
+    Unknown,
+}
+
+/// Classifies a datagram using the RFC 7983 first-byte demultiplexing ranges.
+pub fn classify(bytes: &[u8]) -> PacketClass {
+    match bytes.first() {
+        Some(0..=3) if is_stun(bytes) => PacketClass::Stun {
+            ufrag: stun_local_ufrag(bytes),
+        },
+        Some(20..=63) => PacketClass::Dtls,
+        Some(128..=191) => PacketClass::Rtp,
+        _ => PacketClass::Unknown,
+    }
+}
+
+/// Cheaply checks that a datagram has the shape of a protocol we handle.
+///
+/// This runs on every received packet before any parsing, so it only looks
+/// at fixed header fields: the STUN magic cookie and length, the DTLS content
+/// type and version, and the minimum RTP/RTCP header size.
+///
+/// # Returns
+///
+/// `false` for datagrams that are certainly garbage
+pub fn is_plausible(bytes: &[u8]) -> bool {
+    match bytes.first() {
+        Some(0..=3) => is_stun(bytes),
+        Some(b) if DTLS_CONTENT_TYPES.contains(b) => {
+            // DTLS versions are 0xFEFF (1.0) and 0xFEFD (1.2)
+            bytes.len() >= DTLS_HEADER_LEN && bytes[1] == 0xFE && matches!(bytes[2], 0xFD | 0xFF)
+        }
+        Some(128..=191) => bytes.len() >= RTP_HEADER_LEN,
+        _ => false,
+    }
+}
+
+/// Returns `true` if the bytes carry a well-formed STUN header.
+pub fn is_stun(bytes: &[u8]) -> bool {
+    bytes.len() >= 20
+        && bytes[0] & 0xC0 == 0
+        && u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) == STUN_MAGIC_COOKIE
+        && u16::from_be_bytes([bytes[2], bytes[3]]) as usize + 20 <= bytes.len()
+}
+
+/// Extracts the recipient's ufrag from the STUN USERNAME attribute.
+///
+/// USERNAME is `recipient_ufrag:sender_ufrag`, so the first part identifies the
+/// local ICE agent the message was meant for.
+fn stun_local_ufrag(bytes: &[u8]) -> Option<String> {
+    let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+    let mut attrs = &bytes[20..20 + length];
+
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + len)?;
+        if kind == STUN_ATTR_USERNAME {
+            let username = std::str::from_utf8(value).ok()?;
+            return username.split(':').next().map(str::to_string);
+        }
+        // Attributes are padded to a multiple of 4 bytes.
+        let padded = 4 + len.div_ceil(4) * 4;
+        attrs = attrs.get(padded..)?;
+    }
+
+    None
+}
+
+/// Counters of unmatched packets from one source address.
+#[derive(Debug, Clone)]
+pub struct SourceCounters {
+    pub stun: u64,
+    pub dtls: u64,
+    pub rtp: u64,
+    pub unknown: u64,
+    pub last_seen: Instant,
+}
+
+impl SourceCounters {
+    /// Total unmatched packets from the source.
+    pub fn total(&self) -> u64 {
+        self.stun + self.dtls + self.rtp + self.unknown
+    }
+}
+
+/// Per-source counters of packets no client accepted.
+#[derive(Debug)]
+pub struct UnmatchedDiagnostics {
+    level: DiagnosticsLevel,
+    sources: HashMap<SocketAddr, SourceCounters>,
+    malformed: u64,
+    last_summary: Instant,
+}
+
+impl UnmatchedDiagnostics {
+    /// Creates an empty set of counters at the given level.
+    pub fn new(level: DiagnosticsLevel) -> Self {
+        Self {
+            level,
+            sources: HashMap::new(),
+            malformed: 0,
+            last_summary: Instant::now(),
+        }
+    }
+
+    /// Returns `true` unless diagnostics are switched off.
+    pub fn is_enabled(&self) -> bool {
+        self.level != DiagnosticsLevel::Off
+    }
+
+    /// Returns the counters of every tracked source.
+    pub fn sources(&self) -> &HashMap<SocketAddr, SourceCounters> {
+        &self.sources
+    }
+
+    /// Returns the number of malformed datagrams dropped by the pre-filter.
+    pub fn malformed(&self) -> u64 {
+        self.malformed
+    }
+
+    /// Counts a datagram dropped by the pre-filter.
+    pub fn record_malformed(&mut self, source: SocketAddr) {
+        self.malformed += 1;
+        if self.level == DiagnosticsLevel::Verbose {
+            debug!("Dropped malformed datagram from {}", source);
+        }
+    }
+
+    /// Records an unmatched packet.
+    ///
+    /// When the number of tracked sources reaches its bound, the least recently
+    /// seen source is evicted.
+    pub fn record(&mut self, source: SocketAddr, class: &PacketClass) {
+        if self.level == DiagnosticsLevel::Off {
+            return;
+        }
+        if self.level == DiagnosticsLevel::Verbose {
+            debug!("Unmatched packet from {}: {:?}", source, class);
+        }
+
+        if !self.sources.contains_key(&source) && self.sources.len() >= MAX_SOURCES {
+            if let Some(oldest) = self
+                .sources
+                .iter()
+                .min_by_key(|(_, c)| c.last_seen)
+                .map(|(addr, _)| *addr)
+            {
+                self.sources.remove(&oldest);
+            }
+        }
+
+        let counters = self.sources.entry(source).or_insert(SourceCounters {
+            stun: 0,
+            dtls: 0,
+            rtp: 0,
+            unknown: 0,
+            last_seen: Instant::now(),
+        });
+        counters.last_seen = Instant::now();
+        match class {
+            PacketClass::Stun { .. } => counters.stun += 1,
+            PacketClass::Dtls => counters.dtls += 1,
+            PacketClass::Rtp => counters.rtp += 1,
+            PacketClass::Unknown => counters.unknown += 1,
+        }
+    }
+
+    /// Logs the per-source counters if the summary interval has elapsed.
+    pub fn log_summary(&mut self, interval: Duration) {
+        if self.level == DiagnosticsLevel::Off || self.last_summary.elapsed() < interval {
+            return;
+        }
+        self.last_summary = Instant::now();
+
+        if self.malformed > 0 {
+            info!("Dropped {} malformed datagrams", self.malformed);
+        }
+        for (source, c) in &self.sources {
+            info!(
+                "Unmatched from {}: total {} (stun {}, dtls {}, rtp {}, unknown {})",
+                source,
+                c.total(),
+                c.stun,
+                c.dtls,
+                c.rtp,
+                c.unknown
+            );
+        }
+    }
+}
+
+/// Index from source address to the client that last accepted traffic from it.
+///
+/// Lookups are verified with the client's own `accepts` check, so a stale entry
+/// (e.g. after the client's nominated candidate pair moved) only costs one extra
+/// check before falling back to the linear scan, which then re-learns the source.
+#[derive(Debug, Default)]
+pub struct DemuxIndex {
+    by_source: HashMap<SocketAddr, ClientId>,
+    positions: HashMap<ClientId, usize>,
+}
+
+impl DemuxIndex {
+    /// Creates an empty index.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuilds the client positions after the client list changed.
+    ///
+    /// Sources pointing at clients that are no longer present are dropped.
+    pub fn rebuild(&mut self, clients: &[Client]) {
+        self.positions = clients.iter().enumerate().map(|(i, c)| (c.id, i)).collect();
+        let positions = &self.positions;
+        self.by_source.retain(|_, id| positions.contains_key(id));
+    }
+
+    /// Returns the position of the client indexed for a source, if any.
+    pub fn lookup(&self, source: SocketAddr) -> Option<usize> {
+        self.by_source
+            .get(&source)
+            .and_then(|id| self.positions.get(id))
+            .copied()
+    }
+
+    /// Records that a client accepted traffic from a source.
+    pub fn learn(&mut self, source: SocketAddr, id: ClientId) {
+        self.by_source.insert(source, id);
+    }
+
+    /// Removes a stale entry for a source.
+    pub fn forget(&mut self, source: SocketAddr) {
+        self.by_source.remove(&source);
+    }
+}
@@ -0,0 +1,237 @@
+//! Duplicate sending of critical messages
+//!
+//! An emergency stop must arrive even while one link drops packets or is
+//! about to fail. Channels designated in [`DuplicateConfig`] send each of
+//! their messages over every active link of the peer at once, the main
+//! connection and each connected further connection, trading bandwidth for
+//! reliability. The copies carry the same sender stream and sequence number,
+//! and the receiver hands only the first copy to its subscribers.
+//!
+//! A copy is a [`DUPLICATE_MARKER`] byte, the sender's stream ID and the
+//! sequence number as big-endian `u64`, then the data. Copies are only sent
+//! once both sides advertised
+//! [`Feature::Deduplication`](crate::model::control::Feature::Deduplication);
+//! without it a single plain message goes over each link. From then on, a
+//! message starting with the marker byte is a copy, and a message that
+//! happens to start with it is sent on stream `0`, which is never
+//! deduplicated. Copies are framed before fragmentation and batching.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+/// First byte of a copy.
+pub const DUPLICATE_MARKER: u8 = 0xFD;
+
+/// Bytes of the copy header: the marker, stream ID and sequence number.
+pub const DUPLICATE_OVERHEAD: usize = 17;
+
+/// Sequence numbers remembered per stream by default.
+pub const DEFAULT_WINDOW: u64 = 1024;
+
+/// Duplicate sending settings, the `[peer.duplicate]` section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DuplicateConfig {
+    /// Labels of the channels whose messages go over every active link
+    pub channels: Vec<String>,
+    /// Sequence numbers remembered per sender to drop repeated copies; a
+    /// copy older than that is dropped too
+    pub window: u64,
+}
+
+impl Default for DuplicateConfig {
+    fn default() -> Self {
+        Self {
+            channels: vec![],
+            window: DEFAULT_WINDOW,
+        }
+    }
+}
+
+impl DuplicateConfig {
+    /// Checks that the channels and the window are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.channels.iter().any(String::is_empty) {
+            return Err("channels must not contain an empty label".into());
+        }
+        if self.window == 0 {
+            return Err("window must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// One copy of a duplicated message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicate {
+    /// The sender's stream, `0` for a message that is not deduplicated
+    pub stream: u64,
+    /// Position of the message in the stream
+    pub sequence: u64,
+    /// The application data
+    pub data: Vec<u8>,
+}
+
+impl Duplicate {
+    /// Frames the copy.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(DUPLICATE_OVERHEAD + self.data.len());
+        bytes.push(DUPLICATE_MARKER);
+        bytes.extend_from_slice(&self.stream.to_be_bytes());
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Unframes a copy.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Duplicate)` - The copy
+    /// * `None` - If the bytes do not start with the marker and a full header
+    pub fn decode(bytes: &[u8]) -> Option<Duplicate> {
+        if !is_duplicate(bytes) || bytes.len() < DUPLICATE_OVERHEAD {
+            return None;
+        }
+        let stream = u64::from_be_bytes(bytes[1..9].try_into().ok()?);
+        let sequence = u64::from_be_bytes(bytes[9..17].try_into().ok()?);
+        Some(Duplicate {
+            stream,
+            sequence,
+            data: bytes[DUPLICATE_OVERHEAD..].to_vec(),
+        })
+    }
+}
+
+/// Returns `true` if the message is a copy.
+///
+/// Only meaningful once deduplication was negotiated with the sender.
+pub fn is_duplicate(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&DUPLICATE_MARKER)
+}
+
+/// Frames a message that is not duplicated, once deduplication was
+/// negotiated.
+///
+/// # Returns
+///
+/// The message itself, or a copy on stream `0` if it starts with the marker
+/// byte
+pub fn frame_single(message: Vec<u8>) -> Vec<u8> {
+    if is_duplicate(&message) {
+        Duplicate {
+            stream: 0,
+            sequence: 0,
+            data: message,
+        }
+        .encode()
+    } else {
+        message
+    }
+}
+
+/// Numbers the messages of the designated channels.
+#[derive(Debug)]
+pub struct DuplicateSender {
+    stream: u64,
+    next: u64,
+    channels: HashSet<String>,
+}
+
+impl DuplicateSender {
+    /// Creates a sender.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The sender's stream ID, random so that receivers tell
+    ///   senders apart; `0` is replaced by `1`
+    /// * `config` - The designated channels
+    pub fn new(stream: u64, config: &DuplicateConfig) -> Self {
+        Self {
+            stream: stream.max(1),
+            next: 0,
+            channels: config.channels.iter().cloned().collect(),
+        }
+    }
+
+    /// Returns the sender's stream ID.
+    pub fn stream(&self) -> u64 {
+        self.stream
+    }
+
+    /// Returns `true` if the messages of a channel are duplicated.
+    pub fn designates(&self, label: &str) -> bool {
+        self.channels.contains(label)
+    }
+
+    /// Frames a message, the same for every link it is sent over.
+    pub fn frame(&mut self, data: Vec<u8>) -> Vec<u8> {
+        let sequence = self.next;
+        self.next += 1;
+        Duplicate {
+            stream: self.stream,
+            sequence,
+            data,
+        }
+        .encode()
+    }
+}
+
+/// Sequence numbers received from one sender.
+#[derive(Debug, Default)]
+struct SeenWindow {
+    highest: u64,
+    seen: BTreeSet<u64>,
+}
+
+/// Drops the copies of a message after the first.
+#[derive(Debug)]
+pub struct Deduplicator {
+    window: u64,
+    streams: HashMap<u64, SeenWindow>,
+}
+
+impl Default for Deduplicator {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW)
+    }
+}
+
+impl Deduplicator {
+    /// Creates a deduplicator remembering `window` sequence numbers per
+    /// stream.
+    pub fn new(window: u64) -> Self {
+        Self {
+            window: window.max(1),
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Changes how many sequence numbers are remembered per stream.
+    pub fn set_window(&mut self, window: u64) {
+        self.window = window.max(1);
+    }
+
+    /// Returns `true` if the copy is the first of its message.
+    ///
+    /// Copies on stream `0` are always accepted; copies older than the
+    /// window are not, as they cannot be told from a repeat.
+    pub fn accept(&mut self, copy: &Duplicate) -> bool {
+        if copy.stream == 0 {
+            return true;
+        }
+        let window = self.window;
+        let seen = self.streams.entry(copy.stream).or_default();
+        if !seen.seen.is_empty() && copy.sequence.saturating_add(window) <= seen.highest {
+            return false;
+        }
+        if !seen.seen.insert(copy.sequence) {
+            return false;
+        }
+        seen.highest = seen.highest.max(copy.sequence);
+        let oldest = seen.highest.saturating_sub(window - 1);
+        seen.seen = seen.seen.split_off(&oldest);
+        true
+    }
+}
@@ -0,0 +1,83 @@
+//! Bounded history of significant connection events
+//!
+//! The logs of a long-running rover or server rarely go back far enough, or
+//! are too noisy, to tell what a connection went through before a support
+//! ticket was filed. Every client on the server, and the peer, keep the last
+//! [`EVENT_RING_CAPACITY`] significant events (state changes, errors,
+//! handovers) in an [`EventRing`], which state dumps include.
+
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Number of events kept; older ones are dropped first.
+pub const EVENT_RING_CAPACITY: usize = 256;
+
+/// What a recorded event is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventCategory {
+    /// Connection state changes, e.g. of ICE or the setup
+    State,
+    /// Data channels and media tracks opening or closing
+    Channel,
+    /// ICE restarts and reconnections
+    Handover,
+    /// Failures
+    Error,
+}
+
+/// A recorded event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecordedEvent {
+    /// When the event happened
+    pub at: DateTime<Utc>,
+    /// What the event is about
+    pub category: EventCategory,
+    /// Description of the event
+    pub detail: String,
+}
+
+/// The most recent events of a connection, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct EventRing {
+    events: VecDeque<RecordedEvent>,
+    /// Number of events dropped to make room
+    dropped: u64,
+}
+
+impl EventRing {
+    /// Creates an empty ring.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an event, dropping the oldest one if the ring is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `category` - What the event is about
+    /// * `detail` - Description of the event
+    pub fn record(&mut self, category: EventCategory, detail: impl Into<String>) {
+        if self.events.len() >= EVENT_RING_CAPACITY {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back(RecordedEvent {
+            at: Utc::now(),
+            category,
+            detail: detail.into(),
+        });
+    }
+
+    /// Returns the recorded events, oldest first.
+    pub fn snapshot(&self) -> Vec<RecordedEvent> {
+        self.events.iter().cloned().collect()
+    }
+
+    /// Returns the number of events dropped to make room for newer ones.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
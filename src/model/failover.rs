@@ -0,0 +1,390 @@
+//! Warm standby failover of the signaling server
+//!
+//! A deployment may run a standby server next to the primary one. The
+//! primary names the standby in every signaling answer, and the standby
+//! copies the primary's sessions, their tokens, aliases and rooms, every few
+//! seconds from [`FAILOVER_STATE_PATH`], authenticated with the secret both
+//! share in [`FAILOVER_SECRET_ENV`].
+//!
+//! Peers probe the primary's [`HEALTH_PATH`] while connected. After
+//! [`PeerFailoverConfig::max_failures`] failed probes in a row they end the
+//! session and signal to the standby instead, presenting the token of the
+//! lost session in the [`RESUME_PARAM`] query parameter. The standby grants
+//! the access the primary had granted, so the peer keeps its alias and room
+//! without the authentication backend being asked again. Peers signal to the
+//! primary again once it answers its probes.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{Access, Role};
+
+/// Path of the health endpoint every server answers.
+pub const HEALTH_PATH: &str = "/health";
+
+/// Path of the primary's session state, polled by the standby.
+pub const FAILOVER_STATE_PATH: &str = "/failover/state";
+
+/// Query parameter carrying the token of the session lost with the primary.
+pub const RESUME_PARAM: &str = "resume";
+
+/// Environment variable with the secret the standby presents to the primary.
+pub const FAILOVER_SECRET_ENV: &str = "ROVER_FAILOVER_SECRET";
+
+/// Failover settings of the server, the `[server.failover]` section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerFailoverConfig {
+    /// Signaling URL of the standby, announced to peers by the primary
+    pub standby_url: Option<String>,
+    /// Signaling URL of the primary; setting it makes this server the
+    /// standby, copying the primary's sessions
+    pub primary_url: Option<String>,
+    /// Interval between two copies of the primary's sessions, in seconds
+    pub sync_secs: u64,
+}
+
+impl Default for ServerFailoverConfig {
+    fn default() -> Self {
+        Self {
+            standby_url: None,
+            primary_url: None,
+            sync_secs: 2,
+        }
+    }
+}
+
+impl ServerFailoverConfig {
+    /// Checks that the URLs are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(url) = &self.standby_url {
+            validate_signaling_url("standby_url", url)?;
+        }
+        if let Some(url) = &self.primary_url {
+            // Polled over plain HTTP
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(format!("primary_url '{}' must be an http(s) URL", url));
+            }
+        }
+        if self.sync_secs == 0 {
+            return Err("sync_secs must be positive".into());
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if this server is the standby of another.
+    pub fn is_standby(&self) -> bool {
+        self.primary_url.is_some()
+    }
+}
+
+/// Failover settings of the peer, the `[peer.failover]` section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerFailoverConfig {
+    /// Probe the primary and fail over to the standby it announced
+    pub enabled: bool,
+    /// Standby used if the primary announces none
+    pub standby_url: Option<String>,
+    /// Interval between two probes of the primary, in seconds
+    pub probe_secs: u64,
+    /// Time after which a probe counts as failed, in milliseconds
+    pub probe_timeout_ms: u64,
+    /// Failed probes in a row after which the primary is considered down
+    pub max_failures: u32,
+}
+
+impl Default for PeerFailoverConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            standby_url: None,
+            probe_secs: 2,
+            probe_timeout_ms: 1500,
+            max_failures: 3,
+        }
+    }
+}
+
+impl PeerFailoverConfig {
+    /// Checks that the probes are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(url) = &self.standby_url {
+            validate_signaling_url("standby_url", url)?;
+        }
+        if self.probe_secs == 0 || self.probe_timeout_ms == 0 {
+            return Err("probe_secs and probe_timeout_ms must be positive".into());
+        }
+        if self.max_failures == 0 {
+            return Err("max_failures must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// Checks that a URL is one peers can signal to.
+fn validate_signaling_url(name: &str, url: &str) -> Result<(), String> {
+    if ["http://", "https://", "ws://", "wss://"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} '{}' must be an http, https, ws or wss URL",
+            name, url
+        ))
+    }
+}
+
+/// The answer of the health endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthStatus {
+    /// `primary` or `standby`
+    pub role: String,
+    /// Number of connected sessions
+    pub sessions: usize,
+}
+
+/// A session as copied to the standby.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    /// The token identifying the session
+    pub session_token: String,
+    /// The alias the client was registered under, if any
+    pub alias: Option<String>,
+    /// The room the session belongs to, if any
+    pub room: Option<String>,
+    /// Whether the session may only observe
+    pub observer: bool,
+    /// The ID of the guest link used to join, if any
+    pub guest_id: Option<String>,
+    /// When the access expires, in seconds since the Unix epoch
+    pub expires_at: Option<i64>,
+    /// The identity established by the authentication backend, if any
+    pub subject: Option<String>,
+}
+
+impl SessionRecord {
+    /// Records a session accepted by the server.
+    pub fn new(session_token: &str, alias: Option<&str>, access: &Access) -> Self {
+        Self {
+            session_token: session_token.to_string(),
+            alias: alias.map(str::to_string),
+            room: access.room.clone(),
+            observer: access.is_observer(),
+            guest_id: access.guest_id.clone(),
+            expires_at: access.expires_at,
+            subject: access.subject.clone(),
+        }
+    }
+
+    /// Returns the access the primary granted.
+    pub fn access(&self) -> Access {
+        Access {
+            role: if self.observer {
+                Role::Observer
+            } else {
+                Role::Participant
+            },
+            room: self.room.clone(),
+            guest_id: self.guest_id.clone(),
+            expires_at: self.expires_at,
+            subject: self.subject.clone(),
+        }
+    }
+}
+
+/// The sessions of a server, by token.
+#[derive(Debug, Default)]
+pub struct SessionDirectory {
+    sessions: HashMap<String, SessionRecord>,
+}
+
+impl SessionDirectory {
+    /// Creates an empty directory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a session.
+    pub fn insert(&mut self, record: SessionRecord) {
+        self.sessions.insert(record.session_token.clone(), record);
+    }
+
+    /// Removes the session with a token, e.g. once its client disconnected.
+    pub fn remove(&mut self, session_token: &str) {
+        self.sessions.remove(session_token);
+    }
+
+    /// Returns the number of sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Returns `true` if there is no session.
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Lists the sessions, for the standby.
+    pub fn records(&self) -> Vec<SessionRecord> {
+        self.sessions.values().cloned().collect()
+    }
+
+    /// Replaces the sessions with those copied from the primary.
+    pub fn replace(&mut self, records: Vec<SessionRecord>) {
+        self.sessions = records
+            .into_iter()
+            .map(|record| (record.session_token.clone(), record))
+            .collect();
+    }
+
+    /// Takes the session a peer resumes, once.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_token` - The token of the session lost with the primary
+    /// * `now` - The current time, in seconds since the Unix epoch
+    ///
+    /// # Returns
+    ///
+    /// The session, or `None` if it is unknown or its access expired
+    pub fn resume(&mut self, session_token: &str, now: i64) -> Option<SessionRecord> {
+        self.sessions
+            .remove(session_token)
+            .filter(|record| record.expires_at.is_none_or(|at| at > now))
+    }
+}
+
+/// The server a peer signals to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverTarget {
+    Primary,
+    Standby,
+}
+
+/// The peer's view of the primary and standby servers.
+#[derive(Debug)]
+pub struct FailoverState {
+    primary: String,
+    /// The standby, as configured or announced by the primary
+    standby: Option<String>,
+    /// The server of the current or last session
+    target: FailoverTarget,
+    /// The token of the last session with the primary
+    session_token: Option<String>,
+    /// Failed probes of the primary in a row
+    failures: u32,
+    max_failures: u32,
+    next_probe: Instant,
+    probe_interval: Duration,
+}
+
+impl FailoverState {
+    /// Creates the state of a peer signaling to `primary`.
+    pub fn new(primary: &str, config: &PeerFailoverConfig, now: Instant) -> Self {
+        Self {
+            primary: primary.trim_end_matches('/').to_string(),
+            standby: config.standby_url.clone(),
+            target: FailoverTarget::Primary,
+            session_token: None,
+            failures: 0,
+            max_failures: config.max_failures,
+            next_probe: now,
+            probe_interval: Duration::from_secs(config.probe_secs),
+        }
+    }
+
+    /// Records the answer of the server a session was signaled to.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_token` - The token the server assigned
+    /// * `standby_url` - The standby the primary announced, if any
+    pub fn answered(&mut self, session_token: &str, standby_url: Option<&str>) {
+        if self.target == FailoverTarget::Primary {
+            self.session_token = Some(session_token.to_string());
+            if let Some(standby) = standby_url {
+                self.standby = Some(standby.to_string());
+            }
+        }
+    }
+
+    /// Returns the URL of the primary's health endpoint if it is time to
+    /// probe it.
+    pub fn probe_due(&mut self, now: Instant) -> Option<String> {
+        if self.standby.is_none() || now < self.next_probe {
+            return None;
+        }
+        self.next_probe = now + self.probe_interval;
+        // WebSocket signaling servers answer plain HTTP requests too
+        let base = match self.primary.strip_prefix("ws") {
+            Some(rest) => format!("http{}", rest),
+            None => self.primary.clone(),
+        };
+        Some(format!("{}{}", base, HEALTH_PATH))
+    }
+
+    /// Records the outcome of a probe of the primary.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the primary just went down
+    pub fn probed(&mut self, healthy: bool) -> bool {
+        if healthy {
+            self.failures = 0;
+            return false;
+        }
+        self.failures += 1;
+        self.failures == self.max_failures
+    }
+
+    /// Returns `true` if the primary failed too many probes.
+    pub fn primary_down(&self) -> bool {
+        self.failures >= self.max_failures
+    }
+
+    /// Returns `true` if the current session is with a primary that went
+    /// down while a standby is known, so it should end.
+    pub fn should_abandon(&self) -> bool {
+        self.target == FailoverTarget::Primary && self.standby.is_some() && self.primary_down()
+    }
+
+    /// Chooses the server of the next session.
+    ///
+    /// # Returns
+    ///
+    /// The signaling URL of the standby while the primary is down, of the
+    /// primary otherwise
+    pub fn next_target(&mut self) -> String {
+        match (&self.standby, self.primary_down()) {
+            (Some(standby), true) => {
+                self.target = FailoverTarget::Standby;
+                standby.clone()
+            }
+            _ => {
+                self.target = FailoverTarget::Primary;
+                self.primary.clone()
+            }
+        }
+    }
+
+    /// Returns the token of the session to resume, when signaling to the
+    /// standby.
+    pub fn resume_token(&self) -> Option<&str> {
+        match self.target {
+            FailoverTarget::Standby => self.session_token.as_deref(),
+            FailoverTarget::Primary => None,
+        }
+    }
+
+    /// Returns the server of the current or last session.
+    pub fn target(&self) -> FailoverTarget {
+        self.target
+    }
+}
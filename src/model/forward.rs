@@ -0,0 +1,134 @@
+//! Media forwarding between clients
+//!
+//! With forwarding enabled, the server acts as a selective forwarding unit:
+//! every audio or video track a client publishes is offered to the other
+//! clients of its room, and each received frame is written to their tracks
+//! as it is, without decoding. Keyframe requests of the receivers are routed
+//! back to the publisher.
+//!
+//! Adding tracks takes a new offer from the server once the session is up,
+//! which travels on the reliable "forward" data channel: the server sends an
+//! [`ForwardMessage::Offer`] naming the publisher of each new track, and the
+//! peer replies with its [`ForwardMessage::Answer`]. Only peers opening the
+//! channel receive forwarded tracks.
+
+use bincode::config::{self, Configuration};
+use serde::{Deserialize, Serialize};
+
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying the renegotiations of forwarded tracks.
+pub const FORWARD_CHANNEL: &str = "forward";
+
+/// Media forwarding settings of the server, the `[server.forward]` section.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardConfig {
+    /// Forward the tracks of every client to the other clients of its room
+    /// that open the forward channel
+    pub enabled: bool,
+}
+
+/// A track offered to a peer, forwarded from another client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct ForwardedTrack {
+    /// The media ID of the track in the offer
+    pub mid: String,
+    /// The ID of the client publishing the track
+    pub origin: u64,
+    /// `audio` or `video`
+    pub kind: String,
+}
+
+/// Messages exchanged on the forward channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum ForwardMessage {
+    /// Server to peer: an SDP offer adding forwarded tracks
+    Offer {
+        sdp: String,
+        tracks: Vec<ForwardedTrack>,
+    },
+    /// Peer to server: the answer to the last offer
+    Answer { sdp: String },
+    /// Peer to server: the last offer could not be accepted, e.g. because
+    /// the peer's own renegotiation was in progress; the server offers again
+    Rejected { reason: String },
+}
+
+impl ForwardMessage {
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(ForwardMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+}
+
+impl WireSchema for ForwardedTrack {
+    fn wire_schema() -> TypeDef {
+        TypeDef::structure(
+            "ForwardedTrack",
+            "A track offered to a peer, forwarded from another client",
+            vec![
+                field(
+                    "mid",
+                    WireType::String,
+                    "The media ID of the track in the offer",
+                ),
+                field(
+                    "origin",
+                    WireType::U64,
+                    "The ID of the client publishing the track",
+                ),
+                field("kind", WireType::String, "audio or video"),
+            ],
+        )
+    }
+}
+
+impl WireSchema for ForwardMessage {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "ForwardMessage",
+            "Messages exchanged on the forward channel",
+            vec![
+                (
+                    "Offer",
+                    "Server to peer: an SDP offer adding forwarded tracks",
+                    vec![
+                        field("sdp", WireType::String, "The SDP offer"),
+                        field(
+                            "tracks",
+                            WireType::list(WireType::Ref {
+                                name: "ForwardedTrack",
+                            }),
+                            "The tracks the offer adds",
+                        ),
+                    ],
+                ),
+                (
+                    "Answer",
+                    "Peer to server: the answer to the last offer",
+                    vec![field("sdp", WireType::String, "The SDP answer")],
+                ),
+                (
+                    "Rejected",
+                    "Peer to server: the last offer could not be accepted; the server offers again",
+                    vec![field("reason", WireType::String, "Why it was rejected")],
+                ),
+            ],
+        )
+    }
+}
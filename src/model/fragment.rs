@@ -0,0 +1,224 @@
+//! Fragmentation of application messages above the path MTU
+//!
+//! The WebRTC stack sizes its datagrams for common paths, and SCTP splits a
+//! large message into chunks of that size. On a path with a smaller MTU,
+//! e.g. an LTE tunnel, the datagrams are fragmented by IP or, with the
+//! don't-fragment bit set, dropped; many carriers drop IP fragments too.
+//! Once the path MTU is known to be too small, the peer splits the messages
+//! sent through its handle into fragments that fit in one datagram, and the
+//! receiver reassembles them.
+//!
+//! A fragment is a [`FRAGMENT_MARKER`] byte, the message ID and the fragment
+//! index and count as big-endian `u32`, `u16` and `u16`, then the data.
+//! Fragments are only sent once both sides advertised
+//! [`Feature::Fragmentation`](crate::model::control::Feature::Fragmentation);
+//! from then on, a message starting with the marker byte is a fragment, and
+//! a message that happens to start with it is sent as a single fragment.
+//! Fragments are framed before batching, so a batch may carry fragments.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// First byte of a fragment.
+pub const FRAGMENT_MARKER: u8 = 0xFC;
+
+/// Bytes of the fragment header: the marker, message ID, index and count.
+pub const FRAGMENT_OVERHEAD: usize = 9;
+
+/// The largest message reassembled, in bytes.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Time after which an incomplete message is dropped, e.g. when a fragment
+/// was lost on an unreliable channel.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reasons a fragment could not be reassembled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    /// The header is truncated, or the index not below the count
+    Malformed,
+    /// The message would exceed [`MAX_MESSAGE_SIZE`]
+    TooLarge,
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            FragmentError::Malformed => "malformed fragment",
+            FragmentError::TooLarge => "fragmented message too large",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for FragmentError {}
+
+/// Returns `true` if the message is a fragment.
+///
+/// Only meaningful once fragmentation was negotiated with the sender.
+pub fn is_fragment(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&FRAGMENT_MARKER)
+}
+
+fn encode_fragment(id: u32, index: u16, count: u16, data: &[u8]) -> Vec<u8> {
+    let mut fragment = Vec::with_capacity(FRAGMENT_OVERHEAD + data.len());
+    fragment.push(FRAGMENT_MARKER);
+    fragment.extend_from_slice(&id.to_be_bytes());
+    fragment.extend_from_slice(&index.to_be_bytes());
+    fragment.extend_from_slice(&count.to_be_bytes());
+    fragment.extend_from_slice(data);
+    fragment
+}
+
+/// Splits the messages sent once fragmentation was negotiated.
+#[derive(Debug, Default)]
+pub struct Fragmenter {
+    next_id: u32,
+}
+
+impl Fragmenter {
+    /// Creates a fragmenter numbering messages from zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Frames a message for sending.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message
+    /// * `max_size` - The largest SCTP message that fits in one datagram, or
+    ///   `None` if the path carries the stack's datagrams
+    ///
+    /// # Returns
+    ///
+    /// The fragments of the message if it exceeds `max_size`, a single
+    /// fragment if it starts with the marker byte, or the message itself
+    pub fn fragment(&mut self, message: Vec<u8>, max_size: Option<usize>) -> Vec<Vec<u8>> {
+        let chunk = max_size
+            .filter(|max| message.len() > *max)
+            .map(|max| max.saturating_sub(FRAGMENT_OVERHEAD).max(1));
+        let Some(chunk) = chunk else {
+            if is_fragment(&message) {
+                return vec![encode_fragment(0, 0, 1, &message)];
+            }
+            return vec![message];
+        };
+        let count = message.len().div_ceil(chunk);
+        if count > u16::MAX as usize {
+            // Cannot be numbered; SCTP splits it as before
+            return vec![encode_fragment(0, 0, 1, &message)];
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        message
+            .chunks(chunk)
+            .enumerate()
+            .map(|(index, data)| encode_fragment(id, index as u16, count as u16, data))
+            .collect()
+    }
+}
+
+/// The fragments of a message received so far.
+#[derive(Debug)]
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+/// Reassembles the fragmented messages of every channel.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    partial: HashMap<(String, u32), PartialMessage>,
+}
+
+impl Reassembler {
+    /// Creates a reassembler without partial messages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a fragment received on a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel it arrived on
+    /// * `fragment` - The fragment, marker included
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(Vec<u8>))` - The message, if this fragment completed it
+    /// * `Ok(None)` - If fragments of the message are missing
+    /// * `Err(FragmentError)` - If the fragment is malformed or the message
+    ///   too large; its fragments are dropped
+    pub fn push(
+        &mut self,
+        label: &str,
+        fragment: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        let header = fragment
+            .get(..FRAGMENT_OVERHEAD)
+            .filter(|h| h[0] == FRAGMENT_MARKER)
+            .ok_or(FragmentError::Malformed)?;
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let index = u16::from_be_bytes([header[5], header[6]]) as usize;
+        let count = u16::from_be_bytes([header[7], header[8]]) as usize;
+        let data = &fragment[FRAGMENT_OVERHEAD..];
+        if index >= count {
+            return Err(FragmentError::Malformed);
+        }
+        if count == 1 {
+            return Ok(Some(data.to_vec()));
+        }
+
+        let key = (label.to_string(), id);
+        let partial = self
+            .partial
+            .entry(key.clone())
+            .or_insert_with(|| PartialMessage {
+                fragments: vec![None; count],
+                received: 0,
+                size: 0,
+                started: now,
+            });
+        if partial.fragments.len() != count {
+            self.partial.remove(&key);
+            return Err(FragmentError::Malformed);
+        }
+        if partial.fragments[index].is_none() {
+            partial.size += data.len();
+            partial.received += 1;
+            partial.fragments[index] = Some(data.to_vec());
+        }
+        if partial.size > MAX_MESSAGE_SIZE {
+            self.partial.remove(&key);
+            return Err(FragmentError::TooLarge);
+        }
+        if partial.received < count {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&key).expect("partial message");
+        Ok(Some(
+            partial.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Drops the messages still incomplete after [`REASSEMBLY_TIMEOUT`].
+    ///
+    /// # Returns
+    ///
+    /// The number of messages dropped
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.partial.len();
+        self.partial
+            .retain(|_, p| now.duration_since(p.started) < REASSEMBLY_TIMEOUT);
+        before - self.partial.len()
+    }
+}
@@ -0,0 +1,241 @@
+//! Geofence definitions and boundary monitoring
+//!
+//! This module provides configurable geofences evaluated on the server against
+//! the GPS telemetry reported by each client. Boundary crossings are reported as
+//! [`GeofenceEvent`]s which the server turns into webhooks and, optionally,
+//! automatic commands sent back to the rover.
+
+use std::{collections::HashMap, env, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::{client::ClientId, telemetry::GpsFix};
+
+/// Environment variable pointing to the geofence configuration file.
+pub const GEOFENCE_CONFIG_ENV: &str = "ROVER_GEOFENCES";
+
+/// Mean Earth radius in meters, used for great-circle distances.
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Whether a rover is expected to stay inside or outside a fence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GeofenceKind {
+    /// The rover must stay inside the fence
+    Inclusion,
+    /// The rover must stay outside the fence
+    Exclusion,
+}
+
+/// The geometry of a geofence.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GeofenceShape {
+    /// A circle around a center point
+    Circle {
+        latitude: f64,
+        longitude: f64,
+        radius_m: f64,
+    },
+    /// A polygon given as `[latitude, longitude]` vertices
+    Polygon { vertices: Vec<[f64; 2]> },
+}
+
+impl GeofenceShape {
+    /// Returns `true` if the given position lies within the shape.
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        match self {
+            GeofenceShape::Circle {
+                latitude: lat,
+                longitude: lon,
+                radius_m,
+            } => haversine_m(*lat, *lon, latitude, longitude) <= *radius_m,
+            GeofenceShape::Polygon { vertices } => {
+                // Ray casting on the lat/lon plane, adequate for small fences.
+                let mut inside = false;
+                let mut j = vertices.len().wrapping_sub(1);
+                for i in 0..vertices.len() {
+                    let [yi, xi] = vertices[i];
+                    let [yj, xj] = vertices[j];
+                    if (yi > latitude) != (yj > latitude)
+                        && longitude < (xj - xi) * (latitude - yi) / (yj - yi) + xi
+                    {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+        }
+    }
+}
+
+/// A named geofence with an optional automatic command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Geofence {
+    /// Human-readable name used in events
+    pub name: String,
+    /// Whether the rover must stay inside or outside
+    pub kind: GeofenceKind,
+    /// The fence geometry
+    pub shape: GeofenceShape,
+    /// Message sent to the rover when the fence is breached
+    #[serde(default)]
+    pub command: Option<String>,
+}
+
+/// Geofence configuration loaded by the server.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GeofenceConfig {
+    /// URL receiving a JSON POST for every boundary crossing; crossings are
+    /// dropped while too many are waiting for a slow webhook
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// The configured fences
+    #[serde(default)]
+    pub fences: Vec<Geofence>,
+}
+
+impl GeofenceConfig {
+    /// Loads the configuration from a JSON file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Loads the configuration from the file named by [`GEOFENCE_CONFIG_ENV`].
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(GeofenceConfig)` - The loaded config, or an empty one if the variable is unset
+    /// * `Err` - If the file could not be read or parsed
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env::var(GEOFENCE_CONFIG_ENV) {
+            Ok(path) => Self::load(Path::new(&path)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Direction of a boundary crossing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GeofenceTransition {
+    /// The rover moved into the fence
+    Entered,
+    /// The rover moved out of the fence
+    Exited,
+}
+
+/// A boundary crossing detected for a client.
+#[derive(Debug, Clone, Serialize)]
+pub struct GeofenceEvent {
+    /// The client that crossed the boundary
+    pub client_id: u64,
+    /// The name of the fence
+    pub fence: String,
+    /// The direction of the crossing
+    pub transition: GeofenceTransition,
+    /// `true` if the crossing violates the fence kind
+    pub breach: bool,
+    /// Command to send to the rover, if configured and breached
+    #[serde(skip)]
+    pub command: Option<String>,
+    /// Latitude of the fix that triggered the event, in decimal degrees
+    pub latitude: f64,
+    /// Longitude of the fix that triggered the event, in decimal degrees
+    pub longitude: f64,
+    /// Time of the fix, in nanoseconds since the Unix epoch
+    pub timestamp: i64,
+}
+
+/// Evaluates GPS fixes against the configured fences.
+///
+/// Keeps the last known inside/outside state per client and fence so only
+/// transitions are reported.
+#[derive(Debug, Default)]
+pub struct GeofenceMonitor {
+    config: GeofenceConfig,
+    inside: HashMap<(u64, usize), bool>,
+}
+
+impl GeofenceMonitor {
+    /// Creates a monitor for the given configuration.
+    pub fn new(config: GeofenceConfig) -> Self {
+        Self {
+            config,
+            inside: HashMap::new(),
+        }
+    }
+
+    /// Returns the webhook URL, if configured.
+    pub fn webhook(&self) -> Option<&str> {
+        self.config.webhook.as_deref()
+    }
+
+    /// Evaluates a fix for a client.
+    ///
+    /// The first fix of a client only produces events for fences it already
+    /// violates; subsequent fixes produce an event for every crossing.
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client that reported the fix
+    /// * `fix` - The reported GPS fix
+    ///
+    /// # Returns
+    ///
+    /// The boundary crossings caused by the fix
+    pub fn update(&mut self, client: ClientId, fix: &GpsFix) -> Vec<GeofenceEvent> {
+        let mut events = vec![];
+
+        for (index, fence) in self.config.fences.iter().enumerate() {
+            let inside = fence.shape.contains(fix.latitude, fix.longitude);
+            let previous = self.inside.insert((*client, index), inside);
+
+            let violating = match fence.kind {
+                GeofenceKind::Inclusion => !inside,
+                GeofenceKind::Exclusion => inside,
+            };
+            let crossed = match previous {
+                Some(was_inside) => was_inside != inside,
+                None => violating,
+            };
+            if !crossed {
+                continue;
+            }
+
+            events.push(GeofenceEvent {
+                client_id: *client,
+                fence: fence.name.clone(),
+                transition: if inside {
+                    GeofenceTransition::Entered
+                } else {
+                    GeofenceTransition::Exited
+                },
+                breach: violating,
+                command: fence.command.clone().filter(|_| violating),
+                latitude: fix.latitude,
+                longitude: fix.longitude,
+                timestamp: fix.timestamp,
+            });
+        }
+
+        events
+    }
+
+    /// Forgets all state kept for a client.
+    pub fn remove_client(&mut self, client: ClientId) {
+        self.inside.retain(|(id, _), _| *id != *client);
+    }
+}
+
+/// Great-circle distance between two positions, in meters.
+fn haversine_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
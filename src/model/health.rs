@@ -0,0 +1,331 @@
+//! Connection health tracking shared by the peer and the server
+//!
+//! Both ends of a session record when the connection last carried traffic,
+//! the failures implicating it and, once heartbeats are negotiated, the link
+//! quality they measure. At every health check a [`HealthPolicy`] turns that
+//! record into a [`HealthAction`]: nothing while the connection is healthy,
+//! a recovery attempt while it is degraded, and giving up once the attempts
+//! are exhausted. The server waits for the peer to restart ICE and evicts
+//! the client when it gives up; the peer restarts ICE itself and ends the
+//! session, so its reconnection logic takes over.
+//!
+//! [`ThresholdPolicy`] is the policy of both ends, with different thresholds
+//! set in the `[server.health]` and `[peer.health]` sections ([`HealthConfig`]);
+//! applications with other needs implement [`HealthPolicy`] themselves.
+//!
+//! Along the way, a connection moves through the states of [`HealthState`]:
+//! healthy, degraded from the first check that finds it so until traffic
+//! flows again, and dead once the recovery attempts are exhausted or it
+//! carried no traffic for the dead-peer timeout. A dead connection does
+//! not come back; the server removes the client and the peer ends the
+//! session. Both report each change, as
+//! [`ServerEvent::HealthChanged`](crate::server::ServerEvent::HealthChanged)
+//! and [`PeerEvent::HealthChanged`](crate::peer::PeerEvent::HealthChanged).
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::heartbeat::{LinkMonitor, LinkStats};
+
+/// Health thresholds, the `[server.health]` and `[peer.health]` sections.
+///
+/// Connections negotiating heartbeats are degraded once `max_missed` of them
+/// in a row were lost (see `[protocol.heartbeat]`, which also sets how often
+/// they are sent); the idle thresholds apply to the others.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// How long a connection without heartbeats may carry no traffic before
+    /// it is degraded, in seconds; 10 on the server and 15 on the peer if
+    /// unset
+    pub idle_secs: Option<u64>,
+    /// Failures in a row a connection without heartbeats must also have; 4
+    /// on the server and 0 on the peer if unset
+    pub min_failures: Option<u32>,
+    /// Recovery attempts before a degraded connection is dead
+    pub max_recoveries: u32,
+    /// How long a connection may carry no traffic before it is dead, however
+    /// many recovery attempts remain, in seconds; not limited if unset
+    pub dead_secs: Option<u64>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            idle_secs: None,
+            min_failures: None,
+            max_recoveries: 3,
+            dead_secs: None,
+        }
+    }
+}
+
+impl HealthConfig {
+    /// Checks that the thresholds are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.idle_secs == Some(0) {
+            return Err("idle_secs must be at least 1".into());
+        }
+        if self.dead_secs == Some(0) {
+            return Err("dead_secs must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// Where a connection stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    /// The connection carries traffic
+    Healthy,
+    /// The connection stopped carrying traffic and is being recovered
+    Degraded,
+    /// The connection was given up
+    Dead,
+}
+
+impl fmt::Display for HealthState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Healthy => write!(f, "healthy"),
+            Self::Degraded => write!(f, "degraded"),
+            Self::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+/// What a connection's health calls for at a health check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthAction {
+    /// The connection is healthy, or is being recovered
+    None,
+    /// The connection is degraded; try to recover it
+    Recover {
+        /// The number of this attempt, from 1
+        attempt: u32,
+    },
+    /// The connection is degraded and the recovery attempts are exhausted
+    GiveUp,
+}
+
+/// Decides what a connection's health calls for.
+pub trait HealthPolicy: Send + Sync {
+    /// Returns `true` if the connection is degraded.
+    fn is_degraded(&self, health: &ConnectionHealth) -> bool;
+
+    /// Returns the number of recovery attempts before giving up.
+    fn max_recoveries(&self) -> u32;
+
+    /// Returns how long a connection may carry no traffic before it is
+    /// given up, however many recovery attempts remain.
+    fn dead_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Considers a connection degraded when its heartbeats stopped being
+/// answered or, without heartbeats, when it stayed idle too long while
+/// failures implicated it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThresholdPolicy {
+    /// How long a connection without heartbeats may carry no traffic
+    pub idle: Duration,
+    /// Failures in a row a connection without heartbeats must also have
+    pub min_failures: u32,
+    /// Recovery attempts before giving up
+    pub max_recoveries: u32,
+    /// How long a connection may carry no traffic before giving up
+    pub dead_after: Option<Duration>,
+}
+
+impl ThresholdPolicy {
+    /// The policy of the server, which sees failures in the datagrams it
+    /// cannot attribute to a client.
+    pub fn server(config: &HealthConfig) -> Self {
+        Self::with_defaults(config, 10, 4)
+    }
+
+    /// The policy of the peer, whose only socket belongs to its session, so
+    /// silence alone tells a dead path; its recoveries are ICE restarts.
+    pub fn peer(config: &HealthConfig) -> Self {
+        Self::with_defaults(config, 15, 0)
+    }
+
+    fn with_defaults(config: &HealthConfig, idle_secs: u64, min_failures: u32) -> Self {
+        Self {
+            idle: Duration::from_secs(config.idle_secs.unwrap_or(idle_secs)),
+            min_failures: config.min_failures.unwrap_or(min_failures),
+            max_recoveries: config.max_recoveries,
+            dead_after: config.dead_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+impl HealthPolicy for ThresholdPolicy {
+    fn is_degraded(&self, health: &ConnectionHealth) -> bool {
+        match health.link() {
+            // Heartbeats tell a dead link from an idle one
+            Some(_) => health.is_link_down(),
+            None => health.idle() > self.idle && health.consecutive_failures() >= self.min_failures,
+        }
+    }
+
+    fn max_recoveries(&self) -> u32 {
+        self.max_recoveries
+    }
+
+    fn dead_after(&self) -> Option<Duration> {
+        self.dead_after
+    }
+}
+
+/// The health of one connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionHealth {
+    last_activity: Instant,
+    consecutive_failures: u32,
+    recovery_attempts: u32,
+    /// Link quality from the heartbeats, once the remote answers them
+    link: Option<LinkStats>,
+    /// Whether so many heartbeats in a row were lost that the link is down
+    link_down: bool,
+    state: HealthState,
+    /// When the connection was found degraded, until it recovers
+    degraded_since: Option<Instant>,
+}
+
+impl Default for ConnectionHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionHealth {
+    /// Creates the health of a connection that just carried traffic.
+    pub fn new() -> Self {
+        Self {
+            last_activity: Instant::now(),
+            consecutive_failures: 0,
+            recovery_attempts: 0,
+            link: None,
+            link_down: false,
+            state: HealthState::Healthy,
+            degraded_since: None,
+        }
+    }
+
+    /// Records the link quality the heartbeats measured.
+    pub fn mark_link(&mut self, link: &LinkMonitor) {
+        self.link = Some(link.stats());
+        self.link_down = link.is_down();
+    }
+
+    /// Records traffic on the connection.
+    pub fn mark_activity(&mut self) {
+        self.last_activity = Instant::now();
+        self.consecutive_failures = 0;
+    }
+
+    /// Records a failure implicating the connection.
+    pub fn mark_failure(&mut self) {
+        self.consecutive_failures += 1;
+    }
+
+    /// Records that the connection recovered, e.g. after an ICE restart, so
+    /// later degradations get all their attempts again.
+    pub fn mark_recovered(&mut self) {
+        self.mark_activity();
+        self.recovery_attempts = 0;
+        self.link_down = false;
+        if self.state == HealthState::Degraded {
+            self.state = HealthState::Healthy;
+            self.degraded_since = None;
+        }
+    }
+
+    /// Decides what the connection's health calls for, counting a recovery
+    /// attempt if it calls for one, and moves it to the state it is in.
+    ///
+    /// A degraded connection is healthy again once it carried traffic and
+    /// the policy no longer finds it degraded; it is dead once it is given
+    /// up, which it stays.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The policy telling a degraded connection, how often to
+    ///   try recovering it and when to give up on a silent one
+    pub fn check(&mut self, policy: &dyn HealthPolicy) -> HealthAction {
+        if self.state == HealthState::Dead {
+            return HealthAction::GiveUp;
+        }
+        if policy
+            .dead_after()
+            .is_some_and(|after| self.idle() >= after)
+        {
+            self.state = HealthState::Dead;
+            return HealthAction::GiveUp;
+        }
+        let degraded = policy.is_degraded(self);
+        if let Some(since) = self.degraded_since {
+            if !degraded && self.last_activity > since {
+                self.state = HealthState::Healthy;
+                self.degraded_since = None;
+                return HealthAction::None;
+            }
+        }
+        if !degraded {
+            return HealthAction::None;
+        }
+        self.state = HealthState::Degraded;
+        self.degraded_since.get_or_insert_with(Instant::now);
+        if self.recovery_attempts >= policy.max_recoveries() {
+            self.state = HealthState::Dead;
+            return HealthAction::GiveUp;
+        }
+        self.recovery_attempts += 1;
+        // Give the recovery a chance before counting failures again
+        self.consecutive_failures = 0;
+        self.link_down = false;
+        HealthAction::Recover {
+            attempt: self.recovery_attempts,
+        }
+    }
+
+    /// Returns where the connection stands.
+    pub fn state(&self) -> HealthState {
+        self.state
+    }
+
+    /// Returns how long the connection carried no traffic.
+    pub fn idle(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Returns the failures implicating the connection since its last
+    /// traffic.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Returns the recovery attempts since the connection was last healthy.
+    pub fn recovery_attempts(&self) -> u32 {
+        self.recovery_attempts
+    }
+
+    /// Returns the link quality from the heartbeats, if the remote answers
+    /// them.
+    pub fn link(&self) -> Option<LinkStats> {
+        self.link
+    }
+
+    /// Returns `true` if so many heartbeats in a row were lost that the link
+    /// is down.
+    pub fn is_link_down(&self) -> bool {
+        self.link_down
+    }
+}
@@ -0,0 +1,216 @@
+//! Application-level heartbeats and link quality
+//!
+//! ICE consent checks keep a path alive, but say little about how the data
+//! channels behind it perform: a congested SCTP association or a stalled
+//! event loop looks healthy to ICE. Once both sides advertised
+//! [`Feature::Heartbeat`](crate::model::control::Feature::Heartbeat), each
+//! side sends a numbered
+//! [`ControlMessage::Ping`](crate::model::control::ControlMessage::Ping) on the
+//! control channel at a fixed interval, and the remote echoes the number in a
+//! [`ControlMessage::Pong`](crate::model::control::ControlMessage::Pong).
+//!
+//! The sender measures the round-trip time of every answered ping, and counts
+//! a ping as lost if it is not answered within the timeout. From these it
+//! derives the smoothed RTT, the jitter (the smoothed variation between
+//! consecutive RTTs, as in RFC 3550) and the loss over the recent pings.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Number of recent pings the loss is computed over.
+const LOSS_WINDOW: usize = 64;
+
+/// Weight of a new RTT sample in the smoothed RTT.
+const RTT_GAIN: f64 = 1.0 / 8.0;
+
+/// Weight of a new RTT variation in the jitter.
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// Heartbeat settings, the `[protocol.heartbeat]` configuration section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// Interval between two pings, in milliseconds
+    pub interval_ms: u64,
+    /// Time after which an unanswered ping counts as lost, in milliseconds
+    pub timeout_ms: u64,
+    /// Consecutive lost pings after which the link is considered down
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 1000,
+            timeout_ms: 3000,
+            max_missed: 3,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// Checks that the interval, timeout and threshold are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_ms == 0 {
+            return Err("interval_ms must be at least 1".into());
+        }
+        if self.timeout_ms == 0 {
+            return Err("timeout_ms must be at least 1".into());
+        }
+        if self.max_missed == 0 {
+            return Err("max_missed must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// Link quality measured from the heartbeats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct LinkStats {
+    /// Smoothed round-trip time, in milliseconds; `None` before the first pong
+    pub rtt_ms: Option<f64>,
+    /// Smoothed variation of the round-trip time, in milliseconds
+    pub jitter_ms: Option<f64>,
+    /// Fraction of the recent pings that were lost, from 0 to 1
+    pub loss: f64,
+    /// Pings sent
+    pub sent: u64,
+    /// Pongs received in time
+    pub received: u64,
+    /// Pings lost in a row since the last pong
+    pub missed: u32,
+}
+
+/// Sends pings and measures the link from the pongs.
+#[derive(Debug, Clone)]
+pub struct LinkMonitor {
+    config: HeartbeatConfig,
+    next_sequence: u64,
+    next_ping: Option<Instant>,
+    outstanding: BTreeMap<u64, Instant>,
+    outcomes: VecDeque<bool>,
+    last_rtt: Option<f64>,
+    stats: LinkStats,
+}
+
+impl LinkMonitor {
+    /// Creates a monitor that has not sent a ping yet.
+    pub fn new(config: HeartbeatConfig) -> Self {
+        Self {
+            config,
+            next_sequence: 0,
+            next_ping: None,
+            outstanding: BTreeMap::new(),
+            outcomes: VecDeque::new(),
+            last_rtt: None,
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// Expires unanswered pings and returns the sequence number of the next
+    /// ping if one is due.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// * `Some(u64)` - The sequence number to send in a ping, now counted as sent
+    /// * `None` - If the next ping is not due yet
+    pub fn poll(&mut self, now: Instant) -> Option<u64> {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let expired: Vec<u64> = self
+            .outstanding
+            .iter()
+            .filter(|(_, sent)| now.duration_since(**sent) >= timeout)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+        for sequence in expired {
+            self.outstanding.remove(&sequence);
+            self.stats.missed += 1;
+            self.record_outcome(false);
+        }
+
+        if self.next_ping.is_some_and(|due| now < due) {
+            return None;
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.next_ping = Some(now + Duration::from_millis(self.config.interval_ms));
+        self.outstanding.insert(sequence, now);
+        self.stats.sent += 1;
+        Some(sequence)
+    }
+
+    /// Returns when [`poll`](Self::poll) has to be called next.
+    pub fn next_due(&self) -> Option<Instant> {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let expiry = self.outstanding.values().next().map(|sent| *sent + timeout);
+        match (self.next_ping, expiry) {
+            (Some(ping), Some(expiry)) => Some(ping.min(expiry)),
+            (ping, expiry) => ping.or(expiry),
+        }
+    }
+
+    /// Records the pong to a ping.
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence` - The sequence number echoed by the remote
+    /// * `now` - The time the pong arrived
+    ///
+    /// # Returns
+    ///
+    /// The round-trip time of the ping, or `None` if it was not outstanding,
+    /// e.g. because it already counted as lost
+    pub fn pong(&mut self, sequence: u64, now: Instant) -> Option<Duration> {
+        let sent = self.outstanding.remove(&sequence)?;
+        let rtt = now.duration_since(sent);
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+
+        self.stats.rtt_ms = Some(match self.stats.rtt_ms {
+            Some(smoothed) => smoothed + RTT_GAIN * (rtt_ms - smoothed),
+            None => rtt_ms,
+        });
+        if let Some(last) = self.last_rtt {
+            let variation = (rtt_ms - last).abs();
+            let jitter = self.stats.jitter_ms.unwrap_or(0.0);
+            self.stats.jitter_ms = Some(jitter + JITTER_GAIN * (variation - jitter));
+        }
+        self.last_rtt = Some(rtt_ms);
+        self.stats.received += 1;
+        self.stats.missed = 0;
+        self.record_outcome(true);
+        Some(rtt)
+    }
+
+    /// Returns the link quality measured so far.
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Returns `true` if so many pings in a row were lost that the link is
+    /// considered down.
+    pub fn is_down(&self) -> bool {
+        self.stats.missed >= self.config.max_missed
+    }
+
+    /// Forgets all pings and measurements, for a new session.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config.clone());
+    }
+
+    fn record_outcome(&mut self, answered: bool) {
+        if self.outcomes.len() == LOSS_WINDOW {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(answered);
+        let lost = self.outcomes.iter().filter(|answered| !**answered).count();
+        self.stats.loss = lost as f64 / self.outcomes.len() as f64;
+    }
+}
@@ -0,0 +1,302 @@
+//! Per-session application keys
+//!
+//! Payloads that leave the DTLS connection, e.g. forwarded to another room or
+//! written to a recording, lose its protection. Signing or encrypting them at
+//! the application layer would need keys distributed to every rover; instead
+//! both sides agree on fresh keys for each session once they advertised
+//! [`Feature::KeyAgreement`](crate::model::control::Feature::KeyAgreement).
+//!
+//! The keys would ideally come from the DTLS exporter (RFC 5705), but str0m
+//! does not expose it. Each side sends an ephemeral X25519 public key in a
+//! [`ControlMessage::KeyShare`] over the control channel, which DTLS already
+//! protects, and the shared secret is expanded with HKDF-SHA256 over both
+//! public keys and both DTLS certificate fingerprints. The keys are thus
+//! bound to the DTLS session they were agreed in and as secret as the data
+//! it carries; a new session, e.g. after a reconnection, agrees on new ones.
+//! An ICE restart keeps the DTLS session and the keys.
+//!
+//! Each direction has its own ChaCha20-Poly1305 key for [`SessionKeys::seal`]
+//! and its own HMAC-SHA256 key for [`SessionKeys::sign`], so neither side can
+//! forge the other's messages. Both sides derive the same
+//! [`SessionKeys::id`], which can be compared out of band.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use hmac::{Hmac, Mac};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    hkdf::{KeyType, Salt, HKDF_SHA256},
+    rand::SystemRandom,
+};
+use sha2::{Digest, Sha256};
+
+use crate::model::control::ControlMessage;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// HKDF salt, naming the derivation.
+const KEY_SALT: &[u8] = b"rover-rtc session keys v1";
+
+/// Bytes of each derived key.
+const KEY_LEN: usize = 32;
+
+/// Bytes of the counter prefixed to sealed messages.
+const COUNTER_LEN: usize = 8;
+
+/// Reasons the keys of a session could not be agreed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAgreementError {
+    /// The remote's share arrived before this side sent its own, or after
+    /// the keys were agreed
+    Unexpected,
+    /// The remote's public key is invalid, or this side's own
+    InvalidShare,
+    /// The DTLS fingerprint of the remote is not known yet
+    NoFingerprint,
+}
+
+impl fmt::Display for KeyAgreementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            KeyAgreementError::Unexpected => "unexpected key share",
+            KeyAgreementError::InvalidShare => "invalid key share",
+            KeyAgreementError::NoFingerprint => "remote DTLS fingerprint unknown",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for KeyAgreementError {}
+
+/// The key agreement of one session.
+#[derive(Default)]
+pub struct KeyAgreement {
+    private: Option<EphemeralPrivateKey>,
+    public: Vec<u8>,
+}
+
+impl fmt::Debug for KeyAgreement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyAgreement")
+            .field("started", &self.is_started())
+            .finish()
+    }
+}
+
+impl KeyAgreement {
+    /// Creates an agreement that has not started.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` once this side sent its share.
+    pub fn is_started(&self) -> bool {
+        !self.public.is_empty()
+    }
+
+    /// Generates this side's ephemeral key.
+    ///
+    /// # Returns
+    ///
+    /// The share to send to the remote, or `None` if the agreement already
+    /// started or no key could be generated
+    pub fn start(&mut self) -> Option<ControlMessage> {
+        if self.is_started() {
+            return None;
+        }
+        let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new()).ok()?;
+        self.public = private.compute_public_key().ok()?.as_ref().to_vec();
+        self.private = Some(private);
+        Some(ControlMessage::KeyShare {
+            public_key: self.public.clone(),
+        })
+    }
+
+    /// Derives the session keys from the remote's share.
+    ///
+    /// # Arguments
+    ///
+    /// * `remote_share` - The public key the remote sent
+    /// * `local_fingerprint` - The digest of this side's DTLS certificate
+    /// * `remote_fingerprint` - The digest of the remote's DTLS certificate
+    ///
+    /// # Returns
+    ///
+    /// The keys, or why they could not be agreed
+    pub fn finish(
+        &mut self,
+        remote_share: &[u8],
+        local_fingerprint: &[u8],
+        remote_fingerprint: &[u8],
+    ) -> Result<SessionKeys, KeyAgreementError> {
+        let private = self.private.take().ok_or(KeyAgreementError::Unexpected)?;
+        // A reflected share would give both directions the same keys
+        if remote_share == self.public {
+            return Err(KeyAgreementError::InvalidShare);
+        }
+        let remote = UnparsedPublicKey::new(&X25519, remote_share);
+        let secret = agreement::agree_ephemeral(private, &remote, |secret| secret.to_vec())
+            .map_err(|_| KeyAgreementError::InvalidShare)?;
+
+        // Both sides order the transcript by public key, so they derive the
+        // same keys and tell the directions apart the same way
+        let local_first = self.public.as_slice() < remote_share;
+        let (first, second) = if local_first {
+            (
+                (self.public.as_slice(), local_fingerprint),
+                (remote_share, remote_fingerprint),
+            )
+        } else {
+            (
+                (remote_share, remote_fingerprint),
+                (self.public.as_slice(), local_fingerprint),
+            )
+        };
+        let info = [first.1, second.1, first.0, second.0];
+        let mut keys = [0; 4 * KEY_LEN];
+        Salt::new(HKDF_SHA256, KEY_SALT)
+            .extract(&secret)
+            .expand(&info, OkmLen(keys.len()))
+            .and_then(|okm| okm.fill(&mut keys))
+            .map_err(|_| KeyAgreementError::InvalidShare)?;
+        let (seal_first, rest) = keys.split_at(KEY_LEN);
+        let (seal_second, rest) = rest.split_at(KEY_LEN);
+        let (sign_first, sign_second) = rest.split_at(KEY_LEN);
+        let (seal_local, seal_remote, sign_local, sign_remote) = if local_first {
+            (seal_first, seal_second, sign_first, sign_second)
+        } else {
+            (seal_second, seal_first, sign_second, sign_first)
+        };
+
+        let mut transcript = Sha256::new();
+        for part in info {
+            transcript.update(part);
+        }
+        let id = transcript.finalize()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        Ok(SessionKeys {
+            id,
+            seal_local: aead_key(seal_local)?,
+            seal_remote: aead_key(seal_remote)?,
+            sign_local: sign_local.to_vec(),
+            sign_remote: sign_remote.to_vec(),
+            counter: AtomicU64::new(0),
+        })
+    }
+}
+
+/// The application keys of a session, agreed with the remote.
+pub struct SessionKeys {
+    id: String,
+    seal_local: LessSafeKey,
+    seal_remote: LessSafeKey,
+    sign_local: Vec<u8>,
+    sign_remote: Vec<u8>,
+    /// Number of messages sealed, the nonce of the next one
+    counter: AtomicU64,
+}
+
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKeys")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionKeys {
+    /// Returns the hex ID of the keys, the same on both sides.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Encrypts and authenticates a message for the remote.
+    ///
+    /// # Returns
+    ///
+    /// The message counter, the ciphertext and the authentication tag
+    pub fn seal(&self, data: &[u8]) -> Vec<u8> {
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut sealed = counter.to_be_bytes().to_vec();
+        let mut body = data.to_vec();
+        self.seal_local
+            .seal_in_place_append_tag(nonce(counter), Aad::empty(), &mut body)
+            .expect("ChaCha20-Poly1305 seals messages of any length");
+        sealed.extend(body);
+        sealed
+    }
+
+    /// Decrypts a message sealed by the remote.
+    ///
+    /// Messages are not checked for replays; add a sequence number to the
+    /// data if that matters.
+    ///
+    /// # Returns
+    ///
+    /// The message, or `None` if it was not sealed with the remote's key of
+    /// this session or was altered
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < COUNTER_LEN + CHACHA20_POLY1305.tag_len() {
+            return None;
+        }
+        let (counter, body) = sealed.split_at(COUNTER_LEN);
+        let counter = u64::from_be_bytes(counter.try_into().ok()?);
+        let mut body = body.to_vec();
+        let length = self
+            .seal_remote
+            .open_in_place(nonce(counter), Aad::empty(), &mut body)
+            .ok()?
+            .len();
+        body.truncate(length);
+        Some(body)
+    }
+
+    /// Signs a message for the remote.
+    ///
+    /// # Returns
+    ///
+    /// The HMAC-SHA256 tag of the message
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.sign_local).expect("HMAC accepts any key length");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Returns `true` if a tag is the remote's signature of a message.
+    pub fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.sign_remote).expect("HMAC accepts any key length");
+        mac.update(data);
+        mac.verify_slice(tag).is_ok()
+    }
+}
+
+/// Length of the HKDF output.
+struct OkmLen(usize);
+
+impl KeyType for OkmLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn aead_key(bytes: &[u8]) -> Result<LessSafeKey, KeyAgreementError> {
+    UnboundKey::new(&CHACHA20_POLY1305, bytes)
+        .map(LessSafeKey::new)
+        .map_err(|_| KeyAgreementError::InvalidShare)
+}
+
+/// Returns the nonce of a message: the counter, zero-padded.
+fn nonce(counter: u64) -> Nonce {
+    let mut bytes = [0; aead::NONCE_LEN];
+    bytes[aead::NONCE_LEN - COUNTER_LEN..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(bytes)
+}
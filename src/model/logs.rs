@@ -0,0 +1,339 @@
+//! Remote log retrieval protocol
+//!
+//! The server asks a peer for its recent log lines on the reliable "logs"
+//! data channel, so a misbehaving rover can be diagnosed without a shell on
+//! it. A [`LogMessage::Request`] carries the filters; the peer answers with
+//! the matching text, optionally deflate-compressed, split into
+//! [`LogMessage::Chunk`]s of at most [`CHUNK_SIZE`] bytes, or with a
+//! [`LogMessage::Refused`] if serving logs is disabled.
+
+use std::time::Instant;
+
+use bincode::config::{self, Configuration};
+use serde::{Deserialize, Serialize};
+
+use crate::model::chunked::ChunkAssembler;
+use crate::model::fragment::MAX_MESSAGE_SIZE;
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying log messages.
+pub const LOGS_CHANNEL: &str = "logs";
+
+/// Maximum number of log bytes per chunk, keeping each SCTP message small.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Severity of a log line, most severe first.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// The lowercase name of the level.
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl From<tracing::Level> for LogLevel {
+    fn from(level: tracing::Level) -> Self {
+        match level {
+            tracing::Level::ERROR => LogLevel::Error,
+            tracing::Level::WARN => LogLevel::Warn,
+            tracing::Level::INFO => LogLevel::Info,
+            tracing::Level::DEBUG => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        }
+    }
+}
+
+/// Filters selecting the log lines to fetch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogQuery {
+    /// Maximum number of lines, the newest matching ones
+    pub tail: u32,
+    /// Only lines whose message or target contain this text
+    pub contains: Option<String>,
+    /// Only lines at least as severe as this level
+    pub level: Option<LogLevel>,
+    /// Only lines logged in the last this many seconds
+    pub since_secs: Option<u32>,
+    /// Deflate-compress the text before sending it
+    pub compress: bool,
+}
+
+impl Default for LogQuery {
+    fn default() -> Self {
+        Self {
+            tail: 200,
+            contains: None,
+            level: None,
+            since_secs: None,
+            compress: true,
+        }
+    }
+}
+
+/// Messages exchanged on the logs channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum LogMessage {
+    /// Asks the peer for the log lines matching a query.
+    Request { id: u32, query: LogQuery },
+    /// A slice of the answer to a request, identified by its chunk index.
+    Chunk {
+        id: u32,
+        index: u32,
+        total: u32,
+        compressed: bool,
+        data: Vec<u8>,
+    },
+    /// The peer does not serve its logs.
+    Refused { id: u32, reason: String },
+}
+
+impl LogMessage {
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(LogMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+
+    /// Splits the answer to a request into chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the request
+    /// * `compressed` - Whether `data` is deflate-compressed
+    /// * `data` - The log text
+    ///
+    /// # Returns
+    ///
+    /// At least one chunk, even for empty text
+    pub fn chunks(id: u32, compressed: bool, data: &[u8]) -> Vec<LogMessage> {
+        let slices: Vec<&[u8]> = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(CHUNK_SIZE).collect()
+        };
+        let total = slices.len() as u32;
+        slices
+            .into_iter()
+            .enumerate()
+            .map(|(index, slice)| LogMessage::Chunk {
+                id,
+                index: index as u32,
+                total,
+                compressed,
+                data: slice.to_vec(),
+            })
+            .collect()
+    }
+}
+
+/// The complete answer to a log request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogReply {
+    /// The log text, still compressed if `compressed` is set
+    Logs { compressed: bool, data: Vec<u8> },
+    /// The peer refused the request
+    Refused(String),
+}
+
+/// Reassembles the chunked replies to log requests.
+///
+/// Replies above [`MAX_MESSAGE_SIZE`] are refused, see
+/// [`crate::model::chunked`] for the other limits.
+#[derive(Debug)]
+pub struct LogAssembler {
+    partial: ChunkAssembler<u32, bool>,
+}
+
+impl Default for LogAssembler {
+    fn default() -> Self {
+        Self {
+            partial: ChunkAssembler::new(MAX_MESSAGE_SIZE),
+        }
+    }
+}
+
+impl LogAssembler {
+    /// Creates an assembler with no reply in progress.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles a message received on the logs channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message received from the peer
+    ///
+    /// # Returns
+    ///
+    /// The request ID and its reply, once the last chunk or a refusal arrived,
+    /// or a refusal if the reply cannot be reassembled
+    pub fn handle(&mut self, message: LogMessage) -> Option<(u32, LogReply)> {
+        match message {
+            LogMessage::Request { .. } => None,
+            LogMessage::Refused { id, reason } => {
+                self.partial.remove(&id);
+                Some((id, LogReply::Refused(reason)))
+            }
+            LogMessage::Chunk {
+                id,
+                index,
+                total,
+                compressed,
+                data,
+            } => match self
+                .partial
+                .push(id, index, total, compressed, data, Instant::now())
+            {
+                Ok(reply) => {
+                    reply.map(|(compressed, data)| (id, LogReply::Logs { compressed, data }))
+                }
+                Err(e) => Some((id, LogReply::Refused(e.to_string()))),
+            },
+        }
+    }
+
+    /// Forgets a reply still in progress, e.g. once its requester gave up.
+    pub fn abandon(&mut self, id: u32) {
+        self.partial.remove(&id);
+    }
+}
+
+impl WireSchema for LogLevel {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "LogLevel",
+            "Severity of a log line, most severe first",
+            vec![
+                ("Error", "Errors", vec![]),
+                ("Warn", "Warnings", vec![]),
+                ("Info", "Informational messages", vec![]),
+                ("Debug", "Debugging details", vec![]),
+                ("Trace", "Very verbose tracing", vec![]),
+            ],
+        )
+    }
+}
+
+impl WireSchema for LogQuery {
+    fn wire_schema() -> TypeDef {
+        TypeDef::structure(
+            "LogQuery",
+            "Filters selecting the log lines to fetch",
+            vec![
+                field(
+                    "tail",
+                    WireType::U32,
+                    "Maximum number of lines, the newest matching ones",
+                ),
+                field(
+                    "contains",
+                    WireType::option(WireType::String),
+                    "Only lines whose message or target contain this text",
+                ),
+                field(
+                    "level",
+                    WireType::option(WireType::Ref { name: "LogLevel" }),
+                    "Only lines at least as severe as this level",
+                ),
+                field(
+                    "since_secs",
+                    WireType::option(WireType::U32),
+                    "Only lines logged in the last this many seconds",
+                ),
+                field(
+                    "compress",
+                    WireType::U8,
+                    "1 to deflate-compress the text before sending it, 0 otherwise",
+                ),
+            ],
+        )
+    }
+}
+
+impl WireSchema for LogMessage {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "LogMessage",
+            "Messages exchanged on the logs channel",
+            vec![
+                (
+                    "Request",
+                    "Asks the peer for the log lines matching a query",
+                    vec![
+                        field("id", WireType::U32, "Request ID echoed in the reply"),
+                        field("query", WireType::Ref { name: "LogQuery" }, "The filters"),
+                    ],
+                ),
+                (
+                    "Chunk",
+                    "A slice of the answer to a request, identified by its chunk index",
+                    vec![
+                        field("id", WireType::U32, "The request ID"),
+                        field("index", WireType::U32, "Zero-based chunk index"),
+                        field("total", WireType::U32, "Number of chunks of the answer"),
+                        field(
+                            "compressed",
+                            WireType::U8,
+                            "1 if the concatenated data is raw deflate, 0 otherwise",
+                        ),
+                        field(
+                            "data",
+                            WireType::Bytes,
+                            "UTF-8 log lines, newline-terminated",
+                        ),
+                    ],
+                ),
+                (
+                    "Refused",
+                    "The peer does not serve its logs",
+                    vec![
+                        field("id", WireType::U32, "The request ID"),
+                        field("reason", WireType::String, "Why the request was refused"),
+                    ],
+                ),
+            ],
+        )
+    }
+}
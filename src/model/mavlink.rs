@@ -0,0 +1,143 @@
+//! MAVLink framing
+//!
+//! Autopilots speak MAVLink over a serial line, a byte stream with no
+//! message boundaries. The bridge (see [`crate::bridge`]) splits the stream
+//! into frames with a [`MavlinkFramer`] and sends each frame as one message
+//! on the [`MAVLINK_CHANNEL`], so the other side can forward it as a UDP
+//! datagram to ground-control software, as MAVLink routers do.
+//!
+//! Both protocol versions are framed: v1 frames start with `0xFE`, v2 frames
+//! with `0xFD` and may carry a signature. Frames are forwarded as they are;
+//! their checksum depends on a per-message constant of the dialect, so it is
+//! left to the ground-control software and the autopilot to check. Bytes
+//! between frames, e.g. a boot banner on the serial line, are skipped.
+
+/// Label of the data channel carrying the MAVLink frames.
+pub const MAVLINK_CHANNEL: &str = "mavlink";
+
+/// Start markers of the two protocol versions.
+const STX_V1: u8 = 0xfe;
+const STX_V2: u8 = 0xfd;
+
+/// Header lengths, from the start marker to the payload.
+const HEADER_V1: usize = 6;
+const HEADER_V2: usize = 10;
+
+/// Length of the checksum, and of a v2 signature.
+const CHECKSUM_LEN: usize = 2;
+const SIGNATURE_LEN: usize = 13;
+
+/// The v2 incompatibility flag of signed frames.
+const FLAG_SIGNED: u8 = 0x01;
+
+/// The header fields of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MavlinkHeader {
+    /// 1 or 2
+    pub version: u8,
+    pub sequence: u8,
+    pub system_id: u8,
+    pub component_id: u8,
+    pub message_id: u32,
+}
+
+impl MavlinkHeader {
+    /// Parses the header of a complete frame.
+    ///
+    /// # Returns
+    ///
+    /// The header, or `None` if the frame does not start with a header
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        match *frame.first()? {
+            STX_V1 => {
+                let header = frame.get(..HEADER_V1)?;
+                Some(Self {
+                    version: 1,
+                    sequence: header[2],
+                    system_id: header[3],
+                    component_id: header[4],
+                    message_id: header[5] as u32,
+                })
+            }
+            STX_V2 => {
+                let header = frame.get(..HEADER_V2)?;
+                Some(Self {
+                    version: 2,
+                    sequence: header[4],
+                    system_id: header[5],
+                    component_id: header[6],
+                    message_id: u32::from_le_bytes([header[7], header[8], header[9], 0]),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Splits a byte stream into MAVLink frames.
+#[derive(Debug, Default)]
+pub struct MavlinkFramer {
+    buffer: Vec<u8>,
+    /// Bytes skipped while looking for a start marker
+    skipped: u64,
+}
+
+impl MavlinkFramer {
+    /// Creates a framer waiting for the first frame.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of bytes skipped between frames so far.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Adds bytes read from the stream.
+    ///
+    /// # Returns
+    ///
+    /// The frames completed by the bytes, in order; an incomplete frame is
+    /// kept until the rest arrives
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = vec![];
+        loop {
+            let start = self
+                .buffer
+                .iter()
+                .position(|b| *b == STX_V1 || *b == STX_V2)
+                .unwrap_or(self.buffer.len());
+            if start > 0 {
+                self.skipped += start as u64;
+                self.buffer.drain(..start);
+            }
+            let Some(len) = frame_len(&self.buffer) else {
+                break;
+            };
+            if self.buffer.len() < len {
+                break;
+            }
+            frames.push(self.buffer.drain(..len).collect());
+        }
+        frames
+    }
+}
+
+/// Returns the length of the frame at the start of a buffer, or `None` if
+/// the buffer is too short to tell.
+fn frame_len(buffer: &[u8]) -> Option<usize> {
+    let payload = *buffer.get(1)? as usize;
+    match buffer[0] {
+        STX_V1 => Some(HEADER_V1 + payload + CHECKSUM_LEN),
+        _ => {
+            let flags = *buffer.get(2)?;
+            let signature = if flags & FLAG_SIGNED != 0 {
+                SIGNATURE_LEN
+            } else {
+                0
+            };
+            Some(HEADER_V2 + payload + CHECKSUM_LEN + signature)
+        }
+    }
+}
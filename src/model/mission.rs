@@ -0,0 +1,438 @@
+//! Mission waypoint transfer protocol
+//!
+//! This module defines a typed, chunked protocol for uploading and downloading
+//! waypoint lists over the reliable "mission" data channel. Plans are versioned
+//! and staged on the receiving side: the active plan is only replaced once every
+//! chunk has been received and the complete list passes validation.
+
+use std::time::Instant;
+
+use bincode::config::{self, Configuration};
+use serde::{Deserialize, Serialize};
+
+use crate::model::fragment::{MAX_MESSAGE_SIZE, REASSEMBLY_TIMEOUT};
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying mission messages.
+pub const MISSION_CHANNEL: &str = "mission";
+
+/// Default number of waypoints per chunk, keeping each SCTP message small.
+pub const DEFAULT_CHUNK_SIZE: usize = 32;
+
+/// The most chunks a plan is sent in; larger transfers are rejected.
+pub const MAX_CHUNKS: u32 = u16::MAX as u32;
+
+/// The most waypoints a plan holds, keeping a staged plan within
+/// [`MAX_MESSAGE_SIZE`]; larger transfers are rejected.
+pub const MAX_WAYPOINTS: u32 = (MAX_MESSAGE_SIZE / std::mem::size_of::<Waypoint>()) as u32;
+
+/// A single navigation waypoint.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct Waypoint {
+    /// Latitude in decimal degrees
+    pub latitude: f64,
+    /// Longitude in decimal degrees
+    pub longitude: f64,
+    /// Altitude in meters
+    pub altitude: f32,
+    /// Time to hold position at the waypoint, in seconds
+    pub hold_secs: u32,
+}
+
+impl Waypoint {
+    /// Returns `true` if the coordinates are finite and within WGS84 bounds.
+    pub fn is_valid(&self) -> bool {
+        self.latitude.is_finite()
+            && self.longitude.is_finite()
+            && self.altitude.is_finite()
+            && (-90.0..=90.0).contains(&self.latitude)
+            && (-180.0..=180.0).contains(&self.longitude)
+    }
+}
+
+/// A versioned list of waypoints making up a mission.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct MissionPlan {
+    /// Monotonically increasing plan version
+    pub version: u32,
+    /// Ordered waypoints to visit
+    pub waypoints: Vec<Waypoint>,
+}
+
+/// Messages exchanged on the mission channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum MissionMessage {
+    /// Announces a new plan transfer.
+    Begin {
+        version: u32,
+        total_waypoints: u32,
+        total_chunks: u32,
+        checksum: u32,
+    },
+    /// A slice of the plan's waypoints, identified by its chunk index.
+    Chunk {
+        version: u32,
+        index: u32,
+        waypoints: Vec<Waypoint>,
+    },
+    /// All chunks have been sent; the receiver should validate and activate.
+    Commit { version: u32 },
+    /// The plan was validated and is now active.
+    Ack { version: u32 },
+    /// The plan was rejected and the previous plan remains active.
+    Reject { version: u32, reason: String },
+    /// Requests the currently active plan from the remote side.
+    RequestDownload,
+}
+
+impl MissionMessage {
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(MissionMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+}
+
+impl MissionPlan {
+    /// Splits the plan into the sequence of messages needed to transfer it.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk_size` - Maximum number of waypoints per chunk
+    ///
+    /// # Returns
+    ///
+    /// A `Begin` message, one `Chunk` per slice of waypoints, and a final `Commit`
+    pub fn to_messages(&self, chunk_size: usize) -> Vec<MissionMessage> {
+        let chunk_size = chunk_size.max(1);
+        let chunks: Vec<&[Waypoint]> = self.waypoints.chunks(chunk_size).collect();
+
+        let mut messages = Vec::with_capacity(chunks.len() + 2);
+        messages.push(MissionMessage::Begin {
+            version: self.version,
+            total_waypoints: self.waypoints.len() as u32,
+            total_chunks: chunks.len() as u32,
+            checksum: checksum(&self.waypoints),
+        });
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            messages.push(MissionMessage::Chunk {
+                version: self.version,
+                index: index as u32,
+                waypoints: chunk.to_vec(),
+            });
+        }
+        messages.push(MissionMessage::Commit {
+            version: self.version,
+        });
+
+        messages
+    }
+}
+
+/// Computes an FNV-1a checksum over the encoded waypoints.
+pub fn checksum(waypoints: &[Waypoint]) -> u32 {
+    let bytes = bincode::encode_to_vec(waypoints, BINCODE_CONFIG).expect("Serialization failed");
+    bytes.iter().fold(0x811c_9dc5, |hash: u32, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// A plan transfer in progress, not yet visible to the rest of the system.
+#[derive(Debug)]
+struct StagedPlan {
+    version: u32,
+    total_waypoints: u32,
+    checksum: u32,
+    chunks: Vec<Option<Vec<Waypoint>>>,
+    received_waypoints: usize,
+    updated: Instant,
+}
+
+/// Receiving side of the mission protocol.
+///
+/// Incoming chunks are staged until a `Commit` arrives; the active plan is then
+/// swapped atomically if validation passes, otherwise it is left untouched.
+/// Transfers above [`MAX_CHUNKS`] or [`MAX_WAYPOINTS`] are rejected, and a
+/// staged plan receiving no chunk for [`REASSEMBLY_TIMEOUT`] is dropped.
+#[derive(Debug, Default)]
+pub struct MissionReceiver {
+    active: Option<MissionPlan>,
+    staged: Option<StagedPlan>,
+    /// Accept the version of the active plan again, see [`Self::downloads`]
+    downloads: bool,
+}
+
+impl MissionReceiver {
+    /// Creates a receiver with no active plan.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a receiver for the plans downloaded from a peer.
+    ///
+    /// Unlike an upload, a download repeats the peer's active plan, so the
+    /// version already held is accepted again; only older versions are
+    /// rejected.
+    pub fn downloads() -> Self {
+        Self {
+            downloads: true,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the currently active plan, if any.
+    pub fn active(&self) -> Option<&MissionPlan> {
+        self.active.as_ref()
+    }
+
+    /// Handles an incoming mission message.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message received from the remote side
+    ///
+    /// # Returns
+    ///
+    /// The messages to send back in response (acknowledgements, rejections or
+    /// the active plan for download requests)
+    pub fn handle(&mut self, message: MissionMessage) -> Vec<MissionMessage> {
+        match message {
+            MissionMessage::Begin {
+                version,
+                total_waypoints,
+                total_chunks,
+                checksum,
+            } => {
+                if let Some(active) = &self.active {
+                    let stale = if self.downloads {
+                        version < active.version
+                    } else {
+                        version <= active.version
+                    };
+                    if stale {
+                        return vec![MissionMessage::Reject {
+                            version,
+                            reason: format!(
+                                "version {} is not newer than active version {}",
+                                version, active.version
+                            ),
+                        }];
+                    }
+                }
+                if total_chunks > MAX_CHUNKS || total_waypoints > MAX_WAYPOINTS {
+                    self.staged = None;
+                    return vec![MissionMessage::Reject {
+                        version,
+                        reason: format!(
+                            "{} waypoints in {} chunks exceed the limit of {} in {}",
+                            total_waypoints, total_chunks, MAX_WAYPOINTS, MAX_CHUNKS
+                        ),
+                    }];
+                }
+                self.staged = Some(StagedPlan {
+                    version,
+                    total_waypoints,
+                    checksum,
+                    chunks: vec![None; total_chunks as usize],
+                    received_waypoints: 0,
+                    updated: Instant::now(),
+                });
+                vec![]
+            }
+            MissionMessage::Chunk {
+                version,
+                index,
+                waypoints,
+            } => {
+                self.expire();
+                let Some(staged) = self.staged.as_mut().filter(|s| s.version == version) else {
+                    return vec![MissionMessage::Reject {
+                        version,
+                        reason: "chunk received without a matching transfer".to_string(),
+                    }];
+                };
+                let Some(slot) = staged.chunks.get_mut(index as usize) else {
+                    self.staged = None;
+                    return vec![MissionMessage::Reject {
+                        version,
+                        reason: format!("chunk index {} out of range", index),
+                    }];
+                };
+                let replaced = slot.as_ref().map_or(0, Vec::len);
+                let received = staged.received_waypoints - replaced + waypoints.len();
+                let total_waypoints = staged.total_waypoints;
+                if received > total_waypoints as usize {
+                    self.staged = None;
+                    return vec![MissionMessage::Reject {
+                        version,
+                        reason: format!(
+                            "more than the announced {} waypoints received",
+                            total_waypoints
+                        ),
+                    }];
+                }
+                *slot = Some(waypoints);
+                staged.received_waypoints = received;
+                staged.updated = Instant::now();
+                vec![]
+            }
+            MissionMessage::Commit { version } => {
+                self.expire();
+                match self.commit(version) {
+                    Ok(()) => vec![MissionMessage::Ack { version }],
+                    Err(reason) => vec![MissionMessage::Reject { version, reason }],
+                }
+            }
+            MissionMessage::RequestDownload => match &self.active {
+                Some(plan) => plan.to_messages(DEFAULT_CHUNK_SIZE),
+                None => vec![],
+            },
+            MissionMessage::Ack { .. } | MissionMessage::Reject { .. } => vec![],
+        }
+    }
+
+    /// Drops the staged plan if no chunk arrived for [`REASSEMBLY_TIMEOUT`].
+    fn expire(&mut self) {
+        if self
+            .staged
+            .as_ref()
+            .is_some_and(|s| s.updated.elapsed() >= REASSEMBLY_TIMEOUT)
+        {
+            self.staged = None;
+        }
+    }
+
+    /// Validates the staged plan and makes it the active one.
+    fn commit(&mut self, version: u32) -> Result<(), String> {
+        let staged = self
+            .staged
+            .take()
+            .filter(|s| s.version == version)
+            .ok_or_else(|| "commit received without a matching transfer".to_string())?;
+
+        let mut waypoints = Vec::with_capacity(staged.total_waypoints as usize);
+        for (index, chunk) in staged.chunks.into_iter().enumerate() {
+            let chunk = chunk.ok_or_else(|| format!("chunk {} missing", index))?;
+            waypoints.extend(chunk);
+        }
+
+        if waypoints.len() != staged.total_waypoints as usize {
+            return Err(format!(
+                "expected {} waypoints, received {}",
+                staged.total_waypoints,
+                waypoints.len()
+            ));
+        }
+        if checksum(&waypoints) != staged.checksum {
+            return Err("checksum mismatch".to_string());
+        }
+        if let Some(index) = waypoints.iter().position(|w| !w.is_valid()) {
+            return Err(format!("waypoint {} has invalid coordinates", index));
+        }
+
+        self.active = Some(MissionPlan { version, waypoints });
+        Ok(())
+    }
+}
+
+impl WireSchema for Waypoint {
+    fn wire_schema() -> TypeDef {
+        TypeDef::structure(
+            "Waypoint",
+            "A single navigation waypoint",
+            vec![
+                field("latitude", WireType::F64, "Latitude in decimal degrees"),
+                field("longitude", WireType::F64, "Longitude in decimal degrees"),
+                field("altitude", WireType::F32, "Altitude in meters"),
+                field(
+                    "hold_secs",
+                    WireType::U32,
+                    "Time to hold position at the waypoint, in seconds",
+                ),
+            ],
+        )
+    }
+}
+
+impl WireSchema for MissionMessage {
+    fn wire_schema() -> TypeDef {
+        let waypoints = || WireType::list(WireType::Ref { name: "Waypoint" });
+        TypeDef::enumeration(
+            "MissionMessage",
+            "Messages exchanged on the mission channel",
+            vec![
+                (
+                    "Begin",
+                    "Announces a new plan transfer",
+                    vec![
+                        field(
+                            "version",
+                            WireType::U32,
+                            "Monotonically increasing plan version",
+                        ),
+                        field(
+                            "total_waypoints",
+                            WireType::U32,
+                            "Number of waypoints in the plan",
+                        ),
+                        field(
+                            "total_chunks",
+                            WireType::U32,
+                            "Number of chunks that follow",
+                        ),
+                        field(
+                            "checksum",
+                            WireType::U32,
+                            "FNV-1a 32-bit hash of the encoded list of all waypoints",
+                        ),
+                    ],
+                ),
+                (
+                    "Chunk",
+                    "A slice of the plan's waypoints, identified by its chunk index",
+                    vec![
+                        field("version", WireType::U32, "The plan version"),
+                        field("index", WireType::U32, "Zero-based chunk index"),
+                        field("waypoints", waypoints(), "The waypoints of the chunk"),
+                    ],
+                ),
+                (
+                    "Commit",
+                    "All chunks have been sent; the receiver should validate and activate",
+                    vec![field("version", WireType::U32, "The plan version")],
+                ),
+                (
+                    "Ack",
+                    "The plan was validated and is now active",
+                    vec![field("version", WireType::U32, "The plan version")],
+                ),
+                (
+                    "Reject",
+                    "The plan was rejected and the previous plan remains active",
+                    vec![
+                        field("version", WireType::U32, "The plan version"),
+                        field("reason", WireType::String, "Human-readable explanation"),
+                    ],
+                ),
+                (
+                    "RequestDownload",
+                    "Requests the currently active plan from the remote side",
+                    vec![],
+                ),
+            ],
+        )
+    }
+}
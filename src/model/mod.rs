@@ -2,6 +2,87 @@
 //!
 //! This module contains the core data structures used throughout the application
 //! for managing clients, tracks, and propagated events.
+//!
+//! The wire protocol modules (batch, chunked, control, heartbeat, payload, rate, schema and the
+//! typed channel messages) are portable; the rest needs the `native` feature.
 
+pub mod announce;
+#[cfg(feature = "native")]
+pub mod audio;
+pub mod batch;
+#[cfg(feature = "native")]
+pub mod blocklist;
+#[cfg(feature = "native")]
+pub mod bonding;
+#[cfg(feature = "native")]
+pub mod broker;
+#[cfg(feature = "native")]
+pub mod bus;
+pub mod capture;
+#[cfg(feature = "native")]
+pub mod channel;
+pub mod chunked;
+#[cfg(feature = "native")]
 pub mod client;
+pub mod control;
+pub mod coordination;
+pub mod crash;
+#[cfg(feature = "native")]
+pub mod demux;
+pub mod duplicate;
+#[cfg(feature = "native")]
+pub mod events;
+#[cfg(feature = "native")]
+pub mod failover;
+pub mod forward;
+pub mod fragment;
+#[cfg(feature = "native")]
+pub mod geofence;
+#[cfg(feature = "native")]
+pub mod health;
+pub mod heartbeat;
+#[cfg(feature = "native")]
+pub mod keys;
+pub mod logs;
+pub mod mavlink;
+pub mod mission;
+#[cfg(feature = "native")]
+pub mod outbound;
 pub mod payload;
+#[cfg(feature = "native")]
+pub mod propagated;
+#[cfg(feature = "native")]
+pub mod provisioning;
+pub mod rate;
+#[cfg(feature = "native")]
+pub mod reconnect;
+#[cfg(feature = "native")]
+pub mod recording;
+#[cfg(feature = "native")]
+pub mod registry;
+#[cfg(feature = "native")]
+pub mod routing;
+#[cfg(feature = "native")]
+pub mod rules;
+#[cfg(feature = "native")]
+pub mod schedule;
+#[cfg(feature = "native")]
+pub mod scheduler;
+pub mod schema;
+pub mod session;
+#[cfg(feature = "native")]
+pub mod setup;
+#[cfg(feature = "native")]
+pub mod signaling;
+#[cfg(feature = "native")]
+pub mod stats;
+#[cfg(feature = "native")]
+pub mod subscription;
+pub mod telemetry;
+#[cfg(feature = "native")]
+pub mod tracks;
+pub mod transfer;
+#[cfg(feature = "native")]
+pub mod transport;
+#[cfg(feature = "native")]
+pub mod video;
@@ -0,0 +1,198 @@
+//! Outbound queues of the server's data channels
+//!
+//! Writing to a data channel never refuses data while the connection is
+//! congested: the SCTP send buffer just grows, and a control message written
+//! after a burst waits behind all of it. A write only fails while the channel
+//! cannot take data at all, e.g. before its stream is re-established, and the
+//! data would then be lost.
+//!
+//! The server's [`crate::model::client::Client`] therefore queues messages per
+//! channel while the channel's buffered amount is above
+//! [`SendQueueConfig::buffered_limit`] or its last write failed, and writes
+//! them in order once the buffer drains. A full queue drops its oldest
+//! message, so a long outage loses the stalest data first. Channels configured
+//! to coalesce keep only their newest message: a queued telemetry reading is
+//! worthless once the next one is there. A message whose write fails is
+//! retried, reported or dropped according to its channel's
+//! [`WriteRetry`](crate::model::channel::WriteRetry) policy.
+//!
+//! Each queue belongs to its channel's [`QosClass`]. A message is only
+//! written while no queue of a higher class holds messages, and, below the
+//! control class, while all channels together buffer less than the limit, so
+//! a command never waits behind a burst of bulk data in SCTP.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::channel::QosClass;
+use crate::model::telemetry::TELEMETRY_CHANNEL;
+
+/// Settings of the outbound queues, the `[server.send_queue]` section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendQueueConfig {
+    /// Messages each channel may queue before the oldest are dropped
+    pub capacity: usize,
+    /// Bytes a channel may have buffered in SCTP before further messages
+    /// are queued instead of written; below the control class, the limit of
+    /// all channels together
+    pub buffered_limit: usize,
+    /// Labels of the channels whose queued messages are superseded by newer
+    /// ones, e.g. telemetry readings
+    pub coalesce: Vec<String>,
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            buffered_limit: 64 * 1024,
+            coalesce: vec![TELEMETRY_CHANNEL.to_string()],
+        }
+    }
+}
+
+impl SendQueueConfig {
+    /// Checks that the queues can hold and release messages.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capacity == 0 {
+            return Err("capacity must be at least 1".into());
+        }
+        if self.buffered_limit == 0 {
+            return Err("buffered_limit must be positive".into());
+        }
+        Ok(())
+    }
+
+    /// Creates the queue of a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel
+    /// * `class` - The QoS class of the channel
+    pub fn queue(&self, label: &str, class: QosClass) -> OutboundQueue {
+        OutboundQueue::new(self.capacity, self.coalesce.iter().any(|l| l == label))
+            .with_class(class)
+    }
+}
+
+/// A message waiting to be written to a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundMessage {
+    /// Whether the message is binary rather than text
+    pub binary: bool,
+    /// The message
+    pub data: Vec<u8>,
+    /// The number of failed writes of the message
+    pub attempts: u32,
+}
+
+/// The messages waiting to be written to one channel.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    messages: VecDeque<OutboundMessage>,
+    capacity: usize,
+    coalesce: bool,
+    class: QosClass,
+    /// Whether messages were dropped since the queue was last empty
+    overflowed: bool,
+}
+
+impl OutboundQueue {
+    /// Creates an empty queue of the telemetry class.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The number of messages it holds before dropping the oldest
+    /// * `coalesce` - Keep only the newest message
+    pub fn new(capacity: usize, coalesce: bool) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            capacity: capacity.max(1),
+            coalesce,
+            class: QosClass::Telemetry,
+            overflowed: false,
+        }
+    }
+
+    /// Sets the QoS class of the queue's channel.
+    pub fn with_class(mut self, class: QosClass) -> Self {
+        self.class = class;
+        self
+    }
+
+    /// Returns the QoS class of the queue's channel.
+    pub fn class(&self) -> QosClass {
+        self.class
+    }
+
+    /// Queues a message behind the others.
+    ///
+    /// # Returns
+    ///
+    /// The number of older messages discarded to make room for it
+    pub fn push(&mut self, message: OutboundMessage) -> usize {
+        let discarded = if self.coalesce {
+            let superseded = self.messages.len();
+            self.messages.clear();
+            superseded
+        } else if self.messages.len() >= self.capacity {
+            self.messages.pop_front();
+            self.overflowed = true;
+            1
+        } else {
+            0
+        };
+        self.messages.push_back(message);
+        discarded
+    }
+
+    /// Returns the message to write next.
+    pub fn front(&self) -> Option<&OutboundMessage> {
+        self.messages.front()
+    }
+
+    /// Returns the message to write next, to count a failed write.
+    pub fn front_mut(&mut self) -> Option<&mut OutboundMessage> {
+        self.messages.front_mut()
+    }
+
+    /// Removes the message returned by [`OutboundQueue::front`] once written.
+    pub fn pop_front(&mut self) -> Option<OutboundMessage> {
+        let message = self.messages.pop_front();
+        if self.messages.is_empty() {
+            self.overflowed = false;
+        }
+        message
+    }
+
+    /// Returns `true` if the queue dropped messages because it was full,
+    /// since it was last empty; superseded messages of a coalescing queue do
+    /// not count.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Returns the number of queued messages.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Returns `true` if no message is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Discards all queued messages.
+    ///
+    /// # Returns
+    ///
+    /// The number of discarded messages
+    pub fn clear(&mut self) -> usize {
+        let discarded = self.messages.len();
+        self.messages.clear();
+        self.overflowed = false;
+        discarded
+    }
+}
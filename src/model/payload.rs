@@ -3,9 +3,199 @@ use serde::{Deserialize, Serialize};
 
 use bincode::config::{self, Configuration};
 
+use crate::error::RoverRtcError;
+use crate::model::control::Negotiation;
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
 const BINCODE_CONFIG: Configuration = config::standard();
 
-#[derive(Debug, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+/// First byte of an enveloped payload.
+///
+/// A legacy payload starts with the bincode varint length of its data, and
+/// `0xFF` is never the first byte of a varint, so the two formats can be told
+/// apart from the first byte.
+pub const ENVELOPE_MARKER: u8 = 0xFF;
+
+/// Version of the envelope format, following the marker.
+pub const ENVELOPE_VERSION: u8 = 2;
+
+/// Version of the first envelope format, which wraps a bare [`Payload`]
+/// without an [`Envelope`] header; still decoded, and sent to peers that
+/// agreed on protocol v1.
+pub const ENVELOPE_V1: u8 = 1;
+
+/// The oldest protocol version whose peers decode [`ENVELOPE_VERSION`].
+const ENVELOPE_PROTOCOL_VERSION: u16 = 2;
+
+/// How payloads are framed on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Marker and version byte, then the bincode [`Envelope`]
+    Envelope,
+    /// Marker and version 1, then the bincode payload
+    EnvelopeV1,
+    /// The bare bincode payload spoken by fielded rovers
+    Legacy,
+}
+
+impl WireFormat {
+    /// Chooses the format to send to a peer.
+    ///
+    /// Envelopes are only sent once the peer completed the protocol handshake;
+    /// until then, or if it fell back after a version mismatch, it may be an
+    /// old rover, and gets legacy payloads if interop is enabled. Peers that
+    /// agreed on protocol v1 get the first envelope format.
+    ///
+    /// # Arguments
+    ///
+    /// * `protocol` - The outcome of the session's version negotiation
+    /// * `legacy_interop` - Whether legacy payloads are exchanged at all
+    pub fn for_session(protocol: Negotiation, legacy_interop: bool) -> Self {
+        match protocol {
+            Negotiation::Agreed { version } if version >= ENVELOPE_PROTOCOL_VERSION => {
+                WireFormat::Envelope
+            }
+            Negotiation::Agreed { .. } => WireFormat::EnvelopeV1,
+            _ if legacy_interop => WireFormat::Legacy,
+            _ => WireFormat::EnvelopeV1,
+        }
+    }
+}
+
+/// What an enveloped message carries, so receivers can route it without
+/// looking into the payload.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// Opaque application data; also the kind of payloads without a header
+    #[default]
+    Data,
+    /// A telemetry sample
+    Telemetry,
+    /// A command to execute
+    Command,
+    /// The acknowledgement of a command
+    Ack,
+    /// A liveness signal
+    Heartbeat,
+}
+
+impl MessageKind {
+    /// All kinds, in wire order.
+    pub const ALL: [MessageKind; 5] = [
+        MessageKind::Data,
+        MessageKind::Telemetry,
+        MessageKind::Command,
+        MessageKind::Ack,
+        MessageKind::Heartbeat,
+    ];
+
+    /// The name used in logs and the browser bindings.
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageKind::Data => "data",
+            MessageKind::Telemetry => "telemetry",
+            MessageKind::Command => "command",
+            MessageKind::Ack => "ack",
+            MessageKind::Heartbeat => "heartbeat",
+        }
+    }
+
+    /// Looks a kind up by its name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        MessageKind::ALL.into_iter().find(|k| k.name() == name)
+    }
+}
+
+/// A payload with the header of the current envelope format.
+///
+/// The sequence number counts the messages a sender sent on a channel, so
+/// receivers can detect gaps and reordering on unreliable channels; the topic
+/// is the label of the channel, or a finer subject within it.
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct Envelope {
+    /// What the payload carries
+    pub kind: MessageKind,
+    /// Position of the message in the sender's stream
+    pub sequence: u64,
+    /// The topic of the message
+    pub topic: String,
+    /// The timestamped application data
+    pub payload: Payload,
+}
+
+impl Envelope {
+    /// Wraps a payload.
+    pub fn new(kind: MessageKind, topic: &str, sequence: u64, payload: Payload) -> Self {
+        Self {
+            kind,
+            sequence,
+            topic: topic.to_string(),
+            payload,
+        }
+    }
+
+    /// Encodes the message in the given wire format.
+    ///
+    /// The older formats carry the payload alone; the header is dropped.
+    pub fn encode(&self, format: WireFormat) -> Vec<u8> {
+        match format {
+            WireFormat::Envelope => {
+                let body =
+                    bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed");
+                [vec![ENVELOPE_MARKER, ENVELOPE_VERSION], body].concat()
+            }
+            WireFormat::EnvelopeV1 => [
+                vec![ENVELOPE_MARKER, ENVELOPE_V1],
+                Payload::serialize(self.payload.clone()),
+            ]
+            .concat(),
+            WireFormat::Legacy => Payload::serialize(self.payload.clone()),
+        }
+    }
+
+    /// Decodes a message in any wire format.
+    ///
+    /// Payloads in the older formats get an empty header: kind
+    /// [`MessageKind::Data`], sequence `0` and no topic.
+    ///
+    /// # Returns
+    ///
+    /// * `Some((Envelope, WireFormat))` - The message and the format it arrived in
+    /// * `None` - If the bytes are not a payload, or an envelope of an unknown version
+    pub fn decode(bytes: &[u8]) -> Option<(Envelope, WireFormat)> {
+        let (body, format) = match bytes {
+            [ENVELOPE_MARKER, ENVELOPE_VERSION, body @ ..] => {
+                return decode_exact(body).map(|envelope| (envelope, WireFormat::Envelope));
+            }
+            [ENVELOPE_MARKER, ENVELOPE_V1, body @ ..] => (body, WireFormat::EnvelopeV1),
+            [ENVELOPE_MARKER, ..] => return None,
+            body => (body, WireFormat::Legacy),
+        };
+        decode_exact(body).map(|payload| (Envelope::new(MessageKind::Data, "", 0, payload), format))
+    }
+}
+
+/// Decodes a value that must span all of the bytes.
+fn decode_exact<T: bincode::Decode<()>>(bytes: &[u8]) -> Option<T> {
+    bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+        .ok()
+        .filter(|(_, read)| *read == bytes.len())
+        .map(|(value, _)| value)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub struct Payload {
     pub data: Vec<u8>,
     pub timestamp: i64,
@@ -31,13 +221,108 @@ impl Payload {
         (Utc::now() - Utc.timestamp_nanos(self.timestamp)).to_string()
     }
 
+    /// Latency since the payload was created, in milliseconds
+    pub fn latency_ms(&self) -> f64 {
+        (Utc::now().timestamp_nanos_opt().unwrap_or(0) - self.timestamp) as f64 / 1e6
+    }
+
+    /// Encodes the payload in the given wire format, as opaque data without
+    /// a topic or sequence number.
+    pub fn encode(&self, format: WireFormat) -> Vec<u8> {
+        Envelope::new(MessageKind::Data, "", 0, self.clone()).encode(format)
+    }
+
+    /// Decodes a payload in any wire format, dropping the envelope header.
+    ///
+    /// # Returns
+    ///
+    /// * `Some((Payload, WireFormat))` - The payload and the format it arrived in
+    /// * `None` - If the bytes are not a payload, or an envelope of an unknown version
+    pub fn decode(bytes: &[u8]) -> Option<(Payload, WireFormat)> {
+        Envelope::decode(bytes).map(|(envelope, format)| (envelope.payload, format))
+    }
+
     pub fn serialize(payload: Payload) -> Vec<u8> {
         bincode::encode_to_vec(payload, BINCODE_CONFIG).expect("Serialization failed")
     }
     /// Deserialize from received bytes
-    pub fn deserialize(bytes: Vec<u8>) -> Self {
-        let (payload, _): (Payload, usize) =
-            bincode::decode_from_slice(&bytes, BINCODE_CONFIG).expect("Deserialization failed");
-        payload
+    ///
+    /// # Returns
+    ///
+    /// The payload, or [`RoverRtcError::Decode`] if the bytes are not a
+    /// legacy payload
+    pub fn deserialize(bytes: Vec<u8>) -> Result<Self, RoverRtcError> {
+        let (payload, _): (Payload, usize) = bincode::decode_from_slice(&bytes, BINCODE_CONFIG)
+            .map_err(|e| RoverRtcError::Decode {
+                what: "payload",
+                reason: e.to_string(),
+            })?;
+        Ok(payload)
+    }
+}
+
+impl WireSchema for MessageKind {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "MessageKind",
+            "What an enveloped message carries",
+            vec![
+                ("Data", "Opaque application data", vec![]),
+                ("Telemetry", "A telemetry sample", vec![]),
+                ("Command", "A command to execute", vec![]),
+                ("Ack", "The acknowledgement of a command", vec![]),
+                ("Heartbeat", "A liveness signal", vec![]),
+            ],
+        )
+    }
+}
+
+impl WireSchema for Envelope {
+    fn wire_schema() -> TypeDef {
+        TypeDef::structure(
+            "Envelope",
+            "A payload with its header, following the marker and envelope version",
+            vec![
+                field(
+                    "kind",
+                    WireType::Ref {
+                        name: "MessageKind",
+                    },
+                    "What the payload carries",
+                ),
+                field(
+                    "sequence",
+                    WireType::U64,
+                    "Position of the message in the sender's stream on the channel",
+                ),
+                field(
+                    "topic",
+                    WireType::String,
+                    "The channel label, or a finer subject within it",
+                ),
+                field(
+                    "payload",
+                    WireType::Ref { name: "Payload" },
+                    "The timestamped application data",
+                ),
+            ],
+        )
+    }
+}
+
+impl WireSchema for Payload {
+    fn wire_schema() -> TypeDef {
+        TypeDef::structure(
+            "Payload",
+            "Application data, framed as described in the envelope section",
+            vec![
+                field("data", WireType::Bytes, "The application data"),
+                field(
+                    "timestamp",
+                    WireType::I64,
+                    "Send time, in nanoseconds since the Unix epoch",
+                ),
+            ],
+        )
     }
 }
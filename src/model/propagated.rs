@@ -13,6 +13,7 @@ use crate::model::{client::ClientId, tracks::TrackIn};
 ///
 /// These events enable sharing of media tracks and data between connected clients,
 /// supporting scenarios like broadcasting or relaying.
+#[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum Propagated {
     /// When we have nothing to propagate.
@@ -0,0 +1,320 @@
+//! Pre-provisioned peers of the server
+//!
+//! Setting up a session costs the server a DTLS certificate, and its ICE
+//! credentials are random, so the first STUN request of a peer identifies
+//! nothing until the event loop received the session from the signaling
+//! thread. A peer that is quick to start its connectivity checks races the
+//! handover, and its first requests are dropped with "No client accepts UDP
+//! input" and only answered once retransmitted.
+//!
+//! For the known rovers of a fleet, the `[server.provisioning]` section
+//! assigns the server's ICE credentials per identity, the subject its
+//! credentials authenticate as or the alias it announces. [`ProvisionedRtcs`]
+//! keeps an `Rtc` ready for each, with the credentials and the host
+//! candidate set, and builds the next one in the background once a session
+//! took it. As the ufrag is known ahead of time, the event loop recognizes
+//! the STUN requests of a provisioned peer whose session did not arrive yet:
+//! [`HeldPackets`] keeps them until it does, when they are replayed and the
+//! demultiplexing index learns the peer's address.
+//!
+//! A provisioned peer may also pin the DTLS fingerprint it is expected to
+//! offer, e.g. a rover keeping its certificate across restarts; offers with
+//! another one are rejected. Only a peer with a pinned fingerprint is
+//! identified by the alias it announces, otherwise by its subject alone.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use str0m::Rtc;
+use tracing::{info, warn};
+
+use crate::error::RoverRtcError;
+use crate::model::registry::is_valid_alias;
+
+/// How long packets of a provisioned peer wait for its session.
+pub const HOLD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Packets held per provisioned peer; STUN requests are retransmitted, so
+/// the first few suffice.
+pub const MAX_HELD_PACKETS: usize = 8;
+
+/// Provisioned peers of the server, the `[server.provisioning]` section.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvisioningConfig {
+    /// The provisioned peers, by the subject or alias they connect as
+    pub peers: BTreeMap<String, ProvisionedPeer>,
+}
+
+/// Settings of one provisioned peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProvisionedPeer {
+    /// ICE username fragment of the server's end of the session
+    pub ice_ufrag: String,
+    /// ICE password of the server's end of the session
+    pub ice_pwd: String,
+    /// DTLS fingerprint the peer's offer must carry, e.g.
+    /// `"sha-256 AB:CD:…"`; any is accepted if unset
+    pub fingerprint: Option<String>,
+}
+
+impl ProvisioningConfig {
+    /// Checks that the identities and credentials are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut ufrags = HashMap::new();
+        for (identity, peer) in &self.peers {
+            if !is_valid_alias(identity) {
+                return Err(format!("peers: '{}' is not a valid identity", identity));
+            }
+            // RFC 8839: ice-chars, at least 4 for the ufrag and 22 for the password
+            if !is_ice_chars(&peer.ice_ufrag, 4) {
+                return Err(format!(
+                    "peers.{}.ice_ufrag must be 4 to 256 letters, digits, '+' or '/'",
+                    identity
+                ));
+            }
+            if !is_ice_chars(&peer.ice_pwd, 22) {
+                return Err(format!(
+                    "peers.{}.ice_pwd must be 22 to 256 letters, digits, '+' or '/'",
+                    identity
+                ));
+            }
+            if let Some(other) = ufrags.insert(peer.ice_ufrag.as_str(), identity) {
+                return Err(format!(
+                    "peers.{}.ice_ufrag is also used by '{}'",
+                    identity, other
+                ));
+            }
+            if let Some(fingerprint) = &peer.fingerprint {
+                if parse_fingerprint(fingerprint).is_none() {
+                    return Err(format!(
+                        "peers.{}.fingerprint must be a hash function and hex bytes separated by ':'",
+                        identity
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the provisioned peer connecting with the given subject or
+    /// alias, the subject taking precedence.
+    ///
+    /// The alias is announced by the peer and checked by nobody, so it only
+    /// identifies a peer whose fingerprint is pinned; anyone could otherwise
+    /// take the session prepared for it by announcing its alias.
+    pub fn identify(
+        &self,
+        subject: Option<&str>,
+        alias: Option<&str>,
+    ) -> Option<(&str, &ProvisionedPeer)> {
+        subject
+            .and_then(|subject| self.peers.get_key_value(subject))
+            .or_else(|| {
+                alias
+                    .and_then(|alias| self.peers.get_key_value(alias))
+                    .filter(|(_, peer)| peer.fingerprint.is_some())
+            })
+            .map(|(identity, peer)| (identity.as_str(), peer))
+    }
+
+    /// Returns `true` if the server's ufrag belongs to a provisioned peer.
+    pub fn expects(&self, ufrag: &str) -> bool {
+        self.peers.values().any(|peer| peer.ice_ufrag == ufrag)
+    }
+}
+
+impl ProvisionedPeer {
+    /// Checks the DTLS fingerprint of an offer against the pinned one.
+    ///
+    /// # Returns
+    ///
+    /// An error naming the fingerprint offered if it does not match
+    pub fn verify_offer(&self, sdp: &str) -> Result<(), String> {
+        let Some(pinned) = self.fingerprint.as_deref().and_then(parse_fingerprint) else {
+            return Ok(());
+        };
+        let offered = sdp
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+            .find_map(parse_fingerprint);
+        match offered {
+            Some(offered) if offered == pinned => Ok(()),
+            Some((hash, bytes)) => Err(format!("unexpected fingerprint {} {}", hash, bytes)),
+            None => Err("the offer carries no fingerprint".into()),
+        }
+    }
+}
+
+/// Returns `true` if the value is `min` to 256 ice-chars.
+fn is_ice_chars(value: &str, min: usize) -> bool {
+    (min..=256).contains(&value.len())
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+}
+
+/// Parses a fingerprint into its lowercase hash function and uppercase hex
+/// bytes.
+fn parse_fingerprint(value: &str) -> Option<(String, String)> {
+    let (hash, bytes) = value.trim().split_once(' ')?;
+    let bytes = bytes.trim();
+    let valid = !hash.is_empty()
+        && bytes
+            .split(':')
+            .all(|b| b.len() == 2 && b.chars().all(|c| c.is_ascii_hexdigit()));
+    valid.then(|| (hash.to_ascii_lowercase(), bytes.to_ascii_uppercase()))
+}
+
+/// Builds the `Rtc` of a provisioned peer, with its credentials and the
+/// host candidate.
+pub type RtcBuilder = dyn Fn(&ProvisionedPeer) -> Result<Rtc, RoverRtcError> + Send + Sync;
+
+/// An `Rtc` kept ready for each provisioned peer.
+#[derive(Clone)]
+pub struct ProvisionedRtcs {
+    config: ProvisioningConfig,
+    build: Arc<RtcBuilder>,
+    ready: Arc<Mutex<HashMap<String, Rtc>>>,
+}
+
+impl ProvisionedRtcs {
+    /// Starts building an `Rtc` for each provisioned peer in the background.
+    pub fn new(config: &ProvisioningConfig, build: Arc<RtcBuilder>) -> Self {
+        let rtcs = Self {
+            config: config.clone(),
+            build,
+            ready: Arc::default(),
+        };
+        for identity in config.peers.keys() {
+            rtcs.refill(identity);
+        }
+        rtcs
+    }
+
+    /// Returns the settings of the provisioned peer connecting with the
+    /// given subject or alias.
+    pub fn identify(
+        &self,
+        subject: Option<&str>,
+        alias: Option<&str>,
+    ) -> Option<(&str, &ProvisionedPeer)> {
+        self.config.identify(subject, alias)
+    }
+
+    /// Takes the `Rtc` ready for a provisioned peer and builds the next one
+    /// in the background.
+    ///
+    /// # Returns
+    ///
+    /// The `Rtc`, built right away if the previous session took the last one
+    /// moments ago
+    pub fn take(&self, identity: &str) -> Result<Rtc, RoverRtcError> {
+        let ready = self
+            .ready
+            .lock()
+            .expect("provisioning lock")
+            .remove(identity);
+        let rtc = match ready {
+            Some(rtc) => rtc,
+            None => {
+                let peer = self.config.peers.get(identity).ok_or_else(|| {
+                    RoverRtcError::Webrtc(format!("'{}' is not provisioned", identity))
+                })?;
+                info!(
+                    "Provisioning: No session ready for '{}', building one",
+                    identity
+                );
+                (self.build)(peer)?
+            }
+        };
+        self.refill(identity);
+        Ok(rtc)
+    }
+
+    /// Builds an `Rtc` for a provisioned peer in the background.
+    fn refill(&self, identity: &str) {
+        let Some(peer) = self.config.peers.get(identity).cloned() else {
+            return;
+        };
+        let identity = identity.to_string();
+        let build = self.build.clone();
+        let ready = self.ready.clone();
+        thread::spawn(move || match build(&peer) {
+            Ok(rtc) => {
+                ready
+                    .lock()
+                    .expect("provisioning lock")
+                    .entry(identity)
+                    .or_insert(rtc);
+            }
+            Err(e) => warn!(
+                "Provisioning: Building the session of '{}': {}",
+                identity, e
+            ),
+        });
+    }
+}
+
+/// A datagram received before the session it belongs to.
+#[derive(Debug, Clone)]
+pub struct HeldPacket {
+    pub received: Instant,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// Packets of provisioned peers waiting for their sessions, by the server's
+/// ufrag.
+#[derive(Debug, Default)]
+pub struct HeldPackets {
+    packets: HashMap<String, Vec<HeldPacket>>,
+}
+
+impl HeldPackets {
+    /// Creates an empty set of held packets.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Holds a packet for the session with the given ufrag.
+    ///
+    /// # Returns
+    ///
+    /// `false` if [`MAX_HELD_PACKETS`] are already waiting for the session
+    pub fn hold(&mut self, ufrag: &str, packet: HeldPacket) -> bool {
+        let held = self.packets.entry(ufrag.to_string()).or_default();
+        if held.len() >= MAX_HELD_PACKETS {
+            return false;
+        }
+        held.push(packet);
+        true
+    }
+
+    /// Takes the packets waiting for the session with the given ufrag, in
+    /// the order they arrived.
+    pub fn take(&mut self, ufrag: &str) -> Vec<HeldPacket> {
+        self.packets.remove(ufrag).unwrap_or_default()
+    }
+
+    /// Drops the packets held longer than [`HOLD_TIMEOUT`].
+    pub fn prune(&mut self, now: Instant) {
+        self.packets.retain(|_, held| {
+            held.retain(|p| now.duration_since(p.received) < HOLD_TIMEOUT);
+            !held.is_empty()
+        });
+    }
+
+    /// Returns `true` if no packet is held.
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
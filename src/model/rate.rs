@@ -0,0 +1,187 @@
+//! Subscriber-driven publishing rates
+//!
+//! Rovers publish some topics faster than most consumers need: an operator UI
+//! refreshing a map wants GPS at 1 Hz, while a recorder for autonomy replay
+//! wants every sample at 50 Hz. A topic is the label of the channel its
+//! samples are published on. Receivers declare the rate they want for a
+//! topic; each side sends the highest rate its receivers want in a
+//! [`ControlMessage::RequestRate`](crate::model::control::ControlMessage::RequestRate),
+//! once both sides advertised
+//! [`Feature::RateControl`](crate::model::control::Feature::RateControl).
+//!
+//! The sender publishes a topic at the rate requested by the remote, or at
+//! its configured default rate while nothing was requested, dropping the
+//! samples in between. Topics without either are published unthrottled.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Publishing rate settings, the `[peer.rate_control]` configuration section.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateControlConfig {
+    /// Rate in Hz at which each topic is published while no receiver
+    /// requested one
+    pub default_hz: BTreeMap<String, f64>,
+}
+
+impl RateControlConfig {
+    /// Checks that every rate is positive.
+    pub fn validate(&self) -> Result<(), String> {
+        match self
+            .default_hz
+            .iter()
+            .find(|(_, hz)| !(hz.is_finite() && **hz > 0.0))
+        {
+            Some((topic, hz)) => Err(format!("default_hz.{} must be positive, got {}", topic, hz)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Converts a rate in Hz to the millihertz sent on the wire.
+pub fn to_millihertz(hz: f64) -> u32 {
+    (hz * 1000.0).round().clamp(0.0, f64::from(u32::MAX)) as u32
+}
+
+/// Converts a rate received on the wire to Hz.
+pub fn from_millihertz(millihertz: u32) -> f64 {
+    f64::from(millihertz) / 1000.0
+}
+
+/// Enforces the publishing rate of each topic on the sending side.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    defaults: HashMap<String, f64>,
+    requested: HashMap<String, f64>,
+    last_sent: HashMap<String, Instant>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter publishing at the configured default rates.
+    pub fn new(config: &RateControlConfig) -> Self {
+        Self {
+            defaults: config.default_hz.clone().into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Applies the rate the remote requested for a topic.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic, i.e. the channel label
+    /// * `millihertz` - The requested rate; `0` withdraws the request
+    pub fn set_requested(&mut self, topic: &str, millihertz: u32) {
+        if millihertz == 0 {
+            self.requested.remove(topic);
+        } else {
+            self.requested
+                .insert(topic.to_string(), from_millihertz(millihertz));
+        }
+    }
+
+    /// Returns the rate a topic is published at, or `None` if unthrottled.
+    pub fn rate(&self, topic: &str) -> Option<f64> {
+        self.requested
+            .get(topic)
+            .or_else(|| self.defaults.get(topic))
+            .copied()
+    }
+
+    /// Decides whether a sample is published or dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The topic, i.e. the channel label
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// `true` if the sample is due, which then counts as sent
+    pub fn admit(&mut self, topic: &str, now: Instant) -> bool {
+        let Some(hz) = self.rate(topic) else {
+            return true;
+        };
+        let interval = Duration::from_secs_f64(1.0 / hz);
+        match self.last_sent.get(topic) {
+            Some(last) if now.duration_since(*last) < interval => false,
+            _ => {
+                self.last_sent.insert(topic.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+/// Collects the rates receivers want on the receiving side.
+///
+/// Each receiver, e.g. an operator UI or a recorder, declares its own rate
+/// per topic; the remote is asked for the highest one.
+#[derive(Debug, Clone, Default)]
+pub struct RateDemand {
+    requests: BTreeMap<String, BTreeMap<String, u32>>,
+    sent: BTreeMap<String, u32>,
+}
+
+impl RateDemand {
+    /// Creates an empty demand.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares or withdraws the rate a receiver wants for a topic.
+    ///
+    /// # Arguments
+    ///
+    /// * `receiver` - Name of the receiver, e.g. `operator-ui`
+    /// * `topic` - The topic, i.e. the channel label
+    /// * `hz` - The wanted rate, or `None` to withdraw the receiver's request
+    pub fn request(&mut self, receiver: &str, topic: &str, hz: Option<f64>) {
+        let receivers = self.requests.entry(topic.to_string()).or_default();
+        match hz.map(to_millihertz).filter(|mhz| *mhz > 0) {
+            Some(millihertz) => {
+                receivers.insert(receiver.to_string(), millihertz);
+            }
+            None => {
+                receivers.remove(receiver);
+            }
+        }
+    }
+
+    /// Returns the rate to request for a topic, in millihertz; `0` if no
+    /// receiver wants one.
+    pub fn wanted(&self, topic: &str) -> u32 {
+        self.requests
+            .get(topic)
+            .and_then(|receivers| receivers.values().max().copied())
+            .unwrap_or(0)
+    }
+
+    /// Returns the requests that changed since they were last taken, as
+    /// topics and rates in millihertz, and marks them as sent.
+    pub fn take_changes(&mut self) -> Vec<(String, u32)> {
+        let mut changes = vec![];
+        for topic in self.requests.keys() {
+            let wanted = self.wanted(topic);
+            if self.sent.get(topic).copied().unwrap_or(0) != wanted {
+                changes.push((topic.clone(), wanted));
+            }
+        }
+        for (topic, wanted) in &changes {
+            self.sent.insert(topic.clone(), *wanted);
+        }
+        self.requests.retain(|_, receivers| !receivers.is_empty());
+        self.sent.retain(|_, mhz| *mhz > 0);
+        changes
+    }
+
+    /// Forgets what was sent, so every request is sent again to a new remote.
+    pub fn reset(&mut self) {
+        self.sent.clear();
+    }
+}
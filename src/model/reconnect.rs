@@ -0,0 +1,154 @@
+//! Automatic reconnection of the peer
+//!
+//! A rover driving out of coverage loses its connection for longer than an
+//! ICE restart can bridge, and the signaling server may be restarting when it
+//! comes back. Instead of giving up, the peer runs signaling again after an
+//! exponentially growing delay with random jitter, so a fleet reconnecting to
+//! a restarted server does not hit it all at once. The new session opens the
+//! same data channels, and the time from losing the connection to restoring
+//! it is reported as the outage.
+
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Reconnection settings, the `[peer.reconnect]` configuration section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    /// Reconnect after the connection is lost or signaling fails
+    pub enabled: bool,
+    /// Delay before the first attempt, in milliseconds
+    pub initial_delay_ms: u64,
+    /// Upper bound of the doubling delay, in milliseconds
+    pub max_delay_ms: u64,
+    /// Fraction of the delay added or removed at random, from 0 to 1
+    pub jitter: f64,
+    /// Attempts without a restored connection before giving up; retries
+    /// forever if `None`
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initial_delay_ms: 500,
+            max_delay_ms: 30_000,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Checks that the delays and jitter are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.initial_delay_ms == 0 {
+            return Err("initial_delay_ms must be at least 1".into());
+        }
+        if self.max_delay_ms < self.initial_delay_ms {
+            return Err("max_delay_ms must not be less than initial_delay_ms".into());
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err("jitter must be between 0 and 1".into());
+        }
+        Ok(())
+    }
+
+    /// Returns the delay before an attempt, without jitter.
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt` - The attempt number, starting at 1
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(32);
+        Duration::from_millis(
+            self.initial_delay_ms
+                .saturating_mul(factor)
+                .min(self.max_delay_ms),
+        )
+    }
+}
+
+/// A lost connection that was restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outage {
+    /// Time from losing the connection to restoring it
+    pub duration: Duration,
+    /// Reconnection attempts it took
+    pub attempts: u32,
+}
+
+/// Tracks connection losses and schedules reconnection attempts.
+#[derive(Debug)]
+pub struct Reconnection {
+    config: ReconnectConfig,
+    connected: bool,
+    lost_at: Option<Instant>,
+    attempts: u32,
+}
+
+impl Reconnection {
+    /// Creates the state of a peer that has not connected yet.
+    pub fn new(config: ReconnectConfig) -> Self {
+        Self {
+            config,
+            connected: false,
+            lost_at: None,
+            attempts: 0,
+        }
+    }
+
+    /// Records that a session ended without being stopped.
+    ///
+    /// The outage starts here if the session was connected; failures to
+    /// connect in the first place only count as attempts.
+    pub fn lost(&mut self, now: Instant) {
+        if self.connected {
+            self.connected = false;
+            self.lost_at = Some(now);
+        }
+    }
+
+    /// Schedules the next attempt.
+    ///
+    /// # Returns
+    ///
+    /// * `Some((attempt, delay))` - The attempt number and how long to wait first
+    /// * `None` - If reconnection is disabled or the attempts are exhausted
+    pub fn next_attempt(&mut self) -> Option<(u32, Duration)> {
+        if !self.config.enabled
+            || self
+                .config
+                .max_attempts
+                .is_some_and(|max| self.attempts >= max)
+        {
+            return None;
+        }
+        self.attempts += 1;
+        let delay = self.config.base_delay(self.attempts);
+        let jitter = self.config.jitter;
+        let scale = if jitter > 0.0 {
+            rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+        } else {
+            1.0
+        };
+        Some((self.attempts, delay.mul_f64(scale)))
+    }
+
+    /// Records that a session connected and resets the backoff.
+    ///
+    /// # Returns
+    ///
+    /// The outage this ends, if the connection had been lost before
+    pub fn restored(&mut self, now: Instant) -> Option<Outage> {
+        self.connected = true;
+        let attempts = std::mem::take(&mut self.attempts);
+        self.lost_at.take().map(|lost_at| Outage {
+            duration: now.duration_since(lost_at),
+            attempts,
+        })
+    }
+}
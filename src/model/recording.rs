@@ -0,0 +1,309 @@
+//! Recorded sessions and their replay
+//!
+//! A recording is a JSON Lines file where each line is a [`RecordedMessage`]
+//! captured from a data channel. A [`Recorder`] writes the messages the peer
+//! or the server sends and receives to such a file, rotating it at a size
+//! limit. A [`ReplaySession`] plays a recording back into a room, releasing
+//! each message once its original offset (scaled by a speed factor) has
+//! elapsed, so operator tooling sees it as a live rover.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Direction of a recorded message relative to the recording side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Received from the remote side
+    Inbound,
+    /// Sent to the remote side
+    Outbound,
+}
+
+/// A single data channel message captured in a recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Capture time, in nanoseconds since the Unix epoch
+    pub at: i64,
+    /// Whether the message was received or sent
+    pub direction: Direction,
+    /// Label of the channel the message was carried on
+    pub channel: String,
+    /// The client the server exchanged the message with, by alias or ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// The raw message bytes
+    pub data: Vec<u8>,
+}
+
+/// Recording of data channel traffic, the `[peer.recording]` and
+/// `[server.recording]` sections.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecorderConfig {
+    /// Record the messages sent and received
+    pub enabled: bool,
+    /// The recording file; rotated files get a `.1`, `.2`, ... suffix
+    pub path: PathBuf,
+    /// Size at which the file is rotated, in bytes
+    pub max_bytes: u64,
+    /// Rotated files kept besides the current one
+    pub max_files: usize,
+    /// Labels of the channels to record; all of them if empty
+    pub channels: Vec<String>,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("recording.jsonl"),
+            max_bytes: 64 * 1024 * 1024,
+            max_files: 4,
+            channels: vec![],
+        }
+    }
+}
+
+impl RecorderConfig {
+    /// Checks that the limits are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.path.as_os_str().is_empty() {
+            return Err("path must not be empty".into());
+        }
+        if self.max_bytes == 0 {
+            return Err("max_bytes must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+/// Writes the messages handed to it to a rotating recording file.
+///
+/// Messages are written by a background thread, so recording never blocks
+/// the event loop; the thread ends once every clone of the recorder is
+/// dropped.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    channels: Arc<HashSet<String>>,
+    messages: mpsc::Sender<RecordedMessage>,
+}
+
+impl Recorder {
+    /// Opens the recording file and starts the writer thread.
+    ///
+    /// # Returns
+    ///
+    /// The recorder, `None` if recording is disabled, or an error if the file
+    /// could not be opened
+    pub fn start(config: &RecorderConfig) -> io::Result<Option<Recorder>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let mut file = RotatingFile::open(config)?;
+        info!(
+            "Recording data channel traffic to {}",
+            config.path.display()
+        );
+        let (tx, rx) = mpsc::channel::<RecordedMessage>();
+        thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || {
+                while let Ok(message) = rx.recv() {
+                    let result = std::iter::once(message)
+                        .chain(rx.try_iter())
+                        .try_for_each(|message| file.write(&message))
+                        .and_then(|_| file.flush());
+                    if let Err(e) = result {
+                        warn!("Recording stopped: {}", e);
+                        return;
+                    }
+                }
+            })?;
+        Ok(Some(Recorder {
+            channels: Arc::new(config.channels.iter().cloned().collect()),
+            messages: tx,
+        }))
+    }
+
+    /// Records a message, if its channel is recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `direction` - Whether the message was received or sent
+    /// * `channel` - Label of the channel
+    /// * `client` - The client the server exchanged it with, if any
+    /// * `data` - The message
+    pub fn record(&self, direction: Direction, channel: &str, client: Option<&str>, data: &[u8]) {
+        if !self.channels.is_empty() && !self.channels.contains(channel) {
+            return;
+        }
+        // Fails only once the writer gave up, which it logged
+        let _ = self.messages.send(RecordedMessage {
+            at: Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            direction,
+            channel: channel.to_string(),
+            client: client.map(str::to_string),
+            data: data.to_vec(),
+        });
+    }
+}
+
+/// A recording file rotated at a size limit.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(config: &RecorderConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        Ok(Self {
+            path: config.path.clone(),
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+            size: file.metadata()?.len(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn write(&mut self, message: &RecordedMessage) -> io::Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.writer.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Shifts the rotated files by one, dropping the oldest, and starts an
+    /// empty file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.writer = BufWriter::new(File::create(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Reads a recording from a JSON Lines file.
+///
+/// Blank lines are skipped; messages are sorted by capture time.
+///
+/// # Arguments
+///
+/// * `path` - Path of the recording file
+pub fn read_recording(path: &Path) -> anyhow::Result<Vec<RecordedMessage>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut messages = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        messages.push(serde_json::from_str::<RecordedMessage>(&line)?);
+    }
+    messages.sort_by_key(|m| m.at);
+    Ok(messages)
+}
+
+/// Playback of a recording into a room.
+#[derive(Debug)]
+pub struct ReplaySession {
+    /// The room receiving the replayed messages
+    pub room: String,
+    messages: Vec<RecordedMessage>,
+    next: usize,
+    speed: f64,
+    started: Instant,
+}
+
+impl ReplaySession {
+    /// Creates a replay of the inbound messages of a recording.
+    ///
+    /// Only inbound messages are replayed, since those are what the rover sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `room` - The room receiving the replayed messages
+    /// * `messages` - The recorded messages, sorted by capture time
+    /// * `speed` - Playback speed factor; `1.0` respects the original timing
+    pub fn new(room: String, messages: Vec<RecordedMessage>, speed: f64) -> Self {
+        let messages = messages
+            .into_iter()
+            .filter(|m| m.direction == Direction::Inbound)
+            .collect();
+        Self {
+            room,
+            messages,
+            next: 0,
+            speed: if speed > 0.0 { speed } else { 1.0 },
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns the messages whose playback time has been reached.
+    pub fn due(&mut self, now: Instant) -> Vec<RecordedMessage> {
+        let Some(first) = self.messages.first().map(|m| m.at) else {
+            return vec![];
+        };
+        let elapsed = now.duration_since(self.started).as_secs_f64() * self.speed;
+
+        let mut due = vec![];
+        while let Some(message) = self.messages.get(self.next) {
+            let offset = Duration::from_nanos(message.at.saturating_sub(first).max(0) as u64);
+            if offset.as_secs_f64() > elapsed {
+                break;
+            }
+            due.push(message.clone());
+            self.next += 1;
+        }
+        due
+    }
+
+    /// Returns `true` once every message has been played back.
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.messages.len()
+    }
+}
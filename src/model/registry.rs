@@ -0,0 +1,126 @@
+//! Registry of connected clients and their aliases
+//!
+//! Numeric client IDs are unique but meaningless to operators. A peer may
+//! announce a human-readable alias (e.g. `rover-7`) when it signals; the
+//! registry maps aliases to IDs and back, so logs, the admin API, metrics and
+//! the send-to-client API can all address a client by either.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::model::client::ClientId;
+
+/// Maximum length of an alias.
+const MAX_ALIAS_LEN: usize = 64;
+
+/// A registered client, as listed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientEntry {
+    /// The numeric client ID
+    pub id: u64,
+    /// The client's alias, if it announced one
+    pub alias: Option<String>,
+    /// The room the client belongs to, if any
+    pub room: Option<String>,
+}
+
+/// Bidirectional mapping between client IDs and aliases.
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    entries: HashMap<ClientId, ClientEntry>,
+    by_alias: HashMap<String, ClientId>,
+}
+
+impl ClientRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a client.
+    ///
+    /// Invalid aliases are ignored. If the alias is already taken by another
+    /// client, a numeric suffix is appended (`rover-7`, `rover-7-2`, ...).
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The client's ID
+    /// * `alias` - The alias announced by the peer, if any
+    /// * `room` - The room the client belongs to, if any
+    ///
+    /// # Returns
+    ///
+    /// The alias actually assigned, if any
+    pub fn register(
+        &mut self,
+        id: ClientId,
+        alias: Option<&str>,
+        room: Option<String>,
+    ) -> Option<String> {
+        let alias = alias.filter(|a| is_valid_alias(a)).map(|a| {
+            let mut candidate = a.to_string();
+            let mut n = 2;
+            while self
+                .by_alias
+                .get(&candidate)
+                .is_some_and(|owner| *owner != id)
+            {
+                candidate = format!("{}-{}", a, n);
+                n += 1;
+            }
+            candidate
+        });
+
+        if let Some(alias) = &alias {
+            self.by_alias.insert(alias.clone(), id);
+        }
+        self.entries.insert(
+            id,
+            ClientEntry {
+                id: *id,
+                alias: alias.clone(),
+                room,
+            },
+        );
+        alias
+    }
+
+    /// Removes a client and frees its alias.
+    pub fn remove(&mut self, id: ClientId) {
+        if let Some(alias) = self.entries.remove(&id).and_then(|e| e.alias) {
+            self.by_alias.remove(&alias);
+        }
+    }
+
+    /// Resolves an alias or a numeric ID to a registered client.
+    pub fn resolve(&self, key: &str) -> Option<ClientId> {
+        self.by_alias.get(key).copied().or_else(|| {
+            let n: u64 = key.parse().ok()?;
+            self.entries.keys().find(|id| ***id == n).copied()
+        })
+    }
+
+    /// Returns the alias of a client, if it has one.
+    pub fn alias(&self, id: ClientId) -> Option<&str> {
+        self.entries.get(&id).and_then(|e| e.alias.as_deref())
+    }
+
+    /// Lists the registered clients, ordered by ID.
+    pub fn list(&self) -> Vec<ClientEntry> {
+        let mut entries: Vec<_> = self.entries.values().cloned().collect();
+        entries.sort_by_key(|e| e.id);
+        entries
+    }
+}
+
+/// Returns `true` if an alias is non-empty, reasonably short and made of
+/// characters that are safe in URLs, log lines and metric labels.
+pub fn is_valid_alias(alias: &str) -> bool {
+    !alias.is_empty()
+        && alias.len() <= MAX_ALIAS_LEN
+        && !alias.bytes().all(|b| b.is_ascii_digit())
+        && alias
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
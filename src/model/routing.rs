@@ -0,0 +1,144 @@
+//! Routing of application data between clients
+//!
+//! The server normally only reports the data a client sends to the embedding
+//! application. A routing table lets it pass messages on to other clients as
+//! well, e.g. a rover's telemetry to every operator console or a console's
+//! commands to one rover. Routes are keyed by the identity of the sending
+//! peer, the alias it announced or the subject it authenticated as, with `*`
+//! matching every peer:
+//!
+//! ```toml
+//! [server.routing.routes]
+//! "rover-7" = [{ channel = "telemetry", to = "*" }]
+//! "console" = [{ channel = "commands", to = "rover-7" }]
+//! ```
+//!
+//! A routed message is written as it is on the channel of the same label at
+//! each destination, which must have that channel open, and is still reported
+//! to the application. Messages only travel between clients of the same room.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The identity matching every sending peer, and the destination matching
+/// every other client.
+pub const ANY: &str = "*";
+
+/// Routing settings of the server, the `[server.routing]` section.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// Routes of the messages each peer sends, by the peer's identity
+    pub routes: BTreeMap<String, Vec<Route>>,
+}
+
+/// A route of the messages received on one channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// The label of the channel the messages arrive and leave on
+    pub channel: String,
+    /// Where the messages go
+    pub to: Destination,
+}
+
+/// The clients a routed message is written to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Destination {
+    /// Every other client of the sender's room, written as `*`
+    Broadcast,
+    /// The client with this identity
+    Peer(String),
+}
+
+impl From<String> for Destination {
+    fn from(value: String) -> Self {
+        if value == ANY {
+            Destination::Broadcast
+        } else {
+            Destination::Peer(value)
+        }
+    }
+}
+
+impl From<Destination> for String {
+    fn from(value: Destination) -> Self {
+        match value {
+            Destination::Broadcast => ANY.to_string(),
+            Destination::Peer(identity) => identity,
+        }
+    }
+}
+
+impl Destination {
+    /// Returns `true` if a client with the given identities is a destination.
+    ///
+    /// # Arguments
+    ///
+    /// * `identities` - The alias and subject of the client, if any
+    pub fn matches(&self, identities: &[Option<&str>]) -> bool {
+        match self {
+            Destination::Broadcast => true,
+            Destination::Peer(identity) => identities.contains(&Some(identity.as_str())),
+        }
+    }
+}
+
+impl RoutingConfig {
+    /// Checks the routes.
+    ///
+    /// # Returns
+    ///
+    /// A description of the first invalid setting, relative to the section
+    pub fn validate(&self) -> Result<(), String> {
+        for (identity, routes) in &self.routes {
+            if identity.is_empty() {
+                return Err("routes must not have an empty identity".into());
+            }
+            for route in routes {
+                if route.channel.is_empty() {
+                    return Err(format!("routes.{}: channel must not be empty", identity));
+                }
+                if route.to == Destination::Peer(String::new()) {
+                    return Err(format!("routes.{}: to must not be empty", identity));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if no route is configured.
+    pub fn is_empty(&self) -> bool {
+        self.routes.values().all(Vec::is_empty)
+    }
+
+    /// Returns the destinations of a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `identities` - The alias and subject of the sending client, if any
+    /// * `channel` - The label of the channel the message arrived on
+    ///
+    /// # Returns
+    ///
+    /// The destinations of every matching route, without duplicates
+    pub fn destinations(&self, identities: &[Option<&str>], channel: &str) -> Vec<&Destination> {
+        let mut destinations: Vec<&Destination> = vec![];
+        let matching = self
+            .routes
+            .iter()
+            .filter(|(identity, _)| {
+                *identity == ANY || identities.contains(&Some(identity.as_str()))
+            })
+            .flat_map(|(_, routes)| routes)
+            .filter(|route| route.channel == channel);
+        for route in matching {
+            if !destinations.contains(&&route.to) {
+                destinations.push(&route.to);
+            }
+        }
+        destinations
+    }
+}
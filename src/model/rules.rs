@@ -0,0 +1,396 @@
+//! Event rules evaluated on telemetry
+//!
+//! Simple automations, such as sending a rover home when its battery runs
+//! low, should not need a custom consumer. A rule names a topic, a condition
+//! on the value of its messages and the actions to take when the condition
+//! starts to hold: send a message, post to a webhook or log. The server
+//! evaluates its rules on the data its clients send and the operator on the
+//! telemetry of its rovers; both run the actions the engine returns.
+//!
+//! ```toml
+//! [[server.rules]]
+//! name = "low-battery"
+//! topic = "battery"
+//! field = "/level"
+//! op = "lt"
+//! value = 20
+//! cooldown_secs = 300
+//! actions = [
+//!     { action = "send", message = "return-home" },
+//!     { action = "webhook", url = "https://ops.example.com/hooks/battery" },
+//!     { action = "log", level = "warn", message = "{source} battery at {value}" },
+//! ]
+//! ```
+//!
+//! The topic matches the channel label or the topic of an envelope. Messages
+//! are read as JSON, falling back to a string; GPS fixes on the telemetry
+//! channel read as `{"latitude": ..., "longitude": ..., "altitude": ...,
+//! "timestamp": ...}`. A rule fires once when its condition becomes true for
+//! a source and again only after it was false in between, and no sooner than
+//! `cooldown_secs` after it last fired.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::model::payload::Envelope;
+use crate::model::telemetry::{Telemetry, TELEMETRY_CHANNEL};
+
+/// How a rule compares the value of a message with its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// The string contains the rule's string, or the array its value
+    Contains,
+}
+
+impl Comparison {
+    /// Returns `true` if `actual` compares to `expected` as required.
+    ///
+    /// Ordering comparisons only hold between numbers.
+    pub fn holds(self, actual: &Value, expected: &Value) -> bool {
+        let numbers = actual.as_f64().zip(expected.as_f64());
+        match self {
+            Self::Eq => numbers.map_or(actual == expected, |(a, b)| a == b),
+            Self::Ne => numbers.map_or(actual != expected, |(a, b)| a != b),
+            Self::Lt => numbers.is_some_and(|(a, b)| a < b),
+            Self::Le => numbers.is_some_and(|(a, b)| a <= b),
+            Self::Gt => numbers.is_some_and(|(a, b)| a > b),
+            Self::Ge => numbers.is_some_and(|(a, b)| a >= b),
+            Self::Contains => match (actual, expected) {
+                (Value::String(a), Value::String(b)) => a.contains(b.as_str()),
+                (Value::Array(items), value) => items.contains(value),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Severity of a log action.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleLogLevel {
+    #[default]
+    Info,
+    Warn,
+}
+
+/// What a rule does when it fires.
+///
+/// Messages may contain `{rule}`, `{source}`, `{topic}` and `{value}`,
+/// replaced when the rule fires.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Sends a text message to the source of the data, or to the client or
+    /// rover named by `to`
+    Send {
+        message: String,
+        /// Channel to send on; the default text channel of the server, or
+        /// the command channel of the operator, if unset
+        #[serde(default)]
+        channel: Option<String>,
+        #[serde(default)]
+        to: Option<String>,
+    },
+    /// Posts the firing as JSON to a URL
+    Webhook { url: String },
+    /// Logs a message
+    Log {
+        message: String,
+        #[serde(default)]
+        level: RuleLogLevel,
+    },
+}
+
+/// A rule, an entry of `server.rules` or `operator.rules`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Name of the rule, for the logs and webhooks
+    pub name: String,
+    /// Channel label or envelope topic the rule applies to
+    pub topic: String,
+    /// JSON pointer to the compared field, e.g. `/battery/level`; the whole
+    /// value if unset
+    #[serde(default)]
+    pub field: Option<String>,
+    pub op: Comparison,
+    /// Value the field is compared with
+    pub value: Value,
+    /// Minimum time between two firings for the same source
+    #[serde(default)]
+    pub cooldown_secs: u64,
+    pub actions: Vec<RuleAction>,
+}
+
+impl Rule {
+    /// Checks the rule.
+    ///
+    /// # Returns
+    ///
+    /// A description of the first invalid setting, relative to the rule
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("name must not be empty".into());
+        }
+        if self.topic.is_empty() {
+            return Err("topic must not be empty".into());
+        }
+        if let Some(field) = &self.field {
+            if !field.is_empty() && !field.starts_with('/') {
+                return Err(format!("field '{}' must be a JSON pointer", field));
+            }
+        }
+        let ordering = matches!(
+            self.op,
+            Comparison::Lt | Comparison::Le | Comparison::Gt | Comparison::Ge
+        );
+        if ordering && !self.value.is_number() {
+            return Err(format!("value must be a number for {:?}", self.op));
+        }
+        if self.actions.is_empty() {
+            return Err("actions must not be empty".into());
+        }
+        for action in &self.actions {
+            match action {
+                RuleAction::Send { channel, to, .. } => {
+                    if channel.as_deref() == Some("") || to.as_deref() == Some("") {
+                        return Err("actions: send channel and to must not be empty".into());
+                    }
+                }
+                RuleAction::Webhook { url } => {
+                    if !url.starts_with("http://") && !url.starts_with("https://") {
+                        return Err(format!("actions: webhook url '{}' must be http", url));
+                    }
+                }
+                RuleAction::Log { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the value of a message meets the condition.
+    fn matches(&self, value: &Value) -> bool {
+        let actual = match self.field.as_deref() {
+            Some(pointer) => value.pointer(pointer),
+            None => Some(value),
+        };
+        actual.is_some_and(|actual| self.op.holds(actual, &self.value))
+    }
+}
+
+/// A rule that fired, with its actions' messages filled in.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RuleFiring {
+    pub rule: String,
+    /// The client or rover the data came from
+    pub source: String,
+    pub topic: String,
+    /// The value of the message
+    pub value: Value,
+    pub at: DateTime<Utc>,
+    #[serde(skip)]
+    pub actions: Vec<RuleAction>,
+}
+
+/// Whether a rule holds for a source, and when it last fired.
+#[derive(Debug, Clone, Copy, Default)]
+struct RuleState {
+    active: bool,
+    fired: Option<Instant>,
+}
+
+/// Evaluates the rules on the messages of every source.
+#[derive(Debug, Default)]
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+    states: HashMap<(usize, String), RuleState>,
+}
+
+impl RuleEngine {
+    /// Creates an engine evaluating the given rules.
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            rules,
+            states: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` if there are no rules to evaluate.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Evaluates the rules on a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The client or rover the message came from
+    /// * `channel` - The label of the channel it arrived on
+    /// * `data` - The bytes of the message
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// The rules that fired
+    pub fn evaluate(
+        &mut self,
+        source: &str,
+        channel: &str,
+        data: &[u8],
+        now: Instant,
+    ) -> Vec<RuleFiring> {
+        if self.is_empty() {
+            return vec![];
+        }
+        if channel == TELEMETRY_CHANNEL {
+            if let Some(sample) = Telemetry::decode(data) {
+                return self.evaluate_telemetry(source, &sample, now);
+            }
+        }
+        let (topic, bytes) = match Envelope::decode(data) {
+            Some((envelope, _)) => (envelope.topic, envelope.payload.data),
+            None => (String::new(), data.to_vec()),
+        };
+        let Ok(text) = String::from_utf8(bytes) else {
+            return vec![];
+        };
+        let value = serde_json::from_str(&text).unwrap_or(Value::String(text));
+        let mut fired = self.evaluate_value(source, channel, &value, now);
+        if !topic.is_empty() && topic != channel {
+            fired.extend(self.evaluate_value(source, &topic, &value, now));
+        }
+        fired
+    }
+
+    /// Evaluates the rules of the telemetry channel on a decoded sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The client or rover the sample came from
+    /// * `sample` - The sample
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// The rules that fired
+    pub fn evaluate_telemetry(
+        &mut self,
+        source: &str,
+        sample: &Telemetry,
+        now: Instant,
+    ) -> Vec<RuleFiring> {
+        self.evaluate_value(source, TELEMETRY_CHANNEL, &telemetry_value(sample), now)
+    }
+
+    /// Evaluates the rules of a topic on a value.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The client or rover the value came from
+    /// * `topic` - The channel label or envelope topic of the value
+    /// * `value` - The value
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// The rules that fired
+    pub fn evaluate_value(
+        &mut self,
+        source: &str,
+        topic: &str,
+        value: &Value,
+        now: Instant,
+    ) -> Vec<RuleFiring> {
+        let mut fired = vec![];
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.topic != topic {
+                continue;
+            }
+            let state = self.states.entry((i, source.to_string())).or_default();
+            let holds = rule.matches(value);
+            let rising = holds && !state.active;
+            state.active = holds;
+            if !rising {
+                continue;
+            }
+            let cooldown = Duration::from_secs(rule.cooldown_secs);
+            if state
+                .fired
+                .is_some_and(|at| now.duration_since(at) < cooldown)
+            {
+                continue;
+            }
+            state.fired = Some(now);
+            let render = |message: &str| {
+                message
+                    .replace("{rule}", &rule.name)
+                    .replace("{source}", source)
+                    .replace("{topic}", topic)
+                    .replace("{value}", &value_text(value, rule.field.as_deref()))
+            };
+            let actions = rule
+                .actions
+                .iter()
+                .map(|action| match action {
+                    RuleAction::Send {
+                        message,
+                        channel,
+                        to,
+                    } => RuleAction::Send {
+                        message: render(message),
+                        channel: channel.clone(),
+                        to: to.clone(),
+                    },
+                    RuleAction::Log { message, level } => RuleAction::Log {
+                        message: render(message),
+                        level: *level,
+                    },
+                    RuleAction::Webhook { url } => RuleAction::Webhook { url: url.clone() },
+                })
+                .collect();
+            fired.push(RuleFiring {
+                rule: rule.name.clone(),
+                source: source.to_string(),
+                topic: topic.to_string(),
+                value: value.clone(),
+                at: Utc::now(),
+                actions,
+            });
+        }
+        fired
+    }
+
+    /// Forgets the state of a source that went away.
+    pub fn remove_source(&mut self, source: &str) {
+        self.states.retain(|(_, s), _| s != source);
+    }
+}
+
+/// Returns the value rules see for a telemetry sample.
+fn telemetry_value(sample: &Telemetry) -> Value {
+    match sample {
+        Telemetry::Gps(fix) => serde_json::to_value(fix).unwrap_or(Value::Null),
+    }
+}
+
+/// Formats the compared field of a value for a message, strings unquoted.
+fn value_text(value: &Value, field: Option<&str>) -> String {
+    let value = field.and_then(|f| value.pointer(f)).unwrap_or(value);
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,167 @@
+//! Messages scheduled for a later delivery
+//!
+//! Some messages only make sense at a given time: "release the brake in
+//! 10 s", a burst of telemetry at the top of the minute. Rather than have
+//! every application keep its own timers, the peer's handle takes messages
+//! with a delivery time or delay; the event loop hands them to the outbound
+//! queue of their channel once they are due, like data passed to
+//! [`crate::peer::PeerHandle::send`].
+//!
+//! A message belongs to the session it was scheduled in by default, and is
+//! dropped with it when the connection is lost: a command timed relative to
+//! what the operator saw may be wrong after an outage. Messages scheduled
+//! with [`Persistence::Reconnects`] are kept across reconnections instead,
+//! and one that fell due while the peer was disconnected is sent as soon as
+//! its channel is open again.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How long a scheduled message is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Persistence {
+    /// Dropped when the session it was scheduled in ends
+    #[default]
+    Session,
+    /// Kept across reconnections until it is sent or cancelled
+    Reconnects,
+}
+
+/// A message waiting for its delivery time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScheduledMessage {
+    /// ID for cancelling the message
+    pub id: u64,
+    /// The label of the channel it is sent on
+    pub label: String,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+    /// When it is sent
+    pub due: DateTime<Utc>,
+    pub persistence: Persistence,
+}
+
+/// The scheduled messages of a peer, in order of delivery.
+#[derive(Debug, Default)]
+pub struct MessageSchedule {
+    messages: Vec<ScheduledMessage>,
+    next_id: u64,
+}
+
+impl MessageSchedule {
+    /// Creates an empty schedule.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Schedules a message.
+    ///
+    /// Messages due at the same time are sent in the order they were
+    /// scheduled.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel to send it on
+    /// * `data` - The message
+    /// * `due` - When to send it; a time in the past sends it at once
+    /// * `persistence` - Whether it survives the end of the session
+    ///
+    /// # Returns
+    ///
+    /// The ID of the message
+    pub fn schedule(
+        &mut self,
+        label: &str,
+        data: Vec<u8>,
+        due: DateTime<Utc>,
+        persistence: Persistence,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let position = self.messages.partition_point(|m| m.due <= due);
+        self.messages.insert(
+            position,
+            ScheduledMessage {
+                id,
+                label: label.to_string(),
+                data,
+                due,
+                persistence,
+            },
+        );
+        id
+    }
+
+    /// Cancels a message.
+    ///
+    /// # Returns
+    ///
+    /// `false` if it was already sent, dropped or cancelled
+    pub fn cancel(&mut self, id: u64) -> bool {
+        let before = self.messages.len();
+        self.messages.retain(|m| m.id != id);
+        self.messages.len() < before
+    }
+
+    /// Returns the messages waiting, in order of delivery.
+    pub fn pending(&self) -> &[ScheduledMessage] {
+        &self.messages
+    }
+
+    /// Returns the time until the next message is due, zero if one is
+    /// overdue, or `None` if none is waiting.
+    pub fn next_due(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.messages
+            .first()
+            .map(|m| (m.due - now).to_std().unwrap_or(Duration::ZERO))
+    }
+
+    /// Takes the messages that are due on open channels.
+    ///
+    /// Due messages for channels that are not open keep waiting.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time
+    /// * `open` - Whether the channel of a label is open
+    ///
+    /// # Returns
+    ///
+    /// The labels and data of the messages, in order of delivery
+    pub fn take_due(
+        &mut self,
+        now: DateTime<Utc>,
+        open: impl Fn(&str) -> bool,
+    ) -> Vec<(String, Vec<u8>)> {
+        let due = self.messages.partition_point(|m| m.due <= now);
+        if due == 0 {
+            return vec![];
+        }
+        let mut ready = vec![];
+        let mut waiting = vec![];
+        for message in self.messages.drain(..due) {
+            if open(&message.label) {
+                ready.push((message.label, message.data));
+            } else {
+                waiting.push(message);
+            }
+        }
+        self.messages.splice(0..0, waiting);
+        ready
+    }
+
+    /// Drops the messages of the session that ended.
+    ///
+    /// # Returns
+    ///
+    /// The number of messages dropped
+    pub fn end_session(&mut self) -> usize {
+        let before = self.messages.len();
+        self.messages
+            .retain(|m| m.persistence == Persistence::Reconnects);
+        before - self.messages.len()
+    }
+}
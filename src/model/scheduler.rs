@@ -0,0 +1,245 @@
+//! Weighted fair scheduling of outbound data
+//!
+//! All data channels of a connection share one SCTP association, and with it
+//! one congestion window. A bulk file transfer writing as fast as it can would
+//! fill the association's send buffer, and telemetry or teleoperation
+//! commands written after it would wait behind every queued chunk.
+//!
+//! The peer therefore queues outbound messages per channel and only hands
+//! them to SCTP while its send buffer holds less than
+//! [`SEND_BUFFER_LIMIT`] bytes. Which channel may send next is decided by
+//! deficit round robin: every round, each channel with queued messages earns
+//! [`QUANTUM`] bytes times its weight of credit, and sends messages while its
+//! credit covers them. A channel with weight 4 gets four times the bandwidth
+//! of a channel with weight 1 while both have data queued, and idle channels
+//! leave their share to the others.
+//!
+//! Weights share the bandwidth among channels of the same
+//! [`QosClass`]. Across classes, the [`QosScheduler`] serves strict
+//! priority: control messages are released before any telemetry, and
+//! telemetry before any bulk data, so a constrained link spends what it has
+//! on commands first. A bulk transfer only gets the budget the other classes
+//! leave.
+
+use std::collections::VecDeque;
+
+use crate::model::channel::QosClass;
+
+/// Bytes of credit a channel of weight 1 earns per round.
+pub const QUANTUM: usize = 1200;
+
+/// Bytes the SCTP send buffers may hold before messages wait in the queues.
+pub const SEND_BUFFER_LIMIT: usize = 256 * 1024;
+
+/// The queue of one channel.
+#[derive(Debug)]
+struct Flow {
+    label: String,
+    weight: u32,
+    deficit: usize,
+    messages: VecDeque<Vec<u8>>,
+}
+
+/// Queues outbound messages per channel and releases them in weighted fair
+/// order.
+#[derive(Debug, Default)]
+pub struct FairScheduler {
+    flows: Vec<Flow>,
+    cursor: usize,
+    /// Whether the flow at the cursor already earned its credit this round
+    credited: bool,
+}
+
+impl FairScheduler {
+    /// Creates a scheduler without queued messages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel the message is sent on
+    /// * `weight` - The channel's share of the bandwidth, at least 1
+    /// * `data` - The message
+    pub fn push(&mut self, label: &str, weight: u32, data: Vec<u8>) {
+        let weight = weight.max(1);
+        match self.flows.iter_mut().find(|f| f.label == label) {
+            Some(flow) => {
+                flow.weight = weight;
+                flow.messages.push_back(data);
+            }
+            None => self.flows.push(Flow {
+                label: label.to_string(),
+                weight,
+                deficit: 0,
+                messages: VecDeque::from([data]),
+            }),
+        }
+    }
+
+    /// Puts a message whose write failed back at the head of its channel's
+    /// queue, so it goes out before the messages queued after it.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel the message is sent on
+    /// * `weight` - The channel's share of the bandwidth, at least 1
+    /// * `data` - The message
+    pub fn requeue(&mut self, label: &str, weight: u32, data: Vec<u8>) {
+        let weight = weight.max(1);
+        match self.flows.iter_mut().find(|f| f.label == label) {
+            Some(flow) => {
+                flow.weight = weight;
+                flow.messages.push_front(data);
+            }
+            None => self.flows.push(Flow {
+                label: label.to_string(),
+                weight,
+                deficit: 0,
+                messages: VecDeque::from([data]),
+            }),
+        }
+    }
+
+    /// Returns `true` if no message is queued.
+    pub fn is_empty(&self) -> bool {
+        self.flows.iter().all(|f| f.messages.is_empty())
+    }
+
+    /// Returns the number of messages queued for a channel.
+    pub fn queued(&self, label: &str) -> usize {
+        self.flows
+            .iter()
+            .find(|f| f.label == label)
+            .map_or(0, |f| f.messages.len())
+    }
+
+    /// Releases messages in weighted fair order.
+    ///
+    /// The last message released may exceed the budget, so messages larger
+    /// than the budget are not held back forever.
+    ///
+    /// # Arguments
+    ///
+    /// * `budget` - The number of bytes the send buffers can take now
+    ///
+    /// # Returns
+    ///
+    /// The released messages with the labels of their channels, in sending order
+    pub fn schedule(&mut self, mut budget: usize) -> Vec<(String, Vec<u8>)> {
+        let mut released = vec![];
+        while budget > 0 && !self.is_empty() {
+            let flow = &mut self.flows[self.cursor];
+            if !flow.messages.is_empty() {
+                if !self.credited {
+                    flow.deficit += QUANTUM * flow.weight as usize;
+                    self.credited = true;
+                }
+                while budget > 0
+                    && flow
+                        .messages
+                        .front()
+                        .is_some_and(|m| m.len() <= flow.deficit)
+                {
+                    let message = flow.messages.pop_front().expect("front message");
+                    flow.deficit -= message.len();
+                    budget = budget.saturating_sub(message.len());
+                    released.push((flow.label.clone(), message));
+                }
+                if flow.messages.is_empty() {
+                    flow.deficit = 0;
+                }
+            }
+            // A flow stopped by the budget keeps its turn for the next call
+            if budget > 0 {
+                self.cursor = (self.cursor + 1) % self.flows.len();
+                self.credited = false;
+            }
+        }
+        released
+    }
+
+    /// Releases every queued message, e.g. before closing the connection.
+    pub fn drain(&mut self) -> Vec<(String, Vec<u8>)> {
+        self.schedule(usize::MAX)
+    }
+}
+
+/// Queues outbound messages per channel and releases them by QoS class, in
+/// weighted fair order within a class.
+#[derive(Debug, Default)]
+pub struct QosScheduler {
+    /// The scheduler of each class, from the highest precedence
+    classes: [FairScheduler; 3],
+}
+
+impl QosScheduler {
+    /// Creates a scheduler without queued messages.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel the message is sent on
+    /// * `class` - The channel's QoS class
+    /// * `weight` - The channel's share of its class's bandwidth, at least 1
+    /// * `data` - The message
+    pub fn push(&mut self, label: &str, class: QosClass, weight: u32, data: Vec<u8>) {
+        self.classes[class as usize].push(label, weight, data);
+    }
+
+    /// Puts a message whose write failed back at the head of its channel's
+    /// queue, so it goes out before the messages queued after it.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel the message is sent on
+    /// * `class` - The channel's QoS class
+    /// * `weight` - The channel's share of its class's bandwidth, at least 1
+    /// * `data` - The message
+    pub fn requeue(&mut self, label: &str, class: QosClass, weight: u32, data: Vec<u8>) {
+        self.classes[class as usize].requeue(label, weight, data);
+    }
+
+    /// Returns `true` if no message is queued.
+    pub fn is_empty(&self) -> bool {
+        self.classes.iter().all(FairScheduler::is_empty)
+    }
+
+    /// Returns the number of messages queued for a channel.
+    pub fn queued(&self, label: &str) -> usize {
+        self.classes.iter().map(|class| class.queued(label)).sum()
+    }
+
+    /// Releases messages by class while the budget lasts.
+    ///
+    /// # Arguments
+    ///
+    /// * `budget` - The number of bytes the send buffers can take now
+    ///
+    /// # Returns
+    ///
+    /// The released messages with the labels of their channels, in sending order
+    pub fn schedule(&mut self, mut budget: usize) -> Vec<(String, Vec<u8>)> {
+        let mut released = vec![];
+        for class in &mut self.classes {
+            if budget == 0 {
+                break;
+            }
+            let messages = class.schedule(budget);
+            budget = budget.saturating_sub(messages.iter().map(|(_, m)| m.len()).sum());
+            released.extend(messages);
+        }
+        released
+    }
+
+    /// Releases every queued message, e.g. before closing the connection.
+    pub fn drain(&mut self) -> Vec<(String, Vec<u8>)> {
+        self.schedule(usize::MAX)
+    }
+}
@@ -0,0 +1,404 @@
+//! Machine-readable description of the wire protocol
+//!
+//! Ground-station teams do not all write Rust, so the messages exchanged on
+//! the data channels are described in a JSON document they can implement
+//! encoders from: the bincode encoding rules, the payload envelope, every
+//! built-in channel with the message type it carries, and the layout of each
+//! message type. The document is printed by the `protocol-doc` command.
+//!
+//! Every message type describes itself through [`WireSchema`], implemented
+//! next to its definition so the two are changed together.
+
+use serde::Serialize;
+
+use crate::model::{
+    announce::{AnnounceMessage, ANNOUNCE_CHANNEL},
+    batch::BATCH_MARKER,
+    capture::{CaptureMessage, CAPTURE_CHANNEL},
+    control::{
+        ControlMessage, Feature, FeatureSet, CONTROL_CHANNEL, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION,
+    },
+    coordination::{CoordinationMessage, COORDINATION_CHANNEL},
+    crash::{CrashMessage, CRASH_CHANNEL},
+    duplicate::DUPLICATE_MARKER,
+    forward::{ForwardMessage, ForwardedTrack, FORWARD_CHANNEL},
+    fragment::FRAGMENT_MARKER,
+    logs::{LogLevel, LogMessage, LogQuery, LOGS_CHANNEL},
+    mission::{MissionMessage, Waypoint, MISSION_CHANNEL},
+    payload::{Envelope, MessageKind, Payload, ENVELOPE_MARKER, ENVELOPE_V1, ENVELOPE_VERSION},
+    session::{SessionMessage, SESSION_CHANNEL},
+    telemetry::{GpsFix, Telemetry, TELEMETRY_CHANNEL},
+    transfer::{Manifest, TransferMessage, TRANSFER_CHANNEL},
+};
+
+/// The type of a field, in bincode's data model.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WireType {
+    /// A single byte
+    U8,
+    /// A varint-encoded unsigned integer
+    U16,
+    /// A varint-encoded unsigned integer
+    U32,
+    /// A varint-encoded unsigned integer
+    U64,
+    /// A zigzag varint-encoded signed integer
+    I64,
+    /// An IEEE 754 single, little-endian
+    F32,
+    /// An IEEE 754 double, little-endian
+    F64,
+    /// A varint length followed by that many bytes
+    Bytes,
+    /// A varint length followed by that many bytes of UTF-8
+    String,
+    /// A `0` byte, or a `1` byte followed by the value
+    Option { of: Box<WireType> },
+    /// A varint count followed by that many values
+    List { of: Box<WireType> },
+    /// A type described in the `types` section of the document
+    Ref { name: &'static str },
+}
+
+impl WireType {
+    /// Returns an optional value of the given type.
+    pub fn option(of: WireType) -> Self {
+        WireType::Option { of: Box::new(of) }
+    }
+
+    /// Returns a list of values of the given type.
+    pub fn list(of: WireType) -> Self {
+        WireType::List { of: Box::new(of) }
+    }
+}
+
+/// A field of a struct or enum variant, encoded in declaration order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Field {
+    pub name: &'static str,
+    #[serde(flatten)]
+    pub ty: WireType,
+    pub doc: &'static str,
+}
+
+/// Returns a field description.
+pub fn field(name: &'static str, ty: WireType, doc: &'static str) -> Field {
+    Field { name, ty, doc }
+}
+
+/// A variant of an enum, encoded as its varint index followed by its fields.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Variant {
+    pub index: u32,
+    pub name: &'static str,
+    pub doc: &'static str,
+    pub fields: Vec<Field>,
+}
+
+/// The layout of a message type.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Shape {
+    /// The fields in declaration order
+    Struct { fields: Vec<Field> },
+    /// A variant index, then the fields of that variant
+    Enum { variants: Vec<Variant> },
+}
+
+/// A named message type.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TypeDef {
+    pub name: &'static str,
+    pub doc: &'static str,
+    #[serde(flatten)]
+    pub shape: Shape,
+}
+
+impl TypeDef {
+    /// Describes a struct.
+    pub fn structure(name: &'static str, doc: &'static str, fields: Vec<Field>) -> Self {
+        Self {
+            name,
+            doc,
+            shape: Shape::Struct { fields },
+        }
+    }
+
+    /// Describes an enum; variant indices follow the order of `variants`,
+    /// which must be the declaration order.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the enum
+    /// * `doc` - What the enum is used for
+    /// * `variants` - The name, description and fields of each variant
+    pub fn enumeration(
+        name: &'static str,
+        doc: &'static str,
+        variants: Vec<(&'static str, &'static str, Vec<Field>)>,
+    ) -> Self {
+        let variants = variants
+            .into_iter()
+            .zip(0..)
+            .map(|((name, doc, fields), index)| Variant {
+                index,
+                name,
+                doc,
+                fields,
+            })
+            .collect();
+        Self {
+            name,
+            doc,
+            shape: Shape::Enum { variants },
+        }
+    }
+}
+
+/// A type sent on the wire, able to describe its own encoding.
+pub trait WireSchema {
+    /// Returns the description of the type.
+    fn wire_schema() -> TypeDef;
+}
+
+/// How messages are framed on a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// Each SCTP message holds exactly one bincode-encoded message
+    Message,
+    /// Each SCTP message holds an enveloped payload, or a bare legacy one
+    /// before the protocol handshake
+    Envelope,
+}
+
+/// A data channel and the messages it carries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChannelDoc {
+    pub label: &'static str,
+    pub message: &'static str,
+    pub framing: Framing,
+    pub doc: &'static str,
+}
+
+/// The framing of application payloads.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EnvelopeDoc {
+    pub marker: u8,
+    pub version: u8,
+    pub layout: &'static str,
+    pub v1_version: u8,
+    pub v1: &'static str,
+    pub legacy: &'static str,
+}
+
+/// The framing of coalesced messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BatchDoc {
+    pub marker: u8,
+    pub feature: &'static str,
+    pub layout: &'static str,
+}
+
+/// The framing of messages split to fit the path MTU.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FragmentDoc {
+    pub marker: u8,
+    pub feature: &'static str,
+    pub layout: &'static str,
+}
+
+/// The framing of copies of critical messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DuplicateDoc {
+    pub marker: u8,
+    pub feature: &'static str,
+    pub layout: &'static str,
+}
+
+/// The complete protocol description.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProtocolDoc {
+    pub software: &'static str,
+    pub protocol_version: u16,
+    pub min_protocol_version: u16,
+    pub encoding: Vec<&'static str>,
+    pub envelope: EnvelopeDoc,
+    pub batch: BatchDoc,
+    pub fragment: FragmentDoc,
+    pub duplicate: DuplicateDoc,
+    pub channels: Vec<ChannelDoc>,
+    pub types: Vec<TypeDef>,
+}
+
+impl ProtocolDoc {
+    /// Describes the protocol spoken by this build.
+    pub fn new() -> Self {
+        Self {
+            software: env!("CARGO_PKG_VERSION"),
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            encoding: vec![
+                "bincode 2 standard configuration: little-endian, variable-length integers",
+                "u8 and bool: one byte",
+                "u16, u32, u64: values below 251 are one byte; otherwise a tag byte \
+                 251, 252 or 253 followed by the value as little-endian u16, u32 or u64",
+                "i64: zigzag-mapped to u64 ((n << 1) ^ (n >> 63)), then encoded as u64",
+                "structs: fields in declaration order, without names or padding",
+                "enums: the variant index as u32, then the variant's fields",
+            ],
+            envelope: EnvelopeDoc {
+                marker: ENVELOPE_MARKER,
+                version: ENVELOPE_VERSION,
+                layout: "marker byte, version byte, then the bincode-encoded Envelope; \
+                         sent to peers that agreed on protocol v2 or later",
+                v1_version: ENVELOPE_V1,
+                v1: "marker byte, version byte 1, then the bincode-encoded Payload; \
+                     sent to peers that agreed on protocol v1",
+                legacy: "the bincode-encoded Payload without marker and version; \
+                         its first byte is never the marker",
+            },
+            batch: BatchDoc {
+                marker: BATCH_MARKER,
+                feature: Feature::Batching.name(),
+                layout: "once both sides advertised the feature, an SCTP message on any \
+                         channel but control starting with the marker byte is a batch: \
+                         the marker, then the messages as a list of bytes, each handled \
+                         as if it had arrived alone; a single message starting with the \
+                         marker is sent as a batch of one",
+            },
+            fragment: FragmentDoc {
+                marker: FRAGMENT_MARKER,
+                feature: Feature::Fragmentation.name(),
+                layout: "once both sides advertised the feature, an SCTP message on any \
+                         channel but control starting with the marker byte, or a message of \
+                         a batch starting with it, is a fragment: the marker, the message ID \
+                         as a big-endian u32, the index and count of the fragment as \
+                         big-endian u16, then the data; the fragments of an ID reassemble \
+                         in index order, and a single message starting with the marker is \
+                         sent as fragment 0 of 1",
+            },
+            duplicate: DuplicateDoc {
+                marker: DUPLICATE_MARKER,
+                feature: Feature::Deduplication.name(),
+                layout: "once both sides advertised the feature, a message on any channel \
+                         but control starting with the marker byte, once reassembled, is a \
+                         copy: the marker, the sender's stream ID and the sequence number as \
+                         big-endian u64, then the data; only the first copy of a stream and \
+                         sequence is delivered, except on stream 0, and a single message \
+                         starting with the marker is sent on stream 0",
+            },
+            channels: vec![
+                ChannelDoc {
+                    label: CONTROL_CHANNEL,
+                    message: "ControlMessage",
+                    framing: Framing::Message,
+                    doc: "Version handshake; both sides send Hello then Capabilities first",
+                },
+                ChannelDoc {
+                    label: SESSION_CHANNEL,
+                    message: "SessionMessage",
+                    framing: Framing::Message,
+                    doc: "Session expiry warnings and refreshes",
+                },
+                ChannelDoc {
+                    label: MISSION_CHANNEL,
+                    message: "MissionMessage",
+                    framing: Framing::Message,
+                    doc: "Chunked waypoint plan transfers",
+                },
+                ChannelDoc {
+                    label: LOGS_CHANNEL,
+                    message: "LogMessage",
+                    framing: Framing::Message,
+                    doc: "Requests for the rover's recent log lines and their chunked answers",
+                },
+                ChannelDoc {
+                    label: CAPTURE_CHANNEL,
+                    message: "CaptureMessage",
+                    framing: Framing::Message,
+                    doc: "Bounded captures of the rover's UDP traffic and their chunked pcap files",
+                },
+                ChannelDoc {
+                    label: CRASH_CHANNEL,
+                    message: "CrashMessage",
+                    framing: Framing::Message,
+                    doc: "Crash reports the rover kept on disk, uploaded in chunks",
+                },
+                ChannelDoc {
+                    label: ANNOUNCE_CHANNEL,
+                    message: "AnnounceMessage",
+                    framing: Framing::Message,
+                    doc: "Announcements to every client of a room and their acknowledgments",
+                },
+                ChannelDoc {
+                    label: TRANSFER_CHANNEL,
+                    message: "TransferMessage",
+                    framing: Framing::Message,
+                    doc: "Chunked, resumable file transfers in either direction",
+                },
+                ChannelDoc {
+                    label: FORWARD_CHANNEL,
+                    message: "ForwardMessage",
+                    framing: Framing::Message,
+                    doc:
+                        "Renegotiations adding the tracks of other clients, forwarded by the server",
+                },
+                ChannelDoc {
+                    label: TELEMETRY_CHANNEL,
+                    message: "Telemetry",
+                    framing: Framing::Message,
+                    doc: "Telemetry samples from the rover",
+                },
+                ChannelDoc {
+                    label: COORDINATION_CHANNEL,
+                    message: "CoordinationMessage",
+                    framing: Framing::Message,
+                    doc: "Convoy heartbeats, relayed by the server to every rover",
+                },
+                ChannelDoc {
+                    label: "*",
+                    message: "Envelope",
+                    framing: Framing::Envelope,
+                    doc: "Application data on every other channel",
+                },
+            ],
+            types: vec![
+                ControlMessage::wire_schema(),
+                FeatureSet::wire_schema(),
+                SessionMessage::wire_schema(),
+                MissionMessage::wire_schema(),
+                Waypoint::wire_schema(),
+                LogMessage::wire_schema(),
+                LogQuery::wire_schema(),
+                LogLevel::wire_schema(),
+                CaptureMessage::wire_schema(),
+                CrashMessage::wire_schema(),
+                AnnounceMessage::wire_schema(),
+                TransferMessage::wire_schema(),
+                Manifest::wire_schema(),
+                ForwardMessage::wire_schema(),
+                ForwardedTrack::wire_schema(),
+                Telemetry::wire_schema(),
+                GpsFix::wire_schema(),
+                CoordinationMessage::wire_schema(),
+                Envelope::wire_schema(),
+                MessageKind::wire_schema(),
+                Payload::wire_schema(),
+            ],
+        }
+    }
+
+    /// Renders the description as pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Serialization failed")
+    }
+}
+
+impl Default for ProtocolDoc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
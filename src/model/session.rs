@@ -0,0 +1,243 @@
+//! Session lifetimes and in-band re-authentication
+//!
+//! With a lifetime configured, every session expires some time after it was
+//! established, so a leaked credential only grants access for a bounded time.
+//! Shortly before a session expires, the server asks the peer to refresh it on
+//! the "session" data channel. The peer answers with the refresh token it
+//! received during signaling and, for backends using bearer tokens, a fresh
+//! credential that the server validates again. A successful refresh rotates
+//! the refresh token and extends the session without touching the connection.
+
+use bincode::config::{self, Configuration};
+#[cfg(feature = "native")]
+use chrono::Utc;
+#[cfg(feature = "native")]
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "native")]
+use crate::auth::guest::hex_encode;
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying session messages.
+pub const SESSION_CHANNEL: &str = "session";
+
+/// Session lifetime settings, the `[server.session]` configuration section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// How long a session stays valid without a refresh, in seconds;
+    /// sessions never expire if `None`
+    pub lifetime_secs: Option<u64>,
+    /// How long before expiry the peer is asked to refresh, in seconds
+    pub refresh_before_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            lifetime_secs: None,
+            refresh_before_secs: 60,
+        }
+    }
+}
+
+/// Messages exchanged on the session channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum SessionMessage {
+    /// Server to peer: the session expires soon and should be refreshed
+    Expiring {
+        /// Expiry time, in seconds since the Unix epoch
+        expires_at: i64,
+    },
+    /// Peer to server: extend the session
+    Refresh {
+        /// The current refresh token
+        refresh_token: String,
+        /// A fresh bearer credential, for backends that validate one
+        credential: Option<String>,
+    },
+    /// Server to peer: the session was extended
+    Refreshed {
+        /// The new expiry time, in seconds since the Unix epoch
+        expires_at: i64,
+        /// The token to present on the next refresh
+        refresh_token: String,
+    },
+    /// Server to peer: the refresh was refused; the session ends at expiry
+    Rejected { reason: String },
+}
+
+impl SessionMessage {
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(SessionMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+}
+
+/// The lifetime of a single session, tracked by the server.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone)]
+pub struct SessionLifetime {
+    expires_at: Option<i64>,
+    refresh_token: String,
+    warned: bool,
+}
+
+#[cfg(feature = "native")]
+impl SessionLifetime {
+    /// Starts the lifetime of a new session now.
+    pub fn new(config: &SessionConfig) -> Self {
+        Self {
+            expires_at: config
+                .lifetime_secs
+                .map(|secs| Utc::now().timestamp() + secs as i64),
+            refresh_token: new_refresh_token(),
+            warned: false,
+        }
+    }
+
+    /// Returns the expiry time, in seconds since the Unix epoch, if any.
+    pub fn expires_at(&self) -> Option<i64> {
+        self.expires_at
+    }
+
+    /// Returns the token the peer must present on its next refresh.
+    pub fn refresh_token(&self) -> &str {
+        &self.refresh_token
+    }
+
+    /// Returns `true` once the session has expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time, in seconds since the Unix epoch
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// Returns the warning to send if the session entered its refresh window.
+    ///
+    /// The warning is only returned once per lifetime.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time, in seconds since the Unix epoch
+    /// * `config` - The session settings
+    pub fn take_warning(&mut self, now: i64, config: &SessionConfig) -> Option<SessionMessage> {
+        let expires_at = self.expires_at?;
+        if self.warned || expires_at - now > config.refresh_before_secs as i64 {
+            return None;
+        }
+        self.warned = true;
+        Some(SessionMessage::Expiring { expires_at })
+    }
+
+    /// Checks a refresh token, in constant time.
+    pub fn check_token(&self, token: &str) -> bool {
+        let (expected, given) = (self.refresh_token.as_bytes(), token.as_bytes());
+        expected.len() == given.len()
+            && expected
+                .iter()
+                .zip(given)
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+
+    /// Extends the session by a full lifetime from now and rotates the
+    /// refresh token.
+    ///
+    /// # Returns
+    ///
+    /// The confirmation to send to the peer
+    pub fn extend(&mut self, config: &SessionConfig) -> SessionMessage {
+        *self = Self::new(config);
+        SessionMessage::Refreshed {
+            expires_at: self.expires_at.unwrap_or(i64::MAX),
+            refresh_token: self.refresh_token.clone(),
+        }
+    }
+}
+
+/// Generates a random refresh token.
+#[cfg(feature = "native")]
+fn new_refresh_token() -> String {
+    let mut token = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut token);
+    hex_encode(&token)
+}
+
+impl WireSchema for SessionMessage {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "SessionMessage",
+            "Messages exchanged on the session channel",
+            vec![
+                (
+                    "Expiring",
+                    "Server to peer: the session expires soon and should be refreshed",
+                    vec![field(
+                        "expires_at",
+                        WireType::I64,
+                        "Expiry time, in seconds since the Unix epoch",
+                    )],
+                ),
+                (
+                    "Refresh",
+                    "Peer to server: extend the session",
+                    vec![
+                        field(
+                            "refresh_token",
+                            WireType::String,
+                            "The current refresh token",
+                        ),
+                        field(
+                            "credential",
+                            WireType::option(WireType::String),
+                            "A fresh bearer credential, for backends that validate one",
+                        ),
+                    ],
+                ),
+                (
+                    "Refreshed",
+                    "Server to peer: the session was extended",
+                    vec![
+                        field(
+                            "expires_at",
+                            WireType::I64,
+                            "The new expiry time, in seconds since the Unix epoch",
+                        ),
+                        field(
+                            "refresh_token",
+                            WireType::String,
+                            "The token to present on the next refresh",
+                        ),
+                    ],
+                ),
+                (
+                    "Rejected",
+                    "Server to peer: the refresh was refused; the session ends at expiry",
+                    vec![field(
+                        "reason",
+                        WireType::String,
+                        "Human-readable explanation",
+                    )],
+                ),
+            ],
+        )
+    }
+}
@@ -0,0 +1,193 @@
+//! Connection setup time breakdown
+//!
+//! A slow connection setup in the field can be caused by the signaling server,
+//! candidate gathering, the network path, DTLS or SCTP. This module times each
+//! setup phase of a connection, on both the peer and the server, so the delay
+//! can be attributed to the right layer.
+
+use std::{fmt, time::Instant};
+
+use chrono::Utc;
+use serde::Serialize;
+use str0m::{Event, IceConnectionState};
+
+/// The phases of a connection setup, in the order they usually complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupPhase {
+    /// Candidate gathering before the offer or answer is created
+    IceGathering,
+    /// The offer/answer exchange with the signaling server
+    Signaling,
+    /// ICE connectivity checks until a candidate pair is connected
+    IceConnectivity,
+    /// The DTLS handshake on the connected pair
+    DtlsHandshake,
+    /// SCTP association and the first data channel opening
+    ChannelOpen,
+}
+
+impl SetupPhase {
+    /// All phases, in order.
+    pub const ALL: [SetupPhase; 5] = [
+        SetupPhase::IceGathering,
+        SetupPhase::Signaling,
+        SetupPhase::IceConnectivity,
+        SetupPhase::DtlsHandshake,
+        SetupPhase::ChannelOpen,
+    ];
+
+    /// The name used in logs and exported metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            SetupPhase::IceGathering => "ice_gathering",
+            SetupPhase::Signaling => "signaling",
+            SetupPhase::IceConnectivity => "ice_connectivity",
+            SetupPhase::DtlsHandshake => "dtls_handshake",
+            SetupPhase::ChannelOpen => "channel_open",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// The measured duration of a single phase.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PhaseTiming {
+    /// The phase name, see [`SetupPhase::name`]
+    pub phase: &'static str,
+    /// How long the phase took, in milliseconds; `None` if still in progress
+    pub ms: Option<f64>,
+}
+
+/// The setup time breakdown of a connection, as exported.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SetupBreakdown {
+    /// When the setup started, in milliseconds since the Unix epoch
+    pub started_at: i64,
+    /// The phases that have started, in order
+    pub phases: Vec<PhaseTiming>,
+    /// Time from the start until the first channel opened, in milliseconds
+    pub total_ms: Option<f64>,
+}
+
+impl fmt::Display for SetupBreakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, timing) in self.phases.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            match timing.ms {
+                Some(ms) => write!(f, "{} {:.1}ms", timing.phase, ms)?,
+                None => write!(f, "{} pending", timing.phase)?,
+            }
+        }
+        if let Some(total) = self.total_ms {
+            write!(f, " (total {:.1}ms)", total)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records when each setup phase of a connection starts and ends.
+///
+/// Only the first occurrence of a phase is recorded, so ICE restarts and
+/// later channels do not skew the initial setup figures.
+#[derive(Debug, Clone)]
+pub struct SetupTimer {
+    started: Instant,
+    started_at: i64,
+    spans: [(Option<Instant>, Option<Instant>); 5],
+}
+
+impl Default for SetupTimer {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: Utc::now().timestamp_millis(),
+            spans: [(None, None); 5],
+        }
+    }
+}
+
+impl SetupTimer {
+    /// Starts timing a connection setup now.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the start of a phase.
+    pub fn begin(&mut self, phase: SetupPhase) {
+        let span = &mut self.spans[phase.index()];
+        span.0.get_or_insert_with(Instant::now);
+    }
+
+    /// Marks the end of a phase, starting it as well if it had not started.
+    pub fn end(&mut self, phase: SetupPhase) {
+        self.begin(phase);
+        let span = &mut self.spans[phase.index()];
+        span.1.get_or_insert_with(Instant::now);
+    }
+
+    /// Ends a phase and starts the next one at the same instant.
+    pub fn advance(&mut self, from: SetupPhase, to: SetupPhase) {
+        self.end(from);
+        self.begin(to);
+    }
+
+    /// Returns `true` once the first data channel has opened.
+    pub fn is_complete(&self) -> bool {
+        self.spans[SetupPhase::ChannelOpen.index()].1.is_some()
+    }
+
+    /// Updates the phases from a str0m event.
+    ///
+    /// ICE connecting or completing ends the connectivity checks, str0m's
+    /// `Connected` event ends the DTLS handshake, and the first channel
+    /// opening ends the setup.
+    ///
+    /// # Returns
+    ///
+    /// `true` if this event completed the setup
+    pub fn observe(&mut self, event: &Event) -> bool {
+        let was_complete = self.is_complete();
+        match event {
+            Event::IceConnectionStateChange(IceConnectionState::Checking) => {
+                self.begin(SetupPhase::IceConnectivity)
+            }
+            Event::IceConnectionStateChange(
+                IceConnectionState::Connected | IceConnectionState::Completed,
+            ) => self.advance(SetupPhase::IceConnectivity, SetupPhase::DtlsHandshake),
+            Event::Connected => self.advance(SetupPhase::DtlsHandshake, SetupPhase::ChannelOpen),
+            Event::ChannelOpen(_, _) => self.end(SetupPhase::ChannelOpen),
+            _ => {}
+        }
+        !was_complete && self.is_complete()
+    }
+
+    /// Returns the breakdown of the phases so far.
+    pub fn breakdown(&self) -> SetupBreakdown {
+        let phases = SetupPhase::ALL
+            .into_iter()
+            .filter_map(|phase| {
+                let (start, end) = self.spans[phase.index()];
+                start.map(|start| PhaseTiming {
+                    phase: phase.name(),
+                    ms: end.map(|end| millis(end - start)),
+                })
+            })
+            .collect();
+        SetupBreakdown {
+            started_at: self.started_at,
+            phases,
+            total_ms: self.spans[SetupPhase::ChannelOpen.index()]
+                .1
+                .map(|end| millis(end - self.started)),
+        }
+    }
+}
+
+fn millis(duration: std::time::Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,257 @@
+//! Signaling response formats
+//!
+//! Originally the signaling endpoint answered with a bare [`SdpAnswer`]. Peers
+//! that ask for it now get a structured [`SignalingAnswer`] carrying metadata
+//! next to the answer, so new capabilities can be added server-side without
+//! breaking older peers, which keep receiving the bare format.
+
+use std::env;
+
+use serde::{Deserialize, Serialize};
+use str0m::change::{SdpAnswer, SdpOffer};
+
+/// Query parameter a peer uses to select the answer format.
+pub const ANSWER_FORMAT_PARAM: &str = "format";
+
+/// Environment variable with the STUN/TURN servers recommended to peers: either
+/// a comma-separated list of URLs, or a JSON array of [`IceServer`] objects for
+/// servers that need credentials.
+pub const ICE_SERVERS_ENV: &str = "ROVER_ICE_SERVERS";
+
+/// Path of the endpoint peers trickle their late-gathered candidates to.
+pub const TRICKLE_PATH: &str = "/candidates";
+
+/// Path of the WebSocket signaling endpoint.
+pub const WEBSOCKET_PATH: &str = "/ws";
+
+/// Path of the endpoint peers send ICE restart offers to.
+pub const RESTART_PATH: &str = "/restart";
+
+/// Path of the browser operator console, also served at the root.
+pub const CONSOLE_PATH: &str = "/console";
+
+/// Path of the endpoint a peer sends its offer to for a direct connection to
+/// another peer, named by the `target` query parameter.
+pub const MESH_CONNECT_PATH: &str = "/mesh/connect";
+
+/// Path of the endpoint listening peers poll for offers, announcing their
+/// `alias` query parameter.
+pub const MESH_OFFERS_PATH: &str = "/mesh/offers";
+
+/// Path of the endpoint listening peers send their answers to.
+pub const MESH_ANSWERS_PATH: &str = "/mesh/answers";
+
+/// Format of the signaling answer body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnswerFormat {
+    /// The bare serialized [`SdpAnswer`], understood by every peer
+    #[default]
+    Bare,
+    /// A [`SignalingAnswer`] object
+    Structured,
+}
+
+impl AnswerFormat {
+    /// Parses the value of the [`ANSWER_FORMAT_PARAM`] query parameter.
+    ///
+    /// Anything other than `structured` selects the bare format.
+    pub fn from_param(value: Option<&str>) -> Self {
+        match value {
+            Some("structured") => AnswerFormat::Structured,
+            _ => AnswerFormat::Bare,
+        }
+    }
+}
+
+/// A STUN or TURN server recommended to the peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IceServer {
+    /// Server URLs, e.g. `stun:stun.example.com:3478`
+    pub urls: Vec<String>,
+    /// TURN username, if the server requires authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// TURN credential, if the server requires authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+    /// When the credentials expire, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+impl IceServer {
+    /// Returns `true` if the server's credentials have expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time, in seconds since the Unix epoch
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// Returns the URLs with the `stun:` scheme.
+    pub fn stun_urls(&self) -> impl Iterator<Item = &str> {
+        self.urls
+            .iter()
+            .map(String::as_str)
+            .filter(|url| url.starts_with("stun:"))
+    }
+
+    /// Returns the URLs with the `turn:` or `turns:` scheme.
+    pub fn turn_urls(&self) -> impl Iterator<Item = &str> {
+        self.urls
+            .iter()
+            .map(String::as_str)
+            .filter(|url| url.starts_with("turn:") || url.starts_with("turns:"))
+    }
+}
+
+/// Reads the recommended ICE servers from [`ICE_SERVERS_ENV`].
+///
+/// In the comma-separated form, each URL becomes its own server without
+/// credentials.
+///
+/// # Returns
+///
+/// The configured servers, or an error if the JSON form is invalid
+pub fn ice_servers_from_env() -> anyhow::Result<Vec<IceServer>> {
+    let Ok(value) = env::var(ICE_SERVERS_ENV) else {
+        return Ok(vec![]);
+    };
+    if value.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(&value)?);
+    }
+    Ok(value
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| IceServer {
+            urls: vec![url.to_string()],
+            username: None,
+            credential: None,
+            expires_at: None,
+        })
+        .collect())
+}
+
+/// Structured signaling answer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignalingAnswer {
+    /// The SDP answer to the peer's offer
+    pub answer: SdpAnswer,
+    /// The ID the server assigned to the session
+    pub client_id: u64,
+    /// Opaque token identifying the session in later requests
+    pub session_token: String,
+    /// STUN/TURN servers the peer should use
+    #[serde(default)]
+    pub ice_servers: Vec<IceServer>,
+    /// Server time when the answer was created, in milliseconds since the Unix epoch
+    pub server_time: i64,
+    /// Token for refreshing the session on the session channel, if sessions expire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// When the session expires without a refresh, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// Signaling URL of the standby server to fail over to, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub standby_url: Option<String>,
+}
+
+/// A candidate gathered after the offer was sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrickleCandidate {
+    /// The session token from the [`SignalingAnswer`]
+    pub session_token: String,
+    /// The candidate in SDP attribute form, e.g. `candidate:1 1 udp ...`
+    pub candidate: String,
+}
+
+/// An ICE restart offer for an established session.
+///
+/// Sent by a peer whose network changed, e.g. when a rover moves from one
+/// access point to another; the server replies with the bare [`SdpAnswer`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestartOffer {
+    /// The session token from the [`SignalingAnswer`]
+    pub session_token: String,
+    /// The offer with the new ICE credentials
+    pub offer: SdpOffer,
+}
+
+/// A message of the WebSocket signaling protocol.
+///
+/// The peer opens with an [`Offer`](SignalingMessage::Offer) and receives an
+/// [`Answer`](SignalingMessage::Answer); both sides then trickle candidates
+/// until they send [`EndOfCandidates`](SignalingMessage::EndOfCandidates).
+/// The socket stays open so the peer can later send a
+/// [`Restart`](SignalingMessage::Restart) offer after a network change.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SignalingMessage {
+    /// The peer's SDP offer
+    Offer { offer: SdpOffer },
+    /// The server's answer with the session metadata
+    Answer { answer: SignalingAnswer },
+    /// A candidate in SDP attribute form
+    Candidate { candidate: String },
+    /// No more candidates will follow from the sender
+    EndOfCandidates,
+    /// The peer's ICE restart offer
+    Restart { offer: SdpOffer },
+    /// The server's answer to an ICE restart offer
+    RestartAnswer { answer: SdpAnswer },
+    /// The previous message could not be handled
+    Error { message: String },
+}
+
+/// Signaling answer body in either format, as decoded by peers.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum AnswerBody {
+    /// A structured answer from an up-to-date server
+    Structured(SignalingAnswer),
+    /// A bare answer from an older server
+    Bare(SdpAnswer),
+}
+
+impl AnswerBody {
+    /// Returns the SDP answer, whatever the format.
+    pub fn into_sdp(self) -> SdpAnswer {
+        match self {
+            AnswerBody::Structured(answer) => answer.answer,
+            AnswerBody::Bare(answer) => answer,
+        }
+    }
+
+    /// Returns the structured metadata, if the server sent any.
+    pub fn metadata(&self) -> Option<&SignalingAnswer> {
+        match self {
+            AnswerBody::Structured(answer) => Some(answer),
+            AnswerBody::Bare(_) => None,
+        }
+    }
+}
+
+/// An offer brokered to a listening peer in mesh mode.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MeshOffer {
+    /// Identifies the offer when answering it
+    pub id: String,
+    /// Who the offering peer is, if known: its authenticated subject when
+    /// brokered by the server, the alias it announced on the LAN
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// The offering peer's SDP offer
+    pub offer: SdpOffer,
+}
+
+/// A listening peer's answer to a [`MeshOffer`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MeshAnswer {
+    /// The ID of the answered offer
+    pub id: String,
+    /// The listening peer's SDP answer
+    pub answer: SdpAnswer,
+}
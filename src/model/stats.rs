@@ -0,0 +1,538 @@
+//! Per-client statistics history
+//!
+//! This module keeps bounded ring buffers of periodic metric samples and state
+//! changes for each client, so the server can answer time-windowed queries such
+//! as "latency and throughput for client 3 over the last 15 minutes".
+//!
+//! It also tracks the current [`ConnectionStats`] of a connection, combining
+//! the statistics str0m reports every [`STATS_INTERVAL`] with the traffic
+//! counted at the socket, for the clients of the server and the peer alike.
+//! The [`CodecTracker`] adds, per data channel, how its messages are encoded,
+//! the bytes the encoding saves or costs and the time spent on it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use chrono::Utc;
+use serde::Serialize;
+use str0m::{
+    media::{Mid, Rid},
+    Event, IceConnectionState,
+};
+
+use crate::model::control::{FeatureSet, Negotiation};
+use crate::model::heartbeat::LinkStats;
+
+/// Interval between two samples of a client's metrics.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of samples kept per client (one hour at the default interval).
+pub const HISTORY_CAPACITY: usize = 3600;
+
+/// Number of state changes kept per client.
+pub const STATE_CHANGE_CAPACITY: usize = 256;
+
+/// Interval between the statistics str0m reports for a connection.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Cumulative traffic counters maintained by a client.
+#[derive(Debug, Clone, Default)]
+pub struct TrafficCounters {
+    /// Total bytes received on data channels
+    pub bytes_received: u64,
+    /// Total bytes transmitted on the UDP socket
+    pub bytes_sent: u64,
+    /// Total data channel messages received
+    pub messages_received: u64,
+    /// Latency of the most recent timestamped payload, in milliseconds
+    pub last_latency_ms: Option<f64>,
+    /// The current ICE connection state
+    pub ice_state: Option<IceConnectionState>,
+    /// Link quality measured with heartbeats, if the client answers them
+    pub link: Option<LinkStats>,
+    /// Messages waiting in the outbound queues of congested channels
+    pub messages_queued: usize,
+    /// Messages the outbound queues dropped or superseded by newer ones
+    pub messages_dropped: u64,
+}
+
+/// A single sample of a client's metrics.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSample {
+    /// Sample time, in milliseconds since the Unix epoch
+    pub at: i64,
+    /// Latency of the most recent payload, in milliseconds
+    pub latency_ms: Option<f64>,
+    /// Bytes received per second since the previous sample
+    pub rx_bytes_per_sec: f64,
+    /// Bytes sent per second since the previous sample
+    pub tx_bytes_per_sec: f64,
+    /// Messages received per second since the previous sample
+    pub rx_messages_per_sec: f64,
+    /// Smoothed heartbeat round-trip time, in milliseconds
+    pub rtt_ms: Option<f64>,
+    /// Smoothed variation of the heartbeat round-trip time, in milliseconds
+    pub jitter_ms: Option<f64>,
+    /// Fraction of the recent heartbeats that were lost
+    pub loss: Option<f64>,
+    /// Messages waiting in the outbound queues
+    pub queued_messages: usize,
+    /// Messages the outbound queues dropped or coalesced so far
+    pub dropped_messages: u64,
+}
+
+/// A recorded change of a client's connection state.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateChange {
+    /// Change time, in milliseconds since the Unix epoch
+    pub at: i64,
+    /// The new state
+    pub state: String,
+}
+
+/// Time series returned by a windowed query.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsWindow {
+    /// The queried client
+    pub client_id: u64,
+    /// The client's alias, if it has one
+    pub alias: Option<String>,
+    /// The length of the window, in seconds
+    pub window_secs: u64,
+    /// Samples within the window, oldest first
+    pub samples: Vec<StatsSample>,
+    /// State changes within the window, oldest first
+    pub state_changes: Vec<StateChange>,
+    /// The negotiated protocol version, if the handshake completed
+    pub protocol_version: Option<u16>,
+    /// Whether the session fell back to plain data after a version mismatch
+    pub protocol_fallback: bool,
+    /// The optional features both sides can decode
+    pub features: Vec<&'static str>,
+}
+
+/// Ring buffers of a single client's samples and state changes.
+#[derive(Debug, Default)]
+pub struct StatsHistory {
+    samples: VecDeque<StatsSample>,
+    state_changes: VecDeque<StateChange>,
+    previous: Option<(i64, TrafficCounters)>,
+    protocol: Negotiation,
+    features: FeatureSet,
+}
+
+impl StatsHistory {
+    /// Creates an empty history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a sample from the client's current counters.
+    ///
+    /// Rates are computed against the counters of the previous sample; a state
+    /// change is recorded whenever the ICE state differs from the previous one.
+    pub fn record(&mut self, counters: &TrafficCounters) {
+        let now = Utc::now().timestamp_millis();
+
+        let (rx, tx, messages) = match &self.previous {
+            Some((at, prev)) => {
+                let secs = ((now - at) as f64 / 1000.0).max(0.001);
+                (
+                    counters.bytes_received.saturating_sub(prev.bytes_received) as f64 / secs,
+                    counters.bytes_sent.saturating_sub(prev.bytes_sent) as f64 / secs,
+                    counters
+                        .messages_received
+                        .saturating_sub(prev.messages_received) as f64
+                        / secs,
+                )
+            }
+            None => (0.0, 0.0, 0.0),
+        };
+
+        let previous_state = self.previous.as_ref().and_then(|(_, p)| p.ice_state);
+        if counters.ice_state != previous_state {
+            if let Some(state) = counters.ice_state {
+                push_bounded(
+                    &mut self.state_changes,
+                    StateChange {
+                        at: now,
+                        state: format!("{:?}", state),
+                    },
+                    STATE_CHANGE_CAPACITY,
+                );
+            }
+        }
+
+        push_bounded(
+            &mut self.samples,
+            StatsSample {
+                at: now,
+                latency_ms: counters.last_latency_ms,
+                rx_bytes_per_sec: rx,
+                tx_bytes_per_sec: tx,
+                rx_messages_per_sec: messages,
+                rtt_ms: counters.link.and_then(|link| link.rtt_ms),
+                jitter_ms: counters.link.and_then(|link| link.jitter_ms),
+                loss: counters.link.map(|link| link.loss),
+                queued_messages: counters.messages_queued,
+                dropped_messages: counters.messages_dropped,
+            },
+            HISTORY_CAPACITY,
+        );
+        self.previous = Some((now, counters.clone()));
+    }
+
+    /// Records the outcome of the client's protocol handshake.
+    pub fn set_protocol(&mut self, protocol: Negotiation, features: FeatureSet) {
+        self.protocol = protocol;
+        self.features = features;
+    }
+
+    /// Returns the samples and state changes within the given window, with
+    /// the negotiated protocol.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - The client the history belongs to
+    /// * `alias` - The client's alias, if it has one
+    /// * `window` - How far back from now to include entries
+    pub fn window(&self, client_id: u64, alias: Option<String>, window: Duration) -> StatsWindow {
+        let window_ms = i64::try_from(window.as_millis()).unwrap_or(i64::MAX);
+        let since = Utc::now().timestamp_millis().saturating_sub(window_ms);
+        StatsWindow {
+            client_id,
+            alias,
+            window_secs: window.as_secs(),
+            samples: self
+                .samples
+                .iter()
+                .filter(|s| s.at >= since)
+                .cloned()
+                .collect(),
+            state_changes: self
+                .state_changes
+                .iter()
+                .filter(|s| s.at >= since)
+                .cloned()
+                .collect(),
+            protocol_version: match self.protocol {
+                Negotiation::Agreed { version } => Some(version),
+                _ => None,
+            },
+            protocol_fallback: self.protocol.is_fallback(),
+            features: self.features.names(),
+        }
+    }
+}
+
+/// Current statistics of a connection, serialized to JSON for dashboards.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConnectionStats {
+    /// When the statistics were taken, in milliseconds since the Unix epoch
+    pub at: i64,
+    /// The current ICE connection state
+    pub ice_state: Option<String>,
+    /// The candidate pair carrying the traffic, once ICE selected one
+    pub selected_pair: Option<SelectedPair>,
+    /// Bytes sent on the UDP socket
+    pub bytes_sent: u64,
+    /// Bytes received on the UDP socket
+    pub bytes_received: u64,
+    /// Datagrams sent on the UDP socket
+    pub packets_sent: u64,
+    /// Datagrams received on the UDP socket
+    pub packets_received: u64,
+    /// RTP payload bytes sent
+    pub media_bytes_sent: u64,
+    /// RTP payload bytes received
+    pub media_bytes_received: u64,
+    /// Round-trip time measured by the WebRTC stack, in milliseconds
+    pub rtt_ms: Option<f64>,
+    /// Fraction of the media packets sent in the last second that were lost
+    pub egress_loss: Option<f32>,
+    /// Fraction of the media packets received since the last report that
+    /// were lost
+    pub ingress_loss: Option<f32>,
+    /// Estimated bandwidth towards the remote, in bits per second
+    pub bandwidth_estimate_bps: Option<u64>,
+    /// Retransmissions of media packets the remote asked for with NACKs
+    pub nacks_received: u64,
+    /// Data channel writes that failed and were kept for another attempt
+    pub write_retries: u64,
+    /// Link quality measured with heartbeats, if the remote answers them
+    pub heartbeat: Option<LinkStats>,
+    /// The open data channels
+    pub channels: Vec<ChannelStats>,
+}
+
+/// The candidate pair ICE selected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SelectedPair {
+    /// The transport protocol, e.g. `udp`
+    pub protocol: String,
+    /// The address of the local candidate
+    pub local: SocketAddr,
+    /// The address of the remote candidate
+    pub remote: SocketAddr,
+}
+
+/// Statistics of an open data channel.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChannelStats {
+    /// The label of the channel
+    pub label: String,
+    /// Bytes written but not yet sent by SCTP
+    pub buffered_amount: usize,
+    /// Messages waiting in the channel's send queue
+    pub queued_messages: usize,
+    /// How the channel's messages are encoded, and at what cost
+    pub codec: CodecStats,
+}
+
+/// Encoding of a data channel's messages, so the CPU time it costs can be
+/// weighed against the bandwidth it saves.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CodecStats {
+    /// The encodings applied to the messages, e.g. `fragment+batch`, or
+    /// `none`
+    pub codec: &'static str,
+    /// Application bytes encoded for sending
+    pub bytes_encoded: u64,
+    /// Bytes the encoded messages took on the channel
+    pub wire_bytes_sent: u64,
+    /// Bytes received on the channel
+    pub wire_bytes_received: u64,
+    /// Application bytes decoded from them
+    pub bytes_decoded: u64,
+    /// Application bytes per byte on the channel, both directions together;
+    /// below 1 when the framing costs more than it saves
+    pub compression_ratio: Option<f64>,
+    /// Time spent encoding, in microseconds
+    pub encode_us: u64,
+    /// Time spent decoding, in microseconds
+    pub decode_us: u64,
+}
+
+impl Default for CodecStats {
+    fn default() -> Self {
+        Self {
+            codec: CODEC_NONE,
+            bytes_encoded: 0,
+            wire_bytes_sent: 0,
+            wire_bytes_received: 0,
+            bytes_decoded: 0,
+            compression_ratio: None,
+            encode_us: 0,
+            decode_us: 0,
+        }
+    }
+}
+
+/// Name of the codec of messages sent as they are.
+pub const CODEC_NONE: &str = "none";
+
+/// Names the encodings applied to a channel's messages.
+///
+/// # Arguments
+///
+/// * `fragmented` - Whether messages are split to fit the path MTU
+/// * `batched` - Whether messages are framed as batches
+pub fn codec_name(fragmented: bool, batched: bool) -> &'static str {
+    match (fragmented, batched) {
+        (true, true) => "fragment+batch",
+        (true, false) => "fragment",
+        (false, true) => "batch",
+        (false, false) => CODEC_NONE,
+    }
+}
+
+/// Tracker of the [`CodecStats`] of a connection's data channels.
+#[derive(Debug, Default)]
+pub struct CodecTracker {
+    channels: HashMap<String, CodecStats>,
+}
+
+impl CodecTracker {
+    /// Creates a tracker without traffic.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts messages encoded for a channel.
+    ///
+    /// Batches are encoded once their window elapsed, so their wire bytes
+    /// may be counted apart from the application bytes they hold.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel
+    /// * `codec` - The name of the encodings applied, see [`codec_name`]
+    /// * `bytes` - The application bytes encoded
+    /// * `wire_bytes` - The bytes produced for the channel
+    /// * `elapsed` - The time spent encoding
+    pub fn encoded(
+        &mut self,
+        label: &str,
+        codec: &'static str,
+        bytes: usize,
+        wire_bytes: usize,
+        elapsed: Duration,
+    ) {
+        let stats = self.channels.entry(label.to_string()).or_default();
+        stats.codec = codec;
+        stats.bytes_encoded += bytes as u64;
+        stats.wire_bytes_sent += wire_bytes as u64;
+        stats.encode_us += elapsed.as_micros() as u64;
+    }
+
+    /// Counts a message decoded from a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel
+    /// * `codec` - The name of the encodings applied, see [`codec_name`]
+    /// * `wire_bytes` - The bytes received on the channel
+    /// * `bytes` - The application bytes decoded
+    /// * `elapsed` - The time spent decoding
+    pub fn decoded(
+        &mut self,
+        label: &str,
+        codec: &'static str,
+        wire_bytes: usize,
+        bytes: usize,
+        elapsed: Duration,
+    ) {
+        let stats = self.channels.entry(label.to_string()).or_default();
+        stats.codec = codec;
+        stats.wire_bytes_received += wire_bytes as u64;
+        stats.bytes_decoded += bytes as u64;
+        stats.decode_us += elapsed.as_micros() as u64;
+    }
+
+    /// Returns the statistics of a channel.
+    pub fn stats(&self, label: &str) -> CodecStats {
+        let Some(stats) = self.channels.get(label) else {
+            return CodecStats::default();
+        };
+        let wire = stats.wire_bytes_sent + stats.wire_bytes_received;
+        CodecStats {
+            compression_ratio: (wire > 0)
+                .then(|| (stats.bytes_encoded + stats.bytes_decoded) as f64 / wire as f64),
+            ..stats.clone()
+        }
+    }
+}
+
+/// Tracker of a connection's [`ConnectionStats`].
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    stats: ConnectionStats,
+    /// NACKs received so far, by media stream
+    nacks: HashMap<(Mid, Option<Rid>), u64>,
+}
+
+impl ConnectionTracker {
+    /// Creates a tracker without traffic.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a datagram sent on the socket.
+    pub fn sent(&mut self, bytes: usize) {
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += bytes as u64;
+    }
+
+    /// Counts a datagram received on the socket.
+    pub fn received(&mut self, bytes: usize) {
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += bytes as u64;
+    }
+
+    /// Counts a data channel write kept for another attempt.
+    pub fn write_retried(&mut self) {
+        self.stats.write_retries += 1;
+    }
+
+    /// Takes the ICE state and the statistics reports from an event of the
+    /// RTC instance; other events are ignored.
+    pub fn handle_event(&mut self, event: &Event) {
+        match event {
+            Event::IceConnectionStateChange(state) => {
+                self.stats.ice_state = Some(format!("{:?}", state));
+            }
+            Event::PeerStats(peer) => {
+                self.stats.media_bytes_sent = peer.bytes_tx;
+                self.stats.media_bytes_received = peer.bytes_rx;
+                self.stats.rtt_ms = peer.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+                self.stats.egress_loss = peer.egress_loss_fraction;
+                self.stats.ingress_loss = peer.ingress_loss_fraction;
+                self.stats.bandwidth_estimate_bps = peer.bwe_tx.map(|bwe| bwe.as_u64());
+                self.stats.selected_pair =
+                    peer.selected_candidate_pair
+                        .as_ref()
+                        .map(|pair| SelectedPair {
+                            protocol: pair.protocol.to_string(),
+                            local: pair.local.addr,
+                            remote: pair.remote.addr,
+                        });
+            }
+            Event::MediaEgressStats(egress) => {
+                // The counts are per stream and cumulative
+                self.nacks.insert((egress.mid, egress.rid), egress.nacks);
+                self.stats.nacks_received = self.nacks.values().sum();
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the current statistics.
+    ///
+    /// # Arguments
+    ///
+    /// * `heartbeat` - The link quality measured with heartbeats, if any
+    /// * `channels` - The statistics of the open data channels
+    pub fn snapshot(
+        &self,
+        heartbeat: Option<LinkStats>,
+        channels: Vec<ChannelStats>,
+    ) -> ConnectionStats {
+        ConnectionStats {
+            at: Utc::now().timestamp_millis(),
+            heartbeat,
+            channels,
+            ..self.stats.clone()
+        }
+    }
+}
+
+/// Parses a window such as `30s`, `15m`, `2h` or a bare number of seconds.
+///
+/// Returns `None` for anything else, including windows overflowing a `u64` of
+/// seconds.
+pub fn parse_window(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (value, unit) = match s.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&s[..i], c),
+        _ => (s, 's'),
+    };
+    let value: u64 = value.parse().ok()?;
+    let secs = match unit {
+        's' => value,
+        'm' => value.checked_mul(60)?,
+        'h' => value.checked_mul(3600)?,
+        'd' => value.checked_mul(86400)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(secs))
+}
+
+fn push_bounded<T>(buffer: &mut VecDeque<T>, item: T, capacity: usize) {
+    if buffer.len() == capacity {
+        buffer.pop_front();
+    }
+    buffer.push_back(item);
+}
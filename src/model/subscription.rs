@@ -0,0 +1,105 @@
+//! Per-channel subscriptions for data channel consumers
+//!
+//! On the rover, several subsystems (navigation, telemetry, diagnostics, ...)
+//! each care about their own data channel. Instead of contending over a single
+//! callback, each subscribes to a channel label and gets its own async receiver.
+//! Every message is fanned out to all subscribers of its channel.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+/// Number of messages buffered per subscriber before new messages are dropped.
+pub const SUBSCRIBER_CAPACITY: usize = 256;
+
+/// Senders of the subscribers of one channel.
+type Subscribers = Vec<mpsc::Sender<Vec<u8>>>;
+
+/// Subscribers of each data channel, by label.
+///
+/// Cloning yields another handle to the same set of subscribers, so consumers
+/// can subscribe from any task while the event loop dispatches messages.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelSubscriptions {
+    subscribers: Arc<Mutex<HashMap<String, Subscribers>>>,
+}
+
+impl ChannelSubscriptions {
+    /// Creates an empty set of subscriptions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes to the messages received on a channel.
+    ///
+    /// The subscription ends when the receiver is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the data channel, e.g. `telemetry`
+    ///
+    /// # Returns
+    ///
+    /// A receiver yielding the raw bytes of every message on the channel
+    pub fn subscribe(&self, label: &str) -> mpsc::Receiver<Vec<u8>> {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        self.subscribers
+            .lock()
+            .expect("subscriptions lock")
+            .entry(label.to_string())
+            .or_default()
+            .push(tx);
+        rx
+    }
+
+    /// Returns the number of live subscribers of a channel.
+    pub fn subscriber_count(&self, label: &str) -> usize {
+        self.subscribers
+            .lock()
+            .expect("subscriptions lock")
+            .get(label)
+            .map_or(0, |subs| subs.iter().filter(|s| !s.is_closed()).count())
+    }
+
+    /// Delivers a message to every subscriber of its channel.
+    ///
+    /// Never blocks: a subscriber whose buffer is full misses the message, so a
+    /// slow consumer cannot stall the event loop or the other subscribers.
+    /// Subscribers whose receiver was dropped are removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the channel the message arrived on
+    /// * `data` - The raw message bytes
+    ///
+    /// # Returns
+    ///
+    /// `true` if at least one subscriber received the message
+    pub fn dispatch(&self, label: &str, data: &[u8]) -> bool {
+        let mut subscribers = self.subscribers.lock().expect("subscriptions lock");
+        let Some(subs) = subscribers.get_mut(label) else {
+            return false;
+        };
+
+        let mut delivered = false;
+        subs.retain(|tx| match tx.try_send(data.to_vec()) {
+            Ok(()) => {
+                delivered = true;
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                warn!("Subscriber of '{}' is lagging, dropping message", label);
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+        if subs.is_empty() {
+            subscribers.remove(label);
+        }
+        delivered
+    }
+}
@@ -0,0 +1,100 @@
+//! Typed telemetry schema
+//!
+//! This module defines the structured telemetry samples sent by rovers on the
+//! "telemetry" data channel, so the server can act on their contents instead of
+//! treating them as opaque bytes.
+
+use bincode::config::{self, Configuration};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying telemetry samples.
+pub const TELEMETRY_CHANNEL: &str = "telemetry";
+
+/// A GPS position fix reported by a rover.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct GpsFix {
+    /// Latitude in decimal degrees
+    pub latitude: f64,
+    /// Longitude in decimal degrees
+    pub longitude: f64,
+    /// Altitude in meters
+    pub altitude: f32,
+    /// Time of the fix, in nanoseconds since the Unix epoch
+    pub timestamp: i64,
+}
+
+impl GpsFix {
+    /// Creates a new fix timestamped with the current time.
+    pub fn new(latitude: f64, longitude: f64, altitude: f32) -> Self {
+        Self {
+            latitude,
+            longitude,
+            altitude,
+            timestamp: Utc::now().timestamp_nanos_opt().unwrap_or(0),
+        }
+    }
+}
+
+/// A telemetry sample sent on the telemetry channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum Telemetry {
+    /// A GPS position fix
+    Gps(GpsFix),
+}
+
+impl Telemetry {
+    /// Serializes the sample for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a sample received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Telemetry)` - If the bytes contain a valid sample
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(sample, _)| sample)
+    }
+}
+
+impl WireSchema for GpsFix {
+    fn wire_schema() -> TypeDef {
+        TypeDef::structure(
+            "GpsFix",
+            "A GPS position fix reported by a rover",
+            vec![
+                field("latitude", WireType::F64, "Latitude in decimal degrees"),
+                field("longitude", WireType::F64, "Longitude in decimal degrees"),
+                field("altitude", WireType::F32, "Altitude in meters"),
+                field(
+                    "timestamp",
+                    WireType::I64,
+                    "Time of the fix, in nanoseconds since the Unix epoch",
+                ),
+            ],
+        )
+    }
+}
+
+impl WireSchema for Telemetry {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "Telemetry",
+            "A telemetry sample sent on the telemetry channel",
+            vec![(
+                "Gps",
+                "A GPS position fix",
+                vec![field("fix", WireType::Ref { name: "GpsFix" }, "The fix")],
+            )],
+        )
+    }
+}
@@ -19,6 +19,8 @@ use crate::model::client::ClientId;
 pub struct TrackIn {
     /// The client ID that originated this track
     pub(crate) origin: ClientId,
+    /// The name the originating client is logged under
+    pub(crate) origin_name: String,
     /// The media ID (Mid) assigned to this track
     pub(crate) mid: Mid,
     /// The kind of media (audio or video)
@@ -59,6 +61,11 @@ pub enum TrackOutState {
 }
 
 impl TrackOut {
+    /// Returns the source track, unless it was closed.
+    pub fn track_in(&self) -> Option<Arc<TrackIn>> {
+        self.track_in.upgrade()
+    }
+
     /// Gets the media ID (Mid) for this track, if assigned.
     ///
     /// # Returns
@@ -0,0 +1,315 @@
+//! File transfer protocol
+//!
+//! Rovers upload logs and images, and operators push files to rovers, on the
+//! reliable "transfer" data channel. The sender announces each file with a
+//! [`Manifest`] naming its size and SHA-256 digest, which doubles as the ID of
+//! the transfer. The receiver answers with an [`TransferMessage::Ack`] of the
+//! first chunk it still needs, so a transfer interrupted by a lost connection
+//! resumes where it stopped once the sender offers the file again. The file
+//! then travels in [`CHUNK_SIZE`] chunks with sequence numbers; the sender
+//! keeps at most [`WINDOW`] chunks beyond the last acknowledgement in flight,
+//! and the receiver acknowledges every [`ACK_INTERVAL`] chunks. A chunk lost
+//! on the way, e.g. dropped from a full send queue, is noticed by the
+//! receiver at the next one, which it reports with
+//! [`TransferMessage::Missing`]; the sender then goes back to it. Once the last
+//! chunk arrived the receiver checks the digest and answers with
+//! [`TransferMessage::Complete`], or [`TransferMessage::Failed`] if it does
+//! not match.
+
+use std::io;
+
+use bincode::config::{self, Configuration};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::model::schema::{field, TypeDef, WireSchema, WireType};
+
+const BINCODE_CONFIG: Configuration = config::standard();
+
+/// Label of the data channel carrying file transfers.
+pub const TRANSFER_CHANNEL: &str = "transfer";
+
+/// Number of file bytes per chunk, keeping each SCTP message small.
+pub const CHUNK_SIZE: u32 = 16 * 1024;
+
+/// Number of chunks a sender writes beyond the last acknowledged one.
+pub const WINDOW: u32 = 32;
+
+/// Number of chunks after which the receiver acknowledges.
+pub const ACK_INTERVAL: u32 = 8;
+
+/// Description of a file, sent before its chunks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct Manifest {
+    /// Hex SHA-256 digest of the file, identifying the transfer
+    pub id: String,
+    /// File name, without a path
+    pub name: String,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Number of file bytes per chunk, the last one excepted
+    pub chunk_size: u32,
+}
+
+impl Manifest {
+    /// Describes a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The file name
+    /// * `data` - The content of the file
+    pub fn new(name: &str, data: &[u8]) -> Self {
+        Self {
+            id: digest(data),
+            name: name.to_string(),
+            size: data.len() as u64,
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+
+    /// Returns the number of chunks of the file, at least one.
+    pub fn chunks(&self) -> u32 {
+        let chunk_size = u64::from(self.chunk_size.max(1));
+        self.size.div_ceil(chunk_size).max(1) as u32
+    }
+}
+
+/// Messages exchanged on the transfer channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub enum TransferMessage {
+    /// Sender to receiver: a file to transfer, sent again after a reconnect
+    Offer { manifest: Manifest },
+    /// Receiver to sender: every chunk before `next` arrived
+    Ack { id: String, next: u32 },
+    /// Receiver to sender: chunk `next` did not arrive, while a later one did
+    Missing { id: String, next: u32 },
+    /// Sender to receiver: a slice of the file
+    Chunk { id: String, seq: u32, data: Vec<u8> },
+    /// Receiver to sender: the file arrived and matches its digest
+    Complete { id: String },
+    /// Either side: the transfer was refused or the file did not match its
+    /// digest; the sender gives up on it
+    Failed { id: String, reason: String },
+}
+
+impl TransferMessage {
+    /// Serializes the message for transmission on the data channel.
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, BINCODE_CONFIG).expect("Serialization failed")
+    }
+
+    /// Deserializes a message received on the data channel.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(TransferMessage)` - If the bytes contain a valid message
+    /// * `None` - If the bytes could not be decoded
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG)
+            .ok()
+            .map(|(message, _)| message)
+    }
+}
+
+/// Returns the hex SHA-256 digest of some data.
+pub fn digest(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+/// Returns the hex SHA-256 digest of everything a reader yields, e.g. of a
+/// received file.
+pub fn digest_reader(mut reader: impl io::Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Returns `true` if a string is a transfer ID, i.e. a hex SHA-256 digest,
+/// and so safe to use in a file name.
+pub fn is_transfer_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The sending side of a transfer, with the file in memory.
+#[derive(Debug, Clone)]
+pub struct OutgoingTransfer {
+    manifest: Manifest,
+    data: Vec<u8>,
+    /// Whether the offer was sent in the current session
+    offered: bool,
+    /// The first chunk the receiver still needs, once it acknowledged
+    acked: Option<u32>,
+    /// The next chunk to send
+    next: u32,
+}
+
+impl OutgoingTransfer {
+    /// Prepares a file for sending.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The file name the receiver stores it under
+    /// * `data` - The content of the file
+    pub fn new(name: &str, data: Vec<u8>) -> Self {
+        Self {
+            manifest: Manifest::new(name, &data),
+            data,
+            offered: false,
+            acked: None,
+            next: 0,
+        }
+    }
+
+    /// Returns the description of the file.
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Returns the number of chunks the receiver acknowledged.
+    pub fn acked(&self) -> u32 {
+        self.acked.unwrap_or(0)
+    }
+
+    /// Offers the file again, after the connection was re-established; the
+    /// receiver's acknowledgement tells where to resume.
+    pub fn restart(&mut self) {
+        self.offered = false;
+        self.acked = None;
+    }
+
+    /// Handles the receiver's acknowledgement.
+    ///
+    /// # Arguments
+    ///
+    /// * `next` - The first chunk the receiver still needs
+    pub fn handle_ack(&mut self, next: u32) {
+        let next = next.min(self.manifest.chunks());
+        if self.acked.is_none() {
+            // The answer to the offer tells where to resume
+            self.next = next;
+        }
+        self.acked = Some(self.acked.map_or(next, |acked| acked.max(next)));
+        self.next = self.next.max(next);
+    }
+
+    /// Goes back to a chunk the receiver reported missing; every chunk
+    /// before it arrived.
+    pub fn handle_missing(&mut self, next: u32) {
+        let next = next.min(self.manifest.chunks());
+        self.acked = Some(self.acked.map_or(next, |acked| acked.max(next)));
+        self.next = next;
+    }
+
+    /// Returns the messages to send now: the offer, then the chunks the
+    /// window allows once the receiver answered it.
+    pub fn poll(&mut self) -> Vec<TransferMessage> {
+        if !self.offered {
+            self.offered = true;
+            return vec![TransferMessage::Offer {
+                manifest: self.manifest.clone(),
+            }];
+        }
+        let Some(acked) = self.acked else {
+            return vec![];
+        };
+        let end = self.manifest.chunks().min(acked.saturating_add(WINDOW));
+        let chunk_size = self.manifest.chunk_size as usize;
+        let mut messages = vec![];
+        while self.next < end {
+            let start = self.next as usize * chunk_size;
+            let stop = (start + chunk_size).min(self.data.len());
+            messages.push(TransferMessage::Chunk {
+                id: self.manifest.id.clone(),
+                seq: self.next,
+                data: self.data[start..stop].to_vec(),
+            });
+            self.next += 1;
+        }
+        messages
+    }
+}
+
+impl WireSchema for Manifest {
+    fn wire_schema() -> TypeDef {
+        TypeDef::structure(
+            "Manifest",
+            "Description of a file, sent before its chunks",
+            vec![
+                field(
+                    "id",
+                    WireType::String,
+                    "Hex SHA-256 digest of the file, identifying the transfer",
+                ),
+                field("name", WireType::String, "File name, without a path"),
+                field("size", WireType::U64, "Size of the file in bytes"),
+                field(
+                    "chunk_size",
+                    WireType::U32,
+                    "Number of file bytes per chunk, the last one excepted",
+                ),
+            ],
+        )
+    }
+}
+
+impl WireSchema for TransferMessage {
+    fn wire_schema() -> TypeDef {
+        TypeDef::enumeration(
+            "TransferMessage",
+            "Messages exchanged on the transfer channel",
+            vec![
+                (
+                    "Offer",
+                    "Sender to receiver: a file to transfer, sent again after a reconnect",
+                    vec![field(
+                        "manifest",
+                        WireType::Ref { name: "Manifest" },
+                        "The file",
+                    )],
+                ),
+                (
+                    "Ack",
+                    "Receiver to sender: every chunk before next arrived",
+                    vec![
+                        field("id", WireType::String, "The transfer"),
+                        field("next", WireType::U32, "The first chunk still needed"),
+                    ],
+                ),
+                (
+                    "Missing",
+                    "Receiver to sender: chunk next did not arrive, while a later one did",
+                    vec![
+                        field("id", WireType::String, "The transfer"),
+                        field("next", WireType::U32, "The chunk to send again from"),
+                    ],
+                ),
+                (
+                    "Chunk",
+                    "Sender to receiver: a slice of the file",
+                    vec![
+                        field("id", WireType::String, "The transfer"),
+                        field("seq", WireType::U32, "Zero-based chunk index"),
+                        field("data", WireType::Bytes, "The bytes of the chunk"),
+                    ],
+                ),
+                (
+                    "Complete",
+                    "Receiver to sender: the file arrived and matches its digest",
+                    vec![field("id", WireType::String, "The transfer")],
+                ),
+                (
+                    "Failed",
+                    "Either side: the transfer was refused or the file did not match its digest",
+                    vec![
+                        field("id", WireType::String, "The transfer"),
+                        field("reason", WireType::String, "What went wrong"),
+                    ],
+                ),
+            ],
+        )
+    }
+}
@@ -0,0 +1,119 @@
+//! The socket and the clock of the server's event loop
+//!
+//! The event loop sends the transmits of its clients through a [`Transport`]
+//! and reads the time from a [`Clock`]. The server runs on its UDP socket and
+//! the [`SystemClock`]; tests replay recorded traces through the same
+//! handling with a [`MemoryTransport`], which keeps what was sent instead of
+//! sending it, and a [`ManualClock`], which only moves when told to, so the
+//! outcome of a replay does not depend on the machine or its load.
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::util::pcap;
+
+/// Where the event loop sends datagrams.
+pub trait Transport: Send + Sync {
+    /// Sends a datagram.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes sent
+    fn send_to(&self, payload: &[u8], destination: SocketAddr) -> io::Result<usize>;
+}
+
+impl Transport for UdpSocket {
+    /// Sends through the packet capture and any simulated impairment.
+    fn send_to(&self, payload: &[u8], destination: SocketAddr) -> io::Result<usize> {
+        pcap::send_to(self, payload, destination)
+    }
+}
+
+/// Where the event loop reads the time.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+}
+
+/// The clock of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until advanced.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl ManualClock {
+    /// Creates a clock showing the given time.
+    pub fn new(start: Instant) -> Self {
+        Self {
+            now: Mutex::new(start),
+        }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().expect("clock lock") += by;
+    }
+
+    /// Moves the clock forward to the given time; earlier times are ignored.
+    pub fn advance_to(&self, to: Instant) {
+        let mut now = self.now.lock().expect("clock lock");
+        *now = (*now).max(to);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("clock lock")
+    }
+}
+
+/// A datagram handed to a [`MemoryTransport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transmitted {
+    /// Where it was sent
+    pub destination: SocketAddr,
+    /// The datagram
+    pub payload: Vec<u8>,
+}
+
+/// A transport keeping the datagrams sent, in order, instead of sending them.
+#[derive(Debug, Default)]
+pub struct MemoryTransport {
+    sent: Mutex<Vec<Transmitted>>,
+}
+
+impl MemoryTransport {
+    /// Creates a transport that sent nothing yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the datagrams sent since the last call.
+    pub fn take(&self) -> Vec<Transmitted> {
+        std::mem::take(&mut *self.sent.lock().expect("transport lock"))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&self, payload: &[u8], destination: SocketAddr) -> io::Result<usize> {
+        self.sent.lock().expect("transport lock").push(Transmitted {
+            destination,
+            payload: payload.to_vec(),
+        });
+        Ok(payload.len())
+    }
+}
@@ -0,0 +1,286 @@
+//! Video streaming from the peer
+//!
+//! A rover's camera pipeline encodes frames itself and hands them to the peer
+//! through [`PeerHandle::send_video_frame`](crate::peer::PeerHandle::send_video_frame).
+//! The peer negotiates one send-only video m-line in its offer and writes the
+//! frames as RTP once ICE connected; str0m packetizes them for the negotiated
+//! payload type. Keyframe requests from the server are reported as
+//! [`PeerEvent::KeyframeRequested`](crate::peer::PeerEvent::KeyframeRequested)
+//! so the encoder can produce one, and frames that could not be decoded
+//! without a missing keyframe are dropped instead of being sent.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use str0m::{
+    format::Codec,
+    media::{MediaTime, Mid},
+    Rtc, RtcConfig, RtcError,
+};
+use tracing::{debug, info, warn};
+
+use crate::model::tracks::TrackOutState;
+
+/// Video codecs the peer can send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    /// H.264 frames as an Annex B byte stream, with start codes
+    H264,
+    /// VP8 frames as produced by the encoder
+    Vp8,
+}
+
+impl VideoCodec {
+    /// The str0m codec of the payload types this codec is sent with.
+    pub fn codec(self) -> Codec {
+        match self {
+            VideoCodec::H264 => Codec::H264,
+            VideoCodec::Vp8 => Codec::Vp8,
+        }
+    }
+}
+
+/// Video settings of the peer, the `[peer.video]` section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// Negotiate a video track and send the frames queued through the handle
+    pub enabled: bool,
+    /// The codec the frames are encoded with; the only one offered
+    pub codec: VideoCodec,
+    /// Number of frames queued while the connection is not ready; once full,
+    /// the queue is emptied and sending resumes with the next keyframe
+    pub queue_frames: usize,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            codec: VideoCodec::H264,
+            queue_frames: 30,
+        }
+    }
+}
+
+impl VideoConfig {
+    /// Checks that frames can be queued.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.queue_frames == 0 {
+            return Err("queue_frames must be positive".into());
+        }
+        Ok(())
+    }
+
+    /// Enables the configured codec in an RTC configuration, if video is
+    /// enabled.
+    pub fn enable_codecs(&self, rtc_config: RtcConfig) -> RtcConfig {
+        if !self.enabled {
+            return rtc_config;
+        }
+        match self.codec {
+            VideoCodec::H264 => rtc_config.enable_h264(true),
+            VideoCodec::Vp8 => rtc_config.enable_vp8(true),
+        }
+    }
+}
+
+/// An encoded video frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoFrame {
+    /// The encoded frame, in the format of the configured [`VideoCodec`]
+    pub data: Vec<u8>,
+    /// Whether the frame decodes without any earlier frame
+    pub keyframe: bool,
+    /// When the frame was captured, from which its RTP timestamp is derived
+    pub captured_at: Instant,
+}
+
+impl VideoFrame {
+    /// Creates a frame captured now.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The encoded frame
+    /// * `keyframe` - Whether the frame decodes without any earlier frame
+    pub fn new(data: Vec<u8>, keyframe: bool) -> Self {
+        Self {
+            data,
+            keyframe,
+            captured_at: Instant::now(),
+        }
+    }
+}
+
+/// Frames waiting for the event loop.
+#[derive(Debug, Default)]
+pub struct VideoQueue {
+    frames: VecDeque<VideoFrame>,
+    /// Maximum number of queued frames; zero while video is disabled
+    capacity: usize,
+    /// Whether frames were dropped since the last call to [`VideoQueue::take`]
+    overflowed: bool,
+}
+
+impl VideoQueue {
+    /// Drops the queued frames, e.g. those of a previous session, and sizes
+    /// the queue for a configuration.
+    pub fn reset(&mut self, config: &VideoConfig) {
+        self.frames.clear();
+        self.capacity = if config.enabled {
+            config.queue_frames
+        } else {
+            0
+        };
+        self.overflowed = false;
+    }
+
+    /// Queues a frame, emptying the queue first if it is full.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the frame or queued frames were dropped
+    pub fn push(&mut self, frame: VideoFrame) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        let room = self.frames.len() < self.capacity;
+        if !room {
+            // The remaining frames would not decode without the dropped ones
+            self.frames.clear();
+            self.overflowed = true;
+        }
+        self.frames.push_back(frame);
+        room
+    }
+
+    /// Takes the queued frames.
+    ///
+    /// # Returns
+    ///
+    /// The frames, oldest first, and whether frames were dropped before them
+    pub fn take(&mut self) -> (Vec<VideoFrame>, bool) {
+        let overflowed = std::mem::take(&mut self.overflowed);
+        (self.frames.drain(..).collect(), overflowed)
+    }
+}
+
+/// The outgoing video track of a session.
+#[derive(Debug)]
+pub struct VideoTrack {
+    /// The codec the frames are encoded with
+    codec: VideoCodec,
+    /// Negotiation state of the m-line
+    state: TrackOutState,
+    /// Whether frames are dropped until the next keyframe
+    awaiting_keyframe: bool,
+    /// Capture time of the first frame sent, the origin of the RTP timestamps
+    epoch: Option<Instant>,
+    /// Number of frames written
+    pub frames_sent: u64,
+    /// Number of frames dropped while waiting for a keyframe
+    pub frames_dropped: u64,
+}
+
+impl VideoTrack {
+    /// Creates a track still to be added to an offer.
+    pub fn new(codec: VideoCodec) -> Self {
+        Self {
+            codec,
+            state: TrackOutState::ToOpen,
+            awaiting_keyframe: true,
+            epoch: None,
+            frames_sent: 0,
+            frames_dropped: 0,
+        }
+    }
+
+    /// Records the media ID the track was given in the offer.
+    pub fn negotiating(&mut self, mid: Mid) {
+        self.state = TrackOutState::Negotiating(mid);
+    }
+
+    /// Marks the track open once the answer to its offer was accepted.
+    ///
+    /// # Returns
+    ///
+    /// The media ID of the track, or `None` if it was not offered
+    pub fn answered(&mut self) -> Option<Mid> {
+        let TrackOutState::Negotiating(mid) = self.state else {
+            return None;
+        };
+        self.state = TrackOutState::Open(mid);
+        Some(mid)
+    }
+
+    /// Returns `true` if `mid` is the media ID of this track.
+    pub fn is_track(&self, mid: Mid) -> bool {
+        match self.state {
+            TrackOutState::ToOpen => false,
+            TrackOutState::Negotiating(m) | TrackOutState::Open(m) => m == mid,
+        }
+    }
+
+    /// Drops frames until the next keyframe, e.g. after queued frames were
+    /// dropped.
+    pub fn await_keyframe(&mut self) {
+        self.awaiting_keyframe = true;
+    }
+
+    /// Writes a frame to the track.
+    ///
+    /// Frames preceding the first keyframe, and those following dropped
+    /// frames until the next keyframe, are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `rtc` - The RTC instance of the session, with ICE connected
+    /// * `frame` - The frame to send
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the frame was written
+    /// * `Ok(false)` - If the frame was dropped
+    /// * `Err(RtcError)` - If str0m refused the frame
+    pub fn write(&mut self, rtc: &mut Rtc, frame: VideoFrame) -> Result<bool, RtcError> {
+        let TrackOutState::Open(mid) = self.state else {
+            return Ok(false);
+        };
+        if self.awaiting_keyframe && !frame.keyframe {
+            self.frames_dropped += 1;
+            return Ok(false);
+        }
+        let Some(writer) = rtc.writer(mid) else {
+            return Ok(false);
+        };
+        let codec = self.codec.codec();
+        let Some(pt) = writer
+            .payload_params()
+            .find(|params| params.spec().codec == codec)
+            .map(|params| params.pt())
+        else {
+            warn!("Peer: The server accepted no {:?} payload type", codec);
+            return Ok(false);
+        };
+
+        let epoch = *self.epoch.get_or_insert(frame.captured_at);
+        let elapsed = frame.captured_at.saturating_duration_since(epoch);
+        writer.write(pt, Instant::now(), rtp_time(elapsed), frame.data)?;
+        if self.awaiting_keyframe {
+            info!("Peer: Sending video from a keyframe");
+            self.awaiting_keyframe = false;
+        }
+        self.frames_sent += 1;
+        debug!("Peer: Sent video frame #{}", self.frames_sent);
+        Ok(true)
+    }
+}
+
+/// Converts the time since the first frame into a 90 kHz RTP timestamp.
+fn rtp_time(elapsed: Duration) -> MediaTime {
+    MediaTime::from_90khz((elapsed.as_micros() * 9 / 100) as u64)
+}
@@ -0,0 +1,595 @@
+//! Operator-side connection multiplexer
+//!
+//! Mission control drives several rovers from one process. For each rover
+//! alias in the `[operator]` section, the operator runs a mesh peer
+//! connecting to it directly (the rovers run with `--mesh-listen`), subscribes
+//! to its telemetry channels and merges them into one local API:
+//! - `GET /rovers` lists the rovers, their connection state and statistics
+//! - `GET /telemetry` returns the latest message of every rover and channel
+//! - `GET /selected` and `PUT /selected` with an alias read and change the
+//!   rover commands go to
+//! - `POST /commands` sends the request body to the selected rover
+//! - `POST /rovers/{alias}/commands` sends the request body to a given rover
+//! - `GET /stream` upgrades to a WebSocket pushing every telemetry message and
+//!   connection change, each a JSON [`OperatorUpdate`]
+//!
+//! Commands go out on the configured command channel of the rover. The
+//! `[[operator.rules]]` act on the telemetry like the server's rules, see
+//! [`rules`](crate::model::rules), sending to the rovers by alias.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Read,
+    net::SocketAddr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rouille::{websocket, Request, Response, Server};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::model::bus::EventTopic;
+use crate::model::heartbeat::LinkStats;
+use crate::model::registry::is_valid_alias;
+use crate::model::rules::{Rule, RuleAction, RuleEngine, RuleFiring, RuleLogLevel};
+use crate::model::stats::ConnectionStats;
+use crate::model::telemetry::TELEMETRY_CHANNEL;
+use crate::peer::{PeerConfig, PeerEvent, PeerHandle};
+use crate::rover::{RoverPeer, RoverRtc};
+use crate::util::{init_log, shutdown::Shutdown};
+
+/// Operator settings, the `[operator]` configuration section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OperatorConfig {
+    /// Address of the local API, e.g. `127.0.0.1:4000`
+    pub listen_addr: String,
+    /// Aliases of the rovers to connect to, each listening in mesh mode
+    pub rovers: Vec<String>,
+    /// Channels whose messages are aggregated as telemetry
+    pub telemetry_channels: Vec<String>,
+    /// Channel commands are sent on
+    pub command_channel: String,
+    /// Rules acting on the telemetry of the rovers
+    pub rules: Vec<Rule>,
+}
+
+impl Default for OperatorConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "127.0.0.1:4000".into(),
+            rovers: Vec::new(),
+            telemetry_channels: vec![TELEMETRY_CHANNEL.into()],
+            command_channel: "commands".into(),
+            rules: vec![],
+        }
+    }
+}
+
+impl OperatorConfig {
+    /// Checks the settings.
+    ///
+    /// # Returns
+    ///
+    /// A description of the first invalid setting, relative to the section
+    pub fn validate(&self) -> Result<(), String> {
+        self.listen_addr
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("listen_addr '{}': {}", self.listen_addr, e))?;
+        let mut rovers = HashSet::new();
+        for rover in &self.rovers {
+            if !is_valid_alias(rover) {
+                return Err(format!("rovers: '{}' is not a valid alias", rover));
+            }
+            if !rovers.insert(rover) {
+                return Err(format!("rovers contains '{}' twice", rover));
+            }
+        }
+        if self.telemetry_channels.iter().any(String::is_empty) {
+            return Err("telemetry_channels must not contain an empty label".into());
+        }
+        if self.command_channel.is_empty() {
+            return Err("command_channel must not be empty".into());
+        }
+        Ok(())
+    }
+
+    /// Returns the configuration of the peer connecting to a rover.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The base peer settings
+    /// * `rover` - The alias the rover listens under
+    fn rover_peer_config(&self, peer: &PeerConfig, rover: &str) -> PeerConfig {
+        let mut channels = peer.channels.clone();
+        for label in self
+            .telemetry_channels
+            .iter()
+            .chain([&self.command_channel])
+        {
+            if !channels.contains(label) {
+                channels.push(label.clone());
+            }
+        }
+        PeerConfig {
+            mesh_target: Some(rover.to_string()),
+            mesh_listen: false,
+            channels,
+            ..peer.clone()
+        }
+    }
+}
+
+/// The connection state of a rover.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoverState {
+    /// Signaling or ICE is in progress
+    Connecting,
+    /// ICE connected to the rover
+    Connected,
+    /// The path changed and ICE is restarting
+    Restarting,
+    /// The connection was lost and signaling runs again
+    Reconnecting,
+    /// The connection was lost or stopped
+    Disconnected,
+}
+
+/// The payload of a telemetry message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageData {
+    /// A UTF-8 message
+    Text(String),
+    /// A binary message, base64 encoded
+    Base64(String),
+}
+
+impl MessageData {
+    fn new(data: Vec<u8>) -> Self {
+        match String::from_utf8(data) {
+            Ok(text) => Self::Text(text),
+            Err(e) => Self::Base64(base64::engine::general_purpose::STANDARD.encode(e.as_bytes())),
+        }
+    }
+}
+
+/// An update pushed to the stream subscribers.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OperatorUpdate {
+    /// A message arrived on a telemetry channel of a rover
+    Telemetry {
+        rover: String,
+        channel: String,
+        at: DateTime<Utc>,
+        #[serde(flatten)]
+        data: MessageData,
+    },
+    /// The connection to a rover changed state
+    Connection {
+        rover: String,
+        state: RoverState,
+        at: DateTime<Utc>,
+    },
+}
+
+/// A rover as listed by `GET /rovers`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoverStatus {
+    pub alias: String,
+    pub state: RoverState,
+    /// Whether commands go to this rover
+    pub selected: bool,
+    /// Link quality measured with heartbeats
+    pub link: Option<LinkStats>,
+    /// Statistics of the current session's connection
+    pub connection: Option<ConnectionStats>,
+}
+
+/// A connected rover.
+struct Rover {
+    handle: PeerHandle,
+    state: RoverState,
+}
+
+/// State shared between the rover subscriptions and the local API.
+#[derive(Default)]
+struct Mux {
+    rovers: BTreeMap<String, Rover>,
+    /// Latest telemetry message per rover and channel
+    latest: BTreeMap<(String, String), OperatorUpdate>,
+    selected: Option<String>,
+    streams: Vec<mpsc::Sender<String>>,
+    rules: RuleEngine,
+    command_channel: String,
+}
+
+impl Mux {
+    /// Records an update and pushes it to the stream subscribers, dropping
+    /// the ones that went away.
+    fn publish(&mut self, update: OperatorUpdate) {
+        let text = serde_json::to_string(&update).expect("operator update to serialise");
+        self.streams
+            .retain(|stream| stream.send(text.clone()).is_ok());
+        match update {
+            OperatorUpdate::Telemetry {
+                ref rover,
+                ref channel,
+                ..
+            } => {
+                self.latest
+                    .insert((rover.clone(), channel.clone()), update.clone());
+            }
+            OperatorUpdate::Connection { rover, state, .. } => {
+                if let Some(entry) = self.rovers.get_mut(&rover) {
+                    entry.state = state;
+                }
+            }
+        }
+    }
+
+    /// Runs the actions of a rule that fired on a rover's telemetry.
+    ///
+    /// Messages go to the rover the telemetry came from, or the one the
+    /// action names, on the command channel unless the action names another.
+    fn run_rule_actions(&self, firing: &RuleFiring) {
+        info!(
+            "Rule '{}' fired for {} on '{}'",
+            firing.rule, firing.source, firing.topic
+        );
+        for action in &firing.actions {
+            match action {
+                RuleAction::Send {
+                    message,
+                    channel,
+                    to,
+                } => {
+                    let alias = to.as_ref().unwrap_or(&firing.source);
+                    match self.rovers.get(alias) {
+                        Some(rover) => rover.handle.send(
+                            channel.as_deref().unwrap_or(&self.command_channel),
+                            message.clone().into_bytes(),
+                        ),
+                        None => warn!("Rule '{}' has no rover '{}' to send to", firing.rule, alias),
+                    }
+                }
+                RuleAction::Webhook { url } => {
+                    let url = url.clone();
+                    let body = firing.clone();
+                    thread::spawn(move || {
+                        let result = reqwest::blocking::Client::new()
+                            .post(&url)
+                            .json(&body)
+                            .send();
+                        if let Err(e) = result {
+                            warn!("Rule webhook to {} failed: {}", url, e);
+                        }
+                    });
+                }
+                RuleAction::Log { message, level } => match level {
+                    RuleLogLevel::Info => info!("Rule '{}': {}", firing.rule, message),
+                    RuleLogLevel::Warn => warn!("Rule '{}': {}", firing.rule, message),
+                },
+            }
+        }
+    }
+
+    fn statuses(&self) -> Vec<RoverStatus> {
+        self.rovers
+            .iter()
+            .map(|(alias, rover)| RoverStatus {
+                alias: alias.clone(),
+                state: rover.state,
+                selected: self.selected.as_ref() == Some(alias),
+                link: rover.handle.link_stats(),
+                connection: rover.handle.stats(),
+            })
+            .collect()
+    }
+}
+
+/// Handle controlling a running operator.
+pub struct OperatorHandle {
+    http_addr: SocketAddr,
+    peers: Vec<RoverPeer>,
+    shutdown: Shutdown,
+    http_stop: mpsc::Sender<()>,
+    http_thread: thread::JoinHandle<()>,
+}
+
+impl OperatorHandle {
+    /// Returns the address the local API listens on.
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http_addr
+    }
+
+    /// Returns the handle stopping the operator from another thread or a
+    /// signal handler.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Blocks until the operator is shut down, then stops the rover peers
+    /// and the local API.
+    pub fn join(mut self) {
+        match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime.block_on(self.shutdown.triggered()),
+            Err(e) => warn!("Failed to create the runtime waiting for shutdown: {}", e),
+        }
+        for peer in &self.peers {
+            peer.handle().stop();
+        }
+        for peer in &mut self.peers {
+            if let Err(e) = peer.wait() {
+                warn!("Rover peer stopped with an error: {}", e);
+            }
+        }
+        let _ = self.http_stop.send(());
+        let _ = self.http_thread.join();
+    }
+}
+
+/// Main entry point for the operator.
+///
+/// Initializes logging and runs the operator with the given configuration
+/// until ctrl-c is pressed.
+///
+/// # Arguments
+///
+/// * `config` - The operator settings
+/// * `peer` - The peer settings each rover connection starts from
+pub fn main(config: OperatorConfig, peer: PeerConfig) -> anyhow::Result<()> {
+    init_log();
+    let operator = start(config, peer)?;
+    if let Err(e) = operator.shutdown().trigger_on_ctrl_c() {
+        warn!("Failed to install the ctrl-c handler: {}", e);
+    }
+    operator.join();
+    info!("Operator stopped");
+    Ok(())
+}
+
+/// Starts the operator.
+///
+/// Starts one peer per rover, each on its own thread, then the local API.
+/// Logging is left to the caller.
+///
+/// # Arguments
+///
+/// * `config` - The operator settings
+/// * `peer` - The peer settings each rover connection starts from
+///
+/// # Returns
+///
+/// A handle controlling the running operator, or an error if no rover is
+/// configured or a peer or the local API could not be started
+pub fn start(config: OperatorConfig, peer: PeerConfig) -> anyhow::Result<OperatorHandle> {
+    if config.rovers.is_empty() {
+        anyhow::bail!("operator.rovers must name at least one rover");
+    }
+    let mux = Arc::new(Mutex::new(Mux {
+        selected: (config.rovers.len() == 1).then(|| config.rovers[0].clone()),
+        rules: RuleEngine::new(config.rules.clone()),
+        command_channel: config.command_channel.clone(),
+        ..Mux::default()
+    }));
+
+    let mut peers = Vec::new();
+    for rover in &config.rovers {
+        let peer = RoverRtc::builder()
+            .peer_config(config.rover_peer_config(&peer, rover))
+            .build_peer();
+        let handle = peer.handle().clone();
+        {
+            let alias = rover.clone();
+            let mux = mux.clone();
+            let topics = [EventTopic::Lifecycle, EventTopic::Handover];
+            handle.events().subscribe(&topics, move |event| {
+                let state = match event {
+                    PeerEvent::Connected => RoverState::Connected,
+                    PeerEvent::Restarting => RoverState::Restarting,
+                    PeerEvent::Reconnecting { .. } => RoverState::Reconnecting,
+                    PeerEvent::Disconnected => RoverState::Disconnected,
+                    _ => return,
+                };
+                info!("Rover {} is {:?}", alias, state);
+                mux.lock()
+                    .expect("operator lock")
+                    .publish(OperatorUpdate::Connection {
+                        rover: alias.clone(),
+                        state,
+                        at: Utc::now(),
+                    });
+            });
+        }
+        for label in &config.telemetry_channels {
+            let mut messages = handle.subscribe(label);
+            let (alias, channel, mux) = (rover.clone(), label.clone(), mux.clone());
+            thread::spawn(move || {
+                while let Some(data) = messages.blocking_recv() {
+                    let mut mux = mux.lock().expect("operator lock");
+                    let fired = mux.rules.evaluate(&alias, &channel, &data, Instant::now());
+                    mux.publish(OperatorUpdate::Telemetry {
+                        rover: alias.clone(),
+                        channel: channel.clone(),
+                        at: Utc::now(),
+                        data: MessageData::new(data),
+                    });
+                    for firing in fired {
+                        mux.run_rule_actions(&firing);
+                    }
+                }
+            });
+        }
+        mux.lock().expect("operator lock").rovers.insert(
+            rover.clone(),
+            Rover {
+                handle,
+                state: RoverState::Connecting,
+            },
+        );
+        peers.push(peer);
+    }
+    for peer in &mut peers {
+        peer.start()?;
+    }
+
+    let command_channel = config.command_channel.clone();
+    let api = mux.clone();
+    let server = Server::new(&config.listen_addr, move |request| {
+        api_request(request, &api, &command_channel)
+    })
+    .map_err(|e| anyhow::anyhow!("starting the operator API: {}", e))?;
+    let http_addr = server.server_addr();
+    info!(
+        "Operator API listening on http://{} for {} rovers",
+        http_addr,
+        config.rovers.len()
+    );
+    let (http_thread, http_stop) = server.stoppable();
+
+    Ok(OperatorHandle {
+        http_addr,
+        peers,
+        shutdown: Shutdown::new(),
+        http_stop,
+        http_thread,
+    })
+}
+
+/// Handles a request to the local API, see the module documentation for the
+/// routes.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request
+/// * `mux` - State shared with the rover subscriptions
+/// * `command_channel` - The channel commands are sent on
+fn api_request(request: &Request, mux: &Mutex<Mux>, command_channel: &str) -> Response {
+    let url = request.url();
+    match (request.method(), url.as_str()) {
+        ("GET", "/rovers") => Response::json(&mux.lock().expect("operator lock").statuses()),
+        ("GET", "/telemetry") => {
+            let mux = mux.lock().expect("operator lock");
+            Response::json(&mux.latest.values().collect::<Vec<_>>())
+        }
+        ("GET", "/selected") => Response::json(&mux.lock().expect("operator lock").selected),
+        ("PUT", "/selected") => {
+            let Ok(alias) = read_body(request).map(|body| body.trim().to_string()) else {
+                return Response::text("invalid body").with_status_code(400);
+            };
+            let mut mux = mux.lock().expect("operator lock");
+            if !mux.rovers.contains_key(&alias) {
+                return Response::text("unknown rover").with_status_code(404);
+            }
+            info!("Commands now go to rover {}", alias);
+            mux.selected = Some(alias);
+            Response::empty_204()
+        }
+        ("POST", "/commands") => {
+            let Some(alias) = mux.lock().expect("operator lock").selected.clone() else {
+                return Response::text("no rover selected").with_status_code(409);
+            };
+            send_command(request, mux, &alias, command_channel)
+        }
+        ("POST", path) if path.starts_with("/rovers/") && path.ends_with("/commands") => {
+            let Some(alias) = path
+                .strip_prefix("/rovers/")
+                .and_then(|p| p.strip_suffix("/commands"))
+            else {
+                return Response::empty_404();
+            };
+            send_command(request, mux, alias, command_channel)
+        }
+        ("GET", "/stream") => stream_request(request, mux),
+        _ => Response::empty_404(),
+    }
+}
+
+/// Sends the request body to a rover on the command channel.
+fn send_command(request: &Request, mux: &Mutex<Mux>, alias: &str, channel: &str) -> Response {
+    let Ok(command) = read_body(request) else {
+        return Response::text("invalid body").with_status_code(400);
+    };
+    let mux = mux.lock().expect("operator lock");
+    let Some(rover) = mux.rovers.get(alias) else {
+        return Response::text("unknown rover").with_status_code(404);
+    };
+    if rover.state != RoverState::Connected {
+        return Response::text("rover not connected").with_status_code(503);
+    }
+    debug!(
+        "Sending a command of {} bytes to rover {}",
+        command.len(),
+        alias
+    );
+    rover.handle.send(channel, command.into_bytes());
+    Response::empty_204()
+}
+
+/// Reads the body of a request as text; an empty body is an empty string.
+fn read_body(request: &Request) -> std::io::Result<String> {
+    let mut body = String::new();
+    if let Some(mut data) = request.data() {
+        data.read_to_string(&mut body)?;
+    }
+    Ok(body)
+}
+
+/// Upgrades a request to a WebSocket streaming the updates.
+///
+/// The stream starts with the state of every rover and the latest telemetry,
+/// then pushes updates as they happen until the client goes away.
+fn stream_request(request: &Request, mux: &Mutex<Mux>) -> Response {
+    let (response, websocket) = match websocket::start(request, None::<&str>) {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            return Response::text(format!("websocket upgrade failed: {:?}", e))
+                .with_status_code(400)
+        }
+    };
+
+    let (tx, updates) = mpsc::channel();
+    {
+        let mut mux = mux.lock().expect("operator lock");
+        let now = Utc::now();
+        let current = mux
+            .rovers
+            .iter()
+            .map(|(alias, rover)| OperatorUpdate::Connection {
+                rover: alias.clone(),
+                state: rover.state,
+                at: now,
+            })
+            .chain(mux.latest.values().cloned());
+        for update in current {
+            let text = serde_json::to_string(&update).expect("operator update to serialise");
+            let _ = tx.send(text);
+        }
+        mux.streams.push(tx);
+    }
+
+    thread::spawn(move || {
+        // The websocket becomes available once the upgrade response is sent.
+        let Ok(mut websocket) = websocket.recv() else {
+            return;
+        };
+        debug!("Operator stream opened");
+        for text in updates {
+            if websocket.send_text(&text).is_err() {
+                break;
+            }
+        }
+        debug!("Operator stream closed");
+    });
+    response
+}
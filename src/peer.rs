@@ -5,273 +5,4538 @@
 //! for bidirectional communication and handles the complete ICE negotiation process.
 
 use std::{
-    error::Error,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt, fs,
     io::ErrorKind,
-    net::{SocketAddrV4, UdpSocket},
-    time::{Duration, Instant},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use str0m::{
-    change::SdpAnswer,
+    change::{SdpAnswer, SdpOffer, SdpPendingOffer},
+    channel::{ChannelConfig, ChannelId},
+    format::Codec,
+    media::{Direction, KeyframeRequestKind, MediaKind, Mid},
     net::{Protocol, Receive},
-    Event, IceConnectionState, Input, Output, Rtc,
+    Candidate, Event, IceConnectionState, Input, Output, Rtc, RtcConfig, RtcError,
 };
 
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use tungstenite::{
+    client::IntoClientRequest, http::header::AUTHORIZATION, stream::MaybeTlsStream, Connector,
+    Message, WebSocket,
+};
+
+#[cfg(feature = "gstreamer")]
+use crate::media::gstreamer::{GstreamerCapture, GstreamerConfig};
+use crate::media::sink::{FrameSink, ReceivedFrame};
+#[cfg(feature = "ros2")]
+use crate::ros::{RosBridge, RosConfig};
+use crate::{
+    bridge::{MavlinkBridge, MavlinkConfig},
+    config::NetworkConfig,
+    crash::{self, CrashConfig},
+    error::RoverRtcError,
+    model::{
+        announce::{AnnounceMessage, ANNOUNCE_CHANNEL},
+        audio::{AudioConfig, AudioFrame},
+        batch::{self, Batcher},
+        bonding::{Bond, BondLink, BondingConfig, LinkObservation, PRIMARY_LINK},
+        bus::{BusEvent, EventBus, EventTopic},
+        capture::{CaptureMessage, CAPTURE_CHANNEL},
+        channel::{ChannelOptions, QosClass, WriteOutcome},
+        control::{
+            common_features, negotiate, ControlMessage, Feature, FeatureSet, Negotiation,
+            ProtocolConfig, CONTROL_CHANNEL,
+        },
+        coordination::{
+            CoordinationEvent, CoordinationMessage, Coordinator, COORDINATION_CHANNEL,
+            HEARTBEAT_INTERVAL,
+        },
+        crash::{CrashMessage, CRASH_CHANNEL},
+        duplicate::{self, Deduplicator, Duplicate, DuplicateConfig, DuplicateSender},
+        events::{EventCategory, EventRing, RecordedEvent},
+        failover::{FailoverState, PeerFailoverConfig, RESUME_PARAM},
+        forward::{ForwardMessage, FORWARD_CHANNEL},
+        fragment::{self, Fragmenter, Reassembler},
+        health::{ConnectionHealth, HealthAction, HealthConfig, HealthState, ThresholdPolicy},
+        heartbeat::{LinkMonitor, LinkStats},
+        keys::{KeyAgreement, KeyAgreementError, SessionKeys},
+        logs::{LogMessage, LOGS_CHANNEL},
+        mission::{MissionMessage, MissionReceiver, MISSION_CHANNEL},
+        payload::{Envelope, MessageKind, Payload, WireFormat},
+        rate::{self, RateControlConfig, RateDemand, RateLimiter},
+        reconnect::{ReconnectConfig, Reconnection},
+        recording::{self, Recorder, RecorderConfig},
+        schedule::{MessageSchedule, Persistence, ScheduledMessage},
+        scheduler::{QosScheduler, SEND_BUFFER_LIMIT},
+        session::{SessionMessage, SESSION_CHANNEL},
+        setup::{SetupBreakdown, SetupPhase, SetupTimer},
+        signaling::{
+            AnswerBody, IceServer, MeshAnswer, MeshOffer, RestartOffer, SignalingAnswer,
+            SignalingMessage, TrickleCandidate, ANSWER_FORMAT_PARAM, MESH_ANSWERS_PATH,
+            MESH_CONNECT_PATH, MESH_OFFERS_PATH, RESTART_PATH, TRICKLE_PATH,
+        },
+        stats::{
+            codec_name, ChannelStats, CodecTracker, ConnectionStats, ConnectionTracker, CODEC_NONE,
+            STATS_INTERVAL,
+        },
+        subscription::ChannelSubscriptions,
+        transfer::{OutgoingTransfer, TRANSFER_CHANNEL},
+        video::{VideoConfig, VideoFrame, VideoQueue, VideoTrack},
+    },
+    transfer::{TransferConfig, TransferEvent, Transfers},
+    util::{
+        bind_udp, canonical_addr,
+        discovery::{self, Announcer, Beacon, DiscoveryConfig},
+        get_candidates, init_log, logbuf,
+        mdns::{MdnsResolver, MdnsResponder},
+        netmon::{NetworkEvent, NetworkMonitor},
+        netsim::{self, NetsimConfig, NetsimStatus},
+        netwait::{has_host_address, NetworkWait},
+        pcap::{self, CaptureConfig, TapStatus},
+        pmtu::{self, DatagramLayer, OversizeMonitor, PathMtu, PmtuConfig, ProbeCredentials},
+        shutdown::Shutdown,
+        stun,
+        turn::{self, TurnClient, TurnEvent},
+    },
+};
+
+/// Label of the general-purpose data channel.
+pub const TEST_CHANNEL: &str = "test";
+
+/// How long an ICE restart may take to reconnect before it is retried.
+const ICE_RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval between two checks of the bonded links.
+const BOND_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Minimum interval between keyframe requests for the same forwarded track.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Configuration of an embedded peer.
+///
+/// Usually loaded as the `[peer]` section of a [`Config`](crate::config::Config).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerConfig {
+    /// URL of the signaling server
+    pub signaling_url: String,
+    /// Alias announced to the signaling server, if any
+    pub alias: Option<String>,
+    /// Additional data channels to open, besides the built-in ones
+    pub channels: Vec<String>,
+    /// Delivery options by channel label, for built-in and additional
+    /// channels; channels without an entry are reliable and ordered
+    pub channel_options: BTreeMap<String, ChannelOptions>,
+    /// STUN/TURN servers of this deployment, used along with the ones the
+    /// signaling server recommends
+    pub ice_servers: Vec<IceServer>,
+    /// Interval between the timestamped test messages, in seconds
+    pub message_interval_secs: u64,
+    /// Interval between scans for network interface changes where the
+    /// kernel does not notify them, in seconds
+    pub interface_scan_secs: u64,
+    /// Interval between queries of the STUN servers for a changed public
+    /// address once connected, in seconds; 0 disables them
+    pub mapping_check_secs: u64,
+    /// Bearer token presented to the signaling server's authentication backend
+    pub auth_token: Option<String>,
+    /// File holding the bearer token; re-read on every session refresh so a
+    /// rotated token is picked up, and preferred over `auth_token`
+    pub auth_token_file: Option<PathBuf>,
+    /// Alias of a listening peer to connect to directly; the signaling
+    /// server only brokers the offer and answer
+    pub mesh_target: Option<String>,
+    /// Wait under `alias` for another peer to connect directly, instead of
+    /// connecting to the signaling server
+    pub mesh_listen: bool,
+    /// Find the mesh peer and exchange the offer and answer with it by
+    /// multicast on the LAN, without the signaling server
+    pub discovery: DiscoveryConfig,
+    /// Failing over to the standby signaling server when the primary is down
+    pub failover: PeerFailoverConfig,
+    /// Further connections kept at the same time as the one to
+    /// `signaling_url`, e.g. to a relay besides the base station
+    pub connections: Vec<ConnectionConfig>,
+    /// Failing the application traffic over to a further connection when
+    /// the main one degrades
+    pub bonding: BondingConfig,
+    /// Channels whose messages go over every active link at once
+    pub duplicate: DuplicateConfig,
+    /// Thresholds telling a degraded and a dead connection; its recoveries
+    /// are ICE restarts
+    pub health: HealthConfig,
+    /// Recording of the data channel traffic
+    pub recording: RecorderConfig,
+    /// Signal the host candidates under random `.local` names answered over
+    /// mDNS, as browsers do, instead of the local addresses
+    pub mdns_candidates: bool,
+    /// PEM certificate trusted for an `https` or `wss` signaling server in
+    /// addition to the system roots, e.g. a private CA or the server's
+    /// self-signed certificate
+    pub ca_file: Option<PathBuf>,
+    /// Automatic reconnection after the connection is lost or signaling fails
+    pub reconnect: ReconnectConfig,
+    /// Default publishing rates of the topics sent through the handle
+    pub rate_control: RateControlConfig,
+    /// Path MTU discovery once ICE connected
+    pub pmtu: PmtuConfig,
+    /// Answer the server's requests for this process's recent log lines
+    pub serve_logs: bool,
+    /// Limits of the packet captures the server may request
+    pub capture: CaptureConfig,
+    /// Video track fed with the frames sent through the handle
+    pub video: VideoConfig,
+    /// Audio track receiving the operator's voice
+    pub audio: AudioConfig,
+    /// Open the forward channel, on which a server forwarding media offers
+    /// the tracks other clients publish; every codec stays enabled
+    pub receive_media: bool,
+    /// Files sent and received on the transfer channel
+    pub transfer: TransferConfig,
+    /// MAVLink bridged over its own channel, which is opened when enabled
+    pub mavlink: MavlinkConfig,
+    /// ROS 2 topics bridged over their channels, which are opened when
+    /// enabled
+    #[cfg(feature = "ros2")]
+    pub ros: RosConfig,
+    /// Camera capture feeding the video track, which must be enabled
+    #[cfg(feature = "gstreamer")]
+    pub gstreamer: GstreamerConfig,
+    /// Network discovery settings, from the shared `[network]` section
+    #[serde(skip)]
+    pub network: NetworkConfig,
+    /// Protocol negotiation settings, from the shared `[protocol]` section
+    #[serde(skip)]
+    pub protocol: ProtocolConfig,
+    /// Crash reporting settings, from the shared `[crash]` section
+    #[serde(skip)]
+    pub crash: CrashConfig,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            signaling_url: "http://0.0.0.0:3000".into(),
+            alias: None,
+            channels: vec![],
+            channel_options: BTreeMap::new(),
+            ice_servers: vec![],
+            message_interval_secs: 2,
+            interface_scan_secs: 5,
+            mapping_check_secs: 30,
+            auth_token: None,
+            auth_token_file: None,
+            mesh_target: None,
+            mesh_listen: false,
+            discovery: DiscoveryConfig::default(),
+            failover: PeerFailoverConfig::default(),
+            connections: vec![],
+            bonding: BondingConfig::default(),
+            duplicate: DuplicateConfig::default(),
+            health: HealthConfig::default(),
+            recording: RecorderConfig::default(),
+            mdns_candidates: false,
+            ca_file: None,
+            reconnect: ReconnectConfig::default(),
+            rate_control: RateControlConfig::default(),
+            pmtu: PmtuConfig::default(),
+            serve_logs: true,
+            capture: CaptureConfig::default(),
+            video: VideoConfig::default(),
+            audio: AudioConfig::default(),
+            receive_media: false,
+            transfer: TransferConfig::default(),
+            mavlink: MavlinkConfig::default(),
+            #[cfg(feature = "ros2")]
+            ros: RosConfig::default(),
+            #[cfg(feature = "gstreamer")]
+            gstreamer: GstreamerConfig::default(),
+            network: NetworkConfig::default(),
+            protocol: ProtocolConfig::default(),
+            crash: CrashConfig::default(),
+        }
+    }
+}
+
+/// A further connection of the peer, a `[[peer.connections]]` entry.
+///
+/// It opens the same channels with the same settings as the peer's main
+/// connection, on a socket of its own, and is driven through the handle
+/// returned by [`PeerHandle::connection`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// ID of the connection within the peer
+    pub id: String,
+    /// URL of the signaling server of this connection
+    pub signaling_url: String,
+    /// Alias announced on this connection; the peer's alias if unset
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// Alias of a listening peer to connect to directly; the signaling
+    /// server only brokers the offer and answer
+    #[serde(default)]
+    pub mesh_target: Option<String>,
+    /// Name of the interface this connection's socket is bound to, e.g. the
+    /// LTE modem of a backup link; `network.socket.bind_device` if unset
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+impl PeerConfig {
+    /// Returns the configuration of one of the further connections.
+    ///
+    /// Only the main connection records its traffic and fails over to a
+    /// configured standby; the settings of LAN discovery and listening apply
+    /// to it alone as well.
+    fn connection_config(&self, connection: &ConnectionConfig) -> PeerConfig {
+        let mut network = self.network.clone();
+        if let Some(interface) = &connection.interface {
+            network.socket.bind_device = Some(interface.clone());
+        }
+        PeerConfig {
+            signaling_url: connection.signaling_url.clone(),
+            alias: connection.alias.clone().or_else(|| self.alias.clone()),
+            mesh_target: connection.mesh_target.clone(),
+            mesh_listen: false,
+            discovery: DiscoveryConfig::default(),
+            failover: PeerFailoverConfig {
+                standby_url: None,
+                ..self.failover.clone()
+            },
+            connections: vec![],
+            bonding: BondingConfig::default(),
+            recording: RecorderConfig::default(),
+            network,
+            ..self.clone()
+        }
+    }
+
+    /// Returns `true` if a further connection is the backup link of the
+    /// bonding.
+    pub(crate) fn is_backup(&self, id: &str) -> bool {
+        self.bonding.enabled && self.bonding.backup == id
+    }
+
+    /// Returns the str0m configuration of a channel, with the delivery
+    /// options configured for its label.
+    fn channel_config(&self, label: &str) -> ChannelConfig {
+        self.channel_options
+            .get(label)
+            .copied()
+            .unwrap_or_default()
+            .config(label)
+    }
+
+    /// Returns the str0m configuration offering only the codecs of the
+    /// enabled media tracks, or every codec to receive forwarded tracks.
+    fn rtc_config(&self) -> RtcConfig {
+        if self.receive_media || (!self.video.enabled && !self.audio.enabled) {
+            return Rtc::builder();
+        }
+        let rtc_config = Rtc::builder().clear_codecs();
+        self.audio
+            .enable_codecs(self.video.enable_codecs(rtc_config))
+    }
+
+    /// Returns how long messages sent on a channel wait to be batched, if
+    /// batching is configured for its label.
+    fn batch_window(&self, label: &str) -> Option<Duration> {
+        self.channel_options
+            .get(label)
+            .and_then(ChannelOptions::batch_window)
+    }
+
+    /// Returns the bandwidth weight of a channel.
+    fn weight(&self, label: &str) -> u32 {
+        self.channel_options
+            .get(label)
+            .map_or(1, |options| options.weight)
+    }
+
+    /// Returns the QoS class of a channel.
+    fn qos(&self, label: &str) -> QosClass {
+        self.options(label).qos_class(label)
+    }
+
+    /// Returns the delivery options configured for a channel, or the
+    /// defaults.
+    fn options(&self, label: &str) -> ChannelOptions {
+        self.channel_options.get(label).copied().unwrap_or_default()
+    }
+
+    /// Returns the current bearer token, reading it from the token file if
+    /// one is configured.
+    ///
+    /// # Returns
+    ///
+    /// The token, or `None` if none is configured or the file is unreadable
+    pub fn credential(&self) -> Option<String> {
+        match &self.auth_token_file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(token) => Some(token.trim().to_string()),
+                Err(e) => {
+                    warn!("Peer: Failed to read {}: {}", path.display(), e);
+                    None
+                }
+            },
+            None => self.auth_token.clone(),
+        }
+    }
+
+    /// Reads the additionally trusted certificate, if one is configured.
+    fn ca_certificate(&self) -> Result<Option<Vec<u8>>, RoverRtcError> {
+        match &self.ca_file {
+            Some(path) => fs::read(path)
+                .map(Some)
+                .map_err(|e| RoverRtcError::Config(format!("reading {}: {}", path.display(), e))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns an HTTP client for signaling requests, verifying the server's
+    /// certificate against the system roots and `ca_file`.
+    pub fn http_client(&self) -> Result<reqwest::Client, RoverRtcError> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(pem) = self.ca_certificate()? {
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| RoverRtcError::Config(e.to_string()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Returns the TLS connector for a `wss` signaling server trusting
+    /// `ca_file`, or `None` to use the system roots alone.
+    fn tls_connector(&self) -> Result<Option<Connector>, RoverRtcError> {
+        let Some(pem) = self.ca_certificate()? else {
+            return Ok(None);
+        };
+        let tls_error = |e: native_tls::Error| RoverRtcError::Config(e.to_string());
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(native_tls::Certificate::from_pem(&pem).map_err(tls_error)?)
+            .build()
+            .map_err(tls_error)?;
+        Ok(Some(Connector::NativeTls(connector)))
+    }
+}
+
+/// Connection events reported to embedding applications.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerEvent {
+    /// ICE connected to the server, or to the remote peer in mesh mode
+    Connected,
+    /// A data channel opened
+    ChannelOpen { label: String },
+    /// The first data channel opened; reports the time spent in each setup phase
+    SetupComplete { breakdown: SetupBreakdown },
+    /// The network changed or the connection was lost, and ICE is restarting;
+    /// the data channels stay open and [`PeerEvent::Connected`] follows once
+    /// a new path is found
+    Restarting,
+    /// The connection was lost or stopped
+    Disconnected,
+    /// The bonding moved the application traffic to another link
+    LinkFailover {
+        /// The ID of the link now carrying the traffic, [`PRIMARY_LINK`] or
+        /// the backup connection's
+        link: String,
+        /// Why the traffic moved
+        reason: String,
+    },
+    /// The connection is down and signaling runs again after `delay`
+    Reconnecting { attempt: u32, delay: Duration },
+    /// A new session restored the declared channels after a lost connection
+    Reconnected { outage: Duration, attempts: u32 },
+    /// The video track opened, or the server lost video; the encoder should
+    /// produce a keyframe, since other frames are dropped until one is sent
+    /// or cannot be decoded
+    KeyframeRequested,
+    /// The operator's voice arrived on the audio track
+    Audio { frame: AudioFrame },
+    /// A frame arrived on a track another client publishes, forwarded by
+    /// the server, or on a track of the remote itself
+    Media {
+        /// The ID of the publishing client, `0` for a track of the remote
+        origin: u64,
+        kind: MediaKind,
+        /// The codec of the frame, e.g. H.264 as an Annex B byte stream
+        codec: Codec,
+        data: Vec<u8>,
+    },
+    /// A message was given up under the
+    /// [`WriteRetry::Fail`](crate::model::channel::WriteRetry::Fail) or
+    /// [`WriteRetry::RetryAfterPoll`](crate::model::channel::WriteRetry::RetryAfterPoll)
+    /// policy of its channel
+    WriteFailed {
+        label: String,
+        data: Vec<u8>,
+        /// The error of the last write
+        error: String,
+    },
+    /// A file from the remote arrived on the transfer channel and matches
+    /// its digest
+    FileReceived {
+        /// The ID of the transfer, the hex SHA-256 digest of the file
+        id: String,
+        /// The name the remote sent it under
+        name: String,
+        /// Where it was stored
+        path: PathBuf,
+    },
+    /// The remote confirmed a file sent with [`PeerHandle::send_file`] or
+    /// [`PeerHandle::send_blob`]
+    TransferComplete { id: String, name: String },
+    /// A transfer in either direction was refused or its file did not match
+    /// its digest
+    TransferFailed {
+        id: String,
+        name: String,
+        reason: String,
+    },
+    /// An announcement to every client of the room arrived; it is
+    /// acknowledged once the callbacks returned
+    Announcement { id: u32, data: Vec<u8> },
+    /// A health check moved the connection to another state; the session
+    /// ends once it is dead, see [`crate::model::health`]
+    HealthChanged { state: HealthState },
+}
+
+impl BusEvent for PeerEvent {
+    fn topic(&self) -> EventTopic {
+        match self {
+            PeerEvent::Connected
+            | PeerEvent::ChannelOpen { .. }
+            | PeerEvent::SetupComplete { .. }
+            | PeerEvent::Disconnected => EventTopic::Lifecycle,
+            PeerEvent::Restarting
+            | PeerEvent::LinkFailover { .. }
+            | PeerEvent::Reconnecting { .. }
+            | PeerEvent::Reconnected { .. } => EventTopic::Handover,
+            PeerEvent::KeyframeRequested | PeerEvent::Audio { .. } | PeerEvent::Media { .. } => {
+                EventTopic::Media
+            }
+            PeerEvent::WriteFailed { .. } | PeerEvent::Announcement { .. } => {
+                EventTopic::ChannelData
+            }
+            PeerEvent::FileReceived { .. }
+            | PeerEvent::TransferComplete { .. }
+            | PeerEvent::TransferFailed { .. } => EventTopic::Transfer,
+            PeerEvent::HealthChanged { .. } => EventTopic::Health,
+        }
+    }
+}
+
+/// How a session of the peer ended.
+enum SessionEnd {
+    /// The peer was stopped through its handle
+    Stopped,
+    /// The connection was lost or could not be established; a new session
+    /// may restore it
+    Lost(RoverRtcError),
+    /// The remote refused the session; reconnecting would not help
+    Refused(RoverRtcError),
+}
+
+/// What a message on the control channel calls for.
+enum ControlAction {
+    None,
+    /// Use the session keys agreed with the remote
+    Keys(Box<SessionKeys>),
+    /// The server asks for an ICE restart
+    Restart,
+}
+
+/// Callback invoked from the peer's event loop for every [`PeerEvent`].
+pub type PeerCallback = Arc<dyn Fn(&PeerEvent) + Send + Sync>;
+
+/// Data queued for sending, with the label of its channel.
+type Outbox = Vec<(String, Vec<u8>)>;
+
+/// Handle for on-rover subsystems interacting with a running peer.
+///
+/// Each subsystem subscribes to the channels it cares about and consumes them
+/// from its own task; messages are fanned out when several subscribe to the
+/// same channel. The handle also sends data, registers event callbacks and
+/// stops the peer.
+#[derive(Clone, Default)]
+pub struct PeerHandle {
+    subscriptions: ChannelSubscriptions,
+    bus: EventBus<PeerEvent>,
+    outbox: Arc<Mutex<Outbox>>,
+    schedule: Arc<Mutex<MessageSchedule>>,
+    rates: Arc<Mutex<RateDemand>>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    link: Arc<Mutex<Option<LinkStats>>>,
+    connection: Arc<Mutex<Option<ConnectionStats>>>,
+    keys: Arc<Mutex<Option<Arc<SessionKeys>>>>,
+    path_mtu: Arc<Mutex<Option<usize>>>,
+    video: Arc<Mutex<VideoQueue>>,
+    events: Arc<Mutex<EventRing>>,
+    transfers: Arc<Mutex<Transfers>>,
+    /// Handles of the further connections, by ID
+    connections: Arc<Mutex<BTreeMap<String, PeerHandle>>>,
+    /// The backup link carrying what is sent through this handle, while the
+    /// bonding failed over to it
+    route: Arc<Mutex<Option<PeerHandle>>>,
+    /// Whether ICE is connected and not restarting
+    connected: Arc<Mutex<bool>>,
+    /// Copies of messages on the channels sent over every active link,
+    /// framed for remotes that deduplicate them
+    duplicated: Arc<Mutex<Outbox>>,
+    /// Numbering of the duplicated messages, shared by all connections
+    duplicates: Arc<Mutex<Option<DuplicateSender>>>,
+    /// Copies received so far, shared by all connections
+    deduplicator: Arc<Mutex<Deduplicator>>,
+    /// Consumers of the media frames received
+    frame_sinks: Arc<Mutex<Vec<Box<dyn FrameSink>>>>,
+    /// The convoy coordinator of the current session
+    coordinator: Arc<Mutex<Option<Coordinator>>>,
+    shutdown: Shutdown,
+}
+
+/// The state of a peer, as dumped for support tickets.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerState {
+    /// When the state was taken
+    pub time: chrono::DateTime<chrono::Utc>,
+    /// Version of the crate
+    pub version: &'static str,
+    /// Whether the peer has been asked to stop
+    pub stopped: bool,
+    /// Link quality measured with heartbeats in the current session
+    pub link: Option<LinkStats>,
+    /// Statistics of the current session's connection
+    pub connection: Option<ConnectionStats>,
+    /// The datagram size the path to the remote carries, once probed
+    pub path_mtu: Option<usize>,
+    /// The most recent significant events, oldest first
+    pub events: Vec<RecordedEvent>,
+    /// Number of older events dropped from the history
+    pub events_dropped: u64,
+    /// The state of the further connections, by ID
+    pub connections: BTreeMap<String, PeerState>,
+}
+
+impl fmt::Debug for PeerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerHandle")
+            .field("subscriptions", &self.subscriptions)
+            .field("stopped", &self.is_stopped())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PeerHandle {
+    /// Creates a handle without subscribers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a callback for connection events.
+    pub fn on_event(&self, callback: impl Fn(&PeerEvent) + Send + Sync + 'static) {
+        self.bus.subscribe(&EventTopic::ALL, callback);
+    }
+
+    /// Returns the bus the peer's events are published on, to subscribe to
+    /// some of their topics only, e.g. from a bridge.
+    pub fn events(&self) -> &EventBus<PeerEvent> {
+        &self.bus
+    }
+
+    /// Queues data to be sent on a channel by the event loop.
+    ///
+    /// Data for a channel that is not open is dropped with a warning, and so
+    /// is data published faster than the channel's topic rate. While the
+    /// bonding failed over, the data goes over the backup link instead. Data
+    /// on the channels of [`PeerConfig::duplicate`] goes over every active
+    /// link at once, regardless of topic rates.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the data channel
+    /// * `data` - The raw bytes to send
+    pub fn send(&self, label: &str, data: Vec<u8>) {
+        let mut duplicates = self.duplicates.lock().expect("duplicates lock");
+        if let Some(sender) = duplicates.as_mut().filter(|s| s.designates(label)) {
+            let copy = sender.frame(data);
+            drop(duplicates);
+            return self.send_duplicated(label, copy);
+        }
+        drop(duplicates);
+        if let Some(backup) = self.route.lock().expect("route lock").as_ref() {
+            return backup.send(label, data);
+        }
+        let due = self
+            .limiter
+            .lock()
+            .expect("limiter lock")
+            .admit(label, Instant::now());
+        if !due {
+            return;
+        }
+        self.outbox
+            .lock()
+            .expect("outbox lock")
+            .push((label.to_string(), data));
+    }
+
+    /// Queues a copy over this connection and every connected further one.
+    fn send_duplicated(&self, label: &str, copy: Vec<u8>) {
+        let links: Vec<PeerHandle> = self
+            .connections
+            .lock()
+            .expect("connections lock")
+            .values()
+            .filter(|connection| connection.is_connected())
+            .cloned()
+            .collect();
+        for link in links.iter().chain([self]) {
+            link.duplicated
+                .lock()
+                .expect("duplicated lock")
+                .push((label.to_string(), copy.clone()));
+        }
+    }
+
+    /// Designates the channels whose messages go over every active link,
+    /// keeping the numbering if they were designated before.
+    pub(crate) fn configure_duplicates(&self, config: &DuplicateConfig) {
+        let stream = self
+            .duplicates
+            .lock()
+            .expect("duplicates lock")
+            .as_ref()
+            .map_or_else(rand::random, DuplicateSender::stream);
+        *self.duplicates.lock().expect("duplicates lock") =
+            Some(DuplicateSender::new(stream, config));
+        self.deduplicator
+            .lock()
+            .expect("deduplicator lock")
+            .set_window(config.window);
+    }
+
+    /// Hands received data to the channel's subscribers, once per message
+    /// if it is a copy of a duplicated one.
+    ///
+    /// # Returns
+    ///
+    /// `true` if a subscriber took the data
+    fn deliver(&self, label: &str, data: &[u8], deduplicating: bool) -> bool {
+        if !deduplicating || !duplicate::is_duplicate(data) {
+            return self.subscriptions.dispatch(label, data);
+        }
+        match Duplicate::decode(data) {
+            Some(copy)
+                if self
+                    .deduplicator
+                    .lock()
+                    .expect("deduplicator lock")
+                    .accept(&copy) =>
+            {
+                self.subscriptions.dispatch(label, &copy.data)
+            }
+            Some(copy) => {
+                debug!(
+                    "Peer: Dropping a repeated copy {} on '{}'",
+                    copy.sequence, label
+                );
+                false
+            }
+            None => {
+                warn!("Peer: Discarding a truncated copy on '{}'", label);
+                false
+            }
+        }
+    }
+
+    /// Schedules data to be sent on a channel at a given time.
+    ///
+    /// The data joins the channel's outbound queue once it is due and the
+    /// channel is open, within the event loop's 100 ms polling interval;
+    /// topic rates do not apply to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the data channel
+    /// * `data` - The raw bytes to send
+    /// * `at` - When to send them; a time in the past sends them at once
+    /// * `persistence` - Whether they are dropped when the connection is
+    ///   lost or sent after reconnecting
+    ///
+    /// # Returns
+    ///
+    /// The ID of the scheduled message, for [`PeerHandle::cancel_scheduled`]
+    pub fn send_at(
+        &self,
+        label: &str,
+        data: Vec<u8>,
+        at: chrono::DateTime<chrono::Utc>,
+        persistence: Persistence,
+    ) -> u64 {
+        self.schedule
+            .lock()
+            .expect("schedule lock")
+            .schedule(label, data, at, persistence)
+    }
+
+    /// Schedules data to be sent on a channel after a delay, like
+    /// [`PeerHandle::send_at`].
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the data channel
+    /// * `data` - The raw bytes to send
+    /// * `delay` - How long to wait before sending them
+    /// * `persistence` - Whether they are dropped when the connection is
+    ///   lost or sent after reconnecting
+    ///
+    /// # Returns
+    ///
+    /// The ID of the scheduled message
+    pub fn send_after(
+        &self,
+        label: &str,
+        data: Vec<u8>,
+        delay: Duration,
+        persistence: Persistence,
+    ) -> u64 {
+        let delay = chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::MAX);
+        let at = chrono::Utc::now()
+            .checked_add_signed(delay)
+            .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC);
+        self.send_at(label, data, at, persistence)
+    }
+
+    /// Cancels a scheduled message.
+    ///
+    /// # Returns
+    ///
+    /// `false` if it was already sent, dropped or cancelled
+    pub fn cancel_scheduled(&self, id: u64) -> bool {
+        self.schedule.lock().expect("schedule lock").cancel(id)
+    }
+
+    /// Returns the scheduled messages still waiting, in order of delivery.
+    pub fn scheduled(&self) -> Vec<ScheduledMessage> {
+        self.schedule
+            .lock()
+            .expect("schedule lock")
+            .pending()
+            .to_vec()
+    }
+
+    /// Queues an encoded frame for the video track.
+    ///
+    /// Frames are dropped unless video is enabled in the peer's configuration.
+    /// Frames queued faster than the connection takes them, e.g. before it is
+    /// up, are dropped along with the queue, and sending resumes with the
+    /// next keyframe.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The encoded frame
+    ///
+    /// # Returns
+    ///
+    /// `false` if the frame or queued frames were dropped
+    pub fn send_video_frame(&self, frame: VideoFrame) -> bool {
+        self.video.lock().expect("video lock").push(frame)
+    }
+
+    /// Sends a file on the transfer channel.
+    ///
+    /// The file is read into memory and offered once the channel is open; a
+    /// transfer interrupted by a lost connection resumes in the next session.
+    /// Its end is reported as [`PeerEvent::TransferComplete`] or
+    /// [`PeerEvent::TransferFailed`].
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to send; the remote stores it under its file name
+    ///
+    /// # Returns
+    ///
+    /// The ID of the transfer, or an error if the file could not be read
+    pub fn send_file(&self, path: impl AsRef<std::path::Path>) -> Result<String, RoverRtcError> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(self.send_blob(&name, data))
+    }
+
+    /// Sends data the remote stores as a file, e.g. an image taken in memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The file name the remote stores it under
+    /// * `data` - The content of the file
+    ///
+    /// # Returns
+    ///
+    /// The ID of the transfer, the hex SHA-256 digest of the data
+    pub fn send_blob(&self, name: &str, data: Vec<u8>) -> String {
+        self.transfers
+            .lock()
+            .expect("transfers lock")
+            .send(OutgoingTransfer::new(name, data))
+    }
+
+    /// Declares the rate at which a receiver wants the remote to publish a
+    /// topic.
+    ///
+    /// The remote is asked for the highest rate any receiver wants, once it
+    /// agreed to rate control; requests survive reconnections.
+    ///
+    /// # Arguments
+    ///
+    /// * `receiver` - Name of the receiver, e.g. `operator-ui`
+    /// * `topic` - The topic, i.e. the channel label, e.g. `gps`
+    /// * `hz` - The wanted rate, or `None` to withdraw the receiver's request
+    pub fn request_rate(&self, receiver: &str, topic: &str, hz: Option<f64>) {
+        self.rates
+            .lock()
+            .expect("rates lock")
+            .request(receiver, topic, hz);
+    }
+
+    /// Returns the round-trip time, jitter and loss measured with heartbeats
+    /// in the current session, or `None` if the remote does not answer them.
+    pub fn link_stats(&self) -> Option<LinkStats> {
+        *self.link.lock().expect("link lock")
+    }
+
+    /// Returns `true` while ICE is connected and not restarting.
+    pub fn is_connected(&self) -> bool {
+        *self.connected.lock().expect("connected lock")
+    }
+
+    /// Returns the ID of the link carrying what is sent through the handle:
+    /// [`PRIMARY_LINK`], or the backup connection's while the bonding failed
+    /// over to it.
+    pub fn active_link(&self) -> String {
+        let route = self.route.lock().expect("route lock");
+        let backup = route.as_ref().and_then(|backup| {
+            self.connections
+                .lock()
+                .expect("connections lock")
+                .iter()
+                .find(|(_, connection)| Arc::ptr_eq(&connection.outbox, &backup.outbox))
+                .map(|(id, _)| id.clone())
+        });
+        backup.unwrap_or_else(|| PRIMARY_LINK.to_string())
+    }
+
+    /// Returns what the bonding knows of the link of this handle.
+    fn link_observation(&self) -> LinkObservation {
+        LinkObservation {
+            connected: self.is_connected(),
+            link: self.link_stats(),
+        }
+    }
+
+    /// Returns the statistics of the current session's connection: the
+    /// selected candidate pair, the traffic, the round-trip times and the
+    /// state of every open data channel. They are refreshed every
+    /// [`STATS_INTERVAL`], and `None` until the first time in a session.
+    pub fn stats(&self) -> Option<ConnectionStats> {
+        self.connection.lock().expect("connection lock").clone()
+    }
+
+    /// Returns the application keys agreed with the remote for the current
+    /// session, or `None` until both sides agreed on them.
+    ///
+    /// Keys are only agreed once both sides advertised
+    /// [`Feature::KeyAgreement`]; see [`crate::model::keys`].
+    pub fn session_keys(&self) -> Option<Arc<SessionKeys>> {
+        self.keys.lock().expect("keys lock").clone()
+    }
+
+    /// Returns the datagram size the path to the remote carries, or `None`
+    /// until probing found it in the current session.
+    pub fn path_mtu(&self) -> Option<usize> {
+        *self.path_mtu.lock().expect("path MTU lock")
+    }
+
+    /// Runs `f` on the convoy coordinator of the current session, e.g. to
+    /// read the elected leader and the members or to set the state shared
+    /// in the heartbeats, and returns its result.
+    ///
+    /// The state set is kept for the following sessions. The event loop
+    /// waits while `f` runs, so it should return quickly.
+    ///
+    /// # Returns
+    ///
+    /// `None` before the first session started
+    pub fn with_coordinator<T>(&self, f: impl FnOnce(&mut Coordinator) -> T) -> Option<T> {
+        self.coordinator
+            .lock()
+            .expect("coordinator lock")
+            .as_mut()
+            .map(f)
+    }
+
+    /// Starts writing the UDP traffic of the peer's socket to a pcapng file,
+    /// like `[network.pcap]` does from startup.
+    ///
+    /// The file tap is process-wide: a server in the same process writes to
+    /// the same file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The pcapng file, replaced if it exists
+    /// * `max_bytes` - The size after which the file stops growing
+    pub fn start_pcap(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        max_bytes: u64,
+    ) -> Result<(), RoverRtcError> {
+        Ok(pcap::start_file(path.as_ref(), max_bytes)?)
+    }
+
+    /// Stops writing the pcapng file and returns its final state, or `None`
+    /// if none was being written.
+    pub fn stop_pcap(&self) -> Option<TapStatus> {
+        pcap::stop_file()
+    }
+
+    /// Replaces the simulated impairment of the network, like
+    /// `[network.netsim]` does from startup, e.g. to cut the link and restore
+    /// it while testing handovers. Like the file tap, it is process-wide.
+    ///
+    /// # Returns
+    ///
+    /// An error if the settings are invalid
+    pub fn set_netsim(&self, config: &NetsimConfig) -> Result<(), RoverRtcError> {
+        config.validate().map_err(RoverRtcError::Config)?;
+        netsim::configure(config);
+        Ok(())
+    }
+
+    /// Returns the simulated impairment of the network and what it did, or
+    /// `None` if it is off.
+    pub fn netsim(&self) -> Option<NetsimStatus> {
+        netsim::status()
+    }
+
+    /// Asks the peer to close its channels, disconnect and return from [`run`].
+    pub fn stop(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Returns `true` once the peer has been asked to stop.
+    pub fn is_stopped(&self) -> bool {
+        self.shutdown.is_triggered()
+    }
+
+    /// Returns the handle stopping the peer, e.g. from a signal handler.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Returns the handle of one of the further connections of
+    /// [`PeerConfig::connections`], to subscribe to its channels, send on it,
+    /// read its statistics and register callbacks for its events.
+    ///
+    /// The handles exist once the peer is built with
+    /// [`RoverRtc::builder`](crate::RoverRtc::builder), or once it runs.
+    /// Stopping one stops the whole peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the connection
+    ///
+    /// # Returns
+    ///
+    /// The handle, or `None` if no connection has this ID
+    pub fn connection(&self, id: &str) -> Option<PeerHandle> {
+        self.connections
+            .lock()
+            .expect("connections lock")
+            .get(id)
+            .cloned()
+    }
+
+    /// Returns the IDs of the further connections.
+    pub fn connection_ids(&self) -> Vec<String> {
+        self.connections
+            .lock()
+            .expect("connections lock")
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Queues data to be sent on a channel of one of the further
+    /// connections, see [`PeerHandle::send`].
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the connection
+    /// * `label` - The label of the data channel
+    /// * `data` - The raw bytes to send
+    ///
+    /// # Returns
+    ///
+    /// `false` if no connection has this ID
+    pub fn send_on(&self, id: &str, label: &str, data: Vec<u8>) -> bool {
+        match self.connection(id) {
+            Some(connection) => {
+                connection.send(label, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the handle of a further connection, creating it if needed.
+    /// It shares the peer's shutdown, so stopping either stops both.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the connection
+    /// * `backup` - Whether it is the backup link of the bonding, whose
+    ///   messages go to the peer's subscribers
+    pub(crate) fn add_connection(&self, id: &str, backup: bool) -> PeerHandle {
+        self.connections
+            .lock()
+            .expect("connections lock")
+            .entry(id.to_string())
+            .or_insert_with(|| PeerHandle {
+                subscriptions: if backup {
+                    self.subscriptions.clone()
+                } else {
+                    ChannelSubscriptions::default()
+                },
+                duplicates: self.duplicates.clone(),
+                deduplicator: self.deduplicator.clone(),
+                shutdown: self.shutdown.clone(),
+                ..PeerHandle::default()
+            })
+            .clone()
+    }
+
+    /// Returns the current state of the peer, with its recent significant
+    /// events, e.g. to attach to a support ticket.
+    pub fn dump_state(&self) -> PeerState {
+        let events = self.events.lock().expect("events lock");
+        PeerState {
+            time: chrono::Utc::now(),
+            version: env!("CARGO_PKG_VERSION"),
+            stopped: self.is_stopped(),
+            link: self.link_stats(),
+            connection: self.stats(),
+            path_mtu: self.path_mtu(),
+            events: events.snapshot(),
+            events_dropped: events.dropped(),
+            connections: self
+                .connections
+                .lock()
+                .expect("connections lock")
+                .iter()
+                .map(|(id, connection)| (id.clone(), connection.dump_state()))
+                .collect(),
+        }
+    }
+
+    /// Records a significant event for state dumps.
+    fn record(&self, category: EventCategory, detail: String) {
+        self.events
+            .lock()
+            .expect("events lock")
+            .record(category, detail);
+    }
+
+    fn emit(&self, event: PeerEvent) {
+        match &event {
+            PeerEvent::Connected => *self.connected.lock().expect("connected lock") = true,
+            PeerEvent::Restarting | PeerEvent::Disconnected | PeerEvent::Reconnecting { .. } => {
+                *self.connected.lock().expect("connected lock") = false
+            }
+            _ => {}
+        }
+        let significant = match &event {
+            PeerEvent::Connected => Some((EventCategory::State, "connected".to_string())),
+            PeerEvent::ChannelOpen { label } => Some((
+                EventCategory::Channel,
+                format!("channel '{}' opened", label),
+            )),
+            PeerEvent::SetupComplete { breakdown } => Some((
+                EventCategory::State,
+                format!("setup complete: {}", breakdown),
+            )),
+            PeerEvent::Restarting => Some((EventCategory::Handover, "ICE restarting".into())),
+            PeerEvent::LinkFailover { link, reason } => Some((
+                EventCategory::Handover,
+                format!("traffic moved to the {} link: {}", link, reason),
+            )),
+            PeerEvent::Disconnected => Some((EventCategory::State, "disconnected".into())),
+            PeerEvent::Reconnecting { attempt, delay } => Some((
+                EventCategory::Handover,
+                format!(
+                    "reconnecting in {:.1}s, attempt {}",
+                    delay.as_secs_f64(),
+                    attempt
+                ),
+            )),
+            PeerEvent::Reconnected { outage, attempts } => Some((
+                EventCategory::Handover,
+                format!(
+                    "reconnected after {:.1}s and {} attempts",
+                    outage.as_secs_f64(),
+                    attempts
+                ),
+            )),
+            // Media events come at frame rate
+            PeerEvent::FileReceived { name, .. } => {
+                Some((EventCategory::Channel, format!("received file {}", name)))
+            }
+            PeerEvent::TransferComplete { name, .. } => {
+                Some((EventCategory::Channel, format!("sent file {}", name)))
+            }
+            PeerEvent::TransferFailed { name, reason, .. } => Some((
+                EventCategory::Error,
+                format!("transfer of {} failed: {}", name, reason),
+            )),
+            PeerEvent::WriteFailed { label, error, .. } => Some((
+                EventCategory::Error,
+                format!("write on '{}' failed: {}", label, error),
+            )),
+            PeerEvent::Announcement { id, .. } => Some((
+                EventCategory::Channel,
+                format!("received announcement {}", id),
+            )),
+            PeerEvent::HealthChanged { state } => {
+                Some((EventCategory::State, format!("connection {}", state)))
+            }
+            PeerEvent::KeyframeRequested | PeerEvent::Audio { .. } | PeerEvent::Media { .. } => {
+                None
+            }
+        };
+        if let Some((category, detail)) = significant {
+            self.record(category, detail);
+        }
+
+        self.bus.publish(&event);
+    }
+
+    /// Adds a consumer of the media frames the peer receives, e.g. a
+    /// [`FileSink`](crate::media::sink::FileSink) storing the remote's
+    /// camera; see [`crate::media::sink`].
+    ///
+    /// Sinks added before the peer starts get every frame.
+    pub fn add_frame_sink(&self, sink: impl FrameSink + 'static) {
+        self.frame_sinks
+            .lock()
+            .expect("frame sinks lock")
+            .push(Box::new(sink));
+    }
+
+    /// Writes a received frame to every sink, removing those that fail.
+    fn write_frame(&self, frame: &ReceivedFrame) {
+        self.frame_sinks
+            .lock()
+            .expect("frame sinks lock")
+            .retain_mut(|sink| match sink.write_frame(frame) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Peer: Removing a frame sink that failed: {}", e);
+                    false
+                }
+            });
+    }
+
+    /// Completes the output of every sink once the peer stops.
+    fn finish_frame_sinks(&self) {
+        for sink in self
+            .frame_sinks
+            .lock()
+            .expect("frame sinks lock")
+            .iter_mut()
+        {
+            if let Err(e) = sink.finish() {
+                warn!("Peer: A frame sink failed to finish: {}", e);
+            }
+        }
+    }
+
+    fn take_outbox(&self) -> Outbox {
+        std::mem::take(&mut *self.outbox.lock().expect("outbox lock"))
+    }
+
+    fn take_duplicated(&self) -> Outbox {
+        std::mem::take(&mut *self.duplicated.lock().expect("duplicated lock"))
+    }
+
+    /// Subscribes to the messages received on a data channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The label of the data channel, e.g. `telemetry`
+    ///
+    /// # Returns
+    ///
+    /// A receiver yielding the raw bytes of every message on the channel
+    pub fn subscribe(&self, label: &str) -> mpsc::Receiver<Vec<u8>> {
+        self.subscriptions.subscribe(label)
+    }
+}
+
+/// Lists the peers listening in mesh mode on the LAN.
+///
+/// # Arguments
+///
+/// * `config` - The peer configuration with the discovery settings; they
+///   need not be enabled
+/// * `duration` - How long to collect the beacons
+///
+/// # Returns
+///
+/// The peers found, or an error if the discovery group could not be joined
+pub fn discover(config: &PeerConfig, duration: Duration) -> Result<Vec<Beacon>, RoverRtcError> {
+    Ok(discovery::browse(&config.discovery, duration)?)
+}
+
+/// Main entry point for the WebRTC peer client.
+///
+/// Initializes logging, subscribes a consumer logging the payloads received on
+/// the "test" channel, then runs the peer with [`run`] and the given
+/// configuration until it disconnects or ctrl-c is pressed.
+pub fn main(config: PeerConfig) -> Result<(), RoverRtcError> {
+    tokio::runtime::Runtime::new()?.block_on(run_main(config))
+}
+
+async fn run_main(config: PeerConfig) -> Result<(), RoverRtcError> {
+    println!("Starting modern str0m peer...");
+    init_log();
+
+    let handle = PeerHandle::new();
+    if let Err(e) = handle.shutdown().trigger_on_ctrl_c() {
+        warn!("Failed to install the ctrl-c handler: {}", e);
+    }
+
+    let _bridge = if config.mavlink.enabled {
+        Some(MavlinkBridge::start(&config.mavlink, &handle)?)
+    } else {
+        None
+    };
+    #[cfg(feature = "ros2")]
+    let _ros = if config.ros.enabled {
+        Some(RosBridge::start(&config.ros, &handle)?)
+    } else {
+        None
+    };
+    #[cfg(feature = "gstreamer")]
+    let _capture = if config.gstreamer.enabled {
+        Some(GstreamerCapture::start(
+            &config.gstreamer,
+            config.video.codec,
+            &handle,
+        )?)
+    } else {
+        None
+    };
+
+    let mut test_rx = handle.subscribe(TEST_CHANNEL);
+    tokio::spawn(async move {
+        while let Some(data) = test_rx.recv().await {
+            info!(
+                "Received data on channel '{}': {:?}",
+                TEST_CHANNEL,
+                String::from_utf8_lossy(&data)
+            );
+        }
+    });
+
+    run(config, handle).await
+}
+
+/// Runs the WebRTC peer until it is stopped through its handle, reconnecting
+/// with backoff whenever a session is lost.
+///
+/// Each session runs [`connect`]. When one ends without being stopped, a
+/// [`PeerEvent::Reconnecting`] is emitted and signaling runs again after the
+/// delay configured in [`PeerConfig::reconnect`]; the new session opens the
+/// same channels, and [`PeerEvent::Reconnected`] reports the outage once they
+/// are back. Subscriptions and callbacks on the handle carry over.
+///
+/// The further connections of [`PeerConfig::connections`] run the same way on
+/// threads of their own, each with its own socket and the handle returned by
+/// [`PeerHandle::connection`], until the main connection ends.
+///
+/// # Arguments
+///
+/// * `config` - The peer configuration
+/// * `handle` - Handle holding the channel subscriptions and event callbacks
+///
+/// # Returns
+///
+/// * `Ok(())` - If the peer was stopped, or lost its connection with
+///   reconnection disabled
+/// * `Err(RoverRtcError)` - If the remote refused the session, or the last
+///   session failed and no attempt is left
+pub async fn run(config: PeerConfig, handle: PeerHandle) -> Result<(), RoverRtcError> {
+    let _tap = if config.network.pcap.enabled {
+        let pcap = &config.network.pcap;
+        pcap::start_file(&pcap.path, pcap.max_bytes)?;
+        info!("Peer: Writing UDP traffic to {}", pcap.path.display());
+        Some(StopTapOnDrop)
+    } else {
+        None
+    };
+    if config.network.netsim.enabled {
+        netsim::configure(&config.network.netsim);
+        warn!(
+            "Peer: Simulating an impaired network: {:?}",
+            config.network.netsim
+        );
+    }
+    handle.configure_duplicates(&config.duplicate);
+    let connections = config
+        .connections
+        .iter()
+        .map(|connection| spawn_connection(&config, connection, &handle))
+        .collect::<Result<Vec<_>, _>>()?;
+    let bonding = config
+        .bonding
+        .enabled
+        .then(|| spawn_bonding(&config.bonding, &handle))
+        .transpose()?;
+
+    let result = run_connection(&config, &handle).await;
+    handle.finish_frame_sinks();
+    if !connections.is_empty() {
+        // The further connections end with the main one
+        handle.stop();
+        for connection in connections.into_iter().chain(bonding) {
+            if connection.join().is_err() {
+                warn!("Peer: A connection thread panicked");
+            }
+        }
+    }
+    result
+}
+
+/// Moves the application traffic between the primary and the backup link,
+/// see [`crate::model::bonding`].
+///
+/// # Arguments
+///
+/// * `config` - The bonding settings
+/// * `handle` - The peer's handle, holding the backup connection's handle
+///
+/// # Returns
+///
+/// The thread checking the links until the peer stops, or an error if the
+/// backup names no connection
+fn spawn_bonding(
+    config: &BondingConfig,
+    handle: &PeerHandle,
+) -> Result<thread::JoinHandle<()>, RoverRtcError> {
+    let backup = handle.connection(&config.backup).ok_or_else(|| {
+        RoverRtcError::Config(format!(
+            "bonding.backup '{}' names no connection",
+            config.backup
+        ))
+    })?;
+    let backup_id = config.backup.clone();
+    let mut bond = Bond::new(config);
+    let handle = handle.clone();
+    Ok(thread::Builder::new()
+        .name("bonding".to_string())
+        .spawn(move || {
+            while !handle.is_stopped() {
+                thread::sleep(BOND_CHECK_INTERVAL);
+                let Some(switch) = bond.update(
+                    &handle.link_observation(),
+                    &backup.link_observation(),
+                    Instant::now(),
+                ) else {
+                    continue;
+                };
+                let link = match switch.to {
+                    BondLink::Primary => PRIMARY_LINK.to_string(),
+                    BondLink::Backup => backup_id.clone(),
+                };
+                warn!(
+                    "Peer: Moving the traffic to the {} link: {}",
+                    link, switch.reason
+                );
+                *handle.route.lock().expect("route lock") =
+                    (switch.to == BondLink::Backup).then(|| backup.clone());
+                handle.emit(PeerEvent::LinkFailover {
+                    link,
+                    reason: switch.reason,
+                });
+            }
+        })?)
+}
+
+/// Runs one of the further connections of [`PeerConfig::connections`] on a
+/// thread of its own, since a session blocks on its socket between events.
+///
+/// # Arguments
+///
+/// * `config` - The peer configuration
+/// * `connection` - The further connection to run
+/// * `handle` - The peer's handle, holding the connection's handle
+///
+/// # Returns
+///
+/// The thread running the connection until the peer stops, or an error if
+/// its runtime could not be created
+fn spawn_connection(
+    config: &PeerConfig,
+    connection: &ConnectionConfig,
+    handle: &PeerHandle,
+) -> Result<thread::JoinHandle<()>, RoverRtcError> {
+    let handle = handle.add_connection(&connection.id, config.is_backup(&connection.id));
+    let config = config.connection_config(connection);
+    let id = connection.id.clone();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    info!(
+        "Peer: Starting connection '{}' to {}",
+        id, config.signaling_url
+    );
+    Ok(thread::Builder::new()
+        .name(format!("peer-{}", id))
+        .spawn(move || {
+            if let Err(e) = runtime.block_on(run_connection(&config, &handle)) {
+                warn!("Peer: Connection '{}' failed: {}", id, e);
+                handle.record(EventCategory::Error, format!("connection failed: {}", e));
+            }
+        })?)
+}
+
+/// Runs one connection of the peer, reconnecting it whenever a session is
+/// lost, see [`run`].
+async fn run_connection(config: &PeerConfig, handle: &PeerHandle) -> Result<(), RoverRtcError> {
+    let mut reconnection = Reconnection::new(config.reconnect.clone());
+    let failover = Arc::new(Mutex::new(FailoverState::new(
+        &config.signaling_url,
+        &config.failover,
+        Instant::now(),
+    )));
+    // Mesh and LAN sessions do not depend on the server staying up
+    let probe = (config.failover.enabled
+        && config.mesh_target.is_none()
+        && !config.mesh_listen
+        && !config.discovery.enabled)
+        .then(|| spawn_failover_probe(config, handle, failover.clone()))
+        .transpose()?;
+    let _probe = probe.map(AbortOnDrop);
+    let recorder = Recorder::start(&config.recording)?;
+    loop {
+        let signaling_url = failover.lock().expect("failover lock").next_target();
+        if signaling_url != config.signaling_url {
+            info!("Peer: Signaling to the standby server {}", signaling_url);
+        }
+        let session_config = PeerConfig {
+            signaling_url,
+            ..config.clone()
+        };
+        let session = connect(
+            &session_config,
+            handle,
+            &mut reconnection,
+            &failover,
+            recorder.as_ref(),
+        );
+        let error = match session.await {
+            Ok(SessionEnd::Stopped) => return Ok(()),
+            Ok(SessionEnd::Refused(e)) => {
+                handle.record(EventCategory::Error, format!("session refused: {}", e));
+                return Err(e);
+            }
+            Ok(SessionEnd::Lost(e)) => {
+                warn!("Peer: Connection lost: {}", e);
+                handle.record(EventCategory::Error, format!("connection lost: {}", e));
+                None
+            }
+            Err(e) => {
+                warn!("Peer: Session failed: {}", e);
+                handle.record(EventCategory::Error, format!("session failed: {}", e));
+                Some(e)
+            }
+        };
+        reconnection.lost(Instant::now());
+        let dropped = handle.schedule.lock().expect("schedule lock").end_session();
+        if dropped > 0 {
+            info!(
+                "Peer: Dropped {} scheduled messages of the session",
+                dropped
+            );
+        }
+
+        let Some((attempt, delay)) = reconnection.next_attempt() else {
+            return error.map_or(Ok(()), Err);
+        };
+        info!(
+            "Peer: Reconnecting in {:.1}s (attempt {})",
+            delay.as_secs_f64(),
+            attempt
+        );
+        handle.emit(PeerEvent::Reconnecting { attempt, delay });
+        let resume_at = Instant::now() + delay;
+        while Instant::now() < resume_at {
+            if handle.is_stopped() {
+                return Ok(());
+            }
+            tokio::time::sleep(
+                resume_at
+                    .saturating_duration_since(Instant::now())
+                    .min(Duration::from_millis(100)),
+            )
+            .await;
+        }
+    }
+}
+
+/// Aborts a task when dropped.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Stops the pcapng file tap when dropped, so the end of the file is written.
+struct StopTapOnDrop;
+
+impl Drop for StopTapOnDrop {
+    fn drop(&mut self) {
+        pcap::stop_file();
+    }
+}
+
+/// Probes the primary signaling server while a standby is known, see
+/// [`crate::model::failover`].
+///
+/// # Arguments
+///
+/// * `config` - The peer configuration with the probe settings
+/// * `handle` - Handle telling when the peer stops
+/// * `failover` - The failover state recording the probes
+///
+/// # Returns
+///
+/// The probing task, or an error if the HTTP client could not be built
+fn spawn_failover_probe(
+    config: &PeerConfig,
+    handle: &PeerHandle,
+    failover: Arc<Mutex<FailoverState>>,
+) -> Result<tokio::task::JoinHandle<()>, RoverRtcError> {
+    let client = config.http_client()?;
+    let timeout = Duration::from_millis(config.failover.probe_timeout_ms);
+    let handle = handle.clone();
+    Ok(tokio::spawn(async move {
+        while !handle.is_stopped() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let Some(url) = failover
+                .lock()
+                .expect("failover lock")
+                .probe_due(Instant::now())
+            else {
+                continue;
+            };
+            let healthy = client
+                .get(&url)
+                .timeout(timeout)
+                .send()
+                .await
+                .is_ok_and(|response| response.status().is_success());
+            let mut failover = failover.lock().expect("failover lock");
+            let was_down = failover.primary_down();
+            if failover.probed(healthy) {
+                warn!("Peer: The primary signaling server is down");
+                handle.record(EventCategory::Error, "primary server down".to_string());
+            } else if was_down && healthy {
+                info!("Peer: The primary signaling server is back");
+            }
+        }
+    }))
+}
+
+/// Runs one session of the WebRTC peer until it disconnects or is stopped
+/// through its handle.
+///
+/// This async function performs the complete WebRTC connection sequence:
+/// 1. Waits for an interface to get a usable address, then creates a new RTC
+///    instance and binds a UDP socket
+/// 2. Discovers and adds local ICE candidates
+/// 3. Creates a data channel and generates an SDP offer
+/// 4. Sends the offer to the signaling server and receives an answer
+/// 5. Accepts the answer and starts the connection process
+/// 6. Enters the main event loop to handle ICE state changes, channel events, and data
+/// 7. Processes incoming/outgoing UDP packets and drives the WebRTC state machine
+/// 8. Restarts ICE through the signaling server when the network interfaces
+///    change or the connection is lost, keeping the data channels open
+///
+/// Data received on any channel is dispatched to the subscribers registered
+/// through the handle; messages on channels without subscribers are logged.
+///
+/// # Arguments
+///
+/// * `config` - The peer configuration
+/// * `handle` - Handle holding the channel subscriptions and event callbacks
+/// * `reconnection` - The reconnection state, told when the session connects
+/// * `failover` - The failover state, told the server's answer; the session
+///   ends once the primary it was signaled to is down
+/// * `recorder` - Records the messages sent and received, if enabled
+///
+/// # Returns
+///
+/// * `Ok(SessionEnd)` - How the session ended once it was running
+/// * `Err(RoverRtcError)` - If any error occurs during the connection process
+///
+/// # Example Data Channel
+///
+/// The peer creates a data channel named "test" which can be used to send and receive
+/// arbitrary binary data once the connection is established.
+async fn connect(
+    config: &PeerConfig,
+    handle: &PeerHandle,
+    reconnection: &mut Reconnection,
+    failover: &Mutex<FailoverState>,
+    recorder: Option<&Recorder>,
+) -> Result<SessionEnd, RoverRtcError> {
+    let mut setup = SetupTimer::new();
+    let mut rtc = config
+        .rtc_config()
+        .set_stats_interval(Some(STATS_INTERVAL))
+        .build();
+    handle
+        .video
+        .lock()
+        .expect("video lock")
+        .reset(&config.video);
+    let mut video = config
+        .video
+        .enabled
+        .then(|| VideoTrack::new(config.video.codec));
+    // The media ID of the audio track, once offered
+    let mut audio_mid = None;
+    // Tracks of other clients the server forwards, by media ID
+    let mut forwarded: HashMap<Mid, ForwardedTrackIn> = HashMap::new();
+
+    crash::record_state("peer.signaling_url", &config.signaling_url);
+    crash::record_state("peer.channels", Vec::<String>::new());
+
+    // At boot the interfaces may not have their addresses yet
+    let mut wait = NetworkWait::new("host candidate", &config.network);
+    while !has_host_address(&config.network) {
+        if handle.is_stopped() {
+            return Ok(SessionEnd::Stopped);
+        }
+        let Some(delay) = wait.retry(&config.network) else {
+            return Err(RoverRtcError::NoCandidates);
+        };
+        tokio::time::sleep(delay).await;
+    }
+    wait.done();
+
+    let socket = bind_udp(&config.network, 0)?;
+    if config.pmtu.dont_fragment {
+        if let Err(e) = pmtu::set_dont_fragment(&socket) {
+            debug!("Peer: Datagrams may be fragmented: {}", e);
+        }
+    }
+    setup.begin(SetupPhase::IceGathering);
+    let candidates = get_candidates(&socket, &config.network)?;
+    let mdns = if config.mdns_candidates {
+        match MdnsResponder::start() {
+            Ok(responder) => Some(Arc::new(responder)),
+            Err(e) => {
+                warn!(
+                    "Peer: Signaling local addresses, mDNS is unavailable: {}",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let resolver = (config.network.mdns_timeout_ms > 0)
+        .then(|| MdnsResolver::new(Duration::from_millis(config.network.mdns_timeout_ms)));
+
+    // Store the first candidate's address to use as destination in receives
+    // All candidates share the same port, so we can use any of them
+    let mut local_addr = candidates
+        .first()
+        .map(|c| c.addr())
+        .ok_or(RoverRtcError::NoCandidates)?;
+    let mut host_addrs: HashSet<SocketAddr> = candidates.iter().map(|c| c.addr()).collect();
+
+    for candidate in candidates {
+        rtc.add_local_candidate(candidate);
+    }
+    setup.end(SetupPhase::IceGathering);
+
+    let mut buf = vec![0; 2000];
+    let mut ice_servers = config.ice_servers.clone();
+    let mut refresh_token = None;
+    let mut path_mtu = PathMtu::new(config.pmtu.clone());
+    *handle.path_mtu.lock().expect("path MTU lock") = None;
+    let mut signaling = if config.mesh_listen {
+        // The offering peer creates the data channels
+        answer_mesh_offer(
+            config,
+            &mut rtc,
+            &mut setup,
+            &mut path_mtu,
+            mdns.as_deref(),
+            resolver.as_ref(),
+        )
+        .await?
+    } else {
+        let mut change = rtc.sdp_api();
+        let cid = change.add_channel_with_config(config.channel_config(TEST_CHANNEL));
+        change.add_channel_with_config(config.channel_config(MISSION_CHANNEL));
+        change.add_channel_with_config(config.channel_config(COORDINATION_CHANNEL));
+        change.add_channel_with_config(config.channel_config(SESSION_CHANNEL));
+        change.add_channel_with_config(config.channel_config(CONTROL_CHANNEL));
+        change.add_channel_with_config(config.channel_config(LOGS_CHANNEL));
+        change.add_channel_with_config(config.channel_config(CAPTURE_CHANNEL));
+        change.add_channel_with_config(config.channel_config(CRASH_CHANNEL));
+        change.add_channel_with_config(config.channel_config(TRANSFER_CHANNEL));
+        change.add_channel_with_config(config.channel_config(ANNOUNCE_CHANNEL));
+        if config.receive_media {
+            change.add_channel_with_config(config.channel_config(FORWARD_CHANNEL));
+        }
+        for label in &config.channels {
+            change.add_channel_with_config(config.channel_config(label));
+        }
+        if config.mavlink.enabled && !config.channels.contains(&config.mavlink.channel) {
+            change.add_channel_with_config(config.channel_config(&config.mavlink.channel));
+        }
+        #[cfg(feature = "ros2")]
+        if config.ros.enabled {
+            for label in config.ros.channels() {
+                if !config.channels.iter().any(|l| l == label) {
+                    change.add_channel_with_config(config.channel_config(label));
+                }
+            }
+        }
+        if config.audio.enabled {
+            audio_mid =
+                Some(change.add_media(MediaKind::Audio, Direction::RecvOnly, None, None, None));
+        }
+        if let Some(video) = &mut video {
+            video.negotiating(change.add_media(
+                MediaKind::Video,
+                Direction::SendOnly,
+                None,
+                None,
+                None,
+            ));
+        }
+
+        let (offer, pending) = change
+            .apply()
+            .ok_or_else(|| RoverRtcError::Sdp("failed to apply the SDP change".into()))?;
+
+        info!(" Offer SDP:\n{}", offer);
+
+        // // 1. DECLARE INTENT: Request a new data channel.
+        // // This registers your desire for a channel; it doesn't create it yet.
+
+        info!(
+            "Peer: Requested data channel '{}' with ID: {:?}",
+            TEST_CHANNEL, cid
+        );
+
+        // // 2. DRIVE THE STATE MACHINE: The `poll_output` loop.
+        // // This replaces the direct call to `create_offer`.
+
+        setup.begin(SetupPhase::Signaling);
+        let offer = match &mdns {
+            Some(mdns) => mdns.conceal_offer(offer),
+            None => offer,
+        };
+        let resume = failover
+            .lock()
+            .expect("failover lock")
+            .resume_token()
+            .map(str::to_string);
+        let (answer, signaling) = SignalingChannel::exchange_offer(config, offer, resume).await?;
+
+        // Older servers, and listening peers in mesh mode, answer with a bare
+        // SDP answer and no metadata
+        if let Some(metadata) = answer.metadata() {
+            info!(
+                "Peer: Assigned client ID {} ({} ICE servers recommended)",
+                metadata.client_id,
+                metadata.ice_servers.len()
+            );
+            ice_servers.extend(metadata.ice_servers.iter().cloned());
+            if let Some(expires_at) = metadata.expires_at {
+                info!("Peer: Session expires at {} unless refreshed", expires_at);
+            }
+            refresh_token = metadata.refresh_token.clone();
+            failover
+                .lock()
+                .expect("failover lock")
+                .answered(&metadata.session_token, metadata.standby_url.as_deref());
+            crash::record_state("peer.client_id", metadata.client_id);
+        }
+        // Listening peers in mesh mode may conceal their candidates too
+        let answer = match &resolver {
+            Some(resolver) => resolver.resolve_answer(answer.into_sdp()),
+            None => answer.into_sdp(),
+        };
+        info!("Answer SDP:\n{}", answer);
+
+        if let Some(credentials) = ProbeCredentials::from_sdp(&answer.to_string()) {
+            path_mtu.set_credentials(credentials);
+        }
+        rtc.sdp_api().accept_answer(pending, answer)?;
+        // The first frames must include a keyframe
+        if let Some(mid) = video.as_mut().and_then(VideoTrack::answered) {
+            info!("Peer: Video track negotiated, media ID {}", mid);
+            handle.emit(PeerEvent::KeyframeRequested);
+        }
+        signaling
+    };
+    let mut gathering = StunGathering::new(&ice_servers);
+    let mut mapping = MappingCheck::new(config.mapping_check_secs);
+    let mut relays = relay_clients(&ice_servers);
+    setup.advance(SetupPhase::Signaling, SetupPhase::IceConnectivity);
+
+    info!("Peer: Answer accepted, waiting for ICE connection and channel to open...");
+
+    let mut channel_opened = false;
+    let mut ice_connected = false;
+    let mut last_message_time = Instant::now();
+    let mut message_sequence = 0;
+    let mut mission = MissionReceiver::new();
+    // The ID of the capture the server requested, while it runs
+    let mut capture_id = None;
+    if pcap::stop().is_some() {
+        info!("Peer: Discarding the capture of the previous session");
+    }
+    let (node_id, priority) = node_identity();
+    {
+        let mut coordinator = handle.coordinator.lock().expect("coordinator lock");
+        let mut next = Coordinator::new(node_id, priority);
+        if let Some(previous) = coordinator.as_ref() {
+            next.set_state(previous.state().to_vec());
+        }
+        *coordinator = Some(next);
+    }
+    let mut coordination_opened = false;
+    let mut labels: HashMap<ChannelId, String> = HashMap::new();
+    let mut builtin = BuiltinChannels::default();
+    handle
+        .transfers
+        .lock()
+        .expect("transfers lock")
+        .configure(&config.transfer, config.transfer.dir.clone());
+    let mut last_heartbeat_time = Instant::now();
+    let mut netmon = NetworkMonitor::new(Duration::from_secs(config.interface_scan_secs));
+    let mut handover = Handover {
+        mdns: mdns.clone(),
+        max_attempts: config.health.max_recoveries,
+        ..Handover::default()
+    };
+    let mut protocol = Negotiation::default();
+    let mut features = FeatureSet::default();
+    let mut batcher = Batcher::new();
+    let mut fragmenter = Fragmenter::new();
+    let mut reassembler = Reassembler::new();
+    let mut oversize = OversizeMonitor::new();
+    let mut scheduler = QosScheduler::new();
+    let mut write_attempts: HashMap<String, u32> = HashMap::new();
+    *handle.limiter.lock().expect("limiter lock") = RateLimiter::new(&config.rate_control);
+    handle.rates.lock().expect("rates lock").reset();
+    let mut link = LinkMonitor::new(config.protocol.heartbeat.clone());
+    *handle.link.lock().expect("link lock") = None;
+    let mut health = ConnectionHealth::new();
+    let health_policy = ThresholdPolicy::peer(&config.health);
+    let mut agreement = KeyAgreement::new();
+    *handle.keys.lock().expect("keys lock") = None;
+    let mut connection = ConnectionTracker::new();
+    let mut codecs = CodecTracker::new();
+    let mut last_stats_time = Instant::now();
+    *handle.connection.lock().expect("connection lock") = None;
+    let message_interval = Duration::from_secs(config.message_interval_secs);
+    info!(
+        "Peer: Coordination node ID {} with priority {}",
+        node_id, priority
+    );
+
+    loop {
+        if handle.is_stopped() {
+            info!("Peer: Stopped through handle, closing channels");
+            for (label, data) in batcher.flush() {
+                scheduler.push(&label, config.qos(&label), config.weight(&label), data);
+            }
+            for (label, data) in scheduler.drain() {
+                if let Err(e) = write_labeled(&mut rtc, &labels, &label, &data) {
+                    warn!("Peer: Failed to send on '{}': {:?}", label, e);
+                }
+            }
+            close_channels(&mut rtc, &labels, builtin.control, &socket, &mut relays);
+            rtc.disconnect();
+            for relay in &mut relays {
+                relay.close(&socket);
+            }
+            handle.emit(PeerEvent::Disconnected);
+            return Ok(SessionEnd::Stopped);
+        }
+
+        // Move to the standby once the primary is down, rather than waiting
+        // for ICE or the heartbeats to give up on it
+        if failover.lock().expect("failover lock").should_abandon() {
+            rtc.disconnect();
+            handle.emit(PeerEvent::Disconnected);
+            return Ok(SessionEnd::Lost(RoverRtcError::ConnectionLost(
+                "the primary signaling server is down".into(),
+            )));
+        }
+
+        // Query the recommended STUN servers for our reflexive address, and
+        // again from time to time in case a NAT re-mapped it
+        if setup.is_complete()
+            && !handover.is_restarting()
+            && gathering.is_idle()
+            && mapping.is_due()
+        {
+            gathering = StunGathering::new(&ice_servers);
+        }
+        gathering.poll(&socket);
+
+        // Keep the TURN allocations, permissions and channels alive
+        for relay in &mut relays {
+            relay.poll(&socket);
+        }
+
+        // Apply candidates the server trickled and answers to ICE restarts
+        for message in signaling.poll_messages() {
+            match message {
+                SignalingMessage::Candidate { candidate } => {
+                    match Candidate::from_sdp_string(
+                        candidate.strip_prefix("a=").unwrap_or(&candidate),
+                    ) {
+                        Ok(candidate) => rtc.add_remote_candidate(candidate),
+                        Err(e) => warn!("Peer: Ignoring invalid remote candidate: {:?}", e),
+                    }
+                }
+                SignalingMessage::RestartAnswer { answer } => {
+                    // The restarted ICE agent has new credentials
+                    if let Some(credentials) = ProbeCredentials::from_sdp(&answer.to_string()) {
+                        path_mtu.set_credentials(credentials);
+                    }
+                    handover.answered(&mut rtc, answer)
+                }
+                SignalingMessage::Error { message } => {
+                    warn!("Peer: Signaling error: {}", message);
+                    handover.rejected();
+                }
+                _ => {}
+            }
+        }
+
+        // Pick up interfaces that appeared or went away since the last scan
+        let network_events = netmon.poll();
+        log_network_events(&network_events);
+        if !network_events.is_empty() {
+            // A renewed lease may come with a new public address, even if
+            // the interface got its old one back
+            mapping.check_soon();
+            let candidates = get_candidates(&socket, &config.network)?;
+            let current: HashSet<SocketAddr> = candidates.iter().map(|c| c.addr()).collect();
+            if !current.is_empty() && current != host_addrs {
+                info!(
+                    "Peer: Network changed: {} interface address(es) added, {} removed",
+                    current.difference(&host_addrs).count(),
+                    host_addrs.difference(&current).count()
+                );
+                if !current.contains(&local_addr) {
+                    local_addr = candidates[0].addr();
+                }
+                host_addrs = current;
+
+                // Before the session is up, trickling is enough; afterwards
+                // the path must be renegotiated with an ICE restart
+                let established = setup.is_complete();
+                for candidate in candidates {
+                    if let Some(candidate) = rtc.add_local_candidate(candidate) {
+                        info!("Peer: Gathered host candidate {}", candidate.addr());
+                        if !established {
+                            signaling
+                                .trickle(conceal(mdns.as_deref(), candidate.to_sdp_string()))
+                                .await;
+                        }
+                    }
+                }
+                if established {
+                    // Reflexive and relayed addresses depend on the network too
+                    gathering = StunGathering::new(&ice_servers);
+                    mapping.clear();
+                    for relay in &mut relays {
+                        relay.close(&socket);
+                    }
+                    relays = relay_clients(&ice_servers);
+
+                    handle.emit(PeerEvent::Restarting);
+                    handover.restart(&mut rtc, &mut signaling).await;
+                }
+            }
+        }
+
+        // Retry a restart that did not reconnect, or give up
+        if handover.timed_out() && !handover.restart(&mut rtc, &mut signaling).await {
+            warn!("Peer: ICE did not reconnect, giving up");
+            rtc.disconnect();
+            handle.emit(PeerEvent::Disconnected);
+            return Ok(SessionEnd::Lost(RoverRtcError::ConnectionLost(
+                "ICE did not reconnect".into(),
+            )));
+        }
+
+        // Ask the remote for the rates our receivers want
+        if features.contains(Feature::RateControl) {
+            let changes = handle.rates.lock().expect("rates lock").take_changes();
+            if let Some(mut channel) = builtin.control.and_then(|id| rtc.channel(id)) {
+                for (topic, millihertz) in changes {
+                    let request = ControlMessage::RequestRate { topic, millihertz };
+                    if let Err(e) = channel.write(true, &request.encode()) {
+                        warn!("Peer: Failed to request a rate: {:?}", e);
+                    }
+                }
+            }
+        }
+
+        // Measure the link with heartbeats; one that stopped answering them
+        // is lost even if ICE still considers it connected
+        if features.contains(Feature::Heartbeat) {
+            if let Some(sequence) = link.poll(Instant::now()) {
+                if let Some(mut channel) = builtin.control.and_then(|id| rtc.channel(id)) {
+                    let ping = ControlMessage::Ping { sequence };
+                    if let Err(e) = channel.write(true, &ping.encode()) {
+                        warn!("Peer: Failed to send a heartbeat: {:?}", e);
+                    }
+                }
+            }
+            *handle.link.lock().expect("link lock") = Some(link.stats());
+            health.mark_link(&link);
+            if link.is_down() {
+                warn!(
+                    "Peer: {} heartbeat(s) in a row unanswered, the link is down",
+                    link.stats().missed
+                );
+                link.reset();
+            }
+        }
+
+        // Recover a degraded connection, e.g. because a NAT re-mapped the
+        // address under ICE; a pending restart times out on its own
+        if setup.is_complete() && !handover.is_restarting() {
+            let previous = health.state();
+            let action = health.check(&health_policy);
+            if health.state() != previous {
+                handle.emit(PeerEvent::HealthChanged {
+                    state: health.state(),
+                });
+            }
+            let recovering = match action {
+                HealthAction::None => true,
+                HealthAction::Recover { attempt } => {
+                    warn!(
+                        "Peer: Connection degraded, last datagram {:.1}s ago (recovery {}/{})",
+                        health.idle().as_secs_f64(),
+                        attempt,
+                        health_policy.max_recoveries
+                    );
+                    handle.emit(PeerEvent::Restarting);
+                    handover.restart(&mut rtc, &mut signaling).await
+                }
+                HealthAction::GiveUp => false,
+            };
+            if !recovering {
+                rtc.disconnect();
+                handle.emit(PeerEvent::Disconnected);
+                return Ok(SessionEnd::Lost(RoverRtcError::ConnectionLost(
+                    "connection health lost".into(),
+                )));
+            }
+        }
+
+        // Probe the path for the largest datagram it carries, and keep
+        // batches small enough to fit in one
+        if ice_connected {
+            let ufrag = rtc.direct_api().local_ice_credentials().ufrag;
+            if let Some(mtu) = path_mtu.poll(&socket, &ufrag, Instant::now()) {
+                batcher.set_max_size(pmtu::max_message_size(mtu));
+                *handle.path_mtu.lock().expect("path MTU lock") = Some(mtu);
+            }
+        }
+        let expired = reassembler.expire(Instant::now());
+        if expired > 0 {
+            warn!("Peer: Dropped {} incomplete fragmented message(s)", expired);
+        }
+
+        // Send data queued through the handle, split to fit a path that does
+        // not carry the stack's datagrams and coalesced into batches on the
+        // channels configured for it, as far as the remote decodes either
+        let now = Instant::now();
+        let batching = features.contains(Feature::Batching);
+        let max_fragment = path_mtu
+            .mtu()
+            .filter(|mtu| *mtu < pmtu::STACK_DATAGRAM_SIZE)
+            .map(pmtu::max_message_size);
+        let mut ready = vec![];
+        let due = handle
+            .schedule
+            .lock()
+            .expect("schedule lock")
+            .take_due(chrono::Utc::now(), |label| {
+                labels.values().any(|l| l == label)
+            });
+        let fragmenting = features.contains(Feature::Fragmentation);
+        let codec = codec_name(fragmenting, batching);
+        // Copies of duplicated messages keep their frame for remotes that
+        // deduplicate them, and lose it for the others
+        let deduplicating = features.contains(Feature::Deduplication);
+        let copies = handle
+            .take_duplicated()
+            .into_iter()
+            .filter_map(|(label, copy)| Some((label, Duplicate::decode(&copy)?, copy)));
+        let plain = handle.take_outbox().into_iter().chain(due);
+        let outgoing = copies
+            .map(|(label, copy, frame)| (label, copy.data, Some(frame)))
+            .chain(plain.map(|(label, data)| (label, data, None)));
+        for (label, data, frame) in outgoing {
+            if let Some(recorder) = recorder {
+                recorder.record(recording::Direction::Outbound, &label, None, &data);
+            }
+            let data = match (deduplicating, frame) {
+                (true, Some(frame)) => frame,
+                (true, None) => duplicate::frame_single(data),
+                (false, _) => data,
+            };
+            let started = Instant::now();
+            let bytes = data.len();
+            let fragments = if fragmenting {
+                fragmenter.fragment(data, max_fragment)
+            } else {
+                vec![data]
+            };
+            let mut wire_bytes = 0;
+            for data in fragments {
+                let encoded = match config.batch_window(&label).filter(|_| batching) {
+                    Some(window) => batcher.push(&label, data, window, now),
+                    None if batching => vec![batch::frame_single(data)],
+                    None => vec![data],
+                };
+                for message in encoded {
+                    wire_bytes += message.len();
+                    ready.push((label.clone(), message));
+                }
+            }
+            codecs.encoded(&label, codec, bytes, wire_bytes, started.elapsed());
+        }
+        let started = Instant::now();
+        let batches = batcher.poll(now);
+        let elapsed = started.elapsed() / batches.len().max(1) as u32;
+        for (label, message) in batches {
+            codecs.encoded(&label, codec, 0, message.len(), elapsed);
+            ready.push((label, message));
+        }
+
+        // Send a capture once it reached its duration or size limit
+        if capture_id.is_some() && pcap::is_finished() {
+            for message in finish_capture(&mut capture_id) {
+                ready.push((CAPTURE_CHANNEL.to_string(), message.encode()));
+            }
+        }
+
+        // Write the frames of the camera pipeline once the track is up
+        if let Some(video) = video.as_mut().filter(|_| ice_connected) {
+            let (frames, overflowed) = handle.video.lock().expect("video lock").take();
+            if overflowed {
+                warn!("Peer: Video frames dropped, waiting for a keyframe");
+                video.await_keyframe();
+                handle.emit(PeerEvent::KeyframeRequested);
+            }
+            for frame in frames {
+                if let Err(e) = video.write(&mut rtc, frame) {
+                    warn!("Peer: Failed to send a video frame: {:?}", e);
+                }
+            }
+        }
+
+        // Hand the data to SCTP by QoS class, then in weighted fair order,
+        // while its send buffers have room, so a bulk transfer cannot starve
+        // other channels and commands go out first
+        for (label, data) in ready {
+            scheduler.push(&label, config.qos(&label), config.weight(&label), data);
+        }
+        if builtin.transfer.is_some() {
+            let qos = config.qos(TRANSFER_CHANNEL);
+            let weight = config.weight(TRANSFER_CHANNEL);
+            for message in handle.transfers.lock().expect("transfers lock").poll() {
+                scheduler.push(TRANSFER_CHANNEL, qos, weight, message);
+            }
+        }
+        let buffered: usize = labels
+            .keys()
+            .filter_map(|id| rtc.channel(*id).map(|mut c| c.buffered_amount()))
+            .sum();
+        // A failed message blocks the rest of its channel, to keep its order
+        let mut blocked = HashSet::new();
+        let mut retry = vec![];
+        for (label, data) in scheduler.schedule(SEND_BUFFER_LIMIT.saturating_sub(buffered)) {
+            if blocked.contains(&label) {
+                retry.push((label, data));
+                continue;
+            }
+            let Err(e) = write_labeled(&mut rtc, &labels, &label, &data) else {
+                write_attempts.remove(&label);
+                continue;
+            };
+            let attempts = write_attempts.entry(label.clone()).or_insert(0);
+            *attempts += 1;
+            match config.options(&label).after_write_error(*attempts) {
+                WriteOutcome::Retry => {
+                    debug!("Peer: Keeps '{}' queued: {:?}", label, e);
+                    connection.write_retried();
+                    blocked.insert(label.clone());
+                    retry.push((label, data));
+                }
+                WriteOutcome::Drop => {
+                    write_attempts.remove(&label);
+                    warn!("Peer: Dropped a message on '{}': {:?}", label, e);
+                }
+                WriteOutcome::Fail => {
+                    write_attempts.remove(&label);
+                    warn!("Peer: Failed to send on '{}': {:?}", label, e);
+                    handle.emit(PeerEvent::WriteFailed {
+                        label,
+                        data,
+                        error: format!("{:?}", e),
+                    });
+                }
+            }
+        }
+        for (label, data) in retry.into_iter().rev() {
+            scheduler.requeue(&label, config.qos(&label), config.weight(&label), data);
+        }
+
+        // Publish the connection statistics for the handle
+        if last_stats_time.elapsed() >= STATS_INTERVAL {
+            let heartbeat = features.contains(Feature::Heartbeat).then(|| link.stats());
+            let mut channels = vec![];
+            for (id, label) in &labels {
+                if let Some(mut channel) = rtc.channel(*id) {
+                    channels.push(ChannelStats {
+                        label: label.clone(),
+                        buffered_amount: channel.buffered_amount(),
+                        queued_messages: scheduler.queued(label),
+                        codec: codecs.stats(label),
+                    });
+                }
+            }
+            channels.sort_by(|a, b| a.label.cmp(&b.label));
+            *handle.connection.lock().expect("connection lock") =
+                Some(connection.snapshot(heartbeat, channels));
+            last_stats_time = Instant::now();
+        }
+
+        let timeout = match rtc.poll_output()? {
+            Output::Timeout(instant) => {
+                // info!("{:?}", instant);
+                instant
+            }
+            Output::Transmit(transmit) => {
+                connection.sent(transmit.contents.len());
+                let oversized = oversize.check(
+                    &transmit.contents,
+                    path_mtu.mtu(),
+                    transmit.destination,
+                    Instant::now(),
+                );
+                // Traffic from a relayed candidate goes through its TURN server
+                match relays
+                    .iter_mut()
+                    .find(|r| r.relayed_addr() == Some(transmit.source))
+                {
+                    Some(relay) => relay.send(&socket, transmit.destination, &transmit.contents),
+                    None => {
+                        // DTLS records only go to the nominated pair
+                        if matches!(transmit.contents.first(), Some(20..=63)) {
+                            path_mtu.observe_path(transmit.destination);
+                        }
+                        match pcap::send_to(&socket, &transmit.contents, transmit.destination) {
+                            Ok(_) => {}
+                            // The kernel knows the path does not carry it
+                            // and may not fragment it
+                            Err(e) if pmtu::is_message_too_long(&e) => {
+                                if oversized.is_none() {
+                                    debug!(
+                                        "Peer: A {}-byte {} datagram exceeds the path MTU",
+                                        transmit.contents.len(),
+                                        DatagramLayer::of(&transmit.contents).name()
+                                    );
+                                }
+                            }
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                }
+                continue;
+            }
+            Output::Event(event) => {
+                connection.handle_event(&event);
+                if setup.observe(&event) {
+                    let breakdown = setup.breakdown();
+                    info!("Peer: Setup complete: {}", breakdown);
+                    handle.emit(PeerEvent::SetupComplete { breakdown });
+                    if let Some(outage) = reconnection.restored(Instant::now()) {
+                        info!(
+                            "Peer: Reconnected after {:.1}s and {} attempt(s)",
+                            outage.duration.as_secs_f64(),
+                            outage.attempts
+                        );
+                        handle.emit(PeerEvent::Reconnected {
+                            outage: outage.duration,
+                            attempts: outage.attempts,
+                        });
+                    }
+                }
+
+                // Always log events, but filter out too verbose ones
+                match &event {
+                    Event::IceConnectionStateChange(_)
+                    | Event::ChannelOpen(_, _)
+                    | Event::ChannelData(_) => {
+                        info!("Event: {:?}", event);
+                    }
+                    // Audio arrives every 20 ms
+                    Event::MediaData(_) => {}
+                    _ => {
+                        // Still log other events at debug level
+                        info!("Event (other): {:?}", event);
+                    }
+                }
+
+                // Track ICE connection state changes
+                if let Event::IceConnectionStateChange(state) = &event {
+                    info!("ICE Connection State: {:?}", state);
+                    match state {
+                        IceConnectionState::New => info!("ICE is starting..."),
+                        IceConnectionState::Checking => info!("ICE is checking candidates..."),
+                        IceConnectionState::Connected => {
+                            info!("ICE Connected! Data channel should open soon.");
+                        }
+                        IceConnectionState::Completed => info!("ICE Completed!"),
+                        IceConnectionState::Disconnected => info!("ICE Disconnected"),
+                    }
+
+                    // The controlling side may go straight from checking to completed
+                    let connected = matches!(
+                        state,
+                        IceConnectionState::Connected | IceConnectionState::Completed
+                    );
+                    if connected && !ice_connected {
+                        handover.connected();
+                        health.mark_recovered();
+                        handle.emit(PeerEvent::Connected);
+                    }
+                    ice_connected = connected;
+                    crash::record_state("peer.ice_state", format!("{:?}", state));
+                }
+
+                // Hand the operator's voice and the media received to the
+                // application and the frame sinks
+                if let Event::MediaData(data) = &event {
+                    let codec = data.params.spec().codec;
+                    let forwarded_track = forwarded.get_mut(&data.mid);
+                    let origin = forwarded_track.as_ref().map_or(0, |track| track.origin);
+                    if Some(data.mid) == audio_mid {
+                        match AudioFrame::from_media(codec, data.data.clone()) {
+                            Some(frame) => handle.emit(PeerEvent::Audio { frame }),
+                            None => debug!("Peer: Dropping {:?} audio", codec),
+                        }
+                    } else {
+                        // Frames following lost packets may not decode until
+                        // the next keyframe
+                        if let Some(track) = forwarded_track.filter(|_| !data.contiguous) {
+                            track.request_keyframe(&mut rtc, data.mid);
+                        }
+                        handle.emit(PeerEvent::Media {
+                            origin,
+                            kind: codec.kind(),
+                            codec,
+                            data: data.data.clone(),
+                        });
+                    }
+                    handle.write_frame(&ReceivedFrame {
+                        origin,
+                        mid: data.mid.to_string(),
+                        kind: codec.kind(),
+                        codec,
+                        time: data.time,
+                        keyframe: ReceivedFrame::is_keyframe(codec, &data.data),
+                        contiguous: data.contiguous,
+                        data: data.data.clone(),
+                    });
+                    continue;
+                }
+
+                // Ask the encoder for a keyframe whenever the server lost video
+                if let Event::KeyframeRequest(request) = &event {
+                    if video.as_ref().is_some_and(|v| v.is_track(request.mid)) {
+                        debug!("Peer: The server requested a keyframe ({:?})", request.kind);
+                        handle.emit(PeerEvent::KeyframeRequested);
+                    }
+                }
+
+                // Handle channel opening
+                if let Event::ChannelOpen(channel_id, name) = &event {
+                    info!(
+                        "Peer: Channel opened - Name: '{}', ID: {:?}",
+                        name, channel_id
+                    );
+                    labels.insert(*channel_id, name.clone());
+                    crash::record_state("peer.channels", labels.values().collect::<Vec<_>>());
+                    builtin.opened(*channel_id, name);
+                    handle.emit(PeerEvent::ChannelOpen {
+                        label: name.clone(),
+                    });
+                    if builtin.test == Some(*channel_id) {
+                        info!("   Test channel ready");
+                        channel_opened = true;
+                    } else if builtin.mission == Some(*channel_id) {
+                        info!("   Mission channel ready");
+                    } else if builtin.coordination == Some(*channel_id) {
+                        info!("   Coordination channel ready");
+                        coordination_opened = true;
+                    } else if builtin.control == Some(*channel_id) {
+                        info!("   Control channel ready, sending hello");
+                        if let Some(mut channel) = rtc.channel(*channel_id) {
+                            let hello = [
+                                ControlMessage::hello(),
+                                ControlMessage::capabilities(&config.protocol),
+                            ];
+                            for message in hello {
+                                if let Err(e) = channel.write(true, &message.encode()) {
+                                    warn!("Peer: Failed to send hello: {:?}", e);
+                                }
+                            }
+                        }
+                    } else if builtin.logs == Some(*channel_id) {
+                        info!("   Logs channel ready");
+                    } else if builtin.announce == Some(*channel_id) {
+                        info!("   Announce channel ready");
+                    } else if builtin.capture == Some(*channel_id) {
+                        info!("   Capture channel ready");
+                    } else if builtin.crash == Some(*channel_id) {
+                        info!("   Crash channel ready");
+                        for message in pending_crash_reports(config) {
+                            let qos = config.qos(CRASH_CHANNEL);
+                            let weight = config.weight(CRASH_CHANNEL);
+                            scheduler.push(CRASH_CHANNEL, qos, weight, message.encode());
+                        }
+                    } else if builtin.transfer == Some(*channel_id) {
+                        info!("   Transfer channel ready");
+                        handle.transfers.lock().expect("transfers lock").restart();
+                    } else if builtin.session != Some(*channel_id) {
+                        info!("   Additional channel ready");
+                    }
+                }
+
+                // Unpack batches and hand each message to the subscribers
+                if let Event::ChannelData(msg) = &event {
+                    if features.contains(Feature::Batching)
+                        && builtin.control != Some(msg.id)
+                        && batch::is_batch(&msg.data)
+                    {
+                        let started = Instant::now();
+                        match (labels.get(&msg.id), batch::decode_batch(&msg.data)) {
+                            (Some(label), Some(messages)) => {
+                                let fragmenting = features.contains(Feature::Fragmentation);
+                                let mut decoded = vec![];
+                                for message in messages {
+                                    if fragmenting && fragment::is_fragment(&message) {
+                                        decoded.extend(reassemble(
+                                            &mut reassembler,
+                                            label,
+                                            &message,
+                                        ));
+                                    } else {
+                                        decoded.push(message);
+                                    }
+                                }
+                                codecs.decoded(
+                                    label,
+                                    codec_name(fragmenting, true),
+                                    msg.data.len(),
+                                    decoded.iter().map(Vec::len).sum(),
+                                    started.elapsed(),
+                                );
+                                for message in decoded {
+                                    if let Some(recorder) = recorder {
+                                        recorder.record(
+                                            recording::Direction::Inbound,
+                                            label,
+                                            None,
+                                            &message,
+                                        );
+                                    }
+                                    handle.deliver(
+                                        label,
+                                        &message,
+                                        features.contains(Feature::Deduplication),
+                                    );
+                                }
+                            }
+                            _ => warn!("Peer: Discarding undecodable batch"),
+                        }
+                        continue;
+                    }
+                }
+
+                // Reassemble fragmented messages and hand them to the
+                // subscribers once complete
+                if let Event::ChannelData(msg) = &event {
+                    if features.contains(Feature::Fragmentation)
+                        && builtin.control != Some(msg.id)
+                        && fragment::is_fragment(&msg.data)
+                    {
+                        if let Some(label) = labels.get(&msg.id) {
+                            let started = Instant::now();
+                            let message = reassemble(&mut reassembler, label, &msg.data);
+                            codecs.decoded(
+                                label,
+                                codec_name(true, false),
+                                msg.data.len(),
+                                message.as_ref().map_or(0, Vec::len),
+                                started.elapsed(),
+                            );
+                            if let Some(message) = message {
+                                if let Some(recorder) = recorder {
+                                    recorder.record(
+                                        recording::Direction::Inbound,
+                                        label,
+                                        None,
+                                        &message,
+                                    );
+                                }
+                                handle.deliver(
+                                    label,
+                                    &message,
+                                    features.contains(Feature::Deduplication),
+                                );
+                            }
+                        }
+                        continue;
+                    }
+                }
+
+                // Fan incoming data out to the channel's subscribers
+                let mut dispatched = false;
+                if let Event::ChannelData(msg) = &event {
+                    if let Some(label) = labels.get(&msg.id) {
+                        let size = msg.data.len();
+                        codecs.decoded(label, CODEC_NONE, size, size, Duration::ZERO);
+                        if let Some(recorder) = recorder {
+                            recorder.record(recording::Direction::Inbound, label, None, &msg.data);
+                        }
+                        dispatched = handle.deliver(
+                            label,
+                            &msg.data,
+                            features.contains(Feature::Deduplication)
+                                && builtin.control != Some(msg.id),
+                        );
+                    }
+                }
+
+                // Negotiate the protocol version; an incompatible server
+                // ends the session with a precise error
+                if let Event::ChannelData(msg) = &event {
+                    if builtin.control == Some(msg.id) {
+                        let result = handle_control_data(
+                            &mut rtc,
+                            msg.id,
+                            config,
+                            (&mut protocol, &mut features, &mut agreement),
+                            &mut handle.limiter.lock().expect("limiter lock"),
+                            &mut link,
+                            &msg.data,
+                        );
+                        match result {
+                            Ok(ControlAction::Keys(keys)) => {
+                                *handle.keys.lock().expect("keys lock") = Some(Arc::new(*keys));
+                            }
+                            Ok(ControlAction::Restart) => {
+                                if setup.is_complete() && !handover.is_restarting() {
+                                    info!("Peer: The server asks for an ICE restart");
+                                    handle.emit(PeerEvent::Restarting);
+                                    if !handover.restart(&mut rtc, &mut signaling).await {
+                                        warn!("Peer: ICE restarts exhausted, not restarting");
+                                    }
+                                }
+                            }
+                            Ok(ControlAction::None) => {}
+                            Err(end) => {
+                                rtc.disconnect();
+                                handle.emit(PeerEvent::Disconnected);
+                                return Ok(end);
+                            }
+                        }
+                        continue;
+                    }
+                    if protocol.is_fallback()
+                        && [builtin.mission, builtin.coordination, builtin.session]
+                            .contains(&Some(msg.id))
+                    {
+                        info!("Peer: Dropping versioned data in protocol fallback mode");
+                        continue;
+                    }
+                }
+
+                // Handle incoming mission messages
+                if let Event::ChannelData(msg) = &event {
+                    if builtin.mission == Some(msg.id) {
+                        handle_mission_data(&mut rtc, &mut mission, msg.id, &msg.data);
+                        continue;
+                    }
+                    if builtin.coordination == Some(msg.id) {
+                        if let Some(message) = CoordinationMessage::decode(&msg.data) {
+                            let events = handle
+                                .with_coordinator(|c| c.handle(message, Instant::now()))
+                                .unwrap_or_default();
+                            log_coordination_events(&events);
+                        }
+                        continue;
+                    }
+                    if builtin.logs == Some(msg.id) {
+                        handle_logs_data(&mut rtc, msg.id, config, &msg.data);
+                        continue;
+                    }
+                    if builtin.capture == Some(msg.id) {
+                        for reply in handle_capture_data(&mut capture_id, config, &msg.data) {
+                            let qos = config.qos(CAPTURE_CHANNEL);
+                            let weight = config.weight(CAPTURE_CHANNEL);
+                            scheduler.push(CAPTURE_CHANNEL, qos, weight, reply.encode());
+                        }
+                        continue;
+                    }
+                    if builtin.crash == Some(msg.id) {
+                        handle_crash_data(&config.crash, &msg.data);
+                        continue;
+                    }
+                    if builtin.announce == Some(msg.id) {
+                        handle_announce_data(&mut rtc, msg.id, handle, &msg.data);
+                        continue;
+                    }
+                    if builtin.transfer == Some(msg.id) {
+                        let events = handle
+                            .transfers
+                            .lock()
+                            .expect("transfers lock")
+                            .handle(&msg.data);
+                        for event in events {
+                            handle.emit(transfer_event(event));
+                        }
+                        continue;
+                    }
+                    if builtin.forward == Some(msg.id) {
+                        let restarting = handover.pending.is_some();
+                        handle_forward_data(
+                            &mut rtc,
+                            msg.id,
+                            restarting,
+                            &mut forwarded,
+                            &msg.data,
+                        );
+                        continue;
+                    }
+                    if builtin.session == Some(msg.id) {
+                        handle_session_data(
+                            &mut rtc,
+                            msg.id,
+                            config,
+                            &mut refresh_token,
+                            &msg.data,
+                        );
+                        continue;
+                    }
+                }
+
+                // Log incoming data nobody subscribed to
+                if let (Event::ChannelData(msg), false) = (&event, dispatched) {
+                    info!(
+                        "Received data on channel {:?}: {:?}",
+                        msg.id,
+                        String::from_utf8_lossy(&msg.data)
+                    );
+                }
+
+                // Try another path if an established session loses its
+                // connection, otherwise abort
+                if event == Event::IceConnectionStateChange(IceConnectionState::Disconnected) {
+                    if setup.is_complete() && !handover.is_restarting() {
+                        handle.emit(PeerEvent::Restarting);
+                        if handover.restart(&mut rtc, &mut signaling).await {
+                            continue;
+                        }
+                    } else if handover.is_restarting() {
+                        continue;
+                    }
+                    info!("Disconnecting due to ICE state change");
+                    handle.emit(PeerEvent::Disconnected);
+                    return Ok(SessionEnd::Lost(RoverRtcError::ConnectionLost(
+                        "ICE disconnected".into(),
+                    )));
+                }
+
+                continue;
+            }
+        };
+
+        // Send periodic heartbeats to the convoy and expire silent members
+        if coordination_opened
+            && !protocol.is_fallback()
+            && last_heartbeat_time.elapsed() > HEARTBEAT_INTERVAL
+        {
+            let (events, heartbeat) = handle
+                .with_coordinator(|c| (c.poll(Instant::now()), c.heartbeat()))
+                .expect("coordinator of the session");
+            log_coordination_events(&events);
+            if let Some(mut channel) = builtin.coordination.and_then(|id| rtc.channel(id)) {
+                if let Err(e) = channel.write(true, &heartbeat.encode()) {
+                    warn!("Peer: Failed to send heartbeat: {:?}", e);
+                }
+            }
+            last_heartbeat_time = Instant::now();
+        }
+
+        // Send periodic timestamps to server if channel is open
+        if channel_opened && last_message_time.elapsed() > message_interval {
+            if let Some(mut channel) = builtin.test.and_then(|id| rtc.channel(id)) {
+                let payload: Payload = Payload::new("ciao".as_bytes());
+                info!(
+                    "Sending message {}\n Timestamp: {}",
+                    payload.data(),
+                    payload.timestamp()
+                );
+                let format = WireFormat::for_session(protocol, config.protocol.legacy_payloads);
+                let envelope =
+                    Envelope::new(MessageKind::Data, TEST_CHANNEL, message_sequence, payload);
+                match channel.write(true, &envelope.encode(format)) {
+                    Ok(_) => {
+                        info!("Message sent");
+                        message_sequence += 1;
+                        last_message_time = Instant::now();
+                        // Continue immediately to poll_output and flush the written data
+                        continue;
+                    }
+                    Err(e) => {
+                        info!("Peer: Failed to send message: {:?}", e);
+                    }
+                }
+            }
+        }
+
+        // Duration until timeout, or until the next batch is due.
+        // Cap the duration at 100ms to ensure we process incoming packets frequently
+        let timeout = batcher
+            .next_deadline()
+            .map_or(timeout, |due| due.min(timeout));
+        let duration = (timeout - Instant::now())
+            .max(Duration::from_millis(1))
+            .min(Duration::from_millis(100));
+
+        // socket.set_read_timeout(Some(0)) is not ok
+        if duration.is_zero() {
+            // Drive time forwards in rtc straight away.
+            rtc.handle_input(Input::Timeout(Instant::now()))?;
+            continue;
+        }
+
+        socket.set_read_timeout(Some(duration))?;
+
+        // Scale up buffer to receive an entire UDP packet.
+        buf.resize(2000, 0);
+
+        // Try to receive. Because we have a timeout on the socket,
+        // we will either receive a packet, or timeout.
+        let input = match socket.recv_from(&mut buf) {
+            Ok((n, source)) => {
+                // UDP data received.
+                buf.truncate(n);
+                connection.received(n);
+                pcap::received(&socket, source, &buf);
+                if netsim::drops_received() {
+                    continue;
+                }
+                health.mark_activity();
+                // A dual-stack socket reports IPv4 sources as mapped IPv6 addresses
+                let source = canonical_addr(source);
+
+                // Answers to path MTU probes and responses from STUN servers
+                // are ours, not str0m's
+                if path_mtu.handle_response(source, &buf) {
+                    continue;
+                }
+                if let Some(mapped) = gathering.handle_response(source, &buf) {
+                    let previous = mapping.observe(source, mapped);
+                    match Candidate::server_reflexive(mapped, local_addr, "udp") {
+                        Ok(candidate) => {
+                            add_gathered_candidate(
+                                &mut rtc,
+                                &mut signaling,
+                                mdns.as_deref(),
+                                candidate,
+                            )
+                            .await
+                        }
+                        Err(e) => warn!("Peer: Invalid reflexive address {}: {:?}", mapped, e),
+                    }
+                    // The server still answers the old mapping, so the path
+                    // has to be checked again
+                    if let Some(previous) = previous.filter(|_| setup.is_complete()) {
+                        info!(
+                            "Peer: Public address changed from {} to {}",
+                            previous, mapped
+                        );
+                        handle.record(
+                            EventCategory::Handover,
+                            format!("public address changed from {} to {}", previous, mapped),
+                        );
+                        if !handover.is_restarting() {
+                            handle.emit(PeerEvent::Restarting);
+                            handover.restart(&mut rtc, &mut signaling).await;
+                        }
+                    }
+                    continue;
+                }
+
+                // So are datagrams from TURN servers, which may carry relayed data
+                if let Some(relay) = relays.iter_mut().find(|r| r.server() == source) {
+                    let relayed = relay.relayed_addr();
+                    match relay.handle(&buf) {
+                        Some(TurnEvent::Allocated(addr)) => {
+                            match Candidate::relayed(addr, local_addr, "udp") {
+                                Ok(candidate) => {
+                                    add_gathered_candidate(
+                                        &mut rtc,
+                                        &mut signaling,
+                                        mdns.as_deref(),
+                                        candidate,
+                                    )
+                                    .await
+                                }
+                                Err(e) => warn!("Peer: Invalid relayed address {}: {:?}", addr, e),
+                            }
+                        }
+                        Some(TurnEvent::Data { peer, data }) => {
+                            if let (Some(relayed), Ok(contents)) =
+                                (relayed, data.as_slice().try_into())
+                            {
+                                rtc.handle_input(Input::Receive(
+                                    Instant::now(),
+                                    Receive {
+                                        proto: Protocol::Udp,
+                                        source: peer,
+                                        destination: relayed,
+                                        contents,
+                                    },
+                                ))?;
+                            }
+                        }
+                        Some(TurnEvent::Failed) => {
+                            warn!(
+                                "Peer: TURN server {} unavailable, no relay fallback",
+                                source
+                            )
+                        }
+                        None => {}
+                    }
+                    continue;
+                }
+
+                let Ok(contents) = buf.as_slice().try_into() else {
+                    debug!("Peer: Dropping an unrecognized datagram from {}", source);
+                    continue;
+                };
+                // str0m matches the destination against the local
+                // candidates, so it has to be one of the source's IP version
+                let destination = if source.is_ipv4() == local_addr.is_ipv4() {
+                    local_addr
+                } else {
+                    host_addrs
+                        .iter()
+                        .find(|addr| addr.is_ipv4() == source.is_ipv4())
+                        .copied()
+                        .unwrap_or(local_addr)
+                };
+                Input::Receive(
+                    Instant::now(),
+                    Receive {
+                        proto: Protocol::Udp,
+                        source,
+                        destination,
+                        contents,
+                    },
+                )
+            }
+
+            Err(e) => match e.kind() {
+                // Expected error for set_read_timeout().
+                // One for windows, one for the rest.
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => Input::Timeout(Instant::now()),
+                // A signal such as ctrl-c interrupted the read
+                ErrorKind::Interrupted => Input::Timeout(Instant::now()),
+
+                // Any other error is unexpected and should be propagated.
+                // We can't handle it here, so we pass it up to the caller.
+                _ => return Err(e.into()),
+            },
+        };
+
+        // Input is either a Timeout or Receive of data. Both drive the state forward.
+        rtc.handle_input(input)?;
+    }
+}
+
+/// Maximum number of Binding requests sent to each STUN server.
+const STUN_ATTEMPTS: u32 = 3;
+
+/// Interval between Binding request retransmissions.
+const STUN_RETRANSMIT: Duration = Duration::from_millis(500);
+
+/// An outstanding Binding request to a STUN server.
+struct StunProbe {
+    server: SocketAddr,
+    transaction: [u8; 12],
+    request: Vec<u8>,
+    attempts: u32,
+    last_sent: Option<Instant>,
+}
+
+/// Gathering of server-reflexive candidates from the recommended STUN servers.
+///
+/// Requests are sent from the WebRTC socket, so the mapped address is the one
+/// the remote side will see; responses are picked out of the receive path.
+#[derive(Default)]
+struct StunGathering {
+    probes: Vec<StunProbe>,
+}
+
+impl StunGathering {
+    /// Prepares a probe for every STUN URL of the servers whose credentials
+    /// have not expired.
+    fn new(servers: &[IceServer]) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+
+        let mut probes = vec![];
+        for server in servers.iter().filter(|s| !s.is_expired(now)) {
+            for url in server.stun_urls() {
+                let Some(addr) = stun::resolve_stun_url(url) else {
+                    warn!("Peer: Unable to resolve STUN server {}", url);
+                    continue;
+                };
+                let (transaction, request) = stun::binding_request();
+                probes.push(StunProbe {
+                    server: addr,
+                    transaction,
+                    request,
+                    attempts: 0,
+                    last_sent: None,
+                });
+            }
+        }
+        Self { probes }
+    }
+
+    /// Sends the requests that are due and gives up on unresponsive servers.
+    fn poll(&mut self, socket: &UdpSocket) {
+        self.probes.retain_mut(|probe| {
+            if probe
+                .last_sent
+                .is_some_and(|at| at.elapsed() < STUN_RETRANSMIT)
+            {
+                return true;
+            }
+            if probe.attempts >= STUN_ATTEMPTS {
+                warn!("Peer: STUN server {} did not respond", probe.server);
+                return false;
+            }
+            if let Err(e) = pcap::send_to(socket, &probe.request, probe.server) {
+                warn!("Peer: Failed to query STUN server {}: {}", probe.server, e);
+            }
+            probe.attempts += 1;
+            probe.last_sent = Some(Instant::now());
+            true
+        });
+    }
+
+    /// Matches a datagram against the outstanding requests.
+    ///
+    /// # Returns
+    ///
+    /// The mapped address if the datagram answers one of the requests
+    fn handle_response(&mut self, source: SocketAddr, bytes: &[u8]) -> Option<SocketAddr> {
+        let i = self.probes.iter().position(|p| p.server == source)?;
+        let mapped = stun::parse_binding_response(bytes, &self.probes[i].transaction)?;
+        self.probes.remove(i);
+        Some(mapped)
+    }
+
+    /// Returns `true` once every server answered or gave up.
+    fn is_idle(&self) -> bool {
+        self.probes.is_empty()
+    }
+}
+
+/// Detection of a changed public address.
+///
+/// A carrier-grade NAT may re-map the rover's address and port, e.g. after an
+/// upstream lease renewal, while every local interface stays as it is. The
+/// connected peer queries its STUN servers again from time to time and
+/// compares the answers with the earlier ones of the same server.
+struct MappingCheck {
+    /// Interval between the queries, if enabled
+    interval: Option<Duration>,
+    /// When the STUN servers are queried next
+    next: Instant,
+    /// The last address each STUN server reported
+    mapped: HashMap<SocketAddr, SocketAddr>,
+}
+
+impl MappingCheck {
+    /// Starts checking, the first time after an interval.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval_secs` - Interval between the queries; 0 disables them
+    fn new(interval_secs: u64) -> Self {
+        let interval = (interval_secs > 0).then(|| Duration::from_secs(interval_secs));
+        Self {
+            interval,
+            next: Instant::now() + interval.unwrap_or_default(),
+            mapped: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` if the STUN servers should be queried now, and
+    /// schedules the next query.
+    fn is_due(&mut self) -> bool {
+        let Some(interval) = self.interval else {
+            return false;
+        };
+        let now = Instant::now();
+        if now < self.next {
+            return false;
+        }
+        self.next = now + interval;
+        true
+    }
+
+    /// Queries the STUN servers at the next opportunity.
+    fn check_soon(&mut self) {
+        self.next = Instant::now();
+    }
+
+    /// Forgets the reported addresses, after the local addresses changed.
+    fn clear(&mut self) {
+        self.mapped.clear();
+    }
+
+    /// Records the address a STUN server reported.
+    ///
+    /// # Returns
+    ///
+    /// The address the server reported before, if it differs
+    fn observe(&mut self, server: SocketAddr, mapped: SocketAddr) -> Option<SocketAddr> {
+        self.mapped
+            .insert(server, mapped)
+            .filter(|previous| *previous != mapped)
+    }
+}
+
+/// Adds a server-reflexive or relayed candidate and trickles it to the
+/// signaling server.
+///
+/// # Arguments
+///
+/// * `rtc` - The RTC instance gathering candidates
+/// * `signaling` - The channel to the signaling server
+/// * `mdns` - The responder concealing the host candidates, if enabled
+/// * `candidate` - The candidate gathered from a STUN or TURN server
+async fn add_gathered_candidate(
+    rtc: &mut Rtc,
+    signaling: &mut SignalingChannel,
+    mdns: Option<&MdnsResponder>,
+    candidate: Candidate,
+) {
+    let Some(candidate) = rtc.add_local_candidate(candidate) else {
+        // Already known, e.g. because we are not behind a NAT
+        return;
+    };
+    info!(
+        "Peer: Gathered {} candidate {}",
+        candidate.kind(),
+        candidate.addr()
+    );
+    signaling
+        .trickle(conceal(mdns, candidate.to_sdp_string()))
+        .await;
+}
+
+/// Conceals the local addresses in a candidate or SDP when mDNS candidates
+/// are enabled.
+fn conceal(mdns: Option<&MdnsResponder>, sdp: String) -> String {
+    match mdns {
+        Some(mdns) => mdns.conceal_sdp(&sdp),
+        None => sdp,
+    }
+}
+
+/// Writes data on the open channel with the given label.
+///
+/// Data for a channel that is not open is dropped with a warning.
+///
+/// # Returns
+///
+/// The error of the write, for the channel's retry policy to handle
+fn write_labeled(
+    rtc: &mut Rtc,
+    labels: &HashMap<ChannelId, String>,
+    label: &str,
+    data: &[u8],
+) -> Result<(), RtcError> {
+    let channel = labels
+        .iter()
+        .find(|(_, l)| *l == label)
+        .and_then(|(id, _)| rtc.channel(*id));
+    match channel {
+        Some(mut channel) => channel.write(true, data).map(|_| ()),
+        None => {
+            warn!("Peer: Channel '{}' is not open, dropping data", label);
+            Ok(())
+        }
+    }
+}
+
+/// Says goodbye on the control channel, so the server drops the session at
+/// once instead of waiting for the ICE timeout, closes the open data channels
+/// and transmits what is still queued.
+///
+/// # Arguments
+///
+/// * `rtc` - The RTC instance of the connection
+/// * `labels` - The open channels
+/// * `control` - The control channel, if open
+/// * `socket` - The UDP socket for sending the closing packets
+/// * `relays` - The TURN clients relaying traffic from relayed candidates
+fn close_channels(
+    rtc: &mut Rtc,
+    labels: &HashMap<ChannelId, String>,
+    control: Option<ChannelId>,
+    socket: &UdpSocket,
+    relays: &mut [TurnClient],
+) {
+    if let Some(mut channel) = control.and_then(|cid| rtc.channel(cid)) {
+        let goodbye = ControlMessage::Goodbye {
+            reason: "peer stopped".to_string(),
+        };
+        let _ = channel.write(true, &goodbye.encode());
+    }
+    for cid in labels.keys() {
+        rtc.direct_api().close_data_channel(*cid);
+    }
+    let _ = rtc.handle_input(Input::Timeout(Instant::now()));
+    // Transmit everything queued until str0m only has a timeout left
+    loop {
+        match rtc.poll_output() {
+            Ok(Output::Transmit(transmit)) => match relays
+                .iter_mut()
+                .find(|r| r.relayed_addr() == Some(transmit.source))
+            {
+                Some(relay) => relay.send(socket, transmit.destination, &transmit.contents),
+                None => {
+                    let _ = pcap::send_to(socket, &transmit.contents, transmit.destination);
+                }
+            },
+            Ok(Output::Event(_)) => {}
+            Ok(Output::Timeout(_)) | Err(_) => break,
+        }
+    }
+}
+
+/// Creates a TURN client for every supported TURN URL of the servers that
+/// have credentials which have not expired.
+fn relay_clients(servers: &[IceServer]) -> Vec<TurnClient> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+
+    let mut relays = vec![];
+    for server in servers.iter().filter(|s| !s.is_expired(now)) {
+        let (Some(username), Some(credential)) = (&server.username, &server.credential) else {
+            continue;
+        };
+        for url in server.turn_urls() {
+            match turn::resolve_turn_url(url) {
+                Some(addr) => {
+                    info!("Peer: Using TURN server {} as relay fallback", url);
+                    relays.push(TurnClient::new(addr, username.clone(), credential.clone()));
+                }
+                None => warn!("Peer: Unsupported or unresolvable TURN server {}", url),
+            }
+        }
+    }
+    relays
+}
+
+/// The IDs of the built-in data channels, learned as they open.
+///
+/// A peer answering a mesh offer does not create the channels itself, so the
+/// IDs are taken from the open events on both sides.
+#[derive(Debug, Default)]
+struct BuiltinChannels {
+    test: Option<ChannelId>,
+    mission: Option<ChannelId>,
+    coordination: Option<ChannelId>,
+    session: Option<ChannelId>,
+    control: Option<ChannelId>,
+    logs: Option<ChannelId>,
+    capture: Option<ChannelId>,
+    crash: Option<ChannelId>,
+    transfer: Option<ChannelId>,
+    forward: Option<ChannelId>,
+    announce: Option<ChannelId>,
+}
+
+impl BuiltinChannels {
+    /// Records the ID of a channel that opened, if it is a built-in one.
+    fn opened(&mut self, id: ChannelId, label: &str) {
+        let slot = match label {
+            TEST_CHANNEL => &mut self.test,
+            MISSION_CHANNEL => &mut self.mission,
+            COORDINATION_CHANNEL => &mut self.coordination,
+            SESSION_CHANNEL => &mut self.session,
+            CONTROL_CHANNEL => &mut self.control,
+            LOGS_CHANNEL => &mut self.logs,
+            CAPTURE_CHANNEL => &mut self.capture,
+            CRASH_CHANNEL => &mut self.crash,
+            TRANSFER_CHANNEL => &mut self.transfer,
+            FORWARD_CHANNEL => &mut self.forward,
+            ANNOUNCE_CHANNEL => &mut self.announce,
+            _ => return,
+        };
+        *slot = Some(id);
+    }
+}
+
+/// Waits for another peer's offer through the signaling server, or on the
+/// LAN if discovery is enabled, and answers it.
+///
+/// The peer listens under its alias until an offer arrives; ICE, DTLS and the
+/// data channels then run directly between the two peers.
+///
+/// # Arguments
+///
+/// * `config` - The peer configuration with the signaling URL and alias
+/// * `rtc` - The RTC instance with the local candidates
+/// * `setup` - The setup timer; signaling starts once the offer arrives
+/// * `path_mtu` - The path MTU search, given the offering peer's credentials
+/// * `mdns` - The responder concealing the host candidates, if enabled
+/// * `resolver` - Resolves the `.local` candidates of the offer, if enabled
+///
+/// # Returns
+///
+/// The signaling channel, or an error if polling or answering failed
+async fn answer_mesh_offer(
+    config: &PeerConfig,
+    rtc: &mut Rtc,
+    setup: &mut SetupTimer,
+    path_mtu: &mut PathMtu,
+    mdns: Option<&MdnsResponder>,
+    resolver: Option<&MdnsResolver>,
+) -> Result<SignalingChannel, RoverRtcError> {
+    let alias = config
+        .alias
+        .as_deref()
+        .ok_or_else(|| RoverRtcError::Config("mesh_listen requires an alias".into()))?;
+    if config.discovery.enabled {
+        let announcer = Announcer::start(&config.discovery, alias)?;
+        info!(
+            "Peer: Listening for direct connections on the LAN as '{}'",
+            alias
+        );
+        let offer = loop {
+            match announcer.next_offer() {
+                Some(offer) => break offer,
+                None => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        };
+        info!(
+            "Peer: Received offer {} from {} on the LAN",
+            offer.id,
+            offer.from.as_deref().unwrap_or("an anonymous peer")
+        );
+        let answer = accept_mesh_offer(rtc, setup, path_mtu, (mdns, resolver), offer.offer)?;
+        announcer.answer(&offer.id, answer)?;
+        return Ok(SignalingChannel::Lan {
+            _announcer: Some(announcer),
+        });
+    }
+
+    let base_url = config.signaling_url.trim_end_matches('/').to_string();
+    let client = config.http_client()?;
+    let credential = config.credential();
+    info!("Peer: Listening for direct connections as '{}'", alias);
+
+    let offer = loop {
+        let mut request = client
+            .get(format!("{}{}", base_url, MESH_OFFERS_PATH))
+            .query(&[("alias", alias)]);
+        if let Some(token) = &credential {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?.error_for_status()?;
+        if response.status() == reqwest::StatusCode::NO_CONTENT {
+            continue;
+        }
+        break response.json::<MeshOffer>().await?;
+    };
+    info!(
+        "Peer: Received offer {} from {}",
+        offer.id,
+        offer.from.as_deref().unwrap_or("an anonymous peer")
+    );
+
+    let answer = accept_mesh_offer(rtc, setup, path_mtu, (mdns, resolver), offer.offer)?;
+    let mut request = client
+        .post(format!("{}{}", base_url, MESH_ANSWERS_PATH))
+        .json(&MeshAnswer {
+            id: offer.id,
+            answer,
+        });
+    if let Some(token) = &credential {
+        request = request.bearer_auth(token);
+    }
+    request.send().await?.error_for_status()?;
+
+    Ok(SignalingChannel::Http {
+        client,
+        base_url,
+        session_token: None,
+    })
+}
+
+/// Accepts another peer's offer in mesh mode.
+///
+/// # Arguments
+///
+/// * `rtc` - The RTC instance with the local candidates
+/// * `setup` - The setup timer; signaling starts with the offer
+/// * `path_mtu` - The path MTU search, given the offering peer's credentials
+/// * `mdns` - The responder concealing the host candidates, and the resolver
+///   of the offer's `.local` candidates, if enabled
+/// * `offer` - The offer
+///
+/// # Returns
+///
+/// The answer to send back, or an error if the offer was not accepted
+fn accept_mesh_offer(
+    rtc: &mut Rtc,
+    setup: &mut SetupTimer,
+    path_mtu: &mut PathMtu,
+    mdns: (Option<&MdnsResponder>, Option<&MdnsResolver>),
+    offer: SdpOffer,
+) -> Result<SdpAnswer, RoverRtcError> {
+    let (responder, resolver) = mdns;
+    setup.begin(SetupPhase::Signaling);
+    if let Some(credentials) = ProbeCredentials::from_sdp(&offer.to_string()) {
+        path_mtu.set_credentials(credentials);
+    }
+    let remote = match resolver {
+        Some(resolver) => resolver.resolve_offer(offer),
+        None => offer,
+    };
+    let answer = rtc.sdp_api().accept_offer(remote)?;
+    info!("Answer SDP:\n{}", answer);
+    Ok(match responder {
+        Some(responder) => responder.conceal_answer(answer),
+        None => answer,
+    })
+}
+
+/// Network handover through ICE restarts.
+///
+/// A restart renegotiates the ICE credentials and candidates over the
+/// signaling channel while DTLS, SCTP and the data channels carry on, so the
+/// application state survives moving to another network.
+#[derive(Default)]
+struct Handover {
+    /// The restart offer awaiting its answer over the WebSocket
+    pending: Option<SdpPendingOffer>,
+    /// When the current restart started, until ICE reconnects
+    started: Option<Instant>,
+    /// Restarts since ICE was last connected
+    attempts: u32,
+    /// Restarts attempted before giving up
+    max_attempts: u32,
+    /// The responder concealing the host candidates of restart offers
+    mdns: Option<Arc<MdnsResponder>>,
+}
+
+impl Handover {
+    /// Returns `true` while a restart has not reconnected.
+    fn is_restarting(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// Returns `true` if the current restart has not reconnected in time.
+    fn timed_out(&self) -> bool {
+        self.started
+            .is_some_and(|at| at.elapsed() > ICE_RESTART_TIMEOUT)
+    }
+
+    /// Sends an ICE restart offer with the current local candidates.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the restarts are exhausted and the peer should give up
+    async fn restart(&mut self, rtc: &mut Rtc, signaling: &mut SignalingChannel) -> bool {
+        if self.attempts >= self.max_attempts {
+            return false;
+        }
+        self.attempts += 1;
+        self.started = Some(Instant::now());
+        info!(
+            "Peer: Restarting ICE (attempt {}/{})",
+            self.attempts, self.max_attempts
+        );
+
+        let mut change = rtc.sdp_api();
+        change.ice_restart(true);
+        let Some((offer, pending)) = change.apply() else {
+            return true;
+        };
+        let offer = match &self.mdns {
+            Some(mdns) => mdns.conceal_offer(offer),
+            None => offer,
+        };
+        match signaling.restart(offer).await {
+            Ok(Some(answer)) => accept_restart_answer(rtc, pending, answer),
+            Ok(None) => self.pending = Some(pending),
+            // Retried once the restart times out
+            Err(e) => warn!("Peer: ICE restart failed: {}", e),
+        }
+        true
+    }
+
+    /// Applies the answer to the pending restart offer.
+    fn answered(&mut self, rtc: &mut Rtc, answer: SdpAnswer) {
+        match self.pending.take() {
+            Some(pending) => accept_restart_answer(rtc, pending, answer),
+            None => warn!("Peer: Ignoring unexpected ICE restart answer"),
+        }
+    }
+
+    /// Drops the pending restart offer the server rejected.
+    fn rejected(&mut self) {
+        self.pending = None;
+    }
+
+    /// Records that ICE connected again.
+    fn connected(&mut self) {
+        if let Some(started) = self.started.take() {
+            info!(
+                "Peer: Handover complete after {:.1}s",
+                started.elapsed().as_secs_f64()
+            );
+        }
+        self.attempts = 0;
+        self.pending = None;
+    }
+}
+
+/// Accepts the answer to an ICE restart offer, logging a rejection.
+fn accept_restart_answer(rtc: &mut Rtc, pending: SdpPendingOffer, answer: SdpAnswer) {
+    match rtc.sdp_api().accept_answer(pending, answer) {
+        Ok(()) => info!("Peer: ICE restart answer accepted, checking new candidate pairs"),
+        Err(e) => warn!("Peer: Invalid ICE restart answer: {:?}", e),
+    }
+}
+
+/// Transport used to exchange signaling messages with the server.
+enum SignalingChannel {
+    /// The offer is POSTed once; candidates are POSTed to [`TRICKLE_PATH`]
+    /// and restart offers to [`RESTART_PATH`]
+    Http {
+        client: reqwest::Client,
+        base_url: String,
+        session_token: Option<String>,
+    },
+    /// A WebSocket kept open to trickle candidates in both directions;
+    /// once it closes, signaling falls back to HTTP on the same server
+    WebSocket {
+        socket: Box<WebSocket<MaybeTlsStream<TcpStream>>>,
+        client: reqwest::Client,
+        base_url: String,
+        session_token: Option<String>,
+    },
+    /// The offer and answer were exchanged directly on the LAN; nothing is
+    /// trickled afterwards
+    Lan {
+        /// The listening peer's announcer, kept to answer a retransmitted
+        /// offer again
+        _announcer: Option<Announcer>,
+    },
+}
+
+impl SignalingChannel {
+    /// Sends the offer and waits for the answer.
+    ///
+    /// `ws://` URLs use the WebSocket transport; anything else uses HTTP.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The peer configuration with the signaling URL and alias
+    /// * `offer` - The SDP offer
+    /// * `resume` - The token of the session lost with the primary, when
+    ///   signaling to the standby
+    ///
+    /// # Returns
+    ///
+    /// The answer, and the channel for trickling candidates afterwards
+    async fn exchange_offer(
+        config: &PeerConfig,
+        offer: SdpOffer,
+        resume: Option<String>,
+    ) -> Result<(AnswerBody, SignalingChannel), RoverRtcError> {
+        if let (true, Some(target)) = (config.discovery.enabled, &config.mesh_target) {
+            let discovery = config.discovery.clone();
+            let from = config.alias.clone();
+            let target = target.clone();
+            let answer = tokio::task::spawn_blocking(move || {
+                discovery::offer(&discovery, from.as_deref(), &target, offer)
+            })
+            .await
+            .map_err(|_| RoverRtcError::Disconnected("LAN discovery"))??;
+            return Ok((
+                AnswerBody::Bare(answer),
+                SignalingChannel::Lan { _announcer: None },
+            ));
+        }
+
+        let mut url = reqwest::Url::parse(&config.signaling_url)
+            .map_err(|e| RoverRtcError::Config(format!("signaling_url: {}", e)))?;
+        match &config.mesh_target {
+            // The listening peer answers, through the server's broker
+            Some(target) => {
+                url.set_path(MESH_CONNECT_PATH);
+                url.query_pairs_mut().append_pair("target", target);
+            }
+            None => {
+                url.query_pairs_mut()
+                    .append_pair(ANSWER_FORMAT_PARAM, "structured");
+            }
+        }
+        if let Some(alias) = &config.alias {
+            // Lets the server and its operators refer to this rover by name
+            url.query_pairs_mut().append_pair("alias", alias);
+        }
+        if let Some(token) = &resume {
+            url.query_pairs_mut().append_pair(RESUME_PARAM, token);
+        }
+
+        let credential = config.credential();
+        if matches!(url.scheme(), "ws" | "wss") {
+            let connector = config.tls_connector()?;
+            let (answer, socket) =
+                Self::exchange_offer_websocket(url.as_str(), credential, connector, offer)?;
+            let mut base_url = url.clone();
+            let scheme = if url.scheme() == "wss" {
+                "https"
+            } else {
+                "http"
+            };
+            base_url
+                .set_scheme(scheme)
+                .map_err(|_| RoverRtcError::Config("signaling_url: invalid scheme".into()))?;
+            base_url.set_path("");
+            base_url.set_query(None);
+            let session_token = Some(answer.session_token.clone());
+            return Ok((
+                AnswerBody::Structured(answer),
+                SignalingChannel::WebSocket {
+                    socket: Box::new(socket),
+                    client: config.http_client()?,
+                    base_url: base_url.as_str().trim_end_matches('/').to_string(),
+                    session_token,
+                },
+            ));
+        }
+
+        let client = config.http_client()?;
+        let mut request = client
+            .post(url)
+            .body(serde_json::to_string(&offer).map_err(|e| RoverRtcError::Sdp(e.to_string()))?);
+        if let Some(token) = credential {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let reason = response.text().await.unwrap_or_default();
+            return Err(RoverRtcError::Signaling(format!(
+                "the server answered {}: {}",
+                status, reason
+            )));
+        }
+        let answer: AnswerBody = response.json().await?;
+        let session_token = answer.metadata().map(|m| m.session_token.clone());
+        Ok((
+            answer,
+            SignalingChannel::Http {
+                client,
+                base_url: config.signaling_url.trim_end_matches('/').to_string(),
+                session_token,
+            },
+        ))
+    }
+
+    fn exchange_offer_websocket(
+        url: &str,
+        credential: Option<String>,
+        connector: Option<Connector>,
+        offer: SdpOffer,
+    ) -> Result<(SignalingAnswer, WebSocket<MaybeTlsStream<TcpStream>>), RoverRtcError> {
+        let mut request = url.into_client_request()?;
+        if let Some(token) = credential {
+            request.headers_mut().insert(
+                AUTHORIZATION,
+                format!("Bearer {}", token)
+                    .parse()
+                    .map_err(|_| RoverRtcError::Config("invalid bearer token".into()))?,
+            );
+        }
+        let (mut socket, _) = match connector {
+            Some(connector) => {
+                let host = request
+                    .uri()
+                    .host()
+                    .ok_or_else(|| RoverRtcError::Config("signaling URL without host".into()))?;
+                let default_port = match request.uri().scheme_str() {
+                    Some("ws") => 80,
+                    _ => 443,
+                };
+                let port = request.uri().port_u16().unwrap_or(default_port);
+                let stream = TcpStream::connect((host.trim_matches(['[', ']']), port))?;
+                tungstenite::client_tls_with_config(request, stream, None, Some(connector))
+                    .map_err(|e| RoverRtcError::Signaling(e.to_string()))?
+            }
+            None => tungstenite::connect(request)?,
+        };
+        let offer = serde_json::to_string(&SignalingMessage::Offer { offer })
+            .map_err(|e| RoverRtcError::Sdp(e.to_string()))?;
+        socket.send(Message::text(offer))?;
+
+        let answer = loop {
+            let Message::Text(text) = socket.read()? else {
+                continue;
+            };
+            let message = serde_json::from_str(&text).map_err(|e| RoverRtcError::Decode {
+                what: "signaling message",
+                reason: e.to_string(),
+            })?;
+            match message {
+                SignalingMessage::Answer { answer } => break answer,
+                SignalingMessage::Error { message } => {
+                    return Err(RoverRtcError::Signaling(message))
+                }
+                other => warn!("Peer: Unexpected signaling message {:?}", other),
+            }
+        };
+
+        // From here on the socket is polled from the event loop.
+        match socket.get_ref() {
+            MaybeTlsStream::Plain(stream) => stream.set_nonblocking(true)?,
+            MaybeTlsStream::NativeTls(stream) => stream.get_ref().set_nonblocking(true)?,
+            _ => {}
+        }
+        Ok((answer, socket))
+    }
+
+    /// Sends a late-gathered local candidate to the server.
+    ///
+    /// Failures are logged; the connection may still succeed with the
+    /// candidates the server already knows.
+    async fn trickle(&mut self, candidate: String) {
+        match self {
+            SignalingChannel::Http {
+                client,
+                base_url,
+                session_token: Some(session_token),
+            } => {
+                let body = TrickleCandidate {
+                    session_token: session_token.clone(),
+                    candidate,
+                };
+                let url = format!("{}{}", base_url, TRICKLE_PATH);
+                if let Err(e) = client.post(url).json(&body).send().await {
+                    warn!("Peer: Failed to trickle candidate: {}", e);
+                }
+            }
+            // Older servers did not hand out a session token to trickle with
+            SignalingChannel::Http { .. } => {}
+            SignalingChannel::WebSocket { socket, .. } => {
+                let message = SignalingMessage::Candidate { candidate };
+                let text = serde_json::to_string(&message).expect("candidate to serialise");
+                if let Err(e) = socket.send(Message::text(text)) {
+                    warn!("Peer: Failed to trickle candidate: {}", e);
+                }
+            }
+            SignalingChannel::Lan { .. } => {}
+        }
+    }
+
+    /// Sends an ICE restart offer.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(SdpAnswer))` - The answer, over HTTP
+    /// * `Ok(None)` - If the answer will arrive through [`SignalingChannel::poll_messages`]
+    /// * `Err(RoverRtcError)` - If the offer could not be delivered or was rejected
+    async fn restart(&mut self, offer: SdpOffer) -> Result<Option<SdpAnswer>, RoverRtcError> {
+        match self {
+            SignalingChannel::Http {
+                client,
+                base_url,
+                session_token: Some(session_token),
+            } => {
+                let body = RestartOffer {
+                    session_token: session_token.clone(),
+                    offer,
+                };
+                let answer = client
+                    .post(format!("{}{}", base_url, RESTART_PATH))
+                    .json(&body)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                Ok(Some(answer))
+            }
+            SignalingChannel::Http { .. } => Err(RoverRtcError::Signaling(
+                "the server does not support ICE restarts".into(),
+            )),
+            SignalingChannel::WebSocket { socket, .. } => {
+                let message = SignalingMessage::Restart { offer };
+                let message = serde_json::to_string(&message)
+                    .map_err(|e| RoverRtcError::Sdp(e.to_string()))?;
+                socket.send(Message::text(message))?;
+                Ok(None)
+            }
+            SignalingChannel::Lan { .. } => Err(RoverRtcError::Signaling(
+                "ICE restarts are not supported over the LAN".into(),
+            )),
+        }
+    }
 
-use crate::{
-    model::payload::Payload,
-    util::{get_candidates, init_log},
-};
+    /// Drains the candidates, restart answers and errors the server sent
+    /// since the last call.
+    fn poll_messages(&mut self) -> Vec<SignalingMessage> {
+        let SignalingChannel::WebSocket {
+            socket,
+            client,
+            base_url,
+            session_token,
+        } = self
+        else {
+            return vec![];
+        };
 
-/// Errors that can occur during WebRTC peer operations.
-#[derive(Debug)]
-pub enum WebrtcError {
-    /// Error communicating with the signaling server
-    ServerError(Box<dyn Error + Send + Sync>),
-    /// Error related to SDP negotiation
-    SdpError,
-    /// General WebRTC error
-    WebrtcError(Box<dyn Error + Send + Sync>),
-    /// Network communication error
-    NetworkError(Box<dyn Error + Send + Sync>),
-    /// Error sending data on a channel
-    SendError(String),
-    /// No ICE candidates were found
-    NoCandidates,
+        let mut messages = vec![];
+        loop {
+            match socket.read() {
+                Ok(Message::Text(text)) => match serde_json::from_str(&text) {
+                    Ok(SignalingMessage::EndOfCandidates) => {
+                        info!("Peer: Server finished sending candidates")
+                    }
+                    Ok(
+                        message @ (SignalingMessage::Candidate { .. }
+                        | SignalingMessage::RestartAnswer { .. }
+                        | SignalingMessage::Error { .. }),
+                    ) => messages.push(message),
+                    Ok(other) => warn!("Peer: Unexpected signaling message {:?}", other),
+                    Err(e) => warn!("Peer: Invalid signaling message: {}", e),
+                },
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Peer: Signaling websocket closed: {}", e);
+                    *self = SignalingChannel::Http {
+                        client: client.clone(),
+                        base_url: std::mem::take(base_url),
+                        session_token: session_token.take(),
+                    };
+                    break;
+                }
+            }
+        }
+        messages
+    }
 }
 
-/// Main entry point for the WebRTC peer client.
-///
-/// This async function performs the complete WebRTC connection sequence:
-/// 1. Creates a new RTC instance and binds a UDP socket
-/// 2. Discovers and adds local ICE candidates
-/// 3. Creates a data channel and generates an SDP offer
-/// 4. Sends the offer to the signaling server and receives an answer
-/// 5. Accepts the answer and starts the connection process
-/// 6. Enters the main event loop to handle ICE state changes, channel events, and data
-/// 7. Processes incoming/outgoing UDP packets and drives the WebRTC state machine
+/// Adds a received fragment to the messages being reassembled.
 ///
 /// # Returns
 ///
-/// * `Ok(())` - If the peer completes successfully or disconnects gracefully
-/// * `Err(Box<dyn Error>)` - If any error occurs during the connection process
+/// The message, if the fragment completed it; malformed fragments are
+/// dropped with a warning
+fn reassemble(reassembler: &mut Reassembler, label: &str, fragment: &[u8]) -> Option<Vec<u8>> {
+    match reassembler.push(label, fragment, Instant::now()) {
+        Ok(message) => message,
+        Err(e) => {
+            warn!("Peer: Discarding a fragment on '{}': {}", label, e);
+            None
+        }
+    }
+}
+
+/// Processes a message received on the mission channel.
+///
+/// Feeds the message into the [`MissionReceiver`] and writes any responses
+/// (acknowledgements, rejections or the active plan) back on the same channel.
 ///
-/// # Example Data Channel
+/// # Arguments
 ///
-/// The peer creates a data channel named "test" which can be used to send and receive
-/// arbitrary binary data once the connection is established.
-#[tokio::main]
-pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Starting modern str0m peer...");
-    init_log();
+/// * `rtc` - The RTC instance owning the mission channel
+/// * `mission` - The receiver holding the active and staged plans
+/// * `mission_cid` - The ID of the mission data channel
+/// * `data` - The raw bytes received on the channel
+fn handle_mission_data(
+    rtc: &mut Rtc,
+    mission: &mut MissionReceiver,
+    mission_cid: ChannelId,
+    data: &[u8],
+) {
+    let Some(message) = MissionMessage::decode(data) else {
+        warn!("Peer: Discarding undecodable mission message");
+        return;
+    };
 
-    const CHANNEL: &str = "test";
+    let previous = mission.active().map(|p| p.version);
+    let replies = mission.handle(message);
+
+    if let Some(plan) = mission.active() {
+        if Some(plan.version) != previous {
+            info!(
+                "Peer: Switched to mission plan v{} with {} waypoints",
+                plan.version,
+                plan.waypoints.len()
+            );
+        }
+    }
 
-    let mut rtc = Rtc::new();
+    let Some(mut channel) = rtc.channel(mission_cid) else {
+        return;
+    };
+    for reply in replies {
+        if let Err(e) = channel.write(true, &reply.encode()) {
+            warn!("Peer: Failed to send mission reply: {:?}", e);
+        }
+    }
+}
 
-    let socket = UdpSocket::bind("0.0.0.0:0".parse::<SocketAddrV4>().expect("Parsing failed"))
-        .expect("Should bind udp socket");
-    let candidates = get_candidates(&socket);
+/// Answers a log request received on the logs channel.
+///
+/// The matching lines of the [`logbuf`] buffer are written back in chunks,
+/// compressed if the request asks for it; a peer configured not to serve its
+/// logs refuses the request instead.
+///
+/// # Arguments
+///
+/// * `rtc` - The RTC instance owning the logs channel
+/// * `logs_cid` - The ID of the logs data channel
+/// * `config` - The peer configuration
+/// * `data` - The raw bytes received on the channel
+fn handle_logs_data(rtc: &mut Rtc, logs_cid: ChannelId, config: &PeerConfig, data: &[u8]) {
+    let Some(LogMessage::Request { id, query }) = LogMessage::decode(data) else {
+        warn!("Peer: Discarding unexpected logs message");
+        return;
+    };
 
-    // Store the first candidate's address to use as destination in receives
-    // All candidates share the same port, so we can use any of them
-    let local_addr = candidates
-        .first()
-        .map(|c| c.addr())
-        .expect("At least one candidate should be available");
+    let replies = if config.serve_logs {
+        let text = logbuf::query(&query);
+        info!(
+            "Peer: Serving {} bytes of logs for request {}",
+            text.len(),
+            id
+        );
+        if query.compress {
+            LogMessage::chunks(id, true, &logbuf::compress(text.as_bytes()))
+        } else {
+            LogMessage::chunks(id, false, text.as_bytes())
+        }
+    } else {
+        vec![LogMessage::Refused {
+            id,
+            reason: "log retrieval is disabled on this peer".into(),
+        }]
+    };
 
-    for candidate in candidates {
-        rtc.add_local_candidate(candidate);
+    let Some(mut channel) = rtc.channel(logs_cid) else {
+        return;
+    };
+    for reply in replies {
+        if let Err(e) = channel.write(true, &reply.encode()) {
+            warn!("Peer: Failed to send logs reply: {:?}", e);
+            return;
+        }
     }
+}
 
-    let mut change = rtc.sdp_api();
-    let cid = change.add_channel(CHANNEL.to_string());
-
-    let (offer, pending) = change.apply().ok_or("Failed to apply sdp change")?;
+/// Hands an announcement received on the announce channel to the
+/// application as [`PeerEvent::Announcement`], then acknowledges it.
+///
+/// # Arguments
+///
+/// * `rtc` - The RTC instance owning the announce channel
+/// * `announce_cid` - The ID of the announce data channel
+/// * `handle` - The handle whose callbacks receive the announcement
+/// * `data` - The raw bytes received on the channel
+fn handle_announce_data(rtc: &mut Rtc, announce_cid: ChannelId, handle: &PeerHandle, data: &[u8]) {
+    let Some(AnnounceMessage::Announce { id, data }) = AnnounceMessage::decode(data) else {
+        warn!("Peer: Discarding unexpected announce message");
+        return;
+    };
+    info!("Peer: Received announcement {} ({} bytes)", id, data.len());
+    handle.emit(PeerEvent::Announcement { id, data });
 
-    info!(" Offer SDP:\n{}", offer);
+    let Some(mut channel) = rtc.channel(announce_cid) else {
+        return;
+    };
+    if let Err(e) = channel.write(true, &AnnounceMessage::Ack { id }.encode()) {
+        warn!("Peer: Failed to acknowledge announcement {}: {:?}", id, e);
+    }
+}
 
-    // // 1. DECLARE INTENT: Request a new data channel.
-    // // This registers your desire for a channel; it doesn't create it yet.
+/// Handles a message received on the capture channel.
+///
+/// A start request within the configured limits starts a capture, which the
+/// event loop sends once it is finished; a stop request finishes it at once.
+///
+/// # Arguments
+///
+/// * `capture_id` - The ID of the running capture, if any
+/// * `config` - The peer configuration with the capture limits
+/// * `data` - The raw bytes received on the channel
+///
+/// # Returns
+///
+/// The messages to send back: a refusal, or the chunks of a stopped capture
+fn handle_capture_data(
+    capture_id: &mut Option<u32>,
+    config: &PeerConfig,
+    data: &[u8],
+) -> Vec<CaptureMessage> {
+    let refuse = |id, reason: &str| {
+        warn!("Peer: Refusing capture {}: {}", id, reason);
+        vec![CaptureMessage::Refused {
+            id,
+            reason: reason.to_string(),
+        }]
+    };
+    match CaptureMessage::decode(data) {
+        Some(CaptureMessage::Start {
+            id,
+            duration_secs,
+            max_bytes,
+        }) => {
+            let limits = &config.capture;
+            if !limits.enabled {
+                return refuse(id, "packet capture is disabled on this peer");
+            }
+            let duration =
+                Duration::from_secs((duration_secs as u64).min(limits.max_duration_secs));
+            let max_bytes = (max_bytes as usize).min(limits.max_bytes);
+            if capture_id.is_some() || !pcap::start(duration, max_bytes) {
+                return refuse(id, "a capture is already running");
+            }
+            info!(
+                "Peer: Capturing for {}s or up to {} bytes as capture {}",
+                duration.as_secs(),
+                max_bytes,
+                id
+            );
+            *capture_id = Some(id);
+            vec![]
+        }
+        Some(CaptureMessage::Stop { id }) if *capture_id == Some(id) => finish_capture(capture_id),
+        Some(CaptureMessage::Stop { id }) => {
+            debug!(
+                "Peer: Ignoring stop of capture {}, which is not running",
+                id
+            );
+            vec![]
+        }
+        _ => {
+            warn!("Peer: Discarding unexpected capture message");
+            vec![]
+        }
+    }
+}
 
+/// Ends the running capture.
+///
+/// # Returns
+///
+/// The chunks of the capture file, to send on the capture channel
+fn finish_capture(capture_id: &mut Option<u32>) -> Vec<CaptureMessage> {
+    let (Some(id), Some(file)) = (capture_id.take(), pcap::stop()) else {
+        return vec![];
+    };
     info!(
-        "Peer: Requested data channel '{}' with ID: {:?}",
-        CHANNEL, cid
+        "Peer: Capture {} finished with {} datagrams in {} bytes{}",
+        id,
+        file.packets,
+        file.data.len(),
+        if file.truncated { ", truncated" } else { "" }
     );
+    CaptureMessage::chunks(id, file.packets, file.truncated, &file.data)
+}
 
-    // // 2. DRIVE THE STATE MACHINE: The `poll_output` loop.
-    // // This replaces the direct call to `create_offer`.
-
-    let mut buf = vec![0; 2000];
-    let client = reqwest::Client::new();
-    let answer: SdpAnswer = client
-        .post("http://0.0.0.0:3000")
-        .body(serde_json::to_string(&offer)?)
-        .send()
-        .await?
-        .json()
-        .await?;
+/// Reads the crash reports to upload on a new crash channel.
+///
+/// Reports are only uploaded to the signaling server, not to another peer in
+/// mesh mode, and each is compressed and split into chunks.
+///
+/// # Arguments
+///
+/// * `config` - The peer configuration with the crash reporting settings
+///
+/// # Returns
+///
+/// The chunks of every pending report, oldest report first
+fn pending_crash_reports(config: &PeerConfig) -> Vec<CrashMessage> {
+    let settings = &config.crash;
+    if !settings.enabled || !settings.upload || config.mesh_listen || config.mesh_target.is_some() {
+        return vec![];
+    }
+    let reports = crash::pending(&settings.dir);
+    if !reports.is_empty() {
+        info!("Peer: Uploading {} crash reports", reports.len());
+    }
+    reports
+        .iter()
+        .flat_map(|report| CrashMessage::chunks(&report.name, &logbuf::compress(&report.data)))
+        .collect()
+}
 
-    info!("Answer SDP:\n{}", answer);
+/// Handles a message received on the crash channel, deleting the reports
+/// the server stored.
+///
+/// # Arguments
+///
+/// * `settings` - The crash reporting settings with the report directory
+/// * `data` - The raw bytes received on the channel
+fn handle_crash_data(settings: &CrashConfig, data: &[u8]) {
+    match CrashMessage::decode(data) {
+        Some(CrashMessage::Stored { name }) => {
+            if crash::remove(&settings.dir, &name) {
+                info!("Peer: The server stored crash report {}", name);
+            } else {
+                warn!("Peer: Failed to delete uploaded crash report {}", name);
+            }
+        }
+        _ => warn!("Peer: Discarding unexpected crash message"),
+    }
+}
 
-    rtc.sdp_api().accept_answer(pending, answer)?;
+/// Turns the end of a transfer into the event reported to the application.
+fn transfer_event(event: TransferEvent) -> PeerEvent {
+    match event {
+        TransferEvent::Received { id, name, path } => PeerEvent::FileReceived { id, name, path },
+        TransferEvent::Completed { id, name } => PeerEvent::TransferComplete { id, name },
+        TransferEvent::Failed { id, name, reason } => {
+            PeerEvent::TransferFailed { id, name, reason }
+        }
+    }
+}
 
-    info!("Peer: Answer accepted, waiting for ICE connection and channel to open...");
+/// A track of another client the server forwards to the peer.
+struct ForwardedTrackIn {
+    /// The ID of the publishing client
+    origin: u64,
+    kind: MediaKind,
+    /// When a keyframe was last requested on the track
+    last_keyframe_request: Option<Instant>,
+}
 
-    let mut channel_opened = false;
-    let mut last_message_time = Instant::now();
+impl ForwardedTrackIn {
+    /// Asks the publisher for a keyframe on a video track, at most once per
+    /// [`KEYFRAME_REQUEST_INTERVAL`]; the server routes the request on.
+    fn request_keyframe(&mut self, rtc: &mut Rtc, mid: Mid) {
+        if self.kind != MediaKind::Video
+            || self
+                .last_keyframe_request
+                .is_some_and(|last| last.elapsed() < KEYFRAME_REQUEST_INTERVAL)
+        {
+            return;
+        }
+        self.last_keyframe_request = Some(Instant::now());
+        let Some(mut writer) = rtc.writer(mid) else {
+            return;
+        };
+        if let Err(e) = writer.request_keyframe(None, KeyframeRequestKind::Pli) {
+            debug!("Peer: Keyframe request failed: {:?}", e);
+        }
+    }
+}
 
-    loop {
-        let timeout = match rtc.poll_output().expect("Unable to poll output") {
-            Output::Timeout(instant) => {
-                // info!("{:?}", instant);
-                instant
+/// Handles a message received on the forward channel, answering the
+/// server's offers of forwarded tracks.
+///
+/// # Arguments
+///
+/// * `rtc` - The RTC instance owning the forward channel
+/// * `forward_cid` - The ID of the forward data channel
+/// * `restarting` - Whether an ICE restart offer of the peer awaits its
+///   answer; accepting an offer meanwhile would invalidate it
+/// * `forwarded` - The forwarded tracks, by media ID
+/// * `data` - The raw bytes received on the channel
+fn handle_forward_data(
+    rtc: &mut Rtc,
+    forward_cid: ChannelId,
+    restarting: bool,
+    forwarded: &mut HashMap<Mid, ForwardedTrackIn>,
+    data: &[u8],
+) {
+    let Some(ForwardMessage::Offer { sdp, tracks }) = ForwardMessage::decode(data) else {
+        warn!("Peer: Discarding unexpected forward message");
+        return;
+    };
+    let reply = if restarting {
+        ForwardMessage::Rejected {
+            reason: "an ICE restart is in progress".into(),
+        }
+    } else {
+        let accepted = SdpOffer::from_sdp_string(&sdp)
+            .map_err(|e| e.to_string())
+            .and_then(|offer| rtc.sdp_api().accept_offer(offer).map_err(|e| e.to_string()));
+        match accepted {
+            Ok(answer) => {
+                for track in tracks {
+                    info!(
+                        "Peer: Receiving {} track {} of Client({})",
+                        track.kind, track.mid, track.origin
+                    );
+                    let kind = if track.kind == MediaKind::Audio.to_string() {
+                        MediaKind::Audio
+                    } else {
+                        MediaKind::Video
+                    };
+                    let forwarded_track = ForwardedTrackIn {
+                        origin: track.origin,
+                        kind,
+                        last_keyframe_request: None,
+                    };
+                    forwarded.insert(Mid::from(track.mid.as_str()), forwarded_track);
+                }
+                ForwardMessage::Answer {
+                    sdp: answer.to_sdp_string(),
+                }
             }
-            Output::Transmit(transmit) => {
-                socket.send_to(&transmit.contents, transmit.destination)?;
-                continue;
+            Err(e) => {
+                warn!("Peer: Rejecting the offer of forwarded tracks: {}", e);
+                ForwardMessage::Rejected { reason: e }
             }
-            Output::Event(event) => {
-                // Always log events, but filter out too verbose ones
-                match &event {
-                    Event::IceConnectionStateChange(_)
-                    | Event::ChannelOpen(_, _)
-                    | Event::ChannelData(_) => {
-                        info!("Event: {:?}", event);
-                    }
-                    _ => {
-                        // Still log other events at debug level
-                        info!("Event (other): {:?}", event);
-                    }
-                }
+        }
+    };
+    let Some(mut channel) = rtc.channel(forward_cid) else {
+        return;
+    };
+    if let Err(e) = channel.write(true, &reply.encode()) {
+        warn!(
+            "Peer: Failed to answer the offer of forwarded tracks: {:?}",
+            e
+        );
+    }
+}
 
-                // Track ICE connection state changes
-                if let Event::IceConnectionStateChange(state) = &event {
-                    info!("ICE Connection State: {:?}", state);
-                    match state {
-                        IceConnectionState::New => info!("ICE is starting..."),
-                        IceConnectionState::Checking => info!("ICE is checking candidates..."),
-                        IceConnectionState::Connected => {
-                            info!("ICE Connected! Data channel should open soon.")
+/// Handles a message received on the control channel.
+///
+/// # Arguments
+///
+/// * `rtc` - The RTC instance owning the control channel
+/// * `control_cid` - The ID of the control data channel
+/// * `config` - The peer configuration with the negotiation settings
+/// * `negotiated` - The negotiated version, common features and key
+///   agreement, updated
+/// * `limiter` - The publishing rates, updated with the remote's requests
+/// * `link` - The heartbeats sent, updated with the remote's pongs
+/// * `data` - The raw bytes received on the channel
+///
+/// # Returns
+///
+/// What the message calls for, or how the session ends if the remote
+/// refused or closed it
+fn handle_control_data(
+    rtc: &mut Rtc,
+    control_cid: ChannelId,
+    config: &PeerConfig,
+    negotiated: (&mut Negotiation, &mut FeatureSet, &mut KeyAgreement),
+    limiter: &mut RateLimiter,
+    link: &mut LinkMonitor,
+    data: &[u8],
+) -> Result<ControlAction, SessionEnd> {
+    let (protocol, features, agreement) = negotiated;
+    let Some(message) = ControlMessage::decode(data) else {
+        warn!("Peer: Discarding undecodable control message");
+        return Ok(ControlAction::None);
+    };
+    match &message {
+        ControlMessage::Incompatible { reason } => {
+            return Err(SessionEnd::Refused(RoverRtcError::Refused(format!(
+                "by the server: {}",
+                reason
+            ))));
+        }
+        ControlMessage::Goodbye { reason } => {
+            return Err(SessionEnd::Lost(RoverRtcError::ConnectionLost(format!(
+                "the server closed the session: {}",
+                reason
+            ))));
+        }
+        ControlMessage::Capabilities { features: remote } => {
+            *features = common_features(*remote, *protocol, &config.protocol);
+            info!("Peer: Negotiated features: {}", features);
+            if features.contains(Feature::KeyAgreement) {
+                if let Some(share) = agreement.start() {
+                    if let Some(mut channel) = rtc.channel(control_cid) {
+                        if let Err(e) = channel.write(true, &share.encode()) {
+                            warn!("Peer: Failed to send the key share: {:?}", e);
                         }
-                        IceConnectionState::Completed => info!("ICE Completed!"),
-                        IceConnectionState::Disconnected => info!("ICE Disconnected"),
                     }
                 }
-
-                // Handle channel opening
-                if let Event::ChannelOpen(channel_id, name) = &event {
-                    info!(
-                        "Peer: Channel opened - Name: '{}', ID: {:?}, Expected ID: {:?}",
-                        name, channel_id, cid
-                    );
-                    if channel_id == &cid {
-                        info!("   Channel ID matches expected ID!");
-                        channel_opened = true;
-                    } else {
-                        info!("WARNING: Channel ID does NOT match expected ID!");
-                    }
+            }
+            return Ok(ControlAction::None);
+        }
+        ControlMessage::KeyShare { public_key } => {
+            let local = rtc.direct_api().local_dtls_fingerprint().bytes.clone();
+            let remote = rtc
+                .direct_api()
+                .remote_dtls_fingerprint()
+                .map(|f| f.bytes.clone());
+            let result = remote
+                .ok_or(KeyAgreementError::NoFingerprint)
+                .and_then(|remote| agreement.finish(public_key, &local, &remote));
+            return match result {
+                Ok(keys) => {
+                    info!("Peer: Agreed on session keys {}", keys.id());
+                    Ok(ControlAction::Keys(Box::new(keys)))
                 }
-
-                // Handle incoming data
-                if let Event::ChannelData(msg) = &event {
-                    info!(
-                        "Received data on channel {:?}: {:?}",
-                        msg.id,
-                        String::from_utf8_lossy(&msg.data)
-                    );
+                Err(e) => {
+                    warn!("Peer: No session keys: {}", e);
+                    Ok(ControlAction::None)
                 }
-
-                // Abort if we disconnect
-                if event == Event::IceConnectionStateChange(IceConnectionState::Disconnected) {
-                    info!("Disconnecting due to ICE state change");
-                    break;
+            };
+        }
+        ControlMessage::RequestRate { topic, millihertz } => {
+            match *millihertz {
+                0 => info!("Peer: Remote withdrew its rate for '{}'", topic),
+                mhz => info!(
+                    "Peer: Remote requests '{}' at {} Hz",
+                    topic,
+                    rate::from_millihertz(mhz)
+                ),
+            }
+            limiter.set_requested(topic, *millihertz);
+            return Ok(ControlAction::None);
+        }
+        ControlMessage::Ping { sequence } => {
+            if let Some(mut channel) = rtc.channel(control_cid) {
+                let pong = ControlMessage::Pong {
+                    sequence: *sequence,
+                };
+                if let Err(e) = channel.write(true, &pong.encode()) {
+                    warn!("Peer: Failed to answer a heartbeat: {:?}", e);
                 }
+            }
+            return Ok(ControlAction::None);
+        }
+        ControlMessage::Pong { sequence } => {
+            if let Some(rtt) = link.pong(*sequence, Instant::now()) {
+                debug!("Peer: Heartbeat {} answered in {:?}", sequence, rtt);
+            }
+            return Ok(ControlAction::None);
+        }
+        ControlMessage::RestartIce => {
+            if !features.contains(Feature::RemoteRestart) {
+                warn!("Peer: Ignoring an ICE restart request without remote_restart");
+                return Ok(ControlAction::None);
+            }
+            return Ok(ControlAction::Restart);
+        }
+        ControlMessage::Hello { .. } => {}
+    }
 
-                continue;
+    match negotiate(&message, &config.protocol) {
+        Ok(negotiation) => {
+            match negotiation {
+                Negotiation::Agreed { version } => {
+                    info!("Peer: Remote speaks protocol v{}", version)
+                }
+                _ => warn!(
+                    "Peer: Remote speaks an incompatible protocol, falling back to plain data"
+                ),
             }
-        };
+            *protocol = negotiation;
+            Ok(ControlAction::None)
+        }
+        Err(mismatch) => {
+            warn!("Peer: {}", mismatch);
+            if let Some(mut channel) = rtc.channel(control_cid) {
+                let reply = ControlMessage::Incompatible {
+                    reason: mismatch.to_string(),
+                };
+                let _ = channel.write(true, &reply.encode());
+            }
+            Err(SessionEnd::Refused(RoverRtcError::Refused(
+                mismatch.to_string(),
+            )))
+        }
+    }
+}
 
-        // Send periodic timestamps to server if channel is open
-        if channel_opened && last_message_time.elapsed() > Duration::from_secs(2) {
-            if let Some(mut channel) = rtc.channel(cid) {
-                let payload: Payload = Payload::new("ciao".as_bytes());
-                info!(
-                    "Sending message {}\n Timestamp: {}",
-                    payload.data(),
-                    payload.timestamp()
+/// Handles a message received on the session channel.
+///
+/// Answers expiry warnings with a refresh carrying the current refresh token
+/// and bearer credential, and keeps the rotated token from the server's
+/// confirmation.
+///
+/// # Arguments
+///
+/// * `rtc` - The RTC instance owning the session channel
+/// * `session_cid` - The ID of the session data channel
+/// * `config` - The peer configuration with the bearer credential
+/// * `refresh_token` - The current refresh token, if the session expires
+/// * `data` - The raw bytes received on the channel
+fn handle_session_data(
+    rtc: &mut Rtc,
+    session_cid: ChannelId,
+    config: &PeerConfig,
+    refresh_token: &mut Option<String>,
+    data: &[u8],
+) {
+    match SessionMessage::decode(data) {
+        Some(SessionMessage::Expiring { expires_at }) => {
+            let Some(token) = refresh_token.clone() else {
+                warn!(
+                    "Peer: Session expires at {} but has no refresh token",
+                    expires_at
                 );
-                match channel.write(false, &Payload::serialize(payload)) {
-                    Ok(_) => {
-                        info!("Message sent");
-                        last_message_time = Instant::now();
-                        // Continue immediately to poll_output and flush the written data
-                        continue;
-                    }
-                    Err(e) => {
-                        info!("Peer: Failed to send message: {:?}", e);
-                    }
+                return;
+            };
+            info!("Peer: Session expires at {}, refreshing", expires_at);
+            let refresh = SessionMessage::Refresh {
+                refresh_token: token,
+                credential: config.credential(),
+            };
+            if let Some(mut channel) = rtc.channel(session_cid) {
+                if let Err(e) = channel.write(true, &refresh.encode()) {
+                    warn!("Peer: Failed to send session refresh: {:?}", e);
                 }
             }
         }
-
-        // Duration until timeout.
-        // Cap the duration at 100ms to ensure we process incoming packets frequently
-        let duration = (timeout - Instant::now())
-            .max(Duration::from_millis(1))
-            .min(Duration::from_millis(100));
-
-        // socket.set_read_timeout(Some(0)) is not ok
-        if duration.is_zero() {
-            // Drive time forwards in rtc straight away.
-            rtc.handle_input(Input::Timeout(Instant::now()))
-                .expect("Input should be handled.");
-            continue;
+        Some(SessionMessage::Refreshed {
+            expires_at,
+            refresh_token: token,
+        }) => {
+            info!("Peer: Session refreshed until {}", expires_at);
+            *refresh_token = Some(token);
         }
+        Some(SessionMessage::Rejected { reason }) => {
+            warn!("Peer: Session refresh rejected: {}", reason);
+        }
+        Some(other) => warn!("Peer: Unexpected session message {:?}", other),
+        None => warn!("Peer: Discarding undecodable session message"),
+    }
+}
 
-        socket
-            .set_read_timeout(Some(duration))
-            .expect("Timeout should be set.");
-
-        // Scale up buffer to receive an entire UDP packet.
-        buf.resize(2000, 0);
-
-        // Try to receive. Because we have a timeout on the socket,
-        // we will either receive a packet, or timeout.
-        let input = match socket.recv_from(&mut buf) {
-            Ok((n, source)) => {
-                // UDP data received.
-                buf.truncate(n);
-                Input::Receive(
-                    Instant::now(),
-                    Receive {
-                        proto: Protocol::Udp,
-                        source,
-                        destination: local_addr,
-                        contents: buf
-                            .as_slice()
-                            .try_into()
-                            .expect("The content of the buffer should be parsed."),
-                    },
-                )
+/// Logs changes to the network interfaces.
+fn log_network_events(events: &[NetworkEvent]) {
+    for event in events {
+        match event {
+            NetworkEvent::InterfaceUp { name } => info!("Peer: Interface {} up", name),
+            NetworkEvent::InterfaceDown { name } => info!("Peer: Interface {} down", name),
+            NetworkEvent::AddressAdded { interface, addr } => {
+                info!("Peer: Address {} added to {}", addr, interface)
             }
+            NetworkEvent::AddressRemoved { interface, addr } => {
+                info!("Peer: Address {} removed from {}", addr, interface)
+            }
+        }
+    }
+}
 
-            Err(e) => match e.kind() {
-                // Expected error for set_read_timeout().
-                // One for windows, one for the rest.
-                ErrorKind::WouldBlock | ErrorKind::TimedOut => Input::Timeout(Instant::now()),
+/// Determines the coordination identity of this peer.
+///
+/// Reads `ROVER_NODE_ID` and `ROVER_NODE_PRIORITY` from the environment. If no
+/// node ID is configured, one is derived from the current time and process ID.
+///
+/// # Returns
+///
+/// A tuple of `(node_id, priority)`
+fn node_identity() -> (u64, u32) {
+    let node_id = env::var("ROVER_NODE_ID")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0);
+            nanos ^ ((process::id() as u64) << 32)
+        });
+    let priority = env::var("ROVER_NODE_PRIORITY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
 
-                // Any other error is unexpected and should be propagated.
-                // We can't handle it here, so we pass it up to the caller.
-                _ => return Err(e.into()),
-            },
-        };
+    (node_id, priority)
+}
 
-        // Input is either a Timeout or Receive of data. Both drive the state forward.
-        rtc.handle_input(input)
-            .expect("The input should be handled correctly.");
+/// Logs membership and leadership changes of the convoy.
+fn log_coordination_events(events: &[CoordinationEvent]) {
+    for event in events {
+        match event {
+            CoordinationEvent::MemberJoined(id) => info!("Peer: Convoy member {} joined", id),
+            CoordinationEvent::MemberLost(id) => warn!("Peer: Convoy member {} lost", id),
+            CoordinationEvent::LeaderChanged(leader) => {
+                info!("Peer: Convoy leader is now {:?}", leader)
+            }
+        }
     }
-
-    Ok(())
 }
@@ -0,0 +1,539 @@
+//! Python bindings, built with the `python` feature
+//!
+//! Mission scientists script telemetry consumers and test scenarios against
+//! real connections with these classes, imported from the `rover_rtc`
+//! extension module (`maturin develop --features python`):
+//!
+//! ```python
+//! import rover_rtc
+//!
+//! peer = rover_rtc.Peer(signaling_url="http://10.0.0.1:3000", channels=["telemetry"])
+//! telemetry = peer.subscribe("telemetry")
+//! peer.start()
+//! while (sample := telemetry.recv(timeout=5.0)) is not None:
+//!     print(len(sample))
+//!
+//! admin = rover_rtc.AdminClient("http://10.0.0.1:3000", token)
+//! print(admin.clients())
+//! ```
+//!
+//! Blocking calls release the GIL, so other Python threads keep running.
+
+// The code generated by `#[pymethods]` converts every `PyErr` into itself
+#![allow(clippy::useless_conversion)]
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+use serde_json::{json, Value};
+
+use crate::admin::AdminClient;
+use crate::config::Config;
+use crate::model::audio::AudioFrame;
+use crate::model::logs::LogQuery;
+use crate::model::schedule::Persistence;
+use crate::peer::PeerEvent;
+use crate::rover::{RoverPeer, RoverRtc};
+
+fn runtime_error(e: impl std::fmt::Display) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+/// Converts a JSON value to the equivalent Python object.
+fn to_python(py: Python<'_>, value: &Value) -> PyResult<PyObject> {
+    let text = serde_json::to_string(value).map_err(runtime_error)?;
+    Ok(py
+        .import_bound("json")?
+        .call_method1("loads", (text,))?
+        .unbind())
+}
+
+/// Waits for the next item of a channel, without holding the GIL.
+///
+/// # Returns
+///
+/// The item, or `None` if none arrived within `timeout` (in seconds) or the
+/// sending side has gone away
+fn recv<T: Send>(py: Python<'_>, rx: &Mutex<Receiver<T>>, timeout: Option<f64>) -> Option<T> {
+    py.allow_threads(|| {
+        let rx = rx.lock().expect("receiver lock");
+        match timeout {
+            Some(secs) => rx.recv_timeout(Duration::from_secs_f64(secs.max(0.0))).ok(),
+            None => rx.recv().ok(),
+        }
+    })
+}
+
+/// Describes a peer event as JSON, e.g. `{"kind": "channel_open", "label": "test"}`.
+fn event_json(event: &PeerEvent) -> Value {
+    match event {
+        PeerEvent::Connected => json!({ "kind": "connected" }),
+        PeerEvent::ChannelOpen { label } => json!({ "kind": "channel_open", "label": label }),
+        PeerEvent::SetupComplete { breakdown } => {
+            json!({ "kind": "setup_complete", "setup": breakdown })
+        }
+        PeerEvent::Restarting => json!({ "kind": "restarting" }),
+        PeerEvent::Disconnected => json!({ "kind": "disconnected" }),
+        PeerEvent::Reconnecting { attempt, delay } => json!({
+            "kind": "reconnecting",
+            "attempt": attempt,
+            "delay_ms": delay.as_millis() as u64,
+        }),
+        PeerEvent::Reconnected { outage, attempts } => json!({
+            "kind": "reconnected",
+            "outage_ms": outage.as_millis() as u64,
+            "attempts": attempts,
+        }),
+        PeerEvent::KeyframeRequested => json!({ "kind": "keyframe_requested" }),
+        PeerEvent::Audio {
+            frame: AudioFrame::Opus(packet),
+        } => json!({ "kind": "audio", "codec": "opus", "data": packet }),
+        PeerEvent::Audio {
+            frame: AudioFrame::Pcm(samples),
+        } => json!({ "kind": "audio", "codec": "pcm", "samples": samples }),
+        PeerEvent::Media {
+            origin,
+            kind,
+            codec,
+            data,
+        } => json!({
+            "kind": "media",
+            "origin": origin,
+            "media": kind.to_string(),
+            "codec": codec.to_string(),
+            "data": data,
+        }),
+        PeerEvent::FileReceived { id, name, path } => json!({
+            "kind": "file_received",
+            "id": id,
+            "name": name,
+            "path": path,
+        }),
+        PeerEvent::TransferComplete { id, name } => json!({
+            "kind": "transfer_complete",
+            "id": id,
+            "name": name,
+        }),
+        PeerEvent::TransferFailed { id, name, reason } => json!({
+            "kind": "transfer_failed",
+            "id": id,
+            "name": name,
+            "reason": reason,
+        }),
+        PeerEvent::LinkFailover { link, reason } => json!({
+            "kind": "link_failover",
+            "link": link,
+            "reason": reason,
+        }),
+        PeerEvent::Announcement { id, data } => json!({
+            "kind": "announcement",
+            "id": id,
+            "data": data,
+        }),
+        PeerEvent::HealthChanged { state } => json!({
+            "kind": "health_changed",
+            "state": state,
+        }),
+        PeerEvent::WriteFailed { label, data, error } => json!({
+            "kind": "write_failed",
+            "label": label,
+            "data": data,
+            "error": error,
+        }),
+    }
+}
+
+/// A peer connecting to a signaling server, or directly to another peer in
+/// mesh mode.
+#[pyclass(name = "Peer", module = "rover_rtc")]
+struct PyPeer {
+    peer: RoverPeer,
+    events: Mutex<Receiver<Value>>,
+}
+
+#[pymethods]
+impl PyPeer {
+    /// Creates a peer from a configuration file (read like the binary's
+    /// `--config`, with the `ROVER_*` environment variables applied), with
+    /// the keyword arguments overriding its settings.
+    #[new]
+    #[pyo3(signature = (config=None, *, signaling_url=None, alias=None, auth_token=None, channels=None))]
+    fn new(
+        config: Option<&str>,
+        signaling_url: Option<String>,
+        alias: Option<String>,
+        auth_token: Option<String>,
+        channels: Option<Vec<String>>,
+    ) -> PyResult<Self> {
+        let config = Config::load(config.map(Path::new)).map_err(runtime_error)?;
+        let mut peer_config = config.peer_config();
+        if let Some(url) = signaling_url {
+            peer_config.signaling_url = url;
+        }
+        if alias.is_some() {
+            peer_config.alias = alias;
+        }
+        if auth_token.is_some() {
+            peer_config.auth_token = auth_token;
+        }
+        if let Some(channels) = channels {
+            peer_config.channels = channels;
+        }
+
+        let (tx, events) = mpsc::channel();
+        let peer = RoverRtc::builder()
+            .peer_config(peer_config)
+            .on_peer_event(move |event| {
+                let _ = tx.send(event_json(event));
+            })
+            .build_peer();
+        Ok(Self {
+            peer,
+            events: Mutex::new(events),
+        })
+    }
+
+    /// Starts the peer on its own thread.
+    fn start(&mut self) -> PyResult<()> {
+        self.peer.start().map_err(runtime_error)
+    }
+
+    /// Stops the peer and waits for it to disconnect.
+    fn stop(&mut self, py: Python<'_>) -> PyResult<()> {
+        py.allow_threads(|| self.peer.stop()).map_err(runtime_error)
+    }
+
+    /// Returns `True` while the peer's thread is running.
+    fn is_running(&self) -> bool {
+        self.peer.is_running()
+    }
+
+    /// Queues data to be sent on a channel; it is dropped if the channel is
+    /// not open.
+    fn send(&self, label: &str, data: &[u8]) {
+        self.peer.handle().send(label, data.to_vec());
+    }
+
+    /// Schedules data to be sent on a channel after `delay` seconds; it is
+    /// dropped if the connection is lost first, unless `persist` is `True`.
+    /// Returns the ID of the scheduled message.
+    #[pyo3(signature = (label, data, delay, *, persist=false))]
+    fn send_after(&self, label: &str, data: &[u8], delay: f64, persist: bool) -> PyResult<u64> {
+        let delay = Duration::try_from_secs_f64(delay)
+            .map_err(|_| PyValueError::new_err("invalid delay"))?;
+        let persistence = if persist {
+            Persistence::Reconnects
+        } else {
+            Persistence::Session
+        };
+        Ok(self
+            .peer
+            .handle()
+            .send_after(label, data.to_vec(), delay, persistence))
+    }
+
+    /// Cancels a scheduled message; returns `False` if it was already sent,
+    /// dropped or cancelled.
+    fn cancel_scheduled(&self, id: u64) -> bool {
+        self.peer.handle().cancel_scheduled(id)
+    }
+
+    /// Sends a file on the transfer channel, resuming it after
+    /// reconnections; returns the ID of the transfer.
+    fn send_file(&self, path: PathBuf) -> PyResult<String> {
+        self.peer.handle().send_file(path).map_err(runtime_error)
+    }
+
+    /// Returns the statistics of the current connection as a dict, or `None`
+    /// until they were first taken in the session.
+    fn stats(&self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        let Some(stats) = self.peer.handle().stats() else {
+            return Ok(None);
+        };
+        let value = serde_json::to_value(stats).map_err(runtime_error)?;
+        to_python(py, &value).map(Some)
+    }
+
+    /// Subscribes to the data received on a channel.
+    fn subscribe(&self, label: &str) -> PySubscription {
+        let mut source = self.peer.handle().subscribe(label);
+        let (tx, rx) = mpsc::channel();
+        // Ends when the peer goes away or the subscription is dropped
+        thread::spawn(move || {
+            while let Some(data) = source.blocking_recv() {
+                if tx.send(data).is_err() {
+                    break;
+                }
+            }
+        });
+        PySubscription {
+            label: label.to_string(),
+            rx: Mutex::new(rx),
+        }
+    }
+
+    /// Waits for the next event, a dict with a `kind` key, or returns `None`
+    /// after `timeout` seconds.
+    #[pyo3(signature = (timeout=None))]
+    fn next_event(&self, py: Python<'_>, timeout: Option<f64>) -> PyResult<Option<PyObject>> {
+        recv(py, &self.events, timeout)
+            .map(|event| to_python(py, &event))
+            .transpose()
+    }
+}
+
+/// Data received on one channel of a peer.
+#[pyclass(name = "Subscription", module = "rover_rtc")]
+struct PySubscription {
+    #[pyo3(get)]
+    label: String,
+    rx: Mutex<Receiver<Vec<u8>>>,
+}
+
+#[pymethods]
+impl PySubscription {
+    /// Waits for the next message as `bytes`, or returns `None` after
+    /// `timeout` seconds.
+    #[pyo3(signature = (timeout=None))]
+    fn recv(&self, py: Python<'_>, timeout: Option<f64>) -> Option<Py<PyBytes>> {
+        recv(py, &self.rx, timeout).map(|data| PyBytes::new_bound(py, &data).unbind())
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> Option<Py<PyBytes>> {
+        self.recv(py, None)
+    }
+}
+
+/// Client for the admin API of a running server.
+#[pyclass(name = "AdminClient", module = "rover_rtc")]
+struct PyAdminClient {
+    client: AdminClient,
+}
+
+#[pymethods]
+impl PyAdminClient {
+    #[new]
+    fn new(base_url: &str, token: &str) -> PyResult<Self> {
+        let client = AdminClient::new(base_url, token).map_err(runtime_error)?;
+        Ok(Self { client })
+    }
+
+    /// Lists the connected clients with their health and connection statistics.
+    fn clients(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.clients());
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Dumps the state of the server and every client, with their recent
+    /// significant events.
+    fn dump_state(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.dump_state());
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Returns a client's metric time series over `window`, e.g. `"15m"`.
+    #[pyo3(signature = (client, window="15m"))]
+    fn stats(&self, py: Python<'_>, client: &str, window: &str) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.stats(client, window));
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Returns a client's current connection statistics.
+    fn connection(&self, py: Python<'_>, client: &str) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.connection(client));
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Returns the time spent in each phase of a client's setup.
+    fn setup(&self, py: Python<'_>, client: &str) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.setup(client));
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Sends a text message to a client.
+    fn send_message(&self, py: Python<'_>, client: &str, message: &str) -> PyResult<()> {
+        py.allow_threads(|| self.client.send_message(client, message))
+            .map_err(runtime_error)
+    }
+
+    /// Closes a client's session.
+    fn disconnect(&self, py: Python<'_>, client: &str) -> PyResult<()> {
+        py.allow_threads(|| self.client.disconnect(client))
+            .map_err(runtime_error)
+    }
+
+    /// Asks a client's peer to restart ICE.
+    fn restart_ice(&self, py: Python<'_>, client: &str) -> PyResult<()> {
+        py.allow_threads(|| self.client.restart_ice(client))
+            .map_err(runtime_error)
+    }
+
+    /// Sends an announcement to every client of a room and returns who
+    /// acknowledged it.
+    #[pyo3(signature = (room, message, timeout_ms=None))]
+    fn broadcast(
+        &self,
+        py: Python<'_>,
+        room: &str,
+        message: &str,
+        timeout_ms: Option<u64>,
+    ) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.broadcast(room, message, timeout_ms));
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Fetches a client's recent log lines, at least as severe as `level`
+    /// (e.g. `"warn"`) and containing `contains`, over its connection.
+    #[pyo3(signature = (client, tail=200, contains=None, level=None, since_secs=None))]
+    fn logs(
+        &self,
+        py: Python<'_>,
+        client: &str,
+        tail: u32,
+        contains: Option<String>,
+        level: Option<&str>,
+        since_secs: Option<u32>,
+    ) -> PyResult<String> {
+        let level = level
+            .map(|level| serde_json::from_value(Value::from(level)))
+            .transpose()
+            .map_err(|_| PyValueError::new_err("invalid level"))?;
+        let query = LogQuery {
+            tail,
+            contains,
+            level,
+            since_secs,
+            compress: true,
+        };
+        py.allow_threads(|| self.client.logs(client, &query))
+            .map_err(runtime_error)
+    }
+
+    /// Starts a capture of a client's UDP traffic on its peer.
+    #[pyo3(signature = (client, duration_secs=30, max_bytes=8388608))]
+    fn start_capture(
+        &self,
+        py: Python<'_>,
+        client: &str,
+        duration_secs: u32,
+        max_bytes: u64,
+    ) -> PyResult<()> {
+        py.allow_threads(|| self.client.start_capture(client, duration_secs, max_bytes))
+            .map_err(runtime_error)
+    }
+
+    /// Returns the state of a client's last capture.
+    fn capture_status(&self, py: Python<'_>, client: &str) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.capture_status(client));
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Ends a client's running capture early.
+    fn stop_capture(&self, py: Python<'_>, client: &str) -> PyResult<()> {
+        py.allow_threads(|| self.client.stop_capture(client))
+            .map_err(runtime_error)
+    }
+
+    /// Downloads a client's finished capture as pcap bytes.
+    fn download_capture(&self, py: Python<'_>, client: &str) -> PyResult<Py<PyBytes>> {
+        let data = py
+            .allow_threads(|| self.client.download_capture(client))
+            .map_err(runtime_error)?;
+        Ok(PyBytes::new_bound(py, &data).unbind())
+    }
+
+    /// Issues a guest link for a room.
+    fn issue_guest_link(&self, py: Python<'_>, room: &str, ttl_secs: i64) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.issue_guest_link(room, ttl_secs));
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Revokes a guest link.
+    fn revoke_guest_link(&self, py: Python<'_>, id: &str) -> PyResult<()> {
+        py.allow_threads(|| self.client.revoke_guest_link(id))
+            .map_err(runtime_error)
+    }
+
+    /// Replays a recording on the server's file system into a room.
+    #[pyo3(signature = (path, room, speed=1.0))]
+    fn replay(&self, py: Python<'_>, path: &str, room: &str, speed: f64) -> PyResult<()> {
+        py.allow_threads(|| self.client.replay(path, room, speed))
+            .map_err(runtime_error)
+    }
+
+    /// Returns the event log verbosity per event kind.
+    fn event_log(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.event_log());
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Changes the event log verbosity, e.g. with `"channel_data=sample:100"`.
+    fn set_event_log(&self, py: Python<'_>, spec: &str) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.set_event_log(spec));
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Lists blocked source addresses.
+    fn blocklist(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let value = py.allow_threads(|| self.client.blocklist());
+        to_python(py, &value.map_err(runtime_error)?)
+    }
+
+    /// Blocks a source address, for `duration_secs` or indefinitely.
+    #[pyo3(signature = (ip, duration_secs=None, reason=None))]
+    fn block(
+        &self,
+        py: Python<'_>,
+        ip: &str,
+        duration_secs: Option<u64>,
+        reason: Option<&str>,
+    ) -> PyResult<()> {
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| PyValueError::new_err("invalid address"))?;
+        py.allow_threads(|| self.client.block(ip, duration_secs, reason))
+            .map_err(runtime_error)
+    }
+
+    /// Unblocks a source address.
+    fn unblock(&self, py: Python<'_>, ip: &str) -> PyResult<()> {
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| PyValueError::new_err("invalid address"))?;
+        py.allow_threads(|| self.client.unblock(ip))
+            .map_err(runtime_error)
+    }
+}
+
+/// Installs the crate's logger, configured with `RUST_LOG`.
+#[pyfunction]
+fn init_logging() {
+    crate::util::init_log();
+}
+
+/// The `rover_rtc` extension module.
+#[pymodule]
+fn rover_rtc(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyPeer>()?;
+    m.add_class::<PySubscription>()?;
+    m.add_class::<PyAdminClient>()?;
+    m.add_function(wrap_pyfunction!(init_logging, m)?)?;
+    Ok(())
+}
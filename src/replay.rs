@@ -0,0 +1,308 @@
+//! Offline replay of a recording through the local stack
+//!
+//! Starts a server and a peer on this machine like the self-test, opens the
+//! channels of a recording and sends its messages from the peer with their
+//! original timing, scaled by a speed factor. The server decodes, routes and
+//! evaluates its rules on them as if a rover sent them, and may record what it
+//! received. The report compares, per channel, the messages sent and received
+//! and the codec statistics of the peer, so a field recording can be analysed
+//! offline or used to reproduce a problem.
+//!
+//! Messages of the protocol's own channels (control, session, missions, ...)
+//! are skipped: replaying them would confuse the handshake of the new session.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+
+use crate::model::capture::CAPTURE_CHANNEL;
+use crate::model::control::CONTROL_CHANNEL;
+use crate::model::coordination::COORDINATION_CHANNEL;
+use crate::model::crash::CRASH_CHANNEL;
+use crate::model::forward::FORWARD_CHANNEL;
+use crate::model::logs::LOGS_CHANNEL;
+use crate::model::mission::MISSION_CHANNEL;
+use crate::model::recording::{read_recording, Direction, RecordedMessage, RecorderConfig};
+use crate::model::session::SESSION_CHANNEL;
+use crate::model::stats::{CodecStats, STATS_INTERVAL};
+use crate::model::transfer::TRANSFER_CHANNEL;
+use crate::peer::{PeerConfig, PeerEvent, TEST_CHANNEL};
+use crate::rover::{RoverPeer, RoverRtc};
+use crate::server::ServerEvent;
+
+/// Channels of the protocol itself, never replayed.
+const PROTOCOL_CHANNELS: [&str; 9] = [
+    CONTROL_CHANNEL,
+    SESSION_CHANNEL,
+    MISSION_CHANNEL,
+    COORDINATION_CHANNEL,
+    LOGS_CHANNEL,
+    CAPTURE_CHANNEL,
+    CRASH_CHANNEL,
+    TRANSFER_CHANNEL,
+    FORWARD_CHANNEL,
+];
+
+/// How long the server may stay silent before the replay stops waiting for
+/// the last messages.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Settings of a replay.
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Playback speed factor; `1.0` respects the original timing
+    pub speed: f64,
+    /// Replay only the messages recorded in this direction; all if `None`
+    pub direction: Option<Direction>,
+    /// File the server records the replayed messages to, if any
+    pub output: Option<PathBuf>,
+    /// How long connecting and opening the channels may take
+    pub timeout: Duration,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            direction: None,
+            output: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The outcome of the replay of one channel.
+#[derive(Debug, Clone)]
+pub struct ChannelReplay {
+    /// The label of the channel
+    pub label: String,
+    /// Messages the peer sent
+    pub sent: usize,
+    /// Messages the server received
+    pub received: usize,
+    /// Application bytes the peer sent
+    pub bytes: u64,
+    /// The peer's codec statistics of the channel, if it reported any
+    pub codec: Option<CodecStats>,
+}
+
+/// The outcome of a replay.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    /// One entry per replayed channel, by label
+    pub channels: Vec<ChannelReplay>,
+    /// Messages of protocol channels, or of the other direction, not replayed
+    pub skipped: usize,
+    /// How long sending the messages took
+    pub elapsed: Duration,
+}
+
+impl ReplayReport {
+    /// Returns `true` if the server received every message sent.
+    pub fn complete(&self) -> bool {
+        self.channels.iter().all(|c| c.received >= c.sent)
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<20} {:>8} {:>8} {:>10} {:<16} {:>6} {:>10} {:>10}",
+            "CHANNEL", "SENT", "RECV", "BYTES", "CODEC", "RATIO", "ENCODE_US", "DECODE_US"
+        )?;
+        for channel in &self.channels {
+            let codec = channel.codec.clone().unwrap_or_default();
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>8} {:>10} {:<16} {:>6} {:>10} {:>10}",
+                channel.label,
+                channel.sent,
+                channel.received,
+                channel.bytes,
+                codec.codec,
+                codec
+                    .compression_ratio
+                    .map_or("-".to_string(), |r| format!("{:.2}", r)),
+                codec.encode_us,
+                codec.decode_us
+            )?;
+        }
+        write!(
+            f,
+            "Replayed in {:.1}s, {} message(s) skipped{}",
+            self.elapsed.as_secs_f64(),
+            self.skipped,
+            if self.complete() {
+                ""
+            } else {
+                "; some messages were LOST"
+            }
+        )
+    }
+}
+
+/// Replays a recording through a local server and peer.
+///
+/// # Arguments
+///
+/// * `path` - The recording, as written by a [`Recorder`](crate::model::recording::Recorder)
+/// * `options` - The playback speed, direction and output
+///
+/// # Returns
+///
+/// The report of every replayed channel, or an error if the recording could
+/// not be read or the stack did not connect
+pub fn run(path: &Path, options: &ReplayOptions) -> anyhow::Result<ReplayReport> {
+    if options.speed <= 0.0 || !options.speed.is_finite() {
+        bail!("the speed must be a positive factor");
+    }
+    let recorded = read_recording(path)?;
+    let total = recorded.len();
+    let messages: Vec<_> = recorded
+        .into_iter()
+        .filter(|m| options.direction.is_none_or(|d| m.direction == d))
+        .filter(|m| !PROTOCOL_CHANNELS.contains(&m.channel.as_str()))
+        .collect();
+    let mut report = ReplayReport {
+        skipped: total - messages.len(),
+        ..ReplayReport::default()
+    };
+    let mut labels: Vec<String> = messages.iter().map(|m| m.channel.clone()).collect();
+    labels.sort();
+    labels.dedup();
+    if labels.is_empty() {
+        return Ok(report);
+    }
+
+    let received: Arc<Mutex<HashMap<String, usize>>> = Arc::default();
+    let (server_tx, server_rx) = mpsc::channel();
+    let counts = received.clone();
+    let mut server = RoverRtc::builder()
+        .http_addr("127.0.0.1:0")
+        .udp_host_local()
+        .server_recording(RecorderConfig {
+            enabled: options.output.is_some(),
+            path: options.output.clone().unwrap_or_default(),
+            ..RecorderConfig::default()
+        })
+        .on_server_event(move |event| {
+            if let ServerEvent::ChannelData { channel, .. } = event {
+                *counts
+                    .lock()
+                    .expect("counts lock")
+                    .entry(channel.clone())
+                    .or_default() += 1;
+                let _ = server_tx.send(());
+            }
+        })
+        .build_server();
+    server.start()?;
+    let http_addr = server
+        .http_addr()
+        .ok_or_else(|| anyhow!("server did not report its address"))?;
+
+    let (peer_tx, peer_rx) = mpsc::channel();
+    let mut peer = RoverRtc::builder()
+        .peer_config(PeerConfig {
+            // The built-in test messages would be counted with the replay
+            message_interval_secs: u32::MAX.into(),
+            channels: labels
+                .iter()
+                .filter(|label| *label != TEST_CHANNEL)
+                .cloned()
+                .collect(),
+            ..PeerConfig::default()
+        })
+        .signaling_url(format!("http://{}", http_addr))
+        .alias("replay")
+        .on_peer_event(move |event| {
+            if let PeerEvent::ChannelOpen { label } = event {
+                let _ = peer_tx.send(label.clone());
+            }
+        })
+        .build_peer();
+    peer.start()?;
+
+    let result = replay(&peer, &labels, &messages, options, &peer_rx, &server_rx);
+    // The peer publishes its statistics periodically
+    thread::sleep(STATS_INTERVAL);
+    let stats = peer.handle().stats();
+    peer.stop()?;
+    server.stop();
+    report.elapsed = result?;
+
+    let received = received.lock().expect("counts lock");
+    let mut sent: BTreeMap<&str, (usize, u64)> = BTreeMap::new();
+    for message in &messages {
+        let entry = sent.entry(&message.channel).or_default();
+        entry.0 += 1;
+        entry.1 += message.data.len() as u64;
+    }
+    report.channels = sent
+        .into_iter()
+        .map(|(label, (count, bytes))| ChannelReplay {
+            label: label.to_string(),
+            sent: count,
+            received: received.get(label).copied().unwrap_or_default(),
+            bytes,
+            codec: stats
+                .as_ref()
+                .and_then(|s| s.channels.iter().find(|c| c.label == label))
+                .map(|c| c.codec.clone()),
+        })
+        .collect();
+    Ok(report)
+}
+
+/// Waits for the channels, sends the messages on time and waits for the
+/// server to receive them.
+///
+/// # Returns
+///
+/// How long sending took
+fn replay(
+    peer: &RoverPeer,
+    labels: &[String],
+    messages: &[RecordedMessage],
+    options: &ReplayOptions,
+    opened: &mpsc::Receiver<String>,
+    received: &mpsc::Receiver<()>,
+) -> anyhow::Result<Duration> {
+    let mut waiting: Vec<&String> = labels.iter().collect();
+    let deadline = Instant::now() + options.timeout;
+    while !waiting.is_empty() {
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .ok_or_else(|| anyhow!("channels did not open: {:?}", waiting))?;
+        if let Ok(label) = opened.recv_timeout(remaining) {
+            waiting.retain(|l| **l != label);
+        }
+    }
+
+    let started = Instant::now();
+    let first = messages.first().map_or(0, |m| m.at);
+    for message in messages {
+        let offset = Duration::from_nanos(message.at.saturating_sub(first).max(0) as u64);
+        let due = started + offset.div_f64(options.speed);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        peer.handle().send(&message.channel, message.data.clone());
+    }
+    let elapsed = started.elapsed();
+
+    // Let the last messages arrive
+    let mut arrived = received.try_iter().count();
+    while arrived < messages.len() && received.recv_timeout(DRAIN_TIMEOUT).is_ok() {
+        arrived += 1;
+    }
+    Ok(elapsed)
+}
@@ -0,0 +1,385 @@
+//! ROS 2 topic bridge
+//!
+//! Carries ROS 2 topics over the peer's connection, so a ROS-based rover can
+//! be teleoperated without a DDS domain spanning the link. The bridge talks
+//! to a [rosbridge](https://github.com/RobotWebTools/rosbridge_suite) server
+//! on the rover or the base station, e.g. started with
+//! `ros2 launch rosbridge_server rosbridge_websocket_launch.xml`, over its
+//! JSON protocol: it subscribes to the outbound topics and sends every
+//! message as JSON on the topic's channel, and advertises the inbound topics
+//! and publishes every message the remote sends on their channels.
+//!
+//! Each topic has a channel of its own, configured under `[peer.ros]`:
+//!
+//! ```toml
+//! [peer.ros]
+//! enabled = true
+//! url = "ws://127.0.0.1:9090"
+//!
+//! [[peer.ros.topics]]
+//! topic = "/odom"
+//! type = "nav_msgs/msg/Odometry"
+//! channel = "odom"
+//! direction = "outbound"
+//! throttle_ms = 100
+//!
+//! [[peer.ros.topics]]
+//! topic = "/cmd_vel"
+//! type = "geometry_msgs/msg/Twist"
+//! channel = "cmd_vel"
+//! direction = "inbound"
+//! ```
+//!
+//! The other side maps the same channels in the opposite direction. Messages
+//! read while a channel is not open are dropped, as are messages the remote
+//! sends while rosbridge is unreachable; the bridge reconnects to rosbridge
+//! every [`RECONNECT_DELAY`] until it is stopped.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+use crate::{
+    error::RoverRtcError,
+    model::bus::EventTopic,
+    peer::{PeerEvent, PeerHandle},
+};
+
+/// Read timeout of the rosbridge connection, after which the bridge sends
+/// the messages the remote sent and checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Time between two attempts to connect to rosbridge.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Which way a topic is bridged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopicDirection {
+    /// Messages published on the local topic are sent on the channel
+    Outbound,
+    /// Messages received on the channel are published on the local topic
+    Inbound,
+}
+
+/// A topic bridged over a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopicMapping {
+    /// Name of the ROS topic, e.g. `/cmd_vel`
+    pub topic: String,
+    /// Message type, e.g. `geometry_msgs/msg/Twist`
+    #[serde(rename = "type")]
+    pub message_type: String,
+    /// Label of the data channel carrying the messages
+    pub channel: String,
+    /// Which way the messages go
+    pub direction: TopicDirection,
+    /// Minimum interval between two messages of an outbound topic, in
+    /// milliseconds, enforced by rosbridge; 0 forwards every message
+    #[serde(default)]
+    pub throttle_ms: u64,
+}
+
+/// ROS 2 bridge settings, the `[peer.ros]` configuration section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RosConfig {
+    /// Bridge the topics over the peer's connection
+    pub enabled: bool,
+    /// WebSocket URL of the rosbridge server
+    pub url: String,
+    /// The bridged topics
+    pub topics: Vec<TopicMapping>,
+}
+
+impl Default for RosConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: "ws://127.0.0.1:9090".to_string(),
+            topics: vec![],
+        }
+    }
+}
+
+impl RosConfig {
+    /// Checks that the URL and the mappings are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.url.starts_with("ws://") && !self.url.starts_with("wss://") {
+            return Err(format!("url '{}' must be a ws:// or wss:// URL", self.url));
+        }
+        let mut channels = HashSet::new();
+        for mapping in &self.topics {
+            if !mapping.topic.starts_with('/') {
+                return Err(format!("topics: '{}' must start with '/'", mapping.topic));
+            }
+            if mapping.message_type.is_empty() || mapping.channel.is_empty() {
+                return Err(format!(
+                    "topics: '{}' needs a type and a channel",
+                    mapping.topic
+                ));
+            }
+            if !channels.insert(mapping.channel.as_str()) {
+                return Err(format!(
+                    "topics: channel '{}' is mapped more than once",
+                    mapping.channel
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the labels of the channels the bridge needs, in mapping order.
+    pub fn channels(&self) -> impl Iterator<Item = &str> {
+        self.topics.iter().map(|mapping| mapping.channel.as_str())
+    }
+}
+
+/// Bridges the configured ROS topics with the peer's channels.
+///
+/// The bridge runs until dropped.
+#[derive(Debug)]
+pub struct RosBridge {
+    stop: Arc<AtomicBool>,
+    forwarders: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl RosBridge {
+    /// Starts bridging the topics with a peer.
+    ///
+    /// Must be called within a Tokio runtime, which forwards the messages
+    /// the remote sends. The peer must open the mapped channels, see
+    /// [`PeerConfig::ros`](crate::peer::PeerConfig::ros). Connecting to
+    /// rosbridge is left to the bridge's thread, which retries until the
+    /// server is up.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The bridge settings
+    /// * `handle` - The handle of the peer carrying the messages
+    ///
+    /// # Returns
+    ///
+    /// The bridge, or an error if its thread could not be spawned
+    pub fn start(config: &RosConfig, handle: &PeerHandle) -> Result<Self, RoverRtcError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let open = Arc::new(Mutex::new(HashSet::new()));
+        {
+            let open = open.clone();
+            let topics = [EventTopic::Lifecycle];
+            handle
+                .events()
+                .subscribe(&topics, move |event| match event {
+                    PeerEvent::ChannelOpen { label } => {
+                        open.lock()
+                            .expect("ROS channels lock")
+                            .insert(label.clone());
+                    }
+                    PeerEvent::Disconnected => open.lock().expect("ROS channels lock").clear(),
+                    _ => {}
+                });
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let forwarders = config
+            .topics
+            .iter()
+            .filter(|mapping| mapping.direction == TopicDirection::Inbound)
+            .map(|mapping| {
+                let mut messages = handle.subscribe(&mapping.channel);
+                let tx = tx.clone();
+                let topic = mapping.topic.clone();
+                tokio::spawn(async move {
+                    while let Some(message) = messages.recv().await {
+                        if tx.send((topic.clone(), message)).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        {
+            let config = config.clone();
+            let handle = handle.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("ros2".to_string())
+                .spawn(move || run(&config, &handle, &rx, &open, &stop))?;
+        }
+        Ok(Self { stop, forwarders })
+    }
+}
+
+impl Drop for RosBridge {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for forwarder in &self.forwarders {
+            forwarder.abort();
+        }
+    }
+}
+
+type RosSocket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// Connects to rosbridge and bridges the topics until stopped, reconnecting
+/// whenever the connection is lost.
+fn run(
+    config: &RosConfig,
+    handle: &PeerHandle,
+    inbound: &Receiver<(String, Vec<u8>)>,
+    open: &Mutex<HashSet<String>>,
+    stop: &AtomicBool,
+) {
+    let outbound: HashMap<&str, &str> = config
+        .topics
+        .iter()
+        .filter(|mapping| mapping.direction == TopicDirection::Outbound)
+        .map(|mapping| (mapping.topic.as_str(), mapping.channel.as_str()))
+        .collect();
+    let mut failures = 0u32;
+    while !stop.load(Ordering::Relaxed) {
+        match connect(config) {
+            Ok(mut socket) => {
+                info!(
+                    "ROS: Bridging {} topics via {}",
+                    config.topics.len(),
+                    config.url
+                );
+                failures = 0;
+                // Drop what the remote sent while rosbridge was unreachable
+                while inbound.try_recv().is_ok() {}
+                match bridge(&mut socket, handle, &outbound, inbound, open, stop) {
+                    Ok(()) => {
+                        let _ = socket.close(None);
+                        return;
+                    }
+                    Err(e) => warn!("ROS: Lost the connection to rosbridge: {}", e),
+                }
+            }
+            Err(e) => {
+                // Only the first failure in a row is worth a warning
+                if failures == 0 {
+                    warn!("ROS: Cannot reach rosbridge at {}: {}", config.url, e);
+                } else {
+                    debug!("ROS: Cannot reach rosbridge at {}: {}", config.url, e);
+                }
+                failures += 1;
+            }
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+/// Opens the connection to rosbridge and sets up the topics.
+fn connect(config: &RosConfig) -> Result<RosSocket, RoverRtcError> {
+    let (mut socket, _) = tungstenite::connect(config.url.as_str())?;
+    match socket.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(POLL_INTERVAL))?,
+        MaybeTlsStream::NativeTls(stream) => {
+            stream.get_ref().set_read_timeout(Some(POLL_INTERVAL))?
+        }
+        _ => {}
+    }
+    for mapping in &config.topics {
+        let op = match mapping.direction {
+            TopicDirection::Outbound => json!({
+                "op": "subscribe",
+                "id": format!("rover-rtc:{}", mapping.channel),
+                "topic": mapping.topic,
+                "type": mapping.message_type,
+                "throttle_rate": mapping.throttle_ms,
+                "queue_length": 1,
+            }),
+            TopicDirection::Inbound => json!({
+                "op": "advertise",
+                "id": format!("rover-rtc:{}", mapping.channel),
+                "topic": mapping.topic,
+                "type": mapping.message_type,
+            }),
+        };
+        socket.send(Message::text(op.to_string()))?;
+    }
+    Ok(socket)
+}
+
+/// Exchanges messages between rosbridge and the channels.
+///
+/// # Returns
+///
+/// `Ok(())` once stopped, or the error that ended the connection
+fn bridge(
+    socket: &mut RosSocket,
+    handle: &PeerHandle,
+    outbound: &HashMap<&str, &str>,
+    inbound: &Receiver<(String, Vec<u8>)>,
+    open: &Mutex<HashSet<String>>,
+    stop: &AtomicBool,
+) -> Result<(), RoverRtcError> {
+    while !stop.load(Ordering::Relaxed) {
+        // Without inbound topics the sender is gone, and nothing arrives
+        while let Ok((topic, message)) = inbound.try_recv() {
+            let msg = match serde_json::from_slice::<Value>(&message) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("ROS: Dropping an invalid message for {}: {}", topic, e);
+                    continue;
+                }
+            };
+            let op = json!({ "op": "publish", "topic": topic, "msg": msg });
+            socket.send(Message::text(op.to_string()))?;
+        }
+
+        let text = match socket.read() {
+            Ok(Message::Text(text)) => text,
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let Ok(op) = serde_json::from_str::<Value>(&text) else {
+            warn!("ROS: Invalid message from rosbridge");
+            continue;
+        };
+        match op["op"].as_str() {
+            Some("publish") => {
+                let Some(channel) = op["topic"].as_str().and_then(|t| outbound.get(t)) else {
+                    continue;
+                };
+                if open.lock().expect("ROS channels lock").contains(*channel) {
+                    handle.send(channel, op["msg"].to_string().into_bytes());
+                }
+            }
+            Some("status") => {
+                let message = op["msg"].as_str().unwrap_or_default();
+                match op["level"].as_str() {
+                    Some("error") => warn!("ROS: rosbridge: {}", message),
+                    _ => debug!("ROS: rosbridge: {}", message),
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,569 @@
+//! Library API for embedding the peer and the server
+//!
+//! [`RoverRtc::builder`] configures either side of a connection and builds a
+//! [`RoverServer`] or a [`RoverPeer`], which the embedding application starts,
+//! observes through callbacks and stops like any other component. Nothing in
+//! here initializes logging or reads the command line unless asked to.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    thread,
+};
+
+use anyhow::anyhow;
+use tracing::warn;
+
+use crate::auth::backend::AuthConfig;
+use crate::model::audio::AudioFrame;
+use crate::model::bonding::BondingConfig;
+use crate::model::bus::{EventBus, WebhookConfig};
+use crate::model::channel::ChannelOptions;
+use crate::model::client::{ClientId, ClientRemoval};
+use crate::model::control::ProtocolConfig;
+use crate::model::duplicate::DuplicateConfig;
+use crate::model::forward::ForwardConfig;
+use crate::model::provisioning::ProvisioningConfig;
+use crate::model::reconnect::ReconnectConfig;
+use crate::model::recording::RecorderConfig;
+use crate::model::routing::RoutingConfig;
+use crate::model::rules::Rule;
+use crate::model::session::SessionConfig;
+use crate::model::signaling::IceServer;
+use crate::model::stats::ConnectionStats;
+use crate::peer::{self, ConnectionConfig, PeerCallback, PeerConfig, PeerEvent, PeerHandle};
+use crate::server::{
+    self, ServerCallback, ServerConfig, ServerEvent, ServerHandle, ServerState, TlsConfig,
+};
+use crate::transfer::TransferConfig;
+use crate::util::{self, init_log, shutdown::Shutdown};
+
+/// Entry point of the library API.
+pub struct RoverRtc;
+
+impl RoverRtc {
+    /// Returns a builder with the default configuration.
+    pub fn builder() -> RoverRtcBuilder {
+        RoverRtcBuilder::default()
+    }
+}
+
+/// Builder for [`RoverServer`] and [`RoverPeer`].
+///
+/// Server-only and peer-only settings are ignored by the other side.
+#[derive(Default)]
+pub struct RoverRtcBuilder {
+    server: ServerConfig,
+    peer: PeerConfig,
+    logging: bool,
+    server_callbacks: Vec<ServerCallback>,
+    peer_callbacks: Vec<PeerCallback>,
+}
+
+impl RoverRtcBuilder {
+    /// Sets the address the server's HTTP signaling endpoint listens on.
+    pub fn http_addr(mut self, addr: impl Into<String>) -> Self {
+        self.server.http_addr = addr.into();
+        self
+    }
+
+    /// Sets the host address of the server's UDP socket.
+    ///
+    /// By default the first interface with internet access is used.
+    pub fn udp_host(mut self, host: IpAddr) -> Self {
+        self.server.udp_host = Some(host);
+        self
+    }
+
+    /// Sets the host address of the server's UDP socket to the primary IPv4
+    /// address of this machine, so peers on the same machine reach it.
+    pub fn udp_host_local(mut self) -> Self {
+        self.server.udp_host = Some(util::local_ipv4());
+        self
+    }
+
+    /// Serves the server's signaling over HTTPS with a PEM certificate chain
+    /// and private key.
+    pub fn tls(mut self, cert_file: impl Into<PathBuf>, key_file: impl Into<PathBuf>) -> Self {
+        self.server.tls = Some(TlsConfig {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+        });
+        self
+    }
+
+    /// Sets the backend authenticating the server's signaling requests.
+    pub fn auth(mut self, auth: AuthConfig) -> Self {
+        self.server.auth = auth;
+        self
+    }
+
+    /// Sets how long the server's sessions stay valid without a refresh.
+    pub fn session(mut self, session: SessionConfig) -> Self {
+        self.server.session = session;
+        self
+    }
+
+    /// Adds a data channel the server opens to every client.
+    pub fn server_channel(mut self, label: impl Into<String>, options: ChannelOptions) -> Self {
+        self.server.channels.insert(label.into(), options);
+        self
+    }
+
+    /// Sets whether the server forwards the clients' media tracks to the
+    /// other clients of their room.
+    pub fn forward(mut self, forward: ForwardConfig) -> Self {
+        self.server.forward = forward;
+        self
+    }
+
+    /// Sets the routes of the messages clients send to other clients.
+    pub fn routing(mut self, routing: RoutingConfig) -> Self {
+        self.server.routing = routing;
+        self
+    }
+
+    /// Adds a rule the server evaluates on the data clients send.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.server.rules.push(rule);
+        self
+    }
+
+    /// Sets where the server records its clients' data channel traffic.
+    pub fn server_recording(mut self, recording: RecorderConfig) -> Self {
+        self.server.recording = recording;
+        self
+    }
+
+    /// Sets the known peers the server prepares sessions for ahead of time.
+    pub fn provisioning(mut self, provisioning: ProvisioningConfig) -> Self {
+        self.server.provisioning = provisioning;
+        self
+    }
+
+    /// Sets the bearer token the server's admin API requires, instead of
+    /// reading it from [`ADMIN_TOKEN_ENV`](crate::server::ADMIN_TOKEN_ENV).
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.server.admin_token = Some(token.into());
+        self
+    }
+
+    /// Adds an endpoint the server posts its events to.
+    pub fn webhook(mut self, webhook: WebhookConfig) -> Self {
+        self.server.webhooks.push(webhook);
+        self
+    }
+
+    /// Sets the file transfer settings of both sides, e.g. where received
+    /// files are stored.
+    pub fn transfer(mut self, transfer: TransferConfig) -> Self {
+        self.server.transfer = transfer.clone();
+        self.peer.transfer = transfer;
+        self
+    }
+
+    /// Sets the protocol negotiation settings of both sides, e.g. the optional
+    /// features they decode.
+    pub fn protocol(mut self, protocol: ProtocolConfig) -> Self {
+        self.server.protocol = protocol.clone();
+        self.peer.protocol = protocol;
+        self
+    }
+
+    /// Replaces the whole peer configuration, e.g. one read with
+    /// [`Config::load`](crate::config::Config::load).
+    pub fn peer_config(mut self, config: PeerConfig) -> Self {
+        self.peer = config;
+        self
+    }
+
+    /// Sets the URL of the signaling server the peer connects to.
+    pub fn signaling_url(mut self, url: impl Into<String>) -> Self {
+        self.peer.signaling_url = url.into();
+        self
+    }
+
+    /// Sets the alias the peer announces to the server.
+    pub fn alias(mut self, alias: impl Into<String>) -> Self {
+        self.peer.alias = Some(alias.into());
+        self
+    }
+
+    /// Sets the bearer token the peer presents to the signaling server.
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.peer.auth_token = Some(token.into());
+        self
+    }
+
+    /// Trusts a PEM certificate, e.g. a private CA, when the peer verifies an
+    /// HTTPS or WSS signaling server.
+    pub fn ca_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.peer.ca_file = Some(path.into());
+        self
+    }
+
+    /// Adds a data channel for the peer to open.
+    pub fn channel(mut self, label: impl Into<String>) -> Self {
+        self.peer.channels.push(label.into());
+        self
+    }
+
+    /// Sets the delivery options of one of the peer's data channels.
+    pub fn channel_options(mut self, label: impl Into<String>, options: ChannelOptions) -> Self {
+        self.peer.channel_options.insert(label.into(), options);
+        self
+    }
+
+    /// Makes the peer connect directly to the peer listening under `alias`,
+    /// with the signaling server only brokering the offer and answer.
+    pub fn mesh_target(mut self, alias: impl Into<String>) -> Self {
+        self.peer.mesh_target = Some(alias.into());
+        self
+    }
+
+    /// Adds a connection the peer keeps besides the one to its signaling
+    /// server, driven through [`PeerHandle::connection`] with its ID.
+    pub fn connection(mut self, connection: ConnectionConfig) -> Self {
+        self.peer.connections.push(connection);
+        self
+    }
+
+    /// Fails the application traffic over to one of the further
+    /// connections while the main one is degraded.
+    pub fn bonding(mut self, bonding: BondingConfig) -> Self {
+        self.peer.bonding = bonding;
+        self
+    }
+
+    /// Sends the messages of some channels over every active link at once,
+    /// e.g. emergency stops.
+    pub fn duplicate(mut self, duplicate: DuplicateConfig) -> Self {
+        self.peer.duplicate = duplicate;
+        self
+    }
+
+    /// Makes the peer wait under its alias for another peer to connect
+    /// directly, instead of connecting to the signaling server.
+    pub fn mesh_listen(mut self) -> Self {
+        self.peer.mesh_listen = true;
+        self
+    }
+
+    /// Makes the peer signal its host candidates under `.local` names
+    /// answered over mDNS instead of its local addresses.
+    pub fn mdns_candidates(mut self) -> Self {
+        self.peer.mdns_candidates = true;
+        self
+    }
+
+    /// Adds a STUN or TURN server for the peer, e.g. a deployment's own TURN
+    /// relay used as a fallback when direct paths fail.
+    pub fn ice_server(mut self, server: IceServer) -> Self {
+        self.peer.ice_servers.push(server);
+        self
+    }
+
+    /// Sets how the peer reconnects after losing its connection, e.g.
+    /// disabled to make [`RoverPeer::wait`] return on the first loss.
+    pub fn reconnect(mut self, reconnect: ReconnectConfig) -> Self {
+        self.peer.reconnect = reconnect;
+        self
+    }
+
+    /// Installs the crate's default tracing subscriber when starting.
+    ///
+    /// Leave this off if the application configures tracing itself.
+    pub fn with_logging(mut self) -> Self {
+        self.logging = true;
+        self
+    }
+
+    /// Registers a callback for server events.
+    pub fn on_server_event(
+        mut self,
+        callback: impl Fn(&ServerEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.server_callbacks.push(Arc::new(callback));
+        self
+    }
+
+    /// Registers a callback for clients removed from the server, with why and
+    /// their final statistics, e.g. to release what a fleet registry or a
+    /// queue holds for them.
+    ///
+    /// It runs on the event loop thread for every
+    /// [`ServerEvent::ClientRemoved`] and should return quickly.
+    pub fn on_client_removed(
+        self,
+        callback: impl Fn(&ClientRemoval) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_server_event(move |event| {
+            if let ServerEvent::ClientRemoved(removal) = event {
+                callback(removal);
+            }
+        })
+    }
+
+    /// Registers a callback for peer events.
+    pub fn on_peer_event(mut self, callback: impl Fn(&PeerEvent) + Send + Sync + 'static) -> Self {
+        self.peer_callbacks.push(Arc::new(callback));
+        self
+    }
+
+    /// Builds a server; it is not started until [`RoverServer::start`].
+    pub fn build_server(self) -> RoverServer {
+        RoverServer {
+            config: self.server,
+            logging: self.logging,
+            callbacks: self.server_callbacks,
+            running: None,
+        }
+    }
+
+    /// Builds a peer; it is not started until [`RoverPeer::start`].
+    pub fn build_peer(self) -> RoverPeer {
+        let handle = PeerHandle::new();
+        // Subscriptions and callbacks may be registered before the peer starts
+        for connection in &self.peer.connections {
+            handle.add_connection(&connection.id, self.peer.is_backup(&connection.id));
+        }
+        for callback in self.peer_callbacks {
+            handle.on_event(move |event| callback(event));
+        }
+        RoverPeer {
+            config: self.peer,
+            logging: self.logging,
+            handle,
+            thread: None,
+        }
+    }
+}
+
+/// An embeddable signaling server.
+pub struct RoverServer {
+    config: ServerConfig,
+    logging: bool,
+    callbacks: Vec<ServerCallback>,
+    running: Option<ServerHandle>,
+}
+
+impl RoverServer {
+    /// Starts the server in background threads.
+    ///
+    /// # Returns
+    ///
+    /// An error if the server is already running or could not be started
+    pub fn start(&mut self) -> anyhow::Result<()> {
+        if self.running.is_some() {
+            return Err(anyhow!("server already running"));
+        }
+        if self.logging {
+            init_log();
+        }
+        self.running = Some(server::start(self.config.clone(), self.callbacks.clone())?);
+        Ok(())
+    }
+
+    /// Returns `true` while the server is running.
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Returns the address of the UDP socket, while running.
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.running.as_ref().map(ServerHandle::udp_addr)
+    }
+
+    /// Returns the address of the HTTP signaling endpoint, while running.
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.running.as_ref().map(ServerHandle::http_addr)
+    }
+
+    /// Returns the bus the server's events are published on, while running,
+    /// see [`ServerHandle::events`].
+    pub fn events(&self) -> Option<&EventBus<ServerEvent>> {
+        self.running.as_ref().map(ServerHandle::events)
+    }
+
+    /// Sends a text message to a connected client.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the server is not running
+    pub fn send_message(&self, id: ClientId, message: &str) -> bool {
+        self.running
+            .as_ref()
+            .is_some_and(|running| running.send_message(id, message))
+    }
+
+    /// Sends a frame of the operator's voice to a connected client, see
+    /// [`ServerHandle::send_audio`].
+    ///
+    /// # Returns
+    ///
+    /// `false` if the server is not running
+    pub fn send_audio(&self, id: ClientId, frame: AudioFrame) -> bool {
+        self.running
+            .as_ref()
+            .is_some_and(|running| running.send_audio(id, frame))
+    }
+
+    /// Sends a file to a connected client, see [`ServerHandle::send_file`].
+    ///
+    /// # Returns
+    ///
+    /// The ID of the transfer, or `None` if the server is not running
+    pub fn send_file(&self, id: ClientId, name: &str, data: Vec<u8>) -> Option<String> {
+        self.running
+            .as_ref()
+            .and_then(|running| running.send_file(id, name, data))
+    }
+
+    /// Declares the rate at which a receiver wants a client to publish a
+    /// topic, see [`ServerHandle::request_rate`].
+    ///
+    /// # Returns
+    ///
+    /// `false` if the server is not running
+    pub fn request_rate(&self, id: ClientId, receiver: &str, topic: &str, hz: Option<f64>) -> bool {
+        self.running
+            .as_ref()
+            .is_some_and(|running| running.request_rate(id, receiver, topic, hz))
+    }
+
+    /// Returns the connection statistics of a client, see
+    /// [`ServerHandle::client_stats`].
+    ///
+    /// # Returns
+    ///
+    /// The statistics, or `None` if the server is not running or has no
+    /// such client
+    pub fn client_stats(&self, id: ClientId) -> Option<ConnectionStats> {
+        self.running
+            .as_ref()
+            .and_then(|running| running.client_stats(id))
+    }
+
+    /// Returns the state of the server and its clients, see
+    /// [`ServerHandle::dump_state`].
+    ///
+    /// # Returns
+    ///
+    /// The state, or `None` if the server is not running or did not answer
+    pub fn dump_state(&self) -> Option<ServerState> {
+        self.running.as_ref().and_then(ServerHandle::dump_state)
+    }
+
+    /// Returns the handle shutting the server down from another thread or a
+    /// signal handler, while running.
+    pub fn shutdown(&self) -> Option<Shutdown> {
+        self.running.as_ref().map(ServerHandle::shutdown)
+    }
+
+    /// Closes the clients' connections, stops the server and waits for its
+    /// threads to finish.
+    ///
+    /// The server can be started again afterwards.
+    pub fn stop(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.stop();
+        }
+    }
+
+    /// Blocks until the server stops on its own.
+    pub fn wait(mut self) {
+        if let Some(running) = self.running.take() {
+            running.join();
+        }
+    }
+
+    /// Waits until the server stops, without blocking an async runtime, see
+    /// [`ServerHandle::stopped`].
+    pub async fn stopped(mut self) {
+        if let Some(running) = self.running.take() {
+            running.stopped().await;
+        }
+    }
+}
+
+/// An embeddable peer.
+pub struct RoverPeer {
+    config: PeerConfig,
+    logging: bool,
+    handle: PeerHandle,
+    thread: Option<thread::JoinHandle<Result<(), String>>>,
+}
+
+impl RoverPeer {
+    /// Returns the handle used to subscribe to channels, send data and register
+    /// more callbacks.
+    pub fn handle(&self) -> &PeerHandle {
+        &self.handle
+    }
+
+    /// Starts the peer on a dedicated thread with its own async runtime.
+    ///
+    /// # Returns
+    ///
+    /// An error if the peer is already running or the runtime could not be created
+    pub fn start(&mut self) -> anyhow::Result<()> {
+        if self.thread.is_some() {
+            return Err(anyhow!("peer already running"));
+        }
+        if self.logging {
+            init_log();
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let config = self.config.clone();
+        let handle = self.handle.clone();
+        self.thread = Some(thread::spawn(move || {
+            runtime
+                .block_on(peer::run(config, handle))
+                .map_err(|e| e.to_string())
+        }));
+        Ok(())
+    }
+
+    /// Returns `true` while the peer's thread is running.
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    /// Returns the handle stopping the peer from another thread or a signal
+    /// handler, e.g. `peer.shutdown().trigger_on_ctrl_c()`.
+    pub fn shutdown(&self) -> Shutdown {
+        self.handle.shutdown()
+    }
+
+    /// Stops the peer and waits for it to close its channels and disconnect.
+    ///
+    /// # Returns
+    ///
+    /// The error the peer stopped with, if any
+    pub fn stop(&mut self) -> anyhow::Result<()> {
+        self.handle.stop();
+        self.wait()
+    }
+
+    /// Blocks until the peer stops.
+    ///
+    /// # Returns
+    ///
+    /// The error the peer stopped with, if any
+    pub fn wait(&mut self) -> anyhow::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        match thread.join() {
+            Ok(result) => result.map_err(|e| anyhow!(e)),
+            Err(_) => {
+                warn!("Peer thread panicked");
+                Err(anyhow!("peer thread panicked"))
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Loopback self-test of the local stack
+//!
+//! Starts a server and a peer on this machine, connects them and exchanges a
+//! message in each direction, reporting the outcome of every stage. Meant as a
+//! quick sanity check when a rover boots, before it is sent on a mission: a
+//! failure names the layer that is broken without needing a remote server.
+
+use std::{
+    fmt,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::RngCore;
+
+use crate::auth::guest::hex_encode;
+use crate::model::client::ClientId;
+use crate::model::payload::Payload;
+use crate::peer::{PeerEvent, TEST_CHANNEL};
+use crate::rover::RoverRtc;
+use crate::server::ServerEvent;
+
+/// Interval at which subscriptions are polled while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The outcome of a single stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StageOutcome {
+    /// The stage succeeded
+    Passed,
+    /// The stage failed for the given reason
+    Failed(String),
+    /// The stage was not run because an earlier stage failed
+    Skipped,
+}
+
+/// The result of a single stage.
+#[derive(Debug, Clone)]
+pub struct StageResult {
+    /// The stage name, e.g. `ice`
+    pub stage: &'static str,
+    /// What happened
+    pub outcome: StageOutcome,
+    /// How long the stage took
+    pub elapsed: Duration,
+}
+
+/// The results of all stages, in order.
+#[derive(Debug, Clone, Default)]
+pub struct SelftestReport {
+    /// One result per stage
+    pub stages: Vec<StageResult>,
+}
+
+impl SelftestReport {
+    /// Returns `true` if every stage passed.
+    pub fn passed(&self) -> bool {
+        self.stages
+            .iter()
+            .all(|s| s.outcome == StageOutcome::Passed)
+    }
+}
+
+impl fmt::Display for SelftestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.stages {
+            match &result.outcome {
+                StageOutcome::Passed => writeln!(
+                    f,
+                    "PASS  {:<16} {:>8.1}ms",
+                    result.stage,
+                    result.elapsed.as_secs_f64() * 1000.0
+                )?,
+                StageOutcome::Failed(reason) => {
+                    writeln!(f, "FAIL  {:<16} {}", result.stage, reason)?
+                }
+                StageOutcome::Skipped => writeln!(f, "SKIP  {}", result.stage)?,
+            }
+        }
+        write!(
+            f,
+            "{}",
+            if self.passed() {
+                "Self-test passed"
+            } else {
+                "Self-test FAILED"
+            }
+        )
+    }
+}
+
+/// Runs the stages in order, skipping the rest after the first failure.
+struct Stages {
+    report: SelftestReport,
+    failed: bool,
+}
+
+impl Stages {
+    fn run<T>(
+        &mut self,
+        stage: &'static str,
+        body: impl FnOnce() -> Result<T, String>,
+    ) -> Option<T> {
+        if self.failed {
+            self.report.stages.push(StageResult {
+                stage,
+                outcome: StageOutcome::Skipped,
+                elapsed: Duration::ZERO,
+            });
+            return None;
+        }
+
+        let started = Instant::now();
+        let result = body();
+        let outcome = match &result {
+            Ok(_) => StageOutcome::Passed,
+            Err(reason) => {
+                self.failed = true;
+                StageOutcome::Failed(reason.clone())
+            }
+        };
+        self.report.stages.push(StageResult {
+            stage,
+            outcome,
+            elapsed: started.elapsed(),
+        });
+        result.ok()
+    }
+}
+
+/// Runs the self-test.
+///
+/// # Arguments
+///
+/// * `timeout` - How long each stage may take
+///
+/// # Returns
+///
+/// The result of every stage
+pub fn run(timeout: Duration) -> SelftestReport {
+    let mut stages = Stages {
+        report: SelftestReport::default(),
+        failed: false,
+    };
+
+    let (server_tx, server_rx) = mpsc::channel();
+    let mut server = RoverRtc::builder()
+        .http_addr("127.0.0.1:0")
+        .udp_host_local()
+        .on_server_event(move |event| {
+            let _ = server_tx.send(event.clone());
+        })
+        .build_server();
+
+    let http_addr = stages.run("server_start", || {
+        server.start().map_err(|e| e.to_string())?;
+        server
+            .http_addr()
+            .ok_or_else(|| "server did not report its address".to_string())
+    });
+
+    let (peer_tx, peer_rx) = mpsc::channel();
+    let mut peer = RoverRtc::builder()
+        .signaling_url(format!(
+            "http://{}",
+            http_addr.map(|a| a.to_string()).unwrap_or_default()
+        ))
+        .alias("selftest")
+        .on_peer_event(move |event| {
+            let _ = peer_tx.send(event.clone());
+        })
+        .build_peer();
+    let mut peer_rx_data = peer.handle().subscribe(TEST_CHANNEL);
+
+    let id = stages.run("signaling", || {
+        peer.start().map_err(|e| e.to_string())?;
+        wait_for(&server_rx, timeout, |event| match event {
+            ServerEvent::ClientConnected { id, .. } => Some(*id),
+            _ => None,
+        })
+        .ok_or_else(|| "server did not accept the peer's offer".to_string())
+    });
+
+    stages.run("ice", || {
+        wait_for(&peer_rx, timeout, |event| {
+            (*event == PeerEvent::Connected).then_some(())
+        })
+        .ok_or_else(|| "ICE did not connect".to_string())
+    });
+
+    stages.run("data_channel", || {
+        wait_for(&peer_rx, timeout, |event| match event {
+            PeerEvent::ChannelOpen { label } if label == TEST_CHANNEL => Some(()),
+            _ => None,
+        })
+        .ok_or_else(|| format!("channel '{}' did not open", TEST_CHANNEL))
+    });
+
+    stages.run("peer_to_server", || {
+        let sent = Payload::serialize(Payload::new(nonce().as_bytes()));
+        peer.handle().send(TEST_CHANNEL, sent.clone());
+        wait_for(&server_rx, timeout, |event| match event {
+            ServerEvent::ChannelData { data, .. } if *data == sent => Some(()),
+            _ => None,
+        })
+        .ok_or_else(|| "server did not receive the peer's message".to_string())
+    });
+
+    stages.run("server_to_peer", || {
+        let sent = nonce();
+        let id: ClientId = id.ok_or("no client ID")?;
+        if !server.send_message(id, &sent) {
+            return Err("server stopped".to_string());
+        }
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            match peer_rx_data.try_recv() {
+                Ok(data) if data == sent.as_bytes() => return Ok(()),
+                Ok(_) => {}
+                Err(_) => thread::sleep(POLL_INTERVAL),
+            }
+        }
+        Err("peer did not receive the server's message".to_string())
+    });
+
+    // Always shut down, whatever failed before
+    stages.failed = false;
+    stages.run("shutdown", || {
+        let result = peer.stop().map_err(|e| e.to_string());
+        server.stop();
+        result
+    });
+
+    stages.report
+}
+
+/// Waits for an event matching the filter, discarding the others.
+fn wait_for<E, T>(
+    events: &Receiver<E>,
+    timeout: Duration,
+    filter: impl Fn(&E) -> Option<T>,
+) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.checked_duration_since(Instant::now())?;
+        if let Some(found) = filter(&events.recv_timeout(remaining).ok()?) {
+            return Some(found);
+        }
+    }
+}
+
+/// Returns a random marker identifying a test message.
+fn nonce() -> String {
+    let mut bytes = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("selftest-{}", hex_encode(&bytes))
+}
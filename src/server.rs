@@ -2,419 +2,4876 @@
 //!
 //! This module implements a simple HTTP-based signaling server for WebRTC connections.
 //! It handles SDP offer/answer exchange and manages multiple WebRTC clients, relaying
-//! UDP packets between them and broadcasting periodic messages.
+//! UDP packets between them and routing application data from one client to others.
 
 use std::{
-    collections::HashMap,
-    io::ErrorKind,
-    net::{SocketAddr, UdpSocket},
-    sync::mpsc::{self, Receiver, SyncSender, TryRecvError},
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    io::{self, ErrorKind, Read},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError, TrySendError},
+        Arc, Mutex,
+    },
     thread,
     time::{Duration, Instant},
 };
 
-use rouille::{Request, Response, Server};
+use anyhow::Context;
+use base64::Engine;
+use chrono::Utc;
+use rand::RngCore;
+use rouille::{
+    input::json_input,
+    websocket::{self, Websocket},
+    Request, Response, Server,
+};
+use serde::{Deserialize, Serialize};
 use str0m::{
-    change::SdpOffer,
+    change::{SdpAnswer, SdpOffer},
+    format::Codec,
+    media::{MediaKind, MediaTime, Mid},
     net::{Protocol, Receive},
-    Candidate, Input, Rtc,
+    Candidate, IceCreds, Input, Rtc,
+};
+use tokio::sync::{
+    mpsc::{
+        error::{SendError, TryRecvError},
+        unbounded_channel, UnboundedReceiver, UnboundedSender,
+    },
+    Notify,
 };
 use tracing::{debug, info, warn};
 
-use crate::util::{init_log, select_host_address};
+use crate::auth::{
+    backend::{AuthBackend, AuthConfig},
+    bearer_token,
+    guest::{hex_encode, GuestAuthority, GuestError, MAX_TTL_SECS},
+    token_matches,
+    turn::TurnMinter,
+    Access,
+};
+use crate::config::NetworkConfig;
+use crate::crash::{self, CrashConfig};
+use crate::error::RoverRtcError;
+use crate::util::{
+    bind_udp_to, event_log, init_log, logbuf,
+    mdns::MdnsResolver,
+    netmon::{NetworkEvent, NetworkMonitor},
+    netsim::{self, NetsimConfig},
+    netwait::wait_for_host_address,
+    pcap::{self, PcapConfig},
+    shutdown::Shutdown,
+};
+
+use crate::model::announce::{BroadcastReport, PendingBroadcast, DEFAULT_ACK_TIMEOUT};
+use crate::model::audio::AudioFrame;
+use crate::model::blocklist::Blocklist;
+use crate::model::broker::{Broker, BrokerError, ANSWER_TIMEOUT, OFFER_POLL_TIMEOUT};
+use crate::model::bus::{BusEvent, EventBus, EventTopic, WebhookConfig};
+use crate::model::capture::MAX_CAPTURE_SIZE;
+use crate::model::channel::ChannelOptions;
+use crate::model::client::{Client, ClientId, ClientRemoval, ClientState, RemovalReason};
+use crate::model::control::{
+    common_features, negotiate, ControlMessage, Feature, Negotiation, ProtocolConfig,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::model::demux::{
+    classify, is_plausible, DemuxIndex, DiagnosticsLevel, PacketClass, UnmatchedDiagnostics,
+};
+use crate::model::events::{EventCategory, EventRing, RecordedEvent};
+use crate::model::failover::{
+    HealthStatus, ServerFailoverConfig, SessionDirectory, SessionRecord, FAILOVER_SECRET_ENV,
+    FAILOVER_STATE_PATH, HEALTH_PATH, RESUME_PARAM,
+};
+use crate::model::forward::ForwardConfig;
+use crate::model::geofence::{GeofenceConfig, GeofenceEvent, GeofenceMonitor};
+use crate::model::health::{
+    ConnectionHealth, HealthAction, HealthConfig, HealthPolicy, HealthState, ThresholdPolicy,
+};
+use crate::model::heartbeat::LinkMonitor;
+use crate::model::keys::{KeyAgreementError, SessionKeys};
+use crate::model::logs::LogQuery;
+use crate::model::outbound::SendQueueConfig;
+use crate::model::payload::{ENVELOPE_MARKER, ENVELOPE_VERSION};
+use crate::model::propagated::Propagated;
+use crate::model::provisioning::{
+    HeldPacket, HeldPackets, ProvisionedPeer, ProvisionedRtcs, ProvisioningConfig,
+};
+use crate::model::recording::{read_recording, Recorder, RecorderConfig, ReplaySession};
+use crate::model::registry::{is_valid_alias, ClientEntry, ClientRegistry};
+use crate::model::routing::RoutingConfig;
+use crate::model::rules::{Rule, RuleAction, RuleEngine, RuleFiring, RuleLogLevel};
+use crate::model::session::{SessionConfig, SessionLifetime, SessionMessage};
+use crate::model::setup::{SetupBreakdown, SetupPhase, SetupTimer};
+use crate::model::signaling::{
+    AnswerFormat, IceServer, MeshAnswer, RestartOffer, SignalingAnswer, SignalingMessage,
+    TrickleCandidate, ANSWER_FORMAT_PARAM, CONSOLE_PATH, MESH_ANSWERS_PATH, MESH_CONNECT_PATH,
+    MESH_OFFERS_PATH, RESTART_PATH, TRICKLE_PATH, WEBSOCKET_PATH,
+};
+use crate::model::stats::{
+    parse_window, ConnectionStats, StatsHistory, SAMPLE_INTERVAL, STATS_INTERVAL,
+};
+use crate::model::telemetry::Telemetry;
+use crate::model::transfer::OutgoingTransfer;
+use crate::model::transport::{Clock, SystemClock, Transport};
+use crate::transfer::{TransferConfig, TransferEvent};
+
+/// State shared between the event loop and the HTTP handlers.
+#[derive(Clone)]
+struct SharedState {
+    /// Authority issuing, verifying and revoking guest links
+    guests: Arc<Mutex<GuestAuthority>>,
+    /// Stats histories of all clients
+    stats: Arc<Mutex<HashMap<u64, StatsHistory>>>,
+    /// Source addresses whose packets are dropped
+    blocklist: Arc<Mutex<Blocklist>>,
+    /// Connected clients by ID and alias
+    registry: Arc<Mutex<ClientRegistry>>,
+    /// Setup time breakdowns of all clients
+    setup: Arc<Mutex<HashMap<u64, SetupBreakdown>>>,
+    /// The latest connection statistics of all clients
+    connections: Arc<Mutex<HashMap<u64, ConnectionStats>>>,
+    /// The health of all clients
+    health: Arc<Mutex<HashMap<u64, HealthState>>>,
+    /// The last packet capture requested from each client
+    captures: Arc<Mutex<HashMap<u64, CaptureStatus>>>,
+    /// Backend authenticating signaling requests and session refreshes
+    auth: Arc<dyn AuthBackend>,
+    /// The sessions of the connected clients, copied by the standby
+    sessions: Arc<Mutex<SessionDirectory>>,
+}
+
+/// A new session accepted by the signaling endpoint.
+struct NewSession {
+    /// The ID allocated to the session
+    id: ClientId,
+    /// The token identifying the session, returned to the peer
+    session_token: String,
+    /// The RTC instance created from the offer
+    rtc: Rtc,
+    /// The access granted to the session
+    access: Access,
+    /// The alias announced by the peer, if any
+    alias: Option<String>,
+    /// Timing of the setup phases so far
+    setup: SetupTimer,
+    /// Expiry and refresh token of the session
+    session: SessionLifetime,
+}
+
+/// An ICE restart offer received by the signaling endpoint.
+struct RestartRequest {
+    /// The token of the session to restart
+    session_token: String,
+    /// The peer's offer with new ICE credentials
+    offer: SdpOffer,
+    /// Receives the answer, or `None` if the session is unknown or the offer
+    /// was rejected
+    reply: mpsc::Sender<Option<SdpAnswer>>,
+}
+
+/// How long a signaling handler waits for the event loop to answer an ICE
+/// restart offer.
+const RESTART_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request for a client's recent log lines made through the admin API.
+struct LogRequest {
+    /// The client whose peer is asked
+    id: ClientId,
+    /// The filters selecting the lines
+    query: LogQuery,
+    /// Receives the log text, or why there is none
+    reply: mpsc::Sender<Result<String, String>>,
+}
+
+/// An announcement to every client of a room made through the admin API.
+struct BroadcastRequest {
+    /// The room the announcement goes to
+    room: String,
+    /// The announcement
+    data: Vec<u8>,
+    /// How long acknowledgments are awaited
+    timeout: Duration,
+    /// Receives the aggregate result once every client acknowledged or the
+    /// timeout passed
+    reply: mpsc::Sender<BroadcastReport>,
+}
+
+/// A command to one client made through the admin API.
+enum ClientCommand {
+    /// Close the client's session
+    Disconnect,
+    /// Ask the client's peer to restart ICE
+    RestartIce,
+}
+
+/// A [`ClientCommand`] with the sender receiving its outcome.
+struct ClientCommandRequest {
+    /// The client the command is for
+    id: ClientId,
+    command: ClientCommand,
+    /// Receives `Ok` once done, or why it could not be
+    reply: mpsc::Sender<Result<(), String>>,
+}
+
+/// Interval at which event webhook threads check whether the server stopped.
+const WEBHOOK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most geofence crossings waiting for the webhook; further ones are dropped.
+const GEOFENCE_WEBHOOK_QUEUE: usize = 64;
+
+/// How long a geofence webhook request may take.
+const GEOFENCE_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client command waits for the event loop.
+const COMMAND_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connected client as listed by the admin API.
+#[derive(Debug, Serialize)]
+struct ClientListing {
+    #[serde(flatten)]
+    entry: ClientEntry,
+    /// The client's health, see [`crate::model::health`]
+    state: Option<HealthState>,
+    /// The latest connection statistics, once sampled
+    connection: Option<ConnectionStats>,
+}
+
+/// Longest acknowledgment timeout of a broadcast made through the admin API;
+/// shorter than the admin client's timeout, so it gets the result.
+const MAX_BROADCAST_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// How long a broadcast handler waits for the event loop past the
+/// acknowledgment timeout.
+const BROADCAST_REPLY_MARGIN: Duration = Duration::from_secs(1);
+
+/// How long a state dump waits for the event loop.
+const STATE_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The state of a running server, as dumped for support tickets.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerState {
+    /// When the state was taken
+    pub time: chrono::DateTime<Utc>,
+    /// Version of the crate
+    pub version: &'static str,
+    /// Address of the UDP socket carrying WebRTC traffic
+    pub udp_addr: SocketAddr,
+    /// The event log verbosity per event kind
+    pub event_log: HashMap<&'static str, String>,
+    /// The most recent significant events of the server, oldest first
+    pub events: Vec<RecordedEvent>,
+    /// Number of older server events dropped from the history
+    pub events_dropped: u64,
+    /// The connected clients
+    pub clients: Vec<ClientState>,
+}
+
+/// A packet capture command made through the admin API.
+enum CaptureCommand {
+    /// Start a capture on the client's peer
+    Start {
+        id: ClientId,
+        duration_secs: u32,
+        max_bytes: u64,
+    },
+    /// End the client's running capture early
+    Stop { id: ClientId },
+}
+
+/// State of a client's last packet capture, as reported by the admin API.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum CaptureStatus {
+    /// The peer is recording
+    Running {
+        id: u32,
+        duration_secs: u32,
+        max_bytes: u64,
+        #[serde(skip)]
+        deadline: Instant,
+    },
+    /// The peer was asked to end the capture and send it
+    Stopping {
+        id: u32,
+        #[serde(skip)]
+        deadline: Instant,
+    },
+    /// The capture arrived
+    Finished {
+        id: u32,
+        packets: u64,
+        truncated: bool,
+        bytes: usize,
+        #[serde(skip)]
+        data: Arc<Vec<u8>>,
+    },
+    /// The peer refused the capture or never sent it
+    Failed { id: Option<u32>, reason: String },
+}
+
+/// Time a peer has past a capture's duration to send it.
+const CAPTURE_GRACE: Duration = Duration::from_secs(60);
+
+/// How long the admin API waits for a peer to answer a log request; shorter
+/// than the admin client's timeout, so it gets the reason.
+const LOG_REPLY_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// Upper bound on the default number of polling workers.
+const MAX_POLL_WORKERS: usize = 4;
+
+/// Minimum number of clients before polling is spread across workers.
+const PARALLEL_POLL_THRESHOLD: usize = 8;
+
+/// Interval between session expiry checks.
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Environment variable holding the bearer token required by the admin API.
+pub const ADMIN_TOKEN_ENV: &str = "ROVER_ADMIN_TOKEN";
+
+/// State shared with the signaling handlers.
+struct SignalingState {
+    /// The socket address of the UDP port for WebRTC traffic
+    addr: SocketAddr,
+    /// Channel sender for passing new sessions to the main loop
+    sessions: LoopSender<NewSession>,
+    /// Channel sender for trickled candidates, keyed by session token
+    candidates: LoopSender<(String, Candidate)>,
+    /// Channel sender for ICE restart offers
+    restarts: LoopSender<RestartRequest>,
+    /// STUN/TURN servers recommended to peers
+    ice_servers: Vec<IceServer>,
+    /// Mints short-lived credentials for the deployment's TURN server, if any
+    turn: Option<TurnMinter>,
+    /// Authority verifying guest tokens
+    guests: Arc<Mutex<GuestAuthority>>,
+    /// Backend authenticating requests without a guest token
+    auth: Arc<dyn AuthBackend>,
+    /// Session lifetime settings
+    session: SessionConfig,
+    /// Mailboxes for offers and answers between peers in mesh mode
+    broker: Broker,
+    /// Resolves the `.local` candidates of browsers, unless disabled
+    mdns: Option<MdnsResolver>,
+    /// Failover settings
+    failover: ServerFailoverConfig,
+    /// The sessions of the connected clients
+    directory: Arc<Mutex<SessionDirectory>>,
+    /// The primary's sessions peers may resume, on a standby
+    replicas: Arc<Mutex<SessionDirectory>>,
+    /// Sessions prepared for the provisioned peers
+    provisioned: ProvisionedRtcs,
+}
+
+/// State shared with the admin API handlers.
+struct AdminState {
+    /// The expected bearer token; the API is disabled if `None`
+    token: Option<String>,
+    /// Channel sender for starting replays in the main loop
+    replays: LoopSender<ReplaySession>,
+    /// Channel sender for messages to individual clients
+    messages: LoopSender<(ClientId, String)>,
+    /// Channel sender for log requests to individual clients
+    logs: LoopSender<LogRequest>,
+    /// Channel sender for announcements to rooms
+    broadcasts: LoopSender<BroadcastRequest>,
+    /// Channel sender for commands to individual clients
+    commands: LoopSender<ClientCommandRequest>,
+    /// Channel sender for packet capture commands
+    captures: LoopSender<CaptureCommand>,
+    /// Channel sender for state dump requests
+    states: LoopSender<mpsc::Sender<ServerState>>,
+    /// Defaults of file taps started through the API
+    pcap: PcapConfig,
+    /// Bus streaming the server's events
+    bus: EventBus<ServerEvent>,
+    /// State shared with the event loop
+    shared: SharedState,
+}
+
+/// Configuration of an embedded signaling server.
+///
+/// Usually loaded as the `[server]` section of a [`Config`](crate::config::Config).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the HTTP signaling server listens on
+    pub http_addr: String,
+    /// Serves signaling over HTTPS with this certificate if set
+    pub tls: Option<TlsConfig>,
+    /// Host address of the UDP socket; selected automatically if `None`
+    pub udp_host: Option<IpAddr>,
+    /// Port of the UDP socket; `0` picks a random port
+    pub udp_port: u16,
+    /// STUN/TURN servers recommended to peers
+    pub ice_servers: Vec<IceServer>,
+    /// Number of client polling workers; derived from the available
+    /// parallelism if `None`
+    pub poll_workers: Option<usize>,
+    /// Interval between client health checks, in seconds
+    pub health_check_secs: u64,
+    /// Thresholds telling degraded and dead clients
+    pub health: HealthConfig,
+    /// Work done per event loop iteration before timeouts are handled again
+    pub loop_budget: LoopBudget,
+    /// Authentication backend for signaling requests
+    pub auth: AuthConfig,
+    /// Session lifetime settings
+    pub session: SessionConfig,
+    /// Data channels the server opens to every client, by label, e.g. a
+    /// lossy telemetry channel for the freshest readings
+    pub channels: BTreeMap<String, ChannelOptions>,
+    /// Queues of messages waiting for congested channels
+    pub send_queue: SendQueueConfig,
+    /// Forwarding of the clients' media tracks to the other clients
+    pub forward: ForwardConfig,
+    /// Routes of the application data clients send to other clients
+    pub routing: RoutingConfig,
+    /// Files sent to and received from the clients
+    pub transfer: TransferConfig,
+    /// Rules acting on the data clients send
+    pub rules: Vec<Rule>,
+    /// Warm standby failover, as the primary or the standby
+    pub failover: ServerFailoverConfig,
+    /// Recording of the clients' data channel traffic
+    pub recording: RecorderConfig,
+    /// Known peers the server prepares sessions for ahead of time
+    pub provisioning: ProvisioningConfig,
+    /// Endpoints the server's events are posted to
+    pub webhooks: Vec<WebhookConfig>,
+    /// Bearer token required by the admin API; falls back to
+    /// [`ADMIN_TOKEN_ENV`], and is never read from configuration files
+    #[serde(skip)]
+    pub admin_token: Option<String>,
+    /// Interface selection settings, from the shared `[network]` section
+    #[serde(skip)]
+    pub network: NetworkConfig,
+    /// Protocol negotiation settings, from the shared `[protocol]` section
+    #[serde(skip)]
+    pub protocol: ProtocolConfig,
+    /// Crash reporting settings, from the shared `[crash]` section
+    #[serde(skip)]
+    pub crash: CrashConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            http_addr: "0.0.0.0:3000".into(),
+            tls: None,
+            udp_host: None,
+            udp_port: 0,
+            ice_servers: vec![],
+            poll_workers: None,
+            health_check_secs: 5,
+            health: HealthConfig::default(),
+            loop_budget: LoopBudget::default(),
+            auth: AuthConfig::default(),
+            session: SessionConfig::default(),
+            channels: BTreeMap::new(),
+            send_queue: SendQueueConfig::default(),
+            forward: ForwardConfig::default(),
+            routing: RoutingConfig::default(),
+            transfer: TransferConfig::default(),
+            rules: vec![],
+            failover: ServerFailoverConfig::default(),
+            recording: RecorderConfig::default(),
+            provisioning: ProvisioningConfig::default(),
+            webhooks: vec![],
+            admin_token: None,
+            network: NetworkConfig::default(),
+            protocol: ProtocolConfig::default(),
+            crash: CrashConfig::default(),
+        }
+    }
+}
+
+/// Work the event loop does per iteration, the `[server.loop_budget]`
+/// section.
+///
+/// Once either limit is reached the loop yields, then handles every client's
+/// timeouts before doing more, so a client flooding the socket or producing
+/// endless output cannot starve the others' retransmissions and keepalives.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoopBudget {
+    /// Datagrams read from the socket per iteration
+    pub max_packets: usize,
+    /// Outputs polled from a single client per iteration
+    pub max_polls: usize,
+}
+
+impl Default for LoopBudget {
+    fn default() -> Self {
+        Self {
+            max_packets: 64,
+            max_polls: 256,
+        }
+    }
+}
+
+impl LoopBudget {
+    /// Checks that the loop can make progress.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_packets == 0 || self.max_polls == 0 {
+            return Err("max_packets and max_polls must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// Certificate and key of the HTTPS signaling endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain presented to peers
+    pub cert_file: PathBuf,
+    /// PEM file with the certificate's private key
+    pub key_file: PathBuf,
+}
+
+impl TlsConfig {
+    /// Reads the certificate chain and private key.
+    ///
+    /// # Returns
+    ///
+    /// The PEM-encoded certificate chain and key, or an error naming the
+    /// unreadable file
+    pub fn load(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let cert = fs::read(&self.cert_file)
+            .with_context(|| format!("reading {}", self.cert_file.display()))?;
+        let key = fs::read(&self.key_file)
+            .with_context(|| format!("reading {}", self.key_file.display()))?;
+        Ok((cert, key))
+    }
+}
+
+/// Events reported to embedding applications.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// A client completed signaling and joined the event loop
+    ClientConnected {
+        id: ClientId,
+        alias: Option<String>,
+        /// The identity established by authentication, if any
+        subject: Option<String>,
+    },
+    /// A client disconnected and was removed
+    ClientDisconnected { id: ClientId },
+    /// A client was removed, right after [`ServerEvent::ClientDisconnected`],
+    /// with why and its final statistics
+    ClientRemoved(Box<ClientRemoval>),
+    /// A health check moved a client to another state; a dead client is
+    /// evicted, see [`crate::model::health`]
+    HealthChanged { id: ClientId, state: HealthState },
+    /// The server accepted a client's ICE restart offer
+    IceRestarted { id: ClientId },
+    /// The server and a client agreed on the application keys of their
+    /// session, see [`crate::model::keys`]
+    KeysAgreed {
+        id: ClientId,
+        keys: Arc<SessionKeys>,
+    },
+    /// A client sent application data
+    ChannelData {
+        id: ClientId,
+        channel: String,
+        data: Vec<u8>,
+    },
+    /// A client sent a whole media frame on one of its tracks
+    MediaData {
+        id: ClientId,
+        /// The media ID of the track
+        mid: Mid,
+        kind: MediaKind,
+        /// The codec of the frame, e.g. H.264 as an Annex B byte stream
+        codec: Codec,
+        /// The RTP timestamp of the frame
+        time: MediaTime,
+        /// Whether no packet was lost since the previous frame; otherwise a
+        /// keyframe has been requested
+        contiguous: bool,
+        data: Vec<u8>,
+    },
+    /// A message to a client was given up under the
+    /// [`WriteRetry`](crate::model::channel::WriteRetry) policy of its channel
+    WriteFailed {
+        id: ClientId,
+        channel: String,
+        data: Vec<u8>,
+        /// The error of the last write
+        error: String,
+    },
+    /// A file from a client arrived on its transfer channel and matches its
+    /// digest
+    FileReceived {
+        id: ClientId,
+        /// The ID of the transfer, the hex SHA-256 digest of the file
+        transfer: String,
+        /// The name the peer sent it under
+        name: String,
+        /// Where it was stored
+        path: PathBuf,
+    },
+    /// A client confirmed a file sent with [`ServerHandle::send_file`]
+    TransferComplete {
+        id: ClientId,
+        transfer: String,
+        name: String,
+    },
+    /// A transfer to or from a client was refused or its file did not match
+    /// its digest
+    TransferFailed {
+        id: ClientId,
+        transfer: String,
+        name: String,
+        reason: String,
+    },
+}
+
+/// Callback invoked from the event loop for every [`ServerEvent`].
+///
+/// Callbacks run on the event loop thread and should return quickly.
+pub type ServerCallback = Arc<dyn Fn(&ServerEvent) + Send + Sync>;
+
+impl BusEvent for ServerEvent {
+    fn topic(&self) -> EventTopic {
+        match self {
+            ServerEvent::ClientConnected { .. }
+            | ServerEvent::ClientDisconnected { .. }
+            | ServerEvent::ClientRemoved(_)
+            | ServerEvent::KeysAgreed { .. } => EventTopic::Lifecycle,
+            ServerEvent::HealthChanged { .. } => EventTopic::Health,
+            ServerEvent::IceRestarted { .. } => EventTopic::Handover,
+            ServerEvent::ChannelData { .. } | ServerEvent::WriteFailed { .. } => {
+                EventTopic::ChannelData
+            }
+            ServerEvent::MediaData { .. } => EventTopic::Media,
+            ServerEvent::FileReceived { .. }
+            | ServerEvent::TransferComplete { .. }
+            | ServerEvent::TransferFailed { .. } => EventTopic::Transfer,
+        }
+    }
+}
+
+impl ServerEvent {
+    /// Returns the event as JSON for webhooks and the admin event stream,
+    /// e.g. `{"event": "health_changed", "id": 7, "state": "degraded"}`.
+    ///
+    /// Application data is included in base64, media frames by their size
+    /// only, and session keys not at all.
+    pub fn to_json(&self) -> serde_json::Value {
+        let base64 = |data: &[u8]| base64::engine::general_purpose::STANDARD.encode(data);
+        match self {
+            ServerEvent::ClientConnected { id, alias, subject } => serde_json::json!({
+                "event": "client_connected", "id": **id, "alias": alias, "subject": subject,
+            }),
+            ServerEvent::ClientDisconnected { id } => {
+                serde_json::json!({ "event": "client_disconnected", "id": **id })
+            }
+            ServerEvent::ClientRemoved(removal) => serde_json::json!({
+                "event": "client_removed",
+                "id": *removal.id,
+                "alias": removal.alias,
+                "subject": removal.subject,
+                "room": removal.room,
+                "reason": removal.reason.to_string(),
+                "stats": removal.stats,
+            }),
+            ServerEvent::HealthChanged { id, state } => {
+                serde_json::json!({ "event": "health_changed", "id": **id, "state": state })
+            }
+            ServerEvent::IceRestarted { id } => {
+                serde_json::json!({ "event": "ice_restarted", "id": **id })
+            }
+            ServerEvent::KeysAgreed { id, keys } => serde_json::json!({
+                "event": "keys_agreed", "id": **id, "keys": keys.id().to_string(),
+            }),
+            ServerEvent::ChannelData { id, channel, data } => serde_json::json!({
+                "event": "channel_data", "id": **id, "channel": channel, "data": base64(data),
+            }),
+            ServerEvent::MediaData {
+                id,
+                mid,
+                kind,
+                codec,
+                time,
+                contiguous,
+                data,
+            } => serde_json::json!({
+                "event": "media_data",
+                "id": **id,
+                "mid": mid.to_string(),
+                "kind": kind.to_string(),
+                "codec": codec.to_string(),
+                "time": time.numer(),
+                "contiguous": contiguous,
+                "bytes": data.len(),
+            }),
+            ServerEvent::WriteFailed {
+                id,
+                channel,
+                data,
+                error,
+            } => serde_json::json!({
+                "event": "write_failed",
+                "id": **id,
+                "channel": channel,
+                "data": base64(data),
+                "error": error,
+            }),
+            ServerEvent::FileReceived {
+                id,
+                transfer,
+                name,
+                path,
+            } => serde_json::json!({
+                "event": "file_received",
+                "id": **id,
+                "transfer": transfer,
+                "name": name,
+                "path": path,
+            }),
+            ServerEvent::TransferComplete { id, transfer, name } => serde_json::json!({
+                "event": "transfer_complete", "id": **id, "transfer": transfer, "name": name,
+            }),
+            ServerEvent::TransferFailed {
+                id,
+                transfer,
+                name,
+                reason,
+            } => serde_json::json!({
+                "event": "transfer_failed",
+                "id": **id,
+                "transfer": transfer,
+                "name": name,
+                "reason": reason,
+            }),
+        }
+    }
+}
 
-use crate::model::client::Client;
+/// A rate a receiver wants a client to publish a topic at, see
+/// [`RateDemand::request`](crate::model::rate::RateDemand::request).
+struct RateRequest {
+    id: ClientId,
+    receiver: String,
+    topic: String,
+    hz: Option<f64>,
+}
 
-/// Tracks connection health for each client
-#[derive(Debug)]
-struct ConnectionHealth {
-    last_activity: Instant,
-    consecutive_failures: u32,
-    ice_restart_attempts: u32,
+/// Channel sender that wakes the event loop for every input it passes.
+///
+/// Sending never blocks, so the HTTP handler threads can drive the loop
+/// without an async runtime of their own.
+struct LoopSender<T> {
+    tx: UnboundedSender<T>,
+    wake: Arc<Notify>,
 }
 
-impl ConnectionHealth {
-    fn new() -> Self {
+impl<T> Clone for LoopSender<T> {
+    fn clone(&self) -> Self {
         Self {
-            last_activity: Instant::now(),
-            consecutive_failures: 0,
-            ice_restart_attempts: 0,
+            tx: self.tx.clone(),
+            wake: self.wake.clone(),
+        }
+    }
+}
+
+impl<T> LoopSender<T> {
+    /// Queues an input for the event loop and wakes it.
+    ///
+    /// # Returns
+    ///
+    /// The input back, if the event loop has stopped
+    fn send(&self, input: T) -> Result<(), SendError<T>> {
+        self.tx.send(input)?;
+        self.wake.notify_one();
+        Ok(())
+    }
+}
+
+/// Creates a channel into the event loop, woken through `wake`.
+fn loop_channel<T>(wake: &Arc<Notify>) -> (LoopSender<T>, UnboundedReceiver<T>) {
+    let (tx, rx) = unbounded_channel();
+    let sender = LoopSender {
+        tx,
+        wake: wake.clone(),
+    };
+    (sender, rx)
+}
+
+/// Takes the inputs queued in a channel without waiting for more.
+fn drain<T>(rx: &mut UnboundedReceiver<T>) -> impl Iterator<Item = T> + '_ {
+    std::iter::from_fn(move || rx.try_recv().ok())
+}
+
+/// Receivers through which the HTTP handlers drive the event loop.
+struct LoopInputs {
+    /// New sessions from the signaling endpoint
+    sessions: UnboundedReceiver<NewSession>,
+    /// Replays started through the admin API
+    replays: UnboundedReceiver<ReplaySession>,
+    /// Messages to individual clients sent through the admin API
+    messages: UnboundedReceiver<(ClientId, String)>,
+    /// Operator voice sent through the handle
+    audio: UnboundedReceiver<(ClientId, AudioFrame)>,
+    /// Files sent to individual clients through the handle
+    files: UnboundedReceiver<(ClientId, OutgoingTransfer)>,
+    /// Publishing rates requested through the handle
+    rates: UnboundedReceiver<RateRequest>,
+    /// Candidates trickled by peers, keyed by session token
+    candidates: UnboundedReceiver<(String, Candidate)>,
+    /// ICE restart offers from peers whose network changed
+    restarts: UnboundedReceiver<RestartRequest>,
+    /// Log requests made through the admin API
+    logs: UnboundedReceiver<LogRequest>,
+    /// Announcements to rooms made through the admin API
+    broadcasts: UnboundedReceiver<BroadcastRequest>,
+    /// Commands to individual clients made through the admin API
+    commands: UnboundedReceiver<ClientCommandRequest>,
+    /// Packet capture commands made through the admin API
+    captures: UnboundedReceiver<CaptureCommand>,
+    /// State dump requests, each with the sender receiving the state
+    states: UnboundedReceiver<mpsc::Sender<ServerState>>,
+    /// Notified whenever one of the senders queues an input
+    wake: Arc<Notify>,
+}
+
+/// A running signaling server.
+///
+/// Dropping the handle leaves the server running; call [`ServerHandle::stop`]
+/// to shut it down, or trigger its [`Shutdown`] from elsewhere.
+pub struct ServerHandle {
+    udp_addr: SocketAddr,
+    http_addr: SocketAddr,
+    messages: LoopSender<(ClientId, String)>,
+    audio: LoopSender<(ClientId, AudioFrame)>,
+    files: LoopSender<(ClientId, OutgoingTransfer)>,
+    rates: LoopSender<RateRequest>,
+    states: LoopSender<mpsc::Sender<ServerState>>,
+    connections: Arc<Mutex<HashMap<u64, ConnectionStats>>>,
+    bus: EventBus<ServerEvent>,
+    shutdown: Shutdown,
+    http_stop: mpsc::Sender<()>,
+    http_thread: thread::JoinHandle<()>,
+    loop_thread: thread::JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Returns the address of the UDP socket carrying WebRTC traffic.
+    pub fn udp_addr(&self) -> SocketAddr {
+        self.udp_addr
+    }
+
+    /// Returns the address the HTTP signaling server listens on.
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http_addr
+    }
+
+    /// Returns the bus the server's events are published on, to subscribe
+    /// to some of their topics while the server runs, e.g. from a bridge.
+    pub fn events(&self) -> &EventBus<ServerEvent> {
+        &self.bus
+    }
+
+    /// Sends a text message to a client over its general-purpose data channel.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the event loop has stopped
+    pub fn send_message(&self, id: ClientId, message: &str) -> bool {
+        self.messages.send((id, message.to_string())).is_ok()
+    }
+
+    /// Sends a frame of the operator's voice to a client's audio track.
+    ///
+    /// Frames for a client without an audio track are dropped.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the event loop has stopped
+    pub fn send_audio(&self, id: ClientId, frame: AudioFrame) -> bool {
+        self.audio.send((id, frame)).is_ok()
+    }
+
+    /// Sends a file to a client on its transfer channel.
+    ///
+    /// The end of the transfer is reported as [`ServerEvent::TransferComplete`]
+    /// or [`ServerEvent::TransferFailed`]. Files for a departed client are
+    /// dropped; sending one again to its next session resumes where the
+    /// peer's copy stopped.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The client
+    /// * `name` - The file name the peer stores it under
+    /// * `data` - The content of the file
+    ///
+    /// # Returns
+    ///
+    /// The ID of the transfer, or `None` if the event loop has stopped
+    pub fn send_file(&self, id: ClientId, name: &str, data: Vec<u8>) -> Option<String> {
+        let transfer = OutgoingTransfer::new(name, data);
+        let transfer_id = transfer.manifest().id.clone();
+        self.files.send((id, transfer)).ok()?;
+        Some(transfer_id)
+    }
+
+    /// Declares the rate at which a receiver wants a client to publish a
+    /// topic.
+    ///
+    /// The client is asked for the highest rate any receiver wants, once it
+    /// agreed to rate control.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The client publishing the topic
+    /// * `receiver` - Name of the receiver, e.g. `operator-ui`
+    /// * `topic` - The topic, i.e. the channel label, e.g. `gps`
+    /// * `hz` - The wanted rate, or `None` to withdraw the receiver's request
+    ///
+    /// # Returns
+    ///
+    /// `false` if the event loop has stopped
+    pub fn request_rate(&self, id: ClientId, receiver: &str, topic: &str, hz: Option<f64>) -> bool {
+        let request = RateRequest {
+            id,
+            receiver: receiver.to_string(),
+            topic: topic.to_string(),
+            hz,
+        };
+        self.rates.send(request).is_ok()
+    }
+
+    /// Returns the current state of the server and its clients, with their
+    /// recent significant events, e.g. to attach to a support ticket.
+    ///
+    /// # Returns
+    ///
+    /// The state, or `None` if the event loop has stopped or did not answer
+    /// in time
+    pub fn dump_state(&self) -> Option<ServerState> {
+        let (reply, state) = mpsc::channel();
+        self.states.send(reply).ok()?;
+        state.recv_timeout(STATE_REPLY_TIMEOUT).ok()
+    }
+
+    /// Returns the connection statistics of a client, as of the last sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The client
+    ///
+    /// # Returns
+    ///
+    /// The statistics, or `None` if no client has the ID or it was not
+    /// sampled yet
+    pub fn client_stats(&self, id: ClientId) -> Option<ConnectionStats> {
+        self.connections
+            .lock()
+            .expect("connections lock")
+            .get(&*id)
+            .cloned()
+    }
+
+    /// Returns the handle shutting the server down, e.g. from a signal handler.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Closes the clients' data channels, stops the event loop and the HTTP
+    /// server, and waits for both to finish.
+    pub fn stop(self) {
+        self.shutdown.trigger();
+        self.join();
+    }
+
+    /// Blocks until the event loop stops, then stops the HTTP server.
+    pub fn join(self) {
+        let _ = self.loop_thread.join();
+        let _ = self.http_stop.send(());
+        let _ = self.http_thread.join();
+    }
+
+    /// Waits without blocking the async runtime until the server is shut down,
+    /// then waits for the event loop and the HTTP server to finish.
+    ///
+    /// Lets async applications run the server alongside their own tasks, e.g.
+    /// in a `tokio::select!` with their other work.
+    pub async fn stopped(self) {
+        self.shutdown.triggered().await;
+        let _ = tokio::task::spawn_blocking(move || self.join()).await;
+    }
+}
+
+/// Main entry point for the WebRTC signaling server.
+///
+/// Initializes logging and runs a server with the given configuration until
+/// ctrl-c is pressed.
+///
+/// # Panics
+///
+/// Panics if the server cannot be started
+pub fn main(config: ServerConfig) {
+    init_log();
+    let server = start(config, vec![]).expect("starting the server");
+    if let Err(e) = server.shutdown().trigger_on_ctrl_c() {
+        warn!("Failed to install the ctrl-c handler: {}", e);
+    }
+    server.join();
+    info!("Server stopped");
+}
+
+/// Starts a signaling server.
+///
+/// This function:
+/// 1. Selects a host address for the UDP socket, unless configured
+/// 2. Binds a random UDP port for WebRTC traffic
+/// 3. Loads the geofence and ICE server configuration, if any
+/// 4. Spawns a background thread to handle WebRTC client connections
+/// 5. Starts an HTTP server for signaling and administration
+///
+/// Logging is left to the caller.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration
+/// * `callbacks` - Callbacks receiving the server's events
+///
+/// # Returns
+///
+/// A handle controlling the running server, or an error if the UDP socket,
+/// the geofence or ICE server configuration or the HTTP server could not be set up
+pub fn start(config: ServerConfig, callbacks: Vec<ServerCallback>) -> anyhow::Result<ServerHandle> {
+    let host_addr = match config.udp_host {
+        Some(addr) => addr,
+        None => wait_for_host_address(&config.network)?,
+    };
+
+    let wake = Arc::new(Notify::new());
+    let (tx, rx) = loop_channel(&wake);
+
+    let socket = bind_udp_to(SocketAddr::new(host_addr, config.udp_port), &config.network)?;
+    let addr = socket.local_addr()?;
+    info!("Bound UDP port: {}", addr);
+
+    let pcap = config.network.pcap.clone();
+    if pcap.enabled {
+        pcap::start_file(&pcap.path, pcap.max_bytes)?;
+        info!("Writing UDP traffic to {}", pcap.path.display());
+    }
+    if config.network.netsim.enabled {
+        netsim::configure(&config.network.netsim);
+        warn!(
+            "Simulating an impaired network: {:?}",
+            config.network.netsim
+        );
+    }
+
+    if let Err(e) = event_log::init_from_env() {
+        warn!("Ignoring invalid {}: {}", event_log::EVENT_LOG_ENV, e);
+    }
+
+    let geofences = GeofenceConfig::from_env()?;
+    info!("Loaded {} geofences", geofences.fences.len());
+
+    let ice_servers = config.ice_servers.clone();
+    info!("Recommending {} ICE servers to peers", ice_servers.len());
+
+    let auth: Arc<dyn AuthBackend> = Arc::from(config.auth.build()?);
+    info!(
+        "Authenticating signaling requests with the {} backend",
+        auth.name()
+    );
+
+    if let Some(lifetime) = config.session.lifetime_secs {
+        info!("Sessions expire after {}s without a refresh", lifetime);
+    }
+
+    let turn = TurnMinter::from_env();
+    if let Some(turn) = &turn {
+        info!("Minting TURN credentials for {}", turn.urls().join(", "));
+    }
+
+    let shared = SharedState {
+        guests: Arc::new(Mutex::new(GuestAuthority::from_env())),
+        stats: Arc::default(),
+        blocklist: Arc::new(Mutex::new(Blocklist::from_env())),
+        registry: Arc::default(),
+        setup: Arc::default(),
+        connections: Arc::default(),
+        health: Arc::default(),
+        captures: Arc::default(),
+        auth: auth.clone(),
+        sessions: Arc::default(),
+    };
+    let bus = EventBus::new();
+    track_clients(&bus, &shared);
+    for callback in callbacks {
+        bus.subscribe(&EventTopic::ALL, move |event| callback(event));
+    }
+    let (replay_tx, replay_rx) = loop_channel(&wake);
+    let (message_tx, message_rx) = loop_channel(&wake);
+    let (rate_tx, rate_rx) = loop_channel(&wake);
+    let (audio_tx, audio_rx) = loop_channel(&wake);
+    let (file_tx, file_rx) = loop_channel(&wake);
+    let (candidate_tx, candidate_rx) = loop_channel(&wake);
+    let (restart_tx, restart_rx) = loop_channel(&wake);
+    let (log_tx, log_rx) = loop_channel(&wake);
+    let (broadcast_tx, broadcast_rx) = loop_channel(&wake);
+    let (command_tx, command_rx) = loop_channel(&wake);
+    let (capture_tx, capture_rx) = loop_channel(&wake);
+    let (state_tx, state_rx) = loop_channel(&wake);
+    let admin = AdminState {
+        token: config
+            .admin_token
+            .clone()
+            .or_else(|| env::var(ADMIN_TOKEN_ENV).ok()),
+        replays: replay_tx,
+        messages: message_tx.clone(),
+        logs: log_tx,
+        broadcasts: broadcast_tx,
+        commands: command_tx,
+        captures: capture_tx,
+        states: state_tx.clone(),
+        pcap,
+        bus: bus.clone(),
+        shared: shared.clone(),
+    };
+    if admin.token.is_none() {
+        warn!("{} not set, admin API disabled", ADMIN_TOKEN_ENV);
+    }
+
+    let shutdown = Shutdown::new();
+    for webhook in &config.webhooks {
+        spawn_webhook(&bus, webhook, shutdown.clone());
+    }
+    let inputs = LoopInputs {
+        sessions: rx,
+        replays: replay_rx,
+        messages: message_rx,
+        audio: audio_rx,
+        files: file_rx,
+        rates: rate_rx,
+        candidates: candidate_rx,
+        restarts: restart_rx,
+        logs: log_rx,
+        broadcasts: broadcast_rx,
+        commands: command_rx,
+        captures: capture_rx,
+        states: state_rx,
+        wake,
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let loop_shared = shared.clone();
+    let loop_shutdown = shutdown.clone();
+    let loop_config = config.clone();
+    let loop_bus = bus.clone();
+    let loop_thread = thread::spawn(move || {
+        let result = runtime.block_on(run(
+            socket,
+            inputs,
+            GeofenceMonitor::new(geofences),
+            loop_shared,
+            loop_bus,
+            loop_shutdown.clone(),
+            loop_config,
+        ));
+        if let Err(e) = result {
+            warn!("Event loop failed: {}", e);
+        }
+        // Let async waiters know even if the loop ended on its own
+        loop_shutdown.trigger();
+    });
+
+    let signaling = Arc::new(SignalingState {
+        addr,
+        sessions: tx,
+        candidates: candidate_tx,
+        restarts: restart_tx,
+        ice_servers,
+        turn,
+        guests: shared.guests.clone(),
+        auth,
+        session: config.session.clone(),
+        broker: Broker::new(),
+        mdns: (config.network.mdns_timeout_ms > 0)
+            .then(|| MdnsResolver::new(Duration::from_millis(config.network.mdns_timeout_ms))),
+        failover: config.failover.clone(),
+        directory: shared.sessions.clone(),
+        replicas: Arc::default(),
+        provisioned: ProvisionedRtcs::new(
+            &config.provisioning,
+            Arc::new(move |peer: &ProvisionedPeer| {
+                build_rtc(
+                    addr,
+                    Some(IceCreds {
+                        ufrag: peer.ice_ufrag.clone(),
+                        pass: peer.ice_pwd.clone(),
+                    }),
+                )
+            }),
+        ),
+    });
+    let failover_secret = env::var(FAILOVER_SECRET_ENV).ok();
+    if let Some(primary) = &config.failover.primary_url {
+        info!("Standing by for {}", primary);
+        spawn_standby_sync(
+            &config.failover,
+            failover_secret.clone(),
+            signaling.replicas.clone(),
+            shutdown.clone(),
+        )?;
+    } else if let Some(standby) = &config.failover.standby_url {
+        info!("Announcing the standby {} to peers", standby);
+        if failover_secret.is_none() {
+            warn!(
+                "{} not set, the standby cannot copy the sessions",
+                FAILOVER_SECRET_ENV
+            );
         }
     }
+    let handler = move |request: &Request| {
+        if request.method() == "GET" && request.url() == HEALTH_PATH {
+            return health_request(&signaling);
+        }
+        if request.url() == FAILOVER_STATE_PATH {
+            return failover_state_request(request, &signaling, failover_secret.as_deref());
+        }
+        if request.url().starts_with("/admin/") || request.url().starts_with("/clients/") {
+            return admin_request(request, &admin);
+        }
+        if request.url() == TRICKLE_PATH {
+            return trickle_request(request, &signaling);
+        }
+        if request.url() == RESTART_PATH {
+            return restart_request(request, &signaling);
+        }
+        if request.url() == WEBSOCKET_PATH {
+            return websocket_request(request, &signaling);
+        }
+        if request.url().starts_with("/mesh/") {
+            return mesh_request(request, &signaling);
+        }
+        if request.method() == "GET" && (request.url() == "/" || request.url() == CONSOLE_PATH) {
+            return console_page();
+        }
+        web_request(request, &signaling)
+    };
+    let (server, scheme) = match &config.tls {
+        Some(tls) => {
+            let (cert, key) = tls.load()?;
+            (
+                Server::new_ssl(&config.http_addr, handler, cert, key),
+                "https",
+            )
+        }
+        None => (Server::new(&config.http_addr, handler), "http"),
+    };
+    let server = server.map_err(|e| anyhow::anyhow!("starting the web server: {}", e))?;
+
+    let http_addr = server.server_addr();
+    info!(
+        "Connect a browser to {}://{:?}:{:?}",
+        scheme,
+        addr.ip(),
+        http_addr.port()
+    );
+
+    let (http_thread, http_stop) = server.stoppable();
+
+    Ok(ServerHandle {
+        udp_addr: addr,
+        http_addr,
+        messages: message_tx,
+        audio: audio_tx,
+        files: file_tx,
+        rates: rate_tx,
+        states: state_tx,
+        connections: shared.connections.clone(),
+        bus,
+        shutdown,
+        http_stop,
+        http_thread,
+        loop_thread,
+    })
+}
+
+/// Main event loop for managing WebRTC clients.
+///
+/// This function:
+/// - Maintains a list of active clients
+/// - Polls each client for output and handles timeouts
+/// - Routes incoming UDP packets to the appropriate client
+/// - Routes application data to other clients through the routing table
+/// - Evaluates received GPS telemetry against the configured geofences
+/// - Negotiates the protocol version with each client
+/// - Relays coordination messages between rovers
+/// - Asks peers to refresh their sessions, and disconnects expired ones
+/// - Reports network interface changes affecting the UDP socket
+/// - Plays back recorded sessions into rooms
+/// - Delivers messages sent to individual clients through the admin API
+/// - Samples client metrics into the stats history every second
+/// - Removes disconnected clients
+/// - Publishes connections, disconnections and application data on the bus
+///
+/// The loop runs until `shutdown` is triggered or the web server thread goes
+/// away, then closes the data channels of the remaining clients. Between
+/// iterations it sleeps until a datagram arrives, a handler queues an input,
+/// the shutdown is triggered or the earliest client timeout passes.
+///
+/// # Arguments
+///
+/// * `socket` - The UDP socket for receiving/sending WebRTC traffic
+/// * `inputs` - Channel receivers for new sessions, replays and client messages
+/// * `geofences` - Monitor evaluating GPS telemetry against the configured fences
+/// * `shared` - Guest authority, stats histories, blocklist and client registry shared
+///   with the HTTP handlers
+/// * `bus` - Bus the server's events are published on
+/// * `shutdown` - Handle requesting the loop to exit
+/// * `config` - Polling and health check settings
+///
+/// # Returns
+///
+/// An error if the socket could not be registered with the async runtime or
+/// failed while reading
+async fn run(
+    socket: UdpSocket,
+    mut inputs: LoopInputs,
+    mut geofences: GeofenceMonitor,
+    shared: SharedState,
+    bus: EventBus<ServerEvent>,
+    shutdown: Shutdown,
+    config: ServerConfig,
+) -> Result<(), RoverRtcError> {
+    let mut replays: Vec<ReplaySession> = vec![];
+    let health_policy = ThresholdPolicy::server(&config.health);
+    let mut pending_logs: HashMap<(ClientId, u32), PendingLogs> = HashMap::new();
+    let mut pending_broadcasts: Vec<(PendingBroadcast, mpsc::Sender<BroadcastReport>)> = vec![];
+    let mut next_broadcast: u32 = 0;
+    let mut rules = RuleEngine::new(config.rules.clone());
+    let recorder = Recorder::start(&config.recording)?;
+    // Significant events of the server itself, for state dumps
+    let mut events = EventRing::new();
+    let webhook = geofences
+        .webhook()
+        .map(GeofenceWebhook::spawn)
+        .transpose()?;
+    let mut buf = vec![0; 2000];
+    let local_addr = socket.local_addr()?;
+    let bound_ip = Some(local_addr.ip());
+    let transport: Arc<dyn Transport> = Arc::new(socket.try_clone()?);
+    let mut core = LoopCore::new(
+        transport.clone(),
+        Arc::new(SystemClock),
+        local_addr,
+        config.provisioning.clone(),
+        shared.blocklist.clone(),
+    );
+    let mut last_health_check = core.now();
+    let mut last_stats_sample = core.now();
+    let mut last_session_check = core.now();
+    let mut poll_pool = PollPool::new(poll_worker_count(config.poll_workers), &transport)?;
+    let health_check_interval = Duration::from_secs(config.health_check_secs);
+    let mut netmon = NetworkMonitor::new(health_check_interval);
+    // Datagrams are awaited on a clone of the socket registered with the
+    // runtime, while the clients and the polling workers send through the
+    // original. Both are non-blocking from here on: a send finding the buffer
+    // full is dropped and logged like any lost datagram.
+    let incoming = socket.try_clone()?;
+    incoming.set_nonblocking(true)?;
+    let incoming = tokio::net::UdpSocket::from_std(incoming)?;
+    info!("Polling core.clients with {} worker(s)", poll_pool.workers);
+
+    let emit = |event: ServerEvent| bus.publish(&event);
+
+    'event_loop: while !shutdown.is_triggered() {
+        let mut membership_changed = false;
+
+        // Remove disconnected clients and their health records
+        core.clients.retain_mut(|c| {
+            let alive = c.rtc.is_alive();
+            if !alive {
+                membership_changed = true;
+                let removal = c.removal(c.removal_reason());
+                info!(
+                    "{} disconnected ({}), removing from pool",
+                    c.name(),
+                    removal.reason
+                );
+                events.record(EventCategory::State, format!("{} removed", c.name()));
+                core.health.remove(&*c.id);
+                geofences.remove_client(c.id);
+                rules.remove_source(&rule_source(c));
+                shared.registry.lock().expect("registry lock").remove(c.id);
+                shared
+                    .sessions
+                    .lock()
+                    .expect("sessions lock")
+                    .remove(&c.session_token);
+                emit(ServerEvent::ClientDisconnected { id: c.id });
+                emit(ServerEvent::ClientRemoved(Box::new(removal)));
+            }
+            alive
+        });
+
+        // Spawn new clients from the web server thread
+        loop {
+            let client = match spawn_new_client(
+                &mut inputs.sessions,
+                &shared.registry,
+                &config,
+                recorder.as_ref(),
+            ) {
+                Ok(Some(client)) => client,
+                Ok(None) => break,
+                Err(e) => {
+                    info!("{}, leaving the event loop", e);
+                    break 'event_loop;
+                }
+            };
+            info!("New client connected: {}", client.name());
+            events.record(EventCategory::State, format!("{} connected", client.name()));
+            emit(ServerEvent::ClientConnected {
+                id: client.id,
+                alias: client.alias.clone(),
+                subject: client.access.subject.clone(),
+            });
+            core.admit(client);
+        }
+
+        if membership_changed {
+            core.index.rebuild(&core.clients);
+        }
+
+        // Periodic health check
+        if core.now() - last_health_check > health_check_interval {
+            for (id, state) in
+                check_client_health(&mut core.clients, &mut core.health, &health_policy)
+            {
+                let name = client_name(&core.clients, id);
+                events.record(EventCategory::State, format!("{} {}", name, state));
+                emit(ServerEvent::HealthChanged { id, state });
+            }
+            enforce_guest_access(&mut core.clients, &shared.guests);
+            shared.blocklist.lock().expect("blocklist lock").prune();
+            last_health_check = core.now();
+        }
+
+        // Report changes to the network the UDP socket depends on
+        for event in netmon.poll() {
+            handle_network_event(&event, bound_ip);
+            events.record(EventCategory::Handover, format!("{:?}", event));
+        }
 
-    fn mark_activity(&mut self) {
-        self.last_activity = Instant::now();
-        self.consecutive_failures = 0;
+        // Expire sessions and ask peers to refresh them in time
+        if core.now() - last_session_check >= SESSION_CHECK_INTERVAL {
+            expire_sessions(&mut core.clients, &config.session);
+            last_session_check = core.now();
+        }
+
+        // Sample client metrics into the stats history
+        if core.now() - last_stats_sample >= SAMPLE_INTERVAL {
+            let mut stats = shared.stats.lock().expect("stats lock");
+            for client in core.clients.iter_mut() {
+                client.counters.link = client
+                    .features
+                    .contains(Feature::Heartbeat)
+                    .then(|| client.link.stats());
+                let history = stats.entry(*client.id).or_default();
+                history.record(&client.counters);
+                history.set_protocol(client.protocol, client.features);
+            }
+            drop(stats);
+            crash::record_state("server.clients", crash_snapshot(&core.clients));
+            let mut setup = shared.setup.lock().expect("setup lock");
+            for client in &core.clients {
+                setup.insert(*client.id, client.setup.breakdown());
+            }
+            drop(setup);
+            let mut connections = shared.connections.lock().expect("connections lock");
+            for client in core.clients.iter_mut() {
+                connections.insert(*client.id, client.stats());
+            }
+            last_stats_sample = core.now();
+        }
+
+        // Poll all clients and get the earliest timeout
+        let (timeout, exhausted) =
+            poll_clients(&mut core, &mut poll_pool, config.loop_budget.max_polls).await;
+        if exhausted {
+            // Let the runtime run, then come back at once for the rest
+            tokio::task::yield_now().await;
+        }
+
+        // Update health on successful poll
+        for client in &core.clients {
+            if let Some(h) = core.health.get_mut(&*client.id) {
+                h.mark_activity();
+            }
+        }
+
+        // Evaluate GPS telemetry received during the poll
+        let mut fired = vec![];
+        for (i, client) in core.clients.iter_mut().enumerate() {
+            for fix in client.take_gps_fixes() {
+                for event in geofences.update(client.id, &fix) {
+                    handle_geofence_event(client, &event, webhook.as_ref());
+                }
+                if !rules.is_empty() {
+                    let source = rule_source(client);
+                    let sample = Telemetry::Gps(fix);
+                    for firing in rules.evaluate_telemetry(&source, &sample, core.clock.now()) {
+                        fired.push((i, firing));
+                    }
+                }
+            }
+        }
+
+        for (id, keys) in negotiate_protocols(&mut core.clients, &config.protocol) {
+            emit(ServerEvent::KeysAgreed { id, keys });
+        }
+        relay_coordination(&mut core.clients);
+        refresh_sessions(&mut core.clients, shared.auth.as_ref(), &config.session);
+
+        // Report application data to the embedding application
+        let mut propagated = vec![];
+        let mut routed = vec![];
+        for (i, client) in core.clients.iter_mut().enumerate() {
+            for (channel, data) in client.take_received() {
+                if !config.routing.is_empty() {
+                    routed.push((i, channel.clone(), data.clone()));
+                }
+                if !rules.is_empty() {
+                    let source = rule_source(client);
+                    for firing in rules.evaluate(&source, &channel, &data, core.clock.now()) {
+                        fired.push((i, firing));
+                    }
+                }
+                emit(ServerEvent::ChannelData {
+                    id: client.id,
+                    channel,
+                    data,
+                });
+            }
+            for event in client.take_transfer_events() {
+                emit(transfer_event(client.id, event));
+            }
+            for failure in client.take_write_failures() {
+                emit(ServerEvent::WriteFailed {
+                    id: client.id,
+                    channel: failure.channel,
+                    data: failure.data,
+                    error: failure.error,
+                });
+            }
+            for mut media in client.take_media() {
+                let Some(kind) = client.media_kind(media.mid) else {
+                    continue;
+                };
+                let data = if config.forward.enabled {
+                    media.data.clone()
+                } else {
+                    std::mem::take(&mut media.data)
+                };
+                emit(ServerEvent::MediaData {
+                    id: client.id,
+                    mid: media.mid,
+                    kind,
+                    codec: media.params.spec().codec,
+                    time: media.time,
+                    contiguous: media.contiguous,
+                    data,
+                });
+                if config.forward.enabled {
+                    propagated.push(Propagated::MediaData(client.id, media));
+                }
+            }
+        }
+        route_messages(&mut core.clients, routed, &config.routing);
+        for (i, firing) in fired {
+            run_rule_actions(&mut core.clients, i, &firing);
+        }
+        if config.forward.enabled {
+            forward_media(&mut core.clients, propagated);
+        } else {
+            for client in core.clients.iter_mut() {
+                client.take_propagated();
+            }
+        }
+
+        // Forward log requests to the peers and their answers to the requesters
+        for request in drain(&mut inputs.logs) {
+            match core.clients.iter_mut().find(|c| c.id == request.id) {
+                Some(client) => match client.request_logs(request.query) {
+                    Some(id) => {
+                        let pending = PendingLogs {
+                            reply: request.reply,
+                            deadline: core.clock.now() + LOG_REPLY_TIMEOUT,
+                        };
+                        pending_logs.insert((client.id, id), pending);
+                    }
+                    None => {
+                        let reason = "the peer has no logs channel".to_string();
+                        let _ = request.reply.send(Err(reason));
+                    }
+                },
+                None => debug!("Dropping log request for departed Client({})", request.id),
+            }
+        }
+        answer_log_requests(&mut core.clients, &mut pending_logs);
+
+        // Send announcements to the rooms and report who acknowledged them
+        for request in drain(&mut inputs.broadcasts) {
+            let id = next_broadcast;
+            next_broadcast = next_broadcast.wrapping_add(1);
+            let recipients = core
+                .clients
+                .iter_mut()
+                .filter(|c| c.access.room.as_deref() == Some(request.room.as_str()))
+                .map(|c| {
+                    let sent = c.announce(id, &request.data);
+                    (*c.id, c.alias.clone(), sent)
+                })
+                .collect();
+            info!(
+                "Broadcasting announcement {} to room '{}'",
+                id, request.room
+            );
+            let broadcast = PendingBroadcast::new(id, &request.room, recipients, request.timeout);
+            pending_broadcasts.push((broadcast, request.reply));
+        }
+        answer_broadcasts(&mut core.clients, &mut pending_broadcasts);
+
+        // Disconnect clients and restart their ICE as operators ask
+        for request in drain(&mut inputs.commands) {
+            let Some(client) = core.clients.iter_mut().find(|c| c.id == request.id) else {
+                let _ = request.reply.send(Err("the client left".into()));
+                continue;
+            };
+            let result = match request.command {
+                ClientCommand::Disconnect => {
+                    info!("Disconnecting {} as an administrator asks", client.name());
+                    let reason = "disconnected by an administrator";
+                    client.close(transport.as_ref(), reason);
+                    client.evict(reason);
+                    Ok(())
+                }
+                ClientCommand::RestartIce if client.request_ice_restart() => {
+                    info!("Asking {} to restart ICE", client.name());
+                    Ok(())
+                }
+                ClientCommand::RestartIce => Err("the peer does not support remote_restart".into()),
+            };
+            let _ = request.reply.send(result);
+        }
+
+        // Start and stop packet captures and collect the finished ones
+        let commands: Vec<CaptureCommand> = drain(&mut inputs.captures).collect();
+        update_captures(&mut core.clients, commands, &shared.captures);
+
+        // Keep the crash reports peers uploaded
+        store_crash_reports(&mut core.clients, &config.crash);
+
+        // Answer state dumps made through the admin API or the handle
+        for reply in drain(&mut inputs.states) {
+            let _ = reply.send(server_state(&core.clients, &events, local_addr));
+        }
+
+        // Play back recorded sessions into their rooms
+        replays.extend(drain(&mut inputs.replays));
+        play_replays(&mut core.clients, &mut replays);
+
+        // Deliver messages sent to individual clients
+        for (id, message) in drain(&mut inputs.messages) {
+            match core.clients.iter_mut().find(|c| c.id == id) {
+                Some(client) => client.send_message(&message),
+                None => debug!("Dropping message to departed Client({})", id),
+            }
+        }
+        for (id, frame) in drain(&mut inputs.audio) {
+            match core.clients.iter_mut().find(|c| c.id == id) {
+                Some(client) => {
+                    client.send_audio(frame);
+                }
+                None => debug!("Dropping audio to departed Client({})", id),
+            }
+        }
+        for (id, transfer) in drain(&mut inputs.files) {
+            match core.clients.iter_mut().find(|c| c.id == id) {
+                Some(client) => {
+                    client.send_file(transfer);
+                }
+                None => debug!("Dropping file to departed Client({})", id),
+            }
+        }
+        for client in core.clients.iter_mut() {
+            client.poll_transfers();
+        }
+
+        // Collect the publishing rates requested for individual clients
+        for request in drain(&mut inputs.rates) {
+            match core.clients.iter_mut().find(|c| c.id == request.id) {
+                Some(client) => client
+                    .rates
+                    .request(&request.receiver, &request.topic, request.hz),
+                None => debug!("Dropping rate request for departed Client({})", request.id),
+            }
+        }
+
+        // Apply candidates trickled by peers after signaling
+        for (token, candidate) in drain(&mut inputs.candidates) {
+            match core.clients.iter_mut().find(|c| c.session_token == token) {
+                Some(client) => {
+                    info!("{} trickled candidate {}", client.name(), candidate.addr());
+                    client.rtc.add_remote_candidate(candidate);
+                }
+                None => debug!("Dropping candidate for unknown session"),
+            }
+        }
+
+        // Answer ICE restarts of peers whose network changed
+        for restart in drain(&mut inputs.restarts) {
+            let answer = match core
+                .clients
+                .iter_mut()
+                .find(|c| c.session_token == restart.session_token)
+            {
+                Some(client) => match client.accept_restart_offer(restart.offer) {
+                    Ok(answer) => {
+                        if let Some(h) = core.health.get_mut(&*client.id) {
+                            h.mark_recovered();
+                        }
+                        emit(ServerEvent::IceRestarted { id: client.id });
+                        Some(answer)
+                    }
+                    Err(e) => {
+                        warn!("{} rejected ICE restart offer: {:?}", client.name(), e);
+                        None
+                    }
+                },
+                None => {
+                    debug!("Dropping ICE restart for unknown session");
+                    None
+                }
+            };
+            let _ = restart.reply.send(answer);
+        }
+
+        // Wait for a datagram, an input from the handlers or the shutdown, until
+        // the earliest client timeout at the latest.
+        buf.resize(2000, 0);
+        let mut received_len = 0;
+        let mut next = tokio::select! {
+            received = incoming.recv_from(&mut buf) => {
+                received_len = received.as_ref().map_or(0, |(n, _)| *n);
+                socket_input(received, &socket, &mut buf)?
+            }
+            _ = inputs.wake.notified() => None,
+            _ = shutdown.triggered() => None,
+            _ = tokio::time::sleep_until(timeout.into()) => None,
+        };
+
+        // Handle the datagrams already waiting too, up to the budget
+        let mut packets = 0;
+        loop {
+            if let Some(source) = next.take() {
+                core.receive(source, &buf);
+            }
+
+            if received_len == 0 {
+                break;
+            }
+            packets += 1;
+            if packets >= config.loop_budget.max_packets {
+                debug!("Read {} datagrams, handling timeouts first", packets);
+                tokio::task::yield_now().await;
+                break;
+            }
+            buf.resize(2000, 0);
+            let received = incoming.try_recv_from(&mut buf);
+            received_len = received.as_ref().map_or(0, |(n, _)| *n);
+            next = socket_input(received, &socket, &mut buf)?;
+        }
+
+        core.unmatched.log_summary(Duration::from_secs(30));
+
+        // Drive time forward in all clients.
+        core.handle_timeout();
+    }
+
+    // Close the remaining connections cleanly before leaving
+    if !core.clients.is_empty() {
+        info!("Closing {} client connection(s)", core.clients.len());
+    }
+    for client in &mut core.clients {
+        let reason = if client.rtc.is_alive() {
+            RemovalReason::Drained
+        } else {
+            client.removal_reason()
+        };
+        let removal = client.removal(reason);
+        client.close(transport.as_ref(), "server shutting down");
+        emit(ServerEvent::ClientDisconnected { id: client.id });
+        emit(ServerEvent::ClientRemoved(Box::new(removal)));
+    }
+    if let Some(tap) = pcap::stop_file() {
+        info!("Wrote {} datagrams to {}", tap.packets, tap.path.display());
+    }
+    Ok(())
+}
+
+/// The clients of the event loop and the state routing datagrams to them.
+///
+/// The event loop hands it the datagrams it reads and has it drive the
+/// clients. Their transmits go to its [`Transport`] and the time is read from
+/// its [`Clock`], so a recorded trace can be replayed through the same
+/// handling with a [`MemoryTransport`](crate::model::transport::MemoryTransport)
+/// and a [`ManualClock`](crate::model::transport::ManualClock), as
+/// `tests/replay.rs` does.
+pub struct LoopCore {
+    clients: Vec<Client>,
+    health: HashMap<u64, ConnectionHealth>,
+    index: DemuxIndex,
+    held: HeldPackets,
+    unmatched: UnmatchedDiagnostics,
+    provisioning: ProvisioningConfig,
+    blocklist: Arc<Mutex<Blocklist>>,
+    transport: Arc<dyn Transport>,
+    clock: Arc<dyn Clock>,
+    local_addr: SocketAddr,
+}
+
+impl LoopCore {
+    /// Creates a core without clients.
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - Where the clients' transmits are sent
+    /// * `clock` - Where the time is read
+    /// * `local_addr` - The address the datagrams are received on
+    /// * `provisioning` - The provisioned peers, whose early packets are held
+    /// * `blocklist` - Source addresses whose packets are dropped
+    pub fn new(
+        transport: Arc<dyn Transport>,
+        clock: Arc<dyn Clock>,
+        local_addr: SocketAddr,
+        provisioning: ProvisioningConfig,
+        blocklist: Arc<Mutex<Blocklist>>,
+    ) -> Self {
+        Self {
+            clients: vec![],
+            health: HashMap::new(),
+            index: DemuxIndex::new(),
+            held: HeldPackets::new(),
+            unmatched: UnmatchedDiagnostics::new(DiagnosticsLevel::from_env()),
+            provisioning,
+            blocklist,
+            transport,
+            clock,
+            local_addr,
+        }
+    }
+
+    /// Returns the current time of the core's clock.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns the clients, in the order they were admitted.
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    /// Returns the health of a client.
+    pub fn health(&self, id: ClientId) -> Option<HealthState> {
+        self.health.get(&*id).map(ConnectionHealth::state)
+    }
+
+    /// Adds a client whose session arrived from signaling, handing it the
+    /// packets held for it.
+    pub fn admit(&mut self, mut client: Client) {
+        self.health.insert(*client.id, ConnectionHealth::new());
+        replay_held(&mut client, &mut self.held, &mut self.index);
+        self.clients.push(client);
+        self.index.rebuild(&self.clients);
+    }
+
+    /// Hands a received datagram to the client it belongs to.
+    ///
+    /// Datagrams that do not have the shape of STUN, DTLS or RTP are dropped
+    /// and counted before parsing, as are those from blocked sources. STUN
+    /// requests for a provisioned peer whose session did not arrive yet are
+    /// held for it; other datagrams no client accepts count against the
+    /// health of the clients they implicate.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Where the datagram came from
+    /// * `datagram` - The datagram
+    pub fn receive(&mut self, source: SocketAddr, datagram: &[u8]) {
+        let now = self.now();
+        // Drop obvious garbage before paying for a full parse.
+        if !is_plausible(datagram) {
+            self.unmatched.record_malformed(source);
+            return;
+        }
+        // Parse data to a DatagramRecv, which help preparse network data to
+        // figure out the multiplexing of all protocols on one UDP port.
+        let Ok(contents) = datagram.try_into() else {
+            self.unmatched.record_malformed(source);
+            return;
+        };
+        // Drop packets from blocked sources before demultiplexing
+        if self
+            .blocklist
+            .lock()
+            .expect("blocklist lock")
+            .check_and_count(source.ip())
+        {
+            return;
+        }
+        let input = Input::Receive(
+            now,
+            Receive {
+                proto: Protocol::Udp,
+                source,
+                destination: self.local_addr,
+                contents,
+            },
+        );
+
+        // The rtc.accepts() call is how we demultiplex the incoming packet to know which
+        // Rtc instance the traffic belongs to. The index is tried first and verified with
+        // accepts(); the linear scan is the fallback, and re-learns the source.
+        let indexed = self.index.lookup(source);
+        let position = match indexed.filter(|&i| self.clients[i].accepts(&input)) {
+            Some(i) => Some(i),
+            None => {
+                if indexed.is_some() {
+                    self.index.forget(source);
+                }
+                let found = self.clients.iter().position(|c| c.accepts(&input));
+                if let Some(i) = found {
+                    self.index.learn(source, self.clients[i].id);
+                }
+                found
+            }
+        };
+
+        if let Some(client) = position.map(|i| &mut self.clients[i]) {
+            // We found the client that accepts the input.
+            client.count_received(datagram.len());
+            client.handle_input(input);
+
+            // Mark activity on successful input
+            if let Some(h) = self.health.get_mut(&*client.id) {
+                h.mark_activity();
+            }
+        } else if let Some(ufrag) = provisioned_ufrag(&self.provisioning, datagram) {
+            // The session of a provisioned peer is still on its way from
+            // the signaling thread
+            let packet = HeldPacket {
+                received: now,
+                source,
+                destination: self.local_addr,
+                data: datagram.to_vec(),
+            };
+            if !self.held.hold(&ufrag, packet) {
+                debug!("Dropping a packet for a provisioned session, too many held");
+            }
+        } else {
+            // This is quite common because we don't get the Rtc instance via the mpsc channel
+            // quickly enough before the browser send the first STUN.
+            debug!("No client accepts UDP input");
+
+            if self.unmatched.is_enabled() {
+                // Only mark failures for the clients the packet actually implicates
+                let class = classify(datagram);
+                self.unmatched.record(source, &class);
+                self.blocklist
+                    .lock()
+                    .expect("blocklist lock")
+                    .record_unmatched(source.ip());
+                for client in self
+                    .clients
+                    .iter()
+                    .filter(|c| c.is_implicated_by(source, &class))
+                {
+                    if let Some(h) = self.health.get_mut(&*client.id) {
+                        h.mark_failure();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Polls every client in turn, sending their transmits.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_polls` - The outputs polled from each client at most
+    ///
+    /// # Returns
+    ///
+    /// The earliest timeout across all clients, capped at 100ms from now, and
+    /// whether a client used up its budget
+    pub fn poll(&mut self, max_polls: usize) -> (Instant, bool) {
+        let now = self.now();
+        let mut timeouts = TimeoutCollector::new(now);
+        for client in self.clients.iter_mut() {
+            timeouts.collect(
+                poll_client(client, self.transport.as_ref(), max_polls, now),
+                now,
+            );
+        }
+        (timeouts.earliest, timeouts.exhausted)
+    }
+
+    /// Drives time forward in every client and drops the held packets that
+    /// waited too long.
+    pub fn handle_timeout(&mut self) {
+        let now = self.now();
+        self.held.prune(now);
+        for client in &mut self.clients {
+            client.handle_input(Input::Timeout(now));
+        }
+    }
+}
+
+/// Serves the reference browser operator console, `web/index.html`, at the
+/// root and at [`CONSOLE_PATH`].
+///
+/// The page speaks the protocol of this build: its version range and payload
+/// envelope are filled in here.
+fn console_page() -> Response {
+    let page = include_str!("../web/index.html")
+        .replace("{{PROTOCOL_VERSION}}", &PROTOCOL_VERSION.to_string())
+        .replace(
+            "{{MIN_PROTOCOL_VERSION}}",
+            &MIN_PROTOCOL_VERSION.to_string(),
+        )
+        .replace("{{ENVELOPE_MARKER}}", &ENVELOPE_MARKER.to_string())
+        .replace("{{ENVELOPE_VERSION}}", &ENVELOPE_VERSION.to_string())
+        .replace("{{SOFTWARE}}", env!("CARGO_PKG_VERSION"));
+    Response::html(page)
+}
+
+/// Handles incoming HTTP requests for WebRTC signaling.
+///
+/// This function processes SDP offers from clients, creates an SDP answer,
+/// and sends the new RTC instance to the main event loop via the channel.
+///
+/// Requests carrying a guest token (see [`bearer_token`]) are admitted as
+/// observers of the token's room; others are authenticated by the configured
+/// [`AuthBackend`], and rejected with 401 if that fails. The optional `alias`
+/// query parameter names the peer in logs and the admin API.
+///
+/// Peers passing `format=structured` receive a [`SignalingAnswer`] with the
+/// session metadata and recommended ICE servers; others receive the bare SDP
+/// answer for compatibility.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request containing the SDP offer
+/// * `signaling` - State shared with the signaling handlers
+///
+/// # Returns
+///
+/// An HTTP response containing the SDP answer in JSON format
+fn web_request(request: &Request, signaling: &SignalingState) -> Response {
+    // Never the whole request: with an auth backend, its query and headers
+    // carry tokens, JWTs and client certificate details
+    info!(
+        "{} {} from {}",
+        request.method(),
+        request.url(),
+        request.remote_addr()
+    );
+
+    let access = match session_access(request, signaling) {
+        Ok(access) => access,
+        Err(response) => return response,
+    };
+
+    let Some(data) = request.data() else {
+        return Response::text("missing offer").with_status_code(400);
+    };
+    let offer: SdpOffer = match serde_json::from_reader(data) {
+        Ok(offer) => offer,
+        Err(e) => return Response::text(format!("invalid offer: {}", e)).with_status_code(400),
+    };
+    let answer = match create_session(offer, access, request.get_param("alias"), signaling) {
+        Ok(answer) => answer,
+        Err(e) => {
+            warn!("Failed to create a session: {}", e);
+            let status = match e {
+                RoverRtcError::Sdp(_) => 400,
+                _ => 500,
+            };
+            return Response::text(e.to_string()).with_status_code(status);
+        }
+    };
+
+    let format = AnswerFormat::from_param(request.get_param(ANSWER_FORMAT_PARAM).as_deref());
+    let body = match format {
+        AnswerFormat::Bare => serde_json::to_vec(&answer.answer),
+        AnswerFormat::Structured => serde_json::to_vec(&answer),
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => return Response::text(e.to_string()).with_status_code(500),
+    };
+
+    info!("Send answer");
+    Response::from_data("application/json", body)
+}
+
+/// Determines the access granted to a signaling request.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request
+/// * `signaling` - State shared with the signaling handlers
+///
+/// # Returns
+///
+/// The access, or a 401 response if the request carries an invalid guest
+/// token or the authentication backend rejects it
+fn session_access(request: &Request, signaling: &SignalingState) -> Result<Access, Response> {
+    let reject = |reason: String| {
+        warn!("Rejected signaling request: {}", reason);
+        Response::text(reason).with_status_code(401)
+    };
+
+    // A peer failing over from the primary keeps the access it had there
+    if let Some(token) = request.get_param(RESUME_PARAM) {
+        let resumed = signaling
+            .replicas
+            .lock()
+            .expect("replicas lock")
+            .resume(&token, Utc::now().timestamp());
+        match resumed {
+            Some(record) => {
+                info!(
+                    "Resuming session of {} from the primary",
+                    record.alias.as_deref().unwrap_or("an anonymous peer")
+                );
+                return Ok(record.access());
+            }
+            None => debug!("No session of the primary to resume, authenticating"),
+        }
+    }
+
+    if let Some(token) = bearer_token(request) {
+        let room = request.get_param("room");
+        let guests = signaling.guests.lock().expect("guest authority lock");
+        match guests.authorize(&token, room.as_deref()) {
+            Ok(access) => return Ok(access),
+            // Not a guest token, it may be one for the backend
+            Err(GuestError::Malformed | GuestError::BadSignature) => {}
+            Err(e) => return Err(reject(e.to_string())),
+        }
+    }
+
+    let access = signaling
+        .auth
+        .authenticate(request)
+        .map_err(|e| reject(e.to_string()))?;
+    if let Some(subject) = &access.subject {
+        info!(
+            "Authenticated '{}' with the {} backend",
+            subject,
+            signaling.auth.name()
+        );
+    }
+    Ok(access)
+}
+
+/// Creates a session from an SDP offer and hands it to the event loop.
+///
+/// # Arguments
+///
+/// * `offer` - The peer's SDP offer
+/// * `access` - The access granted to the session
+/// * `alias` - The alias announced by the peer, if any; defaults to the
+///   identity established by authentication
+/// * `signaling` - State shared with the signaling handlers
+///
+/// # Returns
+///
+/// The structured answer for the peer, or an error if the offer is not
+/// acceptable or the event loop has stopped
+fn create_session(
+    offer: SdpOffer,
+    access: Access,
+    alias: Option<String>,
+    signaling: &SignalingState,
+) -> Result<SignalingAnswer, RoverRtcError> {
+    info!(
+        "Received offer with {} data channels",
+        offer.to_string().matches("m=application").count()
+    );
+    let alias = alias.or_else(|| access.subject.clone().filter(|s| is_valid_alias(s)));
+    let mut setup = SetupTimer::new();
+    let offer = match &signaling.mdns {
+        Some(mdns) => mdns.resolve_offer(offer),
+        None => offer,
+    };
+    setup.begin(SetupPhase::Signaling);
+    setup.begin(SetupPhase::IceGathering);
+    let provisioned = signaling
+        .provisioned
+        .identify(access.subject.as_deref(), alias.as_deref());
+    let mut rtc = match provisioned {
+        Some((identity, peer)) => {
+            peer.verify_offer(&offer.to_string()).map_err(|e| {
+                warn!("Rejecting the offer of '{}': {}", identity, e);
+                RoverRtcError::Sdp(e)
+            })?;
+            info!("Using the session prepared for '{}'", identity);
+            signaling.provisioned.take(identity)?
+        }
+        None => build_rtc(signaling.addr, None)?,
+    };
+    setup.end(SetupPhase::IceGathering);
+
+    let answer = rtc
+        .sdp_api()
+        .accept_offer(offer)
+        .map_err(|e| RoverRtcError::Sdp(e.to_string()))?;
+
+    info!("Created answer, sending to client thread");
+
+    let id = ClientId::next();
+    let mut token = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut token);
+    let session_token = hex_encode(&token);
+    let session = SessionLifetime::new(&signaling.session);
+    let (refresh_token, expires_at) = match session.expires_at() {
+        Some(at) => (Some(session.refresh_token().to_string()), Some(at)),
+        None => (None, None),
+    };
+
+    signaling
+        .directory
+        .lock()
+        .expect("sessions lock")
+        .insert(SessionRecord::new(
+            &session_token,
+            alias.as_deref(),
+            &access,
+        ));
+
+    // Connectivity checks start once the peer has the answer
+    setup.advance(SetupPhase::Signaling, SetupPhase::IceConnectivity);
+    signaling
+        .sessions
+        .send(NewSession {
+            id,
+            session_token: session_token.clone(),
+            rtc,
+            access,
+            alias: alias.clone(),
+            setup,
+            session,
+        })
+        .map_err(|_| RoverRtcError::Disconnected("event loop"))?;
+
+    let mut ice_servers: Vec<IceServer> = signaling
+        .ice_servers
+        .iter()
+        .filter(|s| !s.is_expired(Utc::now().timestamp()))
+        .cloned()
+        .collect();
+    if let Some(turn) = &signaling.turn {
+        // Named after the session so TURN server logs can be correlated
+        let user = alias
+            .filter(|a| is_valid_alias(a))
+            .unwrap_or_else(|| id.to_string());
+        ice_servers.push(turn.mint(&user));
+    }
+
+    Ok(SignalingAnswer {
+        answer,
+        client_id: *id,
+        session_token,
+        ice_servers,
+        server_time: Utc::now().timestamp_millis(),
+        refresh_token,
+        expires_at,
+        standby_url: signaling.failover.standby_url.clone(),
+    })
+}
+
+/// Builds the `Rtc` of a session, with the host candidate.
+///
+/// # Arguments
+///
+/// * `addr` - The socket address of the UDP port for WebRTC traffic
+/// * `credentials` - ICE credentials of a provisioned peer; random if `None`
+fn build_rtc(addr: SocketAddr, credentials: Option<IceCreds>) -> Result<Rtc, RoverRtcError> {
+    // PCMU carries the voice of consoles without an Opus encoder
+    let mut builder = Rtc::builder()
+        .enable_pcmu(true)
+        .set_stats_interval(Some(STATS_INTERVAL));
+    if let Some(credentials) = credentials {
+        builder = builder.set_local_ice_credentials(credentials);
+    }
+    let mut rtc = builder.build();
+    let candidate = Candidate::host(addr, "udp")?;
+    rtc.add_local_candidate(candidate)
+        .ok_or_else(|| RoverRtcError::Webrtc("the host candidate was rejected".into()))?;
+    Ok(rtc)
+}
+
+/// Answers the health probes of peers and the standby.
+fn health_request(signaling: &SignalingState) -> Response {
+    Response::json(&HealthStatus {
+        role: if signaling.failover.is_standby() {
+            "standby".into()
+        } else {
+            "primary".into()
+        },
+        sessions: signaling.directory.lock().expect("sessions lock").len(),
+    })
+}
+
+/// Hands the sessions of the connected clients to the standby.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request, with the failover secret as its
+///   bearer token
+/// * `signaling` - State shared with the signaling handlers
+/// * `secret` - The failover secret; the endpoint is disabled if `None`
+fn failover_state_request(
+    request: &Request,
+    signaling: &SignalingState,
+    secret: Option<&str>,
+) -> Response {
+    let Some(secret) = secret else {
+        return Response::empty_404();
+    };
+    if request.method() != "GET" {
+        return Response::empty_406();
+    }
+    if !token_matches(bearer_token(request).as_deref(), secret) {
+        return Response::text("unauthorized").with_status_code(401);
+    }
+    Response::json(&signaling.directory.lock().expect("sessions lock").records())
+}
+
+/// Copies the primary's sessions on a standby until shut down.
+///
+/// A copy that fails keeps the previous one, so peers can resume the
+/// sessions the primary had when it went down.
+///
+/// # Arguments
+///
+/// * `config` - The failover settings naming the primary
+/// * `secret` - The failover secret presented to the primary
+/// * `replicas` - Receives the copied sessions
+/// * `shutdown` - Stops the copies
+///
+/// # Returns
+///
+/// An error if the thread could not be spawned
+fn spawn_standby_sync(
+    config: &ServerFailoverConfig,
+    secret: Option<String>,
+    replicas: Arc<Mutex<SessionDirectory>>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let Some(secret) = secret else {
+        warn!("{} not set, no session can be resumed", FAILOVER_SECRET_ENV);
+        return Ok(());
+    };
+    let url = format!(
+        "{}{}",
+        config
+            .primary_url
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches('/'),
+        FAILOVER_STATE_PATH
+    );
+    let interval = Duration::from_secs(config.sync_secs);
+    let client = reqwest::blocking::Client::builder()
+        .timeout(interval.max(Duration::from_secs(1)))
+        .build()?;
+    thread::Builder::new()
+        .name("standby-sync".to_string())
+        .spawn(move || {
+            let mut reachable = true;
+            while !shutdown.is_triggered() {
+                let copied = client
+                    .get(&url)
+                    .bearer_auth(&secret)
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                    .and_then(|response| response.json::<Vec<SessionRecord>>());
+                match copied {
+                    Ok(records) => {
+                        if !reachable {
+                            info!("Primary reachable again, copying its sessions");
+                            reachable = true;
+                        }
+                        debug!("Copied {} sessions from the primary", records.len());
+                        replicas.lock().expect("replicas lock").replace(records);
+                    }
+                    Err(e) if reachable => {
+                        warn!("Primary unreachable, keeping its last sessions: {}", e);
+                        reachable = false;
+                    }
+                    Err(_) => {}
+                }
+                thread::sleep(interval);
+            }
+        })?;
+    Ok(())
+}
+
+/// Upgrades a signaling request to a WebSocket.
+///
+/// Access and alias are taken from the upgrade request as for HTTP signaling.
+/// The conversation itself runs on its own thread, see [`serve_websocket`].
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP upgrade request
+/// * `signaling` - State shared with the signaling handlers
+fn websocket_request(request: &Request, signaling: &Arc<SignalingState>) -> Response {
+    let access = match session_access(request, signaling) {
+        Ok(access) => access,
+        Err(response) => return response,
+    };
+    let alias = request.get_param("alias");
+
+    let (response, websocket) = match websocket::start(request, None::<&str>) {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            return Response::text(format!("websocket upgrade failed: {:?}", e))
+                .with_status_code(400)
+        }
+    };
+
+    let signaling = signaling.clone();
+    thread::spawn(move || {
+        // The websocket becomes available once the upgrade response is sent.
+        if let Ok(websocket) = websocket.recv() {
+            serve_websocket(websocket, access, alias, &signaling);
+        }
+    });
+    response
+}
+
+/// Runs a WebSocket signaling conversation.
+///
+/// The peer sends its offer, receives the answer, and then trickles candidates
+/// as it discovers them, for as long as the socket stays open. The server has
+/// no candidates beyond the one in its answer, so it signals the end of its
+/// candidates right away. A later restart offer is answered in-band.
+///
+/// # Arguments
+///
+/// * `websocket` - The upgraded connection
+/// * `access` - The access granted to the session
+/// * `alias` - The alias announced by the peer, if any
+/// * `signaling` - State shared with the signaling handlers
+fn serve_websocket(
+    mut websocket: Websocket,
+    access: Access,
+    alias: Option<String>,
+    signaling: &SignalingState,
+) {
+    let mut access = Some(access);
+    let mut session_token: Option<String> = None;
+
+    while let Some(message) = websocket.next() {
+        let websocket::Message::Text(text) = message else {
+            continue;
+        };
+        let reply = match serde_json::from_str::<SignalingMessage>(&text) {
+            Ok(SignalingMessage::Offer { offer }) => match access.take() {
+                Some(access) => match create_session(offer, access, alias.clone(), signaling) {
+                    Ok(answer) => {
+                        session_token = Some(answer.session_token.clone());
+                        send_signaling(&mut websocket, &SignalingMessage::Answer { answer });
+                        Some(SignalingMessage::EndOfCandidates)
+                    }
+                    Err(e) => {
+                        warn!("Failed to create a session: {}", e);
+                        Some(SignalingMessage::Error {
+                            message: e.to_string(),
+                        })
+                    }
+                },
+                None => Some(SignalingMessage::Error {
+                    message: "session already established".into(),
+                }),
+            },
+            Ok(SignalingMessage::Candidate { candidate }) => {
+                let parsed = Candidate::from_sdp_string(&resolve_candidate(signaling, &candidate));
+                match (&session_token, parsed) {
+                    (Some(token), Ok(candidate)) => {
+                        if signaling
+                            .candidates
+                            .send((token.clone(), candidate))
+                            .is_err()
+                        {
+                            break;
+                        }
+                        None
+                    }
+                    (None, _) => Some(SignalingMessage::Error {
+                        message: "candidate before offer".into(),
+                    }),
+                    (_, Err(_)) => Some(SignalingMessage::Error {
+                        message: "invalid candidate".into(),
+                    }),
+                }
+            }
+            Ok(SignalingMessage::EndOfCandidates) => {
+                debug!("Peer finished gathering candidates");
+                None
+            }
+            Ok(SignalingMessage::Restart { offer }) => match &session_token {
+                Some(token) => Some(match restart_session(signaling, token.clone(), offer) {
+                    Some(answer) => SignalingMessage::RestartAnswer { answer },
+                    None => SignalingMessage::Error {
+                        message: "ICE restart rejected".into(),
+                    },
+                }),
+                None => Some(SignalingMessage::Error {
+                    message: "restart before offer".into(),
+                }),
+            },
+            Ok(_) | Err(_) => Some(SignalingMessage::Error {
+                message: "unexpected message".into(),
+            }),
+        };
+        if let Some(reply) = reply {
+            send_signaling(&mut websocket, &reply);
+        }
+    }
+    debug!("Signaling websocket closed");
+}
+
+/// Sends a signaling message over a WebSocket, logging failures.
+fn send_signaling(websocket: &mut Websocket, message: &SignalingMessage) {
+    let text = serde_json::to_string(message).expect("signaling message to serialise");
+    if let Err(e) = websocket.send_text(&text) {
+        warn!("Failed to send signaling message: {:?}", e);
+    }
+}
+
+/// Handles candidates trickled by peers after signaling.
+///
+/// The body is a [`TrickleCandidate`]; the candidate is handed to the event
+/// loop, which adds it to the session identified by the token.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request
+/// * `signaling` - State shared with the signaling handlers
+fn trickle_request(request: &Request, signaling: &SignalingState) -> Response {
+    if request.method() != "POST" {
+        return Response::empty_404();
+    }
+    let Ok(body) = json_input::<TrickleCandidate>(request) else {
+        return Response::text("invalid candidate request").with_status_code(400);
+    };
+    let candidate = resolve_candidate(signaling, &body.candidate);
+    let Ok(candidate) = Candidate::from_sdp_string(&candidate) else {
+        return Response::text("invalid candidate").with_status_code(400);
+    };
+    if signaling
+        .candidates
+        .send((body.session_token, candidate))
+        .is_err()
+    {
+        return Response::text("event loop stopped").with_status_code(503);
+    }
+    Response::empty_204()
+}
+
+/// Strips the `a=` prefix of a trickled candidate and resolves its address
+/// if it is a `.local` name.
+fn resolve_candidate(signaling: &SignalingState, candidate: &str) -> String {
+    let candidate = candidate.strip_prefix("a=").unwrap_or(candidate);
+    match &signaling.mdns {
+        Some(mdns) if candidate.contains(".local ") => mdns.resolve_sdp(candidate),
+        _ => candidate.to_string(),
+    }
+}
+
+/// Handles ICE restart offers from peers whose network changed.
+///
+/// The body is a [`RestartOffer`]; the response is the bare SDP answer, or
+/// 404 if the session is unknown or rejected the offer.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request
+/// * `signaling` - State shared with the signaling handlers
+fn restart_request(request: &Request, signaling: &SignalingState) -> Response {
+    if request.method() != "POST" {
+        return Response::empty_404();
+    }
+    let Ok(body) = json_input::<RestartOffer>(request) else {
+        return Response::text("invalid restart request").with_status_code(400);
+    };
+    match restart_session(signaling, body.session_token, body.offer) {
+        Some(answer) => Response::json(&answer),
+        None => Response::text("unknown session").with_status_code(404),
+    }
+}
+
+/// Handles the mesh mode endpoints brokering offers between peers.
+///
+/// * `GET` [`MESH_OFFERS_PATH`] - A listening peer polls for the next offer
+///   under its `alias`; 204 if none arrived in time, 403 if the alias is not
+///   the peer's subject, 409 if another peer holds it
+/// * `POST` [`MESH_CONNECT_PATH`] - A peer sends its offer to the listener
+///   named by `target` and receives the bare SDP answer; 404 if nobody
+///   listens under that alias, 504 if the listener did not answer
+/// * `POST` [`MESH_ANSWERS_PATH`] - A listening peer sends a [`MeshAnswer`]
+///
+/// Every request is authenticated like a signaling request. Observers are
+/// refused, as the server could not drop the data they send directly.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request
+/// * `signaling` - State shared with the signaling handlers
+fn mesh_request(request: &Request, signaling: &SignalingState) -> Response {
+    let access = match session_access(request, signaling) {
+        Ok(access) => access,
+        Err(response) => return response,
+    };
+    if access.is_observer() {
+        return Response::text("observers cannot connect directly").with_status_code(403);
+    }
+
+    match (request.method(), request.url().as_str()) {
+        ("GET", MESH_OFFERS_PATH) => {
+            let Some(alias) = request.get_param("alias").filter(|a| is_valid_alias(a)) else {
+                return Response::text("a valid alias is required").with_status_code(400);
+            };
+            // The alias of an authenticated peer is its subject; other peers
+            // hold theirs by address, and guests may not listen at all
+            let holder = match &access.subject {
+                Some(subject) if *subject != alias => {
+                    return Response::text(format!("'{}' may only listen as itself", subject))
+                        .with_status_code(403);
+                }
+                Some(subject) => subject.clone(),
+                None if access.guest_id.is_some() => {
+                    return Response::text("guests cannot listen").with_status_code(403);
+                }
+                None => request.remote_addr().ip().to_string(),
+            };
+            match signaling
+                .broker
+                .poll_offer(&alias, &holder, OFFER_POLL_TIMEOUT)
+            {
+                Ok(Some(offer)) => {
+                    info!(
+                        "Brokering offer {} from {} to '{}'",
+                        offer.id,
+                        offer.from.as_deref().unwrap_or("an anonymous peer"),
+                        alias
+                    );
+                    Response::json(&offer)
+                }
+                Ok(None) => Response::empty_204(),
+                Err(e) => {
+                    warn!("Refusing to broker offers to '{}': {}", alias, e);
+                    Response::text(e.to_string()).with_status_code(409)
+                }
+            }
+        }
+        ("POST", MESH_CONNECT_PATH) => {
+            let Some(target) = request.get_param("target") else {
+                return Response::text("a target alias is required").with_status_code(400);
+            };
+            // Sent like a signaling offer, without a JSON content type
+            let offer = request
+                .data()
+                .and_then(|data| serde_json::from_reader::<_, SdpOffer>(data).ok());
+            let Some(offer) = offer else {
+                return Response::text("invalid offer").with_status_code(400);
+            };
+            // Only an authenticated subject names the offering peer
+            let from = access.subject.clone();
+            match signaling
+                .broker
+                .connect(&target, from, offer, ANSWER_TIMEOUT)
+            {
+                Ok(answer) => Response::json(&answer),
+                Err(e) => {
+                    warn!("Failed to broker offer to '{}': {}", target, e);
+                    let status = match e {
+                        BrokerError::UnknownTarget(_) | BrokerError::AliasHeld(_) => 404,
+                        BrokerError::Timeout => 504,
+                    };
+                    Response::text(e.to_string()).with_status_code(status)
+                }
+            }
+        }
+        ("POST", MESH_ANSWERS_PATH) => {
+            let Ok(body) = json_input::<MeshAnswer>(request) else {
+                return Response::text("invalid answer").with_status_code(400);
+            };
+            if signaling.broker.answer(&body.id, body.answer) {
+                Response::empty_204()
+            } else {
+                Response::text("unknown or expired offer").with_status_code(404)
+            }
+        }
+        _ => Response::empty_404(),
+    }
+}
+
+/// Hands an ICE restart offer to the event loop and waits for the answer.
+///
+/// # Returns
+///
+/// The answer, or `None` if the session is unknown, rejected the offer or the
+/// event loop did not answer in time
+fn restart_session(
+    signaling: &SignalingState,
+    session_token: String,
+    offer: SdpOffer,
+) -> Option<SdpAnswer> {
+    let offer = match &signaling.mdns {
+        Some(mdns) => mdns.resolve_offer(offer),
+        None => offer,
+    };
+    let (reply, answer) = mpsc::channel();
+    signaling
+        .restarts
+        .send(RestartRequest {
+            session_token,
+            offer,
+            reply,
+        })
+        .ok()?;
+    answer.recv_timeout(RESTART_REPLY_TIMEOUT).ok().flatten()
+}
+
+/// Attempts to receive new clients from the channel and create Client instances.
+///
+/// Uses `try_recv` to avoid blocking the main thread. The client is registered
+/// under the alias its peer announced, made unique if necessary.
+///
+/// # Arguments
+///
+/// * `rx` - The receiver channel for new sessions
+/// * `registry` - The registry the new client is added to
+/// * `protocol` - The protocol settings, for the client's legacy interop
+/// * `recorder` - Records the client's data channel traffic, if enabled
+///
+/// # Returns
+///
+/// * `Ok(Some(Client))` - A new client instance if one was received
+/// * `Ok(None)` - If no client is available in the channel
+/// * `Err(RoverRtcError::Disconnected)` - If the web server thread has gone away
+fn spawn_new_client(
+    rx: &mut UnboundedReceiver<NewSession>,
+    registry: &Mutex<ClientRegistry>,
+    config: &ServerConfig,
+    recorder: Option<&Recorder>,
+) -> Result<Option<Client>, RoverRtcError> {
+    // try_recv here won't lock up the thread.
+    match rx.try_recv() {
+        Ok(session) => {
+            let mut client = Client::new(
+                session.id,
+                session.rtc,
+                session.access,
+                session.session_token,
+            );
+            let alias = registry.lock().expect("registry lock").register(
+                client.id,
+                session.alias.as_deref(),
+                client.access.room.clone(),
+            );
+            client.set_alias(alias);
+            client.setup = session.setup;
+            client.session = session.session;
+            client.legacy_interop = config.protocol.legacy_payloads;
+            client.link = LinkMonitor::new(config.protocol.heartbeat.clone());
+            client.set_send_queue(config.send_queue.clone());
+            client.set_transfer(&config.transfer);
+            client.set_recorder(recorder.cloned());
+            for (label, options) in &config.channels {
+                client.open_channel(label, options);
+            }
+            Ok(Some(client))
+        }
+        Err(TryRecvError::Empty) => Ok(None),
+        Err(TryRecvError::Disconnected) => Err(RoverRtcError::Disconnected("web server")),
+    }
+}
+
+/// Returns the server's ufrag a STUN request of a provisioned peer is meant
+/// for.
+fn provisioned_ufrag(provisioning: &ProvisioningConfig, buf: &[u8]) -> Option<String> {
+    if provisioning.peers.is_empty() {
+        return None;
+    }
+    match classify(buf) {
+        PacketClass::Stun { ufrag: Some(ufrag) } if provisioning.expects(&ufrag) => Some(ufrag),
+        _ => None,
+    }
+}
+
+/// Hands a new client the packets received before its session arrived, and
+/// primes the demultiplexing index with their sources.
+///
+/// # Arguments
+///
+/// * `client` - The new client
+/// * `held` - Packets of provisioned peers waiting for their sessions
+/// * `index` - The demultiplexing index
+fn replay_held(client: &mut Client, held: &mut HeldPackets, index: &mut DemuxIndex) {
+    let packets = held.take(client.local_ufrag());
+    if packets.is_empty() {
+        return;
+    }
+    info!(
+        "Replaying {} packet(s) received before the session of {}",
+        packets.len(),
+        client.name()
+    );
+    for packet in &packets {
+        let Ok(contents) = packet.data.as_slice().try_into() else {
+            continue;
+        };
+        let input = Input::Receive(
+            packet.received,
+            Receive {
+                proto: Protocol::Udp,
+                source: packet.source,
+                destination: packet.destination,
+                contents,
+            },
+        );
+        if client.accepts(&input) {
+            index.learn(packet.source, client.id);
+            client.count_received(packet.data.len());
+            client.handle_input(input);
+        }
+    }
+}
+
+/// Determines how many worker threads poll clients in parallel.
+///
+/// Uses the configured count, defaulting to the available parallelism capped
+/// at [`MAX_POLL_WORKERS`].
+fn poll_worker_count(configured: Option<usize>) -> usize {
+    configured
+        .unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+                .min(MAX_POLL_WORKERS)
+        })
+        .max(1)
+}
+
+/// A client handed to a polling worker, with its position in the event
+/// loop's list.
+struct PollJob {
+    index: usize,
+    client: Client,
+    max_polls: usize,
+    now: Instant,
+}
+
+/// A client back from a polling worker, with the outcome of its poll.
+type PolledClient = (usize, Client, thread::Result<Option<Instant>>);
+
+/// Long-lived threads polling clients for the event loop, see [`poll_clients`].
+///
+/// The workers are started with the loop and pull clients from one shared
+/// queue; they end once the pool is dropped.
+struct PollPool {
+    workers: usize,
+    jobs: mpsc::Sender<PollJob>,
+    done: UnboundedReceiver<PolledClient>,
+}
+
+impl PollPool {
+    /// Starts the workers, none if polling stays on the event loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `workers` - The number of worker threads
+    /// * `transport` - Where the workers send outgoing traffic
+    fn new(workers: usize, transport: &Arc<dyn Transport>) -> io::Result<Self> {
+        let (jobs, queue) = mpsc::channel::<PollJob>();
+        let (finished, done) = unbounded_channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let threads = if workers > 1 { workers } else { 0 };
+        for worker in 0..threads {
+            let queue = queue.clone();
+            let finished = finished.clone();
+            let transport = transport.clone();
+            thread::Builder::new()
+                .name(format!("poll-{}", worker))
+                .spawn(move || loop {
+                    let Ok(mut job) = queue.lock().expect("poll queue lock").recv() else {
+                        break;
+                    };
+                    // Hand the client back even if polling it panicked
+                    let polled = panic::catch_unwind(AssertUnwindSafe(|| {
+                        poll_client(&mut job.client, transport.as_ref(), job.max_polls, job.now)
+                    }));
+                    if finished.send((job.index, job.client, polled)).is_err() {
+                        break;
+                    }
+                })?;
+        }
+        Ok(Self {
+            workers,
+            jobs,
+            done,
+        })
+    }
+}
+
+/// Polls all clients and returns the earliest timeout.
+///
+/// With enough clients, polling is spread over the workers of the pool, which
+/// pull clients from a shared queue, so a client with a heavy transmit burst
+/// only occupies one worker while the others keep draining the rest. Each
+/// client is polled by exactly one worker, preserving its output ordering,
+/// and the clients keep their order. The workers' results are awaited, so
+/// the runtime's thread is free while they poll.
+///
+/// # Arguments
+///
+/// * `core` - The clients to poll and where their traffic goes
+/// * `pool` - The polling workers
+/// * `max_polls` - The outputs polled from each client at most
+///
+/// # Returns
+///
+/// The earliest timeout across all clients, capped at 100ms from now, and
+/// whether a client used up its budget
+async fn poll_clients(
+    core: &mut LoopCore,
+    pool: &mut PollPool,
+    max_polls: usize,
+) -> (Instant, bool) {
+    if pool.workers <= 1 || core.clients.len() < PARALLEL_POLL_THRESHOLD {
+        return core.poll(max_polls);
+    }
+
+    let now = core.now();
+    let count = core.clients.len();
+    for (index, client) in core.clients.drain(..).enumerate() {
+        let job = PollJob {
+            index,
+            client,
+            max_polls,
+            now,
+        };
+        pool.jobs.send(job).expect("poll workers running");
+    }
+    let mut timeouts = TimeoutCollector::new(now);
+    let mut polled: Vec<Option<Client>> = (0..count).map(|_| None).collect();
+    for _ in 0..count {
+        let (index, client, result) = pool.done.recv().await.expect("poll workers running");
+        polled[index] = Some(client);
+        match result {
+            Ok(timeout) => timeouts.collect(timeout, now),
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+    core.clients
+        .extend(polled.into_iter().map(|c| c.expect("polled client")));
+    (timeouts.earliest, timeouts.exhausted)
+}
+
+/// The earliest timeout of the clients polled so far.
+struct TimeoutCollector {
+    earliest: Instant,
+    exhausted: bool,
+}
+
+impl TimeoutCollector {
+    /// Starts with the default timeout, 100ms from now.
+    fn new(now: Instant) -> Self {
+        Self {
+            earliest: now + Duration::from_millis(100),
+            exhausted: false,
+        }
+    }
+
+    /// Adds the outcome of polling a client, `None` if it used up its budget.
+    fn collect(&mut self, polled: Option<Instant>, now: Instant) {
+        match polled {
+            Some(timeout) => self.earliest = self.earliest.min(timeout),
+            None => {
+                self.exhausted = true;
+                self.earliest = now;
+            }
+        }
+    }
+}
+
+/// Polls a client for output events and handles them until a timeout is returned.
+///
+/// This function processes the available output from the client (transmit events)
+/// and returns when the next timeout should occur, or once `max_polls` outputs
+/// were handled, leaving the rest for the next iteration of the event loop.
+///
+/// # Arguments
+///
+/// * `client` - The client to poll
+/// * `transport` - Where outgoing traffic is sent
+/// * `max_polls` - The outputs handled at most
+/// * `now` - The current time
+///
+/// # Returns
+///
+/// The instant at which the next timeout should occur, or `None` if the
+/// client used up its budget
+fn poll_client(
+    client: &mut Client,
+    transport: &dyn Transport,
+    max_polls: usize,
+    now: Instant,
+) -> Option<Instant> {
+    for _ in 0..max_polls {
+        if !client.rtc.is_alive() {
+            // This client will be cleaned up in the next run of the main loop.
+            return Some(now);
+        }
+
+        if let Some(timeout) = client.poll_output(transport) {
+            return Some(timeout);
+        }
+    }
+    debug!("{} used its poll budget, yielding", client.name());
+    None
+}
+
+/// Returns the name a client is logged under, its alias included, or just its
+/// ID once it departed.
+fn client_name(clients: &[Client], id: ClientId) -> String {
+    clients
+        .iter()
+        .find(|c| c.id == id)
+        .map_or_else(|| format!("Client({})", id), |c| c.name().to_string())
+}
+
+/// Checks the health of all clients and attempts recovery if needed
+///
+/// This function monitors connection health and can initiate recovery attempts
+/// for degraded connections, evicting the dead clients.
+///
+/// # Arguments
+///
+/// * `clients` - The list of all clients
+/// * `health` - Mutable reference to the health tracking map
+/// * `policy` - The policy telling degraded and dead connections
+///
+/// # Returns
+///
+/// The clients whose health state changed, with their new state
+fn check_client_health(
+    clients: &mut [Client],
+    health: &mut HashMap<u64, ConnectionHealth>,
+    policy: &dyn HealthPolicy,
+) -> Vec<(ClientId, HealthState)> {
+    let mut changes = vec![];
+    for client in clients {
+        let Some(h) = health.get_mut(&*client.id) else {
+            continue;
+        };
+        if client.features.contains(Feature::Heartbeat) {
+            h.mark_link(&client.link);
+        }
+
+        // Check if client needs recovery
+        let previous = h.state();
+        let action = h.check(policy);
+        if h.state() != previous {
+            changes.push((client.id, h.state()));
+        }
+        if action != HealthAction::None {
+            match h.link() {
+                Some(link) => warn!(
+                    "{} connection health degraded. \
+                    {} heartbeat(s) in a row unanswered, loss {:.0}%",
+                    client.name(),
+                    link.missed,
+                    link.loss * 100.0
+                ),
+                None => warn!(
+                    "{} connection health degraded. \
+                    Last activity: {:?} ago",
+                    client.name(),
+                    h.idle()
+                ),
+            }
+        }
+        match action {
+            HealthAction::None => {}
+            HealthAction::Recover { attempt } => attempt_connection_recovery(client, attempt),
+            HealthAction::GiveUp => {
+                if client.rtc.is_alive() {
+                    warn!("{} is dead, evicting it", client.name());
+                    client.evict("connection health lost");
+                }
+            }
+        }
+
+        // Log connection state for monitoring (every health check)
+        if let Some(link) = h.link() {
+            debug!(
+                "{} RTT {:.1} ms, jitter {:.1} ms, loss {:.0}%",
+                client.name(),
+                link.rtt_ms.unwrap_or(0.0),
+                link.jitter_ms.unwrap_or(0.0),
+                link.loss * 100.0
+            );
+        } else if h.idle() > Duration::from_secs(5) {
+            info!(
+                "{} inactive for {:?}, Failures: {}",
+                client.name(),
+                h.idle(),
+                h.consecutive_failures()
+            );
+        }
+    }
+    changes
+}
+
+/// Attempts to recover a degraded connection
+///
+/// The server cannot reach a peer whose network changed, so the handover is
+/// driven by the peer: it restarts ICE through the signaling endpoint (see
+/// [`restart_request`]), which resets the attempt counter. Until then, this
+/// only keeps the session alive for a few more health checks.
+///
+/// # Arguments
+///
+/// * `client` - The client to recover
+/// * `attempt` - The number of this attempt, from 1
+fn attempt_connection_recovery(client: &Client, attempt: u32) {
+    info!(
+        "Waiting for {} to restart ICE (check {})",
+        client.name(),
+        attempt
+    );
+}
+
+/// Disconnects guest sessions whose link has expired or been revoked.
+///
+/// # Arguments
+///
+/// * `clients` - Mutable reference to the list of all clients
+/// * `guests` - Authority holding the revoked guest links
+fn enforce_guest_access(clients: &mut [Client], guests: &Mutex<GuestAuthority>) {
+    let guests = guests.lock().expect("guest authority lock");
+    for client in clients.iter_mut() {
+        if client.rtc.is_alive() && !guests.still_valid(&client.access) {
+            info!(
+                "{} guest access expired or revoked, disconnecting",
+                client.name()
+            );
+            client.evict("guest access expired or revoked");
+        }
+    }
+}
+
+/// Logs a change to the network interfaces, warning if it affects the
+/// address the UDP socket is bound to.
+///
+/// The socket is not rebound: established sessions would lose their path, so
+/// the operator decides whether to restart the server on the new address.
+///
+/// # Arguments
+///
+/// * `event` - The change
+/// * `bound_ip` - The address of the UDP socket, if known
+fn handle_network_event(event: &NetworkEvent, bound_ip: Option<IpAddr>) {
+    match event {
+        NetworkEvent::AddressRemoved { interface, addr } if Some(*addr) == bound_ip => {
+            warn!(
+                "UDP host address {} was removed from {}; peers cannot reach the server until it returns",
+                addr, interface
+            );
+        }
+        NetworkEvent::AddressAdded { interface, addr } if Some(*addr) == bound_ip => {
+            info!("UDP host address {} is back on {}", addr, interface);
+        }
+        other => info!("Network changed: {:?}", other),
+    }
+}
+
+/// Disconnects clients whose session expired and warns those about to expire.
+///
+/// # Arguments
+///
+/// * `clients` - Mutable reference to the list of all clients
+/// * `config` - The session settings
+fn expire_sessions(clients: &mut [Client], config: &SessionConfig) {
+    let now = Utc::now().timestamp();
+    for client in clients.iter_mut() {
+        if !client.rtc.is_alive() {
+            continue;
+        }
+        if client.session.is_expired(now) {
+            info!("{} session expired, disconnecting", client.name());
+            client.evict("session expired");
+        } else if let Some(warning) = client.session.take_warning(now, config) {
+            debug!("Asking {} to refresh its session", client.name());
+            if !client.send_session(&warning) {
+                warn!(
+                    "{} has no session channel, it will be disconnected at expiry",
+                    client.name()
+                );
+            }
+        }
+    }
+}
+
+/// Handles session refresh requests received from the clients.
+///
+/// A refresh is accepted if it carries the current refresh token and the
+/// authentication backend accepts the peer again; the session is then
+/// extended and the token rotated.
+///
+/// # Arguments
+///
+/// * `clients` - Mutable reference to the list of all clients
+/// * `auth` - The backend that authenticated the sessions
+/// * `config` - The session settings
+fn refresh_sessions(clients: &mut [Client], auth: &dyn AuthBackend, config: &SessionConfig) {
+    for client in clients.iter_mut() {
+        for message in client.take_session_messages() {
+            let SessionMessage::Refresh {
+                refresh_token,
+                credential,
+            } = message
+            else {
+                warn!("{} sent an unexpected session message", client.name());
+                continue;
+            };
+            let result = if !client.session.check_token(&refresh_token) {
+                Err("invalid refresh token".to_string())
+            } else {
+                auth.reauthenticate(&client.access, credential.as_deref())
+                    .map_err(|e| e.to_string())
+            };
+            let reply = match result {
+                Ok(()) => {
+                    info!("{} refreshed its session", client.name());
+                    client.session.extend(config)
+                }
+                Err(reason) => {
+                    warn!("Refused to refresh {} session: {}", client.name(), reason);
+                    SessionMessage::Rejected { reason }
+                }
+            };
+            client.send_session(&reply);
+        }
+    }
+}
+
+/// Body of a guest link creation request.
+#[derive(Debug, Deserialize)]
+struct GuestLinkRequest {
+    room: String,
+    ttl_secs: i64,
+}
+
+/// Body of a replay request.
+#[derive(Debug, Deserialize)]
+struct ReplayRequest {
+    path: String,
+    room: String,
+    #[serde(default = "default_speed")]
+    speed: f64,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+/// Body of a room broadcast request.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BroadcastBody {
+    message: String,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+/// Body of a packet capture request.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CaptureRequest {
+    #[serde(default = "default_capture_secs")]
+    duration_secs: u32,
+    #[serde(default = "default_capture_bytes")]
+    max_bytes: u64,
+}
+
+fn default_capture_secs() -> u32 {
+    30
+}
+
+fn default_capture_bytes() -> u64 {
+    8 * 1024 * 1024
+}
+
+/// Body of a manual block request.
+#[derive(Debug, Deserialize)]
+struct BlockRequest {
+    ip: IpAddr,
+    #[serde(default)]
+    duration_secs: Option<u64>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Body of a request starting the file tap.
+#[derive(Debug, Deserialize)]
+struct PcapRequest {
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    max_bytes: Option<u64>,
+}
+
+/// Handles requests to the admin API.
+///
+/// Every request must carry the admin bearer token. Clients are addressed by
+/// numeric ID or alias. Supported routes:
+/// - `POST /admin/guest-links` with `{"room": ..., "ttl_secs": ...}` issues a guest link
+/// - `DELETE /admin/guest-links/{id}` revokes a guest link
+/// - `POST /admin/replays` with `{"path": ..., "room": ..., "speed": ...}` replays a recording
+/// - `POST /admin/rooms/{room}/broadcast` with `{"message": ..., "timeout_ms": ...}` sends an
+///   announcement to every client of a room and returns who acknowledged it
+/// - `GET /admin/clients` lists the connected clients with their aliases, rooms, health states
+///   and current connection statistics
+/// - `GET /admin/events?topics=lifecycle,health` upgrades to a WebSocket streaming the
+///   server's events of the given topics as JSON, every topic if unset
+/// - `GET /admin/state` dumps the state of the server and every client, with their recent
+///   significant events
+/// - `GET /clients/{id}/stats?window=15m` returns a client's metric time series
+///   and negotiated protocol version and features
+/// - `GET /clients/{id}/connection` returns a client's current connection statistics
+/// - `GET /clients/{id}/setup` returns the time spent in each phase of a client's setup
+/// - `POST /clients/{id}/messages` sends the request body as a text message to a client
+/// - `DELETE /clients/{id}` closes a client's session
+/// - `POST /clients/{id}/restart` asks a client's peer to restart ICE; only peers
+///   advertising `remote_restart` follow it
+/// - `POST /clients/{id}/capture` with `{"duration_secs": ..., "max_bytes": ...}` starts a
+///   capture of the peer's UDP traffic; `DELETE` ends it early
+/// - `GET /clients/{id}/capture` returns the state of the client's last capture
+/// - `GET /clients/{id}/capture.pcap` downloads the finished capture
+/// - `POST /clients/{id}/logs` with `{"tail": ..., "contains": ..., "level": ...,
+///   "since_secs": ..., "compress": ...}` returns the peer's recent log lines
+/// - `GET /admin/blocklist` lists blocked source addresses
+/// - `POST /admin/blocklist` with `{"ip": ..., "duration_secs": ..., "reason": ...}` blocks an address
+/// - `DELETE /admin/blocklist/{ip}` unblocks an address
+/// - `GET /admin/log/events` returns the event log verbosity per event kind
+/// - `PUT /admin/log/events` with a body like `channel_data=sample:100` changes it
+/// - `GET /admin/pcap` returns the state of the pcapng file tap
+/// - `POST /admin/pcap` with `{"path": ..., "max_bytes": ...}` starts writing the server's
+///   UDP traffic to a pcapng file, by default the one of `[network.pcap]`
+/// - `DELETE /admin/pcap` stops writing it
+/// - `GET /admin/netsim` returns the simulated network impairment and what it did
+/// - `PUT /admin/netsim` with a `[network.netsim]` section as JSON replaces it;
+///   `{"enabled": false}` turns it off
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request
+/// * `admin` - State shared with the admin API
+fn admin_request(request: &Request, admin: &AdminState) -> Response {
+    let Some(expected) = admin.token.as_deref() else {
+        return Response::empty_404();
+    };
+    if !token_matches(bearer_token(request).as_deref(), expected) {
+        return Response::text("unauthorized").with_status_code(401);
+    }
+
+    let url = request.url();
+    match (request.method(), url.as_str()) {
+        ("POST", "/admin/guest-links") => {
+            let Ok(body) = json_input::<GuestLinkRequest>(request) else {
+                return Response::text("invalid guest link request").with_status_code(400);
+            };
+            if body.ttl_secs <= 0 || body.ttl_secs > MAX_TTL_SECS {
+                return Response::text(format!(
+                    "ttl_secs must be positive and at most {}",
+                    MAX_TTL_SECS
+                ))
+                .with_status_code(400);
+            }
+            let link = admin
+                .shared
+                .guests
+                .lock()
+                .expect("guest authority lock")
+                .issue(&body.room, body.ttl_secs);
+            info!(
+                "Issued guest link {} for room '{}' expiring at {}",
+                link.id, link.room, link.expires_at
+            );
+            Response::json(&link)
+        }
+        ("DELETE", path) if path.starts_with("/admin/guest-links/") => {
+            let id = &path["/admin/guest-links/".len()..];
+            admin
+                .shared
+                .guests
+                .lock()
+                .expect("guest authority lock")
+                .revoke(id);
+            info!("Revoked guest link {}", id);
+            Response::empty_204()
+        }
+        ("POST", "/admin/replays") => {
+            let Ok(body) = json_input::<ReplayRequest>(request) else {
+                return Response::text("invalid replay request").with_status_code(400);
+            };
+            let messages = match read_recording(Path::new(&body.path)) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    return Response::text(format!("unable to read recording: {}", e))
+                        .with_status_code(400)
+                }
+            };
+            info!(
+                "Replaying {} messages from {} into room '{}' at {}x",
+                messages.len(),
+                body.path,
+                body.room,
+                body.speed
+            );
+            let replay = ReplaySession::new(body.room, messages, body.speed);
+            if admin.replays.send(replay).is_err() {
+                return Response::text("event loop stopped").with_status_code(503);
+            }
+            Response::empty_204()
+        }
+        ("GET", "/admin/log/events") => Response::json(&event_log::current_config()),
+        ("PUT", "/admin/log/events") => {
+            let mut spec = String::new();
+            if let Some(mut data) = request.data() {
+                if data.read_to_string(&mut spec).is_err() {
+                    return Response::text("invalid body").with_status_code(400);
+                }
+            }
+            match event_log::apply_config(&spec) {
+                Ok(()) => {
+                    info!("Event log configuration changed: {}", spec.trim());
+                    Response::json(&event_log::current_config())
+                }
+                Err(e) => Response::text(e).with_status_code(400),
+            }
+        }
+        ("GET", "/admin/pcap") => Response::json(&pcap::file_status()),
+        ("POST", "/admin/pcap") => {
+            let Ok(body) = json_input::<PcapRequest>(request) else {
+                return Response::text("invalid pcap request").with_status_code(400);
+            };
+            let config = PcapConfig {
+                enabled: true,
+                path: body.path.unwrap_or_else(|| admin.pcap.path.clone()),
+                max_bytes: body.max_bytes.unwrap_or(admin.pcap.max_bytes),
+            };
+            if let Err(e) = config.validate() {
+                return Response::text(e).with_status_code(400);
+            }
+            if let Err(e) = pcap::start_file(&config.path, config.max_bytes) {
+                return Response::text(format!("unable to create the file: {}", e))
+                    .with_status_code(500);
+            }
+            info!("Writing UDP traffic to {}", config.path.display());
+            Response::json(&pcap::file_status())
+        }
+        ("DELETE", "/admin/pcap") => {
+            let tap = pcap::stop_file();
+            if let Some(tap) = &tap {
+                info!("Wrote {} datagrams to {}", tap.packets, tap.path.display());
+            }
+            Response::json(&tap)
+        }
+        ("GET", "/admin/netsim") => Response::json(&netsim::status()),
+        ("PUT", "/admin/netsim") => {
+            let Ok(config) = json_input::<NetsimConfig>(request) else {
+                return Response::text("invalid netsim configuration").with_status_code(400);
+            };
+            if let Err(e) = config.validate() {
+                return Response::text(e).with_status_code(400);
+            }
+            if config.enabled {
+                warn!("Simulating an impaired network: {:?}", config);
+            } else {
+                info!("Network impairment turned off");
+            }
+            netsim::configure(&config);
+            Response::json(&netsim::status())
+        }
+        ("GET", "/admin/blocklist") => Response::json(
+            &admin
+                .shared
+                .blocklist
+                .lock()
+                .expect("blocklist lock")
+                .list(),
+        ),
+        ("POST", "/admin/blocklist") => {
+            let Ok(body) = json_input::<BlockRequest>(request) else {
+                return Response::text("invalid block request").with_status_code(400);
+            };
+            let blocked = admin
+                .shared
+                .blocklist
+                .lock()
+                .expect("blocklist lock")
+                .block(
+                    body.ip,
+                    body.reason
+                        .unwrap_or_else(|| "blocked by administrator".into()),
+                    body.duration_secs.map(Duration::from_secs),
+                );
+            if let Err(e) = blocked {
+                return Response::text(e).with_status_code(400);
+            }
+            info!("Manually blocked {}", body.ip);
+            Response::empty_204()
+        }
+        ("DELETE", path) if path.starts_with("/admin/blocklist/") => {
+            let Ok(ip) = path["/admin/blocklist/".len()..].parse::<IpAddr>() else {
+                return Response::text("invalid address").with_status_code(400);
+            };
+            if admin
+                .shared
+                .blocklist
+                .lock()
+                .expect("blocklist lock")
+                .unblock(ip)
+            {
+                info!("Unblocked {}", ip);
+                Response::empty_204()
+            } else {
+                Response::empty_404()
+            }
+        }
+        ("POST", path) if path.starts_with("/admin/rooms/") && path.ends_with("/broadcast") => {
+            let Some(room) = path
+                .strip_prefix("/admin/rooms/")
+                .and_then(|p| p.strip_suffix("/broadcast"))
+                .filter(|room| !room.is_empty())
+            else {
+                return Response::empty_404();
+            };
+            let body = match json_input::<BroadcastBody>(request) {
+                Ok(body) => body,
+                Err(e) => return Response::text(e.to_string()).with_status_code(400),
+            };
+            let timeout = body
+                .timeout_ms
+                .map_or(DEFAULT_ACK_TIMEOUT, Duration::from_millis);
+            if timeout.is_zero() || timeout > MAX_BROADCAST_TIMEOUT {
+                return Response::text(format!(
+                    "timeout_ms must be positive and at most {}",
+                    MAX_BROADCAST_TIMEOUT.as_millis()
+                ))
+                .with_status_code(400);
+            }
+            let (reply, answer) = mpsc::channel();
+            let broadcast = BroadcastRequest {
+                room: room.to_string(),
+                data: body.message.into_bytes(),
+                timeout,
+                reply,
+            };
+            if admin.broadcasts.send(broadcast).is_err() {
+                return Response::text("event loop stopped").with_status_code(503);
+            }
+            match answer.recv_timeout(timeout + BROADCAST_REPLY_MARGIN) {
+                Ok(report) => Response::json(&report),
+                Err(_) => Response::text("event loop did not answer").with_status_code(504),
+            }
+        }
+        ("GET", "/admin/clients") => {
+            let entries = admin.shared.registry.lock().expect("registry lock").list();
+            let health = admin.shared.health.lock().expect("health lock").clone();
+            let connections = admin
+                .shared
+                .connections
+                .lock()
+                .expect("connections lock")
+                .clone();
+            let listings: Vec<_> = entries
+                .into_iter()
+                .map(|entry| ClientListing {
+                    state: health.get(&entry.id).copied(),
+                    connection: connections.get(&entry.id).cloned(),
+                    entry,
+                })
+                .collect();
+            Response::json(&listings)
+        }
+        ("GET", "/admin/events") => event_stream(request, admin),
+        ("GET", "/admin/state") => {
+            let (reply, state) = mpsc::channel();
+            if admin.states.send(reply).is_err() {
+                return Response::text("event loop stopped").with_status_code(503);
+            }
+            match state.recv_timeout(STATE_REPLY_TIMEOUT) {
+                Ok(state) => Response::json(&state),
+                Err(_) => Response::text("the event loop did not answer").with_status_code(504),
+            }
+        }
+        ("GET", path) if path.starts_with("/clients/") && path.ends_with("/stats") => {
+            let Some(key) = path
+                .strip_prefix("/clients/")
+                .and_then(|p| p.strip_suffix("/stats"))
+            else {
+                return Response::empty_404();
+            };
+            let (id, alias) = {
+                let registry = admin.shared.registry.lock().expect("registry lock");
+                let Some(id) = registry.resolve(key) else {
+                    return Response::empty_404();
+                };
+                (id, registry.alias(id).map(str::to_string))
+            };
+            let window = request.get_param("window").unwrap_or_else(|| "15m".into());
+            let Some(window) = parse_window(&window) else {
+                return Response::text("invalid window").with_status_code(400);
+            };
+            match admin.shared.stats.lock().expect("stats lock").get(&*id) {
+                Some(history) => Response::json(&history.window(*id, alias, window)),
+                None => Response::empty_404(),
+            }
+        }
+        ("GET", path) if path.starts_with("/clients/") && path.ends_with("/connection") => {
+            let Some(key) = path
+                .strip_prefix("/clients/")
+                .and_then(|p| p.strip_suffix("/connection"))
+            else {
+                return Response::empty_404();
+            };
+            let Some(id) = admin
+                .shared
+                .registry
+                .lock()
+                .expect("registry lock")
+                .resolve(key)
+            else {
+                return Response::empty_404();
+            };
+            let connections = admin.shared.connections.lock().expect("connections lock");
+            match connections.get(&*id) {
+                Some(stats) => Response::json(stats),
+                None => Response::empty_404(),
+            }
+        }
+        ("GET", path) if path.starts_with("/clients/") && path.ends_with("/setup") => {
+            let Some(key) = path
+                .strip_prefix("/clients/")
+                .and_then(|p| p.strip_suffix("/setup"))
+            else {
+                return Response::empty_404();
+            };
+            let Some(id) = admin
+                .shared
+                .registry
+                .lock()
+                .expect("registry lock")
+                .resolve(key)
+            else {
+                return Response::empty_404();
+            };
+            match admin.shared.setup.lock().expect("setup lock").get(&*id) {
+                Some(breakdown) => Response::json(breakdown),
+                None => Response::empty_404(),
+            }
+        }
+        ("POST", path) if path.starts_with("/clients/") && path.ends_with("/messages") => {
+            let Some(key) = path
+                .strip_prefix("/clients/")
+                .and_then(|p| p.strip_suffix("/messages"))
+            else {
+                return Response::empty_404();
+            };
+            let Some(id) = admin
+                .shared
+                .registry
+                .lock()
+                .expect("registry lock")
+                .resolve(key)
+            else {
+                return Response::empty_404();
+            };
+            let mut message = String::new();
+            if let Some(mut data) = request.data() {
+                if data.read_to_string(&mut message).is_err() {
+                    return Response::text("invalid body").with_status_code(400);
+                }
+            }
+            if admin.messages.send((id, message)).is_err() {
+                return Response::text("event loop stopped").with_status_code(503);
+            }
+            Response::empty_204()
+        }
+        ("DELETE", path)
+            if path.starts_with("/clients/") && !path["/clients/".len()..].contains('/') =>
+        {
+            client_command(admin, &path["/clients/".len()..], ClientCommand::Disconnect)
+        }
+        ("POST", path) if path.starts_with("/clients/") && path.ends_with("/restart") => {
+            let Some(key) = path
+                .strip_prefix("/clients/")
+                .and_then(|p| p.strip_suffix("/restart"))
+            else {
+                return Response::empty_404();
+            };
+            client_command(admin, key, ClientCommand::RestartIce)
+        }
+        (method, path) if path.starts_with("/clients/") && path.ends_with("/capture") => {
+            let Some(key) = path
+                .strip_prefix("/clients/")
+                .and_then(|p| p.strip_suffix("/capture"))
+            else {
+                return Response::empty_404();
+            };
+            let Some(id) = admin
+                .shared
+                .registry
+                .lock()
+                .expect("registry lock")
+                .resolve(key)
+            else {
+                return Response::empty_404();
+            };
+            let status = admin
+                .shared
+                .captures
+                .lock()
+                .expect("captures lock")
+                .get(&*id)
+                .cloned();
+            let running = matches!(
+                status,
+                Some(CaptureStatus::Running { .. } | CaptureStatus::Stopping { .. })
+            );
+            let command = match method {
+                "GET" => {
+                    return match status {
+                        Some(status) => Response::json(&status),
+                        None => Response::empty_404(),
+                    }
+                }
+                "POST" if running => {
+                    return Response::text("a capture is already running").with_status_code(409)
+                }
+                "POST" => {
+                    let body = match json_input::<CaptureRequest>(request) {
+                        Ok(body) => body,
+                        Err(e) => return Response::text(e.to_string()).with_status_code(400),
+                    };
+                    if body.duration_secs == 0 || body.max_bytes == 0 {
+                        return Response::text("duration_secs and max_bytes must be positive")
+                            .with_status_code(400);
+                    }
+                    if body.max_bytes > MAX_CAPTURE_SIZE {
+                        return Response::text(format!(
+                            "max_bytes must not exceed {}",
+                            MAX_CAPTURE_SIZE
+                        ))
+                        .with_status_code(400);
+                    }
+                    CaptureCommand::Start {
+                        id,
+                        duration_secs: body.duration_secs,
+                        max_bytes: body.max_bytes,
+                    }
+                }
+                "DELETE" if running => CaptureCommand::Stop { id },
+                _ => return Response::empty_404(),
+            };
+            if admin.captures.send(command).is_err() {
+                return Response::text("event loop stopped").with_status_code(503);
+            }
+            Response::empty_204().with_status_code(202)
+        }
+        ("GET", path) if path.starts_with("/clients/") && path.ends_with("/capture.pcap") => {
+            let Some(key) = path
+                .strip_prefix("/clients/")
+                .and_then(|p| p.strip_suffix("/capture.pcap"))
+            else {
+                return Response::empty_404();
+            };
+            let Some(id) = admin
+                .shared
+                .registry
+                .lock()
+                .expect("registry lock")
+                .resolve(key)
+            else {
+                return Response::empty_404();
+            };
+            match admin
+                .shared
+                .captures
+                .lock()
+                .expect("captures lock")
+                .get(&*id)
+            {
+                Some(CaptureStatus::Finished { data, .. }) => {
+                    Response::from_data("application/vnd.tcpdump.pcap", data.to_vec())
+                }
+                _ => Response::empty_404(),
+            }
+        }
+        ("POST", path) if path.starts_with("/clients/") && path.ends_with("/logs") => {
+            let Some(key) = path
+                .strip_prefix("/clients/")
+                .and_then(|p| p.strip_suffix("/logs"))
+            else {
+                return Response::empty_404();
+            };
+            let Some(id) = admin
+                .shared
+                .registry
+                .lock()
+                .expect("registry lock")
+                .resolve(key)
+            else {
+                return Response::empty_404();
+            };
+            let mut body = String::new();
+            if let Some(mut data) = request.data() {
+                if data.read_to_string(&mut body).is_err() {
+                    return Response::text("invalid body").with_status_code(400);
+                }
+            }
+            let query = if body.trim().is_empty() {
+                LogQuery::default()
+            } else {
+                match serde_json::from_str::<LogQuery>(&body) {
+                    Ok(query) => query,
+                    Err(e) => return Response::text(e.to_string()).with_status_code(400),
+                }
+            };
+            let (reply, answer) = mpsc::channel();
+            if admin.logs.send(LogRequest { id, query, reply }).is_err() {
+                return Response::text("event loop stopped").with_status_code(503);
+            }
+            match answer.recv_timeout(LOG_REPLY_TIMEOUT) {
+                Ok(Ok(logs)) => Response::text(logs),
+                Ok(Err(reason)) => Response::text(reason).with_status_code(502),
+                Err(_) => Response::text("the peer did not answer").with_status_code(504),
+            }
+        }
+        _ => Response::empty_404(),
+    }
+}
+
+/// Upgrades a request to a WebSocket streaming the server's events of the
+/// topics in the `topics` parameter, every topic if unset, each as JSON.
+///
+/// Events are dropped while the stream lags behind, see
+/// [`EventBus::subscribe_channel`].
+fn event_stream(request: &Request, admin: &AdminState) -> Response {
+    let topics = request.get_param("topics").unwrap_or_default();
+    let topics = match EventTopic::parse_list(&topics) {
+        Ok(topics) => topics,
+        Err(e) => return Response::text(e).with_status_code(400),
+    };
+    let (response, websocket) = match websocket::start(request, None::<&str>) {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            return Response::text(format!("websocket upgrade failed: {:?}", e))
+                .with_status_code(400)
+        }
+    };
+
+    let (subscription, events) = admin.bus.subscribe_channel(&topics);
+    let bus = admin.bus.clone();
+    thread::spawn(move || {
+        // The websocket becomes available once the upgrade response is sent.
+        if let Ok(mut websocket) = websocket.recv() {
+            debug!("Admin event stream opened");
+            while let Ok(event) = events.recv() {
+                if websocket.send_text(&event.to_json().to_string()).is_err() {
+                    break;
+                }
+            }
+            debug!("Admin event stream closed");
+        }
+        bus.unsubscribe(subscription);
+    });
+    response
+}
+
+/// Passes a command for the client with the given ID or alias to the event
+/// loop and answers with its outcome.
+fn client_command(admin: &AdminState, key: &str, command: ClientCommand) -> Response {
+    let Some(id) = admin
+        .shared
+        .registry
+        .lock()
+        .expect("registry lock")
+        .resolve(key)
+    else {
+        return Response::empty_404();
+    };
+    let (reply, outcome) = mpsc::channel();
+    let request = ClientCommandRequest { id, command, reply };
+    if admin.commands.send(request).is_err() {
+        return Response::text("event loop stopped").with_status_code(503);
+    }
+    match outcome.recv_timeout(COMMAND_REPLY_TIMEOUT) {
+        Ok(Ok(())) => Response::empty_204(),
+        Ok(Err(reason)) => Response::text(reason).with_status_code(409),
+        Err(_) => Response::text("the event loop did not answer").with_status_code(504),
+    }
+}
+
+/// Collects the acknowledgments of announcements and reports the settled
+/// broadcasts to the admin handlers waiting for them.
+///
+/// Clients that left before acknowledging count as timed out.
+///
+/// # Arguments
+///
+/// * `clients` - Mutable reference to the list of all clients
+/// * `pending` - The broadcasts awaiting acknowledgments, with their repliers
+fn answer_broadcasts(
+    clients: &mut [Client],
+    pending: &mut Vec<(PendingBroadcast, mpsc::Sender<BroadcastReport>)>,
+) {
+    for client in clients.iter_mut() {
+        for id in client.take_announce_acks() {
+            if let Some((broadcast, _)) = pending.iter_mut().find(|(b, _)| b.id() == id) {
+                broadcast.acknowledge(*client.id);
+            }
+        }
     }
 
-    fn mark_failure(&mut self) {
-        self.consecutive_failures += 1;
+    let now = Instant::now();
+    let mut i = 0;
+    while i < pending.len() {
+        let broadcast = &mut pending[i].0;
+        let departed: Vec<u64> = broadcast
+            .awaited()
+            .filter(|id| !clients.iter().any(|c| *c.id == *id))
+            .collect();
+        for id in departed {
+            broadcast.abandon(id);
+        }
+        if !broadcast.is_settled(now) {
+            i += 1;
+            continue;
+        }
+        let (broadcast, reply) = pending.swap_remove(i);
+        let report = broadcast.finish();
+        info!(
+            "Announcement {} to room '{}' {}",
+            report.id, report.room, report
+        );
+        let _ = reply.send(report);
     }
+}
 
-    fn should_attempt_recovery(&self) -> bool {
-        // Attempt recovery if no activity for 10 seconds and fewer than 3 restart attempts
-        self.last_activity.elapsed() > Duration::from_secs(10)
-            && self.consecutive_failures > 3
-            && self.ice_restart_attempts < 3
-    }
+/// A log request forwarded to a peer, waiting for its answer.
+struct PendingLogs {
+    /// Receives the log text, or why there is none
+    reply: mpsc::Sender<Result<String, String>>,
+    /// When the requester stops waiting
+    deadline: Instant,
 }
 
-/// Main entry point for the WebRTC signaling server.
+/// Hands the answers to log requests to the admin handlers waiting for them.
 ///
-/// This function:
-/// 1. Initializes logging
-/// 2. Selects a host address for the UDP socket
-/// 3. Binds a random UDP port for WebRTC traffic
-/// 4. Spawns a background thread to handle WebRTC client connections
-/// 5. Starts an HTTP server on port 3000 for signaling
+/// Requests of departed clients and requests past their deadline are dropped,
+/// which lets their handlers know there will be no answer.
 ///
-/// # Panics
+/// # Arguments
 ///
-/// Panics if:
-/// - Unable to bind a UDP socket
-/// - Unable to start the HTTP server
-pub fn main() {
-    init_log();
-
-    let host_addr = select_host_address();
-
-    let (tx, rx) = mpsc::sync_channel(1);
-
-    let socket = UdpSocket::bind(format!("{host_addr}:0")).expect("binding a random UDP port");
-    let addr = socket.local_addr().expect("a local socket address");
-    info!("Bound UDP port: {}", addr);
-
-    thread::spawn(move || run(socket, rx));
-
-    let server = Server::new("0.0.0.0:3000", move |request| {
-        web_request(request, addr, tx.clone())
-    })
-    .expect("starting the web server");
-
-    let port = server.server_addr().port();
-    info!("Connect a browser to http://{:?}:{:?}", addr.ip(), port);
+/// * `clients` - Mutable reference to the list of all clients
+/// * `pending` - The forwarded requests, by client and request ID
+fn answer_log_requests(
+    clients: &mut [Client],
+    pending: &mut HashMap<(ClientId, u32), PendingLogs>,
+) {
+    for client in clients.iter_mut() {
+        for (id, result) in client.take_log_replies() {
+            if let Some(request) = pending.remove(&(client.id, id)) {
+                let _ = request.reply.send(result);
+            }
+        }
+    }
 
-    server.run();
+    let now = Instant::now();
+    pending.retain(|(client_id, id), request| {
+        let client = clients.iter_mut().find(|c| c.id == *client_id);
+        match client {
+            Some(client) if request.deadline <= now => {
+                client.abandon_logs(*id);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    });
 }
 
-/// Main event loop for managing WebRTC clients.
+/// Stores the crash reports uploaded by the clients and acknowledges each,
+/// so the peer deletes its copy.
 ///
-/// This function:
-/// - Maintains a list of active clients
-/// - Polls each client for output and handles timeouts
-/// - Routes incoming UDP packets to the appropriate client
-/// - Broadcasts messages to all clients every 5 seconds
-/// - Removes disconnected clients
+/// Reports that cannot be stored are not acknowledged, and the peer sends
+/// them again in its next session.
 ///
 /// # Arguments
 ///
-/// * `socket` - The UDP socket for receiving/sending WebRTC traffic
-/// * `rx` - Channel receiver for new RTC instances from the web server thread
-fn run(socket: UdpSocket, rx: Receiver<Rtc>) {
-    let mut clients: Vec<Client> = vec![];
-    let mut health: HashMap<u64, ConnectionHealth> = HashMap::new();
-    let mut buf = vec![0; 2000];
-    let mut last_health_check = Instant::now();
-
-    loop {
-        // Remove disconnected clients and their health records
-        clients.retain(|c| {
-            let alive = c.rtc.is_alive();
-            if !alive {
-                info!("Client({}) disconnected, removing from pool", *c.id);
-                health.remove(&*c.id);
+/// * `clients` - Mutable reference to the list of all clients
+/// * `settings` - The crash reporting settings with the report directory
+fn store_crash_reports(clients: &mut [Client], settings: &CrashConfig) {
+    for client in clients.iter_mut() {
+        let uploader = match &client.alias {
+            Some(alias) => alias.clone(),
+            None => format!("client-{}", *client.id),
+        };
+        for (name, data) in client.take_crash_reports() {
+            let stored = logbuf::decompress(&data)
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "not valid deflate"))
+                .and_then(|report| crash::store_received(&settings.dir, &uploader, &name, &report));
+            match stored {
+                Ok(path) => {
+                    warn!(
+                        "{} uploaded crash report {}, stored as {}",
+                        client.name(),
+                        name,
+                        path.display()
+                    );
+                    client.acknowledge_crash_report(&name);
+                }
+                Err(e) => warn!(
+                    "Failed to store crash report {} of {}: {}",
+                    name,
+                    client.name(),
+                    e
+                ),
             }
-            alive
-        });
-
-        // Spawn new clients from the web server thread
-        if let Some(client) = spawn_new_client(&rx) {
-            info!("New client connected: Client({})", *client.id);
-            health.insert(*client.id, ConnectionHealth::new());
-            clients.push(client);
         }
+    }
+}
 
-        // Periodic health check every 5 seconds
-        if last_health_check.elapsed() > Duration::from_secs(5) {
-            check_client_health(&mut clients, &mut health, &socket);
-            last_health_check = Instant::now();
-        }
+/// Returns the current state of the server and its clients.
+///
+/// # Arguments
+///
+/// * `clients` - The connected clients
+/// * `events` - The significant events of the server
+/// * `udp_addr` - The address of the UDP socket
+fn server_state(clients: &[Client], events: &EventRing, udp_addr: SocketAddr) -> ServerState {
+    ServerState {
+        time: Utc::now(),
+        version: env!("CARGO_PKG_VERSION"),
+        udp_addr,
+        event_log: event_log::current_config(),
+        events: events.snapshot(),
+        events_dropped: events.dropped(),
+        clients: clients.iter().map(Client::state).collect(),
+    }
+}
 
-        // Poll all clients and get the earliest timeout
-        let mut timeout = Instant::now() + Duration::from_millis(100);
-        for client in clients.iter_mut() {
-            let t = poll_client(client, &socket);
-            timeout = timeout.min(t);
+/// Returns the state of the clients to include in crash reports.
+fn crash_snapshot(clients: &[Client]) -> Vec<serde_json::Value> {
+    clients
+        .iter()
+        .map(|client| {
+            serde_json::json!({
+                "id": *client.id,
+                "name": client.name(),
+                "ice_state": client.counters.ice_state.map(|s| format!("{:?}", s)),
+                "bytes_received": client.counters.bytes_received,
+                "bytes_sent": client.counters.bytes_sent,
+                "messages_queued": client.counters.messages_queued,
+            })
+        })
+        .collect()
+}
 
-            // Update health on successful poll
-            if let Some(h) = health.get_mut(&*client.id) {
-                h.mark_activity();
+/// Forwards packet capture commands to the peers and records the captures
+/// they send.
+///
+/// A capture the peer neither sends nor refuses within [`CAPTURE_GRACE`] of
+/// its end is reported as failed.
+///
+/// # Arguments
+///
+/// * `clients` - Mutable reference to the list of all clients
+/// * `commands` - The commands made through the admin API
+/// * `captures` - The status of each client's last capture
+fn update_captures(
+    clients: &mut [Client],
+    commands: Vec<CaptureCommand>,
+    captures: &Mutex<HashMap<u64, CaptureStatus>>,
+) {
+    let mut captures = captures.lock().expect("captures lock");
+    for command in commands {
+        match command {
+            CaptureCommand::Start {
+                id,
+                duration_secs,
+                max_bytes,
+            } => {
+                let Some(client) = clients.iter_mut().find(|c| c.id == id) else {
+                    debug!("Dropping capture request for departed Client({})", id);
+                    continue;
+                };
+                // Two requests may have raced past the admin API's check
+                if let Some(CaptureStatus::Running { .. } | CaptureStatus::Stopping { .. }) =
+                    captures.get(&*id)
+                {
+                    debug!("{} is already capturing", client.name());
+                    continue;
+                }
+                let status = match client.start_capture(duration_secs, max_bytes) {
+                    Some(capture) => {
+                        info!(
+                            "Requested a {}s capture from {}",
+                            duration_secs,
+                            client.name()
+                        );
+                        CaptureStatus::Running {
+                            id: capture,
+                            duration_secs,
+                            max_bytes,
+                            deadline: Instant::now()
+                                + Duration::from_secs(duration_secs as u64)
+                                + CAPTURE_GRACE,
+                        }
+                    }
+                    None => CaptureStatus::Failed {
+                        id: None,
+                        reason: "the peer has no capture channel".into(),
+                    },
+                };
+                captures.insert(*id, status);
+            }
+            CaptureCommand::Stop { id } => {
+                let Some(client) = clients.iter_mut().find(|c| c.id == id) else {
+                    continue;
+                };
+                if let Some(CaptureStatus::Running { id: capture, .. }) = captures.get(&*id) {
+                    let capture = *capture;
+                    if client.stop_capture(capture) {
+                        let deadline = Instant::now() + CAPTURE_GRACE;
+                        captures.insert(
+                            *id,
+                            CaptureStatus::Stopping {
+                                id: capture,
+                                deadline,
+                            },
+                        );
+                    }
+                }
             }
         }
+    }
 
-        if let Some(input) = read_socket_input(&socket, &mut buf) {
-            // The rtc.accepts() call is how we demultiplex the incoming packet to know which
-            // Rtc instance the traffic belongs to.
-            if let Some(client) = clients.iter_mut().find(|c| c.accepts(&input)) {
-                // We found the client that accepts the input.
-                client.handle_input(input);
-
-                // Mark activity on successful input
-                if let Some(h) = health.get_mut(&*client.id) {
-                    h.mark_activity();
+    for client in clients.iter_mut() {
+        for (capture, result) in client.take_captures() {
+            let Some(status) = captures.get_mut(&*client.id) else {
+                continue;
+            };
+            if !matches!(status,
+                CaptureStatus::Running { id, .. } | CaptureStatus::Stopping { id, .. }
+                    if *id == capture)
+            {
+                continue;
+            }
+            *status = match result {
+                Ok(file) => {
+                    info!(
+                        "Received capture {} from {}: {} datagrams in {} bytes",
+                        capture,
+                        client.name(),
+                        file.packets,
+                        file.data.len()
+                    );
+                    CaptureStatus::Finished {
+                        id: capture,
+                        packets: file.packets,
+                        truncated: file.truncated,
+                        bytes: file.data.len(),
+                        data: Arc::new(file.data),
+                    }
                 }
-            } else {
-                // This is quite common because we don't get the Rtc instance via the mpsc channel
-                // quickly enough before the browser send the first STUN.
-                debug!("No client accepts UDP input");
+                Err(reason) => CaptureStatus::Failed {
+                    id: Some(capture),
+                    reason,
+                },
+            };
+        }
+    }
 
-                // Mark potential failures for all clients
-                for h in health.values_mut() {
-                    h.mark_failure();
-                }
+    let now = Instant::now();
+    for status in captures.values_mut() {
+        if let CaptureStatus::Running { id, deadline, .. }
+        | CaptureStatus::Stopping { id, deadline } = status
+        {
+            if *deadline <= now {
+                *status = CaptureStatus::Failed {
+                    id: Some(*id),
+                    reason: "the peer did not send the capture".into(),
+                };
             }
         }
+    }
+}
 
-        // Drive time forward in all clients.
-        let now = Instant::now();
-        for client in &mut clients {
-            client.handle_input(Input::Timeout(now));
+/// Delivers due replay messages to the clients in each replay's room.
+///
+/// Finished replays are removed.
+///
+/// # Arguments
+///
+/// * `clients` - Mutable reference to the list of all clients
+/// * `replays` - The active replays
+fn play_replays(clients: &mut [Client], replays: &mut Vec<ReplaySession>) {
+    let now = Instant::now();
+    for replay in replays.iter_mut() {
+        for message in replay.due(now) {
+            for client in clients
+                .iter_mut()
+                .filter(|c| c.access.room.as_deref() == Some(replay.room.as_str()))
+            {
+                client.send_on_channel(&message.channel, &message.data);
+            }
         }
     }
+    replays.retain(|r| {
+        if r.is_finished() {
+            info!("Replay into room '{}' finished", r.room);
+        }
+        !r.is_finished()
+    });
 }
 
-/// Handles incoming HTTP requests for WebRTC signaling.
+/// Negotiates the protocol version and features with the clients.
 ///
-/// This function processes SDP offers from clients, creates an SDP answer,
-/// and sends the new RTC instance to the main event loop via the channel.
+/// A client's hello is answered with the server's hello and capabilities.
+/// Clients with an incompatible version are told why and disconnected, unless
+/// fallback is allowed. Clients agreeing to rate control are asked for the
+/// publishing rates requested through the handle, and clients agreeing to
+/// heartbeats are pinged and have their pings answered. Clients agreeing to
+/// key agreement are sent the server's key share, and agree on the session
+/// keys with their own.
 ///
 /// # Arguments
 ///
-/// * `request` - The incoming HTTP request containing the SDP offer
-/// * `addr` - The socket address of the UDP port for WebRTC traffic
-/// * `tx` - Channel sender for passing new RTC instances to the main loop
+/// * `clients` - Mutable reference to the list of all clients
+/// * `config` - The negotiation settings
 ///
 /// # Returns
 ///
-/// An HTTP response containing the SDP answer in JSON format
-fn web_request(request: &Request, addr: SocketAddr, tx: SyncSender<Rtc>) -> Response {
-    // request.
-    info!("{:#?}", request);
-
-    let mut data = request.data().expect("body to be available");
-
-    let offer: SdpOffer = serde_json::from_reader(&mut data).expect("serialised offer");
-    info!(
-        "Received offer with {} data channels",
-        offer.to_string().matches("m=application").count()
-    );
-    let mut rtc: Rtc = Rtc::builder().build();
+/// The clients whose session keys were agreed, with the keys
+fn negotiate_protocols(
+    clients: &mut [Client],
+    config: &ProtocolConfig,
+) -> Vec<(ClientId, Arc<SessionKeys>)> {
+    let mut agreed = vec![];
+    for client in clients.iter_mut() {
+        for message in client.take_control_messages() {
+            match &message {
+                ControlMessage::Incompatible { reason } => {
+                    warn!("{} closed the session: {}", client.name(), reason);
+                    client.rtc.disconnect();
+                    continue;
+                }
+                ControlMessage::Goodbye { reason } => {
+                    info!("{} is leaving: {}", client.name(), reason);
+                    client.rtc.disconnect();
+                    continue;
+                }
+                ControlMessage::Capabilities { features } => {
+                    client.features = common_features(*features, client.protocol, config);
+                    info!("{} negotiated features: {}", client.name(), client.features);
+                    if client.features.contains(Feature::KeyAgreement) {
+                        if let Some(share) = client.key_agreement.start() {
+                            client.send_control(&share);
+                        }
+                    }
+                    continue;
+                }
+                ControlMessage::KeyShare { public_key } => {
+                    let local = client
+                        .rtc
+                        .direct_api()
+                        .local_dtls_fingerprint()
+                        .bytes
+                        .clone();
+                    let remote = client
+                        .rtc
+                        .direct_api()
+                        .remote_dtls_fingerprint()
+                        .map(|f| f.bytes.clone());
+                    let result =
+                        remote
+                            .ok_or(KeyAgreementError::NoFingerprint)
+                            .and_then(|remote| {
+                                client.key_agreement.finish(public_key, &local, &remote)
+                            });
+                    match result {
+                        Ok(keys) => {
+                            info!("{} agreed on session keys {}", client.name(), keys.id());
+                            let keys = Arc::new(keys);
+                            client.session_keys = Some(keys.clone());
+                            agreed.push((client.id, keys));
+                        }
+                        Err(e) => warn!("{} has no session keys: {}", client.name(), e),
+                    }
+                    continue;
+                }
+                ControlMessage::RequestRate { topic, .. } => {
+                    debug!(
+                        "{} requested a rate for '{}', but the server publishes no topics",
+                        client.name(),
+                        topic
+                    );
+                    continue;
+                }
+                ControlMessage::Ping { sequence } => {
+                    client.send_control(&ControlMessage::Pong {
+                        sequence: *sequence,
+                    });
+                    continue;
+                }
+                ControlMessage::Pong { sequence } => {
+                    if let Some(rtt) = client.link.pong(*sequence, Instant::now()) {
+                        debug!(
+                            "{} answered heartbeat {} in {:?}",
+                            client.name(),
+                            sequence,
+                            rtt
+                        );
+                    }
+                    continue;
+                }
+                ControlMessage::RestartIce => {
+                    debug!("{} asked the server to restart ICE", client.name());
+                    continue;
+                }
+                ControlMessage::Hello { .. } => {}
+            }
+            match negotiate(&message, config) {
+                Ok(negotiation) => {
+                    match negotiation {
+                        Negotiation::Agreed { version } => {
+                            info!("{} speaks protocol v{}", client.name(), version)
+                        }
+                        _ => warn!(
+                            "{} speaks an incompatible protocol, falling back to plain data",
+                            client.name()
+                        ),
+                    }
+                    client.protocol = negotiation;
+                    client.send_control(&ControlMessage::hello());
+                    client.send_control(&ControlMessage::capabilities(config));
+                }
+                Err(mismatch) => {
+                    warn!("Disconnecting {}: {}", client.name(), mismatch);
+                    client.send_control(&ControlMessage::Incompatible {
+                        reason: mismatch.to_string(),
+                    });
+                    client.evict(&mismatch.to_string());
+                }
+            }
+        }
 
-    let candidate = Candidate::host(addr, "udp").expect("a host candidate");
-    rtc.add_local_candidate(candidate)
-        .expect("Local candidate should be added.");
+        // Ask for the publishing rates the receivers want
+        if client.features.contains(Feature::RateControl) {
+            for (topic, millihertz) in client.rates.take_changes() {
+                client.send_control(&ControlMessage::RequestRate { topic, millihertz });
+            }
+        }
 
-    let answer = rtc
-        .sdp_api()
-        .accept_offer(offer)
-        .expect("Offer to be accepted.");
+        // Measure the link with heartbeats
+        if client.features.contains(Feature::Heartbeat) {
+            if let Some(sequence) = client.link.poll(Instant::now()) {
+                client.send_control(&ControlMessage::Ping { sequence });
+            }
+        }
+    }
+    agreed
+}
 
-    info!("Created answer, sending to client thread");
+/// Forwards the tracks clients publish to the other clients of their room.
+///
+/// New tracks are offered to the clients receiving forwarded media, and the
+/// tracks already published to those that just opened the forward channel.
+/// Frames are written to the receivers' tracks as they are, and keyframe
+/// requests of the receivers are routed back to the publisher.
+///
+/// # Arguments
+///
+/// * `clients` - Mutable reference to the list of all clients
+/// * `propagated` - The media received from the clients in this iteration
+fn forward_media(clients: &mut [Client], mut propagated: Vec<Propagated>) {
+    for i in 0..clients.len() {
+        if !clients[i].take_forward_opened() {
+            continue;
+        }
+        for j in 0..clients.len() {
+            if i == j || clients[i].access.room != clients[j].access.room {
+                continue;
+            }
+            for track in clients[j].published_tracks() {
+                clients[i].handle_track_open(track);
+            }
+        }
+    }
+    for client in clients.iter_mut() {
+        propagated.extend(client.take_propagated());
+    }
 
-    tx.send(rtc).expect("to send the rtc instance.");
+    for event in propagated {
+        match event {
+            Propagated::TrackOpen(origin, track) => {
+                let Some(room) = clients
+                    .iter()
+                    .find(|c| c.id == origin)
+                    .map(|c| c.access.room.clone())
+                else {
+                    continue;
+                };
+                for client in clients
+                    .iter_mut()
+                    .filter(|c| c.id != origin && c.receives_forwarded() && c.access.room == room)
+                {
+                    client.handle_track_open(track.clone());
+                }
+            }
+            Propagated::MediaData(origin, data) => {
+                for client in clients.iter_mut().filter(|c| c.id != origin) {
+                    client.handle_media_data_out(origin, &data);
+                }
+            }
+            Propagated::KeyframeRequest(receiver, _, origin, mid) => {
+                let receiver = client_name(clients, receiver);
+                if let Some(client) = clients.iter_mut().find(|c| c.id == origin) {
+                    debug!("{} asked {} for a keyframe", receiver, client.name());
+                    client.request_keyframe(mid);
+                }
+            }
+            Propagated::Noop | Propagated::Timeout(_) => {}
+        }
+    }
 
-    let body = serde_json::to_vec(&answer).expect("answer to serialise.");
+    for client in clients.iter_mut() {
+        client.negotiate_forwarded();
+    }
+}
 
-    info!("Send answer");
-    Response::from_data("application/json", body)
+/// Turns the end of a client's transfer into the event reported to the
+/// application.
+fn transfer_event(id: ClientId, event: TransferEvent) -> ServerEvent {
+    match event {
+        TransferEvent::Received {
+            id: transfer,
+            name,
+            path,
+        } => ServerEvent::FileReceived {
+            id,
+            transfer,
+            name,
+            path,
+        },
+        TransferEvent::Completed { id: transfer, name } => {
+            ServerEvent::TransferComplete { id, transfer, name }
+        }
+        TransferEvent::Failed {
+            id: transfer,
+            name,
+            reason,
+        } => ServerEvent::TransferFailed {
+            id,
+            transfer,
+            name,
+            reason,
+        },
+    }
 }
 
-/// Attempts to receive new clients from the channel and create Client instances.
-///
-/// Uses `try_recv` to avoid blocking the main thread.
+/// Writes the application data clients sent to the destinations of the
+/// matching routes, among the other clients of the sender's room.
 ///
 /// # Arguments
 ///
-/// * `rx` - The receiver channel for new RTC instances
-///
-/// # Returns
-///
-/// * `Some(Client)` - A new client instance if one was received
-/// * `None` - If no client is available in the channel
-///
-/// # Panics
-///
-/// Panics if the receiver channel is disconnected
-fn spawn_new_client(rx: &Receiver<Rtc>) -> Option<Client> {
-    // try_recv here won't lock up the thread.
-    match rx.try_recv() {
-        Ok(rtc) => Some(Client::new(rtc)),
-        Err(TryRecvError::Empty) => None,
-        _ => panic!("Receiver<Rtc> disconnected"),
+/// * `clients` - Mutable reference to the list of all clients
+/// * `routed` - The index of the sending client, the channel label and the
+///   data of every message received since the last iteration
+/// * `routing` - The routing table
+fn route_messages(
+    clients: &mut [Client],
+    routed: Vec<(usize, String, Vec<u8>)>,
+    routing: &RoutingConfig,
+) {
+    for (i, channel, data) in routed {
+        let sender = &clients[i];
+        let identities = [sender.alias.as_deref(), sender.access.subject.as_deref()];
+        let destinations: Vec<_> = routing
+            .destinations(&identities, &channel)
+            .into_iter()
+            .cloned()
+            .collect();
+        if destinations.is_empty() {
+            continue;
+        }
+        let name = sender.name().to_string();
+        let room = sender.access.room.clone();
+        for destination in destinations {
+            let mut delivered = false;
+            for (j, other) in clients.iter_mut().enumerate() {
+                let identities = [other.alias.as_deref(), other.access.subject.as_deref()];
+                if i == j || other.access.room != room || !destination.matches(&identities) {
+                    continue;
+                }
+                delivered |= other.send_on_channel(&channel, &data);
+            }
+            if !delivered {
+                debug!(
+                    "No client took the message of {} on '{}' for {}",
+                    name,
+                    channel,
+                    String::from(destination)
+                );
+            }
+        }
     }
 }
 
-/// Polls a client for output events and handles them until a timeout is returned.
+/// Returns the name a client's data is evaluated under by the rules: its
+/// alias, else its authenticated subject, else its ID.
+fn rule_source(client: &Client) -> String {
+    client
+        .alias
+        .clone()
+        .or_else(|| client.access.subject.clone())
+        .unwrap_or_else(|| client.id.to_string())
+}
+
+/// Runs the actions of a rule that fired.
 ///
-/// This function processes all available output from the client (transmit events)
-/// and returns when the next timeout should occur.
+/// Messages go to the client the data came from, or to the clients of its
+/// room whose alias or subject the action names; webhooks are posted on a
+/// background thread.
 ///
 /// # Arguments
 ///
-/// * `client` - The client to poll
-/// * `socket` - The UDP socket for sending outgoing traffic
-///
-/// # Returns
-///
-/// The instant at which the next timeout should occur
-fn poll_client(client: &mut Client, socket: &UdpSocket) -> Instant {
-    loop {
-        if !client.rtc.is_alive() {
-            // This client will be cleaned up in the next run of the main loop.
-            return Instant::now();
-        }
-
-        match client.poll_output(socket) {
-            Some(timeout) => return timeout,
-            None => continue,
+/// * `clients` - Mutable reference to the list of all clients
+/// * `i` - The index of the client the data came from
+/// * `firing` - The rule that fired
+fn run_rule_actions(clients: &mut [Client], i: usize, firing: &RuleFiring) {
+    info!(
+        "Rule '{}' fired for {} on '{}'",
+        firing.rule, firing.source, firing.topic
+    );
+    for action in &firing.actions {
+        match action {
+            RuleAction::Send {
+                message,
+                channel,
+                to,
+            } => {
+                let room = clients[i].access.room.clone();
+                let targets: Vec<usize> = match to {
+                    Some(to) => (0..clients.len())
+                        .filter(|&j| {
+                            let other = &clients[j];
+                            other.access.room == room
+                                && [other.alias.as_deref(), other.access.subject.as_deref()]
+                                    .contains(&Some(to.as_str()))
+                        })
+                        .collect(),
+                    None => vec![i],
+                };
+                if targets.is_empty() {
+                    warn!(
+                        "Rule '{}' has no client '{}' to send to",
+                        firing.rule,
+                        to.as_deref().unwrap_or_default()
+                    );
+                }
+                for j in targets {
+                    match channel {
+                        Some(label) => {
+                            if !clients[j].send_on_channel(label, message.as_bytes()) {
+                                warn!(
+                                    "Rule '{}': {} has no channel '{}'",
+                                    firing.rule,
+                                    clients[j].name(),
+                                    label
+                                );
+                            }
+                        }
+                        None => clients[j].send_message(message),
+                    }
+                }
+            }
+            RuleAction::Webhook { url } => {
+                let url = url.clone();
+                let body = firing.clone();
+                thread::spawn(move || {
+                    let result = reqwest::blocking::Client::new()
+                        .post(&url)
+                        .json(&body)
+                        .send();
+                    if let Err(e) = result {
+                        warn!("Rule webhook to {} failed: {}", url, e);
+                    }
+                });
+            }
+            RuleAction::Log { message, level } => match level {
+                RuleLogLevel::Info => info!("Rule '{}': {}", firing.rule, message),
+                RuleLogLevel::Warn => warn!("Rule '{}': {}", firing.rule, message),
+            },
         }
     }
 }
 
-/// Checks the health of all clients and attempts recovery if needed
+/// Relays coordination messages from each client to the other clients of its
+/// room.
 ///
-/// This function monitors connection health and can initiate recovery attempts
-/// for degraded connections.
+/// The server does not interpret the messages; leader election and membership
+/// are computed by the rovers themselves from the relayed heartbeats, so each
+/// room forms its own convoy.
 ///
 /// # Arguments
 ///
 /// * `clients` - Mutable reference to the list of all clients
-/// * `health` - Mutable reference to the health tracking map
-/// * `socket` - The UDP socket (for potential recovery operations)
-fn check_client_health(
-    clients: &mut Vec<Client>,
-    health: &mut HashMap<u64, ConnectionHealth>,
-    socket: &UdpSocket,
-) {
-    for client in clients.iter_mut() {
-        let Some(h) = health.get_mut(&*client.id) else {
+fn relay_coordination(clients: &mut [Client]) {
+    for i in 0..clients.len() {
+        let messages = clients[i].take_coordination_messages();
+        if messages.is_empty() {
             continue;
-        };
+        }
+        let room = clients[i].access.room.clone();
+        for message in messages {
+            for (j, other) in clients.iter_mut().enumerate() {
+                if i != j && other.access.room == room {
+                    other.send_coordination(&message);
+                }
+            }
+        }
+    }
+}
 
-        // Check if client needs recovery
-        if h.should_attempt_recovery() {
-            warn!(
-                "Client({}) connection health degraded. \
-                Last activity: {:?} ago, Failures: {}",
-                *client.id,
-                h.last_activity.elapsed(),
-                h.consecutive_failures
-            );
+/// Posts geofence crossings to the configured webhook.
+///
+/// One worker thread owns the HTTP client and posts the crossings in order.
+/// The event loop only queues them: when [`GEOFENCE_WEBHOOK_QUEUE`] are
+/// waiting, because the webhook is slow or down, new crossings are dropped
+/// instead of piling up. The worker ends once the webhook is dropped.
+struct GeofenceWebhook {
+    events: mpsc::SyncSender<GeofenceEvent>,
+}
 
-            attempt_connection_recovery(client, h, socket);
-        }
+impl GeofenceWebhook {
+    /// Starts the worker posting to `url`.
+    ///
+    /// # Returns
+    ///
+    /// An error if the thread could not be spawned
+    fn spawn(url: &str) -> io::Result<Self> {
+        let url = url.to_string();
+        let (events, queue) = mpsc::sync_channel::<GeofenceEvent>(GEOFENCE_WEBHOOK_QUEUE);
+        thread::Builder::new()
+            .name("geofence-webhook".to_string())
+            .spawn(move || {
+                let client = match reqwest::blocking::Client::builder()
+                    .timeout(GEOFENCE_WEBHOOK_TIMEOUT)
+                    .build()
+                {
+                    Ok(client) => client,
+                    Err(e) => {
+                        warn!("Geofence webhook disabled: {}", e);
+                        return;
+                    }
+                };
+                for event in queue {
+                    if let Err(e) = client.post(&url).json(&event).send() {
+                        warn!("Geofence webhook to {} failed: {}", url, e);
+                    }
+                }
+            })?;
+        Ok(Self { events })
+    }
 
-        // Log connection state for monitoring (every health check)
-        if h.last_activity.elapsed() > Duration::from_secs(5) {
-            info!(
-                "Client({}) inactive for {:?}, Failures: {}",
-                *client.id,
-                h.last_activity.elapsed(),
-                h.consecutive_failures
-            );
+    /// Queues a crossing for the webhook, dropping it if the queue is full.
+    fn post(&self, event: &GeofenceEvent) {
+        match self.events.try_send(event.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => warn!(
+                "Geofence webhook backed up, dropping the crossing of '{}' by client {}",
+                event.fence, event.client_id
+            ),
+            Err(TrySendError::Disconnected(_)) => {
+                debug!("Geofence webhook stopped, dropping the crossing")
+            }
         }
     }
 }
 
-/// Attempts to recover a degraded connection
+/// Reacts to a geofence boundary crossing.
 ///
-/// This function tries to recover a client connection by adding new candidates
-/// if the socket address has changed.
+/// Logs the crossing, queues it for the configured webhook, and sends the
+/// fence's command to the rover if the fence was breached.
 ///
 /// # Arguments
 ///
-/// * `client` - The client to recover
-/// * `health` - The health tracker for this client
-/// * `socket` - The UDP socket
-fn attempt_connection_recovery(
+/// * `client` - The client that crossed the boundary
+/// * `event` - The boundary crossing
+/// * `webhook` - The webhook worker, if configured
+fn handle_geofence_event(
     client: &mut Client,
-    health: &mut ConnectionHealth,
-    socket: &UdpSocket,
+    event: &GeofenceEvent,
+    webhook: Option<&GeofenceWebhook>,
 ) {
-    health.ice_restart_attempts += 1;
-
-    info!(
-        "Attempting connection recovery for Client({}) (attempt {})",
-        *client.id, health.ice_restart_attempts
-    );
-
-    // Note: Full ICE restart requires signaling channel to exchange new offer/answer
-    // In a real implementation, you would:
-    // 1. Create ICE restart offer: client.create_ice_restart_offer()
-    // 2. Send it to the peer via signaling channel
-    // 3. Receive answer and apply it
-
-    // For now, we can try adding a new candidate if socket address is available
-    if let Ok(local_addr) = socket.local_addr() {
-        client.add_new_candidate(local_addr);
+    if event.breach {
+        warn!(
+            "{} breached geofence '{}' ({:?}) at {}, {}",
+            client.name(),
+            event.fence,
+            event.transition,
+            event.latitude,
+            event.longitude
+        );
+    } else {
         info!(
-            "Added new candidate for Client({}) with address: {}",
-            *client.id, local_addr
+            "{} {:?} geofence '{}'",
+            client.name(),
+            event.transition,
+            event.fence
         );
     }
 
-    // Reset failure counter to give recovery a chance
-    health.consecutive_failures = 0;
+    if let Some(webhook) = webhook {
+        webhook.post(event);
+    }
+
+    if let Some(command) = &event.command {
+        client.send_message(command);
+    }
 }
 
-/// Attempts to read incoming data from the UDP socket.
-///
-/// Handles socket read timeouts gracefully and converts received data into
-/// str0m `Input` events for processing by RTC instances.
+/// Keeps the statistics and health states served by the admin API in step
+/// with the clients joining and leaving.
+fn track_clients(bus: &EventBus<ServerEvent>, shared: &SharedState) {
+    let stats = shared.stats.clone();
+    let setup = shared.setup.clone();
+    let connections = shared.connections.clone();
+    let health = shared.health.clone();
+    let captures = shared.captures.clone();
+    let topics = [EventTopic::Lifecycle, EventTopic::Health];
+    bus.subscribe(&topics, move |event| match event {
+        ServerEvent::ClientConnected { id, .. } => {
+            let mut health = health.lock().expect("health lock");
+            health.insert(**id, HealthState::Healthy);
+        }
+        ServerEvent::HealthChanged { id, state } => {
+            health.lock().expect("health lock").insert(**id, *state);
+        }
+        ServerEvent::ClientRemoved(removal) => {
+            let id = *removal.id;
+            stats.lock().expect("stats lock").remove(&id);
+            setup.lock().expect("setup lock").remove(&id);
+            connections.lock().expect("connections lock").remove(&id);
+            health.lock().expect("health lock").remove(&id);
+            captures.lock().expect("captures lock").remove(&id);
+        }
+        _ => {}
+    });
+}
+
+/// Posts the server's events of a webhook's topics to it as JSON, from a
+/// thread of its own so a slow endpoint does not hold up the event loop.
 ///
 /// # Arguments
 ///
-/// * `socket` - The UDP socket to read from
-/// * `buf` - A buffer for storing received data
+/// * `bus` - Bus the server's events are published on
+/// * `webhook` - The endpoint and its topics
+/// * `shutdown` - Ends the thread once triggered
+fn spawn_webhook(bus: &EventBus<ServerEvent>, webhook: &WebhookConfig, shutdown: Shutdown) {
+    let (_, events) = bus.subscribe_channel(&webhook.topics);
+    let url = webhook.url.clone();
+    info!("Posting server events to {}", url);
+    thread::spawn(move || {
+        let client = reqwest::blocking::Client::new();
+        while !shutdown.is_triggered() {
+            let event = match events.recv_timeout(WEBHOOK_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(e) = client.post(&url).json(&event.to_json()).send() {
+                warn!("Event webhook to {} failed: {}", url, e);
+            }
+        }
+    });
+}
+
+/// Takes the result of a read from the UDP socket, recording the datagram
+/// in packet captures and applying the simulated receive loss.
 ///
-/// # Returns
+/// # Arguments
 ///
-/// * `Some(Input)` - An input event containing the received data and source address
-/// * `None` - If the read timed out or the socket would block
+/// * `received` - The result of the read: the datagram's length and source
+/// * `socket` - The socket the datagram was read from, for packet captures
+/// * `buf` - The buffer holding the received data, truncated to the datagram
 ///
-/// # Panics
+/// # Returns
 ///
-/// Panics on unexpected socket errors (other than timeout/would block)
-fn read_socket_input<'a>(socket: &UdpSocket, buf: &'a mut Vec<u8>) -> Option<Input<'a>> {
-    buf.resize(2000, 0);
-
-    match socket.recv_from(buf) {
+/// * `Ok(Some(SocketAddr))` - The source of the datagram to handle
+/// * `Ok(None)` - If the read was interrupted or the datagram was dropped
+/// * `Err` - If the socket failed
+fn socket_input(
+    received: io::Result<(usize, SocketAddr)>,
+    socket: &UdpSocket,
+    buf: &mut Vec<u8>,
+) -> io::Result<Option<SocketAddr>> {
+    match received {
         Ok((n, source)) => {
             buf.truncate(n);
-
-            // Parse data to a DatagramRecv, which help preparse network data to
-            // figure out the multiplexing of all protocols on one UDP port.
-            let Ok(contents) = buf.as_slice().try_into() else {
-                return None;
-            };
-
-            Some(Input::Receive(
-                Instant::now(),
-                Receive {
-                    proto: Protocol::Udp,
-                    source,
-                    destination: socket
-                        .local_addr()
-                        .expect("Local address should be available."),
-                    contents,
-                },
-            ))
+            pcap::received(socket, source, buf);
+            if netsim::drops_received() {
+                return Ok(None);
+            }
+            Ok(Some(source))
         }
 
         Err(e) => match e.kind() {
-            // Expected error for set_read_timeout(). One for windows, one for the rest.
-            ErrorKind::WouldBlock | ErrorKind::TimedOut => None,
-            _ => panic!("UdpSocket read failed: {e:?}"),
+            // A signal such as ctrl-c interrupted the read
+            ErrorKind::Interrupted | ErrorKind::WouldBlock => Ok(None),
+            _ => Err(e),
         },
     }
 }
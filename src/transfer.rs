@@ -0,0 +1,461 @@
+//! Sending and receiving files
+//!
+//! Both the peer and the server keep a [`Transfers`] endpoint on the
+//! "transfer" data channel (see [`crate::model::transfer`]). Files to send are
+//! held in memory until the remote confirmed them, and offered again whenever
+//! the channel reopens after a lost connection. Received chunks are appended
+//! to a `.part` file named after the transfer ID in the configured directory,
+//! so a new session resumes from what is already on disk; once the digest
+//! matches, the file is moved to its final name.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::model::transfer::{
+    digest_reader, is_transfer_id, Manifest, OutgoingTransfer, TransferMessage, ACK_INTERVAL,
+    CHUNK_SIZE,
+};
+
+/// Extension of partially received files.
+const PART_EXTENSION: &str = "part";
+
+/// File transfer settings, the `[peer.transfer]` and `[server.transfer]`
+/// sections.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransferConfig {
+    /// Accept the files the remote sends
+    pub receive: bool,
+    /// Directory of the received and partially received files
+    pub dir: PathBuf,
+    /// Largest file accepted, in MiB
+    pub max_size_mb: u64,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            receive: true,
+            dir: PathBuf::from("transfers"),
+            max_size_mb: 256,
+        }
+    }
+}
+
+impl TransferConfig {
+    /// Checks that files can be accepted.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_size_mb == 0 {
+            return Err("max_size_mb must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+/// What became of a transfer, reported to the application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    /// A file from the remote arrived and matches its digest
+    Received {
+        id: String,
+        name: String,
+        path: PathBuf,
+    },
+    /// The remote confirmed a file sent to it
+    Completed { id: String, name: String },
+    /// A transfer in either direction was refused or its file did not match
+    /// its digest
+    Failed {
+        id: String,
+        name: String,
+        reason: String,
+    },
+}
+
+/// A file being received.
+#[derive(Debug)]
+struct Incoming {
+    manifest: Manifest,
+    file: File,
+    /// The next chunk expected
+    next: u32,
+    /// Whether the sender was told about a missing chunk since the last one
+    /// arrived
+    gap_reported: bool,
+}
+
+/// The transfers of one side of a connection.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    config: TransferConfig,
+    /// Directory the received files are moved to
+    inbox: PathBuf,
+    outgoing: Vec<OutgoingTransfer>,
+    incoming: HashMap<String, Incoming>,
+    /// Messages to the remote produced while handling its messages
+    replies: Vec<TransferMessage>,
+}
+
+impl Transfers {
+    /// Creates an endpoint without transfers.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The transfer settings
+    /// * `inbox` - The directory received files are moved to
+    pub fn new(config: &TransferConfig, inbox: PathBuf) -> Self {
+        Self {
+            config: config.clone(),
+            inbox,
+            ..Self::default()
+        }
+    }
+
+    /// Replaces the settings, keeping the transfers in progress.
+    pub fn configure(&mut self, config: &TransferConfig, inbox: PathBuf) {
+        self.config = config.clone();
+        self.inbox = inbox;
+    }
+
+    /// Queues a file for sending; it is offered once the channel is open.
+    ///
+    /// # Returns
+    ///
+    /// The ID of the transfer
+    pub fn send(&mut self, transfer: OutgoingTransfer) -> String {
+        let id = transfer.manifest().id.clone();
+        if self.outgoing.iter().any(|t| t.manifest().id == id) {
+            debug!("Transfer {} is already queued", id);
+        } else {
+            self.outgoing.push(transfer);
+        }
+        id
+    }
+
+    /// Returns the number of files waiting to be confirmed by the remote.
+    pub fn pending(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    /// Starts over on a newly opened channel: the files to send are offered
+    /// again, and the partial files are resumed when their senders offer them.
+    pub fn restart(&mut self) {
+        for transfer in &mut self.outgoing {
+            transfer.restart();
+        }
+        self.incoming.clear();
+        self.replies.clear();
+    }
+
+    /// Handles a message received on the transfer channel.
+    ///
+    /// # Returns
+    ///
+    /// The transfers that ended with the message
+    pub fn handle(&mut self, data: &[u8]) -> Vec<TransferEvent> {
+        let Some(message) = TransferMessage::decode(data) else {
+            warn!("Discarding undecodable transfer message");
+            return vec![];
+        };
+        match message {
+            TransferMessage::Offer { manifest } => self.handle_offer(manifest),
+            TransferMessage::Chunk { id, seq, data } => self.handle_chunk(&id, seq, &data),
+            TransferMessage::Ack { id, next } => {
+                if let Some(transfer) = self.outgoing.iter_mut().find(|t| t.manifest().id == id) {
+                    transfer.handle_ack(next);
+                }
+                vec![]
+            }
+            TransferMessage::Missing { id, next } => {
+                if let Some(transfer) = self.outgoing.iter_mut().find(|t| t.manifest().id == id) {
+                    debug!("Resending {} from chunk {}", transfer.manifest().name, next);
+                    transfer.handle_missing(next);
+                }
+                vec![]
+            }
+            TransferMessage::Complete { id } => {
+                let Some(index) = self.outgoing.iter().position(|t| t.manifest().id == id) else {
+                    return vec![];
+                };
+                let name = self.outgoing.remove(index).manifest().name.clone();
+                info!("Transfer of {} completed", name);
+                vec![TransferEvent::Completed { id, name }]
+            }
+            TransferMessage::Failed { id, reason } => {
+                let name =
+                    if let Some(index) = self.outgoing.iter().position(|t| t.manifest().id == id) {
+                        self.outgoing.remove(index).manifest().name.clone()
+                    } else if let Some(incoming) = self.incoming.remove(&id) {
+                        drop(incoming.file);
+                        let _ = fs::remove_file(self.part_path(&id));
+                        incoming.manifest.name
+                    } else {
+                        return vec![];
+                    };
+                warn!("Transfer of {} failed: {}", name, reason);
+                vec![TransferEvent::Failed { id, name, reason }]
+            }
+        }
+    }
+
+    /// Returns the messages to write on the transfer channel now: the
+    /// answers to the remote, then the offers and chunks of the files to send.
+    pub fn poll(&mut self) -> Vec<Vec<u8>> {
+        let mut messages: Vec<Vec<u8>> = self.replies.drain(..).map(|m| m.encode()).collect();
+        for transfer in &mut self.outgoing {
+            messages.extend(transfer.poll().iter().map(TransferMessage::encode));
+        }
+        messages
+    }
+
+    fn handle_offer(&mut self, manifest: Manifest) -> Vec<TransferEvent> {
+        let id = manifest.id.clone();
+        let refusal = if !self.config.receive {
+            Some("receiving files is disabled".to_string())
+        } else if !is_transfer_id(&id) {
+            Some("invalid transfer ID".to_string())
+        } else if manifest.size > self.config.max_size_mb * 1024 * 1024 {
+            Some(format!("larger than {} MiB", self.config.max_size_mb))
+        } else if manifest.chunk_size == 0 || manifest.chunk_size > 4 * CHUNK_SIZE {
+            Some(format!("unsupported chunk size {}", manifest.chunk_size))
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            warn!("Refusing transfer of {}: {}", manifest.name, reason);
+            self.replies.push(TransferMessage::Failed { id, reason });
+            return vec![];
+        }
+
+        let (file, next) = match self.open_part(&manifest) {
+            Ok(part) => part,
+            Err(e) => {
+                warn!("Failed to open the part of {}: {}", manifest.name, e);
+                let reason = format!("receiver cannot store the file: {}", e);
+                self.replies.push(TransferMessage::Failed { id, reason });
+                return vec![];
+            }
+        };
+        if next > 0 {
+            info!("Resuming transfer of {} at chunk {}", manifest.name, next);
+        } else {
+            info!("Receiving {} ({} bytes)", manifest.name, manifest.size);
+        }
+        self.replies.push(TransferMessage::Ack {
+            id: id.clone(),
+            next,
+        });
+        let chunks = manifest.chunks();
+        self.incoming.insert(
+            id.clone(),
+            Incoming {
+                manifest,
+                file,
+                next,
+                gap_reported: false,
+            },
+        );
+        if next == chunks {
+            return self.finish(&id);
+        }
+        vec![]
+    }
+
+    fn handle_chunk(&mut self, id: &str, seq: u32, data: &[u8]) -> Vec<TransferEvent> {
+        let Some(incoming) = self.incoming.get_mut(id) else {
+            debug!("Discarding a chunk of unknown transfer {}", id);
+            return vec![];
+        };
+        if seq != incoming.next {
+            debug!(
+                "Discarding chunk {} of {}, expecting {}",
+                seq, id, incoming.next
+            );
+            if seq > incoming.next && !incoming.gap_reported {
+                incoming.gap_reported = true;
+                self.replies.push(TransferMessage::Missing {
+                    id: id.to_string(),
+                    next: incoming.next,
+                });
+            }
+            return vec![];
+        }
+        let manifest = &incoming.manifest;
+        let offset = u64::from(seq) * u64::from(manifest.chunk_size);
+        let expected = manifest
+            .size
+            .saturating_sub(offset)
+            .min(u64::from(manifest.chunk_size));
+        if data.len() as u64 != expected {
+            let reason = format!(
+                "chunk {} has {} bytes, expected {}",
+                seq,
+                data.len(),
+                expected
+            );
+            return self.fail(id, reason);
+        }
+        if let Err(e) = incoming.file.write_all(data) {
+            return self.fail(id, format!("receiver cannot store the file: {}", e));
+        }
+        incoming.next += 1;
+        incoming.gap_reported = false;
+        if incoming.next == manifest.chunks() {
+            return self.finish(id);
+        }
+        if incoming.next % ACK_INTERVAL == 0 {
+            self.replies.push(TransferMessage::Ack {
+                id: id.to_string(),
+                next: incoming.next,
+            });
+        }
+        vec![]
+    }
+
+    /// Checks the digest of a file whose chunks all arrived and moves it to
+    /// the inbox.
+    fn finish(&mut self, id: &str) -> Vec<TransferEvent> {
+        let Some(incoming) = self.incoming.remove(id) else {
+            return vec![];
+        };
+        let Incoming { manifest, file, .. } = incoming;
+        drop(file);
+        let part = self.part_path(id);
+        let stored = File::open(&part)
+            .and_then(digest_reader)
+            .and_then(|digest| {
+                if digest == manifest.id {
+                    Ok(())
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the file does not match its digest",
+                    ))
+                }
+            })
+            .and_then(|_| {
+                fs::create_dir_all(&self.inbox)?;
+                let path = unique_path(&self.inbox, &manifest);
+                fs::rename(&part, &path)?;
+                Ok(path)
+            });
+        match stored {
+            Ok(path) => {
+                info!("Received {} as {}", manifest.name, path.display());
+                self.replies
+                    .push(TransferMessage::Complete { id: id.to_string() });
+                vec![TransferEvent::Received {
+                    id: id.to_string(),
+                    name: manifest.name,
+                    path,
+                }]
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&part);
+                warn!("Failed to receive {}: {}", manifest.name, e);
+                let reason = e.to_string();
+                self.replies.push(TransferMessage::Failed {
+                    id: id.to_string(),
+                    reason: reason.clone(),
+                });
+                vec![TransferEvent::Failed {
+                    id: id.to_string(),
+                    name: manifest.name,
+                    reason,
+                }]
+            }
+        }
+    }
+
+    /// Gives up on a file being received, telling the sender.
+    fn fail(&mut self, id: &str, reason: String) -> Vec<TransferEvent> {
+        let Some(incoming) = self.incoming.remove(id) else {
+            return vec![];
+        };
+        drop(incoming.file);
+        let _ = fs::remove_file(self.part_path(id));
+        warn!("Failed to receive {}: {}", incoming.manifest.name, reason);
+        self.replies.push(TransferMessage::Failed {
+            id: id.to_string(),
+            reason: reason.clone(),
+        });
+        vec![TransferEvent::Failed {
+            id: id.to_string(),
+            name: incoming.manifest.name,
+            reason,
+        }]
+    }
+
+    /// Opens the part file of a transfer, keeping the whole chunks a previous
+    /// session received.
+    ///
+    /// # Returns
+    ///
+    /// The file, positioned at its end, and the first chunk it lacks
+    fn open_part(&self, manifest: &Manifest) -> io::Result<(File, u32)> {
+        fs::create_dir_all(&self.config.dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.part_path(&manifest.id))?;
+        let chunk_size = u64::from(manifest.chunk_size);
+        let whole = (file.metadata()?.len() / chunk_size).min(u64::from(manifest.chunks()));
+        // A last chunk shorter than the others may not be complete
+        let next = if whole * chunk_size > manifest.size {
+            whole - 1
+        } else {
+            whole
+        } as u32;
+        let kept = (u64::from(next) * chunk_size).min(manifest.size);
+        file.set_len(kept)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok((file, next))
+    }
+
+    fn part_path(&self, id: &str) -> PathBuf {
+        self.config.dir.join(format!("{}.{}", id, PART_EXTENSION))
+    }
+}
+
+/// Returns the path a received file is stored under: its name without any
+/// directory, prefixed with the start of its ID if the name is taken.
+fn unique_path(dir: &Path, manifest: &Manifest) -> PathBuf {
+    let name = file_name(&manifest.name);
+    let path = dir.join(&name);
+    if path.exists() {
+        dir.join(format!("{}-{}", &manifest.id[..8], name))
+    } else {
+        path
+    }
+}
+
+/// Returns a file name safe to store under, from a name chosen by the remote.
+pub fn file_name(name: &str) -> String {
+    let name: String = Path::new(name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() || name.chars().all(|c| c == '.') {
+        "file".to_string()
+    } else {
+        name
+    }
+}
@@ -0,0 +1,579 @@
+//! Peer discovery over LAN multicast
+//!
+//! A rover and its base station on the same network should not need a
+//! signaling server to find each other. A peer listening in mesh mode with
+//! discovery enabled runs an [`Announcer`]: it multicasts a beacon with its
+//! alias to the discovery group at a fixed interval, and at once when another
+//! peer queries the group. A peer connecting to it [`locate`]s its alias
+//! among the beacons and sends the SDP offer straight to the address the
+//! beacon came from; the listening peer answers to where the offer came from.
+//! ICE, DTLS and the data channels then run as in mesh mode through the
+//! server.
+//!
+//! All candidates are gathered before the offer is sent, so nothing is
+//! trickled afterwards, and ICE restarts are not supported. Datagrams can be
+//! lost: offers are retransmitted until answered, and the announcer answers
+//! a retransmitted offer again. Messages are JSON datagrams; anything that
+//! does not decode, e.g. another application's traffic on the group, is
+//! ignored.
+//!
+//! Anyone on the LAN can read the beacons and connect; the DTLS fingerprints
+//! in the SDP are not authenticated by a server. Enable it on trusted
+//! networks only.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use str0m::change::{SdpAnswer, SdpOffer};
+use tracing::{debug, info, warn};
+
+use crate::error::RoverRtcError;
+
+/// Interval at which the announcer checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Interval between retransmissions of an unanswered offer.
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Largest datagram received; SDPs with many candidates exceed an MTU.
+const MAX_DATAGRAM: usize = 65_507;
+
+/// LAN discovery settings, the `[peer.discovery]` configuration section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Locate and answer mesh peers on the LAN instead of through the
+    /// signaling server
+    pub enabled: bool,
+    /// Multicast group and port of the beacons and queries
+    pub group: SocketAddrV4,
+    /// Address of the interface to multicast on; the default route's if unset
+    pub interface: Option<Ipv4Addr>,
+    /// Number of routers the beacons may cross; 1 keeps them on the LAN
+    pub ttl: u32,
+    /// Interval between two beacons of a listening peer, in milliseconds
+    pub interval_ms: u64,
+    /// How long a connecting peer looks for its target and waits for the
+    /// answer, in seconds
+    pub timeout_secs: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 99), 47474),
+            interface: None,
+            ttl: 1,
+            interval_ms: 1000,
+            timeout_secs: 30,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    /// Checks that the group is a multicast address and the timings usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.group.ip().is_multicast() {
+            return Err(format!("group {} is not a multicast address", self.group));
+        }
+        if self.group.port() == 0 {
+            return Err("group needs a port".into());
+        }
+        if !(1..=255).contains(&self.ttl) {
+            return Err("ttl must be between 1 and 255".into());
+        }
+        if self.interval_ms == 0 || self.timeout_secs == 0 {
+            return Err("interval_ms and timeout_secs must be positive".into());
+        }
+        Ok(())
+    }
+
+    /// Returns the interval between two beacons.
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    /// Returns how long to look for a peer and wait for its answer.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+/// A datagram exchanged on the LAN.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum DiscoveryMessage {
+    /// A listening peer announcing itself; offers go to the datagram's source
+    Beacon {
+        alias: String,
+        /// The version of the software the peer runs
+        version: String,
+    },
+    /// Asks the listening peers, or the one with an alias, for a beacon
+    Query {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        alias: Option<String>,
+    },
+    /// An offer to the listening peer the datagram is sent to
+    Offer {
+        id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
+        offer: SdpOffer,
+    },
+    /// The answer to an offer
+    Answer { id: String, answer: SdpAnswer },
+    /// The listening peer does not take an offer
+    Refused { id: String, reason: String },
+}
+
+impl DiscoveryMessage {
+    fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("discovery message to serialise")
+    }
+
+    fn decode(datagram: &[u8]) -> Option<Self> {
+        serde_json::from_slice(datagram).ok()
+    }
+}
+
+/// A peer found on the LAN.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Beacon {
+    /// The alias the peer listens under
+    pub alias: String,
+    /// Where offers to the peer go
+    pub addr: SocketAddr,
+    /// The version of the software the peer runs
+    pub version: String,
+}
+
+/// An offer the announcer received.
+#[derive(Debug)]
+pub struct LanOffer {
+    /// Identifies the offer when answering it
+    pub id: String,
+    /// The alias the offering peer announced, if any
+    pub from: Option<String>,
+    /// The offering peer's SDP offer
+    pub offer: SdpOffer,
+}
+
+/// State shared by the announcer and its thread.
+#[derive(Debug, Default)]
+struct Offers {
+    /// The offer taken, and where its answer goes
+    taken: Option<(String, SocketAddr)>,
+    /// The encoded answer to the offer taken, once answered
+    answer: Option<Vec<u8>>,
+}
+
+/// Announces a listening peer on the LAN and receives the offers to it.
+///
+/// The announcer takes the first offer and refuses others, since a peer
+/// answers a single offer per session. It runs on its own thread until
+/// dropped, re-answering the offer it took if the answer was lost.
+#[derive(Debug)]
+pub struct Announcer {
+    socket: UdpSocket,
+    offers: Arc<Mutex<Offers>>,
+    received: Receiver<LanOffer>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Announcer {
+    /// Starts announcing a peer.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The discovery settings
+    /// * `alias` - The alias the peer listens under
+    ///
+    /// # Returns
+    ///
+    /// The announcer, or an error if the sockets could not be bound or the
+    /// group joined
+    pub fn start(config: &DiscoveryConfig, alias: &str) -> io::Result<Self> {
+        let group = group_socket(config)?;
+        let socket = unicast_socket(config)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let offers = Arc::new(Mutex::new(Offers::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, received) = mpsc::channel();
+        {
+            let announcement = Announcement {
+                socket: socket.try_clone()?,
+                group,
+                config: config.clone(),
+                beacon: DiscoveryMessage::Beacon {
+                    alias: alias.to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                }
+                .encode(),
+                alias: alias.to_string(),
+            };
+            let offers = offers.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("discovery".to_string())
+                .spawn(move || announcement.run(&offers, &sender, &stop))?;
+        }
+        info!(
+            "Announcing '{}' on {} from {}",
+            alias,
+            config.group,
+            socket.local_addr()?
+        );
+        Ok(Self {
+            socket,
+            offers,
+            received,
+            stop,
+        })
+    }
+
+    /// Returns the offer received since the last call, if any.
+    pub fn next_offer(&self) -> Option<LanOffer> {
+        self.received.try_recv().ok()
+    }
+
+    /// Answers the offer taken.
+    ///
+    /// # Returns
+    ///
+    /// An error if no offer with the ID was taken or the answer could not
+    /// be sent
+    pub fn answer(&self, id: &str, answer: SdpAnswer) -> Result<(), RoverRtcError> {
+        let mut offers = self.offers.lock().expect("discovery offers lock");
+        let Some((_, addr)) = offers.taken.clone().filter(|(taken, _)| taken == id) else {
+            return Err(RoverRtcError::Signaling(format!(
+                "no offer {} to answer",
+                id
+            )));
+        };
+        let answer = DiscoveryMessage::Answer {
+            id: id.to_string(),
+            answer,
+        }
+        .encode();
+        self.socket.send_to(&answer, addr)?;
+        offers.answer = Some(answer);
+        Ok(())
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The announcer's thread.
+struct Announcement {
+    /// Sends the beacons and receives the offers
+    socket: UdpSocket,
+    /// Receives the queries sent to the group
+    group: UdpSocket,
+    config: DiscoveryConfig,
+    beacon: Vec<u8>,
+    alias: String,
+}
+
+impl Announcement {
+    /// Beacons, answers queries and takes offers until stopped.
+    fn run(self, offers: &Mutex<Offers>, received: &Sender<LanOffer>, stop: &AtomicBool) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut next_beacon = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            // A peer that took an offer is no longer available
+            let available = offers
+                .lock()
+                .expect("discovery offers lock")
+                .taken
+                .is_none();
+            if available && Instant::now() >= next_beacon {
+                self.send_beacon();
+                next_beacon = Instant::now() + self.config.interval();
+            }
+            while let Ok((len, source)) = self.group.recv_from(&mut buf) {
+                if let Some(DiscoveryMessage::Query { alias }) =
+                    DiscoveryMessage::decode(&buf[..len])
+                {
+                    if available && alias.is_none_or(|alias| alias == self.alias) {
+                        debug!("Answering the discovery query from {}", source);
+                        self.send_beacon();
+                    }
+                }
+            }
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, source)) => {
+                    if let Some(DiscoveryMessage::Offer { id, from, offer }) =
+                        DiscoveryMessage::decode(&buf[..len])
+                    {
+                        self.take_offer(LanOffer { id, from, offer }, source, offers, received);
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => {
+                    warn!("Discovery announcer stopped: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn send_beacon(&self) {
+        let group = SocketAddr::V4(self.config.group);
+        if let Err(e) = self.socket.send_to(&self.beacon, group) {
+            debug!("Failed to send a discovery beacon: {}", e);
+        }
+    }
+
+    /// Takes the first offer, re-answers its retransmissions and refuses
+    /// any other.
+    fn take_offer(
+        &self,
+        offer: LanOffer,
+        source: SocketAddr,
+        offers: &Mutex<Offers>,
+        received: &Sender<LanOffer>,
+    ) {
+        let mut offers = offers.lock().expect("discovery offers lock");
+        let reply = match &offers.taken {
+            None => {
+                offers.taken = Some((offer.id.clone(), source));
+                let _ = received.send(offer);
+                return;
+            }
+            Some((id, _)) if *id == offer.id => match &offers.answer {
+                Some(answer) => answer.clone(),
+                // The answer is not ready yet
+                None => return,
+            },
+            Some(_) => DiscoveryMessage::Refused {
+                id: offer.id,
+                reason: format!("'{}' is already connecting", self.alias),
+            }
+            .encode(),
+        };
+        if let Err(e) = self.socket.send_to(&reply, source) {
+            debug!("Failed to reply to the offer from {}: {}", source, e);
+        }
+    }
+}
+
+/// Looks for the peers announcing themselves on the LAN.
+///
+/// # Arguments
+///
+/// * `config` - The discovery settings
+/// * `duration` - How long to collect beacons
+///
+/// # Returns
+///
+/// The peers found, once each, or an error if the sockets could not be bound
+pub fn browse(config: &DiscoveryConfig, duration: Duration) -> io::Result<Vec<Beacon>> {
+    let mut found: Vec<Beacon> = vec![];
+    collect_beacons(config, None, duration, |beacon| {
+        if !found.iter().any(|f| f.alias == beacon.alias) {
+            found.push(beacon);
+        }
+        false
+    })?;
+    Ok(found)
+}
+
+/// Looks for the peer listening under an alias.
+///
+/// # Returns
+///
+/// The peer's beacon, or an error if it was not found before the timeout
+pub fn locate(config: &DiscoveryConfig, alias: &str) -> Result<Beacon, RoverRtcError> {
+    let mut located = None;
+    collect_beacons(config, Some(alias), config.timeout(), |beacon| {
+        let matches = beacon.alias == alias;
+        if matches {
+            located = Some(beacon);
+        }
+        matches
+    })?;
+    located.ok_or_else(|| RoverRtcError::Signaling(format!("no peer '{}' found on the LAN", alias)))
+}
+
+/// Sends an offer to the peer listening under an alias on the LAN.
+///
+/// # Arguments
+///
+/// * `config` - The discovery settings
+/// * `from` - The alias of the offering peer, if any
+/// * `target` - The alias of the listening peer
+/// * `offer` - The SDP offer
+///
+/// # Returns
+///
+/// The listening peer's answer, or an error if it was not found, refused
+/// the offer or did not answer before the timeout
+pub fn offer(
+    config: &DiscoveryConfig,
+    from: Option<&str>,
+    target: &str,
+    offer: SdpOffer,
+) -> Result<SdpAnswer, RoverRtcError> {
+    let beacon = locate(config, target)?;
+    info!(
+        "Found '{}' at {} (version {})",
+        beacon.alias, beacon.addr, beacon.version
+    );
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let id = format!("{:016x}", rand::thread_rng().next_u64());
+    let datagram = DiscoveryMessage::Offer {
+        id: id.clone(),
+        from: from.map(str::to_string),
+        offer,
+    }
+    .encode();
+
+    let deadline = Instant::now() + config.timeout();
+    let mut buf = vec![0; MAX_DATAGRAM];
+    while Instant::now() < deadline {
+        socket.send_to(&datagram, beacon.addr)?;
+        let retransmit = Instant::now() + RETRANSMIT_INTERVAL;
+        while let Some(wait) = retransmit
+            .min(deadline)
+            .checked_duration_since(Instant::now())
+            .filter(|wait| !wait.is_zero())
+        {
+            socket.set_read_timeout(Some(wait))?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                // The port may not be open yet, or the peer restarted
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => break,
+                Err(e) => return Err(e.into()),
+            };
+            match DiscoveryMessage::decode(&buf[..len]) {
+                Some(DiscoveryMessage::Answer {
+                    id: answered,
+                    answer,
+                }) if answered == id => return Ok(answer),
+                Some(DiscoveryMessage::Refused {
+                    id: refused,
+                    reason,
+                }) if refused == id => return Err(RoverRtcError::Signaling(reason)),
+                _ => {}
+            }
+        }
+    }
+    Err(RoverRtcError::Signaling(format!(
+        "'{}' did not answer the offer",
+        target
+    )))
+}
+
+/// Queries the group and passes the beacons heard to a callback until it
+/// returns `true` or the time is up.
+///
+/// The query is repeated at the beacon interval, at most every
+/// [`RETRANSMIT_INTERVAL`], in case it or the beacons were lost.
+fn collect_beacons(
+    config: &DiscoveryConfig,
+    alias: Option<&str>,
+    duration: Duration,
+    mut found: impl FnMut(Beacon) -> bool,
+) -> io::Result<()> {
+    let group = group_socket(config)?;
+    let socket = unicast_socket(config)?;
+    let query = DiscoveryMessage::Query {
+        alias: alias.map(str::to_string),
+    }
+    .encode();
+    let requery = config.interval().min(RETRANSMIT_INTERVAL);
+    let deadline = Instant::now() + duration;
+    let mut next_query = Instant::now();
+    let mut buf = vec![0; MAX_DATAGRAM];
+    while Instant::now() < deadline {
+        if Instant::now() >= next_query {
+            socket.send_to(&query, SocketAddr::V4(config.group))?;
+            next_query = Instant::now() + requery;
+        }
+        match group.recv_from(&mut buf) {
+            Ok((len, source)) => {
+                if let Some(DiscoveryMessage::Beacon { alias, version }) =
+                    DiscoveryMessage::decode(&buf[..len])
+                {
+                    let beacon = Beacon {
+                        alias,
+                        addr: source,
+                        version,
+                    };
+                    if found(beacon) {
+                        return Ok(());
+                    }
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                thread::sleep(Duration::from_millis(20))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Binds a non-blocking socket to the group's port, shared with the other
+/// peers on the host, and joins the group.
+fn group_socket(config: &DiscoveryConfig) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.group.port())).into())?;
+    socket.join_multicast_v4(
+        config.group.ip(),
+        &config.interface.unwrap_or(Ipv4Addr::UNSPECIFIED),
+    )?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Binds a socket to an ephemeral port that multicasts on the configured
+/// interface and hop limit.
+fn unicast_socket(config: &DiscoveryConfig) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(config.ttl)?;
+    socket.set_multicast_loop_v4(true)?;
+    if let Some(interface) = config.interface {
+        Socket::from(socket.try_clone()?).set_multicast_if_v4(&interface)?;
+    }
+    Ok(socket)
+}
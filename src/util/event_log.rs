@@ -0,0 +1,248 @@
+//! Runtime-configurable logging of high-rate client events
+//!
+//! Logging every data channel message at info is unusable at telemetry rates.
+//! This module lets each event kind be logged at a chosen level, sampled (one
+//! in N), aggregated into per-second counts, or switched off. The configuration
+//! is global and can be changed at runtime; each client keeps its own sampling
+//! state.
+
+use std::{
+    collections::HashMap,
+    env, fmt,
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info};
+
+/// Environment variable with the initial configuration, e.g.
+/// `channel_data=sample:100,transmit=off`.
+pub const EVENT_LOG_ENV: &str = "ROVER_EVENT_LOG";
+
+/// Interval over which aggregated events are counted.
+const AGGREGATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The kinds of client events with configurable verbosity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// ICE connection state changes
+    IceState,
+    /// Data channels opening
+    ChannelOpen,
+    /// Data channel messages
+    ChannelData,
+    /// UDP packets transmitted
+    Transmit,
+    /// Any other str0m event
+    Other,
+}
+
+impl EventKind {
+    /// All event kinds, in configuration order.
+    pub const ALL: [EventKind; 5] = [
+        EventKind::IceState,
+        EventKind::ChannelOpen,
+        EventKind::ChannelData,
+        EventKind::Transmit,
+        EventKind::Other,
+    ];
+
+    /// The name used in configuration strings.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::IceState => "ice_state",
+            EventKind::ChannelOpen => "channel_open",
+            EventKind::ChannelData => "channel_data",
+            EventKind::Transmit => "transmit",
+            EventKind::Other => "other",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EventKind::ALL
+            .into_iter()
+            .find(|k| k.name() == s)
+            .ok_or_else(|| format!("unknown event kind '{}'", s))
+    }
+}
+
+/// How events of a kind are logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Not logged
+    Off,
+    /// Every event at debug level
+    Debug,
+    /// Every event at info level
+    Info,
+    /// One in every N events at info level
+    Sample(u32),
+    /// A per-second count at info level instead of individual events
+    Aggregate,
+}
+
+impl fmt::Display for Verbosity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verbosity::Off => f.write_str("off"),
+            Verbosity::Debug => f.write_str("debug"),
+            Verbosity::Info => f.write_str("info"),
+            Verbosity::Sample(n) => write!(f, "sample:{}", n),
+            Verbosity::Aggregate => f.write_str("aggregate"),
+        }
+    }
+}
+
+impl FromStr for Verbosity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Verbosity::Off),
+            "debug" => Ok(Verbosity::Debug),
+            "info" => Ok(Verbosity::Info),
+            "aggregate" => Ok(Verbosity::Aggregate),
+            _ => s
+                .strip_prefix("sample:")
+                .and_then(|n| n.parse().ok())
+                .filter(|n| *n > 0)
+                .map(Verbosity::Sample)
+                .ok_or_else(|| format!("invalid verbosity '{}'", s)),
+        }
+    }
+}
+
+/// The active verbosity per event kind, indexed by [`EventKind`].
+static CONFIG: RwLock<[Verbosity; 5]> = RwLock::new([
+    Verbosity::Info,
+    Verbosity::Info,
+    Verbosity::Info,
+    Verbosity::Debug,
+    Verbosity::Debug,
+]);
+
+/// Returns the verbosity of an event kind.
+pub fn verbosity(kind: EventKind) -> Verbosity {
+    CONFIG.read().expect("event log config lock")[kind.index()]
+}
+
+/// Sets the verbosity of an event kind.
+pub fn set_verbosity(kind: EventKind, verbosity: Verbosity) {
+    CONFIG.write().expect("event log config lock")[kind.index()] = verbosity;
+}
+
+/// Returns the whole configuration as `kind -> verbosity` strings.
+pub fn current_config() -> HashMap<&'static str, String> {
+    EventKind::ALL
+        .into_iter()
+        .map(|k| (k.name(), verbosity(k).to_string()))
+        .collect()
+}
+
+/// Applies a configuration string such as `channel_data=sample:100,transmit=off`.
+///
+/// Entries are validated before any of them is applied.
+pub fn apply_config(spec: &str) -> Result<(), String> {
+    let mut updates = vec![];
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (kind, level) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("expected kind=verbosity, got '{}'", entry))?;
+        updates.push((kind.trim().parse()?, level.trim().parse()?));
+    }
+    for (kind, level) in updates {
+        set_verbosity(kind, level);
+    }
+    Ok(())
+}
+
+/// Applies the configuration from [`EVENT_LOG_ENV`], if set.
+pub fn init_from_env() -> Result<(), String> {
+    match env::var(EVENT_LOG_ENV) {
+        Ok(spec) => apply_config(&spec),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Per-client sampling and aggregation state.
+#[derive(Debug)]
+pub struct EventLogger {
+    seen: [u64; 5],
+    aggregated: [u64; 5],
+    window_start: Instant,
+}
+
+impl Default for EventLogger {
+    fn default() -> Self {
+        Self {
+            seen: [0; 5],
+            aggregated: [0; 5],
+            window_start: Instant::now(),
+        }
+    }
+}
+
+impl EventLogger {
+    /// Creates a logger with empty counters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Logs an event according to the configured verbosity of its kind.
+    ///
+    /// The message is only built if the event is actually logged.
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - Prefix identifying the client, e.g. `Client(3)`
+    /// * `kind` - The kind of the event
+    /// * `message` - Builds the log message
+    pub fn log(&mut self, owner: &str, kind: EventKind, message: impl FnOnce() -> String) {
+        let i = kind.index();
+        self.seen[i] += 1;
+
+        match verbosity(kind) {
+            Verbosity::Off => {}
+            Verbosity::Debug => debug!("{} {}", owner, message()),
+            Verbosity::Info => info!("{} {}", owner, message()),
+            Verbosity::Sample(n) => {
+                if (self.seen[i] - 1).is_multiple_of(n as u64) {
+                    info!("{} {} [1/{}]", owner, message(), n);
+                }
+            }
+            Verbosity::Aggregate => self.aggregated[i] += 1,
+        }
+
+        self.flush_aggregates(owner);
+    }
+
+    /// Emits the per-second counts of aggregated kinds once the interval elapsed.
+    fn flush_aggregates(&mut self, owner: &str) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < AGGREGATE_INTERVAL {
+            return;
+        }
+        for kind in EventKind::ALL {
+            let count = std::mem::take(&mut self.aggregated[kind.index()]);
+            if count > 0 {
+                info!(
+                    "{} {} {} events in {:.1}s",
+                    owner,
+                    count,
+                    kind.name(),
+                    elapsed.as_secs_f64()
+                );
+            }
+        }
+        self.window_start = Instant::now();
+    }
+}
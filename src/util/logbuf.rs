@@ -0,0 +1,180 @@
+//! In-memory buffer of recent log lines
+//!
+//! [`crate::util::init_log`] installs [`LogBufferLayer`] next to the stdout
+//! formatter, so the most recent lines of the process can be served over the
+//! connection itself when there is no shell on the machine. Only events the
+//! environment filter lets through are recorded, and the oldest lines are
+//! dropped once [`CAPACITY`] is reached.
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write as _},
+    io::{Read, Write},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use tracing::{field::Field, Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::model::logs::{LogLevel, LogQuery};
+
+/// Number of lines kept in memory.
+pub const CAPACITY: usize = 10_000;
+
+/// The recorded lines, oldest first.
+static LINES: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::new());
+
+/// A recorded log line.
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    /// When the event was recorded
+    pub at: DateTime<Utc>,
+    /// The level of the event
+    pub level: LogLevel,
+    /// The module that logged the event
+    pub target: String,
+    /// The message followed by the other fields of the event
+    pub message: String,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:>5} {}: {}",
+            self.at.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            self.level.name().to_uppercase(),
+            self.target,
+            self.message
+        )
+    }
+}
+
+/// A tracing layer recording every event it sees into the buffer.
+pub struct LogBufferLayer;
+
+impl<S: Subscriber> Layer<S> for LogBufferLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        let line = LogLine {
+            at: Utc::now(),
+            level: LogLevel::from(*metadata.level()),
+            target: metadata.target().to_string(),
+            message: visitor.message,
+        };
+        let Ok(mut lines) = LINES.lock() else {
+            return;
+        };
+        if lines.len() >= CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+/// Collects the message and fields of an event into one string.
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+}
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let fields = std::mem::take(&mut self.message);
+            let _ = write!(self.message, "{:?}{}", value, fields);
+        } else {
+            let _ = write!(self.message, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.record_debug(field, &format_args!("{}", value));
+        } else {
+            let _ = write!(self.message, " {}={}", field.name(), value);
+        }
+    }
+}
+
+/// Returns the recorded lines matching a query, oldest first.
+///
+/// # Arguments
+///
+/// * `query` - The filters; `tail` limits the result to the newest matches
+///
+/// # Returns
+///
+/// The matching lines, one per line of text
+pub fn query(query: &LogQuery) -> String {
+    let since = query
+        .since_secs
+        .map(|secs| Utc::now() - chrono::Duration::seconds(secs as i64));
+    let Ok(lines) = LINES.lock() else {
+        return String::new();
+    };
+
+    let mut matches: Vec<&LogLine> = lines
+        .iter()
+        .rev()
+        .filter(|line| query.level.is_none_or(|level| line.level <= level))
+        .filter(|line| since.is_none_or(|since| line.at >= since))
+        .filter(|line| {
+            query
+                .contains
+                .as_deref()
+                .is_none_or(|needle| line.message.contains(needle) || line.target.contains(needle))
+        })
+        .take(query.tail as usize)
+        .collect();
+    matches.reverse();
+
+    let mut text = String::new();
+    for line in matches {
+        let _ = writeln!(text, "{}", line);
+    }
+    text
+}
+
+/// Compresses log text with raw deflate.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    // Writing into a Vec cannot fail
+    encoder.write_all(data).expect("Compression failed");
+    encoder.finish().expect("Compression failed")
+}
+
+/// Decompresses log text compressed by [`compress`].
+///
+/// # Returns
+///
+/// * `Some(Vec<u8>)` - The original text
+/// * `None` - If the data is not valid deflate
+pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut text = Vec::new();
+    DeflateDecoder::new(data).read_to_end(&mut text).ok()?;
+    Some(text)
+}
+
+/// Returns the newest recorded lines without waiting for the buffer, for
+/// code that may run while the buffer is locked, such as a panic hook.
+///
+/// # Arguments
+///
+/// * `tail` - Maximum number of lines
+///
+/// # Returns
+///
+/// The lines, oldest first, or none if the buffer is locked or poisoned
+pub fn try_recent(tail: usize) -> Vec<String> {
+    let Ok(lines) = LINES.try_lock() else {
+        return vec![];
+    };
+    let skip = lines.len().saturating_sub(tail);
+    lines.iter().skip(skip).map(ToString::to_string).collect()
+}
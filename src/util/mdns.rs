@@ -0,0 +1,520 @@
+//! mDNS host candidates
+//!
+//! Browsers hide the local addresses of their host candidates behind random
+//! `<uuid>.local` names, which they answer over multicast DNS on the LAN. The
+//! server and listening peers resolve such candidates with an
+//! [`MdnsResolver`] before handing the SDP to str0m, which only understands
+//! IP addresses. A peer with `mdns_candidates` enabled does the same as a
+//! browser: its [`MdnsResponder`] replaces the addresses of its host
+//! candidates with names in what it signals, and answers the queries for them.
+//!
+//! Only the questions and answers needed for this are implemented: queries
+//! for A and AAAA records, and responses with a single address.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::RngCore;
+use socket2::{Domain, Protocol, Socket, Type};
+use str0m::change::{SdpAnswer, SdpOffer};
+use tracing::{debug, info, warn};
+
+/// The mDNS multicast group and port.
+const MDNS_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// Record types.
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+
+/// Class IN, and the bit asking for a unicast response in questions or
+/// flushing caches in answers.
+const CLASS_IN: u16 = 1;
+const CLASS_UNICAST: u16 = 0x8000;
+
+/// Time to live of the answers, and of the resolver's cache entries.
+const TTL: Duration = Duration::from_secs(120);
+
+/// Interval at which the responder checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Resolves the `.local` names of remote candidates.
+#[derive(Debug)]
+pub struct MdnsResolver {
+    timeout: Duration,
+    cache: Mutex<HashMap<String, (IpAddr, Instant)>>,
+}
+
+impl MdnsResolver {
+    /// Creates a resolver.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait for the answer to a query
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolves a `.local` name, from the cache if it was resolved recently.
+    ///
+    /// # Returns
+    ///
+    /// The address, or `None` if nobody answered in time
+    pub fn resolve(&self, name: &str) -> Option<IpAddr> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some((addr, at)) = self.cache.lock().expect("mDNS cache lock").get(&name) {
+            if at.elapsed() < TTL {
+                return Some(*addr);
+            }
+        }
+        match query(&name, self.timeout) {
+            Ok(Some(addr)) => {
+                debug!("Resolved {} to {}", name, addr);
+                self.cache
+                    .lock()
+                    .expect("mDNS cache lock")
+                    .insert(name, (addr, Instant::now()));
+                Some(addr)
+            }
+            Ok(None) => {
+                warn!("No mDNS answer for {}", name);
+                None
+            }
+            Err(e) => {
+                warn!("Failed to query {} over mDNS: {}", name, e);
+                None
+            }
+        }
+    }
+
+    /// Replaces the `.local` addresses of the candidates in an SDP, or of a
+    /// single candidate line, with the addresses they resolve to.
+    ///
+    /// Candidates that cannot be resolved are left as they are; str0m ignores
+    /// them.
+    pub fn resolve_sdp(&self, sdp: &str) -> String {
+        map_candidates(sdp, |address| {
+            if !address.ends_with(".local") {
+                return None;
+            }
+            self.resolve(address).map(|addr| addr.to_string())
+        })
+    }
+
+    /// Resolves the `.local` candidates of an offer, see
+    /// [`MdnsResolver::resolve_sdp`].
+    pub fn resolve_offer(&self, offer: SdpOffer) -> SdpOffer {
+        let sdp = offer.to_sdp_string();
+        if !sdp.contains(".local ") {
+            return offer;
+        }
+        SdpOffer::from_sdp_string(&self.resolve_sdp(&sdp)).unwrap_or(offer)
+    }
+
+    /// Resolves the `.local` candidates of an answer, see
+    /// [`MdnsResolver::resolve_sdp`].
+    pub fn resolve_answer(&self, answer: SdpAnswer) -> SdpAnswer {
+        let sdp = answer.to_sdp_string();
+        if !sdp.contains(".local ") {
+            return answer;
+        }
+        SdpAnswer::from_sdp_string(&self.resolve_sdp(&sdp)).unwrap_or(answer)
+    }
+}
+
+/// Answers the mDNS queries for the names concealing local addresses.
+///
+/// The responder runs on its own thread until dropped.
+#[derive(Debug)]
+pub struct MdnsResponder {
+    names: Arc<Mutex<HashMap<String, IpAddr>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl MdnsResponder {
+    /// Starts answering on the mDNS port, shared with other responders on
+    /// the host.
+    ///
+    /// # Returns
+    ///
+    /// The responder, or an error if the port could not be bound
+    pub fn start() -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_ADDR.port())).into())?;
+        socket.join_multicast_v4(MDNS_ADDR.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let socket: UdpSocket = socket.into();
+
+        let names = Arc::new(Mutex::new(HashMap::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let names = names.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("mdns".to_string())
+                .spawn(move || respond(socket, &names, &stop))?;
+        }
+        info!("Concealing host candidates behind mDNS names");
+        Ok(Self { names, stop })
+    }
+
+    /// Returns the name concealing a local address, creating it on first use.
+    pub fn name_for(&self, addr: IpAddr) -> String {
+        let mut names = self.names.lock().expect("mDNS names lock");
+        if let Some((name, _)) = names.iter().find(|(_, a)| **a == addr) {
+            return name.clone();
+        }
+        let name = format!("{}.local", random_uuid());
+        names.insert(name.clone(), addr);
+        name
+    }
+
+    /// Replaces the addresses of the host candidates in an SDP, or of a
+    /// single candidate line, with their names, and the related addresses
+    /// of reflexive and relayed candidates with `0.0.0.0`.
+    pub fn conceal_sdp(&self, sdp: &str) -> String {
+        let concealed = map_candidates(sdp, |address| {
+            let addr = address.parse::<IpAddr>().ok()?;
+            Some(self.name_for(addr))
+        });
+        conceal_related(&concealed)
+    }
+
+    /// Conceals the host candidates of an offer, see
+    /// [`MdnsResponder::conceal_sdp`].
+    pub fn conceal_offer(&self, offer: SdpOffer) -> SdpOffer {
+        SdpOffer::from_sdp_string(&self.conceal_sdp(&offer.to_sdp_string())).unwrap_or(offer)
+    }
+
+    /// Conceals the host candidates of an answer, see
+    /// [`MdnsResponder::conceal_sdp`].
+    pub fn conceal_answer(&self, answer: SdpAnswer) -> SdpAnswer {
+        SdpAnswer::from_sdp_string(&self.conceal_sdp(&answer.to_sdp_string())).unwrap_or(answer)
+    }
+}
+
+impl Drop for MdnsResponder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Rewrites the address of every host candidate in an SDP or candidate line.
+///
+/// # Arguments
+///
+/// * `sdp` - The SDP, or a single candidate with or without `a=`
+/// * `map` - Returns the new address, or `None` to keep the candidate
+fn map_candidates(sdp: &str, mut map: impl FnMut(&str) -> Option<String>) -> String {
+    let mut output = String::with_capacity(sdp.len());
+    for line in sdp.split_inclusive('\n') {
+        let body = line.trim_end_matches(['\r', '\n']);
+        let ending = &line[body.len()..];
+        let is_candidate = body.starts_with("a=candidate:") || body.starts_with("candidate:");
+        let fields: Vec<&str> = body.split(' ').collect();
+        let host = fields
+            .iter()
+            .position(|f| *f == "typ")
+            .is_some_and(|i| fields.get(i + 1) == Some(&"host"));
+        let mapped = if is_candidate && host && fields.len() > 4 {
+            map(fields[4])
+        } else {
+            None
+        };
+        match mapped {
+            Some(address) => {
+                let (before, after) = (fields[..4].join(" "), fields[5..].join(" "));
+                output.push_str(&format!("{} {} {}{}", before, address, after, ending));
+            }
+            None => output.push_str(line),
+        }
+    }
+    output
+}
+
+/// Replaces the related address of reflexive and relayed candidates with
+/// `0.0.0.0`, as browsers do, since it is usually a local address.
+fn conceal_related(sdp: &str) -> String {
+    let mut output = String::with_capacity(sdp.len());
+    for line in sdp.split_inclusive('\n') {
+        let mut fields: Vec<&str> = line.split(' ').collect();
+        if let Some(i) = fields.iter().position(|f| *f == "raddr") {
+            if fields.len() > i + 3 && fields[i + 2] == "rport" {
+                fields[i + 1] = "0.0.0.0";
+                fields[i + 3] = "0";
+            }
+        }
+        output.push_str(&fields.join(" "));
+    }
+    output
+}
+
+/// Sends a one-shot query for the A and AAAA records of a name and waits for
+/// the first answer.
+///
+/// The query comes from an ephemeral port, so responders answer it directly
+/// rather than to the group (RFC 6762, section 6.7).
+fn query(name: &str, timeout: Duration) -> io::Result<Option<IpAddr>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let id = rand::thread_rng().next_u32() as u16;
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        socket.send_to(&encode_query(id, name, qtype), MDNS_ADDR)?;
+    }
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 1500];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(addr) = parse_answer(&buf[..len], name) {
+            return Ok(Some(addr));
+        }
+    }
+}
+
+/// Answers the queries for the responder's names until stopped.
+fn respond(socket: UdpSocket, names: &Mutex<HashMap<String, IpAddr>>, stop: &AtomicBool) {
+    let mut buf = [0; 1500];
+    while !stop.load(Ordering::Relaxed) {
+        let (len, source) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => {
+                warn!("mDNS responder stopped: {}", e);
+                return;
+            }
+        };
+        let Some((id, questions)) = parse_query(&buf[..len]) else {
+            continue;
+        };
+        for (name, qtype, qclass) in questions {
+            let Some(addr) = names.lock().expect("mDNS names lock").get(&name).copied() else {
+                continue;
+            };
+            let wanted = match addr {
+                IpAddr::V4(_) => TYPE_A,
+                IpAddr::V6(_) => TYPE_AAAA,
+            };
+            if qtype != wanted && qtype != TYPE_ANY {
+                continue;
+            }
+            // Queries from other ports than 5353 are one-shot and expect a
+            // direct answer echoing the question
+            let legacy = source.port() != MDNS_ADDR.port();
+            let response = encode_answer(if legacy { id } else { 0 }, &name, addr, legacy);
+            let destination = if legacy || qclass & CLASS_UNICAST != 0 {
+                source
+            } else {
+                MDNS_ADDR.into()
+            };
+            debug!("Answering the mDNS query for {} from {}", name, source);
+            if let Err(e) = socket.send_to(&response, destination) {
+                debug!("Failed to answer the mDNS query for {}: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Encodes a query for one record of a name, asking for a unicast response.
+fn encode_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut message = Vec::with_capacity(name.len() + 18);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(&mut message, name);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&(CLASS_IN | CLASS_UNICAST).to_be_bytes());
+    message
+}
+
+/// Encodes an authoritative answer with the address of a name.
+///
+/// # Arguments
+///
+/// * `id` - The ID of the query, `0` for multicast answers
+/// * `name` - The name
+/// * `addr` - Its address
+/// * `legacy` - Whether to echo the question and leave the cache flush bit
+///   unset, as one-shot queries require
+fn encode_answer(id: u16, name: &str, addr: IpAddr, legacy: bool) -> Vec<u8> {
+    let (rtype, rdata) = match addr {
+        IpAddr::V4(v4) => (TYPE_A, v4.octets().to_vec()),
+        IpAddr::V6(v6) => (TYPE_AAAA, v6.octets().to_vec()),
+    };
+    let mut message = Vec::with_capacity(2 * name.len() + 40);
+    message.extend_from_slice(&id.to_be_bytes());
+    // A response with the authoritative answer bit
+    message.extend_from_slice(&[0x84, 0, 0, legacy as u8, 0, 1, 0, 0, 0, 0]);
+    if legacy {
+        encode_name(&mut message, name);
+        message.extend_from_slice(&rtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    encode_name(&mut message, name);
+    message.extend_from_slice(&rtype.to_be_bytes());
+    let class = if legacy {
+        CLASS_IN
+    } else {
+        CLASS_IN | CLASS_UNICAST
+    };
+    message.extend_from_slice(&class.to_be_bytes());
+    message.extend_from_slice(&(TTL.as_secs() as u32).to_be_bytes());
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(&rdata);
+    message
+}
+
+fn encode_name(message: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.') {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        message.push(label.len() as u8);
+        message.extend_from_slice(label);
+    }
+    message.push(0);
+}
+
+/// A question of a query: the lowercase name, the record type and the class.
+type Question = (String, u16, u16);
+
+/// Parses the questions of a query.
+///
+/// # Returns
+///
+/// The ID and the questions, or `None` if the message is a response or
+/// malformed
+fn parse_query(message: &[u8]) -> Option<(u16, Vec<Question>)> {
+    let header = message.get(..12)?;
+    if header[2] & 0x80 != 0 {
+        return None;
+    }
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let count = u16::from_be_bytes([header[4], header[5]]);
+    let mut offset = 12;
+    let mut questions = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let (name, next) = parse_name(message, offset)?;
+        let fields = message.get(next..next + 4)?;
+        questions.push((
+            name,
+            u16::from_be_bytes([fields[0], fields[1]]),
+            u16::from_be_bytes([fields[2], fields[3]]),
+        ));
+        offset = next + 4;
+    }
+    Some((id, questions))
+}
+
+/// Returns the first A or AAAA record for a name in a response.
+fn parse_answer(message: &[u8], name: &str) -> Option<IpAddr> {
+    let header = message.get(..12)?;
+    if header[2] & 0x80 == 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let records = u16::from_be_bytes([header[6], header[7]])
+        + u16::from_be_bytes([header[8], header[9]])
+        + u16::from_be_bytes([header[10], header[11]]);
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = parse_name(message, offset)?.1 + 4;
+    }
+    for _ in 0..records {
+        let (owner, next) = parse_name(message, offset)?;
+        let fields = message.get(next..next + 10)?;
+        let rtype = u16::from_be_bytes([fields[0], fields[1]]);
+        let len = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+        let rdata = message.get(next + 10..next + 10 + len)?;
+        offset = next + 10 + len;
+        if owner != name {
+            continue;
+        }
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => return Some(IpAddr::from(<[u8; 4]>::try_from(rdata).ok()?)),
+            (TYPE_AAAA, 16) => return Some(IpAddr::from(<[u8; 16]>::try_from(rdata).ok()?)),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses a possibly compressed name.
+///
+/// # Returns
+///
+/// The lowercase name and the offset following it in the message
+fn parse_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = vec![];
+    let mut end = None;
+    // Bounds the pointers followed, so a loop of pointers ends
+    for _ in 0..64 {
+        let len = *message.get(offset)? as usize;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = (len & 0x3f) << 8 | *message.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            len => {
+                let label = message.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                offset += 1 + len;
+            }
+        }
+    }
+    None
+}
+
+/// Returns a random version 4 UUID, as browsers name their candidates.
+fn random_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
@@ -1,144 +1,327 @@
 //! Utility functions for network configuration
 //!
 //! This module provides helper functions for discovering network interfaces,
-//! selecting appropriate IP addresses, and generating ICE candidates for WebRTC.
+//! selecting appropriate IP addresses, binding dual-stack sockets, and
+//! generating IPv4 and IPv6 ICE candidates for WebRTC.
+
+pub mod discovery;
+pub mod event_log;
+pub mod logbuf;
+pub mod mdns;
+pub mod netmon;
+pub mod netsim;
+pub mod netwait;
+pub mod pcap;
+pub mod pmtu;
+pub mod serial;
+pub mod shutdown;
+pub mod sockopt;
+pub mod stun;
+pub mod turn;
 
 use local_ip_address::list_afinet_netifas;
-use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+use crate::{
+    config::{IpFamily, NetworkConfig},
+    error::RoverRtcError,
+};
+use socket2::{Domain, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
 use str0m::Candidate;
 use systemstat::{Platform, System};
-use tracing::info;
+use tracing::{debug, info, warn};
 
-/// Selects an appropriate IPv4 address for WebRTC communication.
+/// Selects an appropriate host address for WebRTC communication.
 ///
 /// Iterates over all network interfaces provided by `systemstat`, skipping any
-/// loopback, link-local, broadcast addresses, Docker networks, and bridge networks.
-/// Only returns interfaces that have internet access. The first routable interface
-/// with internet connectivity is returned as an [`IpAddr`].
+/// loopback, link-local and broadcast addresses, addresses of IP versions
+/// [`NetworkConfig::ip_family`] excludes, and interfaces whose name starts
+/// with one of the configured prefixes (Docker and bridge networks by default).
+/// Only returns interfaces that have internet access. The first routable
+/// address with internet connectivity is returned as an [`IpAddr`], IPv4
+/// addresses first.
+///
+/// # Arguments
+///
+/// * `network` - The interface selection settings
 ///
 /// # Returns
 ///
-/// * `IpAddr` - The first routable IPv4 network interface with internet access
+/// * `Ok(IpAddr)` - The first routable network interface with internet access
+/// * `Err(RoverRtcError)` - If the interfaces could not be listed, or none is usable
+pub fn select_host_address(network: &NetworkConfig) -> Result<IpAddr, RoverRtcError> {
+    for (name, ip_addr) in host_addresses(network)? {
+        // Verify internet connectivity by trying to bind and connect
+        if has_internet_access(&ip_addr, network) {
+            info!("Selected interface {} with IP {}", name, ip_addr);
+            return Ok(ip_addr);
+        } else {
+            info!(
+                "Interface {} has no internet access on {}, skipping",
+                name, ip_addr
+            );
+        }
+    }
+
+    Err(RoverRtcError::NoUsableInterface)
+}
+
+/// Lists the addresses that may serve as the server's host address.
 ///
-/// # Panics
+/// Skips the interfaces and addresses [`select_host_address`] does, without
+/// checking for internet access.
+///
+/// # Arguments
+///
+/// * `network` - The interface selection settings
+///
+/// # Returns
 ///
-/// Panics if the host exposes no usable IPv4 address. This is acceptable for
-/// the prototype CLI binaries, but production callers should consider wrapping
-/// the logic in a fallible API and handling the error gracefully.
-pub fn select_host_address() -> IpAddr {
+/// The interface names and addresses, IPv4 addresses first, or an error if
+/// the interfaces could not be listed
+pub fn host_addresses(network: &NetworkConfig) -> Result<Vec<(String, IpAddr)>, RoverRtcError> {
     let system = System::new();
-    let networks = system.networks().expect("Networks should be available.");
+    let networks = system.networks()?;
 
-    info!("Networks {:#?}", networks);
+    debug!("Networks {:#?}", networks);
 
+    let mut addresses = vec![];
     for (name, net) in networks {
         // Skip Docker and bridge interfaces by name
         let name_lower = name.to_lowercase();
-        if name_lower.contains("docker")
-            || name_lower.starts_with("br-")
-            || name_lower.starts_with("veth")
-            || name_lower.starts_with("virbr")
+        if network
+            .skip_interfaces
+            .iter()
+            .any(|prefix| name_lower.starts_with(&prefix.to_lowercase()))
         {
-            info!("Skipping interface {} (Docker/bridge)", name);
+            debug!("Skipping interface {} (Docker/bridge)", name);
             continue;
         }
 
         for n in &net.addrs {
-            if let systemstat::IpAddr::V4(v) = n.addr {
-                if !v.is_loopback() && !v.is_link_local() && !v.is_broadcast() {
-                    let ip_addr = IpAddr::V4(v);
-
-                    // Verify internet connectivity by trying to bind and connect
-                    if has_internet_access(&ip_addr) {
-                        info!("Selected interface {} with IP {}", name, ip_addr);
-                        return ip_addr;
-                    } else {
-                        info!("Interface {} has no internet access, skipping", name);
-                    }
-                }
+            let ip_addr = match n.addr {
+                systemstat::IpAddr::V4(v) if !v.is_broadcast() => IpAddr::V4(v),
+                systemstat::IpAddr::V6(v) => IpAddr::V6(v),
+                _ => continue,
+            };
+            // A server address must be reachable beyond the link
+            if is_host_address(&ip_addr, network) && !is_link_local(&ip_addr) {
+                addresses.push((name.clone(), ip_addr));
             }
         }
     }
-
-    panic!("Found no usable network interface with internet access");
+    addresses.sort_by_key(|(_, ip)| ip.is_ipv6());
+    Ok(addresses)
 }
 
 /// Checks if a given IP address has internet access.
 ///
-/// Attempts to create a UDP socket bound to the given IP and connect to
-/// Google's public DNS server (8.8.8.8:53) to verify internet connectivity.
+/// Attempts to create a UDP socket bound to the given IP and connect to the
+/// probe address of its IP version (by default Google's public DNS server,
+/// 8.8.8.8:53) to verify internet connectivity.
 ///
 /// # Arguments
 ///
 /// * `ip` - The IP address to check for internet access
+/// * `network` - The settings naming the probe addresses
 ///
 /// # Returns
 ///
 /// * `bool` - `true` if the interface has internet access, `false` otherwise
-fn has_internet_access(ip: &IpAddr) -> bool {
+pub fn has_internet_access(ip: &IpAddr, network: &NetworkConfig) -> bool {
+    let probe = match ip {
+        IpAddr::V4(_) => &network.connectivity_probe,
+        IpAddr::V6(_) => &network.connectivity_probe_v6,
+    };
     // Try to bind to the specific IP and connect to a public DNS server
     let bind_addr = SocketAddr::new(*ip, 0);
 
     match UdpSocket::bind(bind_addr) {
         Ok(socket) => {
-            // Try to connect to Google's public DNS (8.8.8.8:53)
             // This doesn't send data, just verifies routing is possible
-            socket.connect("8.8.8.8:53").is_ok()
+            socket.connect(probe).is_ok()
         }
         Err(_) => false,
     }
 }
 
+/// Returns `true` if `ip` may be used as a host address or candidate.
+///
+/// Loopback, unspecified and multicast addresses never are, nor IPv4
+/// link-local ones. IPv6 link-local addresses are only used if
+/// [`NetworkConfig::link_local_v6`] is set.
+fn is_host_address(ip: &IpAddr, network: &NetworkConfig) -> bool {
+    if !network.ip_family.allows(ip) || ip.is_loopback() || ip.is_unspecified() {
+        return false;
+    }
+    match ip {
+        IpAddr::V4(v4) => !v4.is_link_local() && !v4.is_multicast(),
+        IpAddr::V6(v6) => {
+            !v6.is_multicast()
+                && v6.to_ipv4_mapped().is_none()
+                && (!v6.is_unicast_link_local() || network.link_local_v6)
+        }
+    }
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_link_local(),
+        IpAddr::V6(v6) => v6.is_unicast_link_local(),
+    }
+}
+
+/// Returns the index of an interface, the scope of its link-local addresses.
+#[cfg(target_os = "linux")]
+fn interface_index(name: &str) -> u32 {
+    match std::ffi::CString::new(name) {
+        // SAFETY: the name is a valid NUL-terminated string
+        Ok(name) => unsafe { libc::if_nametoindex(name.as_ptr()) },
+        Err(_) => 0,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_index(_name: &str) -> u32 {
+    0
+}
+
+/// Binds the UDP socket of a peer for the configured IP versions.
+///
+/// With [`IpFamily::Both`] the socket is dual-stack: bound to `[::]`, it
+/// also sends to and receives from IPv4 addresses, which it reports as
+/// IPv4-mapped IPv6 addresses, see [`canonical_addr`]. The options of
+/// [`NetworkConfig::socket`] are set before binding.
+///
+/// # Arguments
+///
+/// * `network` - The network settings choosing the IP versions
+/// * `port` - The port to bind, or 0 for any
+///
+/// # Returns
+///
+/// The bound socket, or an error if the address family is not available or
+/// an option was refused
+pub fn bind_udp(network: &NetworkConfig, port: u16) -> std::io::Result<UdpSocket> {
+    let addr = match network.ip_family {
+        IpFamily::V4 => SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        IpFamily::V6 | IpFamily::Both => SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+    };
+    bind_udp_to(addr, network)
+}
+
+/// Binds a UDP socket to an address, e.g. the server's host address, with
+/// the options of [`NetworkConfig::socket`].
+///
+/// An IPv6 socket is IPv6-only unless [`NetworkConfig::ip_family`] is
+/// [`IpFamily::Both`].
+///
+/// # Arguments
+///
+/// * `addr` - The address to bind
+/// * `network` - The network settings with the socket options
+///
+/// # Returns
+///
+/// The bound socket, or an error if the address cannot be bound or an
+/// option was refused
+pub fn bind_udp_to(addr: SocketAddr, network: &NetworkConfig) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(network.ip_family != IpFamily::Both)?;
+    }
+    network.socket.apply(&socket, &addr)?;
+    socket.bind(&addr.into())?;
+    network.socket.report(&socket);
+    Ok(socket.into())
+}
+
+/// Returns the primary IPv4 address of this machine, falling back to
+/// loopback.
+///
+/// A server bound to it has a host candidate matching one a peer on the same
+/// machine gathers, as the self-test, the trace replays and the integration
+/// tests need.
+pub(crate) fn local_ipv4() -> IpAddr {
+    local_ip_address::local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/// Converts an IPv4-mapped IPv6 address, as received on a dual-stack socket,
+/// back to the IPv4 address.
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
 /// Generates a list of ICE candidates from available network interfaces.
 ///
-/// Discovers all network interfaces on the system and creates host ICE candidates
-/// for each routable IPv4 address. Skips loopback and link-local addresses.
-/// IPv6 addresses are currently not supported.
+/// Discovers all network interfaces on the system and creates host ICE
+/// candidates for each routable address of the IP versions the socket is
+/// bound for. Skips loopback and link-local addresses, except IPv6 link-local
+/// ones if [`NetworkConfig::link_local_v6`] is set; those are scoped to their
+/// interface.
 ///
 /// # Arguments
 ///
 /// * `socket` - The UDP socket whose port will be used for the candidates
+/// * `network` - The network settings choosing the IP versions, and the
+///   interface if the socket is bound to one
 ///
 /// # Returns
 ///
-/// A vector of [`Candidate`] objects representing the available network interfaces
+/// A vector of [`Candidate`] objects representing the available network
+/// interfaces, or an error if the socket has no local address
 ///
 /// # Note
 ///
 /// The function logs all discovered interfaces for debugging purposes.
-pub fn get_candidates(socket: &UdpSocket) -> Vec<Candidate> {
+pub fn get_candidates(
+    socket: &UdpSocket,
+    network: &NetworkConfig,
+) -> Result<Vec<Candidate>, RoverRtcError> {
+    let port = socket.local_addr()?.port();
     let mut candidates: Vec<Candidate> = vec![];
     if let Ok(network_interfaces) = list_afinet_netifas() {
         for (name, ip) in network_interfaces {
             info!("iface: {} / {:?}", name, ip);
-            match ip {
-                IpAddr::V4(ip4) => {
-                    if !ip4.is_loopback() && !ip4.is_link_local() {
-                        let socket_addr = SocketAddr::new(
-                            ip,
-                            socket
-                                .local_addr()
-                                .expect("Local address should be available.")
-                                .port(),
-                        );
-                        candidates.push(
-                            Candidate::host(socket_addr, str0m::net::Protocol::Udp)
-                                .expect("Failed to create local candidate"),
-                        );
-                    }
+            if !is_host_address(&ip, network) {
+                continue;
+            }
+            // A bound socket only sends out of its own interface
+            if network
+                .socket
+                .bind_device
+                .as_ref()
+                .is_some_and(|device| *device != name)
+            {
+                continue;
+            }
+            let socket_addr = match ip {
+                IpAddr::V6(ip6) if ip6.is_unicast_link_local() => {
+                    SocketAddr::V6(SocketAddrV6::new(ip6, port, 0, interface_index(&name)))
                 }
-                IpAddr::V6(_ip6) => {}
+                _ => SocketAddr::new(ip, port),
+            };
+            match Candidate::host(socket_addr, str0m::net::Protocol::Udp) {
+                Ok(candidate) => candidates.push(candidate),
+                Err(e) => warn!("Skipping candidate {}: {:?}", socket_addr, e),
             }
         }
     }
 
-    candidates
+    Ok(candidates)
 }
 
 /// Initializes the tracing subscriber with environment-based filtering.
 ///
 /// Defaults to INFO level logging, but can be overridden via the `RUST_LOG`
-/// environment variable. Enables debug logging for HTTP and str0m.
+/// environment variable. Enables debug logging for HTTP and str0m. The lines
+/// are also kept in the [`logbuf`] buffer, from which peers serve remote log
+/// requests. Does nothing if a global subscriber is already installed, e.g. by
+/// an embedding application.
 pub fn init_log() {
     use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
@@ -147,6 +330,8 @@ pub fn init_log() {
 
     tracing_subscriber::registry()
         .with(fmt::layer())
+        .with(logbuf::LogBufferLayer)
         .with(env_filter)
-        .init();
+        .try_init()
+        .ok();
 }
@@ -0,0 +1,271 @@
+//! Network interface change monitoring
+//!
+//! Rovers switch between Wi-Fi, LTE and tethered links during a mission, so
+//! the interfaces selected at startup do not stay valid. The [`NetworkMonitor`]
+//! reports interfaces coming up or going down and addresses being added or
+//! removed, for the peer and server loops to update their candidates or
+//! restart ICE.
+//!
+//! On Linux the monitor subscribes to the kernel's rtnetlink link and address
+//! notifications and only rescans the interfaces when one arrives. Elsewhere,
+//! or if the netlink socket cannot be opened, it rescans at a fixed interval.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use local_ip_address::list_afinet_netifas;
+use tracing::{info, warn};
+
+/// A change to the network interfaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkEvent {
+    /// An interface got its first address
+    InterfaceUp { name: String },
+    /// An interface lost its last address
+    InterfaceDown { name: String },
+    /// An address was added to an interface
+    AddressAdded { interface: String, addr: IpAddr },
+    /// An address was removed from an interface
+    AddressRemoved { interface: String, addr: IpAddr },
+}
+
+/// The addresses of every interface, by interface name.
+type Snapshot = BTreeMap<String, BTreeSet<IpAddr>>;
+
+/// How the monitor learns that the interfaces may have changed.
+enum Trigger {
+    /// Kernel notifications on an rtnetlink socket
+    #[cfg(target_os = "linux")]
+    Netlink(netlink::NetlinkSocket),
+    /// A rescan at a fixed interval
+    Polling {
+        interval: Duration,
+        last_scan: Instant,
+    },
+}
+
+/// Watches the network interfaces for changes.
+pub struct NetworkMonitor {
+    trigger: Trigger,
+    poll_interval: Duration,
+    snapshot: Snapshot,
+}
+
+impl NetworkMonitor {
+    /// Starts monitoring the interfaces as they are now.
+    ///
+    /// # Arguments
+    ///
+    /// * `poll_interval` - Interval between rescans when kernel notifications
+    ///   are unavailable
+    pub fn new(poll_interval: Duration) -> Self {
+        let polling = Trigger::Polling {
+            interval: poll_interval,
+            last_scan: Instant::now(),
+        };
+
+        #[cfg(target_os = "linux")]
+        let trigger = match netlink::NetlinkSocket::open() {
+            Ok(socket) => {
+                info!("Monitoring network interfaces with netlink");
+                Trigger::Netlink(socket)
+            }
+            Err(e) => {
+                warn!(
+                    "Netlink unavailable ({}), polling interfaces every {:?}",
+                    e, poll_interval
+                );
+                polling
+            }
+        };
+        #[cfg(not(target_os = "linux"))]
+        let trigger = {
+            info!("Polling network interfaces every {:?}", poll_interval);
+            polling
+        };
+
+        Self {
+            trigger,
+            poll_interval,
+            snapshot: scan(),
+        }
+    }
+
+    /// Returns the changes since the last call, without blocking.
+    ///
+    /// # Returns
+    ///
+    /// The changes, empty if nothing changed or no rescan was due
+    pub fn poll(&mut self) -> Vec<NetworkEvent> {
+        let due = match &mut self.trigger {
+            #[cfg(target_os = "linux")]
+            Trigger::Netlink(socket) => match socket.drain() {
+                Ok(changed) => changed,
+                Err(e) => {
+                    warn!("Netlink socket failed ({}), falling back to polling", e);
+                    self.trigger = Trigger::Polling {
+                        interval: self.poll_interval,
+                        last_scan: Instant::now(),
+                    };
+                    true
+                }
+            },
+            Trigger::Polling {
+                interval,
+                last_scan,
+            } => {
+                let due = last_scan.elapsed() >= *interval;
+                if due {
+                    *last_scan = Instant::now();
+                }
+                due
+            }
+        };
+        if !due {
+            return vec![];
+        }
+
+        let current = scan();
+        let events = diff(&self.snapshot, &current);
+        self.snapshot = current;
+        events
+    }
+}
+
+/// Reads the current addresses of every interface.
+fn scan() -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    match list_afinet_netifas() {
+        Ok(interfaces) => {
+            for (name, addr) in interfaces {
+                snapshot.entry(name).or_default().insert(addr);
+            }
+        }
+        Err(e) => warn!("Failed to list network interfaces: {}", e),
+    }
+    snapshot
+}
+
+/// Computes the events turning one snapshot into the other.
+fn diff(before: &Snapshot, after: &Snapshot) -> Vec<NetworkEvent> {
+    let empty = BTreeSet::new();
+    let mut events = vec![];
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    for name in names {
+        let old = before.get(name).unwrap_or(&empty);
+        let new = after.get(name).unwrap_or(&empty);
+        if old.is_empty() && !new.is_empty() {
+            events.push(NetworkEvent::InterfaceUp { name: name.clone() });
+        }
+        for addr in new.difference(old) {
+            events.push(NetworkEvent::AddressAdded {
+                interface: name.clone(),
+                addr: *addr,
+            });
+        }
+        for addr in old.difference(new) {
+            events.push(NetworkEvent::AddressRemoved {
+                interface: name.clone(),
+                addr: *addr,
+            });
+        }
+        if !old.is_empty() && new.is_empty() {
+            events.push(NetworkEvent::InterfaceDown { name: name.clone() });
+        }
+    }
+    events
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    //! Subscription to rtnetlink link and address notifications.
+    //!
+    //! The notifications are only used as a trigger: the new state is read
+    //! with a rescan, so their attributes are never parsed.
+
+    use std::{
+        io::{self, ErrorKind},
+        mem,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    };
+
+    /// A non-blocking rtnetlink socket joined to the link and address groups.
+    pub struct NetlinkSocket(OwnedFd);
+
+    impl NetlinkSocket {
+        /// Opens the socket and subscribes to the notifications.
+        pub fn open() -> io::Result<Self> {
+            // SAFETY: plain socket(2) call; the result is checked before use
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_ROUTE,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: fd is a freshly opened descriptor owned by nobody else
+            let socket = NetlinkSocket(unsafe { OwnedFd::from_raw_fd(fd) });
+
+            // SAFETY: sockaddr_nl is plain old data, all zeroes is valid
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups =
+                (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+            // SAFETY: addr is a valid sockaddr_nl of the given length
+            let result = unsafe {
+                libc::bind(
+                    socket.0.as_raw_fd(),
+                    &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(socket)
+        }
+
+        /// Reads all pending notifications.
+        ///
+        /// # Returns
+        ///
+        /// `true` if any notification arrived, or some were lost because the
+        /// receive buffer overflowed
+        pub fn drain(&self) -> io::Result<bool> {
+            let mut buf = [0u8; 8192];
+            let mut changed = false;
+            loop {
+                // SAFETY: buf is valid for writes of its length
+                let n = unsafe {
+                    libc::recv(
+                        self.0.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if n > 0 {
+                    changed = true;
+                    continue;
+                }
+                if n == 0 {
+                    return Ok(changed);
+                }
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    ErrorKind::WouldBlock => return Ok(changed),
+                    ErrorKind::Interrupted => continue,
+                    // Notifications were dropped; a rescan resynchronizes
+                    _ if e.raw_os_error() == Some(libc::ENOBUFS) => changed = true,
+                    _ => return Err(e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,398 @@
+//! Simulated network impairment of the WebRTC UDP sockets
+//!
+//! Handover and recovery logic is hard to exercise on a real link: degrading
+//! it by hand is neither repeatable nor quick. With the `[network.netsim]`
+//! section, or at runtime through the server's `/admin/netsim` endpoint or
+//! [`PeerHandle::set_netsim`](crate::peer::PeerHandle::set_netsim), the
+//! datagrams the process sends are delayed, dropped, reordered and paced to
+//! a bandwidth cap before they reach the socket, and received datagrams are
+//! dropped at a rate of their own:
+//!
+//! ```toml
+//! [network.netsim]
+//! enabled = true
+//! latency_ms = 80
+//! jitter_ms = 20
+//! loss = 0.02          # of the datagrams sent
+//! receive_loss = 0.02  # of the datagrams received
+//! reorder = 0.01
+//! bandwidth_kbps = 2000
+//! seed = 7
+//! ```
+//!
+//! Like packet captures the impairment is process-wide, so the STUN, TURN
+//! and path MTU code is impaired as well without it being threaded through
+//! them. With a `seed` the random decisions repeat from one run to the next
+//! for the same traffic. Delayed datagrams are sent by a background thread;
+//! packet captures record them when they actually leave.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::util::pcap;
+
+/// Whether impairment is enabled, checked before taking the lock.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The impairment and the datagrams it holds back.
+static SIMULATOR: OnceLock<Simulator> = OnceLock::new();
+
+/// Network impairment settings, the `[network.netsim]` section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetsimConfig {
+    /// Impair the traffic of the WebRTC sockets
+    pub enabled: bool,
+    /// Delay added to every datagram sent, in milliseconds
+    pub latency_ms: u64,
+    /// Largest random variation of the delay, in milliseconds, either way
+    pub jitter_ms: u64,
+    /// Fraction of the datagrams sent that are dropped, from 0 to 1
+    pub loss: f64,
+    /// Fraction of the datagrams received that are dropped, from 0 to 1
+    pub receive_loss: f64,
+    /// Fraction of the datagrams sent that are held back by `reorder_ms`,
+    /// so the following ones overtake them
+    pub reorder: f64,
+    /// Extra delay of reordered datagrams, in milliseconds
+    pub reorder_ms: u64,
+    /// Rate at which datagrams leave, in kilobits per second; 0 for no cap
+    pub bandwidth_kbps: u64,
+    /// Datagrams waiting for the link beyond which new ones are dropped
+    pub queue_packets: usize,
+    /// Seed of the random decisions, to repeat a run; random if unset
+    pub seed: Option<u64>,
+}
+
+impl Default for NetsimConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            latency_ms: 0,
+            jitter_ms: 0,
+            loss: 0.0,
+            receive_loss: 0.0,
+            reorder: 0.0,
+            reorder_ms: 20,
+            bandwidth_kbps: 0,
+            queue_packets: 1000,
+            seed: None,
+        }
+    }
+}
+
+impl NetsimConfig {
+    /// Checks that the fractions are probabilities and the queue can hold a
+    /// datagram.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("loss", self.loss),
+            ("receive_loss", self.receive_loss),
+            ("reorder", self.reorder),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(format!("{} must be between 0 and 1", name));
+            }
+        }
+        if self.jitter_ms > self.latency_ms {
+            return Err("jitter_ms must not exceed latency_ms".into());
+        }
+        if self.queue_packets == 0 {
+            return Err("queue_packets must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+/// Datagrams the impairment handled since it was last configured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetsimStats {
+    /// Datagrams sent, at once or after their delay
+    pub sent: u64,
+    /// Datagrams dropped on sending, at random
+    pub dropped: u64,
+    /// Datagrams dropped on sending because the queue was full
+    pub overflowed: u64,
+    /// Datagrams held back for reordering
+    pub reordered: u64,
+    /// Datagrams dropped on receiving
+    pub receive_dropped: u64,
+    /// Datagrams waiting to be sent
+    pub queued: usize,
+}
+
+/// The current impairment, as reported by [`status`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetsimStatus {
+    /// The impairment applied
+    pub config: NetsimConfig,
+    /// What it did so far
+    pub stats: NetsimStats,
+}
+
+/// A datagram waiting for its time to leave.
+#[derive(Debug)]
+struct Delayed {
+    due: Instant,
+    /// Keeps datagrams due at the same time in order
+    seq: u64,
+    socket: UdpSocket,
+    payload: Vec<u8>,
+    destination: SocketAddr,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+/// The state of the impairment.
+#[derive(Debug)]
+struct State {
+    config: NetsimConfig,
+    rng: StdRng,
+    stats: NetsimStats,
+    queue: BinaryHeap<Reverse<Delayed>>,
+    /// When the capped link finishes sending what it was given
+    link_free_at: Instant,
+    /// When the last datagram kept in order is due, so jitter does not
+    /// reorder datagrams
+    last_due: Instant,
+    seq: u64,
+}
+
+impl State {
+    fn new(config: NetsimConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let now = Instant::now();
+        Self {
+            config,
+            rng,
+            stats: NetsimStats::default(),
+            queue: BinaryHeap::new(),
+            link_free_at: now,
+            last_due: now,
+            seq: 0,
+        }
+    }
+
+    /// Decides when a datagram leaves.
+    ///
+    /// # Returns
+    ///
+    /// The time it is due, or `None` if it is dropped
+    fn schedule(&mut self, len: usize, now: Instant) -> Option<Instant> {
+        if self.config.loss > 0.0 && self.rng.gen_bool(self.config.loss) {
+            self.stats.dropped += 1;
+            return None;
+        }
+        if self.queue.len() >= self.config.queue_packets {
+            self.stats.overflowed += 1;
+            return None;
+        }
+
+        let mut due = now;
+        // A cap of 0 means none
+        if let Some(micros) = (len as u64 * 8 * 1000).checked_div(self.config.bandwidth_kbps) {
+            let transmission = Duration::from_micros(micros);
+            self.link_free_at = self.link_free_at.max(now) + transmission;
+            due = self.link_free_at;
+        }
+        let jitter = self.config.jitter_ms as i64;
+        let delay_ms = self.config.latency_ms as i64
+            + if jitter > 0 {
+                self.rng.gen_range(-jitter..=jitter)
+            } else {
+                0
+            };
+        due += Duration::from_millis(delay_ms.max(0) as u64);
+
+        if self.config.reorder > 0.0 && self.rng.gen_bool(self.config.reorder) {
+            self.stats.reordered += 1;
+            return Some(due + Duration::from_millis(self.config.reorder_ms));
+        }
+        due = due.max(self.last_due);
+        self.last_due = due;
+        Some(due)
+    }
+}
+
+/// The impairment, shared with the thread sending delayed datagrams.
+#[derive(Debug)]
+struct Simulator {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+impl Simulator {
+    /// Returns the simulator, starting its sending thread on first use.
+    fn get() -> &'static Simulator {
+        SIMULATOR.get_or_init(|| {
+            thread::Builder::new()
+                .name("netsim".into())
+                .spawn(|| Simulator::get().send_delayed())
+                .expect("spawn netsim thread");
+            Simulator {
+                state: Mutex::new(State::new(NetsimConfig::default())),
+                wake: Condvar::new(),
+            }
+        })
+    }
+
+    /// Sends the delayed datagrams at their time, forever.
+    fn send_delayed(&self) {
+        let mut state = self.state.lock().expect("netsim lock");
+        loop {
+            let now = Instant::now();
+            let wait = match state.queue.peek() {
+                Some(Reverse(next)) if next.due <= now => {
+                    let Reverse(next) = state.queue.pop().expect("peeked datagram");
+                    state.stats.sent += 1;
+                    drop(state);
+                    transmit(&next.socket, &next.payload, next.destination);
+                    state = self.state.lock().expect("netsim lock");
+                    continue;
+                }
+                Some(Reverse(next)) => next.due - now,
+                None => Duration::from_secs(3600),
+            };
+            state = self.wake.wait_timeout(state, wait).expect("netsim lock").0;
+        }
+    }
+}
+
+/// Replaces the impairment, or disables it.
+///
+/// Datagrams still held back by the previous impairment are sent at their
+/// time, and the statistics start over.
+pub fn configure(config: &NetsimConfig) {
+    let simulator = Simulator::get();
+    let mut state = simulator.state.lock().expect("netsim lock");
+    let queue = std::mem::take(&mut state.queue);
+    *state = State::new(config.clone());
+    state.queue = queue;
+    ACTIVE.store(config.enabled, Ordering::Release);
+    if config.enabled {
+        debug!("Impairing the network: {:?}", config);
+    }
+}
+
+/// Returns the current impairment and what it did, or `None` if disabled.
+pub fn status() -> Option<NetsimStatus> {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return None;
+    }
+    let state = Simulator::get().state.lock().expect("netsim lock");
+    Some(NetsimStatus {
+        config: state.config.clone(),
+        stats: NetsimStats {
+            queued: state.queue.len(),
+            ..state.stats
+        },
+    })
+}
+
+/// Sends a datagram through the impairment, if enabled, and records it.
+///
+/// # Arguments
+///
+/// * `socket` - The socket to send on
+/// * `payload` - The datagram
+/// * `destination` - Where to send it
+///
+/// # Returns
+///
+/// The length of the datagram once sent or handed to the impairment, even
+/// if it is dropped, as a lossy link would; an error if sending at once
+/// failed
+pub fn send_to(socket: &UdpSocket, payload: &[u8], destination: SocketAddr) -> io::Result<usize> {
+    if !ACTIVE.load(Ordering::Acquire) {
+        let sent_len = socket.send_to(payload, destination)?;
+        pcap::sent(socket, destination, payload);
+        return Ok(sent_len);
+    }
+
+    let simulator = Simulator::get();
+    let mut state = simulator.state.lock().expect("netsim lock");
+    let now = Instant::now();
+    let Some(due) = state.schedule(payload.len(), now) else {
+        return Ok(payload.len());
+    };
+    if due <= now && state.queue.is_empty() {
+        state.stats.sent += 1;
+        drop(state);
+        let sent_len = socket.send_to(payload, destination)?;
+        pcap::sent(socket, destination, payload);
+        return Ok(sent_len);
+    }
+    let socket = socket.try_clone()?;
+    state.seq += 1;
+    let seq = state.seq;
+    state.queue.push(Reverse(Delayed {
+        due,
+        seq,
+        socket,
+        payload: payload.to_vec(),
+        destination,
+    }));
+    simulator.wake.notify_one();
+    Ok(payload.len())
+}
+
+/// Returns `true` if a datagram just received should be dropped.
+pub fn drops_received() -> bool {
+    if !ACTIVE.load(Ordering::Acquire) {
+        return false;
+    }
+    let mut state = Simulator::get().state.lock().expect("netsim lock");
+    let loss = state.config.receive_loss;
+    if loss > 0.0 && state.rng.gen_bool(loss) {
+        state.stats.receive_dropped += 1;
+        return true;
+    }
+    false
+}
+
+/// Sends a delayed datagram and records it.
+fn transmit(socket: &UdpSocket, payload: &[u8], destination: SocketAddr) {
+    match socket.send_to(payload, destination) {
+        Ok(_) => pcap::sent(socket, destination, payload),
+        // Like a datagram lost on the way
+        Err(e) => warn!(
+            "Failed to send a delayed datagram to {}: {}",
+            destination, e
+        ),
+    }
+}
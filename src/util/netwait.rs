@@ -0,0 +1,174 @@
+//! Waiting for the network at startup
+//!
+//! On a rover the peer is often started at boot, before DHCP or the modem
+//! gave the interfaces their addresses. Instead of failing with no host
+//! address or no candidates, the peer and the server check again until a
+//! usable address appears or [`NetworkConfig::wait_secs`] pass, logging what
+//! they are waiting for every few seconds.
+
+use std::{
+    net::IpAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    config::NetworkConfig,
+    error::RoverRtcError,
+    util::{is_host_address, select_host_address},
+};
+
+/// Interval between checks of the interfaces.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Interval between progress messages while waiting.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A wait for a usable network address, with its deadline.
+#[derive(Debug)]
+pub struct NetworkWait {
+    what: &'static str,
+    started: Instant,
+    timeout: Duration,
+    next_report: Instant,
+}
+
+impl NetworkWait {
+    /// Starts waiting.
+    ///
+    /// # Arguments
+    ///
+    /// * `what` - What is waited for, e.g. `host address`, for the logs
+    /// * `network` - The settings naming the timeout
+    pub fn new(what: &'static str, network: &NetworkConfig) -> Self {
+        let started = Instant::now();
+        Self {
+            what,
+            started,
+            timeout: Duration::from_secs(network.wait_secs),
+            next_report: started,
+        }
+    }
+
+    /// Records that the network is not usable yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `network` - The settings selecting the addresses that count
+    ///
+    /// # Returns
+    ///
+    /// How long to wait before checking again, or `None` if the wait timed out
+    pub fn retry(&mut self, network: &NetworkConfig) -> Option<Duration> {
+        let now = Instant::now();
+        let waited = now.duration_since(self.started);
+        if waited >= self.timeout {
+            if !self.timeout.is_zero() {
+                warn!(
+                    "No usable {} after waiting {}s for the network",
+                    self.what,
+                    waited.as_secs()
+                );
+            }
+            return None;
+        }
+        if now >= self.next_report {
+            info!(
+                "Waiting for the network: no usable {} yet ({}s of {}s), interfaces: {}",
+                self.what,
+                waited.as_secs(),
+                self.timeout.as_secs(),
+                describe_interfaces(network)
+            );
+            self.next_report = now + REPORT_INTERVAL;
+        }
+        Some(CHECK_INTERVAL.min(self.timeout - waited))
+    }
+
+    /// Logs that the wait is over, if it lasted.
+    pub fn done(&self) {
+        let waited = self.started.elapsed();
+        if self.next_report > self.started {
+            info!(
+                "Network is up after {:.1}s, found a usable {}",
+                waited.as_secs_f64(),
+                self.what
+            );
+        }
+    }
+}
+
+/// Selects the server's host address like
+/// [`select_host_address`](crate::util::select_host_address), waiting for
+/// one to become usable.
+///
+/// # Arguments
+///
+/// * `network` - The interface selection settings and the timeout
+///
+/// # Returns
+///
+/// * `Ok(IpAddr)` - The first routable address with internet access
+/// * `Err(RoverRtcError)` - If the interfaces could not be listed, or none
+///   became usable in time
+pub fn wait_for_host_address(network: &NetworkConfig) -> Result<IpAddr, RoverRtcError> {
+    let mut wait = NetworkWait::new("host address", network);
+    loop {
+        // Only probe for internet access once an interface has an address
+        let selected = if has_host_address(network) {
+            select_host_address(network)
+        } else {
+            Err(RoverRtcError::NoUsableInterface)
+        };
+        match selected {
+            Ok(addr) => {
+                wait.done();
+                return Ok(addr);
+            }
+            Err(RoverRtcError::NoUsableInterface) => {}
+            Err(e) => return Err(e),
+        }
+        let Some(delay) = wait.retry(network) else {
+            return Err(RoverRtcError::NoUsableInterface);
+        };
+        thread::sleep(delay);
+    }
+}
+
+/// Returns `true` if an interface has an address that may serve as a host
+/// address or candidate, without logging the interfaces.
+///
+/// # Arguments
+///
+/// * `network` - The settings selecting the addresses that count
+pub fn has_host_address(network: &NetworkConfig) -> bool {
+    local_ip_address::list_afinet_netifas()
+        .map(|interfaces| {
+            interfaces
+                .iter()
+                .any(|(_, ip)| is_host_address(ip, network))
+        })
+        .unwrap_or(false)
+}
+
+/// Lists the interfaces and their addresses for the progress messages,
+/// marking the ones the settings exclude.
+fn describe_interfaces(network: &NetworkConfig) -> String {
+    match local_ip_address::list_afinet_netifas() {
+        Ok(interfaces) if !interfaces.is_empty() => interfaces
+            .iter()
+            .map(|(name, ip)| {
+                if is_host_address(ip, network) {
+                    format!("{} {}", name, ip)
+                } else {
+                    format!("{} {} (unusable)", name, ip)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+        Ok(_) => "none".into(),
+        Err(e) => format!("unknown ({})", e),
+    }
+}
@@ -0,0 +1,571 @@
+//! Bounded packet capture of the peer's UDP traffic
+//!
+//! A remote rover behind an unknown NAT or firewall is often impossible to
+//! diagnose from its logs alone. A capture records every datagram the peer's
+//! socket sends or receives (STUN, TURN, DTLS and SCTP alike) into a pcap
+//! file that Wireshark opens directly, until its duration or size limit is
+//! reached. Only the process's own socket is captured, so no privileges are
+//! needed; each datagram gets a synthesized IP and UDP header, with the
+//! wildcard address where the socket is bound to one.
+//!
+//! One capture runs at a time, process-wide, so the STUN, TURN and path MTU
+//! code can record what they send without the capture being threaded
+//! through them.
+//!
+//! Independently of captures, a file tap writes the same datagrams to a
+//! pcapng file on disk with nanosecond timestamps, for debugging ICE and
+//! DTLS in the field over longer periods. It is enabled from startup in the
+//! `[network.pcap]` section, or toggled at runtime with [`start_file`] and
+//! [`stop_file`], e.g. through the server's `/admin/pcap` endpoint.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::util::{canonical_addr, netsim};
+
+/// Link type of raw IPv4 and IPv6 packets.
+const LINKTYPE_RAW: u32 = 101;
+
+/// Size of the pcap file header.
+const FILE_HEADER_LEN: usize = 24;
+
+/// Size of a pcap record header.
+const RECORD_HEADER_LEN: usize = 16;
+
+/// Block type of a pcapng section header.
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+
+/// Block type of a pcapng interface description.
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+
+/// Block type of a pcapng packet.
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+
+/// Interface option giving the timestamp resolution.
+const IF_TSRESOL: u16 = 9;
+
+/// Size of the pcapng section header and interface description.
+const PCAPNG_HEADER_LEN: u64 = 60;
+
+/// How often the file tap flushes the datagrams it wrote.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether a capture is running, checked before taking the lock.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The running or finished capture.
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+/// Whether the file tap is running, checked before taking the lock.
+static TAP_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The running file tap.
+static TAP: Mutex<Option<FileTap>> = Mutex::new(None);
+
+/// Limits of remotely triggered captures, the `[peer.capture]` section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Accept capture requests from the server
+    pub enabled: bool,
+    /// Longest capture accepted, in seconds; longer requests are shortened
+    pub max_duration_secs: u64,
+    /// Largest capture file accepted, in bytes; larger requests are reduced
+    pub max_bytes: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_duration_secs: 300,
+            max_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl CaptureConfig {
+    /// Checks that a capture can record anything.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_duration_secs == 0 {
+            return Err("max_duration_secs must be positive".into());
+        }
+        if self.max_bytes <= FILE_HEADER_LEN {
+            return Err(format!("max_bytes must exceed {}", FILE_HEADER_LEN));
+        }
+        Ok(())
+    }
+}
+
+/// The file tap, the `[network.pcap]` section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PcapConfig {
+    /// Write the WebRTC socket's traffic to `path` from startup
+    pub enabled: bool,
+    /// The pcapng file, replaced if it exists
+    pub path: PathBuf,
+    /// Size after which the file stops growing, in bytes
+    pub max_bytes: u64,
+}
+
+impl Default for PcapConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("rover-rtc.pcapng"),
+            max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl PcapConfig {
+    /// Checks that the tap can write anything.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.path.as_os_str().is_empty() {
+            return Err("path must not be empty".into());
+        }
+        if self.max_bytes <= PCAPNG_HEADER_LEN {
+            return Err(format!("max_bytes must exceed {}", PCAPNG_HEADER_LEN));
+        }
+        Ok(())
+    }
+}
+
+/// A capture file being recorded.
+#[derive(Debug)]
+struct Capture {
+    /// The pcap file so far
+    data: Vec<u8>,
+    /// When recording stops
+    until: Instant,
+    /// The largest file size
+    max_bytes: usize,
+    /// Whether a datagram did not fit and recording stopped
+    full: bool,
+    /// Number of recorded datagrams
+    packets: u64,
+}
+
+impl Capture {
+    fn new(duration: Duration, max_bytes: usize) -> Self {
+        let mut data = Vec::with_capacity(FILE_HEADER_LEN);
+        data.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&4u16.to_le_bytes());
+        data.extend_from_slice(&0i32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&65_535u32.to_le_bytes());
+        data.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        Self {
+            data,
+            until: Instant::now() + duration,
+            max_bytes,
+            full: false,
+            packets: 0,
+        }
+    }
+
+    fn is_finished(&self, now: Instant) -> bool {
+        self.full || now >= self.until
+    }
+
+    fn record(&mut self, source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+        if self.is_finished(Instant::now()) {
+            return;
+        }
+        let packet = ip_packet(source, destination, payload);
+        if self.data.len() + RECORD_HEADER_LEN + packet.len() > self.max_bytes {
+            self.full = true;
+            return;
+        }
+
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.data
+            .extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+        self.data
+            .extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+        self.data
+            .extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        self.data
+            .extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(&packet);
+        self.packets += 1;
+    }
+}
+
+/// A pcapng file being written.
+#[derive(Debug)]
+struct FileTap {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// The file size so far
+    bytes: u64,
+    /// The largest file size
+    max_bytes: u64,
+    /// Whether a datagram did not fit and writing stopped
+    full: bool,
+    /// Number of written datagrams
+    packets: u64,
+    last_flush: Instant,
+}
+
+impl FileTap {
+    fn create(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(PCAPNG_HEADER_LEN as usize);
+        header.extend_from_slice(&SECTION_HEADER_BLOCK.to_le_bytes());
+        header.extend_from_slice(&28u32.to_le_bytes());
+        header.extend_from_slice(&0x1a2b_3c4du32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        // Section length not given
+        header.extend_from_slice(&(-1i64).to_le_bytes());
+        header.extend_from_slice(&28u32.to_le_bytes());
+
+        header.extend_from_slice(&INTERFACE_DESCRIPTION_BLOCK.to_le_bytes());
+        header.extend_from_slice(&32u32.to_le_bytes());
+        header.extend_from_slice(&(LINKTYPE_RAW as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&65_535u32.to_le_bytes());
+        // Timestamps in nanoseconds, padded to 32 bits
+        header.extend_from_slice(&IF_TSRESOL.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&[9, 0, 0, 0]);
+        // End of options
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&32u32.to_le_bytes());
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&header)?;
+        writer.flush()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer,
+            bytes: header.len() as u64,
+            max_bytes,
+            full: false,
+            packets: 0,
+            last_flush: Instant::now(),
+        })
+    }
+
+    fn record(
+        &mut self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // Taken first, so the time of the datagram does not include encoding it
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        if self.full {
+            return Ok(());
+        }
+        let packet = ip_packet(source, destination, payload);
+        let padding = (4 - packet.len() % 4) % 4;
+        let block_len = 32 + packet.len() + padding;
+        if self.bytes + block_len as u64 > self.max_bytes {
+            self.full = true;
+            return self.writer.flush();
+        }
+
+        let mut block = Vec::with_capacity(block_len);
+        block.extend_from_slice(&ENHANCED_PACKET_BLOCK.to_le_bytes());
+        block.extend_from_slice(&(block_len as u32).to_le_bytes());
+        // The only interface
+        block.extend_from_slice(&0u32.to_le_bytes());
+        block.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        block.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        block.extend_from_slice(&packet);
+        block.resize(block.len() + padding, 0);
+        block.extend_from_slice(&(block_len as u32).to_le_bytes());
+        self.writer.write_all(&block)?;
+        self.bytes += block_len as u64;
+        self.packets += 1;
+
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.last_flush = Instant::now();
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> TapStatus {
+        TapStatus {
+            path: self.path.clone(),
+            packets: self.packets,
+            bytes: self.bytes,
+            full: self.full,
+        }
+    }
+}
+
+/// The state of the file tap.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TapStatus {
+    /// The pcapng file
+    pub path: PathBuf,
+    /// Number of written datagrams
+    pub packets: u64,
+    /// The file size so far
+    pub bytes: u64,
+    /// Whether writing stopped because the size limit was reached
+    pub full: bool,
+}
+
+/// A finished capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureFile {
+    /// The pcap file
+    pub data: Vec<u8>,
+    /// Number of recorded datagrams
+    pub packets: u64,
+    /// Whether recording stopped because the size limit was reached
+    pub truncated: bool,
+}
+
+/// Starts a capture.
+///
+/// # Arguments
+///
+/// * `duration` - How long to record
+/// * `max_bytes` - The largest file size, headers included
+///
+/// # Returns
+///
+/// `false` if a capture is already running or waiting to be collected
+pub fn start(duration: Duration, max_bytes: usize) -> bool {
+    let mut capture = CAPTURE.lock().expect("capture lock");
+    if capture.is_some() {
+        return false;
+    }
+    *capture = Some(Capture::new(duration, max_bytes));
+    ACTIVE.store(true, Ordering::Release);
+    true
+}
+
+/// Returns `true` if the running capture reached its duration or size limit.
+pub fn is_finished() -> bool {
+    CAPTURE
+        .lock()
+        .expect("capture lock")
+        .as_ref()
+        .is_some_and(|capture| capture.is_finished(Instant::now()))
+}
+
+/// Stops the capture, finished or not, and returns the file.
+pub fn stop() -> Option<CaptureFile> {
+    let capture = CAPTURE.lock().expect("capture lock").take()?;
+    ACTIVE.store(false, Ordering::Release);
+    Some(CaptureFile {
+        data: capture.data,
+        packets: capture.packets,
+        truncated: capture.full,
+    })
+}
+
+/// Starts writing every datagram to a pcapng file.
+///
+/// A tap already writing to `path` is kept; one writing elsewhere is stopped
+/// first.
+///
+/// # Arguments
+///
+/// * `path` - The pcapng file, replaced if it exists
+/// * `max_bytes` - The size after which the file stops growing
+///
+/// # Returns
+///
+/// An error if the file could not be created
+pub fn start_file(path: &Path, max_bytes: u64) -> io::Result<()> {
+    let mut tap = TAP.lock().expect("tap lock");
+    if tap.as_ref().is_some_and(|tap| tap.path == path) {
+        return Ok(());
+    }
+    if let Some(mut previous) = tap.take() {
+        previous.writer.flush()?;
+    }
+    *tap = Some(FileTap::create(path, max_bytes)?);
+    TAP_ACTIVE.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Stops the file tap, if running, and flushes the file.
+///
+/// # Returns
+///
+/// The final state of the tap, or `None` if none was running
+pub fn stop_file() -> Option<TapStatus> {
+    let mut tap = TAP.lock().expect("tap lock").take()?;
+    TAP_ACTIVE.store(false, Ordering::Release);
+    if let Err(e) = tap.writer.flush() {
+        warn!("Failed to flush {}: {}", tap.path.display(), e);
+    }
+    Some(tap.status())
+}
+
+/// Returns the state of the file tap, or `None` if none is running.
+pub fn file_status() -> Option<TapStatus> {
+    TAP.lock().expect("tap lock").as_ref().map(FileTap::status)
+}
+
+/// Returns `true` if a capture or the file tap wants datagrams.
+fn is_recording() -> bool {
+    ACTIVE.load(Ordering::Acquire) || TAP_ACTIVE.load(Ordering::Acquire)
+}
+
+/// Sends a datagram and records it, if a capture or the file tap is running.
+///
+/// # Arguments
+///
+/// * `socket` - The socket to send on
+/// * `payload` - The datagram
+/// * `destination` - Where to send it
+///
+/// # Returns
+///
+/// The result of [`UdpSocket::send_to`]; failed sends, and datagrams dropped
+/// by a simulated impairment, are not recorded
+pub fn send_to(socket: &UdpSocket, payload: &[u8], destination: SocketAddr) -> io::Result<usize> {
+    // Records the datagram once it leaves, after any simulated impairment
+    netsim::send_to(socket, payload, destination)
+}
+
+/// Records a datagram sent on a socket, if a capture or the file tap is
+/// running.
+///
+/// # Arguments
+///
+/// * `socket` - The socket the datagram was sent on
+/// * `destination` - Where it was sent
+/// * `payload` - The datagram
+pub(crate) fn sent(socket: &UdpSocket, destination: SocketAddr, payload: &[u8]) {
+    if !is_recording() {
+        return;
+    }
+    let destination = canonical_addr(destination);
+    let source = local_addr(socket, destination);
+    record(source, destination, payload);
+}
+
+/// Records a datagram received on a socket, if a capture or the file tap is
+/// running.
+///
+/// # Arguments
+///
+/// * `socket` - The socket the datagram was received on
+/// * `source` - Where it came from
+/// * `payload` - The datagram
+pub fn received(socket: &UdpSocket, source: SocketAddr, payload: &[u8]) {
+    if !is_recording() {
+        return;
+    }
+    let source = canonical_addr(source);
+    let destination = local_addr(socket, source);
+    record(source, destination, payload);
+}
+
+fn record(source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+    if ACTIVE.load(Ordering::Acquire) {
+        if let Some(capture) = CAPTURE.lock().expect("capture lock").as_mut() {
+            capture.record(source, destination, payload);
+        }
+    }
+    if TAP_ACTIVE.load(Ordering::Acquire) {
+        let mut tap = TAP.lock().expect("tap lock");
+        if let Some(Err(e)) = tap
+            .as_mut()
+            .map(|tap| tap.record(source, destination, payload))
+        {
+            // Stop rather than fail on every datagram, e.g. with a full disk
+            warn!("Stopped writing the pcapng file: {}", e);
+            *tap = None;
+            TAP_ACTIVE.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Returns the socket's address in the IP version of the remote address.
+///
+/// A dual-stack socket is bound to the IPv6 wildcard, which is replaced by
+/// the IPv4 wildcard for IPv4 traffic.
+fn local_addr(socket: &UdpSocket, remote: SocketAddr) -> SocketAddr {
+    let local = socket
+        .local_addr()
+        .map(canonical_addr)
+        .unwrap_or_else(|_| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+    match (local.ip(), remote.ip()) {
+        (IpAddr::V6(_), IpAddr::V4(_)) => {
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local.port())
+        }
+        (IpAddr::V4(_), IpAddr::V6(_)) => {
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), local.port())
+        }
+        _ => local,
+    }
+}
+
+/// Wraps a datagram in IP and UDP headers.
+///
+/// The UDP checksum is left at zero, meaning none for IPv4; Wireshark flags
+/// it for IPv6 but decodes the datagram.
+fn ip_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(48 + payload.len());
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&(20 + udp_len).to_be_bytes());
+            header[8] = 64;
+            header[9] = 17;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let checksum = !header
+                .chunks(2)
+                .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+                .fold(0u32, |sum, word| {
+                    let sum = sum + word;
+                    (sum & 0xffff) + (sum >> 16)
+                }) as u16;
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.extend_from_slice(&0x6000_0000u32.to_be_bytes());
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.push(17);
+            packet.push(64);
+            packet.extend_from_slice(&to_v6(src).octets());
+            packet.extend_from_slice(&to_v6(dst).octets());
+        }
+    }
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&destination.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
@@ -0,0 +1,529 @@
+//! Path MTU discovery over the established path
+//!
+//! Some LTE carriers tunnel traffic with an MTU well below 1500 bytes and
+//! filter the ICMP messages that would report it, so datagrams above the
+//! limit vanish without a trace. Once ICE connected, the peer probes the path
+//! to the remote itself, in the style of packetization-layer PMTU discovery
+//! (RFC 8899): it sends ICE connectivity checks padded to a candidate size
+//! and takes an answer as proof that datagrams of that size get through.
+//!
+//! The socket sets the don't-fragment bit, so a probe is answered only if
+//! it crossed the path whole, and datagrams are never fragmented on the way;
+//! many carriers drop IP fragments. Datagrams above the discovered size are
+//! reported by their protocol layer (see [`OversizeMonitor`]), and the peer
+//! splits its application messages to fit once the remote supports it (see
+//! [`crate::model::fragment`]).
+//!
+//! The search starts with the largest size and bisects between the largest
+//! confirmed and the smallest failed size until they are less than
+//! [`SEARCH_GRANULARITY`] bytes apart. A size fails after
+//! [`PROBE_ATTEMPTS`] unanswered probes. The result is searched again after
+//! [`PmtuConfig::reprobe_secs`] and whenever the path changes, e.g. after an
+//! ICE restart.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::util::{pcap, stun};
+
+/// Size of the datagrams str0m sends; a path below it drops large packets.
+pub const STACK_DATAGRAM_SIZE: usize = 1150;
+
+/// Bytes DTLS and SCTP add to a data channel message in a datagram: the
+/// DTLS record header, nonce and tag, and the SCTP common and DATA chunk
+/// headers.
+pub const MESSAGE_OVERHEAD: usize = 50;
+
+/// Searches stop once the confirmed and failed sizes are this close.
+pub const SEARCH_GRANULARITY: usize = 16;
+
+/// Unanswered probes after which a size counts as too large.
+pub const PROBE_ATTEMPTS: u32 = 3;
+
+/// Minimum interval between two warnings about oversized datagrams of the
+/// same layer.
+const OVERSIZE_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// PRIORITY of the probes, that of a host candidate.
+const PROBE_PRIORITY: u32 = (126 << 24) | (65535 << 8) | 255;
+
+/// Path MTU discovery settings, the `[peer.pmtu]` configuration section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PmtuConfig {
+    /// Probe the path once ICE connected
+    pub enabled: bool,
+    /// The smallest datagram size assumed to get through, in bytes
+    pub min_size: usize,
+    /// The largest datagram size probed, in bytes
+    pub max_size: usize,
+    /// Time after which an unanswered probe is sent again, in milliseconds
+    pub probe_timeout_ms: u64,
+    /// Interval between two searches on the same path, in seconds
+    pub reprobe_secs: u64,
+    /// Set the don't-fragment bit on the peer's datagrams, so they are
+    /// dropped rather than fragmented on a path that does not carry them
+    pub dont_fragment: bool,
+}
+
+impl Default for PmtuConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: 576,
+            max_size: 1472,
+            probe_timeout_ms: 1000,
+            reprobe_secs: 600,
+            dont_fragment: true,
+        }
+    }
+}
+
+impl PmtuConfig {
+    /// Checks that the sizes and timeouts are usable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_size < 128 {
+            return Err("min_size must be at least 128".into());
+        }
+        if self.max_size < self.min_size {
+            return Err("max_size must not be less than min_size".into());
+        }
+        if self.max_size > 65_507 {
+            return Err("max_size must not exceed 65507".into());
+        }
+        if self.probe_timeout_ms == 0 || self.reprobe_secs == 0 {
+            return Err("probe_timeout_ms and reprobe_secs must be positive".into());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the largest data channel message that fits in one datagram.
+///
+/// # Arguments
+///
+/// * `mtu` - The datagram size the path carries
+pub fn max_message_size(mtu: usize) -> usize {
+    mtu.min(STACK_DATAGRAM_SIZE)
+        .saturating_sub(MESSAGE_OVERHEAD)
+}
+
+/// Sets the don't-fragment bit on the datagrams of a socket.
+///
+/// The kernel then refuses datagrams above the path MTU it learned from
+/// ICMP, see [`is_message_too_long`], instead of fragmenting them.
+///
+/// # Returns
+///
+/// An error if the option is not supported on the platform
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let set = |level, name, value: libc::c_int| {
+        // SAFETY: the socket is open and the value a valid c_int
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => set(
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        // Dual-stack sockets send IPv4 datagrams too
+        SocketAddr::V6(_) => {
+            set(
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+            )?;
+            set(
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+            )
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the don't-fragment bit is only set on Linux",
+    ))
+}
+
+/// Returns `true` if a send failed because the datagram exceeds the path
+/// MTU the kernel knows and may not be fragmented.
+pub fn is_message_too_long(error: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    return error.raw_os_error() == Some(libc::EMSGSIZE);
+    #[cfg(not(target_os = "linux"))]
+    return false;
+}
+
+/// The protocol layer of a datagram, told apart by its first bytes as in
+/// RFC 7983.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DatagramLayer {
+    /// ICE connectivity checks and path MTU probes
+    Stun,
+    /// DTLS handshake, alert and cipher change records
+    Dtls,
+    /// DTLS application data, i.e. SCTP carrying the data channels
+    DataChannel,
+    /// Media packets
+    Rtp,
+    /// Media control packets
+    Rtcp,
+    /// Anything else, e.g. TURN channel data
+    Other,
+}
+
+impl DatagramLayer {
+    /// Classifies a datagram.
+    pub fn of(datagram: &[u8]) -> Self {
+        match datagram.first() {
+            Some(0..=3) => DatagramLayer::Stun,
+            Some(23) => DatagramLayer::DataChannel,
+            Some(20..=63) => DatagramLayer::Dtls,
+            Some(128..=191) => match datagram.get(1) {
+                Some(192..=223) => DatagramLayer::Rtcp,
+                _ => DatagramLayer::Rtp,
+            },
+            _ => DatagramLayer::Other,
+        }
+    }
+
+    /// The name used in logs.
+    pub fn name(self) -> &'static str {
+        match self {
+            DatagramLayer::Stun => "stun",
+            DatagramLayer::Dtls => "dtls",
+            DatagramLayer::DataChannel => "data_channel",
+            DatagramLayer::Rtp => "rtp",
+            DatagramLayer::Rtcp => "rtcp",
+            DatagramLayer::Other => "other",
+        }
+    }
+}
+
+/// Datagrams of one layer above the path MTU since the last warning.
+#[derive(Debug)]
+struct Oversized {
+    count: u64,
+    largest: usize,
+    warned_at: Option<Instant>,
+}
+
+/// Reports the datagrams that exceed the path MTU.
+///
+/// Warnings carry the layer, size and path MTU as fields, and are limited
+/// to one per layer every [`OVERSIZE_WARNING_INTERVAL`], counting the
+/// datagrams in between.
+#[derive(Debug, Default)]
+pub struct OversizeMonitor {
+    layers: HashMap<DatagramLayer, Oversized>,
+}
+
+impl OversizeMonitor {
+    /// Creates a monitor that has not seen any oversized datagram.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks a datagram about to be sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `datagram` - The datagram
+    /// * `mtu` - The discovered path MTU, if known
+    /// * `destination` - Where it is sent
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// The layer of the datagram if it exceeds the path MTU
+    pub fn check(
+        &mut self,
+        datagram: &[u8],
+        mtu: Option<usize>,
+        destination: SocketAddr,
+        now: Instant,
+    ) -> Option<DatagramLayer> {
+        let mtu = mtu.filter(|mtu| datagram.len() > *mtu)?;
+        let layer = DatagramLayer::of(datagram);
+        let oversized = self.layers.entry(layer).or_insert(Oversized {
+            count: 0,
+            largest: 0,
+            warned_at: None,
+        });
+        oversized.count += 1;
+        oversized.largest = oversized.largest.max(datagram.len());
+        if oversized
+            .warned_at
+            .is_none_or(|at| now.duration_since(at) >= OVERSIZE_WARNING_INTERVAL)
+        {
+            warn!(
+                layer = layer.name(),
+                size = oversized.largest,
+                path_mtu = mtu,
+                count = oversized.count,
+                %destination,
+                "Path MTU: Datagrams exceed the path MTU and will be lost"
+            );
+            oversized.count = 0;
+            oversized.largest = 0;
+            oversized.warned_at = Some(now);
+        }
+        Some(layer)
+    }
+}
+
+/// The ICE credentials a probe is authenticated with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeCredentials {
+    /// The remote username fragment
+    pub remote_ufrag: String,
+    /// The remote password
+    pub remote_pwd: String,
+}
+
+impl ProbeCredentials {
+    /// Reads the remote credentials from an SDP answer.
+    ///
+    /// # Returns
+    ///
+    /// The credentials, or `None` if the SDP lacks them
+    pub fn from_sdp(sdp: &str) -> Option<Self> {
+        let attribute = |name: &str| {
+            sdp.lines()
+                .find_map(|line| line.trim().strip_prefix(name))
+                .map(str::to_string)
+        };
+        Some(Self {
+            remote_ufrag: attribute("a=ice-ufrag:")?,
+            remote_pwd: attribute("a=ice-pwd:")?,
+        })
+    }
+}
+
+/// A probe waiting for its answer.
+#[derive(Debug)]
+struct PendingProbe {
+    size: usize,
+    transaction: [u8; 12],
+    sent_at: Instant,
+    attempts: u32,
+}
+
+/// Searches the largest datagram size the path carries.
+#[derive(Debug)]
+pub struct PathMtu {
+    config: PmtuConfig,
+    credentials: Option<ProbeCredentials>,
+    path: Option<SocketAddr>,
+    /// The largest size known to get through
+    confirmed: usize,
+    /// The smallest size known to fail, or one above the largest probed
+    failed: usize,
+    pending: Option<PendingProbe>,
+    /// When the next search starts, once one completed
+    next_search: Option<Instant>,
+    mtu: Option<usize>,
+}
+
+impl PathMtu {
+    /// Creates a search that waits for credentials and a path.
+    pub fn new(config: PmtuConfig) -> Self {
+        let (confirmed, failed) = (config.min_size, config.max_size + 1);
+        Self {
+            config,
+            credentials: None,
+            path: None,
+            confirmed,
+            failed,
+            pending: None,
+            next_search: None,
+            mtu: None,
+        }
+    }
+
+    /// Returns the discovered size of the current path, if the search
+    /// completed.
+    pub fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    /// Sets the remote credentials, e.g. from the answer to an ICE restart,
+    /// and searches the path again.
+    pub fn set_credentials(&mut self, credentials: ProbeCredentials) {
+        if self.credentials.as_ref() != Some(&credentials) {
+            self.credentials = Some(credentials);
+            self.restart();
+        }
+    }
+
+    /// Records where the session's traffic is sent; a new path is searched
+    /// from scratch.
+    pub fn observe_path(&mut self, destination: SocketAddr) {
+        if self.path != Some(destination) {
+            if self.path.is_some() {
+                info!("Path MTU: Path changed to {}, probing again", destination);
+            }
+            self.path = Some(destination);
+            self.mtu = None;
+            self.restart();
+        }
+    }
+
+    /// Sends the probe that is due, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The socket carrying the session's traffic
+    /// * `local_ufrag` - The current local username fragment
+    /// * `now` - The current time
+    ///
+    /// # Returns
+    ///
+    /// The discovered size, if a search completed during this call
+    pub fn poll(&mut self, socket: &UdpSocket, local_ufrag: &str, now: Instant) -> Option<usize> {
+        if !self.config.enabled {
+            return None;
+        }
+        let (Some(path), Some(credentials)) = (self.path, self.credentials.clone()) else {
+            return None;
+        };
+        if self.next_search.is_some_and(|at| now < at) {
+            return None;
+        }
+        if self.next_search.take().is_some() {
+            self.restart();
+        }
+
+        let timeout = Duration::from_millis(self.config.probe_timeout_ms);
+        let size = match &self.pending {
+            Some(p) if now.duration_since(p.sent_at) < timeout => return None,
+            Some(p) if p.attempts >= PROBE_ATTEMPTS => {
+                debug!("Path MTU: {} bytes unanswered", p.size);
+                self.failed = p.size;
+                self.pending = None;
+                self.next_size()?
+            }
+            Some(p) => p.size,
+            None => self.next_size()?,
+        };
+        if self.failed.saturating_sub(self.confirmed) <= SEARCH_GRANULARITY
+            && self.pending.is_none()
+        {
+            return self.complete(now);
+        }
+
+        let username = format!("{}:{}", credentials.remote_ufrag, local_ufrag);
+        let Some((transaction, request)) =
+            stun::padded_binding_request(&username, &credentials.remote_pwd, PROBE_PRIORITY, size)
+        else {
+            self.failed = size;
+            return None;
+        };
+        let attempts = match self.pending.take() {
+            Some(p) if p.size == size => p.attempts + 1,
+            _ => 1,
+        };
+        // Sizes the local interface does not carry fail right away
+        if let Err(e) = pcap::send_to(socket, &request, path) {
+            debug!("Path MTU: Failed to send a {}-byte probe: {}", size, e);
+            self.failed = size;
+            return None;
+        }
+        self.pending = Some(PendingProbe {
+            size,
+            transaction,
+            sent_at: now,
+            attempts,
+        });
+        None
+    }
+
+    /// Matches a datagram against the outstanding probe.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the datagram answers the probe and must not be passed on
+    pub fn handle_response(&mut self, source: SocketAddr, bytes: &[u8]) -> bool {
+        let Some(pending) = &self.pending else {
+            return false;
+        };
+        if self.path != Some(source)
+            || stun::parse_binding_response(bytes, &pending.transaction).is_none()
+        {
+            return false;
+        }
+        debug!("Path MTU: {} bytes get through", pending.size);
+        self.confirmed = pending.size;
+        self.pending = None;
+        true
+    }
+
+    /// Returns the next size to probe, or `None` once the search converged.
+    fn next_size(&self) -> Option<usize> {
+        if self.failed.saturating_sub(self.confirmed) <= SEARCH_GRANULARITY {
+            return Some(self.confirmed);
+        }
+        // The largest size first: most paths carry it
+        let size = if self.failed > self.config.max_size {
+            self.config.max_size
+        } else {
+            (self.confirmed + self.failed) / 2
+        };
+        Some(size / 4 * 4)
+    }
+
+    fn complete(&mut self, now: Instant) -> Option<usize> {
+        let mtu = self.confirmed;
+        self.next_search = Some(now + Duration::from_secs(self.config.reprobe_secs));
+        if self.mtu == Some(mtu) {
+            return None;
+        }
+        self.mtu = Some(mtu);
+        if mtu < STACK_DATAGRAM_SIZE {
+            warn!(
+                "Path MTU: The path carries {} bytes, less than the {}-byte datagrams \
+                 of the WebRTC stack; large packets will be lost",
+                mtu, STACK_DATAGRAM_SIZE
+            );
+        } else {
+            info!("Path MTU: The path carries {}-byte datagrams", mtu);
+        }
+        Some(mtu)
+    }
+
+    fn restart(&mut self) {
+        self.confirmed = self.config.min_size;
+        self.failed = self.config.max_size + 1;
+        self.pending = None;
+        self.next_search = None;
+    }
+}
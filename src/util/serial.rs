@@ -0,0 +1,91 @@
+//! Serial ports
+//!
+//! Opens a serial device in raw mode at a given baud rate, for the MAVLink
+//! bridge. Reads return after at most a tenth of a second without data, so
+//! a reading thread can notice it should stop. Only Linux is supported.
+
+use std::{fs::File, io, path::Path};
+
+/// Opens a serial device in raw mode, 8N1 without flow control.
+///
+/// # Arguments
+///
+/// * `path` - The device, e.g. `/dev/ttyACM0`
+/// * `baud` - The baud rate, one of the standard rates
+///
+/// # Returns
+///
+/// The device, or an error if it cannot be opened or the rate is not a
+/// standard one
+#[cfg(target_os = "linux")]
+pub fn open(path: &Path, baud: u32) -> io::Result<File> {
+    use std::{fs::OpenOptions, mem, os::unix::fs::OpenOptionsExt, os::unix::io::AsRawFd};
+
+    let speed = speed(baud).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported baud rate {}", baud),
+        )
+    })?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+    // SAFETY: termios is plain data, filled by tcgetattr before use
+    let mut tio: libc::termios = unsafe { mem::zeroed() };
+    // SAFETY: fd is an open descriptor and tio a valid termios
+    unsafe {
+        if libc::tcgetattr(fd, &mut tio) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut tio);
+        tio.c_cflag |= libc::CLOCAL | libc::CREAD;
+        tio.c_cflag &= !(libc::CSTOPB | libc::CRTSCTS);
+        // Reads wait up to 100 ms for the first byte
+        tio.c_cc[libc::VMIN] = 0;
+        tio.c_cc[libc::VTIME] = 1;
+        if libc::cfsetispeed(&mut tio, speed) != 0
+            || libc::cfsetospeed(&mut tio, speed) != 0
+            || libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        libc::tcflush(fd, libc::TCIOFLUSH);
+    }
+    Ok(file)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open(_path: &Path, _baud: u32) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "serial ports are only supported on Linux",
+    ))
+}
+
+/// Returns the termios constant of a standard baud rate.
+#[cfg(target_os = "linux")]
+fn speed(baud: u32) -> Option<libc::speed_t> {
+    Some(match baud {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19_200 => libc::B19200,
+        38_400 => libc::B38400,
+        57_600 => libc::B57600,
+        115_200 => libc::B115200,
+        230_400 => libc::B230400,
+        460_800 => libc::B460800,
+        500_000 => libc::B500000,
+        921_600 => libc::B921600,
+        1_000_000 => libc::B1000000,
+        1_500_000 => libc::B1500000,
+        2_000_000 => libc::B2000000,
+        3_000_000 => libc::B3000000,
+        4_000_000 => libc::B4000000,
+        _ => return None,
+    })
+}
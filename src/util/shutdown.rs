@@ -0,0 +1,90 @@
+//! Shutdown signaling for the server and the peer
+//!
+//! A [`Shutdown`] is a flag shared by everything that may end a run: the
+//! event loop checks it on every iteration and, once it is triggered, closes
+//! its data channels, flushes the resulting packets and returns. Clones refer
+//! to the same flag, so any thread or the ctrl-c handler can trigger it, and
+//! async code can await it with [`Shutdown::triggered`].
+
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Cloneable handle requesting a server or peer to shut down.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl Shutdown {
+    /// Creates a handle that has not been triggered.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the shutdown; the event loop notices it within one iteration.
+    pub fn trigger(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+        self.notify.notify_waiters();
+    }
+
+    /// Returns `true` once [`Shutdown::trigger`] has been called on any clone.
+    pub fn is_triggered(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// Waits until [`Shutdown::trigger`] has been called on any clone.
+    ///
+    /// Returns immediately if the shutdown was already triggered.
+    pub async fn triggered(&self) {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            // Register before checking the flag, so a trigger in between is not missed
+            notified.as_mut().enable();
+            if self.is_triggered() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Triggers the shutdown when the process receives ctrl-c.
+    ///
+    /// A second ctrl-c exits the process immediately, in case the graceful
+    /// shutdown hangs.
+    ///
+    /// # Returns
+    ///
+    /// An error if the signal handler could not be installed
+    pub fn trigger_on_ctrl_c(&self) -> anyhow::Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let shutdown = self.clone();
+        thread::Builder::new()
+            .name("ctrl-c".to_string())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    while tokio::signal::ctrl_c().await.is_ok() {
+                        if shutdown.is_triggered() {
+                            warn!("Ctrl-c received again, exiting immediately");
+                            process::exit(130);
+                        }
+                        info!("Ctrl-c received, shutting down");
+                        shutdown.trigger();
+                    }
+                })
+            })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! Socket options of the WebRTC UDP sockets
+//!
+//! The kernel's default buffers hold a few hundred kilobytes, which a burst
+//! of video frames overflows: datagrams are then dropped before the peer or
+//! the server reads them. The `[network.socket]` section sizes the buffers
+//! and sets the traffic class, address reuse and the interface the socket is
+//! bound to, for the peer's and the server's socket alike:
+//!
+//! ```toml
+//! [network.socket]
+//! recv_buffer = 4194304
+//! send_buffer = 4194304
+//! tos = 0xB8          # DSCP EF
+//! reuse_addr = true
+//! reuse_port = false
+//! bind_device = "wwan0"
+//! ```
+//!
+//! The kernel caps the buffers at `net.core.rmem_max` and `wmem_max`; a
+//! buffer smaller than requested is logged. Binding to a device needs
+//! `CAP_NET_RAW`, and like `reuse_port` is only supported on Linux.
+
+use std::{io, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use socket2::Socket;
+use tracing::{debug, warn};
+
+/// Socket options, the `[network.socket]` configuration section.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    /// Size of the receive buffer (`SO_RCVBUF`) in bytes; the kernel default
+    /// if unset
+    pub recv_buffer: Option<usize>,
+    /// Size of the send buffer (`SO_SNDBUF`) in bytes; the kernel default if
+    /// unset
+    pub send_buffer: Option<usize>,
+    /// Type of service byte (`IP_TOS`, or the traffic class on IPv6), e.g.
+    /// a DSCP code point shifted left by two
+    pub tos: Option<u8>,
+    /// Allow binding an address still in use (`SO_REUSEADDR`)
+    pub reuse_addr: bool,
+    /// Allow several sockets on the same port (`SO_REUSEPORT`), Linux only
+    pub reuse_port: bool,
+    /// Name of the interface the socket sends and receives on
+    /// (`SO_BINDTODEVICE`), Linux only
+    pub bind_device: Option<String>,
+}
+
+impl SocketConfig {
+    /// Checks that the sizes are usable and the options supported.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.recv_buffer == Some(0) || self.send_buffer == Some(0) {
+            return Err("recv_buffer and send_buffer must be positive".into());
+        }
+        if self.bind_device.as_deref() == Some("") {
+            return Err("bind_device must not be empty".into());
+        }
+        if !cfg!(target_os = "linux") && (self.reuse_port || self.bind_device.is_some()) {
+            return Err("reuse_port and bind_device are only supported on Linux".into());
+        }
+        Ok(())
+    }
+
+    /// Sets the options on a socket, before it is bound.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The socket
+    /// * `addr` - The address it will be bound to, choosing the IP version
+    ///   of the type of service option
+    ///
+    /// # Returns
+    ///
+    /// An error naming the option the kernel refused
+    pub fn apply(&self, socket: &Socket, addr: &SocketAddr) -> io::Result<()> {
+        let context = |option: &'static str| {
+            move |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", option, e))
+        };
+        if let Some(size) = self.recv_buffer {
+            socket
+                .set_recv_buffer_size(size)
+                .map_err(context("SO_RCVBUF"))?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket
+                .set_send_buffer_size(size)
+                .map_err(context("SO_SNDBUF"))?;
+        }
+        if let Some(tos) = self.tos {
+            match addr {
+                SocketAddr::V4(_) => socket.set_tos(tos.into()).map_err(context("IP_TOS"))?,
+                SocketAddr::V6(_) => {
+                    socket
+                        .set_tclass_v6(tos.into())
+                        .map_err(context("IPV6_TCLASS"))?;
+                    // Dual-stack sockets send IPv4 datagrams too; IPv6-only
+                    // ones refuse the option
+                    if let Err(e) = socket.set_tos(tos.into()) {
+                        debug!("Socket: IPv4 datagrams keep their TOS: {}", e);
+                    }
+                }
+            }
+        }
+        if self.reuse_addr {
+            socket
+                .set_reuse_address(true)
+                .map_err(context("SO_REUSEADDR"))?;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if self.reuse_port {
+                socket
+                    .set_reuse_port(true)
+                    .map_err(context("SO_REUSEPORT"))?;
+            }
+            if let Some(device) = &self.bind_device {
+                socket
+                    .bind_device(Some(device.as_bytes()))
+                    .map_err(context("SO_BINDTODEVICE"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs the buffer sizes the kernel granted if they are smaller than
+    /// requested.
+    ///
+    /// Linux reports twice the usable size, the rest being its bookkeeping.
+    pub fn report(&self, socket: &Socket) {
+        let report = |name: &str, requested: Option<usize>, granted: io::Result<usize>| {
+            let (Some(requested), Ok(granted)) = (requested, granted) else {
+                return;
+            };
+            let usable = if cfg!(target_os = "linux") {
+                granted / 2
+            } else {
+                granted
+            };
+            if usable < requested {
+                warn!(
+                    "Socket: The kernel granted a {}-byte {} buffer instead of {} bytes; \
+                     raise net.core.{}",
+                    usable,
+                    name,
+                    requested,
+                    if name == "receive" {
+                        "rmem_max"
+                    } else {
+                        "wmem_max"
+                    }
+                );
+            } else {
+                debug!("Socket: {}-byte {} buffer", usable, name);
+            }
+        };
+        report("receive", self.recv_buffer, socket.recv_buffer_size());
+        report("send", self.send_buffer, socket.send_buffer_size());
+    }
+}
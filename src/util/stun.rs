@@ -0,0 +1,211 @@
+//! Minimal STUN client for discovering server-reflexive addresses
+//!
+//! str0m does not talk to STUN servers itself, so the peer sends its own
+//! Binding requests (RFC 5389) from the socket carrying the WebRTC traffic and
+//! turns the mapped address from the response into a server-reflexive candidate.
+//!
+//! Padded, authenticated Binding requests to the remote ICE agent double as
+//! path MTU probes, see [`crate::util::pmtu`].
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+/// STUN magic cookie (RFC 5389).
+pub(crate) const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xA4, 0x42];
+
+/// Binding request message type.
+const BINDING_REQUEST: u16 = 0x0001;
+
+/// Binding success response message type.
+const BINDING_SUCCESS: u16 = 0x0101;
+
+/// XOR-MAPPED-ADDRESS attribute type.
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// USERNAME attribute type.
+const ATTR_USERNAME: u16 = 0x0006;
+
+/// MESSAGE-INTEGRITY attribute type.
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+
+/// PRIORITY attribute type (RFC 8445).
+const ATTR_PRIORITY: u16 = 0x0024;
+
+/// PADDING attribute type (RFC 5780).
+const ATTR_PADDING: u16 = 0x0026;
+
+/// Size of the MESSAGE-INTEGRITY attribute, header included.
+const INTEGRITY_SIZE: usize = 24;
+
+/// Default STUN port.
+pub(crate) const DEFAULT_PORT: u16 = 3478;
+
+/// Builds a Binding request.
+///
+/// # Returns
+///
+/// The transaction ID, to match the response, and the encoded request
+pub fn binding_request() -> ([u8; 12], Vec<u8>) {
+    let mut transaction = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut transaction);
+
+    let mut message = Vec::with_capacity(20);
+    message.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&MAGIC_COOKIE);
+    message.extend_from_slice(&transaction);
+    (transaction, message)
+}
+
+/// Builds an ICE connectivity check padded to an exact datagram size.
+///
+/// The request carries the short-term credentials of the session, so the
+/// remote ICE agent answers it like any other connectivity check; the
+/// PADDING attribute, which it ignores, brings it to the probed size.
+///
+/// # Arguments
+///
+/// * `username` - `remote_ufrag:local_ufrag`
+/// * `password` - The remote ICE password
+/// * `priority` - The PRIORITY of the local candidate
+/// * `size` - The datagram size, a multiple of 4
+///
+/// # Returns
+///
+/// The transaction ID, to match the response, and the encoded request, or
+/// `None` if the size is too small for the attributes
+pub fn padded_binding_request(
+    username: &str,
+    password: &str,
+    priority: u32,
+    size: usize,
+) -> Option<([u8; 12], Vec<u8>)> {
+    let mut transaction = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut transaction);
+
+    let mut message = Vec::with_capacity(size);
+    message.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&MAGIC_COOKIE);
+    message.extend_from_slice(&transaction);
+    push_attr(&mut message, ATTR_USERNAME, username.as_bytes());
+    push_attr(&mut message, ATTR_PRIORITY, &priority.to_be_bytes());
+    let padding = size.checked_sub(message.len() + 4 + INTEGRITY_SIZE)?;
+    if !size.is_multiple_of(4) {
+        return None;
+    }
+    push_attr(&mut message, ATTR_PADDING, &vec![0; padding]);
+
+    // The integrity covers the header with the length including itself
+    let length = (message.len() - 20 + INTEGRITY_SIZE) as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(password.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(&message);
+    push_attr(
+        &mut message,
+        ATTR_MESSAGE_INTEGRITY,
+        &mac.finalize().into_bytes(),
+    );
+    Some((transaction, message))
+}
+
+/// Appends an attribute, padded to a multiple of 4 bytes.
+fn push_attr(message: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    message.extend_from_slice(value);
+    message.resize(message.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+/// Extracts the mapped address from a Binding success response.
+///
+/// # Arguments
+///
+/// * `bytes` - The received datagram
+/// * `transaction` - The transaction ID of the request
+///
+/// # Returns
+///
+/// The XOR-MAPPED-ADDRESS, or `None` if the datagram is not the response to
+/// the given request
+pub fn parse_binding_response(bytes: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if bytes.len() < 20
+        || u16::from_be_bytes([bytes[0], bytes[1]]) != BINDING_SUCCESS
+        || bytes[4..8] != MAGIC_COOKIE
+        || &bytes[8..20] != transaction
+    {
+        return None;
+    }
+
+    let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+    let mut attrs = bytes.get(20..20 + length)?;
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + len)?;
+        if kind == ATTR_XOR_MAPPED_ADDRESS {
+            return decode_xor_address(value, transaction);
+        }
+        // Attributes are padded to a multiple of 4 bytes.
+        attrs = attrs.get(4 + len.div_ceil(4) * 4..)?;
+    }
+    None
+}
+
+/// Resolves the host of a `stun:` URL.
+///
+/// # Returns
+///
+/// The server address, or `None` for other schemes or unresolvable hosts
+pub fn resolve_stun_url(url: &str) -> Option<SocketAddr> {
+    resolve_host(url.strip_prefix("stun:")?)
+}
+
+/// Resolves the `host[:port][?params]` part of a STUN or TURN URL to an IPv4
+/// address, defaulting to the standard port.
+pub(crate) fn resolve_host(host: &str) -> Option<SocketAddr> {
+    let host = host.split('?').next()?;
+    let target = if host
+        .rsplit_once(':')
+        .is_some_and(|(_, p)| p.parse::<u16>().is_ok())
+    {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, DEFAULT_PORT)
+    };
+    target.to_socket_addrs().ok()?.find(SocketAddr::is_ipv4)
+}
+
+/// Decodes an XOR-MAPPED-ADDRESS style attribute value.
+pub(crate) fn decode_xor_address(value: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if value.len() < 8 {
+        return None;
+    }
+    let port = u16::from_be_bytes([value[2], value[3]])
+        ^ u16::from_be_bytes([MAGIC_COOKIE[0], MAGIC_COOKIE[1]]);
+    let ip = match value[1] {
+        0x01 => {
+            let mut octets = [0u8; 4];
+            for (i, o) in octets.iter_mut().enumerate() {
+                *o = value[4 + i] ^ MAGIC_COOKIE[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 if value.len() >= 20 => {
+            let mut key = [0u8; 16];
+            key[..4].copy_from_slice(&MAGIC_COOKIE);
+            key[4..].copy_from_slice(transaction);
+            let mut octets = [0u8; 16];
+            for (i, o) in octets.iter_mut().enumerate() {
+                *o = value[4 + i] ^ key[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
@@ -0,0 +1,597 @@
+//! Minimal TURN client for relayed candidates
+//!
+//! Rovers behind carrier-grade NAT often cannot be reached through host or
+//! server-reflexive candidates. This client allocates a relayed address on a
+//! TURN server (RFC 5766) from the socket carrying the WebRTC traffic, installs
+//! permissions and binds channels for the remote addresses str0m talks to, and
+//! wraps and unwraps the relayed traffic. Since relayed candidates have the
+//! lowest ICE priority, the relay is only used when the direct paths fail.
+//!
+//! Only UDP transport to the TURN server is supported.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use rand::RngCore;
+use sha1::Sha1;
+use tracing::{info, warn};
+
+use crate::util::{
+    pcap,
+    stun::{decode_xor_address, resolve_host, MAGIC_COOKIE},
+};
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Allocate method.
+const ALLOCATE: u16 = 0x0003;
+/// Refresh method.
+const REFRESH: u16 = 0x0004;
+/// CreatePermission method.
+const CREATE_PERMISSION: u16 = 0x0008;
+/// ChannelBind method.
+const CHANNEL_BIND: u16 = 0x0009;
+/// Send indication message type.
+const SEND_INDICATION: u16 = 0x0016;
+/// Data indication message type.
+const DATA_INDICATION: u16 = 0x0017;
+
+/// Class bits of a success response.
+const SUCCESS_CLASS: u16 = 0x0100;
+/// Class bits of an error response.
+const ERROR_CLASS: u16 = 0x0110;
+
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_CHANNEL_NUMBER: u16 = 0x000C;
+const ATTR_LIFETIME: u16 = 0x000D;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_DATA: u16 = 0x0013;
+const ATTR_REALM: u16 = 0x0014;
+const ATTR_NONCE: u16 = 0x0015;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+
+/// Protocol number of UDP in REQUESTED-TRANSPORT.
+const TRANSPORT_UDP: u8 = 17;
+
+/// Lifetime requested for the allocation.
+const ALLOCATION_LIFETIME: Duration = Duration::from_secs(600);
+
+/// Permissions expire after five minutes; refresh them a minute early.
+const PERMISSION_REFRESH: Duration = Duration::from_secs(240);
+
+/// Channel bindings expire after ten minutes; refresh them a minute early.
+const CHANNEL_REFRESH: Duration = Duration::from_secs(540);
+
+/// First and last channel numbers available to clients.
+const CHANNEL_RANGE: (u16, u16) = (0x4000, 0x7FFE);
+
+/// Maximum number of transmissions of a request.
+const REQUEST_ATTEMPTS: u32 = 5;
+
+/// Interval between request retransmissions.
+const REQUEST_RETRANSMIT: Duration = Duration::from_millis(500);
+
+/// Resolves the server of a `turn:` URL.
+///
+/// # Returns
+///
+/// The server address, or `None` for `turns:` or TCP URLs, which are not
+/// supported, and for unresolvable hosts
+pub fn resolve_turn_url(url: &str) -> Option<SocketAddr> {
+    let host = url.strip_prefix("turn:")?;
+    if host.contains("transport=tcp") {
+        return None;
+    }
+    resolve_host(host)
+}
+
+/// What a datagram from the TURN server meant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TurnEvent {
+    /// The allocation succeeded with the given relayed address
+    Allocated(SocketAddr),
+    /// A remote peer sent data through the relay
+    Data { peer: SocketAddr, data: Vec<u8> },
+    /// The allocation failed and the client gave up
+    Failed,
+}
+
+/// The purpose of an outstanding request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request {
+    Allocate,
+    Refresh { lifetime: u32 },
+    Permission(IpAddr),
+    ChannelBind(SocketAddr, u16),
+}
+
+/// An outstanding request, retransmitted until answered.
+struct Transaction {
+    request: Request,
+    message: Vec<u8>,
+    attempts: u32,
+    last_sent: Option<Instant>,
+}
+
+/// A channel bound, or being bound, to a remote peer.
+struct Channel {
+    number: u16,
+    bound: bool,
+    refresh_at: Instant,
+}
+
+/// Client side of a single TURN allocation.
+pub struct TurnClient {
+    server: SocketAddr,
+    username: String,
+    password: String,
+    realm: Option<String>,
+    nonce: Option<String>,
+    relayed: Option<SocketAddr>,
+    refresh_at: Option<Instant>,
+    failed: bool,
+    transactions: HashMap<[u8; 12], Transaction>,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<SocketAddr, Channel>,
+    next_channel: u16,
+}
+
+impl TurnClient {
+    /// Creates a client for a TURN server; the allocation is requested on the
+    /// first [`TurnClient::poll`].
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The address of the TURN server
+    /// * `username` - The TURN username
+    /// * `password` - The TURN password (the credential of the ICE server)
+    pub fn new(server: SocketAddr, username: String, password: String) -> Self {
+        Self {
+            server,
+            username,
+            password,
+            realm: None,
+            nonce: None,
+            relayed: None,
+            refresh_at: None,
+            failed: false,
+            transactions: HashMap::new(),
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+            next_channel: CHANNEL_RANGE.0,
+        }
+    }
+
+    /// Returns the address of the TURN server.
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Returns the relayed address, once allocated.
+    pub fn relayed_addr(&self) -> Option<SocketAddr> {
+        self.relayed
+    }
+
+    /// Sends the requests that are due: the initial allocation, refreshes of
+    /// the allocation, permissions and channels, and retransmissions.
+    pub fn poll(&mut self, socket: &UdpSocket) {
+        if self.failed {
+            return;
+        }
+        let now = Instant::now();
+
+        if self.relayed.is_none() && !self.has_pending(|r| r == Request::Allocate) {
+            self.request(Request::Allocate);
+        }
+        if self.refresh_at.is_some_and(|at| at <= now) {
+            self.refresh_at = None;
+            self.request(Request::Refresh {
+                lifetime: ALLOCATION_LIFETIME.as_secs() as u32,
+            });
+        }
+        let due: Vec<IpAddr> = self
+            .permissions
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in due {
+            self.permissions.insert(ip, now + PERMISSION_REFRESH);
+            self.request(Request::Permission(ip));
+        }
+        let due: Vec<(SocketAddr, u16)> = self
+            .channels
+            .iter_mut()
+            .filter(|(_, c)| c.refresh_at <= now)
+            .map(|(peer, c)| {
+                c.refresh_at = now + CHANNEL_REFRESH;
+                (*peer, c.number)
+            })
+            .collect();
+        for (peer, number) in due {
+            self.request(Request::ChannelBind(peer, number));
+        }
+
+        let server = self.server;
+        let mut allocation_failed = false;
+        self.transactions.retain(|_, transaction| {
+            if transaction
+                .last_sent
+                .is_some_and(|at| at.elapsed() < REQUEST_RETRANSMIT)
+            {
+                return true;
+            }
+            if transaction.attempts >= REQUEST_ATTEMPTS {
+                warn!(
+                    "TURN server {} did not answer {:?}",
+                    server, transaction.request
+                );
+                allocation_failed |= transaction.request == Request::Allocate;
+                return false;
+            }
+            if let Err(e) = pcap::send_to(socket, &transaction.message, server) {
+                warn!("Failed to send to TURN server {}: {}", server, e);
+            }
+            transaction.attempts += 1;
+            transaction.last_sent = Some(Instant::now());
+            true
+        });
+        self.failed |= allocation_failed;
+    }
+
+    /// Sends data to a remote peer through the relay.
+    ///
+    /// The first send to a peer installs a permission and binds a channel;
+    /// until the channel is bound, data goes out as Send indications.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The socket the allocation was made from
+    /// * `peer` - The remote address, as seen by the relay
+    /// * `data` - The datagram to relay
+    pub fn send(&mut self, socket: &UdpSocket, peer: SocketAddr, data: &[u8]) {
+        if self.relayed.is_none() {
+            return;
+        }
+        if let Entry::Vacant(entry) = self.permissions.entry(peer.ip()) {
+            entry.insert(Instant::now() + PERMISSION_REFRESH);
+            self.request(Request::Permission(peer.ip()));
+        }
+        if !self.channels.contains_key(&peer) && self.next_channel <= CHANNEL_RANGE.1 {
+            let number = self.next_channel;
+            self.next_channel += 1;
+            self.channels.insert(
+                peer,
+                Channel {
+                    number,
+                    bound: false,
+                    refresh_at: Instant::now() + CHANNEL_REFRESH,
+                },
+            );
+            self.request(Request::ChannelBind(peer, number));
+        }
+
+        let datagram = match self.channels.get(&peer) {
+            Some(channel) if channel.bound => {
+                let mut datagram = Vec::with_capacity(4 + data.len());
+                datagram.extend_from_slice(&channel.number.to_be_bytes());
+                datagram.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                datagram.extend_from_slice(data);
+                datagram
+            }
+            _ => {
+                let mut message = MessageBuilder::new(SEND_INDICATION, new_transaction());
+                message.xor_address(ATTR_XOR_PEER_ADDRESS, peer);
+                message.attr(ATTR_DATA, data);
+                message.finish(None)
+            }
+        };
+        if let Err(e) = pcap::send_to(socket, &datagram, self.server) {
+            warn!("Failed to relay data to {}: {}", peer, e);
+        }
+    }
+
+    /// Handles a datagram from the TURN server.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(TurnEvent)` - If the datagram changes the allocation or carries data
+    /// * `None` - If it was handled internally, e.g. a permission response
+    pub fn handle(&mut self, bytes: &[u8]) -> Option<TurnEvent> {
+        // ChannelData messages start with a channel number, STUN messages with 0b00
+        if bytes.len() >= 4 && (0x40..=0x7F).contains(&bytes[0]) {
+            let number = u16::from_be_bytes([bytes[0], bytes[1]]);
+            let len = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+            let data = bytes.get(4..4 + len)?;
+            let peer = self
+                .channels
+                .iter()
+                .find(|(_, c)| c.number == number)
+                .map(|(peer, _)| *peer)?;
+            return Some(TurnEvent::Data {
+                peer,
+                data: data.to_vec(),
+            });
+        }
+
+        let message = Message::parse(bytes)?;
+        if message.kind == DATA_INDICATION {
+            let peer =
+                decode_xor_address(message.attr(ATTR_XOR_PEER_ADDRESS)?, &message.transaction)?;
+            return Some(TurnEvent::Data {
+                peer,
+                data: message.attr(ATTR_DATA)?.to_vec(),
+            });
+        }
+
+        let transaction = self.transactions.remove(&message.transaction)?;
+        if message.kind & ERROR_CLASS == ERROR_CLASS {
+            return self.handle_error(transaction.request, &message);
+        }
+        if message.kind & ERROR_CLASS != SUCCESS_CLASS {
+            return None;
+        }
+
+        match transaction.request {
+            Request::Allocate => {
+                let relayed = decode_xor_address(
+                    message.attr(ATTR_XOR_RELAYED_ADDRESS)?,
+                    &message.transaction,
+                )?;
+                let lifetime = message
+                    .attr(ATTR_LIFETIME)
+                    .and_then(|v| Some(u32::from_be_bytes(v.get(..4)?.try_into().ok()?)))
+                    .unwrap_or(ALLOCATION_LIFETIME.as_secs() as u32);
+                info!(
+                    "TURN server {} allocated {} for {}s",
+                    self.server, relayed, lifetime
+                );
+                self.relayed = Some(relayed);
+                self.refresh_at = Some(Instant::now() + Duration::from_secs(lifetime as u64 / 2));
+                Some(TurnEvent::Allocated(relayed))
+            }
+            Request::Refresh { .. } => {
+                self.refresh_at = Some(Instant::now() + ALLOCATION_LIFETIME / 2);
+                None
+            }
+            Request::Permission(_) => None,
+            Request::ChannelBind(peer, number) => {
+                if let Some(channel) = self.channels.get_mut(&peer) {
+                    channel.bound = channel.number == number;
+                }
+                None
+            }
+        }
+    }
+
+    /// Releases the allocation on the server.
+    pub fn close(&mut self, socket: &UdpSocket) {
+        if self.relayed.take().is_none() {
+            return;
+        }
+        let (_, message) = self.build(Request::Refresh { lifetime: 0 });
+        if let Err(e) = pcap::send_to(socket, &message, self.server) {
+            warn!("Failed to release TURN allocation: {}", e);
+        }
+    }
+
+    /// Handles an error response, retrying with credentials when challenged.
+    fn handle_error(&mut self, request: Request, message: &Message) -> Option<TurnEvent> {
+        let code = message
+            .attr(ATTR_ERROR_CODE)
+            .filter(|v| v.len() >= 4)
+            .map(|v| (v[2] & 0x07) as u16 * 100 + v[3] as u16)
+            .unwrap_or(0);
+
+        // 401 Unauthorized carries the realm and nonce to authenticate with;
+        // 438 Stale Nonce carries a fresh nonce.
+        let challenged = (code == 401 && self.nonce.is_none()) || code == 438;
+        if challenged {
+            if let Some(realm) = message.attr(ATTR_REALM) {
+                self.realm = Some(String::from_utf8_lossy(realm).into_owned());
+            }
+            if let Some(nonce) = message.attr(ATTR_NONCE) {
+                self.nonce = Some(String::from_utf8_lossy(nonce).into_owned());
+                self.request(request);
+                return None;
+            }
+        }
+
+        warn!(
+            "TURN server {} rejected {:?} with error {}",
+            self.server, request, code
+        );
+        match request {
+            Request::Allocate => {
+                self.failed = true;
+                Some(TurnEvent::Failed)
+            }
+            Request::Refresh { .. } => {
+                // The allocation is gone; allocate again on the next poll
+                self.relayed = None;
+                self.refresh_at = None;
+                None
+            }
+            Request::Permission(_) | Request::ChannelBind(..) => None,
+        }
+    }
+
+    /// Returns `true` if a matching request is outstanding.
+    fn has_pending(&self, matches: impl Fn(Request) -> bool) -> bool {
+        self.transactions.values().any(|t| matches(t.request))
+    }
+
+    /// Queues a request, sent on the next [`TurnClient::poll`].
+    fn request(&mut self, request: Request) {
+        let (transaction, message) = self.build(request);
+        self.transactions.insert(
+            transaction,
+            Transaction {
+                request,
+                message,
+                attempts: 0,
+                last_sent: None,
+            },
+        );
+    }
+
+    /// Encodes a request, authenticated once the server sent a nonce.
+    fn build(&self, request: Request) -> ([u8; 12], Vec<u8>) {
+        let transaction = new_transaction();
+        let method = match request {
+            Request::Allocate => ALLOCATE,
+            Request::Refresh { .. } => REFRESH,
+            Request::Permission(_) => CREATE_PERMISSION,
+            Request::ChannelBind(..) => CHANNEL_BIND,
+        };
+
+        let mut message = MessageBuilder::new(method, transaction);
+        match request {
+            Request::Allocate => {
+                message.attr(ATTR_REQUESTED_TRANSPORT, &[TRANSPORT_UDP, 0, 0, 0]);
+                message.attr(
+                    ATTR_LIFETIME,
+                    &(ALLOCATION_LIFETIME.as_secs() as u32).to_be_bytes(),
+                );
+            }
+            Request::Refresh { lifetime } => message.attr(ATTR_LIFETIME, &lifetime.to_be_bytes()),
+            Request::Permission(ip) => {
+                message.xor_address(ATTR_XOR_PEER_ADDRESS, SocketAddr::new(ip, 0))
+            }
+            Request::ChannelBind(peer, number) => {
+                let mut value = [0u8; 4];
+                value[..2].copy_from_slice(&number.to_be_bytes());
+                message.attr(ATTR_CHANNEL_NUMBER, &value);
+                message.xor_address(ATTR_XOR_PEER_ADDRESS, peer);
+            }
+        }
+
+        let key = match (&self.realm, &self.nonce) {
+            (Some(realm), Some(nonce)) => {
+                message.attr(ATTR_USERNAME, self.username.as_bytes());
+                message.attr(ATTR_REALM, realm.as_bytes());
+                message.attr(ATTR_NONCE, nonce.as_bytes());
+                // Long-term credential key: MD5(username:realm:password)
+                let key = Md5::digest(format!("{}:{}:{}", self.username, realm, self.password));
+                Some(key.to_vec())
+            }
+            _ => None,
+        };
+        (transaction, message.finish(key.as_deref()))
+    }
+}
+
+fn new_transaction() -> [u8; 12] {
+    let mut transaction = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut transaction);
+    transaction
+}
+
+/// Incrementally encodes a STUN message.
+struct MessageBuilder {
+    bytes: Vec<u8>,
+    transaction: [u8; 12],
+}
+
+impl MessageBuilder {
+    fn new(kind: u16, transaction: [u8; 12]) -> Self {
+        let mut bytes = Vec::with_capacity(128);
+        bytes.extend_from_slice(&kind.to_be_bytes());
+        bytes.extend_from_slice(&0u16.to_be_bytes());
+        bytes.extend_from_slice(&MAGIC_COOKIE);
+        bytes.extend_from_slice(&transaction);
+        Self { bytes, transaction }
+    }
+
+    /// Appends an attribute, padded to a multiple of 4 bytes.
+    fn attr(&mut self, kind: u16, value: &[u8]) {
+        self.bytes.extend_from_slice(&kind.to_be_bytes());
+        self.bytes
+            .extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.bytes.extend_from_slice(value);
+        self.bytes
+            .resize(self.bytes.len() + (4 - value.len() % 4) % 4, 0);
+    }
+
+    /// Appends an XOR-encoded address attribute.
+    fn xor_address(&mut self, kind: u16, addr: SocketAddr) {
+        let port = addr.port() ^ u16::from_be_bytes([MAGIC_COOKIE[0], MAGIC_COOKIE[1]]);
+        let mut value = vec![0];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                value.push(0x01);
+                value.extend_from_slice(&port.to_be_bytes());
+                value.extend(ip.octets().iter().zip(MAGIC_COOKIE).map(|(o, k)| o ^ k));
+            }
+            IpAddr::V6(ip) => {
+                value.push(0x02);
+                value.extend_from_slice(&port.to_be_bytes());
+                let key = MAGIC_COOKIE.iter().chain(self.transaction.iter());
+                value.extend(ip.octets().iter().zip(key).map(|(o, k)| o ^ k));
+            }
+        }
+        self.attr(kind, &value);
+    }
+
+    /// Sets the length and appends MESSAGE-INTEGRITY if a key is given.
+    fn finish(mut self, key: Option<&[u8]>) -> Vec<u8> {
+        if let Some(key) = key {
+            // The integrity covers the header with the length including itself
+            self.set_length(self.bytes.len() - 20 + 24);
+            let mut mac = HmacSha1::new_from_slice(key).expect("HMAC accepts any key length");
+            mac.update(&self.bytes);
+            let digest = mac.finalize().into_bytes();
+            self.attr(ATTR_MESSAGE_INTEGRITY, &digest);
+        }
+        self.set_length(self.bytes.len() - 20);
+        self.bytes
+    }
+
+    fn set_length(&mut self, length: usize) {
+        self.bytes[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+    }
+}
+
+/// A decoded STUN message.
+struct Message<'a> {
+    kind: u16,
+    transaction: [u8; 12],
+    attrs: Vec<(u16, &'a [u8])>,
+}
+
+impl<'a> Message<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < 20 || bytes[0] & 0xC0 != 0 || bytes[4..8] != MAGIC_COOKIE {
+            return None;
+        }
+        let kind = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        let transaction = bytes[8..20].try_into().ok()?;
+
+        let mut attrs = vec![];
+        let mut rest = bytes.get(20..20 + length)?;
+        while rest.len() >= 4 {
+            let kind = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            attrs.push((kind, rest.get(4..4 + len)?));
+            rest = rest.get((4 + len.div_ceil(4) * 4).min(rest.len())..)?;
+        }
+        Some(Self {
+            kind,
+            transaction,
+            attrs,
+        })
+    }
+
+    fn attr(&self, kind: u16) -> Option<&'a [u8]> {
+        self.attrs.iter().find(|(k, _)| *k == kind).map(|(_, v)| *v)
+    }
+}
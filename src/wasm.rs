@@ -0,0 +1,405 @@
+//! WebAssembly bindings of the wire protocol, built with the `wasm` feature
+//!
+//! A browser-based operator console connects with the browser's own WebRTC
+//! stack, and uses this module to speak the same application protocol as the
+//! Rust peer on its data channels: the control handshake, the payload
+//! envelope and the typed channel messages. Only the portable `model`
+//! modules are compiled in:
+//!
+//! ```bash
+//! wasm-pack build --target web -- --no-default-features --features wasm
+//! ```
+//!
+//! ```js
+//! import init, { ControlSession, decodeMessage } from "./pkg/rover_rtc.js";
+//!
+//! await init();
+//! const session = new ControlSession(["topics"], false, true);
+//! control.onopen = () => {
+//!     control.send(session.hello());
+//!     control.send(session.capabilities());
+//! };
+//! control.onmessage = (e) => session.receive(new Uint8Array(e.data));
+//! telemetry.onmessage = (e) => console.log(decodeMessage("telemetry", new Uint8Array(e.data)));
+//! video.send(session.encodePayload(frame));
+//! commands.send(session.encodeMessage("command", "arm", new TextEncoder().encode("go")));
+//! ```
+
+use wasm_bindgen::prelude::*;
+
+use crate::model::{
+    capture::{CaptureMessage, CAPTURE_CHANNEL},
+    control::{
+        common_features, negotiate, ControlMessage, Feature, FeatureSet, Negotiation,
+        ProtocolConfig, CONTROL_CHANNEL, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    coordination::{CoordinationMessage, COORDINATION_CHANNEL},
+    crash::{CrashMessage, CRASH_CHANNEL},
+    forward::{ForwardMessage, FORWARD_CHANNEL},
+    logs::{LogMessage, LOGS_CHANNEL},
+    mission::{MissionMessage, MISSION_CHANNEL},
+    payload::{Envelope, MessageKind, Payload, WireFormat},
+    rate,
+    schema::ProtocolDoc,
+    session::{SessionMessage, SESSION_CHANNEL},
+    telemetry::{Telemetry, TELEMETRY_CHANNEL},
+    transfer::{TransferMessage, TRANSFER_CHANNEL},
+};
+
+/// Returns the newest protocol version this build speaks.
+#[wasm_bindgen(js_name = protocolVersion)]
+pub fn protocol_version() -> u16 {
+    PROTOCOL_VERSION
+}
+
+/// Returns the oldest protocol version this build speaks.
+#[wasm_bindgen(js_name = minProtocolVersion)]
+pub fn min_protocol_version() -> u16 {
+    MIN_PROTOCOL_VERSION
+}
+
+/// Returns the protocol description printed by the `protocol-doc` command.
+#[wasm_bindgen(js_name = protocolDoc)]
+pub fn protocol_doc() -> String {
+    ProtocolDoc::new().to_json()
+}
+
+/// Encodes a message for one of the built-in channels.
+///
+/// # Arguments
+///
+/// * `label` - The channel label, e.g. `mission`
+/// * `json` - The message, in the JSON form returned by [`decode_message`]
+///
+/// # Returns
+///
+/// The bytes to send on the channel, or an error for an unknown channel or a
+/// message that does not fit it
+#[wasm_bindgen(js_name = encodeMessage)]
+pub fn encode_message(label: &str, json: &str) -> Result<Vec<u8>, JsError> {
+    fn parse<T: serde::de::DeserializeOwned>(json: &str) -> Result<T, JsError> {
+        serde_json::from_str(json).map_err(|e| JsError::new(&e.to_string()))
+    }
+    Ok(match label {
+        CONTROL_CHANNEL => parse::<ControlMessage>(json)?.encode(),
+        SESSION_CHANNEL => parse::<SessionMessage>(json)?.encode(),
+        MISSION_CHANNEL => parse::<MissionMessage>(json)?.encode(),
+        LOGS_CHANNEL => parse::<LogMessage>(json)?.encode(),
+        CAPTURE_CHANNEL => parse::<CaptureMessage>(json)?.encode(),
+        CRASH_CHANNEL => parse::<CrashMessage>(json)?.encode(),
+        TRANSFER_CHANNEL => parse::<TransferMessage>(json)?.encode(),
+        FORWARD_CHANNEL => parse::<ForwardMessage>(json)?.encode(),
+        TELEMETRY_CHANNEL => parse::<Telemetry>(json)?.encode(),
+        COORDINATION_CHANNEL => parse::<CoordinationMessage>(json)?.encode(),
+        _ => {
+            return Err(JsError::new(&format!(
+                "'{}' is not a built-in channel",
+                label
+            )))
+        }
+    })
+}
+
+/// Decodes a message received on one of the built-in channels.
+///
+/// # Returns
+///
+/// The message as JSON, e.g. `{"Gps":{"latitude":...}}` on the
+/// telemetry channel, or an error for an unknown channel or undecodable bytes
+#[wasm_bindgen(js_name = decodeMessage)]
+pub fn decode_message(label: &str, bytes: &[u8]) -> Result<String, JsError> {
+    let json = match label {
+        CONTROL_CHANNEL => ControlMessage::decode(bytes).map(|m| serde_json::to_string(&m)),
+        SESSION_CHANNEL => SessionMessage::decode(bytes).map(|m| serde_json::to_string(&m)),
+        MISSION_CHANNEL => MissionMessage::decode(bytes).map(|m| serde_json::to_string(&m)),
+        LOGS_CHANNEL => LogMessage::decode(bytes).map(|m| serde_json::to_string(&m)),
+        CAPTURE_CHANNEL => CaptureMessage::decode(bytes).map(|m| serde_json::to_string(&m)),
+        CRASH_CHANNEL => CrashMessage::decode(bytes).map(|m| serde_json::to_string(&m)),
+        TRANSFER_CHANNEL => TransferMessage::decode(bytes).map(|m| serde_json::to_string(&m)),
+        FORWARD_CHANNEL => ForwardMessage::decode(bytes).map(|m| serde_json::to_string(&m)),
+        TELEMETRY_CHANNEL => Telemetry::decode(bytes).map(|m| serde_json::to_string(&m)),
+        COORDINATION_CHANNEL => {
+            CoordinationMessage::decode(bytes).map(|m| serde_json::to_string(&m))
+        }
+        _ => {
+            return Err(JsError::new(&format!(
+                "'{}' is not a built-in channel",
+                label
+            )))
+        }
+    };
+    json.ok_or_else(|| JsError::new(&format!("undecodable {} message", label)))?
+        .map_err(|e| JsError::new(&e.to_string()))
+}
+
+/// An application payload received on a data channel.
+#[wasm_bindgen]
+pub struct DecodedPayload {
+    envelope: Envelope,
+    format: WireFormat,
+}
+
+#[wasm_bindgen]
+impl DecodedPayload {
+    /// The application data.
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Vec<u8> {
+        self.envelope.payload.data.clone()
+    }
+
+    /// The send time, in nanoseconds since the Unix epoch.
+    #[wasm_bindgen(getter)]
+    pub fn timestamp(&self) -> i64 {
+        self.envelope.payload.timestamp
+    }
+
+    /// The latency since the payload was sent, in milliseconds.
+    #[wasm_bindgen(getter, js_name = latencyMs)]
+    pub fn latency_ms(&self) -> f64 {
+        self.envelope.payload.latency_ms()
+    }
+
+    /// What the message carries, e.g. `telemetry`; `data` for payloads sent
+    /// without a header.
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.envelope.kind.name().to_string()
+    }
+
+    /// The position of the message in the sender's stream; `0` without a
+    /// header.
+    #[wasm_bindgen(getter)]
+    pub fn sequence(&self) -> u64 {
+        self.envelope.sequence
+    }
+
+    /// The topic of the message; empty without a header.
+    #[wasm_bindgen(getter)]
+    pub fn topic(&self) -> String {
+        self.envelope.topic.clone()
+    }
+
+    /// `false` if the payload arrived in the bare legacy format.
+    #[wasm_bindgen(getter)]
+    pub fn enveloped(&self) -> bool {
+        self.format != WireFormat::Legacy
+    }
+}
+
+/// The protocol state of one connection, driven by the control channel.
+///
+/// Mirrors the Rust peer: both sides send their hello and capabilities when
+/// the control channel opens, payloads are enveloped once the versions are
+/// agreed, and before that (or after a fallback) bare legacy payloads are
+/// sent if legacy interop is enabled.
+#[wasm_bindgen]
+pub struct ControlSession {
+    config: ProtocolConfig,
+    negotiation: Negotiation,
+    features: FeatureSet,
+    sequence: u64,
+}
+
+#[wasm_bindgen]
+impl ControlSession {
+    /// Creates the state of a new connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - The optional features this side decodes, by name
+    /// * `allow_fallback` - Keep the session with an incompatible remote,
+    ///   exchanging plain data only
+    /// * `legacy_payloads` - Exchange bare payloads with remotes that have not
+    ///   completed the handshake
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        features: Vec<String>,
+        allow_fallback: bool,
+        legacy_payloads: bool,
+    ) -> Result<ControlSession, JsError> {
+        let features = features
+            .iter()
+            .map(|name| {
+                Feature::ALL
+                    .into_iter()
+                    .find(|f| f.name() == name)
+                    .ok_or_else(|| JsError::new(&format!("unknown feature '{}'", name)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            config: ProtocolConfig {
+                allow_fallback,
+                features,
+                legacy_payloads,
+                ..ProtocolConfig::default()
+            },
+            negotiation: Negotiation::Pending,
+            features: FeatureSet::default(),
+            sequence: 0,
+        })
+    }
+
+    /// Returns the hello to send first on the control channel.
+    pub fn hello(&self) -> Vec<u8> {
+        ControlMessage::hello().encode()
+    }
+
+    /// Returns the capabilities to send after the hello.
+    pub fn capabilities(&self) -> Vec<u8> {
+        ControlMessage::capabilities(&self.config).encode()
+    }
+
+    /// Returns the goodbye to send on the control channel before closing the
+    /// connection, so the remote drops it at once.
+    pub fn goodbye(&self, reason: &str) -> Vec<u8> {
+        ControlMessage::Goodbye {
+            reason: reason.to_string(),
+        }
+        .encode()
+    }
+
+    /// Returns the request to send on the control channel for the rate at
+    /// which the remote should publish a topic, once both sides agreed to
+    /// `rate_control`; `0` Hz withdraws the request.
+    #[wasm_bindgen(js_name = requestRate)]
+    pub fn request_rate(&self, topic: &str, hz: f64) -> Vec<u8> {
+        ControlMessage::RequestRate {
+            topic: topic.to_string(),
+            millihertz: rate::to_millihertz(hz),
+        }
+        .encode()
+    }
+
+    /// Handles a message received on the control channel.
+    ///
+    /// # Returns
+    ///
+    /// An `Incompatible` message to send before closing the connection if
+    /// the versions are incompatible and fallback is not allowed, a `Pong` to
+    /// send if the remote pinged, otherwise `undefined`; an error if the
+    /// remote closed the session
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<Option<Vec<u8>>, JsError> {
+        let Some(message) = ControlMessage::decode(bytes) else {
+            return Ok(None);
+        };
+        match &message {
+            ControlMessage::Incompatible { reason } | ControlMessage::Goodbye { reason } => {
+                return Err(JsError::new(&format!(
+                    "remote closed the session: {}",
+                    reason
+                )));
+            }
+            ControlMessage::Capabilities { features } => {
+                self.features = common_features(*features, self.negotiation, &self.config);
+                return Ok(None);
+            }
+            // Browser consoles receive topics but publish none
+            ControlMessage::RequestRate { .. } => return Ok(None),
+            // Browser consoles answer heartbeats; the remote measures the link
+            ControlMessage::Ping { sequence } => {
+                return Ok(Some(
+                    ControlMessage::Pong {
+                        sequence: *sequence,
+                    }
+                    .encode(),
+                ));
+            }
+            ControlMessage::Pong { .. } => return Ok(None),
+            // Browser consoles agree on no session keys, and the server asks
+            // none to restart ICE
+            ControlMessage::KeyShare { .. } | ControlMessage::RestartIce => return Ok(None),
+            ControlMessage::Hello { .. } => {}
+        }
+        match negotiate(&message, &self.config) {
+            Ok(negotiation) => {
+                self.negotiation = negotiation;
+                Ok(None)
+            }
+            Err(mismatch) => Ok(Some(
+                ControlMessage::Incompatible {
+                    reason: mismatch.to_string(),
+                }
+                .encode(),
+            )),
+        }
+    }
+
+    /// The agreed protocol version, or `undefined` before the handshake or
+    /// after a fallback.
+    #[wasm_bindgen(getter)]
+    pub fn version(&self) -> Option<u16> {
+        match self.negotiation {
+            Negotiation::Agreed { version } => Some(version),
+            _ => None,
+        }
+    }
+
+    /// `true` if the versions were incompatible and only plain data is
+    /// exchanged.
+    #[wasm_bindgen(getter)]
+    pub fn fallback(&self) -> bool {
+        self.negotiation.is_fallback()
+    }
+
+    /// The optional features both sides decode, by name.
+    #[wasm_bindgen(getter)]
+    pub fn features(&self) -> Vec<String> {
+        self.features
+            .names()
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    /// Encodes application data in the format the remote expects now.
+    #[wasm_bindgen(js_name = encodePayload)]
+    pub fn encode_payload(&mut self, data: &[u8]) -> Vec<u8> {
+        self.encode(MessageKind::Data, "", data)
+    }
+
+    /// Encodes a message with its kind and topic, numbered in sequence with
+    /// the other messages of this session.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - `data`, `telemetry`, `command`, `ack` or `heartbeat`
+    /// * `topic` - The channel label, or a finer subject within it
+    /// * `data` - The application data
+    #[wasm_bindgen(js_name = encodeMessage)]
+    pub fn encode_message(
+        &mut self,
+        kind: &str,
+        topic: &str,
+        data: &[u8],
+    ) -> Result<Vec<u8>, JsError> {
+        let kind = MessageKind::from_name(kind)
+            .ok_or_else(|| JsError::new(&format!("unknown message kind '{}'", kind)))?;
+        Ok(self.encode(kind, topic, data))
+    }
+
+    /// Decodes application data received on a data channel.
+    ///
+    /// # Returns
+    ///
+    /// The payload, or an error for undecodable bytes and, without legacy
+    /// interop, bare payloads
+    #[wasm_bindgen(js_name = decodePayload)]
+    pub fn decode_payload(&self, bytes: &[u8]) -> Result<DecodedPayload, JsError> {
+        match Envelope::decode(bytes) {
+            Some((_, WireFormat::Legacy)) if !self.config.legacy_payloads => {
+                Err(JsError::new("legacy payloads are disabled"))
+            }
+            Some((envelope, format)) => Ok(DecodedPayload { envelope, format }),
+            None => Err(JsError::new("undecodable payload")),
+        }
+    }
+}
+
+impl ControlSession {
+    fn encode(&mut self, kind: MessageKind, topic: &str, data: &[u8]) -> Vec<u8> {
+        let format = WireFormat::for_session(self.negotiation, self.config.legacy_payloads);
+        let envelope = Envelope::new(kind, topic, self.sequence, Payload::new(data));
+        self.sequence += 1;
+        envelope.encode(format)
+    }
+}
@@ -0,0 +1,418 @@
+//! First-run setup wizard
+//!
+//! `rover-rtc init` walks a field technician through configuring a new rover
+//! kit: it lists the network interfaces and whether they reach the internet,
+//! asks for the signaling server and the rover's identity, checks that the
+//! signaling, STUN and TURN servers answer from this network, and writes a
+//! configuration file that is validated before it is saved.
+//!
+//! The probes are usable on their own, e.g. by a diagnostics page.
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::config::{Config, IpFamily, NetworkConfig};
+use crate::model::signaling::IceServer;
+use crate::util::{
+    has_internet_access, host_addresses,
+    stun::{binding_request, parse_binding_response, resolve_stun_url},
+    turn::{resolve_turn_url, TurnClient, TurnEvent},
+};
+
+/// The file written when no path is given.
+pub const DEFAULT_PATH: &str = "rover.toml";
+
+/// How long each server may take to answer a probe.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Interval between retransmissions of a STUN probe.
+const STUN_RETRANSMIT: Duration = Duration::from_millis(500);
+
+/// A host address found while probing the interfaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceProbe {
+    /// The interface name, e.g. `wwan0`
+    pub name: String,
+    /// The address on the interface
+    pub addr: IpAddr,
+    /// Whether the address routes to the internet
+    pub internet: bool,
+}
+
+/// Lists the host addresses of both IP versions and checks which reach the
+/// internet.
+///
+/// # Arguments
+///
+/// * `network` - The interface selection settings; the IP version setting is ignored
+///
+/// # Returns
+///
+/// The addresses, IPv4 first, or an error if the interfaces could not be listed
+pub fn probe_interfaces(network: &NetworkConfig) -> anyhow::Result<Vec<InterfaceProbe>> {
+    let network = NetworkConfig {
+        ip_family: IpFamily::Both,
+        ..network.clone()
+    };
+    Ok(host_addresses(&network)?
+        .into_iter()
+        .map(|(name, addr)| InterfaceProbe {
+            internet: has_internet_access(&addr, &network),
+            name,
+            addr,
+        })
+        .collect())
+}
+
+/// Checks that a signaling server accepts connections.
+///
+/// Only the TCP connection is tested, so the check also passes for servers
+/// requiring authentication.
+///
+/// # Arguments
+///
+/// * `url` - The `http`, `https`, `ws` or `wss` URL of the server
+/// * `timeout` - How long the connection may take
+///
+/// # Returns
+///
+/// The address that accepted the connection, or why none did
+pub fn probe_signaling(url: &str, timeout: Duration) -> anyhow::Result<SocketAddr> {
+    let parsed = reqwest::Url::parse(url).with_context(|| format!("invalid URL '{}'", url))?;
+    if !matches!(parsed.scheme(), "http" | "https" | "ws" | "wss") {
+        bail!("unsupported scheme '{}'", parsed.scheme());
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| anyhow!("URL has no host"))?;
+    let port = parsed
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("URL has no port"))?;
+
+    let mut last_error = anyhow!("{} did not resolve", host);
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(_) => return Ok(addr),
+            Err(e) => last_error = anyhow!("{}: {}", addr, e),
+        }
+    }
+    Err(last_error)
+}
+
+/// Checks that a STUN or TURN server answers.
+///
+/// A STUN server is sent Binding requests; a TURN server is asked for an
+/// allocation, which also checks the credentials, and the allocation is
+/// released again.
+///
+/// # Arguments
+///
+/// * `url` - A `stun:` or `turn:` URL
+/// * `username` - The TURN username
+/// * `credential` - The TURN credential
+/// * `timeout` - How long the server may take to answer
+///
+/// # Returns
+///
+/// This host's public address for a STUN server, or the relayed address for a
+/// TURN server, or why the server did not answer
+pub fn probe_ice_server(
+    url: &str,
+    username: Option<&str>,
+    credential: Option<&str>,
+    timeout: Duration,
+) -> anyhow::Result<SocketAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(STUN_RETRANSMIT))?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; 2000];
+
+    if url.starts_with("stun:") {
+        let server = resolve_stun_url(url).ok_or_else(|| anyhow!("host did not resolve"))?;
+        let (transaction, request) = binding_request();
+        while Instant::now() < deadline {
+            socket.send_to(&request, server)?;
+            if let Ok((n, from)) = socket.recv_from(&mut buf) {
+                if let Some(mapped) =
+                    parse_binding_response(&buf[..n], &transaction).filter(|_| from == server)
+                {
+                    return Ok(mapped);
+                }
+            }
+        }
+        bail!("no answer within {}s", timeout.as_secs());
+    }
+
+    if url.starts_with("turn:") {
+        let server = resolve_turn_url(url)
+            .ok_or_else(|| anyhow!("host did not resolve or TCP transport requested"))?;
+        let (Some(username), Some(credential)) = (username, credential) else {
+            bail!("a TURN server needs a username and credential");
+        };
+        let mut client = TurnClient::new(server, username.into(), credential.into());
+        while Instant::now() < deadline {
+            client.poll(&socket);
+            let Ok((n, from)) = socket.recv_from(&mut buf) else {
+                continue;
+            };
+            match client.handle(&buf[..n]).filter(|_| from == server) {
+                Some(TurnEvent::Allocated(relayed)) => {
+                    client.close(&socket);
+                    return Ok(relayed);
+                }
+                Some(TurnEvent::Failed) => bail!("allocation refused, check the credentials"),
+                _ => {}
+            }
+        }
+        bail!("no answer within {}s", timeout.as_secs());
+    }
+
+    bail!("only stun: and turn: URLs are supported")
+}
+
+/// Asks questions on a terminal, or any other line-based input.
+struct Prompt<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+    /// Prints a line.
+    fn say(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.output, "{}", line)
+    }
+
+    /// Asks a question, returning the trimmed answer or the default if the
+    /// answer is empty.
+    fn ask(&mut self, question: &str, default: Option<&str>) -> io::Result<String> {
+        match default {
+            Some(default) => write!(self.output, "{} [{}]: ", question, default)?,
+            None => write!(self.output, "{}: ", question)?,
+        }
+        self.output.flush()?;
+
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "input closed before setup finished",
+            ));
+        }
+        let answer = answer.trim();
+        Ok(match (answer.is_empty(), default) {
+            (true, Some(default)) => default.to_string(),
+            _ => answer.to_string(),
+        })
+    }
+
+    /// Asks until the answer is not empty.
+    fn ask_required(&mut self, question: &str, default: Option<&str>) -> io::Result<String> {
+        loop {
+            let answer = self.ask(question, default)?;
+            if !answer.is_empty() {
+                return Ok(answer);
+            }
+            self.say("  An answer is required.")?;
+        }
+    }
+
+    /// Asks an optional question; an empty answer is `None`.
+    fn ask_optional(&mut self, question: &str) -> io::Result<Option<String>> {
+        let answer = self.ask(question, None)?;
+        Ok((!answer.is_empty()).then_some(answer))
+    }
+
+    /// Asks a yes/no question.
+    fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        loop {
+            let answer = self.ask(question, Some(if default { "y" } else { "n" }))?;
+            match answer.to_lowercase().as_str() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => self.say("  Please answer y or n.")?,
+            }
+        }
+    }
+}
+
+/// Runs the wizard on the terminal.
+///
+/// # Arguments
+///
+/// * `path` - The configuration file to write
+/// * `force` - Overwrite an existing file without asking
+///
+/// # Returns
+///
+/// An error if the terminal closed, the interfaces could not be listed or the
+/// file could not be written
+pub fn run(path: &Path, force: bool) -> anyhow::Result<()> {
+    run_with(io::stdin().lock(), io::stdout(), path, force).map(|_| ())
+}
+
+/// Runs the wizard, reading the answers from `input` and writing the
+/// questions and probe results to `output`.
+///
+/// # Arguments
+///
+/// * `input` - The answers, one per line
+/// * `output` - Receives the questions and probe results
+/// * `path` - The configuration file to write
+/// * `force` - Overwrite an existing file without asking
+///
+/// # Returns
+///
+/// The written configuration, or an error if the input closed early, the
+/// existing file was kept, the interfaces could not be listed, or the
+/// configuration could not be validated or written
+pub fn run_with<R: BufRead, W: Write>(
+    input: R,
+    output: W,
+    path: &Path,
+    force: bool,
+) -> anyhow::Result<Config> {
+    let mut prompt = Prompt { input, output };
+    prompt.say("Rover RTC setup. Defaults are shown in [brackets]; press enter to keep them.")?;
+
+    if path.exists()
+        && !force
+        && !prompt.confirm(&format!("{} exists. Overwrite it?", path.display()), false)?
+    {
+        bail!("kept the existing {}", path.display());
+    }
+
+    let mut config = Config::default();
+
+    // Interfaces, and the IP versions they support
+    prompt.say("\nNetwork interfaces:")?;
+    let interfaces = probe_interfaces(&config.network)?;
+    for interface in &interfaces {
+        prompt.say(&format!(
+            "  {:<12} {:<40} {}",
+            interface.name,
+            interface.addr,
+            if interface.internet {
+                "internet"
+            } else {
+                "no internet"
+            }
+        ))?;
+    }
+    let online = |v6: bool| {
+        interfaces
+            .iter()
+            .any(|i| i.internet && i.addr.is_ipv6() == v6)
+    };
+    let suggested = match (online(false), online(true)) {
+        (true, true) => "both",
+        (false, true) => "v6",
+        (true, false) => "v4",
+        (false, false) => {
+            prompt.say(
+                "  Warning: no interface reaches the internet; the rover will only \
+                 connect on the local network.",
+            )?;
+            "v4"
+        }
+    };
+    config.network.ip_family = loop {
+        match prompt
+            .ask("IP versions to use (v4, v6, both)", Some(suggested))?
+            .as_str()
+        {
+            "v4" => break IpFamily::V4,
+            "v6" => break IpFamily::V6,
+            "both" => break IpFamily::Both,
+            _ => prompt.say("  Please answer v4, v6 or both.")?,
+        }
+    };
+
+    // Signaling server
+    prompt.say("")?;
+    config.peer.signaling_url = loop {
+        let url = prompt.ask_required("Signaling server URL", Some(&config.peer.signaling_url))?;
+        match probe_signaling(&url, PROBE_TIMEOUT) {
+            Ok(addr) => {
+                prompt.say(&format!("  Reachable at {}", addr))?;
+                break url;
+            }
+            Err(e) => {
+                prompt.say(&format!("  Not reachable: {:#}", e))?;
+                if prompt.confirm("Keep it anyway?", false)? {
+                    break url;
+                }
+            }
+        }
+    };
+
+    // Identity
+    config.peer.alias = Some(prompt.ask_required("Rover alias, e.g. rover-7", None)?);
+    config.peer.auth_token_file = prompt
+        .ask_optional("File holding the bearer token (empty for none)")?
+        .map(Into::into);
+    if config.peer.auth_token_file.is_none() {
+        config.peer.auth_token = prompt.ask_optional("Bearer token (empty for none)")?;
+    }
+
+    // STUN and TURN servers
+    prompt
+        .say("\nSTUN and TURN servers help behind NAT; the signaling server may recommend more.")?;
+    while let Some(url) = prompt.ask_optional("STUN or TURN server URL (empty to finish)")? {
+        let (username, credential) = if url.starts_with("turn") {
+            (
+                Some(prompt.ask_required("  TURN username", None)?),
+                Some(prompt.ask_required("  TURN credential", None)?),
+            )
+        } else {
+            (None, None)
+        };
+        match probe_ice_server(
+            &url,
+            username.as_deref(),
+            credential.as_deref(),
+            PROBE_TIMEOUT,
+        ) {
+            Ok(addr) if url.starts_with("turn") => {
+                prompt.say(&format!("  Allocated relayed address {}", addr))?
+            }
+            Ok(addr) => prompt.say(&format!("  Answered, public address {}", addr))?,
+            Err(e) => {
+                prompt.say(&format!("  Failed: {:#}", e))?;
+                if !prompt.confirm("Keep it anyway?", false)? {
+                    continue;
+                }
+            }
+        }
+        config.peer.ice_servers.push(IceServer {
+            urls: vec![url],
+            username,
+            credential,
+            expires_at: None,
+        });
+    }
+
+    // Validate what will be loaded, not just what was entered
+    config.validate()?;
+    let text = toml::to_string(&config).context("serializing the configuration")?;
+    let written: Config = toml::from_str(&text).context("reading back the configuration")?;
+    written.validate()?;
+
+    fs::write(
+        path,
+        format!("# Written by rover-rtc init\n\n{}", text).as_bytes(),
+    )
+    .with_context(|| format!("writing {}", path.display()))?;
+    prompt.say(&format!(
+        "\nWrote {}. Start the rover with: rover-rtc --config {} peer",
+        path.display(),
+        path.display()
+    ))?;
+    Ok(written)
+}
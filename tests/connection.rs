@@ -0,0 +1,315 @@
+//! Connection flow between a peer and a server in the same process
+
+#![cfg(feature = "native")]
+
+mod harness;
+
+use std::time::{Duration, Instant};
+
+use harness::{start_server, wait_for, Harness, DEFAULT_TIMEOUT};
+use rover_rtc::admin::AdminClient;
+use rover_rtc::model::announce::ANNOUNCE_CHANNEL;
+use rover_rtc::model::bus::{BusEvent, EventTopic};
+use rover_rtc::model::client::RemovalReason;
+use rover_rtc::model::control::CONTROL_CHANNEL;
+use rover_rtc::model::duplicate::DuplicateConfig;
+use rover_rtc::model::payload::Payload;
+use rover_rtc::model::provisioning::{ProvisionedPeer, ProvisioningConfig};
+use rover_rtc::peer::{ConnectionConfig, PeerEvent, TEST_CHANNEL};
+use rover_rtc::server::ServerEvent;
+use rover_rtc::RoverRtc;
+
+#[test]
+fn opens_the_test_channel() {
+    let harness = Harness::start();
+    harness.wait_channel_open(TEST_CHANNEL);
+    harness.stop();
+}
+
+#[test]
+fn exchanges_messages_both_ways() {
+    let harness = Harness::start();
+    harness.wait_channel_open(TEST_CHANNEL);
+    harness.send_to_server(
+        TEST_CHANNEL,
+        &Payload::serialize(Payload::new(b"from the peer")),
+    );
+    harness.send_to_peer(TEST_CHANNEL, "from the server");
+    harness.stop();
+}
+
+#[test]
+fn opens_configured_channels() {
+    let harness = Harness::builder()
+        .peer(|peer| peer.channel("status"))
+        .start();
+    harness.wait_channel_open("status");
+    harness.send_to_server("status", &Payload::serialize(Payload::new(b"battery 80%")));
+    harness.stop();
+}
+
+#[test]
+fn reports_the_peer_leaving() {
+    let mut harness = Harness::start();
+    // The peer says goodbye on the control channel
+    harness.wait_channel_open(CONTROL_CHANNEL);
+    let client = harness.client();
+
+    harness.peer.stop().expect("peer stops cleanly");
+    let removal = harness.wait_server("the client to be removed", |event| match event {
+        ServerEvent::ClientRemoved(removal) => Some(removal.clone()),
+        _ => None,
+    });
+    assert_eq!(removal.id, client);
+    assert_eq!(removal.alias.as_deref(), Some("harness"));
+    assert_eq!(removal.reason, RemovalReason::RtcDead);
+    assert!(removal.stats.bytes_received > 0);
+    harness.stop();
+}
+
+#[test]
+fn drains_clients_on_shutdown() {
+    let mut harness = Harness::start();
+    harness.wait_channel_open(TEST_CHANNEL);
+
+    harness.server.stop();
+    let reason = harness.wait_server("the client to be drained", |event| match event {
+        ServerEvent::ClientRemoved(removal) => Some(removal.reason.clone()),
+        _ => None,
+    });
+    assert_eq!(reason, RemovalReason::Drained);
+    harness.stop();
+}
+
+#[test]
+fn keeps_a_further_connection() {
+    let (mut relay, relay_events) = start_server(|relay| relay);
+    let relay_url = format!("http://{}", relay.http_addr().expect("relay address"));
+    let harness = Harness::builder()
+        .peer(move |peer| {
+            peer.channel("status").connection(ConnectionConfig {
+                id: "relay".into(),
+                signaling_url: relay_url,
+                alias: Some("harness-relay".into()),
+                mesh_target: None,
+                interface: None,
+            })
+        })
+        .start();
+    let alias = wait_for(&relay_events, DEFAULT_TIMEOUT, |event| match event {
+        ServerEvent::ClientConnected { alias, .. } => Some(alias.clone()),
+        _ => None,
+    })
+    .expect("relay: timed out waiting for the client to connect");
+    assert_eq!(alias.as_deref(), Some("harness-relay"));
+
+    // Messages sent before the relay's channel opens are dropped
+    let payload = Payload::serialize(Payload::new(b"to the relay"));
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    let received = loop {
+        assert!(
+            Instant::now() < deadline,
+            "relay: timed out waiting for data"
+        );
+        assert!(harness
+            .peer
+            .handle()
+            .send_on("relay", "status", payload.clone()));
+        let data = wait_for(
+            &relay_events,
+            Duration::from_millis(200),
+            |event| match event {
+                ServerEvent::ChannelData { channel, data, .. } if channel == "status" => {
+                    Some(data.clone())
+                }
+                _ => None,
+            },
+        );
+        if let Some(data) = data {
+            break data;
+        }
+    };
+    assert_eq!(received, payload);
+    assert!(!harness.peer.handle().send_on("unknown", "status", vec![]));
+    harness.stop();
+    relay.stop();
+}
+
+#[test]
+fn duplicates_messages_over_every_link() {
+    let (mut relay, relay_events) = start_server(|relay| relay);
+    let relay_url = format!("http://{}", relay.http_addr().expect("relay address"));
+    let harness = Harness::builder()
+        .peer(move |peer| {
+            peer.channel("estop")
+                .connection(ConnectionConfig {
+                    id: "relay".into(),
+                    signaling_url: relay_url,
+                    alias: Some("harness-relay".into()),
+                    mesh_target: None,
+                    interface: None,
+                })
+                .duplicate(DuplicateConfig {
+                    channels: vec!["estop".into()],
+                    ..DuplicateConfig::default()
+                })
+        })
+        .start();
+    harness.wait_channel_open("estop");
+
+    // Only connected links get a copy, so send until the relay's channel
+    // opened too
+    let payload = Payload::serialize(Payload::new(b"stop"));
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    let received = loop {
+        assert!(
+            Instant::now() < deadline,
+            "relay: timed out waiting for a copy"
+        );
+        harness.peer.handle().send("estop", payload.clone());
+        let data = wait_for(
+            &relay_events,
+            Duration::from_millis(200),
+            |event| match event {
+                ServerEvent::ChannelData { channel, data, .. } if channel == "estop" => {
+                    Some(data.clone())
+                }
+                _ => None,
+            },
+        );
+        if let Some(data) = data {
+            break data;
+        }
+    };
+    assert_eq!(received, payload);
+    let main = harness.wait_server("a copy on the main link", |event| match event {
+        ServerEvent::ChannelData { channel, data, .. } if channel == "estop" => Some(data.clone()),
+        _ => None,
+    });
+    assert_eq!(main, payload);
+    harness.stop();
+    relay.stop();
+}
+
+#[test]
+fn reports_who_acknowledged_an_announcement() {
+    let harness = Harness::builder()
+        .room("lab")
+        .server(|server| server.admin_token("harness"))
+        .start();
+    harness.wait_channel_open(ANNOUNCE_CHANNEL);
+
+    let url = format!("http://{}", harness.server.http_addr().expect("address"));
+    let admin = AdminClient::new(url, "harness").expect("admin client");
+    let report = admin
+        .broadcast("lab", "return to base", Some(3000))
+        .expect("broadcast answers");
+    assert_eq!(report["delivered"], 1);
+    assert_eq!(report["total"], 1);
+    assert_eq!(report["recipients"][0]["alias"], "harness");
+    assert_eq!(report["recipients"][0]["delivery"], "delivered");
+    let data = harness.wait_peer("the announcement", |event| match event {
+        PeerEvent::Announcement { data, .. } => Some(data.clone()),
+        _ => None,
+    });
+    assert_eq!(data, b"return to base");
+
+    let empty = admin
+        .broadcast("elsewhere", "return to base", None)
+        .expect("broadcast answers");
+    assert_eq!(empty["total"], 0);
+    harness.stop();
+}
+
+#[test]
+fn connects_a_provisioned_peer_with_its_credentials() {
+    let mut provisioning = ProvisioningConfig::default();
+    provisioning.peers.insert(
+        "harness".into(),
+        ProvisionedPeer {
+            ice_ufrag: "rover".into(),
+            ice_pwd: "0123456789abcdefghijklmn".into(),
+            fingerprint: None,
+        },
+    );
+    let harness = Harness::builder()
+        .server(move |server| server.provisioning(provisioning))
+        .start();
+    harness.wait_channel_open(TEST_CHANNEL);
+    harness.send_to_server(TEST_CHANNEL, &Payload::serialize(Payload::new(b"ready")));
+    harness.stop();
+}
+
+#[test]
+fn rejects_a_provisioned_peer_offering_another_fingerprint() {
+    let mut provisioning = ProvisioningConfig::default();
+    provisioning.peers.insert(
+        "harness".into(),
+        ProvisionedPeer {
+            ice_ufrag: "rover".into(),
+            ice_pwd: "0123456789abcdefghijklmn".into(),
+            fingerprint: Some(format!("sha-256 {}", ["00"; 32].join(":"))),
+        },
+    );
+    let (mut server, events) = start_server(move |server| server.provisioning(provisioning));
+    let url = format!("http://{}", server.http_addr().expect("address"));
+    let mut peer = RoverRtc::builder()
+        .signaling_url(url)
+        .alias("harness")
+        .build_peer();
+    peer.start().expect("peer starts");
+    let connected = wait_for(&events, Duration::from_secs(2), |event| match event {
+        ServerEvent::ClientConnected { .. } => Some(()),
+        _ => None,
+    });
+    assert_eq!(connected, None);
+    let _ = peer.stop();
+    server.stop();
+}
+
+#[test]
+fn disconnects_a_client_through_the_admin_api() {
+    let harness = Harness::builder()
+        .server(|server| server.admin_token("harness"))
+        .start();
+    harness.wait_channel_open(CONTROL_CHANNEL);
+
+    let url = format!("http://{}", harness.server.http_addr().expect("address"));
+    let admin = AdminClient::new(url, "harness").expect("admin client");
+    let clients = admin.clients().expect("clients answers");
+    assert_eq!(clients[0]["alias"], "harness");
+    assert_eq!(clients[0]["state"], "healthy");
+    // The harness peer does not advertise remote_restart
+    assert!(admin.restart_ice("harness").is_err());
+
+    admin.disconnect("harness").expect("disconnect answers");
+    let reason = harness.wait_server("the client to be removed", |event| match event {
+        ServerEvent::ClientRemoved(removal) => Some(removal.reason.clone()),
+        _ => None,
+    });
+    assert_eq!(
+        reason,
+        RemovalReason::Evicted("disconnected by an administrator".into())
+    );
+    harness.stop();
+}
+
+#[test]
+fn publishes_server_events_by_topic() {
+    let harness = Harness::start();
+    harness.wait_channel_open(TEST_CHANNEL);
+    let bus = harness.server.events().expect("server runs").clone();
+    let (_, events) = bus.subscribe_channel(&[EventTopic::ChannelData]);
+
+    harness.send_to_server(
+        TEST_CHANNEL,
+        &Payload::serialize(Payload::new(b"on the bus")),
+    );
+    let event = events.recv_timeout(DEFAULT_TIMEOUT).expect("channel data");
+    assert_eq!(event.to_json()["event"], "channel_data");
+    harness.stop();
+    // The client's removal is a lifecycle event
+    assert!(events
+        .try_iter()
+        .all(|e| e.topic() == EventTopic::ChannelData));
+}
@@ -0,0 +1,298 @@
+//! In-process harness for connection tests
+//!
+//! Starts a server and a peer on this machine, the way the self-test does,
+//! and waits until the peer is connected and the server accepted it. Tests
+//! then drive either side through [`Harness::server`] and [`Harness::peer`]
+//! and wait for the events they expect; a missing event fails the test with
+//! what was awaited rather than hanging it.
+//!
+//! Either side can be configured through its builder before connecting, so
+//! later regression tests (handovers, reconnection, impaired links) reuse the
+//! same setup:
+//!
+//! ```ignore
+//! let harness = Harness::builder()
+//!     .peer(|peer| peer.channel("telemetry"))
+//!     .start();
+//! harness.wait_channel_open("telemetry");
+//! ```
+
+#![allow(dead_code)]
+
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rover_rtc::model::client::ClientId;
+use rover_rtc::peer::PeerEvent;
+use rover_rtc::server::ServerEvent;
+use rover_rtc::{RoverPeer, RoverRtc, RoverRtcBuilder, RoverServer};
+
+/// How long a test waits for an event by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval at which subscriptions are polled while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+type Configure = Box<dyn FnOnce(RoverRtcBuilder) -> RoverRtcBuilder>;
+
+/// Configures the server and the peer of a [`Harness`] before they connect.
+pub struct HarnessBuilder {
+    server: Configure,
+    peer: Configure,
+    alias: String,
+    room: Option<String>,
+    timeout: Duration,
+}
+
+impl HarnessBuilder {
+    /// Configures the server, e.g. with rules or channels of its own.
+    pub fn server(
+        mut self,
+        configure: impl FnOnce(RoverRtcBuilder) -> RoverRtcBuilder + 'static,
+    ) -> Self {
+        self.server = Box::new(configure);
+        self
+    }
+
+    /// Configures the peer; its signaling URL is set by the harness.
+    pub fn peer(
+        mut self,
+        configure: impl FnOnce(RoverRtcBuilder) -> RoverRtcBuilder + 'static,
+    ) -> Self {
+        self.peer = Box::new(configure);
+        self
+    }
+
+    /// Sets the alias the peer announces, `harness` by default.
+    pub fn alias(mut self, alias: impl Into<String>) -> Self {
+        self.alias = alias.into();
+        self
+    }
+
+    /// Puts the peer in a room, none by default.
+    pub fn room(mut self, room: impl Into<String>) -> Self {
+        self.room = Some(room.into());
+        self
+    }
+
+    /// Sets how long each wait may take.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Starts the server and the peer and waits until they are connected.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either side fails to start or they do not connect in time
+    pub fn start(self) -> Harness {
+        let (server, server_events) = start_server(self.server);
+        let http_addr = server.http_addr().expect("server reports its address");
+
+        let mut url = format!("http://{}", http_addr);
+        if let Some(room) = &self.room {
+            url = format!("{}/?room={}", url, room);
+        }
+        let (peer_tx, peer_events) = mpsc::channel();
+        let mut peer = (self.peer)(RoverRtc::builder())
+            .signaling_url(url)
+            .alias(self.alias)
+            .on_peer_event(move |event| {
+                let _ = peer_tx.send(event.clone());
+            })
+            .build_peer();
+        peer.start().expect("peer starts");
+
+        let mut harness = Harness {
+            server,
+            peer,
+            server_events,
+            peer_events,
+            opened: RefCell::default(),
+            client: None,
+            timeout: self.timeout,
+        };
+        let client = harness.wait_server("the client to connect", |event| match event {
+            ServerEvent::ClientConnected { id, .. } => Some(*id),
+            _ => None,
+        });
+        harness.client = Some(client);
+        harness.wait_peer("ICE to connect", |event| {
+            (*event == PeerEvent::Connected).then_some(())
+        });
+        harness
+    }
+}
+
+/// A server and a peer connected to it, in this process.
+pub struct Harness {
+    pub server: RoverServer,
+    pub peer: RoverPeer,
+    server_events: Receiver<ServerEvent>,
+    peer_events: Receiver<PeerEvent>,
+    /// Channels the peer reported open, whatever was awaited then
+    opened: RefCell<HashSet<String>>,
+    client: Option<ClientId>,
+    timeout: Duration,
+}
+
+impl Harness {
+    /// Returns a builder configuring the server and the peer.
+    pub fn builder() -> HarnessBuilder {
+        HarnessBuilder {
+            server: Box::new(|builder| builder),
+            peer: Box::new(|builder| builder),
+            alias: "harness".into(),
+            room: None,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Starts a server and a peer with the default configuration.
+    pub fn start() -> Harness {
+        Self::builder().start()
+    }
+
+    /// Returns the ID the server assigned to the peer.
+    pub fn client(&self) -> ClientId {
+        self.client.expect("client connected")
+    }
+
+    /// Waits for a server event matching the filter, discarding the others.
+    ///
+    /// # Panics
+    ///
+    /// Panics with `what` if no such event arrives in time
+    pub fn wait_server<T>(&self, what: &str, filter: impl Fn(&ServerEvent) -> Option<T>) -> T {
+        wait_for(&self.server_events, self.timeout, filter)
+            .unwrap_or_else(|| panic!("server: timed out waiting for {}", what))
+    }
+
+    /// Waits for a peer event matching the filter, discarding the others.
+    ///
+    /// # Panics
+    ///
+    /// Panics with `what` if no such event arrives in time
+    pub fn wait_peer<T>(&self, what: &str, filter: impl Fn(&PeerEvent) -> Option<T>) -> T {
+        wait_for(&self.peer_events, self.timeout, |event| {
+            if let PeerEvent::ChannelOpen { label } = event {
+                self.opened.borrow_mut().insert(label.clone());
+            }
+            filter(event)
+        })
+        .unwrap_or_else(|| panic!("peer: timed out waiting for {}", what))
+    }
+
+    /// Waits until the peer opened a data channel, unless it already did.
+    pub fn wait_channel_open(&self, label: &str) {
+        if self.opened.borrow().contains(label) {
+            return;
+        }
+        self.wait_peer(&format!("channel '{}' to open", label), |event| {
+            matches!(event, PeerEvent::ChannelOpen { label: opened } if opened == label)
+                .then_some(())
+        });
+    }
+
+    /// Sends a message from the peer and waits until the server received it.
+    pub fn send_to_server(&self, label: &str, data: &[u8]) {
+        self.peer.handle().send(label, data.to_vec());
+        self.wait_server(
+            &format!("the peer's message on '{}'", label),
+            |event| match event {
+                ServerEvent::ChannelData {
+                    channel,
+                    data: received,
+                    ..
+                } if channel == label && received == data => Some(()),
+                _ => None,
+            },
+        );
+    }
+
+    /// Sends a text message from the server and waits until the peer
+    /// received it on a subscription.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - The channel the server's messages arrive on
+    /// * `message` - The text to send
+    pub fn send_to_peer(&self, label: &str, message: &str) {
+        let mut received = self.peer.handle().subscribe(label);
+        assert!(
+            self.server.send_message(self.client(), message),
+            "server stopped"
+        );
+        let deadline = Instant::now() + self.timeout;
+        while Instant::now() < deadline {
+            match received.try_recv() {
+                Ok(data) if data == message.as_bytes() => return,
+                Ok(_) => {}
+                Err(_) => thread::sleep(POLL_INTERVAL),
+            }
+        }
+        panic!(
+            "peer: timed out waiting for the server's message on '{}'",
+            label
+        );
+    }
+
+    /// Stops the peer and the server, in that order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the peer stopped with an error
+    pub fn stop(mut self) {
+        let result = self.peer.stop();
+        self.server.stop();
+        result.expect("peer stops cleanly");
+    }
+}
+
+/// Starts a server on this machine, e.g. a second one for the peer to
+/// connect to besides the harness's.
+///
+/// # Returns
+///
+/// The running server and the receiver of its events
+///
+/// # Panics
+///
+/// Panics if the server fails to start
+pub fn start_server(
+    configure: impl FnOnce(RoverRtcBuilder) -> RoverRtcBuilder,
+) -> (RoverServer, Receiver<ServerEvent>) {
+    let (server_tx, server_events) = mpsc::channel();
+    let mut server = configure(
+        RoverRtc::builder()
+            .http_addr("127.0.0.1:0")
+            .udp_host_local(),
+    )
+    .on_server_event(move |event| {
+        let _ = server_tx.send(event.clone());
+    })
+    .build_server();
+    server.start().expect("server starts");
+    (server, server_events)
+}
+
+/// Waits for an event matching the filter, discarding the others.
+pub fn wait_for<E, T>(
+    events: &Receiver<E>,
+    timeout: Duration,
+    filter: impl Fn(&E) -> Option<T>,
+) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.checked_duration_since(Instant::now())?;
+        if let Some(found) = filter(&events.recv_timeout(remaining).ok()?) {
+            return Some(found);
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//! Regression tests replaying recorded traces through the server
+//!
+//! The datagram traces under `tests/traces` hold what a rover sent, datagram
+//! by datagram, with the time each arrived. They are replayed through the
+//! event loop's [`LoopCore`] on a [`ManualClock`] and a [`MemoryTransport`],
+//! so each replay sees the same times and keeps every transmit: the tests
+//! check which datagrams the server answers, with what and when, and the
+//! state the session goes through. A change to the demultiplexing, the
+//! holding of early packets or the blocklist fails here.
+//!
+//! The message traces are recordings, as written by the `Recorder`, of what
+//! a rover sent over its data channels. They are replayed through a local
+//! server and peer on real sockets, and checked at the level of messages:
+//! every message arrives, once, in order on its channel, and the protocol's
+//! own channels are left alone.
+
+#![cfg(feature = "native")]
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rover_rtc::auth::Access;
+use rover_rtc::model::blocklist::Blocklist;
+use rover_rtc::model::client::{Client, ClientId};
+use rover_rtc::model::health::HealthState;
+use rover_rtc::model::provisioning::{ProvisionedPeer, ProvisioningConfig};
+use rover_rtc::model::recording::{read_recording, Direction, RecordedMessage};
+use rover_rtc::model::transport::{ManualClock, MemoryTransport, Transmitted};
+use rover_rtc::replay::{self, ReplayOptions};
+use rover_rtc::server::LoopCore;
+use serde::Deserialize;
+use str0m::{change::SdpOffer, Candidate, IceConnectionState, IceCreds, Rtc};
+
+/// The address the replayed server receives on.
+const SERVER: &str = "203.0.113.1:3478";
+
+/// The address of the rover in the datagram traces.
+const ROVER: &str = "192.0.2.7:50000";
+
+/// The ICE username fragment and password provisioned for the rover.
+const ROVER_UFRAG: &str = "rover7";
+const ROVER_PWD: &str = "kF3qZ8pLx2vN0bT6cY4mWs9d";
+
+/// How many outputs a client may produce per poll of a replay.
+const MAX_POLLS: usize = 100;
+
+/// A datagram of a trace.
+#[derive(Deserialize)]
+struct TraceDatagram {
+    /// When it arrived, since the start of the trace
+    at_ms: u64,
+    /// Where it came from
+    source: SocketAddr,
+    /// The datagram, in hex
+    data: String,
+}
+
+impl TraceDatagram {
+    fn bytes(&self) -> Vec<u8> {
+        (0..self.data.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&self.data[i..i + 2], 16).expect("trace hex"))
+            .collect()
+    }
+}
+
+/// What the server sent, as far as the tests tell datagrams apart.
+#[derive(Debug, PartialEq, Eq)]
+enum Sent {
+    /// A STUN binding success answering the given transaction
+    BindingSuccess([u8; 12]),
+    /// A STUN binding request of the server's own connectivity checks
+    BindingRequest,
+    /// A DTLS handshake record opening with the given message type
+    Handshake(u8),
+    /// Anything else, by its first byte
+    Other(u8),
+}
+
+impl From<&Transmitted> for Sent {
+    fn from(transmitted: &Transmitted) -> Self {
+        let payload = transmitted.payload.as_slice();
+        match payload {
+            [0x01, 0x01, ..] if payload.len() >= 20 => {
+                Sent::BindingSuccess(payload[8..20].try_into().expect("transaction id"))
+            }
+            [0x00, 0x01, ..] => Sent::BindingRequest,
+            [0x16, ..] if payload.len() > 13 => Sent::Handshake(payload[13]),
+            _ => Sent::Other(payload.first().copied().unwrap_or_default()),
+        }
+    }
+}
+
+/// Reads a datagram trace.
+fn datagrams(name: &str) -> Vec<TraceDatagram> {
+    std::fs::read_to_string(trace(name))
+        .expect("trace reads")
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("trace parses"))
+        .collect()
+}
+
+/// Returns a transaction ID of the rover's checks, which count up by their
+/// first byte.
+fn transaction(first: u8) -> [u8; 12] {
+    std::array::from_fn(|i| first + i as u8)
+}
+
+/// An event loop replaying a datagram trace.
+struct Replay {
+    core: LoopCore,
+    clock: Arc<ManualClock>,
+    transport: Arc<MemoryTransport>,
+    start: Instant,
+}
+
+impl Replay {
+    /// Creates an event loop with the rover provisioned and no sessions.
+    fn new(blocklist: Blocklist) -> Self {
+        let start = Instant::now();
+        let clock = Arc::new(ManualClock::new(start));
+        let transport = Arc::new(MemoryTransport::new());
+        let mut provisioning = ProvisioningConfig::default();
+        provisioning.peers.insert(
+            "rover-7".into(),
+            ProvisionedPeer {
+                ice_ufrag: ROVER_UFRAG.into(),
+                ice_pwd: ROVER_PWD.into(),
+                fingerprint: None,
+            },
+        );
+        let core = LoopCore::new(
+            transport.clone(),
+            clock.clone(),
+            SERVER.parse().expect("server address"),
+            provisioning,
+            Arc::new(Mutex::new(blocklist)),
+        );
+        Self {
+            core,
+            clock,
+            transport,
+            start,
+        }
+    }
+
+    /// Moves the clock to a time of the trace.
+    fn at(&self, ms: u64) {
+        self.clock
+            .advance_to(self.start + Duration::from_millis(ms));
+    }
+
+    /// Admits the rover's session, answering the offer of the trace.
+    fn admit(&mut self) -> ClientId {
+        let mut rtc = Rtc::builder()
+            .set_local_ice_credentials(IceCreds {
+                ufrag: ROVER_UFRAG.into(),
+                pass: ROVER_PWD.into(),
+            })
+            .build();
+        rtc.add_local_candidate(
+            Candidate::host(SERVER.parse().expect("server address"), "udp")
+                .expect("host candidate"),
+        );
+        let offer = SdpOffer::from_sdp_string(
+            &std::fs::read_to_string(trace("rover-7.offer.sdp")).expect("offer reads"),
+        )
+        .expect("offer parses");
+        rtc.sdp_api().accept_offer(offer).expect("offer accepted");
+        let id = ClientId::next();
+        self.core
+            .admit(Client::new(id, rtc, Access::default(), "replay".into()));
+        id
+    }
+
+    /// Admits the rover's session once the clock reaches `ms`, after the
+    /// timers up to then ran.
+    fn admit_at(&mut self, ms: u64) -> Vec<Transmitted> {
+        self.at(ms);
+        let mut sent = self.step();
+        self.admit();
+        sent.extend(self.step());
+        sent
+    }
+
+    /// Lets the clients act on the time and their input, returning what
+    /// they sent.
+    fn step(&mut self) -> Vec<Transmitted> {
+        self.core.handle_timeout();
+        self.core.poll(MAX_POLLS);
+        self.transport.take()
+    }
+
+    /// Replays a trace, admitting the rover's session once the clock
+    /// reaches `admit_at_ms`.
+    ///
+    /// # Returns
+    ///
+    /// What was sent, by the time it was sent at
+    fn play(&mut self, name: &str, admit_at_ms: u64) -> BTreeMap<u64, Vec<Transmitted>> {
+        let mut sent: BTreeMap<u64, Vec<Transmitted>> = BTreeMap::new();
+        let mut admitted = false;
+        for datagram in datagrams(name) {
+            if !admitted && datagram.at_ms >= admit_at_ms {
+                sent.insert(admit_at_ms, self.admit_at(admit_at_ms));
+                admitted = true;
+            }
+            self.at(datagram.at_ms);
+            self.core.receive(datagram.source, &datagram.bytes());
+            sent.entry(datagram.at_ms).or_default().extend(self.step());
+        }
+        if !admitted {
+            sent.insert(admit_at_ms, self.admit_at(admit_at_ms));
+        }
+        sent.retain(|_, transmits| !transmits.is_empty());
+        sent
+    }
+}
+
+/// Returns what was sent, by time, with the destination checked.
+fn sent_to(sent: &BTreeMap<u64, Vec<Transmitted>>, destination: &str) -> Vec<(u64, Vec<Sent>)> {
+    let destination: SocketAddr = destination.parse().expect("destination address");
+    sent.iter()
+        .map(|(&at, transmits)| {
+            assert!(
+                transmits.iter().all(|t| t.destination == destination),
+                "sent elsewhere at {} ms: {:?}",
+                at,
+                transmits
+            );
+            (at, transmits.iter().map(Sent::from).collect())
+        })
+        .collect()
+}
+
+#[test]
+fn answers_a_provisioned_rover_from_a_trace() {
+    let mut replay = Replay::new(Blocklist::default());
+    let sent = replay.play("rover-7-checks.jsonl", 100);
+
+    // The checks from before the session are held and answered when it
+    // arrives; the stranger's check and the garbage are never answered.
+    assert_eq!(
+        sent_to(&sent, ROVER),
+        [
+            (
+                100,
+                vec![
+                    Sent::BindingSuccess(transaction(0x01)),
+                    Sent::BindingRequest,
+                    Sent::BindingSuccess(transaction(0x11)),
+                ]
+            ),
+            (
+                300,
+                vec![
+                    Sent::BindingSuccess(transaction(0x21)),
+                    Sent::BindingRequest
+                ]
+            ),
+            // ServerHello, Certificate and ServerKeyExchange answer the
+            // rover's ClientHello
+            (
+                320,
+                vec![
+                    Sent::Handshake(0x02),
+                    Sent::Handshake(0x0b),
+                    Sent::Handshake(0x0c)
+                ]
+            ),
+            (600, vec![Sent::BindingSuccess(transaction(0x31))]),
+        ]
+    );
+
+    let client = &replay.core.clients()[0];
+    let states: Vec<_> = client
+        .events
+        .snapshot()
+        .into_iter()
+        .map(|event| event.detail)
+        .filter(|detail| detail.starts_with("ICE "))
+        .collect();
+    assert_eq!(states, ["ICE Checking", "ICE Completed"]);
+    assert_eq!(replay.core.health(client.id), Some(HealthState::Healthy));
+}
+
+#[test]
+fn drops_checks_held_too_long() {
+    let mut replay = Replay::new(Blocklist::default());
+    // The session arrives after the checks from the start of the trace
+    // are no longer held.
+    let sent = replay.play("rover-7-checks.jsonl", 2_100);
+
+    let answered: Vec<_> = sent_to(&sent, ROVER)
+        .into_iter()
+        .flat_map(|(at, sent)| sent.into_iter().map(move |s| (at, s)))
+        .filter(|(_, sent)| matches!(sent, Sent::BindingSuccess(_)))
+        .collect();
+    assert_eq!(
+        answered,
+        [
+            (2_100, Sent::BindingSuccess(transaction(0x21))),
+            (2_100, Sent::BindingSuccess(transaction(0x31))),
+        ]
+    );
+}
+
+#[test]
+fn drops_a_trace_from_a_blocked_source() {
+    let mut blocklist = Blocklist::default();
+    blocklist
+        .block(
+            ROVER.parse::<SocketAddr>().expect("rover address").ip(),
+            "replay".into(),
+            None,
+        )
+        .expect("rover blocked");
+    let mut replay = Replay::new(blocklist);
+    let sent = replay.play("rover-7-checks.jsonl", 100);
+
+    // The server still checks the rover's candidate, but answers nothing.
+    assert!(sent
+        .values()
+        .flatten()
+        .all(|t| Sent::from(t) == Sent::BindingRequest));
+    let client = &replay.core.clients()[0];
+    assert_ne!(
+        client.counters.ice_state,
+        Some(IceConnectionState::Completed)
+    );
+}
+
+/// Speed factor of the replays, so a trace of seconds takes a fraction.
+const SPEED: f64 = 4.0;
+
+/// How long the server's recording may take to be written out.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Returns the path of a trace.
+fn trace(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/traces")
+        .join(name)
+}
+
+/// Returns a path for a recording of this test process.
+fn output(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rover-rtc-{}-{}.jsonl", name, std::process::id()))
+}
+
+/// Groups the data of messages by channel, in order.
+fn by_channel<'a>(
+    messages: impl IntoIterator<Item = &'a RecordedMessage>,
+) -> BTreeMap<String, Vec<Vec<u8>>> {
+    let mut channels: BTreeMap<String, Vec<Vec<u8>>> = BTreeMap::new();
+    for message in messages {
+        channels
+            .entry(message.channel.clone())
+            .or_default()
+            .push(message.data.clone());
+    }
+    channels
+}
+
+/// Reads the messages the server recorded as received, waiting for its
+/// writer to catch up with `expected` of them.
+fn received(path: &Path, expected: usize) -> Vec<RecordedMessage> {
+    let deadline = Instant::now() + WRITE_TIMEOUT;
+    loop {
+        let messages: Vec<_> = read_recording(path)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|m| m.direction == Direction::Inbound)
+            .collect();
+        if messages.len() >= expected || Instant::now() > deadline {
+            return messages;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn replays_every_message_of_a_trace() {
+    let report = replay::run(
+        &trace("rover-status.jsonl"),
+        &ReplayOptions {
+            speed: SPEED,
+            ..ReplayOptions::default()
+        },
+    )
+    .expect("trace replays");
+
+    assert!(report.complete(), "messages were lost:\n{}", report);
+    let counts: Vec<_> = report
+        .channels
+        .iter()
+        .map(|c| (c.label.as_str(), c.sent, c.received))
+        .collect();
+    assert_eq!(counts, [("odometry", 6, 6), ("status", 7, 7)]);
+    // The trace's control message would confuse the new session
+    assert_eq!(report.skipped, 1);
+}
+
+#[test]
+fn delivers_messages_in_order() {
+    let path = output("in-order");
+    let _ = std::fs::remove_file(&path);
+    let report = replay::run(
+        &trace("rover-status.jsonl"),
+        &ReplayOptions {
+            speed: SPEED,
+            direction: Some(Direction::Outbound),
+            output: Some(path.clone()),
+            ..ReplayOptions::default()
+        },
+    )
+    .expect("trace replays");
+    assert!(report.complete(), "messages were lost:\n{}", report);
+    // The inbound message and the control message are not replayed
+    assert_eq!(report.skipped, 2);
+
+    let mut expected = by_channel(
+        read_recording(&trace("rover-status.jsonl"))
+            .expect("trace reads")
+            .iter()
+            .filter(|m| m.direction == Direction::Outbound),
+    );
+    expected.remove("control");
+    let total = expected.values().map(Vec::len).sum();
+    let mut actual = by_channel(&received(&path, total));
+    actual.retain(|label, _| expected.contains_key(label));
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(actual, expected);
+}